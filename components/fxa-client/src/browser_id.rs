@@ -57,7 +57,8 @@ impl FirefoxAccount {
     }
 
     pub fn advance(&mut self) -> Result<()> {
-        let state_machine = LoginStateMachine::new(&self.state.config, self.client.clone());
+        let state_machine =
+            LoginStateMachine::new(&self.state.config, self.client.clone(), self.clock.as_ref());
         let state = std::mem::replace(&mut self.state.login_state, LoginState::Unknown);
         self.state.login_state = state_machine.advance(state)?;
         Ok(())
@@ -84,6 +85,7 @@ impl FirefoxAccount {
         let key_pair = married.key_pair();
         let certificate = married.certificate();
         Ok(jwt_utils::create_assertion(
+            self.clock.as_ref(),
             key_pair,
             &certificate,
             audience,
@@ -36,6 +36,20 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         &*self.0.get_context()
     }
+
+    /// Whether the request that produced this error is worth retrying later,
+    /// e.g. when a load balancer in front of FxA answered with an HTML error
+    /// page during an outage.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind() {
+            ErrorKind::UnexpectedResponse {
+                status,
+                content_type: Some(content_type),
+                ..
+            } => *status >= 500 && *status < 600 && content_type.starts_with("text/html"),
+            _ => false,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -156,6 +170,16 @@ pub enum ErrorKind {
         info: String,
     },
 
+    #[fail(
+        display = "Unexpected server response: status {} content-type {:?} body '{}'",
+        status, content_type, body_snippet
+    )]
+    UnexpectedResponse {
+        status: u16,
+        content_type: Option<String>,
+        body_snippet: String,
+    },
+
     #[fail(display = "Crypto/NSS error: {}", _0)]
     CryptoError(#[fail(cause)] rc_crypto::Error),
 
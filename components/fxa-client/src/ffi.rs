@@ -31,7 +31,8 @@ pub mod error_codes {
     /// and `ErrorKind::RemoteError`'s where `code == 401`.
     pub const AUTHENTICATION: i32 = 2;
 
-    /// Code for network errors.
+    /// Code for network errors, and for server responses that are worth
+    /// retrying (see `Error::is_retryable`).
     pub const NETWORK: i32 = 3;
 }
 
@@ -45,6 +46,10 @@ fn get_code(err: &Error) -> ErrorCode {
             log::warn!("Authentication error: {:?}", err);
             ErrorCode::new(error_codes::AUTHENTICATION)
         }
+        _ if err.is_retryable() => {
+            log::warn!("Retryable server error: {:?}", err);
+            ErrorCode::new(error_codes::NETWORK)
+        }
         ErrorKind::RequestError(_) => {
             log::warn!("Network error: {:?}", err);
             ErrorCode::new(error_codes::NETWORK)
@@ -2,10 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::{config::Config, errors::*};
+use crate::{config::Config, errors::*, util::Clock};
 use serde_derive::*;
 use serde_json::json;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 use viaduct::{header_names, status_codes, Request, Response};
 
 #[cfg(feature = "browserid")]
@@ -55,7 +55,12 @@ pub trait FxAClient {
     ) -> Result<UpdateDeviceResponse>;
 }
 
-pub struct Client;
+pub struct Client {
+    // Only used to sign requests, which needs the "browserid" feature.
+    #[cfg_attr(not(feature = "browserid"), allow(dead_code))]
+    clock: Arc<dyn Clock>,
+}
+
 impl FxAClient for Client {
     fn profile(
         &self,
@@ -79,7 +84,7 @@ impl FxAClient for Client {
             .map(ToString::to_string);
         Ok(Some(ResponseAndETag {
             etag,
-            response: resp.json()?,
+            response: parse_json(&resp)?,
         }))
     }
 
@@ -135,7 +140,7 @@ impl FxAClient for Client {
         if let Some(limit) = limit {
             request = request.query(&[("limit", &limit.to_string())])
         }
-        Ok(Self::make_request(request)?.json()?)
+        parse_json(&Self::make_request(request)?)
     }
 
     fn invoke_command(
@@ -164,7 +169,7 @@ impl FxAClient for Client {
         let url = config.auth_url_path("v1/account/devices")?;
         let request =
             Request::get(url).header(header_names::AUTHORIZATION, bearer_token(refresh_token))?;
-        Ok(Self::make_request(request)?.json()?)
+        parse_json(&Self::make_request(request)?)
     }
 
     fn update_device(
@@ -178,13 +183,13 @@ impl FxAClient for Client {
             .header(header_names::AUTHORIZATION, bearer_token(refresh_token))?
             .header(header_names::CONTENT_TYPE, "application/json")?
             .body(serde_json::to_string(&update)?);
-        Ok(Self::make_request(request)?.json()?)
+        parse_json(&Self::make_request(request)?)
     }
 }
 
 impl Client {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock }
     }
 
     fn make_oauth_token_request(
@@ -193,28 +198,69 @@ impl Client {
         body: serde_json::Value,
     ) -> Result<OAuthTokenResponse> {
         let url = config.token_endpoint()?;
-        Ok(Self::make_request(Request::post(url).json(&body))?.json()?)
+        parse_json(&Self::make_request(Request::post(url).json(&body))?)
     }
 
     fn make_request(request: Request) -> Result<Response> {
-        let resp = request.send()?;
-        if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
-            Ok(resp)
-        } else {
-            let json: std::result::Result<serde_json::Value, _> = resp.json();
-            match json {
-                Ok(json) => Err(ErrorKind::RemoteError {
-                    code: json["code"].as_u64().unwrap_or(0),
-                    errno: json["errno"].as_u64().unwrap_or(0),
-                    error: json["error"].as_str().unwrap_or("").to_string(),
-                    message: json["message"].as_str().unwrap_or("").to_string(),
-                    info: json["info"].as_str().unwrap_or("").to_string(),
-                }
-                .into()),
-                Err(_) => Err(resp.require_success().unwrap_err().into()),
-            }
+        check_response(request.send()?)
+    }
+}
+
+/// The maximum number of characters of a response body we keep around in
+/// `ErrorKind::UnexpectedResponse`, so that HTML error pages don't end up
+/// in their entirety in our logs.
+const MAX_BODY_SNIPPET_LEN: usize = 256;
+
+/// Turns a non-successful response into an error. FxA error bodies are
+/// JSON, but load balancers in front of it can also return HTML pages, in
+/// which case we report an `UnexpectedResponse` instead.
+pub(crate) fn check_response(resp: Response) -> Result<Response> {
+    if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
+        return Ok(resp);
+    }
+    let json: std::result::Result<serde_json::Value, _> = resp.json();
+    match json {
+        Ok(ref json) if json.is_object() => Err(ErrorKind::RemoteError {
+            code: json["code"].as_u64().unwrap_or(0),
+            errno: json["errno"].as_u64().unwrap_or(0),
+            error: json["error"].as_str().unwrap_or("").to_string(),
+            message: json["message"].as_str().unwrap_or("").to_string(),
+            info: json["info"].as_str().unwrap_or("").to_string(),
         }
+        .into()),
+        _ => Err(unexpected_response(&resp)),
+    }
+}
+
+/// Deserializes the body of a response, reporting the status, content-type
+/// and the start of the body if it isn't the JSON we were expecting.
+pub(crate) fn parse_json<T>(resp: &Response) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    resp.json().map_err(|e| {
+        log::warn!("Failed to parse response from {}: {}", resp.url, e);
+        unexpected_response(resp)
+    })
+}
+
+fn unexpected_response(resp: &Response) -> Error {
+    // A well-formed JSON body that didn't match what we expected may well
+    // hold tokens or keys (e.g. a login response), so we don't keep it.
+    let body_snippet = if serde_json::from_slice::<serde_json::Value>(&resp.body).is_ok() {
+        rc_crypto::sensitive::REDACTED.to_string()
+    } else {
+        resp.text().chars().take(MAX_BODY_SNIPPET_LEN).collect()
+    };
+    ErrorKind::UnexpectedResponse {
+        status: resp.status,
+        content_type: resp
+            .headers
+            .get(header_names::CONTENT_TYPE)
+            .map(ToString::to_string),
+        body_snippet,
     }
+    .into()
 }
 
 fn bearer_token(token: &str) -> String {
@@ -9,8 +9,12 @@ use crate::{
     Config,
 };
 use hawk_request::HawkRequestBuilder;
-use rc_crypto::{digest, hkdf, hmac};
+use rc_crypto::{
+    digest, hkdf, hmac,
+    sensitive::{SensitiveBytes, SensitiveString},
+};
 use rsa::RSABrowserIDKeyPair;
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::*;
 use serde_json::json;
 use url::Url;
@@ -80,13 +84,13 @@ impl FxABrowserIDClient for http_client::Client {
         let request = Request::post(url)
             .query(&[("keys", if get_keys { "true" } else { "false" })])
             .json(&parameters);
-        Self::make_request(request)?.json().map_err(Into::into)
+        http_client::parse_json(&Self::make_request(request)?)
     }
 
     fn account_status(&self, config: &Config, uid: &str) -> Result<AccountStatusResponse> {
         let url = config.auth_url_path("v1/account/status")?;
         let request = Request::get(url).query(&[("uid", uid)]);
-        Self::make_request(request)?.json().map_err(Into::into)
+        http_client::parse_json(&Self::make_request(request)?)
     }
 
     fn keys(&self, config: &Config, key_fetch_token: &[u8]) -> Result<KeysResponse> {
@@ -95,30 +99,10 @@ impl FxABrowserIDClient for http_client::Client {
         let key =
             derive_hkdf_sha256_key(&key_fetch_token, &HKDF_SALT, &context_info, KEY_LENGTH * 3)?;
         let key_request_key = &key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
-        let request = HawkRequestBuilder::new(Method::Get, url, &key).build()?;
-        let json: serde_json::Value = Self::make_request(request)?.json()?;
-        let bundle = match json["bundle"].as_str() {
-            Some(bundle) => bundle,
-            None => panic!("Invalid JSON"),
-        };
-        let data = hex::decode(bundle)?;
-        if data.len() != 3 * KEY_LENGTH {
-            return Err(ErrorKind::BadKeyLength("bundle", 3 * KEY_LENGTH, data.len()).into());
-        }
-        let ciphertext = &data[0..(KEY_LENGTH * 2)];
-        let mac_code = &data[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
-        let context_info = kw("account/keys");
-        let bytes =
-            derive_hkdf_sha256_key(key_request_key, &HKDF_SALT, &context_info, KEY_LENGTH * 3)?;
-        let hmac_key = &bytes[0..KEY_LENGTH];
-        let xor_key = &bytes[KEY_LENGTH..(KEY_LENGTH * 3)];
-
-        let v_key = hmac::VerificationKey::new(&digest::SHA256, hmac_key);
-        hmac::verify(&v_key, ciphertext, mac_code).map_err(|_| ErrorKind::HmacMismatch)?;
-
-        let xored_bytes = ciphertext.xored_with(xor_key)?;
-        let wrap_kb = xored_bytes[KEY_LENGTH..(KEY_LENGTH * 2)].to_vec();
-        Ok(KeysResponse { wrap_kb })
+        let request =
+            HawkRequestBuilder::new(Method::Get, url, &key, self.clock.as_ref()).build()?;
+        let resp: KeysBundleResponse = http_client::parse_json(&Self::make_request(request)?)?;
+        decrypt_keys_bundle(key_request_key, &resp.bundle)
     }
 
     fn recovery_email_status(
@@ -128,8 +112,9 @@ impl FxABrowserIDClient for http_client::Client {
     ) -> Result<RecoveryEmailStatusResponse> {
         let url = config.auth_url_path("v1/recovery_email/status")?;
         let key = derive_key_from_session_token(session_token)?;
-        let request = HawkRequestBuilder::new(Method::Get, url, &key).build()?;
-        Self::make_request(request)?.json().map_err(Into::into)
+        let request =
+            HawkRequestBuilder::new(Method::Get, url, &key, self.clock.as_ref()).build()?;
+        http_client::parse_json(&Self::make_request(request)?)
     }
 
     fn oauth_token_with_session_token(
@@ -141,7 +126,8 @@ impl FxABrowserIDClient for http_client::Client {
         let audience = get_oauth_audience(&config.oauth_url()?)?;
         let key_pair = key_pair(1024)?;
         let certificate = self.sign(config, session_token, &key_pair)?.certificate;
-        let assertion = jwt_utils::create_assertion(&key_pair, &certificate, &audience)?;
+        let assertion =
+            jwt_utils::create_assertion(self.clock.as_ref(), &key_pair, &certificate, &audience)?;
         let parameters = json!({
             "assertion": assertion,
             "client_id": config.client_id,
@@ -150,10 +136,10 @@ impl FxABrowserIDClient for http_client::Client {
         });
         let key = derive_key_from_session_token(session_token)?;
         let url = config.authorization_endpoint()?;
-        let request = HawkRequestBuilder::new(Method::Post, url, &key)
+        let request = HawkRequestBuilder::new(Method::Post, url, &key, self.clock.as_ref())
             .body(parameters)
             .build()?;
-        Self::make_request(request)?.json().map_err(Into::into)
+        http_client::parse_json(&Self::make_request(request)?)
     }
 
     fn sign(
@@ -169,10 +155,10 @@ impl FxABrowserIDClient for http_client::Client {
         });
         let key = derive_key_from_session_token(session_token)?;
         let url = config.auth_url_path("v1/certificate/sign")?;
-        let request = HawkRequestBuilder::new(Method::Post, url, &key)
+        let request = HawkRequestBuilder::new(Method::Post, url, &key, self.clock.as_ref())
             .body(parameters)
             .build()?;
-        Self::make_request(request)?.json().map_err(Into::into)
+        http_client::parse_json(&Self::make_request(request)?)
     }
 }
 
@@ -193,7 +179,7 @@ pub fn key_pair(len: u32) -> Result<RSABrowserIDKeyPair> {
     RSABrowserIDKeyPair::generate_random(len)
 }
 
-pub fn derive_sync_key(kb: &[u8]) -> Result<Vec<u8>> {
+pub fn derive_sync_key(kb: &[u8]) -> Result<SensitiveBytes> {
     let salt = [0u8; 0];
     let context_info = kw("oldsync");
     derive_hkdf_sha256_key(&kb, &salt, &context_info, KEY_LENGTH * 2)
@@ -215,7 +201,30 @@ fn get_oauth_audience(oauth_url: &Url) -> Result<String> {
     }
 }
 
-fn derive_key_from_session_token(session_token: &[u8]) -> Result<Vec<u8>> {
+/// Verifies and decrypts the `bundle` returned by `account/keys`, using the
+/// keyRequestKey derived from the keyFetchToken.
+fn decrypt_keys_bundle(key_request_key: &[u8], bundle: &str) -> Result<KeysResponse> {
+    let data = hex::decode(bundle)?;
+    if data.len() != 3 * KEY_LENGTH {
+        return Err(ErrorKind::BadKeyLength("bundle", 3 * KEY_LENGTH, data.len()).into());
+    }
+    let ciphertext = &data[0..(KEY_LENGTH * 2)];
+    let mac_code = &data[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
+    let context_info = kw("account/keys");
+    let bytes = derive_hkdf_sha256_key(key_request_key, &HKDF_SALT, &context_info, KEY_LENGTH * 3)?;
+    let hmac_key = &bytes[0..KEY_LENGTH];
+    let xor_key = &bytes[KEY_LENGTH..(KEY_LENGTH * 3)];
+
+    let v_key = hmac::VerificationKey::new(&digest::SHA256, hmac_key);
+    hmac::verify(&v_key, ciphertext, mac_code).map_err(|_| ErrorKind::HmacMismatch)?;
+
+    let xored_bytes = SensitiveBytes::from(ciphertext.xored_with(xor_key)?);
+    let ka = SensitiveBytes::from(&xored_bytes[0..KEY_LENGTH]);
+    let wrap_kb = SensitiveBytes::from(&xored_bytes[KEY_LENGTH..(KEY_LENGTH * 2)]);
+    Ok(KeysResponse { ka, wrap_kb })
+}
+
+fn derive_key_from_session_token(session_token: &[u8]) -> Result<SensitiveBytes> {
     let context_info = kw("sessionToken");
     Ok(derive_hkdf_sha256_key(
         session_token,
@@ -225,18 +234,35 @@ fn derive_key_from_session_token(session_token: &[u8]) -> Result<Vec<u8>> {
     )?)
 }
 
-fn derive_hkdf_sha256_key(ikm: &[u8], salt: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>> {
+fn derive_hkdf_sha256_key(
+    ikm: &[u8],
+    salt: &[u8],
+    info: &[u8],
+    len: usize,
+) -> Result<SensitiveBytes> {
     let salt = hmac::SigningKey::new(&digest::SHA256, salt);
-    let mut out = vec![0u8; len];
+    let mut out = SensitiveBytes::from(vec![0u8; len]);
     hkdf::extract_and_expand(&salt, ikm, info, &mut out)?;
     Ok(out)
 }
 
-#[derive(Deserialize)]
+fn deserialize_sensitive_string<'de, D>(
+    deserializer: D,
+) -> std::result::Result<SensitiveString, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(Into::into)
+}
+
+#[derive(Deserialize, Debug)]
 pub struct LoginResponse {
     pub uid: String,
-    #[serde(rename = "sessionToken")]
-    pub session_token: String,
+    #[serde(
+        rename = "sessionToken",
+        deserialize_with = "deserialize_sensitive_string"
+    )]
+    pub session_token: SensitiveString,
     pub verified: bool,
 }
 
@@ -258,9 +284,26 @@ pub struct SignResponse {
 }
 
 #[derive(Deserialize)]
+struct KeysBundleResponse {
+    bundle: String,
+}
+
+#[derive(Debug)]
 pub struct KeysResponse {
-    // ka: Vec<u8>,
-    pub wrap_kb: Vec<u8>,
+    pub ka: SensitiveBytes,
+    pub wrap_kb: SensitiveBytes,
+}
+
+impl KeysResponse {
+    /// Unwraps kB using the unwrapBKey derived from the user's password.
+    pub fn unwrap_kb(&self, unwrap_b_key: &[u8]) -> Result<SensitiveBytes> {
+        if unwrap_b_key.len() != KEY_LENGTH {
+            return Err(
+                ErrorKind::BadKeyLength("unwrapBKey", KEY_LENGTH, unwrap_b_key.len()).into(),
+            );
+        }
+        Ok(SensitiveBytes::from(self.wrap_kb.xored_with(unwrap_b_key)?))
+    }
 }
 
 #[cfg(test)]
@@ -310,4 +353,187 @@ mod tests {
             "247b675ffb4c46310bc87e26d712153abe5e1c90ef00a4784594f97ef54f2375"
         );
     }
+
+    fn account_status_response(status: u16, content_type: &str, body: &str) -> viaduct::Response {
+        let mut headers = viaduct::Headers::new();
+        headers
+            .insert(viaduct::header_names::CONTENT_TYPE, content_type)
+            .unwrap();
+        viaduct::Response {
+            request_method: Method::Get,
+            url: Url::parse("https://accounts.example.com/v1/account/status?uid=123").unwrap(),
+            status,
+            headers,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_account_status_html_502() {
+        let body = format!(
+            "<html><head><title>502 Bad Gateway</title></head><body>{}</body></html>",
+            "x".repeat(1000)
+        );
+        let resp = account_status_response(502, "text/html; charset=utf-8", &body);
+        let err = http_client::check_response(resp).unwrap_err();
+        assert!(err.is_retryable());
+        match err.kind() {
+            ErrorKind::UnexpectedResponse {
+                status,
+                content_type,
+                body_snippet,
+            } => {
+                assert_eq!(*status, 502);
+                assert_eq!(content_type.as_ref().unwrap(), "text/html; charset=utf-8");
+                assert!(body_snippet.starts_with("<html><head><title>502 Bad Gateway"));
+                assert_eq!(body_snippet.chars().count(), 256);
+            }
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_account_status_empty_200() {
+        let resp = account_status_response(200, "application/json", "");
+        let resp = http_client::check_response(resp).unwrap();
+        let err = http_client::parse_json::<AccountStatusResponse>(&resp).unwrap_err();
+        assert!(!err.is_retryable());
+        match err.kind() {
+            ErrorKind::UnexpectedResponse {
+                status,
+                content_type,
+                body_snippet,
+            } => {
+                assert_eq!(*status, 200);
+                assert_eq!(content_type.as_ref().unwrap(), "application/json");
+                assert_eq!(body_snippet, "");
+            }
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_account_status_json_error() {
+        let resp = account_status_response(
+            400,
+            "application/json",
+            r#"{"code":400,"errno":107,"error":"Bad Request","message":"Invalid parameter"}"#,
+        );
+        let err = http_client::check_response(resp).unwrap_err();
+        assert!(!err.is_retryable());
+        match err.kind() {
+            ErrorKind::RemoteError { code, errno, .. } => {
+                assert_eq!(*code, 400);
+                assert_eq!(*errno, 107);
+            }
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
+
+    // From the "Test Vectors" section of
+    // https://github.com/mozilla/fxa-auth-server/wiki/onepw-protocol
+    const KEY_FETCH_TOKEN: &str =
+        "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f";
+    const KEYS_BUNDLE: &str = "ee5c58845c7c9412b11bbd20920c2fddd83c33c9cd2c2de2d66b222613364636\
+                               c2c0f8cfbb7c630472c0bd88451342c6c05b14ce342c5ad46ad89e84464c993c\
+                               3927d30230157d0817a077eef4b20d976f7a97363faf3f064c003ada7d01aa70";
+
+    fn key_request_key() -> Vec<u8> {
+        let token = hex::decode(KEY_FETCH_TOKEN).unwrap();
+        let key = derive_hkdf_sha256_key(&token, &HKDF_SALT, &kw("keyFetchToken"), KEY_LENGTH * 3)
+            .unwrap();
+        assert_eq!(
+            hex::encode(&key[0..KEY_LENGTH]),
+            "3d0a7c02a15a62a2882f76e39b6494b500c022a8816e048625a495718998ba60"
+        );
+        key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)].to_vec()
+    }
+
+    #[test]
+    fn test_decrypt_keys_bundle() {
+        let key_request_key = key_request_key();
+        assert_eq!(
+            hex::encode(&key_request_key),
+            "14f338a9e8c6324d9e102d4e6ee83b209796d5c74bb734a410e729e014a4a546"
+        );
+        let keys = decrypt_keys_bundle(&key_request_key, KEYS_BUNDLE).unwrap();
+        assert_eq!(
+            hex::encode(&*keys.ka),
+            "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f"
+        );
+        assert_eq!(
+            hex::encode(&*keys.wrap_kb),
+            "404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f"
+        );
+
+        let unwrap_b_key = [0x0Fu8; KEY_LENGTH];
+        let kb = keys.unwrap_kb(&unwrap_b_key).unwrap();
+        assert_eq!(
+            hex::encode(&*kb),
+            "4f4e4d4c4b4a494847464544434241405f5e5d5c5b5a59585756555453525150"
+        );
+        match keys.unwrap_kb(&[0u8; 16]).unwrap_err().kind() {
+            ErrorKind::BadKeyLength(..) => {}
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_keys_bundle_tampered() {
+        let key_request_key = key_request_key();
+        let mut data = hex::decode(KEYS_BUNDLE).unwrap();
+        data[0] ^= 1;
+        match decrypt_keys_bundle(&key_request_key, &hex::encode(&data))
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::HmacMismatch => {}
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+        match decrypt_keys_bundle(&key_request_key, &KEYS_BUNDLE[0..128])
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::BadKeyLength("bundle", 96, 64) => {}
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_secrets_redacted() {
+        let resp: LoginResponse = serde_json::from_str(
+            r#"{"uid":"abcd","sessionToken":"0123456789abcdef","verified":true}"#,
+        )
+        .unwrap();
+        assert_eq!(&*resp.session_token, "0123456789abcdef");
+        let debug = format!("{:?}", resp);
+        assert!(debug.contains(rc_crypto::sensitive::REDACTED));
+        assert!(!debug.contains("0123456789abcdef"));
+
+        let keys = KeysResponse {
+            ka: vec![0xABu8; 32].into(),
+            wrap_kb: vec![0xABu8; 32].into(),
+        };
+        let debug = format!("{:?}", keys);
+        assert!(debug.contains(rc_crypto::sensitive::REDACTED));
+        assert!(!debug.contains("171"));
+
+        let key = derive_key_from_session_token(&[0u8; 32]).unwrap();
+        assert_eq!(key.len(), KEY_LENGTH * 2);
+        assert_eq!(format!("{:?}", key), "SensitiveBytes([REDACTED])");
+    }
+
+    #[test]
+    fn test_unexpected_json_body_redacted() {
+        let body = r#"{"uid":"abcd","sessionToken":"0123456789abcdef"}"#;
+        let resp = account_status_response(200, "application/json", body);
+        let err = http_client::parse_json::<LoginResponse>(&resp).unwrap_err();
+        assert!(!err.to_string().contains("0123456789abcdef"));
+        match err.kind() {
+            ErrorKind::UnexpectedResponse { body_snippet, .. } => {
+                assert_eq!(body_snippet, rc_crypto::sensitive::REDACTED);
+            }
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
 }
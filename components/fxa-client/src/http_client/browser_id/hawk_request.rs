@@ -2,27 +2,35 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::errors::*;
+use crate::{
+    errors::*,
+    util::{self, Clock},
+};
 use hawk::{Credentials, Key, PayloadHasher, RequestBuilder, SHA256};
+use std::time::UNIX_EPOCH;
+use time::Timespec;
 use url::Url;
 use viaduct::{header_names, Method, Request};
 
 const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 10;
 
 pub struct HawkRequestBuilder<'a> {
     url: Url,
     method: Method,
     body: Option<String>,
     hkdf_sha256_key: &'a [u8],
+    clock: &'a dyn Clock,
 }
 
 impl<'a> HawkRequestBuilder<'a> {
-    pub fn new(method: Method, url: Url, hkdf_sha256_key: &'a [u8]) -> Self {
+    pub fn new(method: Method, url: Url, hkdf_sha256_key: &'a [u8], clock: &'a dyn Clock) -> Self {
         HawkRequestBuilder {
             url,
             method,
             body: None,
             hkdf_sha256_key,
+            clock,
         }
     }
 
@@ -49,7 +57,19 @@ impl<'a> HawkRequestBuilder<'a> {
             id: token_id,
             key: Key::new(hmac_key, &SHA256),
         };
-        let header = hawk_request.make_header(&hawk_credentials)?;
+        // Use the account's clock, instead of letting `hawk` read the system
+        // time.
+        let since_epoch = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| ErrorKind::IllegalState("Current date before Unix Epoch."))?;
+        let ts = Timespec::new(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_nanos() as i32,
+        );
+        let nonce = util::random_base64_url_string(NONCE_LENGTH)?;
+        let header = hawk_request.make_header_full(&hawk_credentials, ts, nonce)?;
         Ok(format!("Hawk {}", header))
     }
 
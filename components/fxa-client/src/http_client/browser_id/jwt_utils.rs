@@ -2,23 +2,23 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::{errors::*, http_client::browser_id::BrowserIDKeyPair};
+use crate::{
+    errors::*,
+    http_client::browser_id::BrowserIDKeyPair,
+    util::{self, Clock},
+};
 use serde_json::{self, json};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 const DEFAULT_ASSERTION_ISSUER: &str = "127.0.0.1";
 const DEFAULT_ASSERTION_DURATION: u64 = 60 * 60 * 1000;
 
 pub fn create_assertion(
+    clock: &dyn Clock,
     key_pair: &dyn BrowserIDKeyPair,
     certificate: &str,
     audience: &str,
 ) -> Result<String> {
-    let since_epoch = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Something is very wrong.");
-    let issued_at =
-        since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_nanos()) / 1_000_000;
+    let issued_at = util::now(clock);
     let expires_at = issued_at + DEFAULT_ASSERTION_DURATION;
     let issuer = DEFAULT_ASSERTION_ISSUER;
     create_assertion_full(
@@ -16,7 +16,12 @@ use crate::{
     oauth::{OAuthFlow, RefreshToken},
     scoped_keys::ScopedKey,
 };
-pub use crate::{config::Config, oauth::AccessTokenInfo, profile::Profile};
+pub use crate::{
+    config::Config,
+    oauth::AccessTokenInfo,
+    profile::Profile,
+    util::{Clock, SystemClock},
+};
 use lazy_static::lazy_static;
 use ring::rand::SystemRandom;
 use serde_derive::*;
@@ -59,6 +64,7 @@ type FxAClient = dyn http_client::FxAClient + Sync + Send;
 
 pub struct FirefoxAccount {
     client: Arc<FxAClient>,
+    clock: Arc<dyn Clock>,
     state: StateV2,
     access_token_cache: HashMap<String, AccessTokenInfo>,
     flow_store: HashMap<String, OAuthFlow>,
@@ -86,8 +92,10 @@ pub(crate) struct StateV2 {
 
 impl FirefoxAccount {
     fn from_state(state: StateV2) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
         Self {
-            client: Arc::new(http_client::Client::new()),
+            client: Arc::new(http_client::Client::new(clock.clone())),
+            clock,
             state,
             access_token_cache: HashMap::new(),
             flow_store: HashMap::new(),
@@ -123,6 +131,14 @@ impl FirefoxAccount {
         Self::with_config(config)
     }
 
+    /// Replaces the account's clock, which is the system clock by default.
+    /// This also replaces the HTTP client, so that it uses the new clock to
+    /// sign requests.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.client = Arc::new(http_client::Client::new(clock.clone()));
+        self.clock = clock;
+    }
+
     #[cfg(test)]
     #[allow(dead_code)] // FIXME
     pub(crate) fn set_client(&mut self, client: Arc<FxAClient>) {
@@ -266,7 +282,7 @@ mod tests {
                     amr_values: vec![],
                     two_factor_authentication: false,
                 },
-                cached_at: util::now(),
+                cached_at: util::now(self.clock.as_ref()),
                 etag: "fake etag".into(),
             });
         }
@@ -5,23 +5,30 @@
 use crate::{
     errors::*,
     http_client::{self, browser_id::rsa::RSABrowserIDKeyPair, *},
-    util::{now, Xorable},
+    util::{now, Clock},
     Config,
 };
+use rc_crypto::sensitive::{SensitiveBytes, REDACTED};
 use serde_derive::*;
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
 pub struct LoginStateMachine<'a> {
     config: &'a Config,
     client: Arc<dyn http_client::browser_id::FxABrowserIDClient>,
+    clock: &'a dyn Clock,
 }
 
 impl<'a> LoginStateMachine<'a> {
     pub fn new(
         config: &'a Config,
         client: Arc<dyn http_client::browser_id::FxABrowserIDClient>,
-    ) -> LoginStateMachine<'_> {
-        LoginStateMachine { config, client }
+        clock: &'a dyn Clock,
+    ) -> LoginStateMachine<'a> {
+        LoginStateMachine {
+            config,
+            client,
+            clock,
+        }
     }
 
     pub fn advance(&self, from: LoginState) -> Result<LoginState> {
@@ -39,10 +46,10 @@ impl<'a> LoginStateMachine<'a> {
     }
 
     fn advance_one(&self, from: LoginState) -> Result<LoginState> {
-        log::info!("advancing from state {:?}", from);
+        log::info!("advancing from state {}", from.label());
         match from {
             LoginState::Married(state) => {
-                let now = now();
+                let now = now(self.clock);
                 log::debug!("Checking key pair and certificate freshness.");
                 if now > state.token_keys_and_key_pair.key_pair_expires_at {
                     log::info!("Key pair has expired. Transitioning to CohabitingBeforeKeyPair.");
@@ -72,7 +79,7 @@ impl<'a> LoginStateMachine<'a> {
                 let new_state = CohabitingAfterKeyPairState {
                     token_and_keys: state,
                     key_pair,
-                    key_pair_expires_at: now() + 30 * 24 * 3600 * 1000,
+                    key_pair_expires_at: now(self.clock) + 30 * 24 * 3600 * 1000,
                 };
                 Ok(LoginState::CohabitingAfterKeyPair(new_state))
             }
@@ -89,7 +96,7 @@ impl<'a> LoginStateMachine<'a> {
                         let new_state = MarriedState {
                             token_keys_and_key_pair: state,
                             certificate: resp.certificate,
-                            certificate_expires_at: now() + 24 * 3600 * 1000,
+                            certificate_expires_at: now(self.clock) + 24 * 3600 * 1000,
                         };
                         Ok(LoginState::Married(new_state))
                     }
@@ -127,7 +134,7 @@ impl<'a> LoginStateMachine<'a> {
         let resp = self.client.keys(&self.config, &state.key_fetch_token);
         match resp {
             Ok(resp) => {
-                let kb = match resp.wrap_kb.xored_with(&state.unwrap_kb) {
+                let kb = match resp.unwrap_kb(&state.unwrap_kb) {
                     Ok(kb) => kb,
                     Err(_) => {
                         log::error!("Failed to unwrap keys response!  Transitioning to Separated.");
@@ -139,7 +146,7 @@ impl<'a> LoginStateMachine<'a> {
                 let xcs = browser_id::compute_client_state(&kb)?;
                 Ok(LoginState::CohabitingBeforeKeyPair(TokenAndKeysState {
                     base: state.base,
-                    session_token: state.session_token.to_vec(),
+                    session_token: state.session_token,
                     sync_key,
                     xcs,
                 }))
@@ -192,16 +199,21 @@ pub type SeparatedState = BaseState;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ReadyForKeysState {
     base: BaseState,
-    session_token: Vec<u8>,
-    key_fetch_token: Vec<u8>,
-    unwrap_kb: Vec<u8>,
+    #[serde(with = "sensitive_bytes")]
+    session_token: SensitiveBytes,
+    #[serde(with = "sensitive_bytes")]
+    key_fetch_token: SensitiveBytes,
+    #[serde(with = "sensitive_bytes")]
+    unwrap_kb: SensitiveBytes,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TokenAndKeysState {
     base: BaseState,
-    session_token: Vec<u8>,
-    sync_key: Vec<u8>,
+    #[serde(with = "sensitive_bytes")]
+    session_token: SensitiveBytes,
+    #[serde(with = "sensitive_bytes")]
+    sync_key: SensitiveBytes,
     xcs: String,
 }
 
@@ -212,12 +224,42 @@ pub struct TokenKeysAndKeyPairState {
     key_pair_expires_at: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct BaseState {
     uid: String,
     email: String,
 }
 
+impl fmt::Debug for BaseState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BaseState")
+            .field("uid", &self.uid)
+            .field("email", &REDACTED)
+            .finish()
+    }
+}
+
+/// Persists tokens and keys as byte arrays, like `Vec<u8>`, so that we can
+/// still read states that we saved before wrapping them.
+mod sensitive_bytes {
+    use rc_crypto::sensitive::SensitiveBytes;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(bytes: &SensitiveBytes, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (**bytes).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SensitiveBytes, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<u8>::deserialize(deserializer).map(Into::into)
+    }
+}
+
 impl ReadyForKeysState {
     pub fn new(
         uid: String,
@@ -228,9 +270,9 @@ impl ReadyForKeysState {
     ) -> ReadyForKeysState {
         ReadyForKeysState {
             base: BaseState { uid, email },
-            session_token,
-            key_fetch_token,
-            unwrap_kb,
+            session_token: session_token.into(),
+            key_fetch_token: key_fetch_token.into(),
+            unwrap_kb: unwrap_kb.into(),
         }
     }
 }
@@ -279,6 +321,21 @@ impl MarriedState {
 }
 
 impl LoginState {
+    /// The name of the state, for logging. The states themselves hold
+    /// tokens and keys, so we never log them with `{:?}`.
+    pub fn label(&self) -> &'static str {
+        use self::LoginState::*;
+        match self {
+            Married(_) => "Married",
+            CohabitingBeforeKeyPair(_) => "CohabitingBeforeKeyPair",
+            CohabitingAfterKeyPair(_) => "CohabitingAfterKeyPair",
+            EngagedBeforeVerified(_) => "EngagedBeforeVerified",
+            EngagedAfterVerified(_) => "EngagedAfterVerified",
+            Separated(_) => "Separated",
+            Unknown => "Unknown",
+        }
+    }
+
     pub fn into_separated(self) -> Self {
         use self::LoginState::*;
         match self {
@@ -292,3 +349,32 @@ impl LoginState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_secrets_redacted() {
+        let state = ReadyForKeysState::new(
+            "uid".to_string(),
+            "foo@example.com".to_string(),
+            vec![0xABu8; 32],
+            vec![0xCDu8; 32],
+            vec![0xEFu8; 32],
+        );
+        let debug = format!("{:?}", state);
+        assert!(debug.contains(REDACTED));
+        for secret in &["171", "205", "239", "foo@example.com"] {
+            assert!(!debug.contains(secret));
+        }
+
+        // Tokens are still persisted as byte arrays.
+        let value = serde_json::to_value(&state).unwrap();
+        assert_eq!(value["session_token"], json!(vec![0xABu8; 32]));
+        let state: ReadyForKeysState = serde_json::from_value(value).unwrap();
+        assert_eq!(state.session_token(), &[0xABu8; 32][..]);
+        assert_eq!(&*state.unwrap_kb, &[0xEFu8; 32][..]);
+    }
+}
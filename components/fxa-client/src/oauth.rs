@@ -9,11 +9,7 @@ use crate::{
 };
 use rc_crypto::digest;
 use serde_derive::*;
-use std::{
-    collections::HashSet,
-    iter::FromIterator,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{collections::HashSet, iter::FromIterator, time::UNIX_EPOCH};
 use url::Url;
 
 // If a cached token has less than `OAUTH_MIN_TIME_LEFT` seconds left to live,
@@ -32,7 +28,7 @@ impl FirefoxAccount {
             return Err(ErrorKind::MultipleScopesRequested.into());
         }
         if let Some(oauth_info) = self.access_token_cache.get(scope) {
-            if oauth_info.expires_at > util::now_secs() + OAUTH_MIN_TIME_LEFT {
+            if oauth_info.expires_at > util::now_secs(self.clock.as_ref()) + OAUTH_MIN_TIME_LEFT {
                 return Ok(oauth_info.clone());
             }
         }
@@ -66,7 +62,9 @@ impl FirefoxAccount {
                 }
             }
         };
-        let since_epoch = SystemTime::now()
+        let since_epoch = self
+            .clock
+            .now()
             .duration_since(UNIX_EPOCH)
             .map_err(|_| ErrorKind::IllegalState("Current date before Unix Epoch."))?;
         let expires_at = since_epoch.as_secs() + resp.expires_in;
@@ -262,6 +260,7 @@ pub struct AccessTokenInfo {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::FrozenClock;
     use std::borrow::Cow;
 
     #[test]
@@ -466,4 +465,34 @@ mod tests {
             },
         }
     }
+
+    #[test]
+    fn test_access_token_cache_expiry() {
+        let now_secs = 1_500_000_000;
+        let clock = FrozenClock::at(now_secs * 1000);
+        let mut fxa = FirefoxAccount::new(
+            "https://accounts.firefox.com",
+            "12345678",
+            "https://foo.bar",
+        );
+        fxa.set_clock(clock.clone());
+        fxa.access_token_cache.insert(
+            "profile".to_string(),
+            AccessTokenInfo {
+                scope: "profile".to_string(),
+                token: "toktok".to_string(),
+                key: None,
+                expires_at: now_secs + 2 * 60,
+            },
+        );
+        assert_eq!(fxa.get_access_token("profile").unwrap().token, "toktok");
+
+        // Tokens with less than a minute left are treated as expired, and we
+        // don't have a refresh token to fetch a new one.
+        clock.set((now_secs + 61) * 1000);
+        match fxa.get_access_token("profile").unwrap_err().kind() {
+            ErrorKind::NoCachedToken(scope) => assert_eq!(scope, "profile"),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
 }
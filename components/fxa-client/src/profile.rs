@@ -19,7 +19,9 @@ impl FirefoxAccount {
     pub fn get_profile(&mut self, ignore_cache: bool) -> Result<Profile> {
         let mut etag = None;
         if let Some(ref cached_profile) = self.profile_cache {
-            if !ignore_cache && util::now() < cached_profile.cached_at + PROFILE_FRESHNESS_THRESHOLD
+            if !ignore_cache
+                && util::now(self.clock.as_ref())
+                    < cached_profile.cached_at + PROFILE_FRESHNESS_THRESHOLD
             {
                 return Ok(cached_profile.response.clone());
             }
@@ -34,7 +36,7 @@ impl FirefoxAccount {
                 if let Some(etag) = response_and_etag.etag {
                     self.profile_cache = Some(CachedResponse {
                         response: response_and_etag.response.clone(),
-                        cached_at: util::now(),
+                        cached_at: util::now(self.clock.as_ref()),
                         etag,
                     });
                 }
@@ -46,7 +48,7 @@ impl FirefoxAccount {
                         // Update `cached_at` timestamp.
                         self.profile_cache.replace(CachedResponse {
                             response: cached_profile.response.clone(),
-                            cached_at: util::now(),
+                            cached_at: util::now(self.clock.as_ref()),
                             etag: cached_profile.etag.clone(),
                         });
                         Ok(cached_profile.response.clone())
@@ -4,18 +4,72 @@
 
 use crate::errors::*;
 use rc_crypto::rand;
+use std::fmt;
+#[cfg(test)]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+#[cfg(test)]
+use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// A source of the current time. Each `FirefoxAccount` has a clock, which it
+/// uses to expire cached tokens, profiles and certificates, and which its
+/// client uses to timestamp HAWK requests and assertions.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock. Accounts use this unless they're given another clock
+/// with `FirefoxAccount::set_clock`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that stays at a given time, in ms since the epoch, until it's
+/// moved.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct FrozenClock(AtomicU64);
+
+#[cfg(test)]
+impl FrozenClock {
+    pub fn at(now: u64) -> Arc<Self> {
+        Arc::new(FrozenClock(AtomicU64::new(now)))
+    }
+
+    /// Moves the frozen clock to a new time.
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FrozenClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.0.load(Ordering::SeqCst))
+    }
+}
+
 // Gets the unix epoch in ms.
-pub fn now() -> u64 {
-    let since_epoch = SystemTime::now()
+pub fn now(clock: &dyn Clock) -> u64 {
+    let since_epoch = clock
+        .now()
         .duration_since(UNIX_EPOCH)
         .expect("Something is very wrong.");
     since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_nanos()) / 1_000_000
 }
 
-pub fn now_secs() -> u64 {
-    let since_epoch = SystemTime::now()
+pub fn now_secs(clock: &dyn Clock) -> u64 {
+    let since_epoch = clock
+        .now()
         .duration_since(UNIX_EPOCH)
         .expect("Something is very wrong.");
     since_epoch.as_secs()
@@ -129,7 +129,8 @@ pub extern "C" fn sync15_passwords_sync(
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
             &mut sync_ping,
-        )
+        )?;
+        Ok(())
     })
 }
 
@@ -6,6 +6,7 @@ use crate::error::*;
 use crate::login::Login;
 use std::cell::Cell;
 use std::path::Path;
+use std::time::Duration;
 use sync15::{
     sync_multiple, telemetry, KeyBundle, MemoryCachedState, StoreSyncAssociation,
     Sync15StorageClientInit,
@@ -91,13 +92,14 @@ impl PasswordEngine {
         self.db.new_interrupt_handle()
     }
 
-    /// A convenience wrapper around sync_multiple.
+    /// A convenience wrapper around sync_multiple. Returns the recommended
+    /// time to wait before the next sync, if any.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
         sync_ping: &mut telemetry::SyncTelemetryPing,
-    ) -> Result<()> {
+    ) -> Result<Option<Duration>> {
         // migrate our V1 state - this needn't live for long.
         self.db.migrate_global_state()?;
 
@@ -117,15 +119,7 @@ impl PasswordEngine {
         // We always update the state - sync_multiple does the right thing
         // if it needs to be dropped (ie, they will be None or contain Nones etc)
         self.db.set_global_state(&disk_cached_state)?;
-        let failures = result?;
-        if failures.is_empty() {
-            Ok(())
-        } else {
-            assert_eq!(failures.len(), 1);
-            let (name, err) = failures.into_iter().next().unwrap();
-            assert_eq!(name, "passwords");
-            Err(err.into())
-        }
+        Ok(result?.into_single_store_result("passwords")?)
     }
 }
 
@@ -47,6 +47,9 @@ fn get_code(err: &Error) -> ErrorCode {
                 Sync15ErrorKind::TokenserverHttpError(401) | Sync15ErrorKind::BadKeyLength(..) => {
                     ErrorCode::new(error_codes::AUTH_INVALID)
                 }
+                Sync15ErrorKind::TokenserverError(e) if e.is_auth_error() => {
+                    ErrorCode::new(error_codes::AUTH_INVALID)
+                }
                 Sync15ErrorKind::RequestError(_) => ErrorCode::new(error_codes::NETWORK),
                 _ => ErrorCode::new(error_codes::UNEXPECTED),
             }
@@ -158,6 +158,7 @@ internal interface LibPlacesFFI : Library {
         startDate: Long,
         endDate: Long,
         excludeTypes: Int,
+        includeHidden: Byte,
         error: RustError.ByReference
     ): RustBuffer.ByValue
 
@@ -166,6 +167,7 @@ internal interface LibPlacesFFI : Library {
         offset: Long,
         count: Long,
         excludeTypes: Int,
+        includeHidden: Byte,
         error: RustError.ByReference
     ): RustBuffer.ByValue
 
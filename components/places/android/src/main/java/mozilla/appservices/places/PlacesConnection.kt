@@ -244,10 +244,16 @@ open class PlacesReaderConnection internal constructor(connHandle: Long) :
         return result
     }
 
-    override fun getVisitInfos(start: Long, end: Long, excludeTypes: List<VisitType>): List<VisitInfo> {
+    override fun getVisitInfos(
+        start: Long,
+        end: Long,
+        excludeTypes: List<VisitType>,
+        includeHidden: Boolean
+    ): List<VisitInfo> {
         val infoBuffer = rustCall { error ->
+            val incHiddenArg: Byte = if (includeHidden) { 1 } else { 0 }
             LibPlacesFFI.INSTANCE.places_get_visit_infos(
-                    this.handle.get(), start, end, visitTransitionSet(excludeTypes), error)
+                    this.handle.get(), start, end, visitTransitionSet(excludeTypes), incHiddenArg, error)
         }
         try {
             val infos = MsgTypes.HistoryVisitInfos.parseFrom(infoBuffer.asCodedInputStream()!!)
@@ -257,10 +263,16 @@ open class PlacesReaderConnection internal constructor(connHandle: Long) :
         }
     }
 
-    override fun getVisitPage(offset: Long, count: Long, excludeTypes: List<VisitType>): List<VisitInfo> {
+    override fun getVisitPage(
+        offset: Long,
+        count: Long,
+        excludeTypes: List<VisitType>,
+        includeHidden: Boolean
+    ): List<VisitInfo> {
         val infoBuffer = rustCall { error ->
+            val incHiddenArg: Byte = if (includeHidden) { 1 } else { 0 }
             LibPlacesFFI.INSTANCE.places_get_visit_page(
-                    this.handle.get(), offset, count, visitTransitionSet(excludeTypes), error)
+                    this.handle.get(), offset, count, visitTransitionSet(excludeTypes), incHiddenArg, error)
         }
         try {
             val infos = MsgTypes.HistoryVisitInfos.parseFrom(infoBuffer.asCodedInputStream()!!)
@@ -583,11 +595,15 @@ interface ReadableHistoryConnection : InterruptibleConnection {
      *
      * @param start The (inclusive) start time to bound the query.
      * @param end The (inclusive) end time to bound the query.
+     * @param excludeTypes List of visit types to exclude.
+     * @param includeHidden Whether to include visits to hidden pages, like
+     * embedded frames and redirect sources.
      */
     fun getVisitInfos(
         start: Long,
         end: Long = Long.MAX_VALUE,
-        excludeTypes: List<VisitType> = listOf()
+        excludeTypes: List<VisitType> = listOf(),
+        includeHidden: Boolean = true
     ): List<VisitInfo>
 
     /**
@@ -601,8 +617,14 @@ interface ReadableHistoryConnection : InterruptibleConnection {
      * @param offset The offset where the page begins.
      * @param count The number of items to return in the page.
      * @param excludeTypes List of visit types to exclude.
+     * @param includeHidden Whether to include visits to hidden pages.
      */
-    fun getVisitPage(offset: Long, count: Long, excludeTypes: List<VisitType> = listOf()): List<VisitInfo>
+    fun getVisitPage(
+        offset: Long,
+        count: Long,
+        excludeTypes: List<VisitType> = listOf(),
+        includeHidden: Boolean = true
+    ): List<VisitInfo>
 
     /**
      * Get the number of history visits.
@@ -6,7 +6,7 @@ use places::api::{
     matcher::{match_url, search_frecent, SearchParams},
     places_api::ConnectionType,
 };
-use places::PlacesDb;
+use places::{BusyRetryPolicy, PlacesDb};
 use sql_support::ConnExt;
 use std::rc::Rc;
 use tempdir::TempDir;
@@ -28,7 +28,11 @@ fn init_db(db: &mut PlacesDb) -> places::Result<()> {
         for i in 0..20 {
             let obs = places::VisitObservation::new(url.clone())
                 .with_title(entry.title.clone())
-                .with_is_remote(i < 10)
+                .with_source(if i < 10 {
+                    places::ObservationSource::Sync
+                } else {
+                    places::ObservationSource::Local
+                })
                 .with_visit_type(places::VisitTransition::Link)
                 .with_at(places::Timestamp(now.0 - day_ms * (1 + i)));
             places::storage::history::apply_observation_direct(&db, obs)?;
@@ -54,6 +58,7 @@ impl TestDb {
             ConnectionType::ReadWrite,
             0,
             Arc::new(Mutex::new(())),
+            BusyRetryPolicy::default(),
         )
         .unwrap();
         println!("Populating test database...");
@@ -7,7 +7,7 @@
 
 use clap::value_t;
 use failure::bail;
-use places::{PlacesDb, VisitObservation, VisitTransition};
+use places::{ObservationSource, PlacesDb, VisitObservation, VisitTransition};
 use rusqlite::NO_PARAMS;
 use serde_derive::*;
 use sql_support::ConnExt;
@@ -44,7 +44,11 @@ impl SerializedObservation {
         let mut obs = VisitObservation::new(url)
             .with_title(self.title)
             .with_is_error(self.error)
-            .with_is_remote(self.remote)
+            .with_source(if self.remote {
+                ObservationSource::Sync
+            } else {
+                ObservationSource::Local
+            })
             .with_is_redirect_source(self.is_redirect_source)
             .with_referrer(referrer);
         if let Some(visit_type) = self.visit_type.and_then(VisitTransition::from_primitive) {
@@ -66,7 +70,7 @@ impl From<VisitObservation> for SerializedObservation {
             at: visit.at.map(Into::into),
             error: visit.is_error.unwrap_or(false),
             is_redirect_source: visit.is_redirect_source.unwrap_or(false),
-            remote: visit.is_remote.unwrap_or(false),
+            remote: visit.source() == ObservationSource::Sync,
             referrer: visit.referrer,
         }
     }
@@ -130,7 +134,11 @@ impl LegacyPlace {
                 )
                 .with_at(places::Timestamp((v.date / 1000) as u64))
                 .with_title(self.title.clone())
-                .with_is_remote(rand::random::<f64>() < options.remote_probability);
+                .with_source(if rand::random::<f64>() < options.remote_probability {
+                    ObservationSource::Sync
+                } else {
+                    ObservationSource::Local
+                });
             places::storage::history::apply_observation_direct(db, obs)?;
         }
         Ok(())
@@ -304,6 +304,7 @@ pub extern "C" fn places_get_visit_infos(
     start_date: i64,
     end_date: i64,
     exclude_types: i32,
+    include_hidden: u8,
     error: &mut ExternError,
 ) -> ByteBuffer {
     log::debug!("places_get_visit_infos");
@@ -314,6 +315,7 @@ pub extern "C" fn places_get_visit_infos(
             places::Timestamp(end_date.max(0) as u64),
             VisitTransitionSet::from_u16(exclude_types as u16)
                 .expect("Bug: Invalid VisitTransitionSet"),
+            include_hidden != 0,
         )?)
     })
 }
@@ -342,6 +344,7 @@ pub extern "C" fn places_get_visit_page(
     offset: i64,
     count: i64,
     exclude_types: i32,
+    include_hidden: u8,
     error: &mut ExternError,
 ) -> ByteBuffer {
     log::debug!("places_get_visit_page");
@@ -354,6 +357,7 @@ pub extern "C" fn places_get_visit_page(
             // if this expect fires.
             VisitTransitionSet::from_u16(exclude_types as u16)
                 .expect("Bug: Invalid VisitTransitionSet"),
+            include_hidden != 0,
         )
     })
 }
@@ -369,7 +373,7 @@ pub extern "C" fn sync15_history_sync(
 ) {
     log::debug!("sync15_history_sync");
     APIS.call_with_result(error, handle, |api| -> places::Result<_> {
-        // Note that api.sync returns a SyncPing which we drop on the floor.
+        // Note that api.sync returns a SyncOutcome which we drop on the floor.
         api.sync_history(
             &sync15::Sync15StorageClientInit {
                 key_id: key_id.into_string(),
@@ -393,7 +397,7 @@ pub extern "C" fn sync15_bookmarks_sync(
 ) {
     log::debug!("sync15_bookmarks_sync");
     APIS.call_with_result(error, handle, |api| -> places::Result<_> {
-        // Note that api.sync returns a SyncPing which we drop on the floor.
+        // Note that api.sync returns a SyncOutcome which we drop on the floor.
         api.sync_bookmarks(
             &sync15::Sync15StorageClientInit {
                 key_id: key_id.into_string(),
@@ -461,8 +465,8 @@ pub unsafe extern "C" fn bookmarks_insert(
         let buffer = get_buffer(data, len);
         let bookmark: BookmarkNode = prost::Message::decode(buffer)?;
         let insertable = bookmark.into_insertable()?;
-        let guid = bookmarks::insert_bookmark(conn, &insertable)?;
-        Ok(guid.0)
+        let inserted = bookmarks::insert_bookmark(conn, &insertable)?;
+        Ok(inserted.guid.0)
     })
 }
 
@@ -488,8 +492,14 @@ pub extern "C" fn bookmarks_delete(handle: u64, id: FfiStr<'_>, error: &mut Exte
     log::debug!("bookmarks_delete");
     CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
         let guid = SyncGuid(id.into_string());
-        let did_delete = bookmarks::delete_bookmark(conn, &guid)?;
-        Ok(did_delete)
+        // Consumers expect roots to fail with `CannotUpdateRoot`, and to know
+        // whether the bookmark existed.
+        if let Some(root) = guid.as_root() {
+            return Err(InvalidPlaceInfo::CannotUpdateRoot(root).into());
+        }
+        let existed = bookmarks::public_node::fetch_bookmark(conn, &guid, false)?.is_some();
+        bookmarks::delete_bookmark(conn, &guid)?;
+        Ok(existed)
     })
 }
 
@@ -0,0 +1,155 @@
+-- A miniature Firefox for iOS `browser.db`, with just the tables and
+-- columns that the iOS importer reads.
+
+CREATE TABLE history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guid TEXT NOT NULL UNIQUE,
+    url TEXT UNIQUE,
+    title TEXT NOT NULL,
+    server_modified INTEGER,
+    local_modified INTEGER,
+    is_deleted TINYINT NOT NULL DEFAULT 0,
+    should_upload TINYINT NOT NULL DEFAULT 0,
+    domain_id INTEGER
+);
+
+CREATE TABLE visits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    siteID INTEGER NOT NULL REFERENCES history(id) ON DELETE CASCADE,
+    date REAL NOT NULL,
+    type INTEGER NOT NULL,
+    is_local TINYINT NOT NULL DEFAULT 1,
+    UNIQUE (siteID, date, type)
+);
+
+CREATE TABLE bookmarksMirror (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guid TEXT NOT NULL UNIQUE,
+    type TINYINT NOT NULL,
+    date_added INTEGER,
+    parentid TEXT,
+    parentName TEXT,
+    title TEXT,
+    bmkUri TEXT,
+    is_deleted TINYINT NOT NULL DEFAULT 0,
+    server_modified INTEGER NOT NULL,
+    is_overridden TINYINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE bookmarksLocal (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guid TEXT NOT NULL UNIQUE,
+    type TINYINT NOT NULL,
+    date_added INTEGER,
+    parentid TEXT,
+    parentName TEXT,
+    title TEXT,
+    bmkUri TEXT,
+    is_deleted TINYINT NOT NULL DEFAULT 0,
+    local_modified INTEGER,
+    sync_status TINYINT NOT NULL
+);
+
+CREATE TABLE bookmarksBuffer (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guid TEXT NOT NULL UNIQUE,
+    type TINYINT NOT NULL,
+    date_added INTEGER,
+    parentid TEXT,
+    parentName TEXT,
+    title TEXT,
+    bmkUri TEXT,
+    is_deleted TINYINT NOT NULL DEFAULT 0,
+    server_modified INTEGER NOT NULL
+);
+
+CREATE TABLE bookmarksMirrorStructure (
+    parent TEXT NOT NULL,
+    child TEXT NOT NULL,
+    idx INTEGER NOT NULL
+);
+
+CREATE TABLE bookmarksLocalStructure (
+    parent TEXT NOT NULL,
+    child TEXT NOT NULL,
+    idx INTEGER NOT NULL
+);
+
+CREATE TABLE bookmarksBufferStructure (
+    parent TEXT NOT NULL,
+    child TEXT NOT NULL,
+    idx INTEGER NOT NULL
+);
+
+INSERT INTO bookmarksMirror(guid, type, date_added, parentid, title, bmkUri, is_deleted, server_modified) VALUES
+    ('root________', 2, 1500000000000, 'root________', '', NULL, 0, 1),
+    ('menu________', 2, 1500000000000, 'root________', 'Bookmarks Menu', NULL, 0, 1),
+    ('toolbar_____', 2, 1500000000000, 'root________', 'Bookmarks Toolbar', NULL, 0, 1),
+    ('unfiled_____', 2, 1500000000000, 'root________', 'Unsorted Bookmarks', NULL, 0, 1),
+    ('mobile______', 2, 1500000000000, 'root________', 'Mobile Bookmarks', NULL, 0, 1),
+    ('folderAAAAAA', 2, 1500000000000, 'menu________', 'A', NULL, 0, 1),
+    ('bookmarkBBBB', 1, 1500000000000, 'folderAAAAAA', 'B', 'https://b.example.com/', 0, 1),
+    ('bookmarkCCCC', 1, 1500000000000, 'folderAAAAAA', 'C', 'https://c.example.com/', 0, 1),
+    ('separatorDDD', 3, 1500000000000, 'menu________', NULL, NULL, 0, 1),
+    ('queryEEEEEEE', 6, 1500000000000, 'menu________', 'Tagged', 'place:tag=foo', 0, 1),
+    ('bookmarkINVL', 1, 1500000000000, 'menu________', 'Invalid', 'not a url', 0, 1),
+    ('livemarkLLLL', 5, 1500000000000, 'menu________', 'Livemark', NULL, 0, 1),
+    ('bookmarkTAKN', 1, 1500000000000, 'toolbar_____', 'Taken', 'https://taken.example.com/', 0, 1),
+    ('bookmarkMMMM', 1, 1500000000000, 'mobile', 'Mobile', 'https://mobile.example.com/', 0, 1),
+    -- Deleted on the server, so it shouldn't be imported even though it's
+    -- still in the structure table.
+    ('bookmarkGONE', 1, 1500000000000, 'unfiled_____', 'Gone', 'https://gone.example.com/', 1, 1);
+
+UPDATE bookmarksMirror SET is_overridden = 1
+WHERE guid IN ('folderAAAAAA', 'bookmarkCCCC');
+
+INSERT INTO bookmarksMirrorStructure(parent, child, idx) VALUES
+    ('root________', 'menu________', 0),
+    ('root________', 'toolbar_____', 1),
+    ('root________', 'unfiled_____', 2),
+    ('root________', 'mobile______', 3),
+    ('menu________', 'folderAAAAAA', 0),
+    ('menu________', 'separatorDDD', 1),
+    ('menu________', 'queryEEEEEEE', 2),
+    ('menu________', 'bookmarkINVL', 3),
+    ('menu________', 'livemarkLLLL', 4),
+    ('folderAAAAAA', 'bookmarkBBBB', 0),
+    ('folderAAAAAA', 'bookmarkCCCC', 1),
+    ('toolbar_____', 'bookmarkTAKN', 0),
+    ('unfiled_____', 'bookmarkGONE', 0),
+    -- Structure copied from Sync records uses record IDs for roots.
+    ('mobile', 'bookmarkMMMM', 0);
+
+-- The folder was renamed, C was deleted, and a new bookmark was added.
+INSERT INTO bookmarksLocal(guid, type, date_added, parentid, title, bmkUri, is_deleted, local_modified, sync_status) VALUES
+    ('folderAAAAAA', 2, 1500000000000, 'menu________', 'Renamed locally', NULL, 0, 1, 1),
+    ('bookmarkCCCC', 1, 1500000000000, 'folderAAAAAA', NULL, NULL, 1, 1, 1),
+    ('bookmarkNEWW', 1, 1550000000000, 'folderAAAAAA', 'New', 'https://new.example.com/', 0, 1, 0);
+
+INSERT INTO bookmarksLocalStructure(parent, child, idx) VALUES
+    ('folderAAAAAA', 'bookmarkBBBB', 0),
+    ('folderAAAAAA', 'bookmarkNEWW', 1);
+
+-- Incoming, but not yet merged, so it shouldn't be imported.
+INSERT INTO bookmarksBuffer(guid, type, date_added, parentid, title, bmkUri, server_modified) VALUES
+    ('bookmarkBUFF', 1, 1500000000000, 'unfiled_____', 'Buffered', 'https://buffered.example.com/', 1);
+
+INSERT INTO bookmarksBufferStructure(parent, child, idx) VALUES
+    ('unfiled_____', 'bookmarkBUFF', 0);
+
+INSERT INTO history(id, guid, url, title, is_deleted) VALUES
+    (1, 'historyAAAAA', 'https://a.example.com/', 'A', 0),
+    (2, 'historyBBBBB', 'https://b.example.com/', 'B', 0),
+    (3, 'historyINVLD', 'not a url', 'Invalid', 0),
+    (4, 'historyDELTD', 'https://deleted.example.com/', 'Deleted', 1);
+
+-- Visit dates are in microseconds.
+INSERT INTO visits(siteID, date, type, is_local) VALUES
+    (1, 1550000000000000, 1, 1),
+    (1, 1550000100000000, 2, 0),
+    -- Invalid date.
+    (2, 0, 1, 1),
+    -- In the future.
+    (2, 1600000000000000, 1, 1),
+    (3, 1550000000000000, 1, 1),
+    (4, 1550000000000000, 1, 1);
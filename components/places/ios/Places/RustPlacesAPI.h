@@ -120,6 +120,7 @@ PlacesRustBuffer places_get_visit_infos(PlacesConnectionHandle handle,
                                         int64_t start_date,
                                         int64_t end_date,
                                         int32_t exclude_types,
+                                        uint8_t include_hidden,
                                         PlacesRustError *_Nonnull out_err);
 
 void sync15_history_sync(PlacesConnectionHandle handle,
@@ -74,6 +74,19 @@ CREATE TABLE IF NOT EXISTS moz_historyvisits (
 
 CREATE INDEX IF NOT EXISTS placedateindex ON moz_historyvisits(place_id, visit_date);
 CREATE INDEX IF NOT EXISTS fromindex ON moz_historyvisits(from_visit);
+
+-- Search terms for visits that came from a search. We store these separately
+-- from the visits, since most visits don't have one. Note that Desktop has an
+-- unrelated `moz_places_metadata` table, which we avoid for clarity.
+CREATE TABLE IF NOT EXISTS moz_historyvisit_search_terms (
+    visit_id INTEGER PRIMARY KEY,
+    place_id INTEGER NOT NULL,
+    search_term TEXT NOT NULL,
+
+    FOREIGN KEY(visit_id) REFERENCES moz_historyvisits(id) ON DELETE CASCADE,
+    FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS visitsearchtermindex ON moz_historyvisit_search_terms(search_term);
 CREATE INDEX IF NOT EXISTS dateindex ON moz_historyvisits(visit_date);
 CREATE INDEX IF NOT EXISTS islocalindex ON moz_historyvisits(is_local);
 
@@ -103,12 +116,18 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks (
     parent INTEGER,
     position INTEGER NOT NULL,
     title TEXT, -- a'la bug 1356159, NULL is special here - it means 'not edited'
+    -- Like all other dates in this schema, these are in milliseconds. This
+    -- differs from Desktop, which uses microseconds.
     dateAdded INTEGER NOT NULL DEFAULT 0,
     lastModified INTEGER NOT NULL DEFAULT 0,
     guid TEXT NOT NULL UNIQUE CHECK(length(guid) == 12),
 
     syncStatus INTEGER NOT NULL DEFAULT 0,
     syncChangeCounter INTEGER NOT NULL DEFAULT 1,
+    -- The value of the bookmark change counter in `moz_meta` when this item,
+    -- or its list of children, last changed. Maintained by triggers, and used
+    -- for folder change tokens.
+    changeSeq INTEGER NOT NULL DEFAULT 0,
 
     -- bookmarks must have a fk to a URL, other types must not.
     CHECK((type == 1 AND fk IS NOT NULL) OR (type > 1 AND fk IS NULL))
@@ -119,11 +138,14 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks (
     FOREIGN KEY(parent) REFERENCES moz_bookmarks(id) ON DELETE CASCADE
 );
 
--- CREATE INDEX IF NOT EXISTS itemindex ON moz_bookmarks(fk, type);
--- CREATE INDEX IF NOT EXISTS parentindex ON moz_bookmarks(parent, position);
+CREATE INDEX IF NOT EXISTS itemindex ON moz_bookmarks(fk, type);
+CREATE INDEX IF NOT EXISTS parentindex ON moz_bookmarks(parent, position);
 CREATE INDEX IF NOT EXISTS itemlastmodifiedindex ON moz_bookmarks(fk, lastModified);
 -- CREATE INDEX IF NOT EXISTS dateaddedindex ON moz_bookmarks(dateAdded);
 CREATE UNIQUE INDEX IF NOT EXISTS guid_uniqueindex ON moz_bookmarks(guid);
+-- For finding GUIDs that only differ by case, which some servers and older
+-- clients treat as the same record.
+CREATE INDEX IF NOT EXISTS guid_nocaseindex ON moz_bookmarks(guid COLLATE NOCASE);
 
 
 CREATE TABLE IF NOT EXISTS moz_bookmarks_deleted (
@@ -131,7 +153,37 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks_deleted (
     dateRemoved INTEGER NOT NULL
 ) WITHOUT ROWID;
 
--- Note: desktop has/had a 'keywords' table, but we intentionally do not.
+-- `place:` query bookmarks that the user hid on this device. This is
+-- local-only, so hiding a query doesn't change or delete it on the server.
+CREATE TABLE IF NOT EXISTS moz_bookmarks_hidden_queries (
+    bookmarkId INTEGER PRIMARY KEY REFERENCES moz_bookmarks(id)
+                                   ON DELETE CASCADE
+);
+
+-- A log of destructive operations, like deleting history or bookmarks. We
+-- only store counts, never what was deleted. See `storage::deletion_log`.
+CREATE TABLE IF NOT EXISTS moz_deletion_log (
+    id INTEGER PRIMARY KEY,
+    op TEXT NOT NULL,
+    ts INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    initiator TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS deletionlogtsindex ON moz_deletion_log(ts);
+
+-- Keywords are shortcuts for URLs: typing a keyword into the address bar
+-- loads its URL. Each keyword maps to one URL, and we only set one keyword
+-- per URL, since that's all a synced bookmark record can hold.
+CREATE TABLE IF NOT EXISTS moz_keywords(
+    id INTEGER PRIMARY KEY,
+    keyword TEXT UNIQUE NOT NULL,
+    place_id INTEGER NOT NULL REFERENCES moz_places(id) ON DELETE CASCADE,
+    -- When the keyword was set locally, or the server modified time of the
+    -- synced bookmark that set it. We use this to resolve conflicts when
+    -- two devices use the same keyword for different URLs.
+    lastModified INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS keywordplaceindex ON moz_keywords(place_id);
 
 
 CREATE TABLE IF NOT EXISTS moz_origins (
@@ -194,7 +246,24 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks_synced(
     loadInSidebar BOOLEAN,
     smartBookmarkName TEXT,
     feedURL TEXT,
-    siteURL TEXT
+    siteURL TEXT,
+    -- The `pos` from separator records. We prefer the position from the
+    -- parent's `children`, but fall back to this for deduping separators
+    -- if we haven't seen their parent yet.
+    position INTEGER,
+    -- The `sortindex` from the BSO. Desktop sets this from frecency for
+    -- bookmarks, so we use it to rank bookmarks without local visits.
+    serverSortindex INTEGER,
+    -- The `hasDupe` flag from the record. Older Desktop clients set this on
+    -- items that they've already deduped, and we set it on everything we
+    -- upload.
+    hasDupe BOOLEAN NOT NULL DEFAULT 0,
+    -- The `parentName` from the record. This is only informational, but
+    -- it's the only hint we have about where an orphaned item came from.
+    parentTitle TEXT,
+    -- A JSON object with fields from the record that we don't understand.
+    -- We include these when we reupload the item.
+    unknownFields TEXT
 );
 
 -- This table holds parent-child relationships and positions for synced items,
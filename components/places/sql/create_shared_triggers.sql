@@ -7,8 +7,13 @@
 CREATE TEMP TRIGGER moz_places_afterinsert_trigger
 AFTER INSERT ON moz_places FOR EACH ROW
 BEGIN
+    -- We insert new origins with a frecency of 0, since
+    -- `moz_updateoriginsinsert_afterdelete_trigger` adds the place's
+    -- frecency once we flush `moz_updateoriginsinsert_temp`. Using
+    -- `NEW.frecency` here would count it twice, or, for new places with a
+    -- frecency of -1, leave the origin's frecency off by one.
     INSERT OR IGNORE INTO moz_origins(prefix, host, rev_host, frecency)
-    VALUES(get_prefix(NEW.url), get_host_and_port(NEW.url), reverse_host(get_host_and_port(NEW.url)), NEW.frecency);
+    VALUES(get_prefix(NEW.url), get_host_and_port(NEW.url), reverse_host(get_host_and_port(NEW.url)), 0);
 
     -- This is temporary.
     UPDATE moz_places SET
@@ -60,6 +65,11 @@ BEGIN
                                          WHERE place_id = OLD.place_id AND NOT(is_local)
                                          ORDER BY visit_date DESC LIMIT 1), 0)
     WHERE id = OLD.place_id;
+    -- Visits that came from this one, like redirect targets, now have an
+    -- unknown referrer.
+    UPDATE moz_historyvisits SET
+        from_visit = NULL
+    WHERE from_visit = OLD.id;
 END;
 
 CREATE TEMP TRIGGER moz_bookmarks_foreign_count_afterdelete_trigger
@@ -68,6 +78,13 @@ BEGIN
     UPDATE moz_places
     SET foreign_count = foreign_count - 1
     WHERE id = OLD.fk;
+
+    -- Like desktop, remove the keyword for a URL when its last bookmark is
+    -- removed.
+    DELETE FROM moz_keywords
+    WHERE place_id = OLD.fk AND
+          NOT EXISTS(SELECT 1 FROM moz_bookmarks
+                     WHERE fk = OLD.fk);
 END;
 
 -- Note that the desktop versions of the triggers below call a note_sync_change()
@@ -93,6 +110,54 @@ BEGIN
         WHERE OLD.fk <> NEW.fk AND id = OLD.fk;
 END;
 
+-- These triggers stamp changed bookmarks with the next value of a counter in
+-- moz_meta, which we use to build folder change tokens. Unlike `lastModified`,
+-- the counter only goes up, and it's bumped for local and synced changes
+-- alike. Removing an item, or moving it to a different folder, stamps its old
+-- parent, so that the change is visible from the old parent's subtree.
+CREATE TEMP TRIGGER moz_bookmarks_changeseq_afterinsert_trigger
+AFTER INSERT ON moz_bookmarks FOR EACH ROW
+BEGIN
+    INSERT OR REPLACE INTO moz_meta(key, value)
+    VALUES('{bookmark_change_counter}',
+           IFNULL((SELECT value FROM moz_meta
+                   WHERE key = '{bookmark_change_counter}'), 0) + 1);
+    UPDATE moz_bookmarks SET
+        changeSeq = (SELECT value FROM moz_meta
+                     WHERE key = '{bookmark_change_counter}')
+    WHERE id = NEW.id;
+END;
+
+-- Changes to the Sync change counter and status aren't changes to the
+-- bookmark, so we leave those out.
+CREATE TEMP TRIGGER moz_bookmarks_changeseq_afterupdate_trigger
+AFTER UPDATE OF fk, type, parent, position, title, dateAdded, lastModified,
+                guid ON moz_bookmarks FOR EACH ROW
+BEGIN
+    INSERT OR REPLACE INTO moz_meta(key, value)
+    VALUES('{bookmark_change_counter}',
+           IFNULL((SELECT value FROM moz_meta
+                   WHERE key = '{bookmark_change_counter}'), 0) + 1);
+    UPDATE moz_bookmarks SET
+        changeSeq = (SELECT value FROM moz_meta
+                     WHERE key = '{bookmark_change_counter}')
+    WHERE id = NEW.id OR
+          (OLD.parent <> NEW.parent AND id = OLD.parent);
+END;
+
+CREATE TEMP TRIGGER moz_bookmarks_changeseq_afterdelete_trigger
+AFTER DELETE ON moz_bookmarks FOR EACH ROW
+BEGIN
+    INSERT OR REPLACE INTO moz_meta(key, value)
+    VALUES('{bookmark_change_counter}',
+           IFNULL((SELECT value FROM moz_meta
+                   WHERE key = '{bookmark_change_counter}'), 0) + 1);
+    UPDATE moz_bookmarks SET
+        changeSeq = (SELECT value FROM moz_meta
+                     WHERE key = '{bookmark_change_counter}')
+    WHERE id = OLD.parent;
+END;
+
 -- The next several triggers are a workaround for the lack of FOR EACH STATEMENT
 -- in Sqlite, (see bug 871908).
 --
@@ -306,3 +371,33 @@ BEGIN
         foreign_count = foreign_count - 1
     WHERE id = OLD.place_id;
 END;
+
+-- These triggers adjust the foreign count for URLs with keywords, so that
+-- they won't be expired or automatically removed.
+CREATE TEMP TRIGGER moz_keywords_foreign_count_afterinsert_trigger
+AFTER INSERT ON moz_keywords
+BEGIN
+    UPDATE moz_places SET
+        foreign_count = foreign_count + 1
+    WHERE id = NEW.place_id;
+END;
+
+CREATE TEMP TRIGGER moz_keywords_foreign_count_afterupdate_trigger
+AFTER UPDATE OF place_id ON moz_keywords
+BEGIN
+    UPDATE moz_places SET
+        foreign_count = foreign_count + 1
+    WHERE id = NEW.place_id;
+
+    UPDATE moz_places SET
+        foreign_count = foreign_count - 1
+    WHERE id = OLD.place_id;
+END;
+
+CREATE TEMP TRIGGER moz_keywords_foreign_count_afterdelete_trigger
+AFTER DELETE ON moz_keywords
+BEGIN
+    UPDATE moz_places SET
+        foreign_count = foreign_count - 1
+    WHERE id = OLD.place_id;
+END;
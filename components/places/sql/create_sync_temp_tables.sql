@@ -38,7 +38,7 @@ CREATE TEMP VIEW itemsToMerge(localId, localGuid, remoteId, remoteGuid,
                               newType,
                               newDateAdded,
                               newTitle, oldPlaceId, newPlaceId,
-                              newKeyword) AS
+                              newKeyword, newServerModified) AS
 SELECT b.id, b.guid, v.id, v.guid,
        r.mergedGuid, r.useRemote, r.shouldUpload, r.level,
        (CASE WHEN v.kind IN (
@@ -53,8 +53,14 @@ SELECT b.id, b.guid, v.id, v.guid,
              END),
        (CASE WHEN b.dateAdded < v.dateAdded THEN b.dateAdded
              ELSE v.dateAdded END),
-       v.title, b.fk, v.placeId,
-       v.keyword
+       /* Root titles are local-only, so we never take the remote title. */
+       (CASE WHEN r.mergedGuid IN ("menu________", "toolbar_____",
+                                   "unfiled_____", "mobile______")
+             THEN b.title
+             ELSE v.title END),
+       b.fk, v.placeId,
+       /* Incoming keywords are normalized when we stage them. */
+       v.keyword, v.serverModified
 FROM mergedTree r
 LEFT JOIN moz_bookmarks_synced v ON v.guid = r.remoteGuid
 LEFT JOIN moz_bookmarks b ON b.guid = r.localGuid
@@ -95,7 +101,8 @@ CREATE TEMP TABLE itemsToUpload(
     placeId INTEGER,
     url TEXT,
     keyword TEXT,
-    position INTEGER
+    position INTEGER,
+    unknownFields TEXT
 );
 
 CREATE TEMP TABLE structureToUpload(
@@ -105,6 +112,10 @@ CREATE TEMP TABLE structureToUpload(
     position INTEGER NOT NULL
 ) WITHOUT ROWID;
 
+-- We fetch the children of each outgoing folder as we build its record, so
+-- we need to look them up by parent.
+CREATE INDEX structureToUploadParentPosition ON structureToUpload(parentId, position);
+
 CREATE TEMP TABLE tagsToUpload(
     id INTEGER REFERENCES itemsToUpload(id)
                ON DELETE CASCADE,
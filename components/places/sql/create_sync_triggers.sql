@@ -25,11 +25,12 @@ BEGIN
     -- what's on the server now.
     REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge,
                                       validity, isDeleted, kind, dateAdded, title,
-                                      placeId, keyword)
+                                      placeId, keyword, parentTitle, unknownFields)
     VALUES(NEW.guid, NEW.parentGuid, NEW.uploadedAt, 0,
            1, -- SyncedBookmarkValidity::Valid
            NEW.isDeleted, NEW.kind, NEW.dateAdded, NEW.title,
-           NEW.placeId, NEW.keyword);
+           NEW.placeId, NEW.keyword, NULLIF(NEW.parentTitle, ''),
+           NEW.unknownFields);
 
     INSERT INTO moz_bookmarks_synced_structure(guid, parentGuid, position)
     SELECT guid, NEW.guid, position
@@ -93,7 +94,7 @@ CREATE TEMP TRIGGER updateGuidsAndSyncFlags
 INSTEAD OF DELETE ON itemsToMerge
 BEGIN
     UPDATE moz_bookmarks SET
-        -- We update GUIDs here, instead of in the `updateExistingLocalItems`
+        -- We update GUIDs here, instead of in the `updateLocalItems`
         -- trigger, because deduped items with a local merge state won't have
         -- `useRemote` set.
         guid = OLD.mergedGuid,
@@ -157,6 +158,51 @@ BEGIN
     SELECT tagId, OLD.newPlaceId
     FROM moz_bookmarks_synced_tag_relation
     WHERE itemId = OLD.remoteId;
+
+    -- If another URL uses the new keyword, the most recent change wins. If
+    -- the remote item is newer, we take the keyword away from the other URL,
+    -- and flag its bookmarks for reupload without it.
+    UPDATE moz_bookmarks SET
+        syncChangeCounter = syncChangeCounter + 1
+    WHERE fk IN (SELECT place_id FROM moz_keywords
+                 WHERE keyword = OLD.newKeyword AND
+                       place_id <> OLD.newPlaceId AND
+                       lastModified <= OLD.newServerModified);
+
+    DELETE FROM moz_keywords
+    WHERE keyword = OLD.newKeyword AND
+          place_id <> OLD.newPlaceId AND
+          lastModified <= OLD.newServerModified;
+
+    -- Keywords are per-URL, so replacing the keywords for the old and new
+    -- URLs also changes them for other bookmarks with those URLs.
+    UPDATE moz_bookmarks SET
+        syncChangeCounter = syncChangeCounter + 1
+    WHERE guid <> OLD.mergedGuid AND
+          fk IN (SELECT place_id FROM moz_keywords
+                 WHERE place_id IN (OLD.oldPlaceId, OLD.newPlaceId) AND
+                       keyword IS NOT OLD.newKeyword);
+
+    DELETE FROM moz_keywords
+    WHERE place_id IN (OLD.oldPlaceId, OLD.newPlaceId) AND
+          keyword IS NOT OLD.newKeyword;
+
+    -- Insert the new keyword, unless the other URL kept it.
+    INSERT OR IGNORE INTO moz_keywords(keyword, place_id, lastModified)
+    SELECT OLD.newKeyword, OLD.newPlaceId, OLD.newServerModified
+    WHERE OLD.newKeyword NOT NULL AND
+          OLD.newPlaceId NOT NULL;
+
+    -- If the other URL kept the keyword, reupload the remote item without
+    -- it. We can't bump the change counter here, because the
+    -- `updateGuidsAndSyncFlags` trigger might reset it.
+    INSERT OR IGNORE INTO idsToWeaklyUpload(id)
+    SELECT id FROM moz_bookmarks
+    WHERE guid = OLD.mergedGuid AND
+          OLD.newKeyword NOT NULL AND
+          NOT EXISTS(SELECT 1 FROM moz_keywords
+                     WHERE keyword = OLD.newKeyword AND
+                           place_id = OLD.newPlaceId);
 END;
 
 -- Updates all parents and positions to reflect the merged tree.
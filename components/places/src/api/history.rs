@@ -5,7 +5,7 @@
 use super::apply_observation;
 use crate::db::PlacesDb;
 use crate::error::*;
-use crate::observation::VisitObservation;
+use crate::observation::{ObservationSource, VisitObservation};
 use crate::types::*;
 use url::Url;
 // This module can become, roughly: PlacesUtils.history()
@@ -45,7 +45,11 @@ pub fn insert(conn: &mut PlacesDb, place: AddablePlaceInfo) -> Result<()> {
             .with_visit_type(v.transition)
             .with_at(v.date)
             .with_title(place.title.clone())
-            .with_is_remote(!v.is_local);
+            .with_source(if v.is_local {
+                ObservationSource::Local
+            } else {
+                ObservationSource::Sync
+            });
         // .with_referrer(...) ????
 
         //if place.referrer
@@ -57,7 +61,7 @@ pub fn insert(conn: &mut PlacesDb, place: AddablePlaceInfo) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::test::new_mem_connection;
+    use crate::testing::new_mem_connection;
     use rusqlite::NO_PARAMS;
 
     #[test]
@@ -23,7 +23,10 @@ pub struct SearchParams {
 /// A provider can be anything that returns URL suggestions: Places history
 /// and bookmarks, synced tabs, search engine suggestions, and search keywords.
 pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<SearchResult>> {
-    // TODO: Tokenize the query.
+    // The adaptive and suggestion matchers tokenize the query on whitespace
+    // in `AUTOCOMPLETE_MATCH`, so that "moz fire" matches pages with both
+    // words in their URL or title. Origin and URL matches use the whole
+    // query, since they're for autofilling what the user typed.
 
     // Try to find the first heuristic result. Desktop tries extensions,
     // search engine aliases, origins, URLs, search engine domains, and
@@ -74,7 +77,7 @@ fn match_with_limit(
     matchers: &[&dyn Matcher],
     max_results: u32,
 ) -> Result<(Vec<SearchResult>)> {
-    let mut results = Vec::new();
+    let mut results: Vec<SearchResult> = Vec::new();
     let mut rem_results = max_results;
     let scope = conn.begin_interrupt_scope();
     for m in matchers {
@@ -83,15 +86,32 @@ fn match_with_limit(
         }
         scope.err_if_interrupted()?;
         let matches = m.search(conn, rem_results)?;
-        results.extend(matches);
-        rem_results = rem_results.saturating_sub(results.len() as u32);
+        // Earlier matchers take precedence, so a page that we've already
+        // matched, like a previously used result, isn't listed again as a
+        // suggestion.
+        for result in matches {
+            if results.iter().all(|r| r.url != result.url) {
+                results.push(result);
+            }
+        }
+        rem_results = max_results.saturating_sub(results.len() as u32);
     }
     Ok(results)
 }
 
+/// How much `decay_input_history` reduces the use counts of accepted
+/// matches each time it's called. This is the same rate that Desktop uses.
+pub const INPUT_HISTORY_DECAY_RATE: f64 = 0.975;
+
+/// Accepted matches with a lower use count than this are forgotten by
+/// `decay_input_history`.
+pub const INPUT_HISTORY_MIN_USE_COUNT: f64 = 0.01;
+
 /// Records an accepted autocomplete match, recording the query string,
-/// and chosen URL for subsequent matches.
-pub fn accept_result(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
+/// and chosen URL for subsequent matches. Accepting the same URL for the same
+/// query again increases its use count, which ranks it higher than
+/// suggestions with a higher frecency.
+pub fn accept_result(conn: &PlacesDb, search_string: &str, url: &Url) -> Result<()> {
     // See `nsNavHistory::AutoCompleteFeedback`.
     conn.execute_named(
         "INSERT OR REPLACE INTO moz_inputhistory(place_id, input, use_count)
@@ -100,14 +120,33 @@ pub fn accept_result(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
          LEFT JOIN moz_inputhistory i ON i.place_id = h.id AND i.input = :input_text
          WHERE url_hash = hash(:page_url) AND url = :page_url",
         &[
-            (":input_text", &result.search_string),
-            (":page_url", &result.url.as_str()),
+            (":input_text", &search_string),
+            (":page_url", &url.as_str()),
         ],
     )?;
 
     Ok(())
 }
 
+/// Decays the use counts of accepted matches, so that matches the user
+/// hasn't picked in a while stop outranking other suggestions. Matches that
+/// haven't been picked in long enough are forgotten. This should be called
+/// once a day. Returns the number of matches forgotten.
+pub fn decay_input_history(conn: &PlacesDb) -> Result<usize> {
+    // See `PlacesDBUtils._refreshUI` and `nsNavHistory::DecayFrecency`.
+    let tx = conn.begin_transaction()?;
+    conn.execute_named(
+        "UPDATE moz_inputhistory SET use_count = use_count * :decayRate",
+        &[(":decayRate", &INPUT_HISTORY_DECAY_RATE)],
+    )?;
+    let forgotten = conn.execute_named(
+        "DELETE FROM moz_inputhistory WHERE use_count < :minUseCount",
+        &[(":minUseCount", &INPUT_HISTORY_MIN_USE_COUNT)],
+    )?;
+    tx.commit()?;
+    Ok(forgotten)
+}
+
 pub fn split_after_prefix(href: &str) -> (&str, &str) {
     match memchr::memchr(b':', href.as_bytes()) {
         None => ("", href),
@@ -217,10 +256,11 @@ impl SearchResult {
     }
 
     pub fn from_suggestion_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
-        let mut reasons = vec![MatchReason::Bookmark];
+        let mut reasons = Vec::new();
 
         let search_string = row.get::<_, String>("searchString")?;
         let url = row.get::<_, String>("url")?;
+        let bookmarked = row.get::<_, bool>("bookmarked")?;
 
         let history_title = row.get::<_, Option<String>>("title")?;
         let bookmark_title = row.get::<_, Option<String>>("btitle")?;
@@ -230,6 +270,9 @@ impl SearchResult {
         if let Some(tags) = tags {
             reasons.push(MatchReason::Tags(tags));
         }
+        if bookmarked {
+            reasons.push(MatchReason::Bookmark);
+        }
         let url = Url::parse(&url).expect("Invalid URL in Places");
 
         let frecency = row.get::<_, i64>("frecency")?;
@@ -546,9 +589,9 @@ impl<'query> Matcher for Suggestions<'query> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::test::new_mem_connection;
     use crate::observation::VisitObservation;
     use crate::storage::history::apply_observation;
+    use crate::testing::new_mem_connection;
     use crate::types::{Timestamp, VisitTransition};
 
     #[test]
@@ -633,18 +676,7 @@ mod tests {
                 && result.url.as_str() == "http://example.com/123"
                 && result.reasons == [MatchReason::Url]));
 
-        accept_result(
-            &conn,
-            &SearchResult {
-                search_string: "ample".into(),
-                url: url.clone(),
-                title: "Example page 123".into(),
-                icon_url: None,
-                frecency: -1,
-                reasons: vec![],
-            },
-        )
-        .expect("Should accept input history match");
+        accept_result(&conn, "ample", &url).expect("Should accept input history match");
 
         let by_adaptive = search_frecent(
             &conn,
@@ -680,6 +712,203 @@ mod tests {
             }]
         );
     }
+
+    #[test]
+    fn search_origin_prefix() {
+        let conn = new_mem_connection();
+        apply_observation(
+            &conn,
+            VisitObservation::new(Url::parse("https://www.mozilla.org/en-US/firefox/").unwrap())
+                .with_title("Firefox".to_string())
+                .with_visit_type(VisitTransition::Typed)
+                .with_at(Timestamp::now()),
+        )
+        .expect("Should apply visit");
+
+        // Typing the start of the host autofills the origin, even without
+        // the "www." prefix.
+        assert_eq!(
+            match_url(&conn, "moz").expect("Should match origin"),
+            Some("https://www.mozilla.org/".to_string())
+        );
+        let results = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "moz".into(),
+                limit: 10,
+            },
+        )
+        .expect("Should search by origin prefix");
+        assert_eq!(results[0].url.as_str(), "https://www.mozilla.org/");
+        assert_eq!(results[0].reasons, [MatchReason::Origin]);
+
+        // Tokens can match anywhere in the URL or title.
+        let results = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "firefox en-us".into(),
+                limit: 10,
+            },
+        )
+        .expect("Should search by tokens");
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| result.url.as_str())
+                .collect::<Vec<_>>(),
+            vec!["https://www.mozilla.org/en-US/firefox/"]
+        );
+
+        assert_eq!(match_url(&conn, "example").unwrap(), None);
+    }
+
+    #[test]
+    fn search_adaptive_boost() {
+        let conn = new_mem_connection();
+        let popular_url = Url::parse("https://example.com/popular").unwrap();
+        let rare_url = Url::parse("https://example.com/rare").unwrap();
+        for _ in 0..5 {
+            apply_observation(
+                &conn,
+                VisitObservation::new(popular_url.clone())
+                    .with_title("Popular page".to_string())
+                    .with_visit_type(VisitTransition::Typed)
+                    .with_at(Timestamp::now()),
+            )
+            .expect("Should apply visit");
+        }
+        apply_observation(
+            &conn,
+            VisitObservation::new(rare_url.clone())
+                .with_title("Rare page".to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now()),
+        )
+        .expect("Should apply visit");
+
+        let search = |search_string: &str| {
+            search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                },
+            )
+            .expect("Should search")
+            .into_iter()
+            .map(|result| (result.url.into_string(), result.reasons))
+            .collect::<Vec<_>>()
+        };
+
+        // Without input history, the more frecent page comes first.
+        assert_eq!(
+            search("page"),
+            vec![
+                (popular_url.to_string(), vec![]),
+                (rare_url.to_string(), vec![]),
+            ]
+        );
+
+        // Once the user picks the less frecent page, it's listed first, but
+        // only once, for the same input and for prefixes of it.
+        let rare_result = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "page".into(),
+                limit: 10,
+            },
+        )
+        .expect("Should search")
+        .into_iter()
+        .find(|result| result.url == rare_url)
+        .expect("Should find rare page");
+        accept_result(&conn, &rare_result.search_string, &rare_result.url)
+            .expect("Should accept result");
+        for search_string in &["page", "pa"] {
+            assert_eq!(
+                search(search_string),
+                vec![
+                    (rare_url.to_string(), vec![MatchReason::PreviousUse]),
+                    (popular_url.to_string(), vec![]),
+                ],
+                "Should boost rare page for {:?}",
+                search_string
+            );
+        }
+    }
+
+    #[test]
+    fn accept_and_decay_input_history() {
+        let conn = new_mem_connection();
+        let twitter_url = Url::parse("https://mobile.twitter.com/home").unwrap();
+        let facebook_url = Url::parse("https://www.facebook.com/settings/two_factor").unwrap();
+        for _ in 0..10 {
+            apply_observation(
+                &conn,
+                VisitObservation::new(facebook_url.clone())
+                    .with_title("Facebook".to_string())
+                    .with_visit_type(VisitTransition::Typed)
+                    .with_at(Timestamp::now()),
+            )
+            .expect("Should apply visit");
+        }
+        apply_observation(
+            &conn,
+            VisitObservation::new(twitter_url.clone())
+                .with_title("Twitter".to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now()),
+        )
+        .expect("Should apply visit");
+
+        let search_tw = || {
+            search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: "tw".into(),
+                    limit: 10,
+                },
+            )
+            .expect("Should search")
+            .into_iter()
+            .map(|result| result.url)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(search_tw(), vec![facebook_url.clone(), twitter_url.clone()]);
+
+        // Accepting a match for a URL we don't know about does nothing.
+        accept_result(
+            &conn,
+            "tw",
+            &Url::parse("https://example.com/unknown").unwrap(),
+        )
+        .expect("Should ignore unknown URL");
+        let input_history_count: i64 = conn
+            .query_one("SELECT COUNT(*) FROM moz_inputhistory")
+            .unwrap();
+        assert_eq!(input_history_count, 0);
+
+        // Once the user picks Twitter, it outranks the more frecent page.
+        accept_result(&conn, "tw", &twitter_url).expect("Should accept result");
+        accept_result(&conn, "tw", &twitter_url).expect("Should accept result again");
+        let use_count: f64 = conn
+            .query_one("SELECT use_count FROM moz_inputhistory")
+            .unwrap();
+        assert!((use_count - 1.9).abs() < 1e-9, "{}", use_count);
+        assert_eq!(search_tw(), vec![twitter_url.clone(), facebook_url.clone()]);
+
+        // Decaying the use count eventually forgets the match, and it's
+        // ranked by frecency again.
+        assert_eq!(decay_input_history(&conn).unwrap(), 0);
+        assert_eq!(search_tw(), vec![twitter_url.clone(), facebook_url.clone()]);
+        let mut forgotten = 0;
+        for _ in 0..365 {
+            forgotten += decay_input_history(&conn).unwrap();
+        }
+        assert_eq!(forgotten, 1);
+        assert_eq!(search_tw(), vec![facebook_url, twitter_url]);
+    }
+
     #[test]
     fn search_unicode() {
         let conn = new_mem_connection();
@@ -10,7 +10,7 @@ use crate::error::Result;
 use crate::observation::VisitObservation;
 use crate::storage;
 
-pub fn apply_observation(conn: &mut PlacesDb, visit_obs: VisitObservation) -> Result<()> {
+pub fn apply_observation(conn: &PlacesDb, visit_obs: VisitObservation) -> Result<()> {
     storage::history::apply_observation(conn, visit_obs)?;
     Ok(())
 }
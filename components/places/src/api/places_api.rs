@@ -2,14 +2,16 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::bookmark_sync::store::BookmarksStore;
-use crate::db::db::PlacesDb;
+use crate::bookmark_sync::store::{BookmarksStore, SyncMode};
+use crate::db::db::{BusyRetryPolicy, PlacesDb};
 use crate::error::*;
+use crate::frecency::FrecencySettings;
 use crate::history_sync::store::HistoryStore;
-use crate::storage::{delete_meta, get_meta, put_meta};
+use crate::storage::{delete_internal_meta, erase_everything, get_meta, put_internal_meta};
 use crate::util::normalize_path;
 use lazy_static::lazy_static;
 use rusqlite::OpenFlags;
+use sql_support::SqlInterruptScope;
 use std::cell::Cell;
 use std::collections::HashMap;
 use std::fs;
@@ -19,6 +21,7 @@ use std::sync::{
     atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc, Mutex, Weak,
 };
+use std::time::Duration;
 use sync15::{telemetry, MemoryCachedState};
 
 // Not clear if this should be here, but this is the "global sync state"
@@ -28,6 +31,9 @@ use sync15::{telemetry, MemoryCachedState};
 // per collection.
 pub const GLOBAL_STATE_META_KEY: &str = "global_sync_state_v2";
 
+/// The number of statements to include in verbose sync telemetry.
+const MAX_SQL_STATS_EVENTS: usize = 10;
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ConnectionType {
@@ -72,6 +78,15 @@ struct SyncState {
     disk_cached_state: Cell<Option<String>>,
 }
 
+/// The result of a successful history or bookmark sync.
+#[derive(Debug)]
+pub struct SyncOutcome {
+    pub telemetry: telemetry::SyncTelemetryPing,
+    /// How long to wait before syncing again, or `None` to use the usual
+    /// interval. See `sync15::SyncScheduleInputs::next_sync_after`.
+    pub next_sync_after: Option<Duration>,
+}
+
 /// The entry-point to the places API. This object gives access to database
 /// connections and other helpers. It enforces that only 1 write connection
 /// can exist to the database at once.
@@ -81,13 +96,27 @@ pub struct PlacesApi {
     sync_state: Mutex<Option<SyncState>>,
     coop_tx_lock: Arc<Mutex<()>>,
     sync_conn_active: AtomicBool,
+    verbose_sync_telemetry: AtomicBool,
+    frecency_settings: Mutex<FrecencySettings>,
+    busy_retry_policy: BusyRetryPolicy,
     id: usize,
 }
 impl PlacesApi {
     /// Create a new, or fetch an already open, PlacesApi backed by a file on disk.
     pub fn new(db_name: impl AsRef<Path>) -> Result<Arc<Self>> {
+        Self::new_with_busy_retry_policy(db_name, BusyRetryPolicy::default())
+    }
+
+    /// Like `new`, but write APIs on the connections that this PlacesApi
+    /// opens retry according to `busy_retry_policy` when the database is
+    /// busy. If the database is already open, this returns the existing
+    /// PlacesApi, which keeps its policy.
+    pub fn new_with_busy_retry_policy(
+        db_name: impl AsRef<Path>,
+        busy_retry_policy: BusyRetryPolicy,
+    ) -> Result<Arc<Self>> {
         let db_name = normalize_path(db_name)?;
-        Self::new_or_existing(db_name)
+        Self::new_or_existing(db_name, busy_retry_policy)
     }
 
     /// Create a new, or fetch an already open, memory-based PlacesApi. You must
@@ -95,11 +124,12 @@ impl PlacesApi {
     ///  reader connections to the same memory DB open.
     pub fn new_memory(db_name: &str) -> Result<Arc<Self>> {
         let name = PathBuf::from(format!("file:{}?mode=memory&cache=shared", db_name));
-        Self::new_or_existing(name)
+        Self::new_or_existing(name, BusyRetryPolicy::default())
     }
     fn new_or_existing_into(
         target: &mut HashMap<PathBuf, Weak<PlacesApi>>,
         db_name: PathBuf,
+        busy_retry_policy: BusyRetryPolicy,
         delete_on_fail: bool,
     ) -> Result<Arc<Self>> {
         let id = ID_COUNTER.fetch_add(1, Ordering::SeqCst);
@@ -114,6 +144,7 @@ impl PlacesApi {
                     ConnectionType::ReadWrite,
                     id,
                     coop_tx_lock.clone(),
+                    busy_retry_policy,
                 ) {
                     Ok(connection) => {
                         let new = PlacesApi {
@@ -121,6 +152,9 @@ impl PlacesApi {
                             write_connection: Mutex::new(Some(connection)),
                             sync_state: Mutex::new(None),
                             sync_conn_active: AtomicBool::new(false),
+                            verbose_sync_telemetry: AtomicBool::new(false),
+                            frecency_settings: Mutex::default(),
+                            busy_retry_policy,
                             id,
                             coop_tx_lock,
                         };
@@ -134,7 +168,7 @@ impl PlacesApi {
                         }
                         if let ErrorKind::DatabaseUpgradeError = e.kind() {
                             fs::remove_file(&db_name)?;
-                            Self::new_or_existing_into(target, db_name, false)
+                            Self::new_or_existing_into(target, db_name, busy_retry_policy, false)
                         } else {
                             Err(e)
                         }
@@ -144,14 +178,14 @@ impl PlacesApi {
         }
     }
 
-    fn new_or_existing(db_name: PathBuf) -> Result<Arc<Self>> {
+    fn new_or_existing(db_name: PathBuf, busy_retry_policy: BusyRetryPolicy) -> Result<Arc<Self>> {
         let mut guard = APIS.lock().unwrap();
-        Self::new_or_existing_into(&mut guard, db_name, true)
+        Self::new_or_existing_into(&mut guard, db_name, busy_retry_policy, true)
     }
 
     /// Open a connection to the database.
     pub fn open_connection(&self, conn_type: ConnectionType) -> Result<PlacesDb> {
-        match conn_type {
+        let db = match conn_type {
             ConnectionType::ReadOnly => {
                 // make a new one - we can have as many of these as we want.
                 PlacesDb::open(
@@ -159,20 +193,23 @@ impl PlacesApi {
                     ConnectionType::ReadOnly,
                     self.id,
                     self.coop_tx_lock.clone(),
-                )
+                    self.busy_retry_policy,
+                )?
             }
             ConnectionType::ReadWrite => {
                 // We only allow one of these.
                 let mut guard = self.write_connection.lock().unwrap();
                 match mem::replace(&mut *guard, None) {
-                    None => Err(ErrorKind::ConnectionAlreadyOpen.into()),
-                    Some(db) => Ok(db),
+                    None => return Err(ErrorKind::ConnectionAlreadyOpen.into()),
+                    Some(db) => db,
                 }
             }
             ConnectionType::Sync => {
                 panic!("Use `open_sync_connection` to open a sync connection");
             }
-        }
+        };
+        db.set_frecency_settings(self.frecency_settings());
+        Ok(db)
     }
 
     pub fn open_sync_connection(&self) -> Result<SyncConn<'_>> {
@@ -187,7 +224,9 @@ impl PlacesApi {
                 ConnectionType::Sync,
                 self.id,
                 self.coop_tx_lock.clone(),
+                self.busy_retry_policy,
             )?;
+            db.set_frecency_settings(self.frecency_settings());
             Ok(SyncConn {
                 db,
                 flag: &self.sync_conn_active,
@@ -216,64 +255,154 @@ impl PlacesApi {
 
     fn set_disk_persisted_state(&self, conn: &PlacesDb, state: &Option<String>) -> Result<()> {
         match state {
-            Some(ref s) => put_meta(&conn, GLOBAL_STATE_META_KEY, s),
-            None => delete_meta(&conn, GLOBAL_STATE_META_KEY),
+            Some(ref s) => put_internal_meta(&conn, GLOBAL_STATE_META_KEY, s),
+            None => delete_internal_meta(&conn, GLOBAL_STATE_META_KEY),
+        }
+    }
+
+    /// Erases all local data, as if the database was just created. This
+    /// waits for a sync in progress to finish, and uses the write
+    /// connection, so that nothing can observe a half-wiped database. It
+    /// fails with `ConnectionAlreadyOpen` if the write connection or a sync
+    /// connection is open. See `storage::erase_everything` for details.
+    pub fn wipe_local(&self) -> Result<()> {
+        let mut sync_state = self.sync_state.lock().unwrap();
+        let guard = self.write_connection.lock().unwrap();
+        let writer = match &*guard {
+            Some(db) => db,
+            None => return Err(ErrorKind::ConnectionAlreadyOpen.into()),
+        };
+        // Hold the sync connection flag, so that we can't open one while
+        // we're wiping.
+        if self
+            .sync_conn_active
+            .compare_and_swap(false, true, Ordering::SeqCst)
+        {
+            return Err(ErrorKind::ConnectionAlreadyOpen.into());
         }
+        let result = erase_everything(writer);
+        self.sync_conn_active.store(false, Ordering::SeqCst);
+        // The cached global state is gone from the database, so we need to
+        // forget it, too.
+        *sync_state = None;
+        result
+    }
+
+    /// Sets the weights and bonuses used to calculate frecencies. The
+    /// settings apply to every connection opened afterward, including the
+    /// write connection the next time it's opened, and the connections used
+    /// for syncing and wiping. This doesn't recalculate existing frecencies.
+    pub fn set_frecency_settings(&self, settings: FrecencySettings) {
+        let guard = self.write_connection.lock().unwrap();
+        if let Some(db) = &*guard {
+            db.set_frecency_settings(settings.clone());
+        }
+        *self.frecency_settings.lock().unwrap() = settings;
+    }
+
+    pub fn frecency_settings(&self) -> FrecencySettings {
+        self.frecency_settings.lock().unwrap().clone()
+    }
+
+    /// Enables or disables verbose sync telemetry. When enabled, we trace the
+    /// SQL that the Sync connection runs, and add events for the most
+    /// expensive statements to the sync ping.
+    pub fn set_verbose_sync_telemetry(&self, verbose: bool) {
+        self.verbose_sync_telemetry.store(verbose, Ordering::SeqCst);
+    }
+
+    /// Enables SQL tracing on the Sync connection if verbose telemetry is
+    /// on, and returns whether it is.
+    fn start_sql_tracing(&self, conn: &mut SyncConn<'_>) -> bool {
+        let verbose = self.verbose_sync_telemetry.load(Ordering::SeqCst);
+        if verbose {
+            conn.db.reset_sql_stats();
+            conn.db.set_sql_tracing(true);
+        }
+        verbose
     }
 
-    // TODO: We need a better result here so we can return telemetry.
-    // We possibly want more than just a `SyncTelemetryPing` so we can
-    // return additional "custom" telemetry if the app wants it.
     pub fn sync_history(
         &self,
         client_init: &sync15::Sync15StorageClientInit,
         key_bundle: &sync15::KeyBundle,
-    ) -> Result<telemetry::SyncTelemetryPing> {
-        let mut guard = self.sync_state.lock().unwrap();
-        let conn = self.open_sync_connection()?;
-        if guard.is_none() {
-            *guard = Some(SyncState {
-                mem_cached_state: Cell::default(),
-                disk_cached_state: Cell::new(self.get_disk_persisted_state(&conn)?),
-            });
-        }
-
-        let sync_state = guard.as_ref().unwrap();
-        // Note that counter-intuitively, this must be called before we do a
-        // bookmark sync too, to ensure the shared global state is correct.
-        HistoryStore::migrate_v1_global_state(&conn)?;
+    ) -> Result<SyncOutcome> {
+        self.do_sync_one(|conn, interruptee, mem_state, disk_state, ping| {
+            HistoryStore::new(conn, interruptee).sync(
+                client_init,
+                key_bundle,
+                mem_state,
+                disk_state,
+                ping,
+            )
+        })
+    }
 
-        let interruptee = conn.begin_interrupt_scope();
-        let store = HistoryStore::new(&conn, &interruptee);
-        let mut mem_cached_state = sync_state.mem_cached_state.take();
-        let mut disk_cached_state = sync_state.disk_cached_state.take();
-        let mut sync_ping = telemetry::SyncTelemetryPing::new();
-        let result = store.sync(
-            &client_init,
-            &key_bundle,
-            &mut mem_cached_state,
-            &mut disk_cached_state,
-            &mut sync_ping,
-        );
-        // even on failure we set the persisted state - sync itself takes care
-        // to ensure this has been None'd out if necessary.
-        self.set_disk_persisted_state(&conn, &disk_cached_state)?;
-        sync_state.mem_cached_state.replace(mem_cached_state);
-        sync_state.disk_cached_state.replace(disk_cached_state);
+    pub fn sync_bookmarks(
+        &self,
+        client_init: &sync15::Sync15StorageClientInit,
+        key_bundle: &sync15::KeyBundle,
+    ) -> Result<SyncOutcome> {
+        self.sync_bookmarks_with_mode(client_init, key_bundle, SyncMode::Full)
+    }
 
-        result?;
+    /// Like `sync_bookmarks`, but only downloads and stages incoming
+    /// bookmarks, without merging or uploading them. This is much cheaper
+    /// for large trees, so apps can call it when they have a limited
+    /// background execution budget, then call
+    /// `complete_pending_bookmark_merge`, or `sync_bookmarks`, later.
+    pub fn stage_bookmarks(
+        &self,
+        client_init: &sync15::Sync15StorageClientInit,
+        key_bundle: &sync15::KeyBundle,
+    ) -> Result<SyncOutcome> {
+        self.sync_bookmarks_with_mode(client_init, key_bundle, SyncMode::StageOnly)
+    }
 
-        Ok(sync_ping)
+    /// Merges bookmarks staged by `stage_bookmarks` into the local tree,
+    /// without syncing. The next sync uploads any changes from the merge.
+    pub fn complete_pending_bookmark_merge(&self) -> Result<()> {
+        let conn = self.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        BookmarksStore::new(&conn, &interruptee).complete_pending_merge()
     }
 
-    // TODO: reduce duplication with above
-    pub fn sync_bookmarks(
+    fn sync_bookmarks_with_mode(
         &self,
         client_init: &sync15::Sync15StorageClientInit,
         key_bundle: &sync15::KeyBundle,
-    ) -> Result<telemetry::SyncTelemetryPing> {
+        mode: SyncMode,
+    ) -> Result<SyncOutcome> {
+        self.do_sync_one(|conn, interruptee, mem_state, disk_state, ping| {
+            let store = BookmarksStore::new(conn, interruptee).with_mode(mode);
+            let result = store.sync(client_init, key_bundle, mem_state, disk_state, ping);
+            // The sync15 engine telemetry already counts uploaded records,
+            // so we only need to add what we found while validating the
+            // remote tree.
+            let validation = store.take_telemetry().validation;
+            if let Some(engine) = ping.engine_mut("bookmarks") {
+                engine.validation(validation.to_validation());
+            }
+            result
+        })
+    }
+
+    /// Opens the Sync connection, loads the cached global state, and calls
+    /// `syncer` to sync a single store. The state is persisted again
+    /// afterward, even if the sync failed.
+    fn do_sync_one<F>(&self, syncer: F) -> Result<SyncOutcome>
+    where
+        F: FnOnce(
+            &PlacesDb,
+            &SqlInterruptScope,
+            &mut MemoryCachedState,
+            &mut Option<String>,
+            &mut telemetry::SyncTelemetryPing,
+        ) -> Result<Option<Duration>>,
+    {
         let mut guard = self.sync_state.lock().unwrap();
-        let conn = self.open_sync_connection()?;
+        let mut conn = self.open_sync_connection()?;
+        let verbose = self.start_sql_tracing(&mut conn);
         if guard.is_none() {
             *guard = Some(SyncState {
                 mem_cached_state: Cell::default(),
@@ -287,13 +416,12 @@ impl PlacesApi {
         HistoryStore::migrate_v1_global_state(&conn)?;
 
         let interruptee = conn.begin_interrupt_scope();
-        let store = BookmarksStore::new(&conn, &interruptee);
         let mut mem_cached_state = sync_state.mem_cached_state.take();
         let mut disk_cached_state = sync_state.disk_cached_state.take();
         let mut sync_ping = telemetry::SyncTelemetryPing::new();
-        let result = store.sync(
-            &client_init,
-            &key_bundle,
+        let result = syncer(
+            &conn,
+            &interruptee,
             &mut mem_cached_state,
             &mut disk_cached_state,
             &mut sync_ping,
@@ -303,10 +431,39 @@ impl PlacesApi {
         self.set_disk_persisted_state(&conn, &disk_cached_state)?;
         sync_state.mem_cached_state.replace(mem_cached_state);
         sync_state.disk_cached_state.replace(disk_cached_state);
+        if verbose {
+            add_sql_stats_events(&conn, &mut sync_ping);
+        }
+
+        let next_sync_after = result?;
 
-        result?;
+        Ok(SyncOutcome {
+            telemetry: sync_ping,
+            next_sync_after,
+        })
+    }
+}
 
-        Ok(sync_ping)
+/// Adds an event for each of the most expensive statements that we traced
+/// during a sync. Statement fingerprints never include literals, so they're
+/// safe to report.
+fn add_sql_stats_events(conn: &PlacesDb, sync_ping: &mut telemetry::SyncTelemetryPing) {
+    for stats in conn.sql_stats().into_iter().take(MAX_SQL_STATS_EVENTS) {
+        // Event extras are limited to 85 bytes.
+        let mut stmt = stats.fingerprint;
+        if stmt.len() > 85 {
+            let mut end = 85;
+            while !stmt.is_char_boundary(end) {
+                end -= 1;
+            }
+            stmt.truncate(end);
+        }
+        sync_ping.event(
+            telemetry::Event::new("sql", "stats")
+                .extra("stmt", stmt)
+                .extra("count", stats.count.to_string())
+                .extra("ms", stats.total_time.as_millis().to_string()),
+        );
     }
 }
 
@@ -330,48 +487,23 @@ impl<'a> std::ops::Deref for SyncConn<'a> {
     }
 }
 
-#[cfg(test)]
-pub mod test {
-    use super::*;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-
-    // A helper for our tests to get their own memory Api.
-    static ATOMIC_COUNTER: AtomicUsize = AtomicUsize::new(0);
-
-    pub fn new_mem_api() -> Arc<PlacesApi> {
-        let counter = ATOMIC_COUNTER.fetch_add(1, Ordering::Relaxed);
-        PlacesApi::new_memory(&format!("test-api-{}", counter)).expect("should get an API")
-    }
-
-    pub fn new_mem_connection() -> PlacesDb {
-        new_mem_api()
-            .open_connection(ConnectionType::ReadWrite)
-            .expect("should get a connection")
-    }
-
-    pub struct MemConnections {
-        pub read: PlacesDb,
-        pub write: PlacesDb,
-        pub api: Arc<PlacesApi>,
-    }
-
-    pub fn new_mem_connections() -> MemConnections {
-        let api = new_mem_api();
-        let read = api
-            .open_connection(ConnectionType::ReadOnly)
-            .expect("should get a read connection");
-        let write = api
-            .open_connection(ConnectionType::ReadWrite)
-            .expect("should get a write connection");
-        MemConnections { api, read, write }
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use super::test::*;
     use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+    };
+    use crate::storage::deletion_log::{get_deletion_log, DeletionInitiator, DeletionOp};
+    use crate::storage::history::apply_observation;
+    use crate::storage::keywords::set_keyword;
+    use crate::storage::put_meta;
+    use crate::storage::tags::tag_url;
+    use crate::testing::*;
+    use crate::types::{Timestamp, VisitTransition};
+    use serde_json::json;
     use sql_support::ConnExt;
+    use url::Url;
 
     #[test]
     fn test_multi_writers_fails() {
@@ -388,6 +520,162 @@ mod tests {
             .expect("should get a writer after closing the other");
     }
 
+    #[test]
+    fn test_multi_sync_conns_fails() {
+        let api = new_mem_api();
+        let syncer = api
+            .open_sync_connection()
+            .expect("should get sync connection");
+        match api.open_sync_connection() {
+            Err(e) => match e.kind() {
+                ErrorKind::ConnectionAlreadyOpen => {}
+                e => panic!("Expected error ConnectionAlreadyOpen, got {:?}", e),
+            },
+            Ok(_) => panic!("should fail to get second sync connection"),
+        }
+        // Dropping the sync connection lets us open another.
+        drop(syncer);
+        api.open_sync_connection()
+            .expect("should get a sync connection after dropping the other");
+    }
+
+    #[test]
+    fn test_reader_cant_write() {
+        let api = new_mem_api();
+        let reader = api
+            .open_connection(ConnectionType::ReadOnly)
+            .expect("should get reader");
+        let bookmark = InsertableBookmark {
+            parent_guid: BookmarkRootGuid::Unfiled.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            url: Url::parse("https://www.example.com/").unwrap(),
+            title: None,
+            page_title: None,
+        };
+        let err = insert_bookmark(&reader, &bookmark.into())
+            .expect_err("should fail to insert with a reader");
+        match err.kind() {
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(e, _)) => {
+                assert_eq!(e.code, rusqlite::ErrorCode::ReadOnly)
+            }
+            e => panic!("Expected a read-only error, got {:?}", e),
+        }
+    }
+
+    /// Returns the row count of each table, the bookmark GUIDs, and the
+    /// meta keys, so that we can compare databases. The deletion log isn't
+    /// included, since wiping adds an entry to it.
+    fn snapshot(conn: &PlacesDb) -> (Vec<(String, i64)>, Vec<String>, Vec<String>) {
+        let tables = conn
+            .query_rows_and_then_named(
+                "SELECT name FROM sqlite_master
+                 WHERE type = 'table' AND
+                       name NOT LIKE 'sqlite_%' AND
+                       name <> 'moz_deletion_log'
+                 ORDER BY name",
+                &[],
+                |row| row.get::<_, String>(0),
+            )
+            .expect("should get tables");
+        let counts = tables
+            .into_iter()
+            .map(|table| {
+                let count = conn
+                    .query_one::<i64>(&format!("SELECT COUNT(*) FROM {}", table))
+                    .expect("should count rows");
+                (table, count)
+            })
+            .collect();
+        let guids = conn
+            .query_rows_and_then_named("SELECT guid FROM moz_bookmarks ORDER BY guid", &[], |row| {
+                row.get::<_, String>(0)
+            })
+            .expect("should get bookmarks");
+        let keys = conn
+            .query_rows_and_then_named("SELECT key FROM moz_meta ORDER BY key", &[], |row| {
+                row.get::<_, String>(0)
+            })
+            .expect("should get meta keys");
+        (counts, guids, keys)
+    }
+
+    #[test]
+    fn test_wipe_local() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://www.example.com/")?;
+        apply_observation(
+            &writer,
+            VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+        )?;
+        insert_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    { "title": "A", "url": url.as_str() },
+                    { "title": "B", "children": [] },
+                ],
+            }),
+        )?;
+        tag_url(&writer, &url, "tag")?;
+        set_keyword(&writer, "kw", &url)?;
+        put_meta(&writer, "app_key", &1)?;
+
+        // Opening a sync connection creates the synced roots.
+        drop(api.open_sync_connection()?);
+
+        // We need the write connection to wipe.
+        match api.wipe_local().unwrap_err().kind() {
+            ErrorKind::ConnectionAlreadyOpen => {}
+            e => panic!("Expected error ConnectionAlreadyOpen, got {:?}", e),
+        }
+        api.close_connection(writer)?;
+        api.wipe_local()?;
+
+        let fresh_api = new_mem_api();
+        let fresh = fresh_api.open_connection(ConnectionType::ReadOnly)?;
+        let reader = api.open_connection(ConnectionType::ReadOnly)?;
+        assert_eq!(snapshot(&reader), snapshot(&fresh));
+
+        // The wipe is logged, with one visit, and two bookmarks.
+        let log = get_deletion_log(&reader, Timestamp(0))?;
+        assert_eq!(
+            log.iter()
+                .map(|entry| (entry.op, entry.count, entry.initiator))
+                .collect::<Vec<_>>(),
+            vec![(DeletionOp::WipeLocal, 3, DeletionInitiator::Api)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_frecency_settings() -> Result<()> {
+        let api = new_mem_api();
+        let settings = FrecencySettings {
+            typed_visit_bonus: 0,
+            ..FrecencySettings::default()
+        };
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        api.set_frecency_settings(settings.clone());
+        // The write connection was checked out, so it should pick up the
+        // new settings the next time it's opened.
+        assert_eq!(writer.frecency_settings(), FrecencySettings::default());
+        api.close_connection(writer)?;
+
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        assert_eq!(writer.frecency_settings(), settings);
+        let reader = api.open_connection(ConnectionType::ReadOnly)?;
+        assert_eq!(reader.frecency_settings(), settings);
+        let syncer = api.open_sync_connection()?;
+        assert_eq!(syncer.frecency_settings(), settings);
+        Ok(())
+    }
+
     #[test]
     fn test_shared_memory() {
         let api = new_mem_api();
@@ -481,4 +769,42 @@ mod tests {
         assert_ne!(1, conn.db.query_one::<i64>("PRAGMA user_version")?);
         Ok(())
     }
+
+    #[test]
+    fn test_sync_state_persisted_on_failure() -> Result<()> {
+        let api = new_mem_api();
+
+        let err = api
+            .do_sync_one(|_, _, _, disk_state, _| {
+                assert!(disk_state.is_none());
+                *disk_state = Some("state".to_string());
+                Err(InvalidPlaceInfo::InvalidGuid.into())
+            })
+            .expect_err("Sync should fail");
+        match err.kind() {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidGuid) => {}
+            kind => panic!("Unexpected error kind: {:?}", kind),
+        }
+
+        // The state should be persisted even though the sync failed...
+        {
+            let conn = api.open_sync_connection()?;
+            assert_eq!(
+                api.get_disk_persisted_state(&conn)?,
+                Some("state".to_string())
+            );
+        }
+
+        // ...and passed to the next sync, which can clear it.
+        let outcome = api.do_sync_one(|_, _, _, disk_state, _| {
+            assert_eq!(disk_state.as_ref().map(String::as_str), Some("state"));
+            *disk_state = None;
+            Ok(Some(Duration::from_secs(60)))
+        })?;
+        assert_eq!(outcome.next_sync_after, Some(Duration::from_secs(60)));
+
+        let conn = api.open_sync_connection()?;
+        assert_eq!(api.get_disk_persisted_state(&conn)?, None);
+        Ok(())
+    }
 }
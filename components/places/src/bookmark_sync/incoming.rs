@@ -4,35 +4,195 @@
 
 use super::record::{
     BookmarkItemRecord, BookmarkRecord, BookmarkRecordId, FolderRecord, LivemarkRecord,
-    QueryRecord, SeparatorRecord,
+    QueryRecord, SeparatorRecord, UnknownFields,
 };
 use super::{SyncedBookmarkKind, SyncedBookmarkValidity};
 use crate::error::*;
+use crate::log_sampler::LogSampler;
 use crate::storage::{
-    bookmarks::maybe_truncate_title,
-    tags::{validate_tag, ValidatedTag},
-    URL_LENGTH_MAX,
+    bookmarks::{
+        maybe_truncate_title,
+        query::{with_exclude_items, PlaceQuery},
+        BookmarkRootGuid, USER_CONTENT_ROOTS,
+    },
+    keywords::normalize_synced_keyword,
+    parse_href,
+    tags::{get_or_insert_tag_id, validate_tag, ValidatedTag},
+    RowId, URL_LENGTH_MAX,
 };
 use crate::types::SyncGuid;
-use rusqlite::Connection;
+use log::Level;
+use rusqlite::{types::ToSql, Connection};
 use sql_support::{self, ConnExt};
-use std::iter;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use sync15::ServerTimestamp;
 use url::Url;
 
-// From Desktop's Ci.nsINavHistoryQueryOptions, but we define it as a str
-// as that's how we use it here.
-const RESULTS_AS_TAG_CONTENTS: &str = "7";
+/// "Auto" fields that Sync copies from the BSO envelope into the payload.
+/// These aren't part of the record, so we don't keep them with its unknown
+/// fields.
+const AUTO_FIELDS: &[&str] = &["sortindex", "ttl"];
+
+/// The number of items that `IncomingApplicator` buffers before writing
+/// them to the mirror.
+const MAX_PENDING_ITEMS: usize = 1000;
+
+/// The number of binding parameters for each item in the statement that
+/// writes pending items to `moz_bookmarks_synced`.
+const PARAMS_PER_ITEM: usize = 17;
+
+/// Serializes the unknown fields from an incoming record for the mirror, or
+/// returns `None` if there aren't any.
+fn unknown_fields_to_json(fields: &UnknownFields) -> Result<Option<String>> {
+    let fields = fields
+        .iter()
+        .filter(|(name, _)| !AUTO_FIELDS.contains(&name.as_str()))
+        .collect::<HashMap<_, _>>();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::to_string(&fields)?))
+}
+
+/// An incoming item that we haven't written to `moz_bookmarks_synced` yet.
+/// This has all the columns that we set for any kind of item; the ones that
+/// a kind doesn't use are `None`.
+struct PendingItem {
+    guid: SyncGuid,
+    parent_guid: Option<SyncGuid>,
+    server_modified: i64,
+    // `None` for tombstones.
+    kind: Option<SyncedBookmarkKind>,
+    date_added: Option<i64>,
+    title: Option<String>,
+    keyword: Option<String>,
+    validity: SyncedBookmarkValidity,
+    url: Option<String>,
+    sortindex: Option<i32>,
+    has_dupe: bool,
+    parent_title: Option<String>,
+    unknown_fields: Option<String>,
+    feed_url: Option<String>,
+    site_url: Option<String>,
+    position: Option<i64>,
+    is_deleted: bool,
+}
+
+impl PendingItem {
+    fn new(guid: &SyncGuid, modified: ServerTimestamp) -> Self {
+        Self {
+            guid: guid.clone(),
+            parent_guid: None,
+            server_modified: modified.as_millis() as i64,
+            kind: None,
+            date_added: None,
+            title: None,
+            keyword: None,
+            validity: SyncedBookmarkValidity::Valid,
+            url: None,
+            sortindex: None,
+            has_dupe: false,
+            parent_title: None,
+            unknown_fields: None,
+            feed_url: None,
+            site_url: None,
+            position: None,
+            is_deleted: false,
+        }
+    }
+}
+
+/// Rows from incoming records that we haven't written yet. We buffer these
+/// so that we can write them with a few multi-row statements, instead of
+/// several statements for each record.
+#[derive(Default)]
+struct PendingWrites {
+    /// URLs to insert into `moz_places`, in the order we saw them.
+    urls: Vec<String>,
+    seen_urls: HashSet<String>,
+    items: Vec<PendingItem>,
+    /// Tag IDs for bookmarks, keyed by bookmark GUID.
+    tags: HashMap<SyncGuid, Vec<RowId>>,
+    /// Children for folders, keyed by folder GUID.
+    children: HashMap<SyncGuid, Vec<BookmarkRecordId>>,
+}
+
+impl PendingWrites {
+    fn add_url(&mut self, url: &Url) {
+        // Inserting the same new URL twice in one statement would add two
+        // places for it, so we dedupe them here.
+        if self.seen_urls.insert(url.as_str().to_owned()) {
+            self.urls.push(url.as_str().to_owned());
+        }
+    }
+
+    fn add_item(&mut self, item: PendingItem) {
+        // Replacing an item in the mirror deletes its tags and children, so
+        // an item that appears more than once only keeps the last ones.
+        self.tags.remove(&item.guid);
+        self.children.remove(&item.guid);
+        self.items.push(item);
+    }
+}
 
 /// Manages the application of incoming records into the moz_bookmarks_synced
 /// and related tables.
+///
+/// Records are buffered, and written in batches. `apply_payload` writes
+/// pending records once there are enough of them, and `finish` writes the
+/// rest. `finish` consumes the applicator, so that records can't be applied
+/// after it.
 pub struct IncomingApplicator<'a> {
     db: &'a Connection,
+    pending: RefCell<PendingWrites>,
+    // A corrupt collection can have many invalid records, so we limit how
+    // many we log.
+    invalid_bookmark_urls: RefCell<LogSampler>,
+    invalid_query_urls: RefCell<LogSampler>,
+    invalid_livemark_urls: RefCell<LogSampler>,
 }
 
 impl<'a> IncomingApplicator<'a> {
     pub fn new(db: &'a Connection) -> Self {
-        Self { db }
+        Self {
+            db,
+            pending: RefCell::new(PendingWrites::default()),
+            invalid_bookmark_urls: RefCell::new(LogSampler::new(
+                "bookmarkUrl",
+                module_path!(),
+                Level::Warn,
+            )),
+            invalid_query_urls: RefCell::new(LogSampler::new(
+                "queryUrl",
+                module_path!(),
+                Level::Warn,
+            )),
+            invalid_livemark_urls: RefCell::new(LogSampler::new(
+                "livemarkUrl",
+                module_path!(),
+                Level::Warn,
+            )),
+        }
+    }
+
+    /// Writes all pending records to the mirror, then logs summaries for
+    /// messages we didn't log while applying records, and returns the number
+    /// of suppressed messages for each kind. This must be called after
+    /// applying the last payload, or the pending records are lost.
+    pub fn finish(self) -> Result<Vec<(&'static str, usize)>> {
+        self.flush()?;
+        Ok([
+            &self.invalid_bookmark_urls,
+            &self.invalid_query_urls,
+            &self.invalid_livemark_urls,
+        ]
+        .iter()
+        .map(|sampler| {
+            let mut sampler = sampler.borrow_mut();
+            (sampler.name(), sampler.finish())
+        })
+        .collect())
     }
 
     pub fn apply_payload(
@@ -44,65 +204,254 @@ impl<'a> IncomingApplicator<'a> {
             self.store_incoming_tombstone(
                 timestamp,
                 BookmarkRecordId::from_payload_id(payload.id).as_guid(),
-            )?;
+            );
         } else {
+            // The sortindex is an "auto" field from the BSO envelope, not
+            // part of the record, so we need to grab it before we convert.
+            let sortindex = payload
+                .data
+                .get("sortindex")
+                .and_then(|v| v.as_i64())
+                .map(|v| v as i32);
             let item: BookmarkItemRecord = payload.into_record()?;
             match item {
-                BookmarkItemRecord::Bookmark(b) => self.store_incoming_bookmark(timestamp, b)?,
+                BookmarkItemRecord::Bookmark(b) => {
+                    self.store_incoming_bookmark(timestamp, sortindex, b)?
+                }
                 BookmarkItemRecord::Query(q) => self.store_incoming_query(timestamp, q)?,
                 BookmarkItemRecord::Folder(f) => self.store_incoming_folder(timestamp, f)?,
                 BookmarkItemRecord::Livemark(l) => self.store_incoming_livemark(timestamp, l)?,
                 BookmarkItemRecord::Separator(s) => self.store_incoming_sep(timestamp, s)?,
             }
         }
+        if self.pending.borrow().items.len() >= MAX_PENDING_ITEMS {
+            self.flush()?;
+        }
         Ok(())
     }
 
-    fn store_incoming_bookmark(&self, modified: ServerTimestamp, b: BookmarkRecord) -> Result<()> {
+    /// Writes all pending records to the mirror.
+    fn flush(&self) -> Result<()> {
+        let mut pending = self.pending.borrow_mut();
+        // Items look up their places, and tags and structure look up their
+        // items, so we need to write them in this order.
+        self.flush_urls(&pending.urls)?;
+        self.flush_items(&pending.items)?;
+        self.flush_tags(&pending.tags)?;
+        self.flush_children(&pending.children)?;
+        *pending = PendingWrites::default();
+        Ok(())
+    }
+
+    fn flush_urls(&self, urls: &[String]) -> Result<()> {
+        sql_support::each_chunk(urls, |chunk, _| -> Result<()> {
+            self.db.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO moz_places(guid, url, url_hash, frecency)
+                     VALUES {}",
+                    sql_support::repeat_display(chunk.len(), ",", |index, f| {
+                        // Each row uses its URL for several columns, so we
+                        // bind it once, and refer to it by index.
+                        write!(
+                            f,
+                            "(IFNULL((SELECT guid FROM moz_places
+                                      WHERE url_hash = hash(?{url}) AND
+                                            url = ?{url}),
+                                     generate_guid()), ?{url}, hash(?{url}),
+                              (CASE substr(?{url}, 1, 6) WHEN 'place:' THEN 0 ELSE -1 END))",
+                            url = index + 1
+                        )
+                    })
+                ),
+                chunk,
+            )?;
+            Ok(())
+        })
+    }
+
+    fn flush_items(&self, items: &[PendingItem]) -> Result<()> {
+        sql_support::each_sized_chunk(
+            items,
+            sql_support::default_max_variable_number() / PARAMS_PER_ITEM,
+            |chunk, _| -> Result<()> {
+                let mut params: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * PARAMS_PER_ITEM);
+                for item in chunk {
+                    params.push(&item.guid);
+                    params.push(&item.parent_guid);
+                    params.push(&item.server_modified);
+                    params.push(&item.kind);
+                    params.push(&item.date_added);
+                    params.push(&item.title);
+                    params.push(&item.keyword);
+                    params.push(&item.validity);
+                    params.push(&item.url);
+                    params.push(&item.sortindex);
+                    params.push(&item.has_dupe);
+                    params.push(&item.parent_title);
+                    params.push(&item.unknown_fields);
+                    params.push(&item.feed_url);
+                    params.push(&item.site_url);
+                    params.push(&item.position);
+                    params.push(&item.is_deleted);
+                }
+                // `REPLACE` uses the default for `NULL`s in `NOT NULL`
+                // columns, so tombstones get the default kind, and items
+                // without a `dateAdded` get 0.
+                self.db.execute(
+                    &format!(
+                        "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified,
+                                                           needsMerge, kind, dateAdded, title,
+                                                           keyword, validity, placeId,
+                                                           serverSortindex, hasDupe, parentTitle,
+                                                           unknownFields, feedURL, siteURL,
+                                                           position, isDeleted)
+                         VALUES {}",
+                        sql_support::repeat_display(chunk.len(), ",", |index, f| {
+                            let p = index * PARAMS_PER_ITEM;
+                            write!(
+                                f,
+                                "(?{}, ?{}, ?{}, 1, ?{}, ?{}, NULLIF(?{}, ''), ?{}, ?{},
+                                  (SELECT id FROM moz_places
+                                   WHERE url_hash = hash(?{url}) AND
+                                         url = ?{url}),
+                                  ?{}, ?{}, NULLIF(?{}, ''), ?{}, ?{}, ?{}, ?{}, ?{})",
+                                p + 1,
+                                p + 2,
+                                p + 3,
+                                p + 4,
+                                p + 5,
+                                p + 6,
+                                p + 7,
+                                p + 8,
+                                p + 10,
+                                p + 11,
+                                p + 12,
+                                p + 13,
+                                p + 14,
+                                p + 15,
+                                p + 16,
+                                p + 17,
+                                url = p + 9,
+                            )
+                        })
+                    ),
+                    &params,
+                )?;
+                Ok(())
+            },
+        )
+    }
+
+    fn flush_tags(&self, tags: &HashMap<SyncGuid, Vec<RowId>>) -> Result<()> {
+        let mut params: Vec<&dyn ToSql> = Vec::new();
+        for (guid, tag_ids) in tags {
+            for tag_id in tag_ids {
+                params.push(guid);
+                params.push(tag_id);
+            }
+        }
+        sql_support::each_sized_chunk(
+            &params,
+            // Round down to an even number, so that each chunk has both
+            // parameters for every row.
+            sql_support::default_max_variable_number() / 2 * 2,
+            |chunk, _| -> Result<()> {
+                // Records can have the same tag more than once, with
+                // different cases, so we ignore repeated tags.
+                self.db.execute(
+                    &format!(
+                        "INSERT OR IGNORE INTO moz_bookmarks_synced_tag_relation(itemId, tagId)
+                         VALUES {}",
+                        sql_support::repeat_display(chunk.len() / 2, ",", |_, f| {
+                            write!(
+                                f,
+                                "((SELECT id FROM moz_bookmarks_synced
+                                   WHERE guid = ?),
+                                  ?)"
+                            )
+                        })
+                    ),
+                    chunk,
+                )?;
+                Ok(())
+            },
+        )
+    }
+
+    fn flush_children(&self, children: &HashMap<SyncGuid, Vec<BookmarkRecordId>>) -> Result<()> {
+        // `flush_items` already replaced the mirror rows for these folders,
+        // which cascades to their old structure rows, so children that a
+        // folder no longer lists don't linger.
+        let mut rows = Vec::new();
+        for (parent_guid, child_record_ids) in children {
+            for (position, child_record_id) in child_record_ids.iter().enumerate() {
+                rows.push((child_record_id.as_guid(), parent_guid, position));
+            }
+        }
+        sql_support::each_sized_chunk(
+            &rows,
+            sql_support::default_max_variable_number() / 2,
+            |chunk, _| -> Result<()> {
+                let mut params: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * 2);
+                for (child_guid, parent_guid, _) in chunk {
+                    params.push(child_guid);
+                    params.push(parent_guid);
+                }
+                self.db.execute(
+                    &format!(
+                        "INSERT INTO moz_bookmarks_synced_structure(guid, parentGuid, position)
+                         VALUES {}",
+                        // Builds a fragment like `(?1, ?2, 0), (?3, ?4, 1), ...`,
+                        // where [?1, ?3] are the child GUIDs, [?2, ?4] are
+                        // their parents' GUIDs, and [0, 1] are the children's
+                        // positions in their parents' `children`.
+                        sql_support::repeat_display(chunk.len(), ",", |index, f| {
+                            let (_, _, position) = chunk[index];
+                            write!(f, "(?, ?, {})", position)
+                        })
+                    ),
+                    &params,
+                )?;
+                Ok(())
+            },
+        )
+    }
+
+    fn store_incoming_bookmark(
+        &self,
+        modified: ServerTimestamp,
+        sortindex: Option<i32>,
+        b: BookmarkRecord,
+    ) -> Result<()> {
         let url = match self.maybe_store_href(b.url.as_ref()) {
             Ok(url) => (Some(url.into_string())),
             Err(e) => {
-                log::warn!("Incoming bookmark has an invalid URL: {:?}", e);
+                self.invalid_bookmark_urls.borrow_mut().log(format_args!(
+                    "Incoming bookmark has an invalid URL: {:?}",
+                    e
+                ));
                 None
             }
         };
         let tags = b.tags.iter().map(|t| validate_tag(t));
+        // Keywords are normalized the same way as local keywords, so that
+        // the merger can compare them.
+        let (keyword, keyword_is_original) =
+            normalize_synced_keyword(b.keyword.as_ref().map(String::as_str));
         let validity = if url.is_none() {
             // The bookmark has an invalid URL, so we can't apply it.
             SyncedBookmarkValidity::Replace
-        } else if tags.clone().all(|t| t.is_original()) {
-            // The bookmark has a valid URL and all original tags, so we can
-            // apply it as-is.
+        } else if keyword_is_original && tags.clone().all(|t| t.is_original()) {
+            // The bookmark has a valid URL, and its original keyword and
+            // tags, so we can apply it as-is.
             SyncedBookmarkValidity::Valid
         } else {
-            // The bookmark has a valid URL, but invalid or normalized tags. We
-            // can apply it, but should also reupload it with the new tags.
+            // The bookmark has a valid URL, but an invalid or normalized
+            // keyword or tags. We can apply it, but should also reupload it
+            // with the new keyword and tags.
             SyncedBookmarkValidity::Reupload
         };
-        self.db.execute_named_cached(
-            r#"REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge, kind,
-                                                 dateAdded, title, keyword, validity, placeId)
-               VALUES(:guid, :parentGuid, :serverModified, 1, :kind,
-                      :dateAdded, NULLIF(:title, ""), :keyword, :validity,
-                      CASE WHEN :url ISNULL
-                      THEN NULL
-                      ELSE (SELECT id FROM moz_places
-                            WHERE url_hash = hash(:url) AND
-                            url = :url)
-                      END
-                      )"#,
-            &[
-                (":guid", &b.record_id.as_guid().as_ref()),
-                (":parentGuid", &b.parent_record_id.as_ref().map(BookmarkRecordId::as_guid)),
-                (":serverModified", &(modified.as_millis() as i64)),
-                (":kind", &SyncedBookmarkKind::Bookmark),
-                (":dateAdded", &b.date_added),
-                (":title", &maybe_truncate_title(&b.title)),
-                (":keyword", &b.keyword),
-                (":validity", &validity),
-                (":url", &url),
-            ],
-        )?;
+        let mut tag_ids = Vec::new();
         for t in tags {
             match t {
                 ValidatedTag::Invalid(ref t) => {
@@ -110,106 +459,103 @@ impl<'a> IncomingApplicator<'a> {
                     continue;
                 }
                 ValidatedTag::Normalized(ref t) | ValidatedTag::Original(ref t) => {
-                    self.db.execute_named_cached(
-                        "INSERT OR IGNORE INTO moz_tags(tag, lastModified)
-                         VALUES(:tag, now())",
-                        &[(":tag", t)],
-                    )?;
-                    self.db.execute_named_cached(
-                        "INSERT INTO moz_bookmarks_synced_tag_relation(itemId, tagId)
-                         VALUES((SELECT id FROM moz_bookmarks_synced
-                                 WHERE guid = :guid),
-                                (SELECT id FROM moz_tags
-                                 WHERE tag = :tag))",
-                        &[(":guid", &b.record_id.as_guid().as_ref()), (":tag", t)],
-                    )?;
+                    tag_ids.push(get_or_insert_tag_id(self.db, t)?);
                 }
             };
         }
+        let guid = b.record_id.as_guid();
+        let mut pending = self.pending.borrow_mut();
+        pending.add_item(PendingItem {
+            parent_guid: b.parent_record_id.as_ref().map(|id| id.as_guid().clone()),
+            kind: Some(SyncedBookmarkKind::Bookmark),
+            date_added: b.date_added,
+            title: maybe_truncate_title(&b.title).map(str::to_owned),
+            keyword,
+            validity,
+            url,
+            sortindex,
+            has_dupe: b.has_dupe,
+            parent_title: maybe_truncate_title(&b.parent_title).map(str::to_owned),
+            unknown_fields: unknown_fields_to_json(&b.unknown_fields)?,
+            ..PendingItem::new(guid, modified)
+        });
+        if !tag_ids.is_empty() {
+            pending.tags.insert(guid.clone(), tag_ids);
+        }
         Ok(())
     }
 
     fn store_incoming_folder(&self, modified: ServerTimestamp, f: FolderRecord) -> Result<()> {
-        self.db.execute_named_cached(
-            r#"REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge, kind,
-                                                 dateAdded, title)
-               VALUES(:guid, :parentGuid, :serverModified, 1, :kind,
-                      :dateAdded, NULLIF(:title, ""))"#,
-            &[
-                (":guid", &f.record_id.as_guid().as_ref()),
-                (":parentGuid", &f.parent_record_id.as_ref().map(BookmarkRecordId::as_guid)),
-                (":serverModified", &(modified.as_millis() as i64)),
-                (":kind", &SyncedBookmarkKind::Folder),
-                (":dateAdded", &f.date_added),
-                (":title", &maybe_truncate_title(&f.title)),
-            ],
-        )?;
-        sql_support::each_sized_chunk(
-            &f.children,
-            // -1 because we want to leave an extra binding parameter (`?1`)
-            // for the folder's GUID.
-            sql_support::default_max_variable_number() - 1,
-            |chunk, offset| -> Result<()> {
-                let sql = format!(
-                    "INSERT INTO moz_bookmarks_synced_structure(guid, parentGuid, position)
-                     VALUES {}",
-                    // Builds a fragment like `(?2, ?1, 0), (?3, ?1, 1), ...`,
-                    // where ?1 is the folder's GUID, [?2, ?3] are the first and
-                    // second child GUIDs (SQLite binding parameters index
-                    // from 1), and [0, 1] are the positions. This lets us store
-                    // the folder's children using as few statements as
-                    // possible.
-                    sql_support::repeat_display(chunk.len(), ",", |index, f| {
-                        // Each child's position is its index in `f.children`;
-                        // that is, the `offset` of the current chunk, plus the
-                        // child's `index` within the chunk.
-                        let position = offset + index;
-                        write!(f, "(?{}, ?1, {})", index + 2, position)
-                    })
-                );
-                self.db.execute(
-                    &sql,
-                    iter::once(&f.record_id)
-                        .chain(chunk.iter())
-                        .map(|record_id| record_id.as_guid().as_ref()),
-                )?;
-                Ok(())
-            },
-        )?;
+        if f.record_id.as_guid().as_root() == Some(BookmarkRootGuid::Root) {
+            return self.store_incoming_root(modified, &f);
+        }
+        let guid = f.record_id.as_guid();
+        let mut pending = self.pending.borrow_mut();
+        pending.add_item(PendingItem {
+            parent_guid: f.parent_record_id.as_ref().map(|id| id.as_guid().clone()),
+            kind: Some(SyncedBookmarkKind::Folder),
+            date_added: f.date_added,
+            title: maybe_truncate_title(&f.title).map(str::to_owned),
+            has_dupe: f.has_dupe,
+            parent_title: maybe_truncate_title(&f.parent_title).map(str::to_owned),
+            unknown_fields: unknown_fields_to_json(&f.unknown_fields)?,
+            ..PendingItem::new(guid, modified)
+        });
+        if !f.children.is_empty() {
+            pending.children.insert(guid.clone(), f.children);
+        }
         Ok(())
     }
 
-    fn store_incoming_tombstone(&self, modified: ServerTimestamp, guid: &SyncGuid) -> Result<()> {
+    /// Stores an incoming record for the Places root. Desktop uploads this
+    /// record, but the Places root isn't synced, and the user content roots
+    /// always have the same order locally. Storing its `children` would let
+    /// the merger reorder our roots to match another client's order, and
+    /// flag them for reupload on every sync. So we only check that it lists
+    /// all the user content roots, and otherwise ignore its structure.
+    fn store_incoming_root(&self, modified: ServerTimestamp, f: &FolderRecord) -> Result<()> {
+        let missing_roots = USER_CONTENT_ROOTS
+            .iter()
+            .filter(|root| {
+                !f.children
+                    .iter()
+                    .any(|child| child.as_guid().as_root() == Some(**root))
+            })
+            .count();
+        if missing_roots > 0 {
+            log::warn!(
+                "Incoming Places root is missing {} user content roots",
+                missing_roots
+            );
+        }
         self.db.execute_named_cached(
-            "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge,
-                                               dateAdded, isDeleted)
-             VALUES(:guid, NULL, :serverModified, 1, 0, 1)",
+            "UPDATE moz_bookmarks_synced SET
+               serverModified = :serverModified
+             WHERE guid = :guid",
             &[
-                (":guid", guid),
                 (":serverModified", &(modified.as_millis() as i64)),
+                (":guid", &BookmarkRootGuid::Root.as_guid()),
             ],
         )?;
         Ok(())
     }
 
+    fn store_incoming_tombstone(&self, modified: ServerTimestamp, guid: &SyncGuid) {
+        self.pending.borrow_mut().add_item(PendingItem {
+            is_deleted: true,
+            ..PendingItem::new(guid, modified)
+        });
+    }
+
     fn determine_query_url_and_validity(
         &self,
         q: &QueryRecord,
         url: Url,
     ) -> Result<(Option<Url>, SyncedBookmarkValidity)> {
-        // wow - this  is complex, but markh is struggling to see how to
-        // improve it
-        let (maybe_url, validity) = {
+        let (maybe_url, validity) = match PlaceQuery::from_url(&url) {
             // If the URL has `type={RESULTS_AS_TAG_CONTENTS}` then we
             // rewrite the URL as `place:tag=...`
-            // Sadly we can't use `url.query_pairs()` here as the format of
-            // the url is, eg, `place:type=7` - ie, the "params" are actually
-            // the path portion of the URL.
-            let parse = url::form_urlencoded::parse(&url.path().as_bytes());
-            if parse
-                .clone()
-                .any(|(k, v)| k == "type" && v == RESULTS_AS_TAG_CONTENTS)
-            {
+            Some(query) if query.results_as_tag_contents => {
                 if let Some(tag_folder_name) = &q.tag_folder_name {
                     validate_tag(tag_folder_name)
                         .ensure_valid()
@@ -219,151 +565,150 @@ impl<'a> IncomingApplicator<'a> {
                 } else {
                     (None, SyncedBookmarkValidity::Replace)
                 }
-            } else {
-                // If we have `folder=...` the folder value is a row_id
-                // from desktop, so useless to us - so we append `&excludeItems=1`
-                // if it isn't already there.
-                if parse.clone().any(|(k, _)| k == "folder") {
-                    if parse.clone().any(|(k, v)| k == "excludeItems" && v == "1") {
-                        (Some(url), SyncedBookmarkValidity::Valid)
-                    } else {
-                        // need to add excludeItems, and I guess we should do
-                        // it properly without resorting to string manipulation...
-                        let tail = url::form_urlencoded::Serializer::new(String::new())
-                            .extend_pairs(parse.clone())
-                            .append_pair("excludeItems", "1")
-                            .finish();
-                        (
-                            Some(Url::parse(&format!("place:{}", tail))?),
-                            SyncedBookmarkValidity::Reupload,
-                        )
-                    }
-                } else {
-                    // it appears to be fine!
-                    (Some(url), SyncedBookmarkValidity::Valid)
-                }
             }
+            // If we have `folder=...` the folder value is a row_id
+            // from desktop, so useless to us - so we append `&excludeItems=1`
+            // if it isn't already there.
+            Some(query) if query.has_folder() && !query.exclude_items => (
+                Some(with_exclude_items(&url)?),
+                SyncedBookmarkValidity::Reupload,
+            ),
+            // it appears to be fine!
+            _ => (Some(url), SyncedBookmarkValidity::Valid),
         };
         Ok(match self.maybe_store_url(maybe_url) {
             Ok(url) => (Some(url), validity),
             Err(e) => {
-                log::warn!("query {} has invalid URL: {:?}", q.record_id.as_guid(), e);
+                self.invalid_query_urls.borrow_mut().log(format_args!(
+                    "query {} has invalid URL: {:?}",
+                    q.record_id.as_guid(),
+                    e
+                ));
                 (None, SyncedBookmarkValidity::Replace)
             }
         })
     }
 
     fn store_incoming_query(&self, modified: ServerTimestamp, q: QueryRecord) -> Result<()> {
-        let (url, validity) = match q.url.as_ref().and_then(|href| Url::parse(href).ok()) {
+        let (url, validity) = match q.url.as_ref().and_then(|href| parse_href(href).ok()) {
             Some(url) => self.determine_query_url_and_validity(&q, url)?,
             None => {
-                log::warn!("query {} has invalid URL", q.record_id.as_guid(),);
+                self.invalid_query_urls.borrow_mut().log(format_args!(
+                    "query {} has invalid URL",
+                    q.record_id.as_guid()
+                ));
                 (None, SyncedBookmarkValidity::Replace)
             }
         };
 
-        self.db.execute_named_cached(
-            r#"REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge, kind,
-                                                 dateAdded, title, validity, placeId)
-               VALUES(:guid, :parentGuid, :serverModified, 1, :kind,
-                      :dateAdded, NULLIF(:title, ""), :validity,
-                      (SELECT id FROM moz_places
-                            WHERE url_hash = hash(:url) AND
-                            url = :url
-                      )
-                     )"#,
-            &[
-                (":guid", &q.record_id.as_guid().as_ref()),
-                (":parentGuid", &q.parent_record_id.as_ref().map(BookmarkRecordId::as_guid)),
-                (":serverModified", &(modified.as_millis() as i64)),
-                (":kind", &SyncedBookmarkKind::Query),
-                (":dateAdded", &q.date_added),
-                (":title", &maybe_truncate_title(&q.title)),
-                (":validity", &validity),
-                (":url", &url.map(Url::into_string))
-            ],
-        )?;
+        self.pending.borrow_mut().add_item(PendingItem {
+            parent_guid: q.parent_record_id.as_ref().map(|id| id.as_guid().clone()),
+            kind: Some(SyncedBookmarkKind::Query),
+            date_added: q.date_added,
+            title: maybe_truncate_title(&q.title).map(str::to_owned),
+            validity,
+            url: url.map(Url::into_string),
+            has_dupe: q.has_dupe,
+            parent_title: maybe_truncate_title(&q.parent_title).map(str::to_owned),
+            unknown_fields: unknown_fields_to_json(&q.unknown_fields)?,
+            ..PendingItem::new(q.record_id.as_guid(), modified)
+        });
         Ok(())
     }
 
     fn store_incoming_livemark(&self, modified: ServerTimestamp, l: LivemarkRecord) -> Result<()> {
         // livemarks don't store a reference to the place, so we validate it manually.
-        fn validate_href(h: Option<String>, guid: &SyncGuid, what: &str) -> Option<String> {
+        fn validate_href(
+            h: Option<String>,
+            guid: &SyncGuid,
+            what: &str,
+            sampler: &mut LogSampler,
+        ) -> Option<String> {
             match h {
-                Some(h) => match Url::parse(&h) {
+                Some(h) => match parse_href(&h) {
                     Ok(url) => {
                         let s = url.to_string();
                         if s.len() > URL_LENGTH_MAX {
-                            log::warn!("Livemark {} has a {} URL which is too long", &guid, what);
+                            sampler.log(format_args!(
+                                "Livemark {} has a {} URL which is too long",
+                                &guid, what
+                            ));
                             None
                         } else {
                             Some(s)
                         }
                     }
                     Err(e) => {
-                        log::warn!("Livemark {} has an invalid {} URL: {:?}", &guid, what, e);
+                        sampler.log(format_args!(
+                            "Livemark {} has an invalid {} URL: {:?}",
+                            &guid, what, e
+                        ));
                         None
                     }
                 },
                 None => {
-                    log::warn!("Livemark {} has no {} URL", &guid, what);
+                    sampler.log(format_args!("Livemark {} has no {} URL", &guid, what));
                     None
                 }
             }
         }
-        let feed_url = validate_href(l.feed_url, &l.record_id.as_guid(), "feed");
-        let site_url = validate_href(l.site_url, &l.record_id.as_guid(), "site");
-        let validity = if feed_url.is_some() {
-            SyncedBookmarkValidity::Valid
+        let mut sampler = self.invalid_livemark_urls.borrow_mut();
+        let feed_url = validate_href(l.feed_url, &l.record_id.as_guid(), "feed", &mut sampler);
+        let site_url = validate_href(l.site_url, &l.record_id.as_guid(), "site", &mut sampler);
+        drop(sampler);
+        // Livemarks aren't supported anymore, so we convert them to
+        // bookmarks pointing at the site URL, or the feed URL if the
+        // livemark doesn't have a site. The converted bookmark replaces the
+        // livemark on the server. If neither URL is usable, we flag the
+        // livemark for replacement, and the merger deletes it.
+        let url = match site_url.as_ref().or_else(|| feed_url.as_ref()) {
+            Some(url) => Some(self.maybe_store_href(Some(url))?.into_string()),
+            None => None,
+        };
+        let (kind, validity) = if url.is_some() {
+            (
+                SyncedBookmarkKind::Bookmark,
+                SyncedBookmarkValidity::Reupload,
+            )
         } else {
-            SyncedBookmarkValidity::Replace
+            (
+                SyncedBookmarkKind::Livemark,
+                SyncedBookmarkValidity::Replace,
+            )
         };
-        self.db.execute_named_cached(
-            "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge, kind,
-                                               dateAdded, title, feedURL, siteURL, validity)
-             VALUES(:guid, :parentGuid, :serverModified, 1, :kind,
-                    :dateAdded, :title, :feedUrl, :siteUrl, :validity)",
-            &[
-                (":guid", &l.record_id.as_guid().as_ref()),
-                (
-                    ":parentGuid",
-                    &l.parent_record_id.as_ref().map(BookmarkRecordId::as_guid),
-                ),
-                (":serverModified", &(modified.as_millis() as i64)),
-                (":kind", &SyncedBookmarkKind::Livemark),
-                (":dateAdded", &l.date_added),
-                (":title", &l.title),
-                (":feedUrl", &feed_url),
-                (":siteUrl", &site_url),
-                (":validity", &validity),
-            ],
-        )?;
+        self.pending.borrow_mut().add_item(PendingItem {
+            parent_guid: l.parent_record_id.as_ref().map(|id| id.as_guid().clone()),
+            kind: Some(kind),
+            date_added: l.date_added,
+            title: maybe_truncate_title(&l.title).map(str::to_owned),
+            validity,
+            url,
+            has_dupe: l.has_dupe,
+            parent_title: maybe_truncate_title(&l.parent_title).map(str::to_owned),
+            feed_url,
+            site_url,
+            ..PendingItem::new(l.record_id.as_guid(), modified)
+        });
         Ok(())
     }
 
     fn store_incoming_sep(&self, modified: ServerTimestamp, s: SeparatorRecord) -> Result<()> {
-        self.db.execute_named_cached(
-            "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge, kind,
-                                               dateAdded)
-             VALUES(:guid, :parentGuid, :serverModified, 1, :kind,
-                    :dateAdded)",
-            &[
-                (":guid", &s.record_id.as_guid().as_ref()),
-                (
-                    ":parentGuid",
-                    &s.parent_record_id.as_ref().map(BookmarkRecordId::as_guid),
-                ),
-                (":serverModified", &(modified.as_millis() as i64)),
-                (":kind", &SyncedBookmarkKind::Separator),
-                (":dateAdded", &s.date_added),
-            ],
-        )?;
+        self.pending.borrow_mut().add_item(PendingItem {
+            parent_guid: s.parent_record_id.as_ref().map(|id| id.as_guid().clone()),
+            kind: Some(SyncedBookmarkKind::Separator),
+            date_added: s.date_added,
+            has_dupe: s.has_dupe,
+            parent_title: maybe_truncate_title(&s.parent_title).map(str::to_owned),
+            unknown_fields: unknown_fields_to_json(&s.unknown_fields)?,
+            position: s.position,
+            ..PendingItem::new(s.record_id.as_guid(), modified)
+        });
         Ok(())
     }
 
     fn maybe_store_href(&self, href: Option<&String>) -> Result<Url> {
         if let Some(href) = href {
-            self.maybe_store_url(Some(Url::parse(href)?))
+            self.maybe_store_url(Some(parse_href(href)?))
         } else {
             self.maybe_store_url(None)
         }
@@ -374,15 +719,7 @@ impl<'a> IncomingApplicator<'a> {
             if url.as_str().len() > URL_LENGTH_MAX {
                 return Err(ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::UrlTooLong).into());
             }
-            self.db.execute_named_cached(
-                "INSERT OR IGNORE INTO moz_places(guid, url, url_hash, frecency)
-                 VALUES(IFNULL((SELECT guid FROM moz_places
-                                WHERE url_hash = hash(:url) AND
-                                      url = :url),
-                        generate_guid()), :url, hash(:url),
-                        (CASE substr(:url, 1, 6) WHEN 'place:' THEN 0 ELSE -1 END))",
-                &[(":url", &url.as_str())],
-            )?;
+            self.pending.borrow_mut().add_url(&url);
             Ok(url)
         } else {
             Err(ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoUrl).into())
@@ -393,10 +730,13 @@ impl<'a> IncomingApplicator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::{test::new_mem_api, PlacesApi, SyncConn};
-    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::api::places_api::{ConnectionType, PlacesApi, SyncConn};
+    use crate::bookmark_sync::store::{BookmarksStore, Merger};
+    use crate::db::PlacesDb;
+    use crate::testing::new_mem_api;
 
-    use crate::bookmark_sync::tests::SyncedBookmarkItem;
+    use crate::testing::SyncedBookmarkItem;
+    use dogear::{Guid, Store};
     use pretty_assertions::assert_eq;
     use serde_json::{json, Value};
     use sync15::Payload;
@@ -424,6 +764,9 @@ mod tests {
             }
             _ => panic!("unexpected json value"),
         }
+        applicator
+            .finish()
+            .expect("Should write pending incoming records");
 
         conn
     }
@@ -466,6 +809,107 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apply_bookmark_keywords() {
+        let bookmark = |keyword: &str| {
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "unfiled",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "keyword": keyword,
+            })
+        };
+        let expected = |validity, keyword| {
+            let mut item = SyncedBookmarkItem::new();
+            item.validity(validity)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
+                .title(Some("A"))
+                .url(Some("http://example.com/a"))
+                .keyword(keyword);
+            item
+        };
+        // Keywords are trimmed and lowercased, including non-ASCII
+        // characters, and we reupload the normalized keyword.
+        assert_incoming_creates_mirror_item(
+            bookmark(" \u{c9}cole "),
+            &expected(SyncedBookmarkValidity::Reupload, Some("\u{e9}cole")),
+        );
+        // Keywords with whitespace are invalid, so we drop them.
+        assert_incoming_creates_mirror_item(
+            bookmark("a b"),
+            &expected(SyncedBookmarkValidity::Reupload, None),
+        );
+        // Empty keywords mean the bookmark doesn't have one.
+        assert_incoming_creates_mirror_item(
+            bookmark(" "),
+            &expected(SyncedBookmarkValidity::Valid, None),
+        );
+    }
+
+    #[test]
+    fn test_apply_bookmark_repeated_tags() {
+        assert_incoming_creates_mirror_item(
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "unfiled",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "tags": ["foo", "Foo", " FOO ", "bar", "bar"],
+            }),
+            &SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
+                .title(Some("A"))
+                .url(Some("http://example.com/a"))
+                .tags(vec!["foo".into(), "bar".into()]),
+        );
+    }
+
+    #[test]
+    fn test_apply_bookmark_invalid_url() {
+        let api = new_mem_api();
+        let conn = apply_incoming(
+            &api,
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "unfiled",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "not a url",
+            }),
+        );
+        let got = SyncedBookmarkItem::get(&conn, &"bookmarkAAAA".into())
+            .expect("should work")
+            .expect("item should exist");
+        assert_eq!(
+            got,
+            *SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Replace)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
+                .title(Some("A"))
+                .url(None)
+        );
+        let place_id = conn
+            .query_one::<Option<i64>>(
+                "SELECT placeId FROM moz_bookmarks_synced
+                 WHERE guid = 'bookmarkAAAA'",
+            )
+            .expect("should work");
+        assert_eq!(place_id, None);
+    }
+
     #[test]
     fn test_apply_folder() {
         let children = (1..sql_support::default_max_variable_number() * 2)
@@ -482,6 +926,7 @@ mod tests {
                 .iter()
                 .map(|guid| BookmarkRecordId::from(guid.clone()))
                 .collect(),
+            unknown_fields: UnknownFields::default(),
         }))
         .expect("Should serialize folder with children");
         assert_incoming_creates_mirror_item(
@@ -495,6 +940,184 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apply_folder_shrinking_children() {
+        let api = new_mem_api();
+        let folder_json = |children: &[&str]| {
+            json!({
+                "id": "folderAAAAAA",
+                "type": "folder",
+                "parentid": "unfiled",
+                "parentName": "unfiled",
+                "dateAdded": 0,
+                "title": "A",
+                "children": children,
+            })
+        };
+        let bookmark_json = |guid: &str, parent_guid: &str| {
+            json!({
+                "id": guid,
+                "type": "bookmark",
+                "parentid": parent_guid,
+                "parentName": parent_guid,
+                "dateAdded": 0,
+                "title": guid,
+                "bmkUri": format!("http://example.com/{}", guid),
+            })
+        };
+        let children = (0..10)
+            .map(|i| format!("bookmark{:04}", i))
+            .collect::<Vec<_>>();
+        let all_children = children.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut records = vec![folder_json(&all_children)];
+        records.extend(children.iter().map(|c| bookmark_json(c, "folderAAAAAA")));
+        drop(apply_incoming(&api, Value::Array(records)));
+
+        // Move all but the first 3 children to unfiled. Applying the folder
+        // with fewer children should remove the structure rows for the
+        // children it no longer lists.
+        let (kept, moved) = all_children.split_at(3);
+        let mut unfiled_children = vec!["folderAAAAAA"];
+        unfiled_children.extend_from_slice(moved);
+        let mut records = vec![
+            folder_json(kept),
+            json!({
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "Unfiled Bookmarks",
+                "children": unfiled_children,
+            }),
+        ];
+        records.extend(moved.iter().map(|c| bookmark_json(c, "unfiled")));
+        let conn = apply_incoming(&api, Value::Array(records));
+
+        let interrupt_scope = conn.begin_interrupt_scope();
+        let store = BookmarksStore::new(&conn, &interrupt_scope);
+        let merger = Merger::new(&store, ServerTimestamp(0.0));
+        let tree = merger
+            .fetch_remote_tree()
+            .expect("should fetch remote tree");
+        let child_guids = |guid: &str| {
+            tree.node_for_guid(&guid.into())
+                .expect("should exist")
+                .children()
+                .map(|node| node.guid.clone())
+                .collect::<Vec<Guid>>()
+        };
+        assert_eq!(
+            child_guids("folderAAAAAA"),
+            kept.iter().map(|&c| c.into()).collect::<Vec<Guid>>()
+        );
+        assert_eq!(
+            child_guids(BookmarkRootGuid::Unfiled.as_str()),
+            unfiled_children
+                .iter()
+                .map(|&c| c.into())
+                .collect::<Vec<Guid>>()
+        );
+    }
+
+    #[test]
+    fn test_apply_many_records() {
+        // A first sync can download thousands of records, so this catches
+        // pathological slowdowns in staging them.
+        let guids = (0..4999)
+            .map(|i| SyncGuid(format!("{:B>12}", i)))
+            .collect::<Vec<_>>();
+        let mut records = vec![json!({
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "unfiled",
+            "parentName": "unfiled",
+            "title": "A",
+            "children": guids.iter().map(|guid| &guid.0).collect::<Vec<_>>(),
+        })];
+        for (i, guid) in guids.iter().enumerate() {
+            let tags = if i % 10 == 0 { vec!["ten"] } else { vec![] };
+            records.push(json!({
+                "id": &guid.0,
+                "type": "bookmark",
+                "parentid": "folderAAAAAA",
+                "parentName": "A",
+                "title": format!("Bookmark {}", i),
+                // Every URL is used by two bookmarks.
+                "bmkUri": format!("http://example.com/{}", i / 2),
+                "tags": tags,
+            }));
+        }
+
+        let mut conn =
+            PlacesDb::open_in_memory(ConnectionType::Sync).expect("should open connection");
+        conn.set_sql_tracing(true);
+        let applicator = IncomingApplicator::new(&conn);
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            applicator
+                .apply_payload(payload, ServerTimestamp(0.0))
+                .expect("Should apply incoming records");
+        }
+        applicator
+            .finish()
+            .expect("Should write pending incoming records");
+
+        // Staging writes records in batches, so the number of writes should
+        // grow with the number of flushes, not the number of records. The
+        // only statements we run for each record are tag lookups.
+        let flushes = 5000 / MAX_PENDING_ITEMS + 1;
+        let writes: u64 = conn
+            .sql_stats()
+            .iter()
+            .filter(|stats| !stats.fingerprint.starts_with("SELECT"))
+            .map(|stats| stats.count)
+            .sum();
+        assert!(
+            writes <= (flushes * 50) as u64,
+            "Staging 5000 records in {} flushes ran {} writes",
+            flushes,
+            writes
+        );
+
+        let bookmarks: i64 = conn
+            .query_one(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced
+                 WHERE parentGuid = 'folderAAAAAA' AND
+                       placeId NOT NULL",
+            )
+            .expect("Should count bookmarks");
+        assert_eq!(bookmarks, 4999);
+        let children: i64 = conn
+            .query_one(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced_structure
+                 WHERE parentGuid = 'folderAAAAAA'",
+            )
+            .expect("Should count children");
+        assert_eq!(children, 4999);
+        let places: i64 = conn
+            .query_one("SELECT COUNT(*) FROM moz_places")
+            .expect("Should count places");
+        assert_eq!(places, 2500);
+        let tagged: i64 = conn
+            .query_one("SELECT COUNT(*) FROM moz_bookmarks_synced_tag_relation")
+            .expect("Should count tagged bookmarks");
+        assert_eq!(tagged, 500);
+
+        let last = SyncedBookmarkItem::get(&conn, &guids[4998])
+            .expect("should work")
+            .expect("item should exist");
+        assert_eq!(
+            last,
+            *SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Valid)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&"folderAAAAAA".into()))
+                .title(Some("Bookmark 4998"))
+                .url(Some("http://example.com/2499"))
+        );
+    }
+
     #[test]
     fn test_apply_tombstone() {
         assert_incoming_creates_mirror_item(
@@ -636,13 +1259,31 @@ mod tests {
                 .validity(SyncedBookmarkValidity::Valid)
                 .kind(SyncedBookmarkKind::Separator)
                 .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
-                .needs_merge(true),
+                .needs_merge(true)
+                .position(None),
+        );
+        // Older clients also upload the separator's position.
+        assert_incoming_creates_mirror_item(
+            json!({
+                "id": "sep2________",
+                "type": "separator",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "pos": 3,
+            }),
+            &SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Valid)
+                .kind(SyncedBookmarkKind::Separator)
+                .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
+                .needs_merge(true)
+                .position(Some(3)),
         );
     }
 
     #[test]
     fn test_apply_livemark() {
-        // A livemark with missing URLs
+        // A livemark with missing URLs can't be converted, so it's flagged
+        // for replacement.
         assert_incoming_creates_mirror_item(
             json!({
                 "id": "livemark1___",
@@ -655,11 +1296,12 @@ mod tests {
                 .kind(SyncedBookmarkKind::Livemark)
                 .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
                 .needs_merge(true)
+                .url(None)
                 .feed_url(None)
                 .site_url(None),
         );
-        // Valid feed_url but invalid site_url is considered "valid", but the
-        // invalid URL is dropped.
+        // A valid feed URL but invalid site URL is converted to a bookmark
+        // for the feed, and the invalid URL is dropped.
         assert_incoming_creates_mirror_item(
             json!({
                 "id": "livemark1___",
@@ -670,14 +1312,15 @@ mod tests {
                 "siteUri": "foo"
             }),
             &SyncedBookmarkItem::new()
-                .validity(SyncedBookmarkValidity::Valid)
-                .kind(SyncedBookmarkKind::Livemark)
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Bookmark)
                 .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
                 .needs_merge(true)
+                .url(Some("http://example.com/"))
                 .feed_url(Some("http://example.com/"))
                 .site_url(None),
         );
-        // Everything valid
+        // Everything valid, so we convert it to a bookmark for the site.
         assert_incoming_creates_mirror_item(
             json!({
                 "id": "livemark1___",
@@ -688,10 +1331,11 @@ mod tests {
                 "siteUri": "http://example.com/something"
             }),
             &SyncedBookmarkItem::new()
-                .validity(SyncedBookmarkValidity::Valid)
-                .kind(SyncedBookmarkKind::Livemark)
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Bookmark)
                 .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
                 .needs_merge(true)
+                .url(Some("http://example.com/something"))
                 .feed_url(Some("http://example.com/"))
                 .site_url(Some("http://example.com/something")),
         );
@@ -6,15 +6,15 @@ mod incoming;
 pub mod record;
 pub mod store;
 
-#[cfg(test)]
-mod tests;
-
 use crate::db::PlacesDb;
 use crate::error::*;
 use crate::storage::bookmarks::{BookmarkRootGuid, USER_CONTENT_ROOTS};
-use crate::types::SyncGuid;
+use crate::types::{BookmarkType, SyncGuid};
 use rusqlite::types::{ToSql, ToSqlOutput};
 use rusqlite::Result as RusqliteResult;
+use rusqlite::Row;
+use sql_support::ConnExt;
+use std::convert::TryFrom;
 
 /// Sets up the syncable roots. All items in `moz_bookmarks_synced` descend
 /// from these roots.
@@ -60,6 +60,82 @@ pub fn create_synced_bookmark_roots(db: &PlacesDb) -> Result<()> {
     Ok(())
 }
 
+/// An item in `moz_bookmarks_synced`, as we last downloaded or uploaded it.
+/// This is for inspecting the synced tree when diagnosing sync problems.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncedItemInfo {
+    pub guid: SyncGuid,
+    /// The `parentid` from the record.
+    pub parent_guid: Option<SyncGuid>,
+    /// The `parentName` from the record. For orphans, this is the only hint
+    /// about which folder the item came from.
+    pub parent_title: Option<String>,
+    /// `None` for tombstones.
+    pub kind: Option<SyncedBookmarkKind>,
+    pub title: Option<String>,
+    /// The server modified time, in milliseconds.
+    pub server_modified: i64,
+    pub needs_merge: bool,
+    pub is_deleted: bool,
+}
+
+impl SyncedItemInfo {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let kind = match row.get::<_, i64>("kind")? {
+            -1 => None,
+            kind => Some(SyncedBookmarkKind::from_u8(kind as u8)?),
+        };
+        Ok(Self {
+            guid: row.get("guid")?,
+            parent_guid: row.get("parentGuid")?,
+            parent_title: row.get("parentTitle")?,
+            kind,
+            title: row.get("title")?,
+            server_modified: row.get("serverModified")?,
+            needs_merge: row.get("needsMerge")?,
+            is_deleted: row.get("isDeleted")?,
+        })
+    }
+}
+
+const SYNCED_ITEM_COLUMNS: &str = "v.guid, v.parentGuid, v.parentTitle, v.kind, v.title,
+                                   v.serverModified, v.needsMerge, v.isDeleted";
+
+/// Returns the synced item with the given GUID, if we have one.
+pub fn get_synced_item(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<SyncedItemInfo>> {
+    Ok(db.try_query_row(
+        &format!(
+            "SELECT {columns} FROM moz_bookmarks_synced v
+             WHERE v.guid = :guid",
+            columns = SYNCED_ITEM_COLUMNS,
+        ),
+        &[(":guid", guid)],
+        SyncedItemInfo::from_row,
+        true,
+    )?)
+}
+
+/// Returns all synced items whose parent folder we haven't seen, sorted by
+/// GUID. Merging moves these orphans to the unfiled folder, and their
+/// `parent_title` tells the user where they were.
+pub fn get_synced_orphans(db: &PlacesDb) -> Result<Vec<SyncedItemInfo>> {
+    db.query_rows_and_then_named(
+        &format!(
+            "SELECT {columns} FROM moz_bookmarks_synced v
+             WHERE NOT v.isDeleted AND
+                   v.guid <> '{root_guid}' AND
+                   NOT EXISTS(SELECT 1 FROM moz_bookmarks_synced p
+                              WHERE p.guid = v.parentGuid AND
+                                    NOT p.isDeleted)
+             ORDER BY v.guid",
+            columns = SYNCED_ITEM_COLUMNS,
+            root_guid = BookmarkRootGuid::Root.as_str(),
+        ),
+        &[],
+        SyncedItemInfo::from_row,
+    )
+}
+
 /// Synced item kinds. These are stored in `moz_bookmarks_synced.kind` and match
 /// the definitions in `mozISyncedBookmarksMerger`.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -73,8 +149,39 @@ pub enum SyncedBookmarkKind {
 }
 
 impl SyncedBookmarkKind {
+    /// All synced item kinds, in discriminant order. Mostly useful for tests
+    /// that need to check every kind.
+    pub const ALL: [SyncedBookmarkKind; 5] = [
+        SyncedBookmarkKind::Bookmark,
+        SyncedBookmarkKind::Query,
+        SyncedBookmarkKind::Folder,
+        SyncedBookmarkKind::Livemark,
+        SyncedBookmarkKind::Separator,
+    ];
+
     #[inline]
     pub fn from_u8(v: u8) -> Result<Self> {
+        SyncedBookmarkKind::try_from(v)
+    }
+
+    /// Returns the synced kind for a local item with the given type and URL.
+    /// Queries are bookmarks with a `place:` URL. This must match the
+    /// `ItemKindFragment` SQL expression in `store.rs`.
+    pub fn from_local_type(typ: BookmarkType, url: Option<&str>) -> Self {
+        match typ {
+            BookmarkType::Bookmark => match url {
+                Some(url) if url.starts_with("place:") => SyncedBookmarkKind::Query,
+                _ => SyncedBookmarkKind::Bookmark,
+            },
+            BookmarkType::Folder => SyncedBookmarkKind::Folder,
+            BookmarkType::Separator => SyncedBookmarkKind::Separator,
+        }
+    }
+}
+
+impl TryFrom<u8> for SyncedBookmarkKind {
+    type Error = Error;
+    fn try_from(v: u8) -> Result<Self> {
         match v {
             1 => Ok(SyncedBookmarkKind::Bookmark),
             2 => Ok(SyncedBookmarkKind::Query),
@@ -98,6 +205,31 @@ impl From<SyncedBookmarkKind> for dogear::Kind {
     }
 }
 
+impl From<dogear::Kind> for SyncedBookmarkKind {
+    fn from(kind: dogear::Kind) -> SyncedBookmarkKind {
+        match kind {
+            dogear::Kind::Bookmark => SyncedBookmarkKind::Bookmark,
+            dogear::Kind::Query => SyncedBookmarkKind::Query,
+            dogear::Kind::Folder => SyncedBookmarkKind::Folder,
+            dogear::Kind::Livemark => SyncedBookmarkKind::Livemark,
+            dogear::Kind::Separator => SyncedBookmarkKind::Separator,
+        }
+    }
+}
+
+/// Converts a synced kind to the local type we store it as. Local Places
+/// doesn't have separate types for queries and livemarks, so queries fall back
+/// to bookmarks, and livemarks to folders.
+impl From<SyncedBookmarkKind> for BookmarkType {
+    fn from(kind: SyncedBookmarkKind) -> BookmarkType {
+        match kind {
+            SyncedBookmarkKind::Bookmark | SyncedBookmarkKind::Query => BookmarkType::Bookmark,
+            SyncedBookmarkKind::Folder | SyncedBookmarkKind::Livemark => BookmarkType::Folder,
+            SyncedBookmarkKind::Separator => BookmarkType::Separator,
+        }
+    }
+}
+
 impl ToSql for SyncedBookmarkKind {
     fn to_sql(&self) -> RusqliteResult<ToSqlOutput<'_>> {
         Ok(ToSqlOutput::from(*self as u8))
@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
 use crate::{storage::bookmarks::BookmarkRootGuid, types::SyncGuid};
 use serde::{
@@ -134,7 +134,12 @@ impl<'de> Deserialize<'de> for BookmarkRecordId {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+/// Fields in an incoming record that we don't understand, like ones added
+/// by newer clients. We store these in the mirror, and include them when we
+/// reupload the item, so that we don't strip other clients' data.
+pub type UnknownFields = HashMap<String, serde_json::Value>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BookmarkRecord {
     // Note that `SyncGuid` does not check for validity, which is what we
@@ -165,6 +170,9 @@ pub struct BookmarkRecord {
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tags: Vec<String>,
+
+    #[serde(flatten)]
+    pub unknown_fields: UnknownFields,
 }
 
 impl From<BookmarkRecord> for BookmarkItemRecord {
@@ -174,7 +182,7 @@ impl From<BookmarkRecord> for BookmarkItemRecord {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct QueryRecord {
     #[serde(rename = "id")]
@@ -200,6 +208,9 @@ pub struct QueryRecord {
 
     #[serde(rename = "folderName", skip_serializing_if = "Option::is_none")]
     pub tag_folder_name: Option<String>,
+
+    #[serde(flatten)]
+    pub unknown_fields: UnknownFields,
 }
 
 impl From<QueryRecord> for BookmarkItemRecord {
@@ -209,7 +220,7 @@ impl From<QueryRecord> for BookmarkItemRecord {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FolderRecord {
     #[serde(rename = "id")]
@@ -232,6 +243,9 @@ pub struct FolderRecord {
 
     #[serde(default)]
     pub children: Vec<BookmarkRecordId>,
+
+    #[serde(flatten)]
+    pub unknown_fields: UnknownFields,
 }
 
 impl From<FolderRecord> for BookmarkItemRecord {
@@ -241,7 +255,7 @@ impl From<FolderRecord> for BookmarkItemRecord {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LivemarkRecord {
     #[serde(rename = "id")]
@@ -267,6 +281,9 @@ pub struct LivemarkRecord {
 
     #[serde(rename = "siteUri", skip_serializing_if = "Option::is_none")]
     pub site_url: Option<String>,
+
+    #[serde(flatten)]
+    pub unknown_fields: UnknownFields,
 }
 
 impl From<LivemarkRecord> for BookmarkItemRecord {
@@ -276,7 +293,7 @@ impl From<LivemarkRecord> for BookmarkItemRecord {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SeparatorRecord {
     #[serde(rename = "id")]
@@ -298,6 +315,9 @@ pub struct SeparatorRecord {
     // position disagreements. Older clients use this for deduping.
     #[serde(rename = "pos", skip_serializing_if = "Option::is_none")]
     pub position: Option<i64>,
+
+    #[serde(flatten)]
+    pub unknown_fields: UnknownFields,
 }
 
 impl From<SeparatorRecord> for BookmarkItemRecord {
@@ -307,7 +327,7 @@ impl From<SeparatorRecord> for BookmarkItemRecord {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum BookmarkItemRecord {
     Bookmark(BookmarkRecord),
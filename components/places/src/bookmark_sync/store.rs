@@ -6,33 +6,49 @@ use super::create_synced_bookmark_roots;
 use super::incoming::IncomingApplicator;
 use super::record::{
     BookmarkItemRecord, BookmarkRecord, BookmarkRecordId, FolderRecord, QueryRecord,
-    SeparatorRecord,
+    SeparatorRecord, UnknownFields,
 };
-use super::{SyncedBookmarkKind, SyncedBookmarkValidity};
+use super::{get_synced_orphans, SyncedBookmarkKind, SyncedBookmarkValidity};
 use crate::api::places_api::ConnectionType;
 use crate::db::{PlacesDb, PlacesTransaction};
 use crate::error::*;
-use crate::frecency::{calculate_frecency, DEFAULT_FRECENCY_SETTINGS};
-use crate::storage::{bookmarks::BookmarkRootGuid, delete_meta, get_meta, put_meta};
+use crate::frecency::calculate_frecency;
+use crate::log_sampler::record_suppressed_logs;
+use crate::storage::{
+    bookmarks::{self, BookmarkRootGuid, USER_CONTENT_ROOTS},
+    delete_internal_meta,
+    deletion_log::{record_deletion, DeletionInitiator, DeletionOp},
+    get_meta,
+    oversized::url_too_long_fragment,
+    put_internal_meta,
+    sync_log::{record_sync, SyncLogEntry},
+};
 use crate::types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
 use dogear::{
     self, Content, Deletion, IntoTree, Item, MergedDescendant, MergedRoot, Tree, UploadReason,
 };
 use rusqlite::{Row, NO_PARAMS};
+use serde_derive::*;
 use sql_support::{self, ConnExt, SqlInterruptScope};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::result;
+use std::time::Duration;
 use sync15::{
     telemetry, CollSyncIds, CollectionRequest, IncomingChangeset, KeyBundle, MemoryCachedState,
-    OutgoingChangeset, Payload, ServerTimestamp, Store, StoreSyncAssociation,
+    OutgoingChangeset, Payload, RequestOrder, ServerTimestamp, Store, StoreSyncAssociation,
     Sync15StorageClientInit,
 };
 pub const LAST_SYNC_META_KEY: &str = "bookmarks_last_sync_time";
 // Note that all engines in this crate should use a *different* meta key
 // for the global sync ID, because engines are reset individually.
-const GLOBAL_SYNCID_META_KEY: &str = "bookmarks_global_sync_id";
-const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
+pub(crate) const GLOBAL_SYNCID_META_KEY: &str = "bookmarks_global_sync_id";
+pub(crate) const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
+// Set after a mobile-first sync that only staged part of the tree, and
+// cleared after the next full sync.
+pub(crate) const PARTIAL_SYNC_META_KEY: &str = "bookmarks_partial_sync";
 
 /// The maximum number of URLs for which to recalculate frecencies at once.
 /// This is a trade-off between write efficiency and transaction time: higher
@@ -40,15 +56,360 @@ const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
 /// blocking writes from other connections.
 const MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK: usize = 400;
 
+/// Local items modified more than this many milliseconds in the future are
+/// likely the result of a skewed clock, so we report them in telemetry.
+const LOCAL_CLOCK_SKEW_THRESHOLD_MILLIS: i64 = 5 * 60 * 1000;
+
+/// The `hasDupe` flag for all outgoing records. Older Desktop clients
+/// dedupe incoming items without this flag against their local items by
+/// content, which can clobber our changes. We've already deduped against
+/// incoming items in the merger, so we always set it.
+const OUTGOING_HAS_DUPE: bool = true;
+
+/// The maximum number of characters of an item's title or URL to include in
+/// an outgoing preview.
+const OUTGOING_PREVIEW_SNIPPET_LENGTH: usize = 64;
+
+/// The maximum number of mobile item IDs to request on a mobile-first sync.
+/// The server limits how many IDs we can ask for at once, and everything
+/// else is downloaded on the next sync anyway.
+const MAX_MOBILE_FIRST_IDS: usize = 100;
+
+/// Which items to favor when we can only download or upload part of the
+/// tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncPriority {
+    /// Download and upload items in whatever order the server and
+    /// database return them.
+    Default,
+    /// Upload mobile items before everything else, since those are what
+    /// the local UI shows. If we already have a complete copy of the
+    /// server's tree, alternate between syncs that only download the mobile
+    /// root and its descendants, and full syncs. A mobile-only sync just
+    /// stages what it downloads: merging part of the tree could duplicate
+    /// or drop items, so we wait for the next full sync to merge and upload
+    /// everything. A first sync always downloads the whole tree.
+    MobileFirst,
+}
+
+impl Default for SyncPriority {
+    fn default() -> Self {
+        SyncPriority::Default
+    }
+}
+
+/// Whether a sync merges and uploads what it downloads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncMode {
+    /// Download, merge, and upload.
+    Full,
+    /// Only download and stage incoming items. Staging is cheap compared
+    /// to merging large trees, so apps with a limited background execution
+    /// budget can sync in this mode, then call `complete_pending_merge`, or
+    /// do a full sync, later.
+    StageOnly,
+}
+
+impl Default for SyncMode {
+    fn default() -> Self {
+        SyncMode::Full
+    }
+}
+
+/// Why an item would be uploaded on the next sync.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutgoingReason {
+    /// The item has never been synced.
+    New,
+    /// The item, or its tombstone, changed locally since the last sync.
+    Changed,
+    /// The item didn't change locally, but it's older than the incoming
+    /// item, so we'd reupload it with the older creation date.
+    Weak,
+}
+
+/// A summary of an item that the next sync would upload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutgoingPreview {
+    pub guid: SyncGuid,
+    /// The kind of item, or `None` for tombstones.
+    pub kind: Option<SyncedBookmarkKind>,
+    /// The start of the item's title, or its URL if it doesn't have one.
+    pub snippet: Option<String>,
+    pub is_tombstone: bool,
+    pub reason: OutgoingReason,
+}
+
+/// Counts of records that we uploaded, or tried to upload, on the last
+/// sync.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct OutgoingTelemetry {
+    pub uploaded: usize,
+    pub tombstones: usize,
+    /// Records that we staged for upload, but that the server didn't
+    /// accept. These are reuploaded on the next sync.
+    pub failed: usize,
+}
+
+/// Counts of problems with the remote tree on the last sync.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ValidationTelemetry {
+    /// The number of changed remote items with each validity.
+    pub valid: usize,
+    pub reupload: usize,
+    pub replace: usize,
+    /// Structure problems that dogear found while building the remote tree.
+    pub orphans: usize,
+    pub missing_children: usize,
+    /// Remote items whose parents don't exist, which we moved to unfiled.
+    pub reparented_orphans: usize,
+    /// Local and remote items outside the user content roots, which we
+    /// moved to unfiled before merging.
+    pub non_syncable_local: usize,
+    pub non_syncable_remote: usize,
+}
+
+impl ValidationTelemetry {
+    /// Converts these counts to the sync ping's validation record, which
+    /// only includes the problems that we found.
+    pub fn to_validation(&self) -> telemetry::Validation {
+        let mut validation = telemetry::Validation::new(1);
+        validation
+            .problem("reupload", self.reupload)
+            .problem("replace", self.replace)
+            .problem("orphans", self.orphans)
+            .problem("missingChildren", self.missing_children)
+            .problem("reparentedOrphans", self.reparented_orphans)
+            .problem("nonSyncableLocal", self.non_syncable_local)
+            .problem("nonSyncableRemote", self.non_syncable_remote);
+        validation
+    }
+}
+
+/// Telemetry for a bookmark sync, for the sync driver to attach to the sync
+/// ping. See `BookmarksStore::take_telemetry`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct BookmarksTelemetry {
+    pub outgoing: OutgoingTelemetry,
+    pub validation: ValidationTelemetry,
+}
+
 pub struct BookmarksStore<'a> {
     pub db: &'a PlacesDb,
     interruptee: &'a SqlInterruptScope,
+    // The number of log messages we suppressed while staging, keyed by
+    // sampler name. These are reported in the sync ping.
+    suppressed_logs: RefCell<HashMap<&'static str, usize>>,
+    // How far in the future, in milliseconds, the most skewed local item was
+    // modified during the last merge, if it exceeded the threshold.
+    local_clock_skew: Cell<Option<i64>>,
+    // How far ahead of the local clock, in milliseconds, the server's clock
+    // was when we last staged incoming records, or `None` if the server
+    // didn't tell us its clock. This is negative if the server's clock is
+    // behind.
+    server_clock_delta: Cell<Option<i64>>,
+    // Outgoing and validation counts for the current sync.
+    telemetry: RefCell<BookmarksTelemetry>,
+    priority: SyncPriority,
+    mode: SyncMode,
+    // True if the current sync only downloaded and staged items, either
+    // because it was a mobile-only sync, or a stage-only sync.
+    is_partial_sync: Cell<bool>,
 }
 
 impl<'a> BookmarksStore<'a> {
     pub fn new(db: &'a PlacesDb, interruptee: &'a SqlInterruptScope) -> Self {
         assert_eq!(db.conn_type(), ConnectionType::Sync);
-        Self { db, interruptee }
+        Self {
+            db,
+            interruptee,
+            suppressed_logs: RefCell::default(),
+            local_clock_skew: Cell::default(),
+            server_clock_delta: Cell::default(),
+            telemetry: RefCell::default(),
+            priority: SyncPriority::default(),
+            mode: SyncMode::default(),
+            is_partial_sync: Cell::default(),
+        }
+    }
+
+    /// Sets which items to favor when syncing.
+    pub fn with_priority(mut self, priority: SyncPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets whether to merge and upload incoming items, or only stage them.
+    pub fn with_mode(mut self, mode: SyncMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Returns and resets the outgoing and validation telemetry recorded
+    /// since the last call.
+    pub fn take_telemetry(&self) -> BookmarksTelemetry {
+        self.telemetry.replace(BookmarksTelemetry::default())
+    }
+
+    /// Like `sync_finished`, but returns the number of records that the
+    /// server accepted and rejected, so that the caller can decide whether
+    /// to sync again soon. Rejected records are reuploaded on the next sync.
+    pub fn finish_sync(
+        &self,
+        uploaded_at: ServerTimestamp,
+        records_synced: Vec<String>,
+    ) -> Result<OutgoingTelemetry> {
+        if self.is_partial_sync.get() {
+            // We didn't merge or upload anything.
+            return Ok(OutgoingTelemetry::default());
+        }
+        let outgoing = self.push_synced_items(uploaded_at, records_synced)?;
+        self.update_frecencies()?;
+        Ok(outgoing)
+    }
+
+    /// Returns true if we should only download mobile items on this sync.
+    /// That's only safe if the mirror already has the rest of the server's
+    /// tree, and the last sync wasn't also a mobile-only sync.
+    fn is_mobile_first_sync(&self) -> Result<bool> {
+        Ok(self.priority == SyncPriority::MobileFirst
+            && get_meta::<i64>(self.db, LAST_SYNC_META_KEY)?.is_some()
+            && !get_meta::<bool>(self.db, PARTIAL_SYNC_META_KEY)?.unwrap_or(false))
+    }
+
+    /// Returns the record IDs of the mobile root and its descendants on the
+    /// server, as of the last sync, in level order. We use the mirror
+    /// instead of the local tree, so that we also ask for remote items that
+    /// we haven't merged yet.
+    fn fetch_mobile_record_ids(&self) -> Result<Vec<String>> {
+        let sql = format!(
+            "WITH RECURSIVE
+             mobileItems(guid, level, position) AS (
+               SELECT '{mobile_guid}', 0, 0
+               UNION ALL
+               SELECT s.guid, m.level + 1, s.position
+               FROM moz_bookmarks_synced_structure s
+               JOIN mobileItems m ON s.parentGuid = m.guid
+             )
+             SELECT guid FROM mobileItems
+             ORDER BY level, position
+             LIMIT {limit}",
+            mobile_guid = BookmarkRootGuid::Mobile.as_guid().as_ref(),
+            limit = MAX_MOBILE_FIRST_IDS,
+        );
+        self.db
+            .query_rows_and_then_named(&sql, &[], |row| -> Result<_> {
+                Ok(BookmarkRecordId::from(row.get::<_, SyncGuid>("guid")?).into_payload_id())
+            })
+    }
+
+    /// Merges items staged by a `SyncMode::StageOnly` sync into the local
+    /// tree. This doesn't upload anything: merged items that need to be
+    /// uploaded keep their change counters, and weakly uploaded items get
+    /// one, so the next sync uploads them.
+    pub fn complete_pending_merge(&self) -> Result<()> {
+        let timestamp = get_meta::<i64>(self.db, LAST_SYNC_META_KEY)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0))
+            .unwrap_or_default();
+        let tx = self.db.begin_transaction()?;
+        let mut merger = Merger::new(&self, timestamp);
+        merger.merge()?;
+        // We'll stage outgoing items again on the next sync. Like
+        // `push_synced_items` does for rejected items, we bump the change
+        // counters of weakly uploaded items, which don't have one, so that
+        // we stage them again, too.
+        self.db.execute_batch(
+            "UPDATE moz_bookmarks SET
+               syncChangeCounter = MAX(syncChangeCounter, 1)
+             WHERE guid IN (SELECT guid FROM itemsToUpload
+                            WHERE NOT isDeleted);
+             DELETE FROM itemsToUpload;
+             DELETE FROM structureToUpload;
+             DELETE FROM tagsToUpload;",
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns a summary of the records that the next sync would upload,
+    /// without changing anything. This merges items already staged by a
+    /// `SyncMode::StageOnly` sync, but doesn't download new ones.
+    pub fn preview_outgoing(&self) -> Result<Vec<OutgoingPreview>> {
+        // Merging and staging begin their own transactions, which nest
+        // inside this one, so rolling it back undoes everything, including
+        // changes to the temp tables and change counters.
+        let tx = self.db.begin_transaction()?;
+        let local_clock_skew = self.local_clock_skew.get();
+        let result = self.preview_outgoing_in_tx();
+        self.local_clock_skew.set(local_clock_skew);
+        tx.rollback()?;
+        result
+    }
+
+    fn preview_outgoing_in_tx(&self) -> Result<Vec<OutgoingPreview>> {
+        let timestamp = get_meta::<i64>(self.db, LAST_SYNC_META_KEY)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0))
+            .unwrap_or_default();
+        let mut merger = Merger::new(&self, timestamp);
+        merger.merge()?;
+
+        let mut previews: HashMap<SyncGuid, OutgoingPreview> = HashMap::new();
+        let mut stmt = self.db.prepare(
+            "SELECT o.guid, o.kind, o.isDeleted, o.syncChangeCounter,
+                    NULLIF(o.title, '') AS title, o.url, b.syncStatus
+             FROM itemsToUpload o
+             LEFT JOIN moz_bookmarks b ON b.guid = o.guid",
+        )?;
+        let mut results = stmt.query(NO_PARAMS)?;
+        while let Some(row) = results.next()? {
+            self.interruptee.err_if_interrupted()?;
+            let guid = row.get::<_, SyncGuid>("guid")?;
+            let kind = match row.get::<_, Option<u8>>("kind")? {
+                Some(kind) => Some(SyncedBookmarkKind::try_from(kind)?),
+                None => None,
+            };
+            let is_tombstone = row.get::<_, bool>("isDeleted")?;
+            // Tombstones are always flagged with a change counter of 1, so
+            // items without one must be weak uploads.
+            let reason = if is_tombstone {
+                OutgoingReason::Changed
+            } else if row.get::<_, u32>("syncChangeCounter")? == 0 {
+                OutgoingReason::Weak
+            } else if row
+                .get::<_, Option<u8>>("syncStatus")?
+                .map(SyncStatus::from_u8)
+                == Some(SyncStatus::New)
+            {
+                OutgoingReason::New
+            } else {
+                OutgoingReason::Changed
+            };
+            let snippet = row
+                .get::<_, Option<String>>("title")?
+                .or(row.get::<_, Option<String>>("url")?)
+                .map(|s| s.chars().take(OUTGOING_PREVIEW_SNIPPET_LENGTH).collect());
+            previews.insert(
+                guid.clone(),
+                OutgoingPreview {
+                    guid,
+                    kind,
+                    snippet,
+                    is_tombstone,
+                    reason,
+                },
+            );
+        }
+
+        // Inflate the records, too, so that we skip the same items as a
+        // real sync, and return the previews in upload order.
+        let outgoing = self.fetch_outgoing_records(timestamp)?;
+        Ok(outgoing
+            .changes
+            .into_iter()
+            .filter_map(|payload| {
+                previews.remove(BookmarkRecordId::from_payload_id(payload.id).as_guid())
+            })
+            .collect())
     }
 
     fn stage_incoming(
@@ -57,6 +418,14 @@ impl<'a> BookmarksStore<'a> {
         incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> Result<ServerTimestamp> {
         let timestamp = inbound.timestamp;
+        // `timestamp` is when the collection last changed, which can be long
+        // before now, so we can only use the server's current clock for this.
+        let now = self.db.now().as_millis() as i64;
+        self.server_clock_delta.set(
+            inbound
+                .server_time
+                .map(|server_time| server_time.as_millis() as i64 - now),
+        );
         let mut tx = self.db.begin_transaction()?;
 
         let applicator = IncomingApplicator::new(&self.db);
@@ -67,10 +436,251 @@ impl<'a> BookmarksStore<'a> {
             tx.maybe_commit()?;
             self.interruptee.err_if_interrupted()?;
         }
+        let suppressed = applicator.finish()?;
+        {
+            let mut suppressed_logs = self.suppressed_logs.borrow_mut();
+            for (name, count) in suppressed {
+                *suppressed_logs.entry(name).or_default() += count;
+            }
+        }
+        let replaced = self.replace_case_collisions()?;
+        if replaced > 0 {
+            log::warn!(
+                "Replaced {} incoming items with case-colliding GUIDs",
+                replaced
+            );
+            incoming_telemetry.reconciled(replaced as u32);
+        }
         tx.commit()?;
         Ok(timestamp)
     }
 
+    /// Finds staged items whose GUIDs only differ by case, like
+    /// `bookmarkAAAA` and `bookmarkaaaa`. Some servers and older clients
+    /// treat GUIDs case-insensitively, so we can end up with both casings of
+    /// the same item on the server, which we'd otherwise merge into two local
+    /// items. For each set of collisions, we keep the item that a folder
+    /// references (or, failing that, the most recently modified one), and
+    /// replace the others with tombstones. Returns the number of items
+    /// replaced.
+    fn replace_case_collisions(&self) -> Result<usize> {
+        let mut stmt = self.db.prepare(
+            "SELECT guid, lower(guid) AS foldedGuid
+             FROM moz_bookmarks_synced v
+             WHERE NOT isDeleted AND
+                   lower(guid) IN (SELECT lower(guid) FROM moz_bookmarks_synced
+                                   WHERE NOT isDeleted
+                                   GROUP BY lower(guid)
+                                   HAVING COUNT(*) > 1)
+             ORDER BY foldedGuid,
+                      EXISTS(SELECT 1 FROM moz_bookmarks_synced_structure s
+                             WHERE s.guid = v.guid) DESC,
+                      serverModified DESC,
+                      guid",
+        )?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<_> {
+            Ok((
+                row.get::<_, SyncGuid>("guid")?,
+                row.get::<_, String>("foldedGuid")?,
+            ))
+        })?;
+        // Rows are grouped by folded GUID, with the item to keep first.
+        let mut collisions = Vec::new();
+        let mut keep: Option<(SyncGuid, String)> = None;
+        for row in rows {
+            let (guid, folded_guid) = row?;
+            match keep {
+                Some((ref keep_guid, ref keep_folded_guid)) if *keep_folded_guid == folded_guid => {
+                    collisions.push((keep_guid.clone(), guid));
+                }
+                _ => keep = Some((guid, folded_guid)),
+            }
+        }
+        for (keep_guid, replace_guid) in &collisions {
+            log::debug!(
+                "Replacing incoming item {} with {}, which only differs by case",
+                replace_guid,
+                keep_guid
+            );
+            let params: &[(&str, &dyn rusqlite::ToSql)] =
+                &[(":keepGuid", keep_guid), (":replaceGuid", replace_guid)];
+            // Move the replaced item's children, if any, after the kept
+            // item's children.
+            self.db.execute_named_cached(
+                "UPDATE OR IGNORE moz_bookmarks_synced_structure SET
+                   parentGuid = :keepGuid,
+                   position = position + (SELECT IFNULL(MAX(position) + 1, 0)
+                                          FROM moz_bookmarks_synced_structure
+                                          WHERE parentGuid = :keepGuid)
+                 WHERE parentGuid = :replaceGuid",
+                params,
+            )?;
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks_synced SET
+                   parentGuid = :keepGuid
+                 WHERE parentGuid = :replaceGuid",
+                params,
+            )?;
+            self.db.execute_named_cached(
+                "DELETE FROM moz_bookmarks_synced_structure
+                 WHERE guid = :replaceGuid OR
+                       parentGuid = :replaceGuid",
+                params,
+            )?;
+            // Same as `IncomingApplicator::store_incoming_tombstone`.
+            self.db.execute_named_cached(
+                "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified,
+                                                   needsMerge, dateAdded, isDeleted)
+                 SELECT guid, NULL, serverModified, 1, 0, 1
+                 FROM moz_bookmarks_synced
+                 WHERE guid = :replaceGuid",
+                &[(":replaceGuid", replace_guid)],
+            )?;
+        }
+        Ok(collisions.len())
+    }
+
+    /// Moves local items that aren't in a user content root, like items
+    /// parented directly to the Places root, into unfiled, and flags them
+    /// for reupload. This matches what Desktop does. Returns the number of
+    /// items moved. The caller must hold a transaction.
+    fn reparent_non_syncable_local_items(&self) -> Result<usize> {
+        let ids = self.db.query_rows_and_then_named(
+            &format!(
+                "SELECT b.id FROM moz_bookmarks b
+                 JOIN moz_bookmarks p ON p.id = b.parent
+                 WHERE p.guid = '{root_guid}' AND
+                       b.guid NOT IN {user_content_roots}
+                 ORDER BY b.position",
+                root_guid = BookmarkRootGuid::Root.as_guid().as_ref(),
+                user_content_roots = RootsFragment(USER_CONTENT_ROOTS),
+            ),
+            &[],
+            |row| -> Result<i64> { Ok(row.get(0)?) },
+        )?;
+        for id in &ids {
+            // Flag the item and its descendants for upload, since the server
+            // doesn't know about them.
+            self.db.execute_named_cached(
+                "WITH RECURSIVE
+                 descendants(id) AS (
+                   SELECT :id
+                   UNION ALL
+                   SELECT b.id FROM moz_bookmarks b
+                   JOIN descendants d ON d.id = b.parent
+                 )
+                 UPDATE moz_bookmarks SET
+                   syncChangeCounter = syncChangeCounter + 1
+                 WHERE id IN descendants",
+                &[(":id", id)],
+            )?;
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks SET
+                   position = position - 1
+                 WHERE parent = (SELECT parent FROM moz_bookmarks
+                                 WHERE id = :id) AND
+                       position > (SELECT position FROM moz_bookmarks
+                                   WHERE id = :id)",
+                &[(":id", id)],
+            )?;
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks SET
+                   parent = (SELECT id FROM moz_bookmarks
+                             WHERE guid = :unfiledGuid),
+                   position = (SELECT COUNT(*) FROM moz_bookmarks
+                               WHERE parent = (SELECT id FROM moz_bookmarks
+                                               WHERE guid = :unfiledGuid))
+                 WHERE id = :id",
+                &[
+                    (":id", id),
+                    (":unfiledGuid", BookmarkRootGuid::Unfiled.guid()),
+                ],
+            )?;
+        }
+        if !ids.is_empty() {
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks SET
+                   syncChangeCounter = syncChangeCounter + 1
+                 WHERE guid = :unfiledGuid",
+                &[(":unfiledGuid", BookmarkRootGuid::Unfiled.guid())],
+            )?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Moves incoming items that aren't in a user content root, like
+    /// children of an unknown root, into unfiled, and flags them for
+    /// reupload. Like `reparent_non_syncable_local_items`, this matches
+    /// Desktop. Returns the number of items moved. The caller must hold a
+    /// transaction.
+    fn reparent_non_syncable_remote_items(&self) -> Result<usize> {
+        let guids = self.db.query_rows_and_then_named(
+            &format!(
+                "SELECT v.guid FROM moz_bookmarks_synced v
+                 WHERE NOT v.isDeleted AND
+                       v.guid <> '{root_guid}' AND
+                       v.guid NOT IN {user_content_roots} AND
+                       (v.parentGuid = '{root_guid}' OR
+                        EXISTS(SELECT 1 FROM moz_bookmarks_synced_structure s
+                               WHERE s.guid = v.guid AND
+                                     s.parentGuid = '{root_guid}'))
+                 ORDER BY v.guid",
+                root_guid = BookmarkRootGuid::Root.as_guid().as_ref(),
+                user_content_roots = RootsFragment(USER_CONTENT_ROOTS),
+            ),
+            &[],
+            |row| -> Result<SyncGuid> { Ok(row.get(0)?) },
+        )?;
+        for guid in &guids {
+            let params: &[(&str, &dyn rusqlite::ToSql)] = &[
+                (":guid", guid),
+                (":rootGuid", BookmarkRootGuid::Root.guid()),
+                (":unfiledGuid", BookmarkRootGuid::Unfiled.guid()),
+            ];
+            self.db.execute_named_cached(
+                "DELETE FROM moz_bookmarks_synced_structure
+                 WHERE guid = :guid AND
+                       parentGuid = :rootGuid",
+                &params[..2],
+            )?;
+            self.db.execute_named_cached(
+                "INSERT OR IGNORE INTO moz_bookmarks_synced_structure(guid, parentGuid,
+                                                                      position)
+                 VALUES(:guid, :unfiledGuid,
+                        (SELECT IFNULL(MAX(position) + 1, 0)
+                         FROM moz_bookmarks_synced_structure
+                         WHERE parentGuid = :unfiledGuid))",
+                &[params[0], params[2]],
+            )?;
+            // Flag the item for reupload, so that we fix its parent on the
+            // server.
+            self.db.execute_named_cached(
+                &format!(
+                    "UPDATE moz_bookmarks_synced SET
+                       parentGuid = :unfiledGuid,
+                       needsMerge = 1,
+                       validity = {reupload}
+                     WHERE guid = :guid",
+                    reupload = SyncedBookmarkValidity::Reupload as u8
+                ),
+                &[params[0], params[2]],
+            )?;
+        }
+        if !guids.is_empty() {
+            self.db.execute_named_cached(
+                &format!(
+                    "UPDATE moz_bookmarks_synced SET
+                       needsMerge = 1,
+                       validity = {reupload}
+                     WHERE guid = :unfiledGuid",
+                    reupload = SyncedBookmarkValidity::Reupload as u8
+                ),
+                &[(":unfiledGuid", BookmarkRootGuid::Unfiled.guid())],
+            )?;
+        }
+        Ok(guids.len())
+    }
+
     fn has_changes(&self) -> Result<bool> {
         // In the first subquery, we check incoming items with needsMerge = true
         // except the tombstones who don't correspond to any local bookmark because
@@ -113,6 +723,11 @@ impl<'a> BookmarksStore<'a> {
     /// Conceptually, we examine the merge state of each item, and either leave the
     /// item unchanged, upload the local side, apply the remote side, or apply and
     /// then reupload the remote side with a new structure.
+    ///
+    /// The triggers that update the local tree move, reparent, and delete
+    /// items in separate statements, so this must never call `maybe_commit`.
+    /// Otherwise, readers could see items with missing parents or holes in
+    /// their positions between chunks.
     fn update_local_items<'t>(
         &self,
         descendants: Vec<MergedDescendant<'t>>,
@@ -178,8 +793,8 @@ impl<'a> BookmarksStore<'a> {
         })?;
 
         // `itemsToMerge` is a view, so "deleting" from it fires the
-        // `insertNewLocalItems` and `updateExistingLocalItems`
-        // triggers instead.
+        // `updateGuidsAndSyncFlags` and `updateLocalItems` triggers
+        // instead.
         self.db.execute_batch("DELETE FROM itemsToMerge")?;
 
         // `structureToMerge` is also a view, so "deleting" from it fires the
@@ -187,6 +802,16 @@ impl<'a> BookmarksStore<'a> {
         self.db.execute_batch("DELETE FROM structureToMerge")?;
 
         // Deleting from `itemsToRemove` fires the `removeLocalItems` trigger.
+        let removed = self.db.query_one::<i64>(
+            "SELECT COUNT(*) FROM itemsToRemove r
+             JOIN moz_bookmarks b ON b.guid = r.guid",
+        )?;
+        record_deletion(
+            self.db,
+            DeletionOp::SyncDeleteBookmarks,
+            DeletionInitiator::Sync,
+            removed as usize,
+        )?;
         self.db.execute_batch("DELETE FROM itemsToRemove")?;
 
         Ok(())
@@ -202,9 +827,49 @@ impl<'a> BookmarksStore<'a> {
     /// we need to upload, so, if upload is interrupted or fails, we'll stage
     /// the items again on the next sync.
     fn stage_local_items_to_upload(&self) -> Result<()> {
+        // Clear change counters for items where we took the remote side, and
+        // the local item now matches the remote record. Otherwise, we'd
+        // upload a record identical to the one we just downloaded, bumping
+        // its modified time and making other clients download it again.
+        // Titles for roots are local-only, so we don't compare those.
+        self.db.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET
+               syncChangeCounter = 0
+             WHERE id IN (
+               SELECT b.id FROM moz_bookmarks b
+               JOIN mergedTree r ON r.mergedGuid = b.guid
+               JOIN moz_bookmarks_synced v ON v.guid = r.remoteGuid
+               JOIN moz_bookmarks p ON p.id = b.parent
+               WHERE r.useRemote AND
+                     b.syncChangeCounter > 0 AND
+                     r.remoteGuid = r.mergedGuid AND
+                     NOT v.isDeleted AND
+                     v.validity = {valid} AND
+                     b.dateAdded = v.dateAdded AND
+                     p.guid = v.parentGuid AND
+                     (b.title IS v.title OR b.guid IN {user_content_roots}) AND
+                     (SELECT COUNT(*) FROM moz_bookmarks c
+                      WHERE c.parent = b.id) =
+                     (SELECT COUNT(*) FROM moz_bookmarks_synced_structure s
+                      WHERE s.parentGuid = v.guid) AND
+                     NOT EXISTS(
+                       SELECT 1 FROM moz_bookmarks c
+                       LEFT JOIN moz_bookmarks_synced_structure s ON
+                         s.parentGuid = v.guid AND
+                         s.guid = c.guid AND
+                         s.position = c.position
+                       WHERE c.parent = b.id AND
+                             s.guid IS NULL
+                     )
+             )",
+            valid = SyncedBookmarkValidity::Valid as u8,
+            user_content_roots = RootsFragment(USER_CONTENT_ROOTS),
+        ))?;
+
         // Stage remotely changed items with older local creation dates. These are
-        // tracked "weakly": if the upload is interrupted or fails, we won't
-        // reupload the record on the next sync.
+        // tracked "weakly": if the upload is interrupted, we won't reupload
+        // the record on the next sync. If the server rejects the record,
+        // `push_synced_items` flags it for reupload.
         self.db.execute_batch(
             "INSERT OR IGNORE INTO idsToWeaklyUpload(id)
              SELECT b.id FROM moz_bookmarks b
@@ -214,26 +879,36 @@ impl<'a> BookmarksStore<'a> {
                   b.dateAdded < v.dateAdded",
         )?;
 
-        // Stage remaining locally changed items for upload.
+        // Stage remaining locally changed items for upload. We skip
+        // bookmarks with URLs that are too long to sync, but still list them
+        // in their parents' `children` below, so that the server's tree
+        // matches ours; see `storage::oversized`.
         self.db.execute_batch(&format!(
             "WITH RECURSIVE
              {local_items_fragment}
              INSERT INTO itemsToUpload(id, guid, syncChangeCounter, parentGuid,
                                        parentTitle, dateAdded, title, placeId,
-                                       kind, url, keyword, position)
+                                       kind, url, keyword, position, unknownFields)
              SELECT s.id, s.guid, s.syncChangeCounter, s.parentGuid,
                     s.parentTitle, s.dateAdded, s.title, s.placeId,
-                    {kind}, h.url, v.keyword, s.position
+                    {kind}, h.url,
+                    (SELECT k.keyword FROM moz_keywords k
+                     WHERE k.place_id = s.placeId
+                     ORDER BY k.lastModified DESC
+                     LIMIT 1),
+                    s.position, v.unknownFields
              FROM localItems s
              JOIN mergedTree r ON r.mergedGuid = s.guid
              LEFT JOIN moz_bookmarks_synced v ON v.guid = r.remoteGuid
              LEFT JOIN moz_places h ON h.id = s.placeId
              LEFT JOIN idsToWeaklyUpload w ON w.id = s.id
              WHERE s.guid <> '{root_guid}' AND
-                   (s.syncChangeCounter > 0 OR w.id NOT NULL)",
+                   (s.syncChangeCounter > 0 OR w.id NOT NULL) AND
+                   NOT {url_too_long}",
             local_items_fragment = LocalItemsFragment("localItems"),
             kind = item_kind_fragment("s.type", UrlOrPlaceIdFragment::Url("h.url")),
             root_guid = BookmarkRootGuid::Root.as_guid().as_ref(),
+            url_too_long = url_too_long_fragment("h.url"),
         ))?;
 
         // Record the child GUIDs of locally changed folders, which we use to
@@ -265,25 +940,8 @@ impl<'a> BookmarksStore<'a> {
     /// Inflates Sync records for all staged outgoing items.
     fn fetch_outgoing_records(&self, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
         let mut outgoing = OutgoingChangeset::new(self.collection_name().into(), timestamp);
-        let mut child_record_ids_by_local_parent_id: HashMap<i64, Vec<BookmarkRecordId>> =
-            HashMap::new();
         let mut tags_by_local_id: HashMap<i64, Vec<String>> = HashMap::new();
 
-        let mut stmt = self.db.prepare(
-            "SELECT parentId, guid FROM structureToUpload
-             ORDER BY parentId, position",
-        )?;
-        let mut results = stmt.query(NO_PARAMS)?;
-        while let Some(row) = results.next()? {
-            self.interruptee.err_if_interrupted()?;
-            let local_parent_id = row.get::<_, i64>("parentId")?;
-            let child_guid = row.get::<_, SyncGuid>("guid")?;
-            let child_record_ids = child_record_ids_by_local_parent_id
-                .entry(local_parent_id)
-                .or_default();
-            child_record_ids.push(child_guid.into());
-        }
-
         let mut stmt = self.db.prepare("SELECT id, tag FROM tagsToUpload")?;
         let mut results = stmt.query(NO_PARAMS)?;
         while let Some(row) = results.next()? {
@@ -294,12 +952,36 @@ impl<'a> BookmarksStore<'a> {
             tags.push(tag);
         }
 
-        let mut stmt = self.db.prepare(
-            r#"SELECT id, syncChangeCounter, guid, isDeleted, kind, keyword,
+        // Mobile-first syncs upload the mobile root and its descendants
+        // before everything else.
+        let (mobile_items_fragment, order_by_fragment) = match self.priority {
+            SyncPriority::Default => (String::new(), ""),
+            SyncPriority::MobileFirst => (
+                format!(
+                    "WITH RECURSIVE
+                     mobileItems(id) AS (
+                       SELECT id FROM moz_bookmarks
+                       WHERE guid = '{mobile_guid}'
+                       UNION ALL
+                       SELECT b.id FROM moz_bookmarks b
+                       JOIN mobileItems m ON b.parent = m.id
+                     )",
+                    mobile_guid = BookmarkRootGuid::Mobile.as_guid().as_ref(),
+                ),
+                "ORDER BY id NOT IN mobileItems, id",
+            ),
+        };
+        let mut stmt = self.db.prepare(&format!(
+            r#"{mobile_items}
+               SELECT id, syncChangeCounter, guid, isDeleted, kind, keyword,
                       url, IFNULL(title, "") AS title, position, parentGuid,
-                      IFNULL(parentTitle, "") AS parentTitle, dateAdded
-               FROM itemsToUpload"#,
-        )?;
+                      IFNULL(parentTitle, "") AS parentTitle, dateAdded,
+                      unknownFields
+               FROM itemsToUpload
+               {order_by}"#,
+            mobile_items = mobile_items_fragment,
+            order_by = order_by_fragment,
+        ))?;
         let mut results = stmt.query(NO_PARAMS)?;
         while let Some(row) = results.next()? {
             self.interruptee.err_if_interrupted()?;
@@ -314,8 +996,17 @@ impl<'a> BookmarksStore<'a> {
             let parent_guid = row.get::<_, SyncGuid>("parentGuid")?;
             let parent_title = row.get::<_, String>("parentTitle")?;
             let date_added = row.get::<_, i64>("dateAdded")?;
-            let record: BookmarkItemRecord = match SyncedBookmarkKind::from_u8(row.get("kind")?)? {
-                SyncedBookmarkKind::Bookmark => {
+            let kind = SyncedBookmarkKind::try_from(row.get::<_, u8>("kind")?)?;
+            let unknown_fields = match row.get::<_, Option<String>>("unknownFields")? {
+                Some(json) => serde_json::from_str(&json)?,
+                None => UnknownFields::default(),
+            };
+            let record: BookmarkItemRecord = match kind {
+                // Local items can't be livemarks, since we convert incoming
+                // livemarks to bookmarks when we stage them. If one does
+                // slip through, we upload it as a bookmark, like Desktop,
+                // instead of skipping it and trying again on every sync.
+                SyncedBookmarkKind::Bookmark | SyncedBookmarkKind::Livemark => {
                     let local_id = row.get::<_, i64>("id")?;
                     let title = row.get::<_, String>("title")?;
                     let url = row.get::<_, String>("url")?;
@@ -324,11 +1015,12 @@ impl<'a> BookmarksStore<'a> {
                         parent_record_id: Some(parent_guid.into()),
                         parent_title: Some(parent_title),
                         date_added: Some(date_added),
-                        has_dupe: true,
+                        has_dupe: OUTGOING_HAS_DUPE,
                         title: Some(title),
                         url: Some(url),
                         keyword: row.get::<_, Option<String>>("keyword")?,
                         tags: tags_by_local_id.remove(&local_id).unwrap_or_default(),
+                        unknown_fields,
                     }
                     .into()
                 }
@@ -340,31 +1032,35 @@ impl<'a> BookmarksStore<'a> {
                         parent_record_id: Some(parent_guid.into()),
                         parent_title: Some(parent_title),
                         date_added: Some(date_added),
-                        has_dupe: true,
+                        has_dupe: OUTGOING_HAS_DUPE,
                         title: Some(title),
                         url: Some(url),
                         tag_folder_name: None,
+                        unknown_fields,
                     }
                     .into()
                 }
                 SyncedBookmarkKind::Folder => {
-                    let title = row.get::<_, String>("title")?;
+                    // Roots might have localized titles, which we don't
+                    // want to upload.
+                    let title = match guid.as_root() {
+                        Some(root) => root.default_title().to_string(),
+                        None => row.get::<_, String>("title")?,
+                    };
                     let local_id = row.get::<_, i64>("id")?;
-                    let children = child_record_ids_by_local_parent_id
-                        .remove(&local_id)
-                        .unwrap_or_default();
+                    let children = self.fetch_outgoing_child_record_ids(local_id)?;
                     FolderRecord {
                         record_id: guid.into(),
                         parent_record_id: Some(parent_guid.into()),
                         parent_title: Some(parent_title),
                         date_added: Some(date_added),
-                        has_dupe: true,
+                        has_dupe: OUTGOING_HAS_DUPE,
                         title: Some(title),
                         children,
+                        unknown_fields,
                     }
                     .into()
                 }
-                SyncedBookmarkKind::Livemark => continue,
                 SyncedBookmarkKind::Separator => {
                     let position = row.get::<_, i64>("position")?;
                     SeparatorRecord {
@@ -372,8 +1068,9 @@ impl<'a> BookmarksStore<'a> {
                         parent_record_id: Some(parent_guid.into()),
                         parent_title: Some(parent_title),
                         date_added: Some(date_added),
-                        has_dupe: true,
+                        has_dupe: OUTGOING_HAS_DUPE,
                         position: Some(position),
+                        unknown_fields,
                     }
                     .into()
                 }
@@ -384,14 +1081,32 @@ impl<'a> BookmarksStore<'a> {
         Ok(outgoing)
     }
 
+    /// Returns the record IDs of the children of an outgoing folder, in order.
+    /// We fetch these for each folder as we build its record, instead of
+    /// loading the structure for all folders up front, so that we don't hold
+    /// a second copy of the tree in memory when we're uploading everything.
+    fn fetch_outgoing_child_record_ids(
+        &self,
+        local_parent_id: i64,
+    ) -> Result<Vec<BookmarkRecordId>> {
+        self.db.query_rows_into_cached(
+            "SELECT guid FROM structureToUpload
+             WHERE parentId = :parentId
+             ORDER BY position",
+            &[(":parentId", &local_parent_id)],
+            |row| -> Result<_> { Ok(row.get::<_, SyncGuid>("guid")?.into()) },
+        )
+    }
+
     /// Decrements the change counter, updates the sync status, and cleans up
-    /// tombstones for successfully synced items. Sync calls this method at the
-    /// end of each bookmark sync.
+    /// tombstones for successfully synced items. Items that we staged, but
+    /// that aren't in `records_synced`, are flagged for reupload on the next
+    /// sync. Sync calls this method at the end of each bookmark sync.
     fn push_synced_items(
         &self,
         uploaded_at: ServerTimestamp,
         records_synced: Vec<String>,
-    ) -> Result<()> {
+    ) -> Result<OutgoingTelemetry> {
         // Flag all successfully synced records as uploaded. This `UPDATE` fires
         // the `pushUploadedChanges` trigger, which updates local change
         // counters and writes the items back to the synced bookmarks table.
@@ -419,22 +1134,59 @@ impl<'a> BookmarksStore<'a> {
 
         // Fast-forward the last sync time, so that we don't download the
         // records we just uploaded on the next sync.
-        put_meta(
+        put_internal_meta(
             self.db,
             LAST_SYNC_META_KEY,
             &(uploaded_at.as_millis() as i64),
         )?;
+        delete_internal_meta(self.db, PARTIAL_SYNC_META_KEY)?;
+
+        // Items without an upload time weren't in `records_synced`, so the
+        // server didn't accept them.
+        let outgoing = self.db.query_row_and_then_named(
+            "SELECT IFNULL(SUM(uploadedAt >= 0 AND NOT isDeleted), 0) AS uploaded,
+                    IFNULL(SUM(uploadedAt >= 0 AND isDeleted), 0) AS tombstones,
+                    IFNULL(SUM(uploadedAt < 0), 0) AS failed
+             FROM itemsToUpload",
+            &[],
+            |row| -> Result<_> {
+                Ok(OutgoingTelemetry {
+                    uploaded: row.get::<_, i64>("uploaded")? as usize,
+                    tombstones: row.get::<_, i64>("tombstones")? as usize,
+                    failed: row.get::<_, i64>("failed")? as usize,
+                })
+            },
+            false,
+        )?;
+        if outgoing.failed > 0 {
+            log::warn!(
+                "Server didn't accept {} staged bookmark records; reuploading them on the next sync",
+                outgoing.failed
+            );
+            // Weakly uploaded items don't have a change counter, so we need
+            // to bump it. Unacknowledged tombstones stay in
+            // `moz_bookmarks_deleted`, so they're staged again anyway.
+            self.db.execute_batch(
+                "UPDATE moz_bookmarks SET
+                   syncChangeCounter = MAX(syncChangeCounter, 1)
+                 WHERE guid IN (SELECT guid FROM itemsToUpload
+                                WHERE uploadedAt < 0 AND
+                                      NOT isDeleted)",
+            )?;
+        }
+        self.telemetry.borrow_mut().outgoing = outgoing;
 
         // Clean up.
         self.db.execute_batch("DELETE FROM itemsToUpload")?;
         tx.commit()?;
 
-        Ok(())
+        Ok(outgoing)
     }
 
     fn update_frecencies(&self) -> Result<()> {
         let mut tx = self.db.begin_transaction()?;
 
+        let settings = self.db.frecency_settings();
         let mut frecencies = Vec::with_capacity(MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK);
         loop {
             let sql = format!(
@@ -450,12 +1202,7 @@ impl<'a> BookmarksStore<'a> {
                 // Frecency recalculation runs several statements, so check to
                 // make sure we aren't interrupted before each calculation.
                 self.interruptee.err_if_interrupted()?;
-                let frecency = calculate_frecency(
-                    &self.db,
-                    &DEFAULT_FRECENCY_SETTINGS,
-                    place_id,
-                    Some(false),
-                )?;
+                let frecency = calculate_frecency(&self.db, &settings, place_id, Some(false))?;
                 frecencies.push((place_id, frecency));
             }
             if frecencies.is_empty() {
@@ -476,10 +1223,10 @@ impl<'a> BookmarksStore<'a> {
                     write!(f, "({}, {})", id, frecency)
                 })
             ))?;
-            tx.maybe_commit()?;
-            self.interruptee.err_if_interrupted()?;
 
-            // ...And remove them from the stale table.
+            // ...And remove them from the stale table, in the same chunk, so
+            // that we never commit new frecencies that are still marked as
+            // stale.
             self.db.execute_batch(&format!(
                 "DELETE FROM moz_places_stale_frecencies
                  WHERE place_id IN ({})",
@@ -505,6 +1252,8 @@ impl<'a> BookmarksStore<'a> {
         Ok(())
     }
 
+    /// A convenience wrapper around sync_multiple. Returns the recommended
+    /// time to wait before the next sync, if any.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
@@ -512,7 +1261,7 @@ impl<'a> BookmarksStore<'a> {
         mem_cached_state: &mut MemoryCachedState,
         disk_cached_state: &mut Option<String>,
         sync_ping: &mut telemetry::SyncTelemetryPing,
-    ) -> Result<()> {
+    ) -> Result<Option<Duration>> {
         let result = sync15::sync_multiple(
             &[self],
             disk_cached_state,
@@ -522,13 +1271,37 @@ impl<'a> BookmarksStore<'a> {
             sync_ping,
             self.interruptee,
         );
-        let failures = result?;
-        if failures.is_empty() {
-            Ok(())
-        } else {
-            let (_, err) = failures.into_iter().next().unwrap();
-            Err(err.into())
+        // Record the attempt even if the sync failed, so that we can see
+        // why later.
+        let entry = SyncLogEntry::new(self.db.now(), &result);
+        if let Err(e) = record_sync(self.db, entry) {
+            log::warn!("Failed to record sync in the sync log: {}", e);
+        }
+        record_suppressed_logs(
+            "bookmarks",
+            self.suppressed_logs.borrow_mut().drain(),
+            sync_ping,
+        );
+        if let Some(skew) = self.local_clock_skew.take() {
+            sync_ping.event(
+                telemetry::Event::new("bookmarks", "clockSkew")
+                    .extra("skewMillis", skew.to_string()),
+            );
         }
+        // Parent titles are user content, so we only report the number of
+        // orphans. `get_synced_orphans` has the details.
+        match get_synced_orphans(self.db) {
+            Ok(ref orphans) if !orphans.is_empty() => {
+                log::info!("Found {} orphaned synced items", orphans.len());
+                sync_ping.event(
+                    telemetry::Event::new("bookmarks", "orphans")
+                        .extra("count", orphans.len().to_string()),
+                );
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to count orphaned synced items: {}", e),
+        }
+        Ok(result?.into_single_store_result("bookmarks")?)
     }
 }
 
@@ -543,17 +1316,52 @@ impl<'a> Store for BookmarksStore<'a> {
         inbound: IncomingChangeset,
         incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> result::Result<OutgoingChangeset, failure::Error> {
+        let is_partial = self.is_mobile_first_sync()?;
+        self.is_partial_sync.set(is_partial);
+
         // Stage all incoming items.
         let timestamp = self.stage_incoming(inbound, incoming_telemetry)?;
 
+        if is_partial {
+            // A mobile-first sync only downloads part of the tree, so we
+            // leave the staged items for the next full sync to merge, and
+            // keep the last sync time so that it downloads everything else.
+            put_internal_meta(self.db, PARTIAL_SYNC_META_KEY, &true)?;
+            return Ok(OutgoingChangeset::new(
+                self.collection_name().into(),
+                timestamp,
+            ));
+        }
+
         // write the timestamp now, so if we are interrupted merging or
         // creating outgoing changesets we don't need to re-download the same
-        // records.
-        put_meta(self.db, LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
+        // records. This is safe because staged records keep `needsMerge` set
+        // until the merge transaction commits, so a failed merge is retried
+        // from the mirror on the next sync.
+        put_internal_meta(self.db, LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
+
+        if self.mode == SyncMode::StageOnly {
+            // Leave the staged items for `complete_pending_merge`, or the
+            // next full sync, to merge.
+            self.is_partial_sync.set(true);
+            return Ok(OutgoingChangeset::new(
+                self.collection_name().into(),
+                timestamp,
+            ));
+        }
 
-        // Merge and stage outgoing items.
+        // Merge and stage outgoing items. If the local tree is inconsistent,
+        // repair it and try once more.
         let mut merger = Merger::new(&self, timestamp);
-        merger.merge()?;
+        if let Err(err) = merger.merge() {
+            if !is_inconsistent_tree_error(&err) {
+                return Err(err.into());
+            }
+            log::warn!("Repairing local tree after failed merge: {}", err);
+            bookmarks::repair(self.db)?;
+            let mut merger = Merger::new(&self, timestamp);
+            merger.merge()?;
+        }
 
         let outgoing = self.fetch_outgoing_records(timestamp)?;
         Ok(outgoing)
@@ -564,12 +1372,17 @@ impl<'a> Store for BookmarksStore<'a> {
         new_timestamp: ServerTimestamp,
         records_synced: Vec<String>,
     ) -> result::Result<(), failure::Error> {
-        self.push_synced_items(new_timestamp, records_synced)?;
-        self.update_frecencies()?;
+        self.finish_sync(new_timestamp, records_synced)?;
         Ok(())
     }
 
     fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        if self.is_mobile_first_sync()? {
+            return Ok(CollectionRequest::new(self.collection_name())
+                .full()
+                .ids(self.fetch_mobile_record_ids()?)
+                .sort_by(RequestOrder::Index));
+        }
         let since = get_meta::<i64>(self.db, LAST_SYNC_META_KEY)?
             .map(|millis| ServerTimestamp(millis as f64 / 1000.0))
             .unwrap_or_default();
@@ -590,30 +1403,34 @@ impl<'a> Store for BookmarksStore<'a> {
 
     /// Removes all sync metadata, such that the next sync is treated as a
     /// first sync. Unlike `wipe`, this keeps all local items, but clears
-    /// all synced items and pending tombstones. This also forgets the last
-    /// sync time.
+    /// all synced items and pending tombstones, and flags all local items
+    /// for upload. This also forgets the last sync time, so the next sync
+    /// downloads all records from the server.
     fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
         let tx = self.db.begin_transaction()?;
         self.db.execute_batch(&format!(
-            "DELETE FROM moz_bookmarks_synced;
+            "DELETE FROM moz_bookmarks_synced_structure;
+
+             DELETE FROM moz_bookmarks_synced;
 
              DELETE FROM moz_bookmarks_deleted;
 
              UPDATE moz_bookmarks
-             SET syncChangeCounter = 0,
+             SET syncChangeCounter = 1,
                  syncStatus = {}",
             (SyncStatus::New as u8)
         ))?;
         create_synced_bookmark_roots(self.db)?;
-        put_meta(self.db, LAST_SYNC_META_KEY, &0)?;
+        delete_internal_meta(self.db, LAST_SYNC_META_KEY)?;
+        delete_internal_meta(self.db, PARTIAL_SYNC_META_KEY)?;
         match assoc {
             StoreSyncAssociation::Disconnected => {
-                delete_meta(self.db, GLOBAL_SYNCID_META_KEY)?;
-                delete_meta(self.db, COLLECTION_SYNCID_META_KEY)?;
+                delete_internal_meta(self.db, GLOBAL_SYNCID_META_KEY)?;
+                delete_internal_meta(self.db, COLLECTION_SYNCID_META_KEY)?;
             }
             StoreSyncAssociation::Connected(ids) => {
-                put_meta(self.db, GLOBAL_SYNCID_META_KEY, &ids.global)?;
-                put_meta(self.db, COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+                put_internal_meta(self.db, GLOBAL_SYNCID_META_KEY, &ids.global)?;
+                put_internal_meta(self.db, COLLECTION_SYNCID_META_KEY, &ids.coll)?;
             }
         };
         tx.commit()?;
@@ -628,6 +1445,23 @@ impl<'a> Store for BookmarksStore<'a> {
     /// remote tree.
     fn wipe(&self) -> result::Result<(), failure::Error> {
         let tx = self.db.begin_transaction()?;
+        let roots = RootsFragment(&[
+            BookmarkRootGuid::Root,
+            BookmarkRootGuid::Menu,
+            BookmarkRootGuid::Mobile,
+            BookmarkRootGuid::Toolbar,
+            BookmarkRootGuid::Unfiled,
+        ]);
+        let wiped = self.db.query_one::<i64>(&format!(
+            "SELECT COUNT(*) FROM moz_bookmarks WHERE guid NOT IN {roots}",
+            roots = roots,
+        ))?;
+        record_deletion(
+            self.db,
+            DeletionOp::WipeBookmarks,
+            DeletionInitiator::Sync,
+            wiped as usize,
+        )?;
         let sql = format!(
             "INSERT INTO moz_bookmarks_deleted(guid, dateRemoved)
              SELECT guid, now()
@@ -641,13 +1475,7 @@ impl<'a> Store for BookmarksStore<'a> {
 
              DELETE FROM moz_bookmarks
              WHERE guid NOT IN {roots};",
-            roots = RootsFragment(&[
-                BookmarkRootGuid::Root,
-                BookmarkRootGuid::Menu,
-                BookmarkRootGuid::Mobile,
-                BookmarkRootGuid::Toolbar,
-                BookmarkRootGuid::Unfiled
-            ]),
+            roots = roots,
             sync_status = SyncStatus::Normal as u8
         );
         self.db.execute_batch(&sql)?;
@@ -665,44 +1493,244 @@ impl dogear::Driver for Driver {
     }
 }
 
+/// Returns true if the merge failed because the local tree is inconsistent,
+/// which `bookmarks::repair` can fix. We can't tell if dogear's errors are
+/// about the local or remote tree, but the remote tree builder reparents
+/// orphans and ignores invalid structure, so it shouldn't return them.
+fn is_inconsistent_tree_error(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::Corruption(Corruption::InvalidLocalRoots) => true,
+        ErrorKind::MergeError(err) => match err.kind() {
+            dogear::ErrorKind::DuplicateItem(..)
+            | dogear::ErrorKind::InvalidParent(..)
+            | dogear::ErrorKind::MissingParent(..)
+            | dogear::ErrorKind::MissingItem(..)
+            | dogear::ErrorKind::Cycle(..) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 // The "merger", which is just a thin wrapper for dogear.
-struct Merger<'a> {
+pub(super) struct Merger<'a> {
     store: &'a BookmarksStore<'a>,
-    remote_time: ServerTimestamp,
     local_time: Timestamp,
+    // The server's clock at `local_time`, in milliseconds, if we know how far
+    // apart the clocks are, or else the time of the last sync. Remote ages
+    // are relative to this, so that both sides' ages are as of the same
+    // instant.
+    remote_now: i64,
+    // The absolute difference, in milliseconds, between the local and
+    // server clocks. See `clamp_age`.
+    max_clock_skew: i64,
+    // Items modified after `local_time` or `remote_now`.
+    future_local_items: Cell<usize>,
+    future_remote_items: Cell<usize>,
+    // The largest difference, in milliseconds, between a local item's last
+    // modified time and `local_time`.
+    max_local_skew: Cell<i64>,
+    // Items that changed on both sides at the same time, mapped to true if
+    // the local side wins. See `fetch_value_tiebreaks`.
+    value_tiebreaks: HashMap<SyncGuid, bool>,
 }
 
 impl<'a> Merger<'a> {
-    fn new(store: &'a BookmarksStore<'_>, remote_time: ServerTimestamp) -> Self {
+    pub(super) fn new(store: &'a BookmarksStore<'_>, remote_time: ServerTimestamp) -> Self {
+        let local_time = store.db.now();
+        let delta = store.server_clock_delta.get();
         Self {
             store,
-            remote_time,
-            local_time: Timestamp::now(),
+            local_time,
+            remote_now: match delta {
+                Some(delta) => local_time.as_millis() as i64 + delta,
+                None => remote_time.as_millis() as i64,
+            },
+            max_clock_skew: delta.map_or(0, i64::abs),
+            future_local_items: Cell::new(0),
+            future_remote_items: Cell::new(0),
+            max_local_skew: Cell::new(0),
+            value_tiebreaks: HashMap::new(),
         }
     }
 
     fn merge(&mut self) -> Result<()> {
         use dogear::Store;
+        let tx = self.store.db.begin_transaction()?;
+        let non_syncable_local = self.store.reparent_non_syncable_local_items()?;
+        let non_syncable_remote = self.store.reparent_non_syncable_remote_items()?;
+        tx.commit()?;
+        {
+            let validation = &mut self.store.telemetry.borrow_mut().validation;
+            validation.non_syncable_local = non_syncable_local;
+            validation.non_syncable_remote = non_syncable_remote;
+        }
         if !self.store.has_changes()? {
             return Ok(());
         }
+        self.value_tiebreaks = self.fetch_value_tiebreaks()?;
         // Merge and stage outgoing items via dogear.
         let stats = self.merge_with_driver(&Driver)?;
         log::debug!("merge completed: {:?}", stats);
+        self.report_future_items();
+        Ok(())
+    }
+
+    /// Records validation telemetry for the remote tree: the number of
+    /// changed items with each validity, the structure problems that
+    /// dogear found while building the tree, and the number of orphans
+    /// that we moved to unfiled. This keeps the non-syncable item counts
+    /// that `merge` already recorded.
+    fn record_validation(&self, tree: &Tree, reparented_orphans: usize) -> Result<()> {
+        let (non_syncable_local, non_syncable_remote) = {
+            let telemetry = self.store.telemetry.borrow();
+            (
+                telemetry.validation.non_syncable_local,
+                telemetry.validation.non_syncable_remote,
+            )
+        };
+        let mut validation = ValidationTelemetry {
+            non_syncable_local,
+            non_syncable_remote,
+            ..ValidationTelemetry::default()
+        };
+        let mut stmt = self.store.db.prepare(
+            "SELECT validity, COUNT(*) AS count FROM moz_bookmarks_synced
+             WHERE needsMerge AND
+                   NOT isDeleted
+             GROUP BY validity",
+        )?;
+        let mut results = stmt.query(NO_PARAMS)?;
+        while let Some(row) = results.next()? {
+            let count = row.get::<_, i64>("count")? as usize;
+            match SyncedBookmarkValidity::from_u8(row.get("validity")?)? {
+                SyncedBookmarkValidity::Valid => validation.valid = count,
+                SyncedBookmarkValidity::Reupload => validation.reupload = count,
+                SyncedBookmarkValidity::Replace => validation.replace = count,
+            }
+        }
+        let problems = tree.problems().counts();
+        validation.orphans = problems.orphans;
+        validation.missing_children = problems.missing_children;
+        validation.reparented_orphans = reparented_orphans;
+        self.store.telemetry.borrow_mut().validation = validation;
         Ok(())
     }
 
+    fn report_future_items(&self) {
+        let future_local_items = self.future_local_items.get();
+        let future_remote_items = self.future_remote_items.get();
+        if future_local_items > 0 || future_remote_items > 0 {
+            log::warn!(
+                "Found {} local and {} remote items modified in the future",
+                future_local_items,
+                future_remote_items
+            );
+        }
+        let max_local_skew = self.max_local_skew.get();
+        if max_local_skew > LOCAL_CLOCK_SKEW_THRESHOLD_MILLIS {
+            log::warn!(
+                "Local clock skew detected: items modified up to {}ms in the future",
+                max_local_skew
+            );
+            self.store.local_clock_skew.set(Some(max_local_skew));
+        }
+    }
+
+    /// Finds items that changed locally and remotely at the same time, so
+    /// that both sides have the same age, and picks a winner for each.
+    ///
+    /// Dogear keeps the newer side of a value conflict, and the remote side
+    /// if both are the same age. That's not enough for simultaneous edits on
+    /// two devices: each device would take the other's change, and they'd
+    /// keep swapping titles on every sync. Instead, we deterministically pick
+    /// the side with the greater title, then URL, so that every device makes
+    /// the same choice. If both sides have the same title and URL, we keep
+    /// the remote side.
+    fn fetch_value_tiebreaks(&self) -> Result<HashMap<SyncGuid, bool>> {
+        let sql = format!(
+            "SELECT b.guid,
+                    IFNULL(b.title, '') AS localTitle,
+                    IFNULL(h.url, '') AS localUrl,
+                    IFNULL(v.title, '') AS remoteTitle,
+                    IFNULL(u.url, '') AS remoteUrl,
+                    b.lastModified AS localModified,
+                    v.serverModified
+             FROM moz_bookmarks b
+             JOIN moz_bookmarks_synced v ON v.guid = b.guid
+             LEFT JOIN moz_places h ON h.id = b.fk
+             LEFT JOIN moz_places u ON u.id = v.placeId
+             WHERE b.syncChangeCounter > 0 AND
+                   v.needsMerge AND
+                   NOT v.isDeleted AND
+                   b.parent NOT NULL AND
+                   b.guid NOT IN {user_content_roots}",
+            user_content_roots = RootsFragment(USER_CONTENT_ROOTS),
+        );
+        let tiebreaks = self.store.db.query_rows_and_then_named(
+            &sql,
+            &[],
+            |row| -> Result<Option<(SyncGuid, bool)>> {
+                let local_age = self.local_age(row.get("localModified")?);
+                let remote_age = self.remote_age(row.get("serverModified")?);
+                if self.clamp_age(local_age) != self.clamp_age(remote_age) {
+                    // The newer side wins, as usual.
+                    return Ok(None);
+                }
+                let local = (
+                    row.get::<_, String>("localTitle")?,
+                    row.get::<_, String>("localUrl")?,
+                );
+                let remote = (
+                    row.get::<_, String>("remoteTitle")?,
+                    row.get::<_, String>("remoteUrl")?,
+                );
+                Ok(Some((row.get("guid")?, local > remote)))
+            },
+        )?;
+        Ok(tiebreaks.into_iter().flatten().collect())
+    }
+
+    /// Returns the age of a local item, relative to `local_time`.
+    fn local_age(&self, local_modified: Timestamp) -> i64 {
+        self.local_time.as_millis() as i64 - local_modified.as_millis() as i64
+    }
+
+    /// Returns the age of a remote item, relative to `remote_now`. Note that
+    /// `serverModified` in the mirror is an int with ms, unlike a
+    /// `ServerTimestamp`, which is in seconds.
+    fn remote_age(&self, server_modified: i64) -> i64 {
+        self.remote_now - server_modified
+    }
+
+    /// Clamps the age of an item modified in the future. Items modified at
+    /// most as far in the future as the skew between the local and server
+    /// clocks keep their negative ages, so that the more recently modified
+    /// side still wins if both sides are in the future. Items modified even
+    /// later are treated as modified at the limit of the skew, or, if the
+    /// clocks agree, as just modified.
+    fn clamp_age(&self, age: i64) -> i64 {
+        age.max(-self.max_clock_skew)
+    }
+
     /// Creates a local tree item from a row in the `localItems` CTE.
     fn local_row_to_item(&self, row: &Row<'_>) -> Result<Item> {
         let guid = row.get::<_, SyncGuid>("guid")?;
-        let kind = SyncedBookmarkKind::from_u8(row.get("kind")?)?;
+        let kind = SyncedBookmarkKind::try_from(row.get::<_, u8>("kind")?)?;
+        let tiebreak = self.value_tiebreaks.get(&guid).cloned();
         let mut item = Item::new(guid.into(), kind.into());
-        // Note that this doesn't account for local clock skew.
-        let age = self
-            .local_time
-            .duration_since(row.get::<_, Timestamp>("localModified")?)
-            .unwrap_or_default();
-        item.age = age.as_secs() as i64 * 1000 + i64::from(age.subsec_millis());
+        let age = self.local_age(row.get("localModified")?);
+        if age < 0 {
+            // A local item modified in the future is likely the result of a
+            // skewed clock.
+            self.future_local_items
+                .set(self.future_local_items.get() + 1);
+            self.max_local_skew.set(self.max_local_skew.get().max(-age));
+        }
+        // Tiebreaks only exist for items that are the same age on both
+        // sides, so making the losing side a millisecond older is enough to
+        // make the winning side newer. See `fetch_value_tiebreaks`.
+        item.age = self.clamp_age(age) + tiebreak.map_or(0, |local_wins| i64::from(!local_wins));
         item.needs_merge = row.get::<_, u32>("syncChangeCounter")? > 0;
         Ok(item)
     }
@@ -710,18 +1738,15 @@ impl<'a> Merger<'a> {
     /// Creates a remote tree item from a row in `moz_bookmarks_synced`.
     fn remote_row_to_item(&self, row: &Row<'_>) -> Result<Item> {
         let guid = row.get::<_, SyncGuid>("guid")?;
-        let kind = SyncedBookmarkKind::from_u8(row.get("kind")?)?;
+        let kind = SyncedBookmarkKind::try_from(row.get::<_, u8>("kind")?)?;
+        let tiebreak = self.value_tiebreaks.get(&guid).cloned();
         let mut item = Item::new(guid.into(), kind.into());
-        // note that serverModified in this table is an int with ms, which isn't
-        // the format of a ServerTimestamp - so we convert it into a number
-        // of seconds before creating a ServerTimestamp and doing duration_since.
-        let age = self
-            .remote_time
-            .duration_since(ServerTimestamp(
-                row.get::<_, f64>("serverModified")? / 1000.0,
-            ))
-            .unwrap_or_default();
-        item.age = age.as_secs() as i64 * 1000 + i64::from(age.subsec_millis());
+        let age = self.remote_age(row.get("serverModified")?);
+        if age < 0 {
+            self.future_remote_items
+                .set(self.future_remote_items.get() + 1);
+        }
+        item.age = self.clamp_age(age) + tiebreak.map_or(0, i64::from);
         item.needs_merge = row.get("needsMerge")?;
         item.validity = SyncedBookmarkValidity::from_u8(row.get("validity")?)?.into();
         Ok(item)
@@ -850,22 +1875,49 @@ impl<'a> dogear::Store<Error> for Merger<'a> {
             .ok_or_else(|| ErrorKind::Corruption(Corruption::InvalidSyncedRoots))?;
         builder.reparent_orphans_to(&dogear::UNFILED_GUID);
 
+        // An item is an orphan if its parent doesn't exist, and no folder
+        // lists it in its `children`. This happens when an older client
+        // uploads an item, but not its parent.
         let sql = format!(
-            "SELECT guid, parentGuid, serverModified, kind, needsMerge, validity
-             FROM moz_bookmarks_synced
-             WHERE NOT isDeleted AND
-                   guid <> '{root_guid}'
-             ORDER BY guid",
+            "SELECT v.guid, v.parentGuid, v.serverModified, v.kind, v.needsMerge,
+                    v.validity,
+                    NOT EXISTS(SELECT 1 FROM moz_bookmarks_synced p
+                               WHERE p.guid = v.parentGuid AND
+                                     NOT p.isDeleted) AND
+                    NOT EXISTS(SELECT 1 FROM moz_bookmarks_synced_structure s
+                               WHERE s.guid = v.guid) AS isOrphan
+             FROM moz_bookmarks_synced v
+             WHERE NOT v.isDeleted AND
+                   v.guid <> '{root_guid}'
+             ORDER BY v.guid",
             root_guid = BookmarkRootGuid::Root.as_guid().as_ref()
         );
+        let mut reparented_orphans = 0;
         let mut stmt = self.store.db.prepare(&sql)?;
         let mut results = stmt.query(NO_PARAMS)?;
         while let Some(row) = results.next()? {
-            let p = builder.item(self.remote_row_to_item(&row)?)?;
+            let mut item = self.remote_row_to_item(&row)?;
+            if row.get("isOrphan")? {
+                // Move orphans to unfiled, and reupload them, so that other
+                // clients see them in the same place.
+                item.validity = dogear::Validity::Reupload;
+                builder
+                    .item(item)?
+                    .by_parent_guid(dogear::UNFILED_GUID.clone())?;
+                reparented_orphans += 1;
+                continue;
+            }
+            let p = builder.item(item)?;
             if let Some(parent_guid) = row.get::<_, Option<SyncGuid>>("parentGuid")? {
                 p.by_parent_guid(parent_guid.into())?;
             }
         }
+        if reparented_orphans > 0 {
+            log::warn!(
+                "Moved {} orphaned remote items to unfiled",
+                reparented_orphans
+            );
+        }
 
         let sql = format!(
             "SELECT guid, parentGuid FROM moz_bookmarks_synced_structure
@@ -896,6 +1948,8 @@ impl<'a> dogear::Store<Error> for Merger<'a> {
             tree.note_deleted(guid.into());
         }
 
+        self.record_validation(&tree, reparented_orphans)?;
+
         Ok(tree)
     }
 
@@ -906,21 +1960,44 @@ impl<'a> dogear::Store<Error> for Merger<'a> {
 
         let sql = format!(
             "SELECT v.guid, v.kind, IFNULL(v.title, '') AS title, h.url,
-                    s.position
+                    IFNULL(s.position, v.position) AS position
              FROM moz_bookmarks_synced v
-             JOIN moz_bookmarks_synced_structure s ON s.guid = v.guid
+             LEFT JOIN moz_bookmarks_synced_structure s ON s.guid = v.guid
              LEFT JOIN moz_places h ON h.id = v.placeId
              LEFT JOIN moz_bookmarks b ON b.guid = v.guid
              WHERE NOT v.isDeleted AND
                    v.needsMerge AND
                    b.guid IS NULL AND
-                   s.parentGuid <> '{root_guid}'",
-            root_guid = BookmarkRootGuid::Root.as_guid().as_ref()
+                   -- Separators that we've seen before their parents don't
+                   -- have structure yet, so we fall back to the parent and
+                   -- position from the record. Other items need structure.
+                   (s.guid NOT NULL OR v.kind = {separator_kind}) AND
+                   IFNULL(s.parentGuid, v.parentGuid) <> '{root_guid}' AND
+                   -- If a sibling with the same content has `hasDupe` set,
+                   -- another client already knows about the duplicates, so
+                   -- prefer deduping local items to that sibling.
+                   (v.hasDupe OR NOT EXISTS(
+                     SELECT 1 FROM moz_bookmarks_synced d
+                     JOIN moz_bookmarks_synced_structure ds ON ds.guid = d.guid
+                     LEFT JOIN moz_bookmarks bd ON bd.guid = d.guid
+                     WHERE d.hasDupe AND
+                           d.guid <> v.guid AND
+                           NOT d.isDeleted AND
+                           d.needsMerge AND
+                           bd.guid IS NULL AND
+                           ds.parentGuid = s.parentGuid AND
+                           d.kind = v.kind AND
+                           d.kind <> {separator_kind} AND
+                           IFNULL(d.title, '') = IFNULL(v.title, '') AND
+                           d.placeId IS v.placeId
+                   ))",
+            root_guid = BookmarkRootGuid::Root.as_guid().as_ref(),
+            separator_kind = SyncedBookmarkKind::Separator as u8,
         );
         let mut stmt = self.store.db.prepare(&sql)?;
         let mut results = stmt.query(NO_PARAMS)?;
         while let Some(row) = results.next()? {
-            let content = match SyncedBookmarkKind::from_u8(row.get("kind")?)? {
+            let content = match SyncedBookmarkKind::try_from(row.get::<_, u8>("kind")?)? {
                 SyncedBookmarkKind::Bookmark | SyncedBookmarkKind::Query => {
                     let title = row.get("title")?;
                     let url_href = row.get("url")?;
@@ -930,10 +2007,10 @@ impl<'a> dogear::Store<Error> for Merger<'a> {
                     let title = row.get("title")?;
                     Content::Folder { title }
                 }
-                SyncedBookmarkKind::Separator => {
-                    let position = row.get("position")?;
-                    Content::Separator { position }
-                }
+                SyncedBookmarkKind::Separator => match row.get("position")? {
+                    Some(position) => Content::Separator { position },
+                    None => continue,
+                },
                 _ => continue,
             };
             let guid = row.get::<_, SyncGuid>("guid")?;
@@ -951,16 +2028,27 @@ impl<'a> dogear::Store<Error> for Merger<'a> {
         let descendants = root.descendants();
         let deletions = deletions.collect::<Vec<_>>();
 
+        // If any of the statements below fail, we must roll back everything,
+        // including changes that the merge triggers already made to the
+        // local tree. The incoming records stay staged in the mirror with
+        // `needsMerge` set, so the next sync retries the merge without
+        // downloading them again.
         let mut tx = self.store.db.begin_transaction()?;
-        self.store
-            .update_local_items(descendants, deletions, &mut tx)?;
-        self.store.stage_local_items_to_upload()?;
-        self.store.db.execute_batch(
-            "DELETE FROM mergedTree;
-             DELETE FROM idsToWeaklyUpload;",
-        )?;
-        tx.commit()?;
-        Ok(())
+        let result = self
+            .store
+            .update_local_items(descendants, deletions, &mut tx)
+            .and_then(|_| self.store.stage_local_items_to_upload())
+            .and_then(|_| {
+                Ok(self.store.db.execute_batch(
+                    "DELETE FROM mergedTree;
+                     DELETE FROM idsToWeaklyUpload;",
+                )?)
+            });
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
     }
 }
 
@@ -1078,17 +2166,27 @@ impl<'a> fmt::Display for RootsFragment<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::{test::new_mem_api, ConnectionType, PlacesApi};
+    use crate::api::matcher::{search_frecent, SearchParams};
+    use crate::api::places_api::{ConnectionType, PlacesApi};
     use crate::bookmark_sync::store::BookmarksStore;
     use crate::db::PlacesDb;
+    use crate::frecency::DEFAULT_FRECENCY_SETTINGS;
     use crate::storage::{
-        bookmarks::{get_raw_bookmark, update_bookmark, UpdatableBookmark, USER_CONTENT_ROOTS},
-        history::frecency_stale_at,
-        tags,
+        bookmarks::{
+            delete_bookmark, folder_changed_since, get_folder_change_token, get_query_bookmarks,
+            get_raw_bookmark, insert_bookmark, set_query_bookmark_hidden, set_root_titles,
+            update_bookmark, BookmarkPosition, InsertableBookmark, PlaceQueryKind, QueryBookmark,
+            UpdatableBookmark, UpdateTreeLocation, USER_CONTENT_ROOTS,
+        },
+        deletion_log::get_deletion_log,
+        history::{frecency_stale_at, get_frecencies},
+        keywords, tags,
     };
-    use crate::tests::{
+    use crate::testing::{
         assert_json_tree as assert_local_json_tree, insert_json_tree as insert_local_json_tree,
+        new_mem_api, SyncedBookmarkItem,
     };
+    use crate::types::FrozenClock;
     use dogear::{Store as DogearStore, Validity};
     use pretty_assertions::assert_eq;
     use serde_json::{json, Value};
@@ -1246,7 +2344,8 @@ mod tests {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
 
         let interrupt_scope = syncer.begin_interrupt_scope();
         let store = BookmarksStore::new(&syncer, &interrupt_scope);
@@ -1340,7 +2439,8 @@ mod tests {
                     }
                 ],
             }),
-        );
+        )
+        .expect("should insert tree");
         assert_incoming_creates_local_tree(
             &api,
             json!([{
@@ -1404,6 +2504,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apply_sortindex_boosts_frecency() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/local-page",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        let syncer = api.open_sync_connection()?;
+        apply_incoming(
+            &syncer,
+            json!([{
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "B",
+                "bmkUri": "http://example.com/remote-page",
+                "sortindex": 2000,
+            }, {
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "places",
+                "dateAdded": 0,
+                "title": "Unfiled",
+                "children": ["bookmarkBBBB"],
+            }]),
+        );
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        store.update_frecencies()?;
+
+        let local_url = Url::parse("http://example.com/local-page")?;
+        let remote_url = Url::parse("http://example.com/remote-page")?;
+        let frecencies = get_frecencies(&syncer, &[local_url.clone(), remote_url.clone()])?;
+        let unvisited_bookmark_frecency = DEFAULT_FRECENCY_SETTINGS.unvisited_bookmark_bonus;
+        assert_eq!(
+            frecencies,
+            vec![
+                Some(unvisited_bookmark_frecency),
+                Some(
+                    unvisited_bookmark_frecency
+                        + DEFAULT_FRECENCY_SETTINGS.max_remote_sortindex_bonus
+                ),
+            ],
+            "Should clamp the sortindex bonus for the remote bookmark"
+        );
+
+        let results = search_frecent(
+            &syncer,
+            SearchParams {
+                search_string: "page".into(),
+                limit: 10,
+            },
+        )?;
+        assert_eq!(
+            results.into_iter().map(|r| r.url).collect::<Vec<_>>(),
+            vec![remote_url, local_url],
+            "Should rank the remote bookmark first"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_apply_query() {
         // should we add some more query variations here?
@@ -1442,59 +2616,47 @@ mod tests {
     }
 
     #[test]
-    fn test_apply() -> Result<()> {
+    fn test_apply_livemarks() -> Result<()> {
         let api = new_mem_api();
         let writer = api.open_connection(ConnectionType::ReadWrite)?;
         let syncer = api.open_sync_connection()?;
 
-        syncer
-            .execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)
-            .expect("should work");
-
-        insert_local_json_tree(
-            &writer,
+        let records = vec![
             json!({
-                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
-                "children": [
-                    {
-                        "guid": "bookmarkAAAA",
-                        "title": "A",
-                        "url": "http://example.com/a",
-                    },
-                    {
-                        "guid": "bookmarkBBBB",
-                        "title": "B",
-                        "url": "http://example.com/b",
-                    },
-                ]
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "places",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "Unfiled",
+                "children": ["livemarkAAAA", "livemarkBBBB", "livemarkCCCC"],
             }),
-        );
-        tags::tag_url(
-            &writer,
-            &Url::parse("http://example.com/a").expect("Should parse URL for A"),
-            "baz",
-        )
-        .expect("Should tag A");
-
-        let records = vec![
             json!({
-                "id": "bookmarkCCCC",
-                "type": "bookmark",
-                "parentid": "menu",
-                "parentName": "menu",
-                "dateAdded": 1_552_183_116_885u64,
-                "title": "C",
-                "bmkUri": "http://example.com/c",
-                "tags": ["foo", "bar"],
+                "id": "livemarkAAAA",
+                "type": "livemark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "feedUri": "http://example.com/a/feed",
+                "siteUri": "http://example.com/a",
             }),
             json!({
-                "id": "menu",
-                "type": "folder",
-                "parentid": "places",
-                "parentName": "",
-                "dateAdded": 0,
-                "title": "menu",
-                "children": ["bookmarkCCCC"],
+                "id": "livemarkBBBB",
+                "type": "livemark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "B",
+                "feedUri": "http://example.com/b/feed",
+            }),
+            json!({
+                "id": "livemarkCCCC",
+                "type": "livemark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "C",
+                "siteUri": "foo",
             }),
         ];
 
@@ -1508,138 +2670,110 @@ mod tests {
             incoming.changes.push((payload, ServerTimestamp(0.0)));
         }
 
-        let mut outgoing = store
+        let outgoing = store
             .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
             .expect("Should apply incoming and stage outgoing records");
-        outgoing.changes.sort_by(|a, b| a.id.cmp(&b.id));
-        assert_eq!(
-            outgoing.changes.iter().map(|p| &p.id).collect::<Vec<_>>(),
-            vec!["bookmarkAAAA", "bookmarkBBBB", "unfiled",]
-        );
-        let record_for_a = outgoing
-            .changes
-            .iter()
-            .find(|p| p.id == "bookmarkAAAA")
-            .expect("Should upload A");
-        assert_eq!(
-            record_for_a.data["tags"]
-                .as_array()
-                .expect("Should upload tags for A"),
-            &["baz"]
-        );
 
+        // Livemarks with a site or feed URL become bookmarks, and the
+        // livemark without either is deleted.
         assert_local_json_tree(
             &writer,
-            &BookmarkRootGuid::Root.as_guid(),
+            &BookmarkRootGuid::Unfiled.as_guid(),
             json!({
-                "guid": &BookmarkRootGuid::Root.as_guid(),
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
                 "children": [
                     {
-                        "guid": &BookmarkRootGuid::Menu.as_guid(),
-                        "children": [
-                            {
-                                "guid": "bookmarkCCCC",
-                                "title": "C",
-                                "url": "http://example.com/c",
-                                "date_added": Timestamp(1_552_183_116_885),
-                            },
-                        ],
-                    },
-                    {
-                        "guid": &BookmarkRootGuid::Toolbar.as_guid(),
-                        "children": [],
-                    },
-                    {
-                        "guid": &BookmarkRootGuid::Unfiled.as_guid(),
-                        "children": [
-                            {
-                                "guid": "bookmarkAAAA",
-                                "title": "A",
-                                "url": "http://example.com/a",
-                            },
-                            {
-                                "guid": "bookmarkBBBB",
-                                "title": "B",
-                                "url": "http://example.com/b",
-                            },
-                        ],
+                        "guid": "livemarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
                     },
                     {
-                        "guid": &BookmarkRootGuid::Mobile.as_guid(),
-                        "children": [],
+                        "guid": "livemarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b/feed",
                     },
                 ],
             }),
         );
 
-        // We haven't finished the sync yet, so all local change counts for
-        // items to upload should still be > 0.
-        let guid_for_a: SyncGuid = "bookmarkAAAA".into();
-        let info_for_a = get_raw_bookmark(&writer, &guid_for_a)
-            .expect("Should fetch info for A")
-            .unwrap();
-        assert_eq!(info_for_a.sync_change_counter, 1);
-        let info_for_unfiled = get_raw_bookmark(&writer, &BookmarkRootGuid::Unfiled.as_guid())
-            .expect("Should fetch info for unfiled")
-            .unwrap();
-        assert_eq!(info_for_unfiled.sync_change_counter, 1);
+        // The converted bookmarks should replace the livemarks on the
+        // server, and the unusable livemark should be deleted.
+        let record_for_a = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "livemarkAAAA")
+            .expect("Should upload A");
+        assert_eq!(record_for_a.data["type"], "bookmark");
+        assert_eq!(record_for_a.data["bmkUri"], "http://example.com/a");
+        let record_for_b = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "livemarkBBBB")
+            .expect("Should upload B");
+        assert_eq!(record_for_b.data["type"], "bookmark");
+        assert_eq!(record_for_b.data["bmkUri"], "http://example.com/b/feed");
+        let record_for_c = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "livemarkCCCC")
+            .expect("Should upload a tombstone for C");
+        assert!(record_for_c.deleted);
 
+        // Once uploaded, the livemarks are replaced with bookmarks in the
+        // mirror, too, so we don't reupload them on the next sync.
         store
             .sync_finished(
-                ServerTimestamp(0.0),
-                vec![
-                    "bookmarkAAAA".into(),
-                    "bookmarkBBBB".into(),
-                    "unfiled".into(),
-                ],
+                ServerTimestamp(1.0),
+                outgoing.changes.iter().map(|p| p.id.clone()).collect(),
             )
             .expect("Should push synced changes back to the store");
-
-        let info_for_a = get_raw_bookmark(&writer, &guid_for_a)
-            .expect("Should fetch info for A")
-            .unwrap();
-        assert_eq!(info_for_a.sync_change_counter, 0);
-        let info_for_unfiled = get_raw_bookmark(&writer, &BookmarkRootGuid::Unfiled.as_guid())
-            .expect("Should fetch info for unfiled")
-            .unwrap();
-        assert_eq!(info_for_unfiled.sync_change_counter, 0);
-
-        let mut tags_for_c = tags::get_tags_for_url(
-            &writer,
-            &Url::parse("http://example.com/c").expect("Should parse URL for C"),
-        )
-        .expect("Should return tags for C");
-        tags_for_c.sort();
-        assert_eq!(tags_for_c, &["bar", "foo"]);
+        let synced_a = SyncedBookmarkItem::get(&syncer, &"livemarkAAAA".into())?
+            .expect("Should keep A in the mirror");
+        assert_eq!(
+            synced_a,
+            *SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Valid)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .needs_merge(false)
+                .url(Some("http://example.com/a"))
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_keywords() -> Result<()> {
+    fn test_telemetry() -> Result<()> {
         let api = new_mem_api();
-        let writer = api.open_connection(ConnectionType::ReadWrite)?;
         let syncer = api.open_sync_connection()?;
 
         let records = vec![
             json!({
-                "id": "toolbar",
+                "id": "unfiled",
                 "type": "folder",
                 "parentid": "places",
-                "parentName": "",
-                "dateAdded": 0,
-                "title": "toolbar",
-                "children": ["bookmarkAAAA"],
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "Unfiled",
+                "children": ["bookmarkAAAA", "queryBBBBBBB", "missingCCCCC"],
             }),
             json!({
                 "id": "bookmarkAAAA",
                 "type": "bookmark",
-                "parentid": "toolbar",
-                "parentName": "toolbar",
-                "dateAdded": 1_552_183_116_885u64,
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
                 "title": "A",
                 "bmkUri": "http://example.com/a",
-                "keyword": "a",
+            }),
+            // A tag query without a tag folder name can't be applied, so we
+            // replace it with a tombstone.
+            json!({
+                "id": "queryBBBBBBB",
+                "type": "query",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "B",
+                "bmkUri": "place:type=7",
             }),
         ];
 
@@ -1655,39 +2789,3357 @@ mod tests {
 
         let outgoing = store
             .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
-            .expect("Should apply incoming records");
-        let mut outgoing_ids = outgoing
+            .expect("Should apply incoming and stage outgoing records");
+        let validation = store.take_telemetry().validation;
+        assert_eq!(
+            validation,
+            ValidationTelemetry {
+                valid: 2,
+                reupload: 0,
+                replace: 1,
+                orphans: 0,
+                missing_children: 1,
+                reparented_orphans: 0,
+                non_syncable_local: 0,
+                non_syncable_remote: 0,
+            }
+        );
+        // The sync ping only includes the problems we found.
+        assert_eq!(
+            serde_json::to_value(validation.to_validation()).unwrap(),
+            json!({
+                "version": 1,
+                "problems": [
+                    {"name": "replace", "count": 1},
+                    {"name": "missingChildren", "count": 1}
+                ]
+            })
+        );
+
+        // Report everything except one item as uploaded, so that we count it
+        // as a failure.
+        let (tombstones, items): (Vec<_>, Vec<_>) =
+            outgoing.changes.iter().partition(|p| p.deleted);
+        assert_eq!(
+            tombstones.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+            vec!["queryBBBBBBB"]
+        );
+        assert!(items.len() > 1);
+        let records_synced = outgoing
             .changes
             .iter()
+            .filter(|p| p.id != items[0].id)
             .map(|p| p.id.clone())
             .collect::<Vec<_>>();
-        outgoing_ids.sort();
-        assert_eq!(outgoing_ids, &["menu", "mobile", "toolbar", "unfiled"],);
-
         store
-            .sync_finished(ServerTimestamp(0.0), outgoing_ids)
+            .sync_finished(ServerTimestamp(1.0), records_synced)
             .expect("Should push synced changes back to the store");
 
-        update_bookmark(
-            &writer,
-            &"bookmarkAAAA".into(),
-            &UpdatableBookmark {
-                title: Some("A (local)".into()),
-                ..UpdatableBookmark::default()
+        let outgoing_telemetry = store.take_telemetry().outgoing;
+        assert_eq!(
+            outgoing_telemetry,
+            OutgoingTelemetry {
+                uploaded: items.len() - 1,
+                tombstones: 1,
+                failed: 1,
+            }
+        );
+
+        // Taking the telemetry resets it.
+        assert_eq!(store.take_telemetry(), BookmarksTelemetry::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reupload_unacknowledged_items() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    { "guid": "bookmarkAAAA", "title": "A", "url": "http://example.com/a" },
+                    { "guid": "bookmarkBBBB", "title": "B", "url": "http://example.com/b" },
+                    { "guid": "bookmarkCCCC", "title": "C", "url": "http://example.com/c" },
+                ],
+            }),
+        )?;
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let stage = || -> Vec<String> {
+            let outgoing = store
+                .apply_incoming(
+                    IncomingChangeset::new(
+                        store.collection_name().to_string(),
+                        ServerTimestamp(0.0),
+                    ),
+                    &mut telemetry::EngineIncoming::new(),
+                )
+                .expect("Should stage outgoing records");
+            let mut ids = outgoing
+                .changes
+                .into_iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        // The first sync uploads everything, and the server accepts it.
+        let records_synced = stage();
+        let outgoing = store.finish_sync(ServerTimestamp(0.0), records_synced.clone())?;
+        assert_eq!(outgoing.uploaded, records_synced.len());
+        assert_eq!(outgoing.failed, 0);
+
+        for (guid, title) in &[
+            ("bookmarkAAAA", "A (updated)"),
+            ("bookmarkBBBB", "B (updated)"),
+            ("bookmarkCCCC", "C (updated)"),
+        ] {
+            update_bookmark(
+                &writer,
+                &(*guid).into(),
+                &UpdatableBookmark {
+                    title: Some(title.to_string()),
+                    ..UpdatableBookmark::default()
+                }
+                .into(),
+            )?;
+        }
+        assert_eq!(
+            stage(),
+            vec!["bookmarkAAAA", "bookmarkBBBB", "bookmarkCCCC"]
+        );
+
+        // The server only accepts A, so we should reupload B and C on the
+        // next sync.
+        let outgoing = store.finish_sync(ServerTimestamp(1.0), vec!["bookmarkAAAA".into()])?;
+        assert_eq!(
+            outgoing,
+            OutgoingTelemetry {
+                uploaded: 1,
+                tombstones: 0,
+                failed: 2,
+            }
+        );
+        assert_eq!(stage(), vec!["bookmarkBBBB", "bookmarkCCCC"]);
+
+        // Another device changes A, with a newer date added. We take its
+        // title, and upload A weakly, without bumping its change counter,
+        // to fix the date added on the server.
+        let local_date_added = get_raw_bookmark(&writer, &"bookmarkAAAA".into())?
+            .expect("Should fetch A")
+            .date_added;
+        let outgoing = store.apply_incoming(
+            incoming_changeset(
+                &store,
+                ServerTimestamp(2.0),
+                json!([{
+                    "id": "bookmarkAAAA",
+                    "type": "bookmark",
+                    "parentid": "unfiled",
+                    "parentName": "Unfiled",
+                    "dateAdded": local_date_added.as_millis() + 1000,
+                    "title": "A (remote)",
+                    "bmkUri": "http://example.com/a",
+                }]),
+            ),
+            &mut telemetry::EngineIncoming::new(),
+        )?;
+        let mut ids = outgoing
+            .changes
+            .into_iter()
+            .map(|p| p.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec!["bookmarkAAAA", "bookmarkBBBB", "bookmarkCCCC"]);
+        let a = get_raw_bookmark(&syncer, &"bookmarkAAAA".into())?.expect("Should fetch A");
+        assert_eq!(a.title, Some("A (remote)".into()));
+        assert_eq!(a.sync_change_counter, 0);
+
+        // The server doesn't accept the weak upload, so we should stage A
+        // again on the next sync, even though nothing changed locally.
+        let outgoing = store.finish_sync(
+            ServerTimestamp(3.0),
+            vec!["bookmarkBBBB".into(), "bookmarkCCCC".into()],
+        )?;
+        assert_eq!(outgoing.failed, 1);
+        assert_eq!(stage(), vec!["bookmarkAAAA"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_orphan() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        // The parent folder for A never arrives.
+        let records = vec![json!({
+            "id": "bookmarkAAAA",
+            "type": "bookmark",
+            "parentid": "folderBBBBBB",
+            "parentName": "B",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "A",
+            "bmkUri": "http://example.com/a",
+        })];
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming and stage outgoing records");
+        assert_eq!(store.take_telemetry().validation.reparented_orphans, 1);
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        );
+
+        // We should reupload A with its new parent, and unfiled with A as
+        // its child.
+        let record_for_a = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkAAAA")
+            .expect("Should reupload A");
+        assert_eq!(record_for_a.data["parentid"], "unfiled");
+        let record_for_unfiled = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "unfiled")
+            .expect("Should upload unfiled");
+        assert_eq!(record_for_unfiled.data["children"], json!(["bookmarkAAAA"]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_case_colliding_guids() {
+        let api = new_mem_api();
+        assert_incoming_creates_local_tree(
+            &api,
+            json!([{
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+            },
+            {
+                "id": "bookmarkaaaa",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled Bookmarks",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "a",
+                "bmkUri": "http://example.com/a",
+            },
+            {
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "root",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "Unfiled",
+                "children": ["bookmarkAAAA"],
+            }]),
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({"children" : [{"guid": "bookmarkAAAA", "title": "A", "url": "http://example.com/a"}]}),
+        );
+    }
+
+    #[test]
+    fn test_repair_case_colliding_guids_round_trip() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend an older version gave B a GUID that collides with A's.
+        writer.execute_batch(
+            "UPDATE moz_bookmarks SET guid = 'bookmarkaaaa' WHERE guid = 'bookmarkBBBB'",
+        )?;
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let outgoing = store.apply_incoming(
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+            &mut telemetry::EngineIncoming::new(),
+        )?;
+        store
+            .sync_finished(
+                ServerTimestamp(1.0),
+                outgoing.changes.iter().map(|p| p.id.clone()).collect(),
+            )
+            .expect("Should push synced changes back to the store");
+
+        let report = bookmarks::repair(&writer)?;
+        assert_eq!(report.guid_case_collisions, 1);
+        let new_guid = writer.query_row_and_then_named(
+            "SELECT guid FROM moz_bookmarks WHERE title = 'B'",
+            &[],
+            |row| row.get::<_, SyncGuid>(0),
+            false,
+        )?;
+        assert_eq!(
+            syncer.query_one::<i64>(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced WHERE guid = 'bookmarkaaaa'"
+            )?,
+            0
+        );
+
+        // The next sync should upload B under its new GUID, without bringing
+        // back the old one.
+        let outgoing = store.apply_incoming(
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0)),
+            &mut telemetry::EngineIncoming::new(),
+        )?;
+        let mut outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        outgoing_ids.sort();
+        let mut expected_ids = vec!["menu".to_string(), new_guid.as_ref().to_string()];
+        expected_ids.sort();
+        assert_eq!(outgoing_ids, expected_ids);
+        store
+            .sync_finished(ServerTimestamp(2.0), outgoing_ids)
+            .expect("Should push synced changes back to the store");
+
+        let outgoing = store.apply_incoming(
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(2.0)),
+            &mut telemetry::EngineIncoming::new(),
+        )?;
+        assert!(outgoing.changes.is_empty());
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": &new_guid,
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                ],
+            }),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_separator_before_folder() {
+        let api = new_mem_api();
+        let writer = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("Should open read-write connection");
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "separatorAAA",
+                        "type": BookmarkType::Separator as u8,
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    }
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        // The separator record comes before its parent folder, so it doesn't
+        // have structure yet when we stage it.
+        assert_incoming_creates_local_tree(
+            &api,
+            json!([{
+                "id": "separatorCCC",
+                "type": "separator",
+                "parentid": "menu",
+                "parentName": "menu",
+                "pos": 0,
+            },
+            {
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["separatorCCC"],
+            }]),
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({"children" : [
+                {"guid": "separatorCCC", "type": BookmarkType::Separator as u8},
+                {"guid": "bookmarkBBBB", "title": "B", "url": "http://example.com/b"},
+            ]}),
+        );
+    }
+
+    #[test]
+    fn test_dedupe_prefers_has_dupe() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        let local_id: i64 = writer.query_row_and_then_named(
+            "SELECT id FROM moz_bookmarks WHERE guid = 'bookmarkAAAA'",
+            &[],
+            |row| row.get(0),
+            false,
+        )?;
+
+        // Both incoming bookmarks have the same content as the local one, but
+        // only C has `hasDupe` set, so we should dedupe A to C instead of B.
+        assert_incoming_creates_local_tree(
+            &api,
+            json!([{
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkBBBB", "bookmarkCCCC"],
+            }, {
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "hasDupe": false,
+            }, {
+                "id": "bookmarkCCCC",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "hasDupe": true,
+            }]),
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({"children" : [
+                {"guid": "bookmarkBBBB", "title": "A", "url": "http://example.com/a"},
+                {"guid": "bookmarkCCCC", "title": "A", "url": "http://example.com/a"},
+            ]}),
+        );
+
+        // Deduping changes the local item's GUID to the remote item's, so
+        // the row that we inserted for A should now be C.
+        let deduped_guid: String = writer.query_row_and_then_named(
+            "SELECT guid FROM moz_bookmarks WHERE id = :id",
+            &[(":id", &local_id)],
+            |row| row.get(0),
+            false,
+        )?;
+        assert_eq!(deduped_guid, "bookmarkCCCC");
+
+        let syncer = api.open_sync_connection()?;
+        let has_dupes = syncer.query_rows_and_then_named(
+            "SELECT guid, hasDupe FROM moz_bookmarks_synced
+             WHERE guid IN ('menu________', 'bookmarkBBBB', 'bookmarkCCCC')
+             ORDER BY guid",
+            &[],
+            |row| -> Result<_> {
+                Ok((
+                    row.get::<_, String>("guid")?,
+                    row.get::<_, bool>("hasDupe")?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            has_dupes,
+            vec![
+                ("bookmarkBBBB".to_string(), false),
+                ("bookmarkCCCC".to_string(), true),
+                ("menu________".to_string(), false),
+            ],
+            "Should store incoming `hasDupe` flags in the mirror"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_localized_root_titles() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        writer
+            .execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)
+            .expect("should work");
+        set_root_titles(
+            &writer,
+            &[
+                (BookmarkRootGuid::Unfiled, "Autres marque-pages"),
+                (BookmarkRootGuid::Mobile, "Marque-pages mobiles"),
+            ],
+        )?;
+        let unfiled = get_raw_bookmark(&writer, &BookmarkRootGuid::Unfiled.as_guid())?
+            .expect("should get unfiled");
+        assert_eq!(unfiled.title, Some("Autres marque-pages".into()));
+        assert_eq!(
+            unfiled.sync_change_counter, 0,
+            "Changing root titles shouldn't bump the change counter"
+        );
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        let payload = Payload::from_json(json!({
+            "id": "unfiled",
+            "type": "folder",
+            "parentid": "places",
+            "parentName": "",
+            "dateAdded": 0,
+            "title": "Other Bookmarks",
+            "children": [],
+        }))
+        .unwrap();
+        incoming.changes.push((payload, ServerTimestamp(0.0)));
+
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming and stage outgoing records");
+        assert!(
+            outgoing.changes.iter().all(|p| p.id != "unfiled"),
+            "Shouldn't upload unfiled because of its remote title"
+        );
+
+        let unfiled = get_raw_bookmark(&writer, &BookmarkRootGuid::Unfiled.as_guid())?
+            .expect("should get unfiled");
+        assert_eq!(
+            unfiled.title,
+            Some("Autres marque-pages".into()),
+            "Should keep the local root title"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reparent_non_syncable_local_items() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        // A bookmark parented directly to the Places root.
+        writer.execute_batch(&format!(
+            "INSERT INTO moz_places(guid, url, url_hash)
+             VALUES('placeAAAAAAA', 'http://example.com/a',
+                    hash('http://example.com/a'));
+             INSERT INTO moz_bookmarks(guid, parent, position, type, fk, title)
+             VALUES('bookmarkAAAA',
+                    (SELECT id FROM moz_bookmarks WHERE guid = '{root}'),
+                    4, {bookmark},
+                    (SELECT id FROM moz_places WHERE guid = 'placeAAAAAAA'),
+                    'A');",
+            root = BookmarkRootGuid::Root.as_guid().as_ref(),
+            bookmark = BookmarkType::Bookmark as u8,
+        ))?;
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let mut outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should apply incoming and stage outgoing records");
+        outgoing.changes.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let record_for_a = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkAAAA")
+            .expect("Should reupload A");
+        assert_eq!(record_for_a.data["parentid"], "unfiled");
+        let record_for_unfiled = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "unfiled")
+            .expect("Should reupload unfiled");
+        assert_eq!(record_for_unfiled.data["children"], json!(["bookmarkAAAA"]));
+        let validation = store.take_telemetry().validation;
+        assert_eq!(validation.non_syncable_local, 1);
+        assert_eq!(validation.non_syncable_remote, 0);
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({"children": [{"guid": "bookmarkAAAA", "title": "A", "url": "http://example.com/a"}]}),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reparent_non_syncable_remote_items() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        let records = vec![
+            json!({
+                "id": "weird_root___",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "Weird",
+                "children": ["bookmarkAAAA"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "weird_root___",
+                "parentName": "Weird",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+            }),
+        ];
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming and stage outgoing records");
+        assert!(
+            outgoing.changes.iter().any(|p| p.id == "unfiled"),
+            "Should reupload unfiled"
+        );
+        let validation = store.take_telemetry().validation;
+        assert_eq!(validation.non_syncable_local, 0);
+        assert_eq!(validation.non_syncable_remote, 1);
+
+        // The unknown root's GUID isn't valid, so we might replace it; but
+        // its contents should end up in unfiled.
+        let bookmark = get_raw_bookmark(&writer, &"bookmarkAAAA".into())?
+            .expect("Should apply bookmark under unknown root");
+        let folder = get_raw_bookmark(
+            &writer,
+            &bookmark.parent_guid.expect("Bookmark should have a parent"),
+        )?
+        .expect("Should apply unknown root");
+        assert_eq!(folder.title, Some("Weird".into()));
+        assert_eq!(
+            folder.parent_guid,
+            Some(BookmarkRootGuid::Unfiled.as_guid())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_incoming_matches_applicator() -> Result<()> {
+        // `BookmarksStore` stages incoming records with `IncomingApplicator`,
+        // which has its own tests for each kind. This checks that staging
+        // through the store doesn't change what ends up in the mirror.
+        let records = vec![
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkAAAA", "queryBBBBBBB", "separatorCCC", "livemarkDDDD"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "tags": ["foo", " bar "],
+                "keyword": "a",
+                "iconUri": "http://example.com/a.ico",
+            }),
+            json!({
+                "id": "queryBBBBBBB",
+                "type": "query",
+                "parentid": "menu",
+                "parentName": "menu",
+                "bmkUri": "place:type=7",
+                "folderName": "foo",
+            }),
+            json!({
+                "id": "separatorCCC",
+                "type": "separator",
+                "parentid": "menu",
+                "parentName": "menu",
+                "pos": 2,
+            }),
+            json!({
+                "id": "livemarkDDDD",
+                "type": "livemark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "siteUri": "http://example.com/d",
+            }),
+            json!({
+                "id": "bookmarkEEEE",
+                "deleted": true,
+            }),
+        ];
+
+        let store_api = new_mem_api();
+        let store_conn = store_api.open_sync_connection()?;
+        let interrupt_scope = store_conn.begin_interrupt_scope();
+        let store = BookmarksStore::new(&store_conn, &interrupt_scope);
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0));
+        for record in &records {
+            let payload = Payload::from_json(record.clone()).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(1.0)));
+        }
+        store.stage_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+        let applicator_api = new_mem_api();
+        let applicator_conn = applicator_api.open_sync_connection()?;
+        let applicator = IncomingApplicator::new(&applicator_conn);
+        for record in &records {
+            let payload = Payload::from_json(record.clone()).unwrap();
+            applicator.apply_payload(payload, ServerTimestamp(1.0))?;
+        }
+        applicator.finish()?;
+
+        for record in &records {
+            let guid = BookmarkRecordId::from_payload_id(
+                record["id"].as_str().expect("id must be a string").into(),
+            );
+            let staged = SyncedBookmarkItem::get(&store_conn, guid.as_guid())?;
+            assert!(staged.is_some(), "Should stage {}", guid.as_guid());
+            assert_eq!(
+                staged,
+                SyncedBookmarkItem::get(&applicator_conn, guid.as_guid())?,
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_only_then_merge() -> Result<()> {
+        let records = json!([{
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "menu",
+            "parentName": "menu",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "A",
+            "children": ["bookmarkBBBB", "bookmarkCCCC"],
+        },
+        {
+            "id": "bookmarkBBBB",
+            "type": "bookmark",
+            "parentid": "folderAAAAAA",
+            "parentName": "A",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "B",
+            "bmkUri": "http://example.com/b",
+        },
+        {
+            "id": "bookmarkCCCC",
+            "type": "bookmark",
+            "parentid": "folderAAAAAA",
+            "parentName": "A",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "C",
+            "bmkUri": "http://example.com/c",
+        },
+        {
+            "id": "menu",
+            "type": "folder",
+            "parentid": "places",
+            "parentName": "",
+            "dateAdded": 0,
+            "title": "menu",
+            "children": ["folderAAAAAA"],
+        }]);
+        let expected_tree = json!({
+            "guid": &BookmarkRootGuid::Menu.as_guid(),
+            "children": [{
+                "guid": "folderAAAAAA",
+                "title": "A",
+                "children": [
+                    {"guid": "bookmarkBBBB", "title": "B", "url": "http://example.com/b"},
+                    {"guid": "bookmarkCCCC", "title": "C", "url": "http://example.com/c"},
+                ],
+            }],
+        });
+
+        // Sync in a single pass.
+        let single_pass_api = new_mem_api();
+        assert_incoming_creates_local_tree(
+            &single_pass_api,
+            records.clone(),
+            &BookmarkRootGuid::Menu.as_guid(),
+            expected_tree.clone(),
+        );
+
+        // Stage, then merge with a new connection and store.
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        {
+            let syncer = api.open_sync_connection()?;
+            let interrupt_scope = syncer.begin_interrupt_scope();
+            let store =
+                BookmarksStore::new(&syncer, &interrupt_scope).with_mode(SyncMode::StageOnly);
+            let mut incoming =
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+            for record in records.as_array().expect("should be an array") {
+                let payload = Payload::from_json(record.clone()).unwrap();
+                incoming.changes.push((payload, ServerTimestamp(0.0)));
+            }
+            let outgoing = store
+                .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+                .expect("Should stage incoming records");
+            assert!(
+                outgoing.changes.is_empty(),
+                "Shouldn't upload anything when staging"
+            );
+        }
+        assert!(
+            get_raw_bookmark(&writer, &"folderAAAAAA".into())?.is_none(),
+            "Shouldn't merge staged items"
+        );
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        assert!(store.has_changes()?, "Should have staged items to merge");
+        store.complete_pending_merge()?;
+        assert_local_json_tree(&writer, &BookmarkRootGuid::Menu.as_guid(), expected_tree);
+        assert!(
+            !store.has_changes()?,
+            "Shouldn't have anything left to merge"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_pending_merge_keeps_weak_uploads() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend we synced A, and that it's older than the incoming record,
+        // so we'll weakly upload it to fix up its date added.
+        writer.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET
+               syncChangeCounter = 0,
+               syncStatus = {normal};
+             UPDATE moz_bookmarks SET dateAdded = 1000
+             WHERE guid = 'bookmarkAAAA';",
+            normal = SyncStatus::Normal as u8,
+        ))?;
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let stager = BookmarksStore::new(&syncer, &interrupt_scope).with_mode(SyncMode::StageOnly);
+        let records = vec![
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A (remote)",
+                "bmkUri": "http://example.com/a",
+            }),
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkAAAA"],
+            }),
+        ];
+        let mut incoming =
+            IncomingChangeset::new(stager.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+        stager
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should stage incoming records");
+
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        store.complete_pending_merge()?;
+        assert_eq!(
+            get_raw_bookmark(&writer, &"bookmarkAAAA".into())?
+                .expect("should exist")
+                .sync_change_counter,
+            1
+        );
+
+        // The next sync should upload A with its older date added.
+        let outgoing = store.apply_incoming(
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+            &mut telemetry::EngineIncoming::new(),
+        )?;
+        let record = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkAAAA")
+            .expect("Should upload A");
+        assert_eq!(record.data["title"], "A (remote)");
+        assert_eq!(record.data["dateAdded"], 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_clock_skew() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "local",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend the local bookmark was changed an hour in the future.
+        let now = Timestamp::now().as_millis() as i64;
+        let hour = 60 * 60 * 1000;
+        writer.execute_named(
+            "UPDATE moz_bookmarks SET lastModified = :lastModified
+             WHERE guid = 'bookmarkAAAA'",
+            &[(":lastModified", &(now + hour))],
+        )?;
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let mut incoming = IncomingChangeset::new(
+            store.collection_name().to_string(),
+            ServerTimestamp(now as f64 / 1000.0),
+        );
+        // The remote bookmark was changed an hour ago, so the local change
+        // should win, even though we treat it as just modified.
+        let records = vec![
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkAAAA"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "remote",
+                "bmkUri": "http://example.com/a",
+            }),
+        ];
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming
+                .changes
+                .push((payload, ServerTimestamp((now - hour) as f64 / 1000.0)));
+        }
+        store.apply_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "local",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        );
+        let skew = store
+            .local_clock_skew
+            .get()
+            .expect("Should detect local clock skew");
+        assert!(skew > LOCAL_CLOCK_SKEW_THRESHOLD_MILLIS && skew <= hour);
+        Ok(())
+    }
+
+    #[test]
+    fn test_future_items_on_both_sides() -> Result<()> {
+        let now = Timestamp::now().as_millis() as i64;
+        let minute = 60 * 1000;
+        let hour = 60 * minute;
+        // The server's clock is an hour behind ours, so we must account for
+        // the difference to tell which side changed the bookmark later. The
+        // collection last changed when the remote bookmark did, which can be
+        // long before the sync, so the ages must be as of the server's
+        // current time, not the collection's.
+        for &(local_offset, remote_ago, expected_title) in &[
+            // The local change is in the future, but within the skew.
+            (10 * minute, 5 * minute, "local"),
+            (-10 * minute, 30 * minute, "local"),
+            (-30 * minute, 10 * minute, "remote"),
+        ] {
+            let api = new_mem_api();
+            let writer = api.open_connection(ConnectionType::ReadWrite)?;
+            insert_local_json_tree(
+                &writer,
+                json!({
+                    "guid": &BookmarkRootGuid::Menu.as_guid(),
+                    "children": [{
+                        "guid": "bookmarkAAAA",
+                        "title": "local",
+                        "url": "http://example.com/a",
+                    }],
+                }),
+            )
+            .expect("should insert tree");
+            writer.execute_named(
+                "UPDATE moz_bookmarks SET lastModified = :lastModified
+                 WHERE guid = 'bookmarkAAAA'",
+                &[(":lastModified", &(now + local_offset))],
+            )?;
+
+            let syncer = api.open_sync_connection()?;
+            let interrupt_scope = syncer.begin_interrupt_scope();
+            let store = BookmarksStore::new(&syncer, &interrupt_scope);
+            let server_now = now - hour;
+            let remote_modified = ServerTimestamp((server_now - remote_ago) as f64 / 1000.0);
+            let mut incoming =
+                IncomingChangeset::new(store.collection_name().to_string(), remote_modified);
+            incoming.server_time = Some(ServerTimestamp(server_now as f64 / 1000.0));
+            let records = vec![
+                json!({
+                    "id": "menu",
+                    "type": "folder",
+                    "parentid": "places",
+                    "parentName": "",
+                    "dateAdded": 0,
+                    "title": "menu",
+                    "children": ["bookmarkAAAA"],
+                }),
+                json!({
+                    "id": "bookmarkAAAA",
+                    "type": "bookmark",
+                    "parentid": "menu",
+                    "parentName": "menu",
+                    "dateAdded": 1_381_542_355_843u64,
+                    "title": "remote",
+                    "bmkUri": "http://example.com/a",
+                }),
+            ];
+            for record in records {
+                let payload = Payload::from_json(record).unwrap();
+                incoming.changes.push((payload, remote_modified));
+            }
+            store.apply_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+            let delta = store
+                .server_clock_delta
+                .get()
+                .expect("Should know the server's clock");
+            assert!(delta <= -hour && delta > -hour - minute);
+            assert_local_json_tree(
+                &writer,
+                &BookmarkRootGuid::Menu.as_guid(),
+                json!({
+                    "guid": &BookmarkRootGuid::Menu.as_guid(),
+                    "children": [{
+                        "guid": "bookmarkAAAA",
+                        "title": expected_title,
+                        "url": "http://example.com/a",
+                    }],
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_conflict_tiebreak() -> Result<()> {
+        // If both devices change the title at the same time, the greater
+        // title should win, whichever order we see the changes in. If one
+        // change is newer, even by less than a second, it should win.
+        for (local_title, local_ago, remote_title, expected_title) in
+            &[("A", 0, "B", "B"), ("B", 0, "A", "B"), ("B", 500, "A", "A")]
+        {
+            let api = new_mem_api();
+            let writer = api.open_connection(ConnectionType::ReadWrite)?;
+            insert_local_json_tree(
+                &writer,
+                json!({
+                    "guid": &BookmarkRootGuid::Menu.as_guid(),
+                    "children": [{
+                        "guid": "bookmarkAAAA",
+                        "title": local_title,
+                        "url": "http://example.com/a",
+                    }],
+                }),
+            )
+            .expect("should insert tree");
+            // Use a whole second, so that the server timestamp is exact.
+            let now = Timestamp::now().as_millis() as i64 / 1000 * 1000;
+            writer.execute_named(
+                "UPDATE moz_bookmarks SET lastModified = :lastModified
+                 WHERE guid = 'bookmarkAAAA'",
+                &[(":lastModified", &(now - local_ago))],
+            )?;
+
+            let syncer = api.open_sync_connection()?;
+            syncer.set_clock(FrozenClock::at(Timestamp(now as u64)))?;
+            let interrupt_scope = syncer.begin_interrupt_scope();
+            let store = BookmarksStore::new(&syncer, &interrupt_scope);
+            let mut incoming = IncomingChangeset::new(
+                store.collection_name().to_string(),
+                ServerTimestamp(now as f64 / 1000.0),
+            );
+            let records = vec![
+                json!({
+                    "id": "menu",
+                    "type": "folder",
+                    "parentid": "places",
+                    "parentName": "",
+                    "dateAdded": 0,
+                    "title": "menu",
+                    "children": ["bookmarkAAAA"],
+                }),
+                json!({
+                    "id": "bookmarkAAAA",
+                    "type": "bookmark",
+                    "parentid": "menu",
+                    "parentName": "menu",
+                    "dateAdded": 1_381_542_355_843u64,
+                    "title": remote_title,
+                    "bmkUri": "http://example.com/a",
+                }),
+            ];
+            for record in records {
+                let payload = Payload::from_json(record).unwrap();
+                incoming
+                    .changes
+                    .push((payload, ServerTimestamp(now as f64 / 1000.0)));
+            }
+            store.apply_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+            assert_local_json_tree(
+                &writer,
+                &BookmarkRootGuid::Menu.as_guid(),
+                json!({
+                    "guid": &BookmarkRootGuid::Menu.as_guid(),
+                    "children": [{
+                        "guid": "bookmarkAAAA",
+                        "title": expected_title,
+                        "url": "http://example.com/a",
+                    }],
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_oversized_urls() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend an older version stored an oversized URL.
+        writer.execute_named(
+            "UPDATE moz_places SET url = :url WHERE url = 'http://example.com/b'",
+            &[(
+                ":url",
+                &format!("javascript:{}", "x".repeat(crate::storage::URL_LENGTH_MAX)),
+            )],
+        )?;
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let outgoing = store.apply_incoming(
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+            &mut telemetry::EngineIncoming::new(),
+        )?;
+
+        assert!(
+            outgoing.changes.iter().all(|p| p.id != "bookmarkBBBB"),
+            "Shouldn't upload the quarantined bookmark"
+        );
+        let menu = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "menu")
+            .expect("Should upload the menu");
+        assert_eq!(
+            menu.data["children"],
+            json!(["bookmarkAAAA", "bookmarkBBBB"]),
+            "Should keep the quarantined bookmark in its parent"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_parent_title() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        apply_incoming(
+            &syncer,
+            json!([{
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "folderAAAAAA",
+                "parentName": "Recipes",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+            }]),
+        );
+
+        // We never saw the parent, so the bookmark should end up in unfiled.
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        );
+
+        let orphans = get_synced_orphans(&syncer)?;
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].guid, SyncGuid::from("bookmarkAAAA"));
+        assert_eq!(orphans[0].parent_guid, Some("folderAAAAAA".into()));
+        assert_eq!(orphans[0].parent_title, Some("Recipes".into()));
+        assert_eq!(orphans[0].kind, Some(SyncedBookmarkKind::Bookmark));
+        assert_eq!(
+            crate::bookmark_sync::get_synced_item(&syncer, &"bookmarkAAAA".into())?,
+            Some(orphans[0].clone())
+        );
+
+        // Once the parent arrives, the item isn't an orphan anymore.
+        apply_incoming(
+            &syncer,
+            json!([{
+                "id": "folderAAAAAA",
+                "type": "folder",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "Recipes",
+                "children": ["bookmarkAAAA"],
+            }, {
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["folderAAAAAA"],
+            }]),
+        );
+        assert!(get_synced_orphans(&syncer)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_date_added_round_trip() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        // Local items, synced items, and records all store creation dates in
+        // milliseconds, so a bookmark created now should have the same
+        // date everywhere.
+        let before = Timestamp::now();
+        let inserted = insert_bookmark(
+            &writer,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some("bookmarkAAAA".into()),
+                url: Url::parse("https://example.com/a")?,
+                title: Some("A".into()),
+                page_title: None,
+            }
+            .into(),
+        )?;
+        let after = Timestamp::now();
+        assert!(inserted.date_added >= before && inserted.date_added <= after);
+
+        let local = get_raw_bookmark(&writer, &"bookmarkAAAA".into())?
+            .expect("Should fetch local bookmark");
+        assert_eq!(local.date_added, inserted.date_added);
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should stage outgoing records");
+        let record = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkAAAA")
+            .expect("Should upload the bookmark");
+        assert_eq!(
+            record.data["dateAdded"],
+            json!(inserted.date_added.as_millis())
+        );
+
+        // Uploading the record writes it back to the mirror.
+        store
+            .sync_finished(
+                ServerTimestamp(after.as_millis() as f64 / 1000.0),
+                outgoing.changes.iter().map(|p| p.id.clone()).collect(),
+            )
+            .expect("Should push synced items");
+        let synced_date_added = syncer.query_row_named_with_context(
+            "SELECT dateAdded FROM moz_bookmarks_synced WHERE guid = :guid",
+            &[(":guid", &"bookmarkAAAA")],
+            |row| row.get::<_, Timestamp>(0),
+        )?;
+        assert_eq!(synced_date_added, inserted.date_added);
+
+        let local = get_raw_bookmark(&writer, &"bookmarkAAAA".into())?
+            .expect("Should fetch local bookmark after syncing");
+        assert_eq!(local.date_added, inserted.date_added);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_folder_change_token_after_sync() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let folder = &SyncGuid::from("folderAAAAAA");
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": folder,
+                    "title": "A",
+                    "children": [{
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    }],
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        let token = get_folder_change_token(&writer, folder)?;
+
+        let syncer = api.open_sync_connection()?;
+        apply_incoming(
+            &syncer,
+            json!([{
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "Unfiled",
+                "children": ["folderAAAAAA"],
+            }, {
+                "id": "folderAAAAAA",
+                "type": "folder",
+                "parentid": "unfiled",
+                "parentName": "Unfiled",
+                "dateAdded": 0,
+                "title": "A",
+                "children": ["bookmarkBBBB", "bookmarkCCCC"],
+            }, {
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "folderAAAAAA",
+                "parentName": "A",
+                "dateAdded": 0,
+                "title": "B",
+                "bmkUri": "http://example.com/b",
+            }, {
+                "id": "bookmarkCCCC",
+                "type": "bookmark",
+                "parentid": "folderAAAAAA",
+                "parentName": "A",
+                "dateAdded": 0,
+                "title": "C",
+                "bmkUri": "http://example.com/c",
+            }]),
+        );
+
+        assert!(folder_changed_since(&writer, folder, &token)?);
+        assert_local_json_tree(
+            &writer,
+            folder,
+            json!({
+                "guid": folder,
+                "children": [
+                    {"guid": "bookmarkBBBB", "url": "http://example.com/b"},
+                    {"guid": "bookmarkCCCC", "url": "http://example.com/c"},
+                ],
+            }),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_root_ignores_children_order() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        // Pretend we've synced the roots before.
+        writer.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET
+               syncChangeCounter = 0,
+               syncStatus = {}",
+            SyncStatus::Normal as u8
+        ))?;
+        let roots_before = USER_CONTENT_ROOTS
+            .iter()
+            .map(|root| get_raw_bookmark(&writer, &root.as_guid()))
+            .collect::<Result<Vec<_>>>()?;
+        let menu_date_added = roots_before[0]
+            .as_ref()
+            .expect("Should have menu")
+            .date_added;
+
+        // Desktop orders the roots differently.
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        let records = vec![
+            json!({
+                "id": "places",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "",
+                "children": ["toolbar", "menu", "mobile", "unfiled"],
+            }),
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": menu_date_added,
+                "title": "menu",
+                "children": [],
+            }),
+        ];
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+        let roots_after = USER_CONTENT_ROOTS
+            .iter()
+            .map(|root| get_raw_bookmark(&writer, &root.as_guid()))
+            .collect::<Result<Vec<_>>>()?;
+        for (before, after) in roots_before.iter().zip(roots_after.iter()) {
+            let before = before.as_ref().expect("Should have root before applying");
+            let after = after.as_ref().expect("Should have root after applying");
+            assert_eq!(before.position, after.position, "{}", before.guid);
+            assert_eq!(after.sync_change_counter, 0, "{}", before.guid);
+        }
+        let ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.as_str())
+            .collect::<Vec<_>>();
+        assert!(
+            ids.iter()
+                .all(|id| !["places", "menu", "toolbar", "unfiled", "mobile"].contains(id)),
+            "Shouldn't upload roots: {:?}",
+            ids
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_hidden_query() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "queryAAAAAAA",
+                    "title": "A",
+                    "url": "place:tag=foo",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        writer.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)?;
+        set_query_bookmark_hidden(&writer, &"queryAAAAAAA".into(), true)?;
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let record_ids = |store: &BookmarksStore<'_>| -> Result<Vec<String>> {
+            let incoming =
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+            let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+            Ok(outgoing.changes.into_iter().map(|p| p.id).collect())
+        };
+
+        // Hiding the query shouldn't upload it...
+        assert!(record_ids(&store)?.is_empty());
+
+        // ...But changing it should, even though it's hidden.
+        update_bookmark(
+            &writer,
+            &"queryAAAAAAA".into(),
+            &UpdatableBookmark {
+                title: Some("A2".into()),
+                ..UpdatableBookmark::default()
+            }
+            .into(),
+        )?;
+        assert!(record_ids(&store)?.contains(&"queryAAAAAAA".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_incoming_hidden_query() -> Result<()> {
+        // Uses only the schema that `PlacesApi` creates, so this also checks
+        // that the mirror and merge tables exist.
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "queryAAAAAAA",
+                    "title": "A",
+                    "url": "place:tag=foo",
+                }],
+            }),
+        );
+        writer.execute(
+            "UPDATE moz_bookmarks SET syncChangeCounter = 0, syncStatus = 2",
+            NO_PARAMS,
+        )?;
+        set_query_bookmark_hidden(&writer, &"queryAAAAAAA".into(), true)?;
+
+        let syncer = api.open_sync_connection()?;
+        apply_incoming(
+            &syncer,
+            json!([{
+                "id": "queryAAAAAAA",
+                "type": "query",
+                "parentid": "menu",
+                "parentName": "Bookmarks Menu",
+                "title": "B",
+                "bmkUri": "place:tag=foo",
+            },
+            {
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "title": "Bookmarks Menu",
+                "children": ["queryAAAAAAA"],
+            }]),
+        );
+
+        // Applying the remote change keeps the query hidden.
+        assert_eq!(
+            get_query_bookmarks(&writer)?,
+            vec![QueryBookmark {
+                guid: "queryAAAAAAA".into(),
+                title: Some("B".into()),
+                url: Url::parse("place:tag=foo")?,
+                kind: PlaceQueryKind::Tag,
+                hidden: true,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_upload_if_remote_wins() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        writer.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)?;
+
+        // Change the title locally an hour ago.
+        let now = Timestamp::now().as_millis() as i64;
+        let hour = 60 * 60 * 1000;
+        writer.execute_named(
+            "UPDATE moz_bookmarks SET
+               title = 'local',
+               dateAdded = 1381542355843,
+               lastModified = :lastModified,
+               syncChangeCounter = 1
+             WHERE guid = 'bookmarkAAAA'",
+            &[(":lastModified", &(now - hour))],
+        )?;
+
+        // ...And on another device, just now.
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let mut incoming = IncomingChangeset::new(
+            store.collection_name().to_string(),
+            ServerTimestamp(now as f64 / 1000.0),
+        );
+        let records = vec![
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkAAAA"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "remote",
+                "bmkUri": "http://example.com/a",
+            }),
+        ];
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming
+                .changes
+                .push((payload, ServerTimestamp(now as f64 / 1000.0)));
+        }
+        let outgoing = store.apply_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "remote",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        );
+        assert!(
+            outgoing.changes.iter().all(|p| p.id != "bookmarkAAAA"),
+            "Shouldn't reupload the remote bookmark"
+        );
+        let bm =
+            get_raw_bookmark(&writer, &"bookmarkAAAA".into())?.expect("should have the bookmark");
+        assert_eq!(bm.sync_change_counter, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        syncer
+            .execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)
+            .expect("should work");
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+        tags::tag_url(
+            &writer,
+            &Url::parse("http://example.com/a").expect("Should parse URL for A"),
+            "baz",
+        )
+        .expect("Should tag A");
+
+        let records = vec![
+            json!({
+                "id": "bookmarkCCCC",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_552_183_116_885u64,
+                "title": "C",
+                "bmkUri": "http://example.com/c",
+                "tags": ["foo", "bar"],
+            }),
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkCCCC"],
+            }),
+        ];
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+
+        let mut outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming and stage outgoing records");
+        outgoing.changes.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(
+            outgoing.changes.iter().map(|p| &p.id).collect::<Vec<_>>(),
+            vec!["bookmarkAAAA", "bookmarkBBBB", "unfiled",]
+        );
+        let record_for_a = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkAAAA")
+            .expect("Should upload A");
+        assert_eq!(
+            record_for_a.data["tags"]
+                .as_array()
+                .expect("Should upload tags for A"),
+            &["baz"]
+        );
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Root.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Root.as_guid(),
+                "children": [
+                    {
+                        "guid": &BookmarkRootGuid::Menu.as_guid(),
+                        "children": [
+                            {
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "http://example.com/c",
+                                "date_added": Timestamp(1_552_183_116_885),
+                            },
+                        ],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                        "children": [],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                        "children": [
+                            {
+                                "guid": "bookmarkAAAA",
+                                "title": "A",
+                                "url": "http://example.com/a",
+                            },
+                            {
+                                "guid": "bookmarkBBBB",
+                                "title": "B",
+                                "url": "http://example.com/b",
+                            },
+                        ],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                        "children": [],
+                    },
+                ],
+            }),
+        );
+
+        // We haven't finished the sync yet, so all local change counts for
+        // items to upload should still be > 0.
+        let guid_for_a: SyncGuid = "bookmarkAAAA".into();
+        let info_for_a = get_raw_bookmark(&writer, &guid_for_a)
+            .expect("Should fetch info for A")
+            .unwrap();
+        assert_eq!(info_for_a.sync_change_counter, 1);
+        let info_for_unfiled = get_raw_bookmark(&writer, &BookmarkRootGuid::Unfiled.as_guid())
+            .expect("Should fetch info for unfiled")
+            .unwrap();
+        assert_eq!(info_for_unfiled.sync_change_counter, 1);
+
+        store
+            .sync_finished(
+                ServerTimestamp(0.0),
+                vec![
+                    "bookmarkAAAA".into(),
+                    "bookmarkBBBB".into(),
+                    "unfiled".into(),
+                ],
+            )
+            .expect("Should push synced changes back to the store");
+
+        let info_for_a = get_raw_bookmark(&writer, &guid_for_a)
+            .expect("Should fetch info for A")
+            .unwrap();
+        assert_eq!(info_for_a.sync_change_counter, 0);
+        let info_for_unfiled = get_raw_bookmark(&writer, &BookmarkRootGuid::Unfiled.as_guid())
+            .expect("Should fetch info for unfiled")
+            .unwrap();
+        assert_eq!(info_for_unfiled.sync_change_counter, 0);
+
+        let mut tags_for_c = tags::get_tags_for_url(
+            &writer,
+            &Url::parse("http://example.com/c").expect("Should parse URL for C"),
+        )
+        .expect("Should return tags for C");
+        tags_for_c.sort();
+        assert_eq!(tags_for_c, &["bar", "foo"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keywords() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        let records = vec![
+            json!({
+                "id": "toolbar",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "toolbar",
+                "children": ["bookmarkAAAA"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "toolbar",
+                "dateAdded": 1_552_183_116_885u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "keyword": "a",
+            }),
+        ];
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming records");
+        let mut outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        outgoing_ids.sort();
+        assert_eq!(outgoing_ids, &["menu", "mobile", "toolbar", "unfiled"],);
+
+        store
+            .sync_finished(ServerTimestamp(0.0), outgoing_ids)
+            .expect("Should push synced changes back to the store");
+
+        update_bookmark(
+            &writer,
+            &"bookmarkAAAA".into(),
+            &UpdatableBookmark {
+                title: Some("A (local)".into()),
+                ..UpdatableBookmark::default()
+            }
+            .into(),
+        )?;
+
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should fetch outgoing records after making local changes");
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
+        assert_eq!(outgoing.changes[0].data["keyword"], "a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_fields_round_trip() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        let records = vec![
+            json!({
+                "id": "toolbar",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "toolbar",
+                "children": ["bookmarkAAAA"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "toolbar",
+                "dateAdded": 0,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+                "iconUri": "http://example.com/a.ico",
+                "sortindex": 100,
+            }),
+        ];
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming records");
+        let outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        store
+            .sync_finished(ServerTimestamp(0.0), outgoing_ids)
+            .expect("Should push synced changes back to the store");
+
+        // We only keep fields that are part of the record, not "auto" fields
+        // like `sortindex`.
+        let unknown_fields = syncer.query_one::<String>(
+            "SELECT unknownFields FROM moz_bookmarks_synced
+             WHERE guid = 'bookmarkAAAA'",
+        )?;
+        assert_eq!(
+            serde_json::from_str::<Value>(&unknown_fields)?,
+            json!({ "iconUri": "http://example.com/a.ico" })
+        );
+
+        update_bookmark(
+            &writer,
+            &"bookmarkAAAA".into(),
+            &UpdatableBookmark {
+                title: Some("A (local)".into()),
+                ..UpdatableBookmark::default()
+            }
+            .into(),
+        )?;
+
+        // The unknown fields should survive a round trip, even though we
+        // changed the bookmark locally.
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should fetch outgoing records after making local changes");
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
+        assert_eq!(outgoing.changes[0].data["title"], "A (local)");
+        assert_eq!(
+            outgoing.changes[0].data["iconUri"],
+            "http://example.com/a.ico"
+        );
+        assert!(outgoing.changes[0].data.get("sortindex").is_none());
+
+        // ...And we should keep them after uploading.
+        store
+            .sync_finished(ServerTimestamp(1.0), vec!["bookmarkAAAA".into()])
+            .expect("Should push synced changes back to the store");
+        let unknown_fields = syncer.query_one::<String>(
+            "SELECT unknownFields FROM moz_bookmarks_synced
+             WHERE guid = 'bookmarkAAAA'",
+        )?;
+        assert_eq!(
+            serde_json::from_str::<Value>(&unknown_fields)?,
+            json!({ "iconUri": "http://example.com/a.ico" })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keywords_round_trip() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        let url_a = Url::parse("http://example.com/a")?;
+        let url_b = Url::parse("http://example.com/b")?;
+        let url_c = Url::parse("http://example.com/c")?;
+
+        // B is a local bookmark that uses the same keyword as C on the
+        // server, but C's keyword is newer.
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "bookmarkBBBB",
+                    "title": "B",
+                    "url": url_b.as_str(),
+                }],
+            }),
+        )?;
+        keywords::set_keyword(&writer, "c", &url_b)?;
+        writer.execute("UPDATE moz_keywords SET lastModified = 0", NO_PARAMS)?;
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let now = ServerTimestamp(Timestamp::now().as_millis() as f64 / 1000.0);
+        let mut incoming = IncomingChangeset::new(store.collection_name().to_string(), now);
+        for record in vec![
+            json!({
+                "id": "toolbar",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "toolbar",
+                "children": ["bookmarkAAAA", "bookmarkCCCC"],
+            }),
+            json!({
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "toolbar",
+                "dateAdded": 0,
+                "title": "A",
+                "bmkUri": url_a.as_str(),
+                "keyword": "A",
+            }),
+            json!({
+                "id": "bookmarkCCCC",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "toolbar",
+                "dateAdded": 0,
+                "title": "C",
+                "bmkUri": url_c.as_str(),
+                "keyword": "c",
+            }),
+        ] {
+            incoming
+                .changes
+                .push((Payload::from_json(record).unwrap(), now));
+        }
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming records");
+
+        // Incoming keywords land locally, and the newer keyword for C wins,
+        // so we should reupload B without it.
+        assert_eq!(
+            keywords::get_url_for_keyword(&writer, "a")?,
+            Some(url_a.clone())
+        );
+        assert_eq!(
+            keywords::get_url_for_keyword(&writer, "c")?,
+            Some(url_c.clone())
+        );
+        assert_eq!(keywords::get_keyword_for_url(&writer, &url_b)?, None);
+        let record_for_b = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkBBBB")
+            .expect("Should upload B");
+        assert!(record_for_b.data.get("keyword").is_none());
+
+        let outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        store
+            .sync_finished(now, outgoing_ids)
+            .expect("Should push synced changes back to the store");
+
+        // Locally set keywords are uploaded.
+        keywords::set_keyword(&writer, "aa", &url_a)?;
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), now),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should fetch outgoing records after setting a keyword");
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
+        assert_eq!(outgoing.changes[0].data["keyword"], "aa");
+        store
+            .sync_finished(now, vec![outgoing.changes[0].id.clone()])
+            .expect("Should push synced changes back to the store");
+
+        // A new remote bookmark wants to use a keyword that we just set
+        // locally for C. Our keyword is newer, so we keep it, and reupload
+        // the remote bookmark without it.
+        keywords::set_keyword(&writer, "d", &url_c)?;
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0));
+        for record in vec![
+            json!({
+                "id": "toolbar",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "toolbar",
+                "children": ["bookmarkAAAA", "bookmarkCCCC", "bookmarkDDDD"],
+            }),
+            json!({
+                "id": "bookmarkDDDD",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "toolbar",
+                "dateAdded": 0,
+                "title": "D",
+                "bmkUri": "http://example.com/d",
+                "keyword": "d",
+            }),
+        ] {
+            incoming
+                .changes
+                .push((Payload::from_json(record).unwrap(), ServerTimestamp(1.0)));
+        }
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming records with conflicting keywords");
+
+        assert_eq!(
+            keywords::get_url_for_keyword(&writer, "d")?,
+            Some(url_c.clone())
+        );
+        let record_for_c = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkCCCC")
+            .expect("Should upload C");
+        assert_eq!(record_for_c.data["keyword"], "d");
+        let record_for_d = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkDDDD")
+            .expect("Should reupload D");
+        assert!(record_for_d.data.get("keyword").is_none());
+
+        Ok(())
+    }
+
+    /// Builds a tree with `folders` folders in the menu, each containing a
+    /// subfolder, a separator, and `bookmarks_per_folder` bookmarks.
+    fn synthetic_local_tree(folders: usize, bookmarks_per_folder: usize) -> Value {
+        let children = (0..folders)
+            .map(|i| {
+                let mut children = vec![
+                    json!({
+                        "guid": format!("folder{:06}", i * 10 + 1),
+                        "title": format!("Subfolder {}", i),
+                        "children": [],
+                    }),
+                    json!({
+                        "guid": format!("separ{:07}", i),
+                        "type": BookmarkType::Separator as u8,
+                    }),
+                ];
+                children.extend((0..bookmarks_per_folder).map(|j| {
+                    json!({
+                        "guid": format!("bk{:05}_{:04}", i, j),
+                        "title": format!("Bookmark {}", j),
+                        "url": format!("http://example.com/{}/{}", i, j),
+                    })
+                }));
+                json!({
+                    "guid": format!("folder{:06}", i * 10),
+                    "title": format!("Folder {}", i),
+                    "children": children,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "guid": &BookmarkRootGuid::Menu.as_guid(),
+            "children": children,
+        })
+    }
+
+    /// Checks that every outgoing folder record lists the same children, in
+    /// the same order, as the local folder.
+    fn assert_outgoing_children_match_local(
+        conn: &PlacesDb,
+        outgoing: &OutgoingChangeset,
+    ) -> Result<usize> {
+        let mut folders = 0;
+        for payload in &outgoing.changes {
+            if payload.data["type"] != "folder" {
+                continue;
+            }
+            let guid = BookmarkRecordId::from_payload_id(payload.id.clone())
+                .as_guid()
+                .clone();
+            let expected = conn
+                .query_rows_and_then_named(
+                    "SELECT c.guid FROM moz_bookmarks c
+                     JOIN moz_bookmarks p ON p.id = c.parent
+                     WHERE p.guid = :guid
+                     ORDER BY c.position",
+                    &[(":guid", &guid)],
+                    |row| -> Result<_> {
+                        Ok(BookmarkRecordId::from(row.get::<_, SyncGuid>("guid")?)
+                            .into_payload_id())
+                    },
+                )?
+                .into_iter()
+                .map(Value::String)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                payload.data["children"],
+                Value::Array(expected),
+                "Children for {} should match",
+                guid
+            );
+            folders += 1;
+        }
+        Ok(folders)
+    }
+
+    #[test]
+    fn test_outgoing_folder_children() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        insert_local_json_tree(&writer, synthetic_local_tree(20, 10)).expect("should insert tree");
+        // Move some items around, so that positions and parents don't match
+        // insertion order.
+        update_bookmark(
+            &writer,
+            &"bk00003_0005".into(),
+            &UpdatableBookmark {
+                location: UpdateTreeLocation::Parent(
+                    "folder000011".into(),
+                    BookmarkPosition::Append,
+                ),
+                ..UpdatableBookmark::default()
+            }
+            .into(),
+        )?;
+        update_bookmark(
+            &writer,
+            &"bk00007_0009".into(),
+            &UpdatableBookmark {
+                location: UpdateTreeLocation::Position(BookmarkPosition::Specific(0)),
+                ..UpdatableBookmark::default()
+            }
+            .into(),
+        )?;
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should stage outgoing records");
+
+        // 4 roots, and 2 folders in each of the 20 top-level folders.
+        assert_eq!(outgoing.changes.len(), 4 + 20 * (2 + 1 + 10));
+        assert_eq!(
+            assert_outgoing_children_match_local(&syncer, &outgoing)?,
+            4 + 20 * 2
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_outgoing_large_tree() -> Result<()> {
+        // We trace the SQL that the sync connection runs, which needs a
+        // mutable connection, so we use a standalone one instead of going
+        // through the API.
+        let mut syncer = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+
+        // We only load the children for one folder at a time, so building
+        // records for a big tree shouldn't need a second copy of the whole
+        // structure.
+        insert_local_json_tree(&syncer, synthetic_local_tree(10, 1000))
+            .expect("should insert tree");
+        syncer.set_sql_tracing(true);
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should stage outgoing records");
+
+        assert_eq!(outgoing.changes.len(), 4 + 10 * (2 + 1 + 1000));
+        let folders = assert_outgoing_children_match_local(&syncer, &outgoing)?;
+        assert_eq!(folders, 4 + 10 * 2);
+
+        // Every read from `structureToUpload` should be for a single folder,
+        // so the most children we hold at once is bounded by the largest
+        // folder, not the size of the tree. We read each folder once.
+        let structure_reads = syncer
+            .sql_stats()
+            .into_iter()
+            .filter(|stats| stats.fingerprint.contains("FROM structureToUpload"))
+            .collect::<Vec<_>>();
+        assert!(
+            structure_reads
+                .iter()
+                .all(|stats| stats.fingerprint.contains("WHERE parentId = :parentId")),
+            "Should only read structure one folder at a time: {:?}",
+            structure_reads
+        );
+        assert_eq!(
+            structure_reads.iter().map(|stats| stats.count).sum::<u64>(),
+            folders as u64
+        );
+        let menu = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "menu")
+            .expect("Should upload the menu");
+        assert_eq!(menu.data["children"].as_array().map(Vec::len), Some(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_merge_retries_from_mirror() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+
+        let records = json!([{
+            "id": "menu",
+            "type": "folder",
+            "parentid": "places",
+            "parentName": "",
+            "title": "menu",
+            "children": ["folderAAAAAA"],
+        }, {
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "menu",
+            "parentName": "menu",
+            "title": "A",
+            "children": ["bookmarkBBBB"],
+        }, {
+            "id": "bookmarkBBBB",
+            "type": "bookmark",
+            "parentid": "folderAAAAAA",
+            "parentName": "A",
+            "title": "B",
+            "bmkUri": "http://example.com/b",
+        }]);
+
+        {
+            let syncer = api.open_sync_connection()?;
+            // Dropping `itemsToRemove` makes the last step of the merge
+            // fail, after the triggers for the first two steps have already
+            // changed the local tree.
+            syncer.execute_batch("DROP TABLE itemsToRemove")?;
+
+            let interrupt_scope = syncer.begin_interrupt_scope();
+            let store = BookmarksStore::new(&syncer, &interrupt_scope);
+            let mut incoming =
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(5.0));
+            if let Value::Array(records) = records {
+                for record in records {
+                    let payload = Payload::from_json(record).unwrap();
+                    incoming.changes.push((payload, ServerTimestamp(5.0)));
+                }
+            }
+            store
+                .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+                .expect_err("Merge should fail without `itemsToRemove`");
+
+            // We advanced the download watermark, so we won't download the
+            // same records again...
+            let request = store.get_collection_request()?;
+            assert_eq!(request.newer, Some(ServerTimestamp(5.0)));
+        }
+
+        // ...But the merge was rolled back, and the records are still
+        // waiting to be merged.
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [],
+            }),
+        );
+        let needs_merge = writer
+            .query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks_synced WHERE needsMerge")?;
+        assert_eq!(needs_merge, 3);
+
+        // A new sync connection has all its temp tables, so the next sync
+        // should merge the staged records, even though it doesn't download
+        // anything new.
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(5.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should merge staged records");
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "folderAAAAAA",
+                    "title": "A",
+                    "children": [{
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    }],
+                }],
+            }),
+        );
+        let needs_merge = writer
+            .query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks_synced WHERE needsMerge")?;
+        assert_eq!(needs_merge, 0);
+
+        Ok(())
+    }
+
+    fn incoming_changeset(
+        store: &BookmarksStore<'_>,
+        timestamp: ServerTimestamp,
+        records: Value,
+    ) -> IncomingChangeset {
+        let mut incoming = IncomingChangeset::new(store.collection_name().to_string(), timestamp);
+        for record in records.as_array().expect("should be an array") {
+            let payload = Payload::from_json(record.clone()).unwrap();
+            incoming.changes.push((payload, timestamp));
+        }
+        incoming
+    }
+
+    #[test]
+    fn test_mobile_first_first_sync() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store =
+            BookmarksStore::new(&syncer, &interrupt_scope).with_priority(SyncPriority::MobileFirst);
+
+        // We don't have the server's tree yet, so the first sync downloads
+        // everything, even if we only have local mobile items.
+        let request = store.get_collection_request()?;
+        assert!(request.full);
+        assert_eq!(request.ids, None);
+        assert_eq!(request.order, None);
+
+        let incoming = incoming_changeset(
+            &store,
+            ServerTimestamp(5.0),
+            json!([{
+                "id": "mobile",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "mobile",
+                "children": ["bookmarkBBBB"],
+            }, {
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "mobile",
+                "parentName": "mobile",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "B",
+                "bmkUri": "http://example.com/b",
+            }, {
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkCCCC"],
+            }, {
+                "id": "bookmarkCCCC",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "C",
+                "bmkUri": "http://example.com/c",
+            }]),
+        );
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming and stage outgoing records");
+
+        // Both the local and remote mobile children are merged, and the
+        // rest of the remote tree is applied, too.
+        for guid in &["bookmarkAAAA", "bookmarkBBBB"] {
+            let b = get_raw_bookmark(&writer, &(*guid).into())?.expect("Should merge mobile child");
+            assert_eq!(b.parent_guid, Some(BookmarkRootGuid::Mobile.as_guid()));
+        }
+        assert!(get_raw_bookmark(&writer, &"bookmarkCCCC".into())?.is_some());
+
+        // ...So the mobile root that we upload keeps the remote child, and
+        // doesn't duplicate either child.
+        let mobile = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "mobile")
+            .expect("Should upload the mobile root");
+        let mut children = mobile.data["children"]
+            .as_array()
+            .expect("Should upload mobile children")
+            .iter()
+            .map(|id| id.as_str().expect("Child IDs should be strings"))
+            .collect::<Vec<_>>();
+        children.sort();
+        assert_eq!(children, vec!["bookmarkAAAA", "bookmarkBBBB"]);
+        assert!(outgoing.changes.iter().any(|p| p.id == "bookmarkAAAA"));
+        assert!(!outgoing.changes.iter().any(|p| p.id == "bookmarkCCCC"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mobile_first_collection_request() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkDDDD",
+                    "url": "http://example.com/d",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        let records = json!([{
+            "id": "mobile",
+            "type": "folder",
+            "parentid": "places",
+            "parentName": "",
+            "dateAdded": 0,
+            "title": "mobile",
+            "children": ["folderAAAAAA", "bookmarkCCCC"],
+        }, {
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "mobile",
+            "parentName": "mobile",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "A",
+            "children": ["bookmarkBBBB"],
+        }, {
+            "id": "bookmarkBBBB",
+            "type": "bookmark",
+            "parentid": "folderAAAAAA",
+            "parentName": "A",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "B",
+            "bmkUri": "http://example.com/b",
+        }, {
+            "id": "bookmarkCCCC",
+            "type": "bookmark",
+            "parentid": "mobile",
+            "parentName": "mobile",
+            "dateAdded": 1_381_542_355_843u64,
+            "title": "C",
+            "bmkUri": "http://example.com/c",
+        }]);
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+
+        // The first sync downloads everything.
+        {
+            let store = BookmarksStore::new(&syncer, &interrupt_scope)
+                .with_priority(SyncPriority::MobileFirst);
+            let request = store.get_collection_request()?;
+            assert_eq!(request.ids, None);
+            let outgoing = store
+                .apply_incoming(
+                    incoming_changeset(&store, ServerTimestamp(5.0), records),
+                    &mut telemetry::EngineIncoming::new(),
+                )
+                .expect("Should apply incoming and stage outgoing records");
+            store.sync_finished(
+                ServerTimestamp(6.0),
+                outgoing.changes.iter().map(|p| p.id.clone()).collect(),
+            )?;
+        }
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkEEEE",
+                    "url": "http://example.com/e",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        // The default priority always requests everything.
+        {
+            let store = BookmarksStore::new(&syncer, &interrupt_scope);
+            let request = store.get_collection_request()?;
+            assert!(request.full);
+            assert_eq!(request.ids, None);
+            assert_eq!(request.order, None);
+        }
+
+        // Now that we have the server's tree, a mobile-first sync only
+        // requests the mobile root and its descendants on the server, in
+        // level order.
+        {
+            let store = BookmarksStore::new(&syncer, &interrupt_scope)
+                .with_priority(SyncPriority::MobileFirst);
+            let request = store.get_collection_request()?;
+            assert!(request.full);
+            assert_eq!(
+                request.ids,
+                Some(vec![
+                    "mobile".to_string(),
+                    "folderAAAAAA".to_string(),
+                    "bookmarkCCCC".to_string(),
+                    "bookmarkBBBB".to_string(),
+                ])
+            );
+            assert_eq!(request.order, Some(RequestOrder::Index));
+
+            // We only stage what we download, and don't upload anything,
+            // even though the menu has a new local item.
+            let outgoing = store
+                .apply_incoming(
+                    incoming_changeset(
+                        &store,
+                        ServerTimestamp(10.0),
+                        json!([{
+                            "id": "bookmarkCCCC",
+                            "type": "bookmark",
+                            "parentid": "mobile",
+                            "parentName": "mobile",
+                            "dateAdded": 1_381_542_355_843u64,
+                            "title": "C (renamed)",
+                            "bmkUri": "http://example.com/c",
+                        }]),
+                    ),
+                    &mut telemetry::EngineIncoming::new(),
+                )
+                .expect("Should stage incoming records");
+            assert!(outgoing.changes.is_empty());
+            store.sync_finished(ServerTimestamp(10.0), vec![])?;
+            let c =
+                get_raw_bookmark(&writer, &"bookmarkCCCC".into())?.expect("Should keep local C");
+            assert_eq!(c.title.as_ref().map(String::as_str), Some("C"));
+        }
+
+        // Since we only downloaded part of the tree, the next sync should
+        // download everything since the last full sync, and merge the
+        // staged items.
+        {
+            let store = BookmarksStore::new(&syncer, &interrupt_scope)
+                .with_priority(SyncPriority::MobileFirst);
+            let request = store.get_collection_request()?;
+            assert_eq!(request.ids, None);
+            assert_eq!(request.order, None);
+            assert_eq!(request.newer, Some(ServerTimestamp(6.0)));
+            let outgoing = store
+                .apply_incoming(
+                    incoming_changeset(&store, ServerTimestamp(10.0), json!([])),
+                    &mut telemetry::EngineIncoming::new(),
+                )
+                .expect("Should merge staged records");
+            assert!(outgoing.changes.iter().any(|p| p.id == "bookmarkEEEE"));
+            store.sync_finished(
+                ServerTimestamp(11.0),
+                outgoing.changes.iter().map(|p| p.id.clone()).collect(),
+            )?;
+            let c =
+                get_raw_bookmark(&writer, &"bookmarkCCCC".into())?.expect("Should keep local C");
+            assert_eq!(c.title.as_ref().map(String::as_str), Some("C (renamed)"));
+        }
+
+        // ...And the sync after that can be mobile-only again.
+        let store =
+            BookmarksStore::new(&syncer, &interrupt_scope).with_priority(SyncPriority::MobileFirst);
+        let request = store.get_collection_request()?;
+        assert_eq!(request.order, Some(RequestOrder::Index));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mobile_first_outgoing_order() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        // Insert the menu items first, so that they have lower IDs than the
+        // mobile items.
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                "children": [{
+                    "guid": "folderBBBBBB",
+                    "title": "B",
+                    "children": [{
+                        "guid": "bookmarkCCCC",
+                        "url": "http://example.com/c",
+                    }],
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store =
+            BookmarksStore::new(&syncer, &interrupt_scope).with_priority(SyncPriority::MobileFirst);
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should stage outgoing records");
+
+        let ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids[..3], ["mobile", "folderBBBBBB", "bookmarkCCCC"]);
+        assert!(ids[3..].contains(&"bookmarkAAAA"));
+        assert!(ids[3..].contains(&"menu"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_outgoing() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [{
+                            "guid": "bookmarkCCCC",
+                            "url": "http://example.com/c",
+                        }],
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let change_counters = || -> Result<Vec<(SyncGuid, u32)>> {
+            Ok(syncer.query_rows_and_then_named(
+                "SELECT guid, syncChangeCounter FROM moz_bookmarks ORDER BY guid",
+                &[],
+                |row| -> Result<_> { Ok((row.get("guid")?, row.get("syncChangeCounter")?)) },
+            )?)
+        };
+        // Previews and uploads the same records as a real sync, and returns
+        // the previews sorted by GUID.
+        let preview_and_sync = |timestamp: ServerTimestamp| -> Result<Vec<OutgoingPreview>> {
+            let counters_before_preview = change_counters()?;
+            let mut preview = store.preview_outgoing()?;
+            assert_eq!(
+                change_counters()?,
+                counters_before_preview,
+                "Preview shouldn't change any change counters"
+            );
+            assert_eq!(
+                syncer.query_one::<i64>("SELECT COUNT(*) FROM itemsToUpload")?,
+                0,
+                "Preview shouldn't leave anything staged"
+            );
+            preview.sort_by(|a, b| a.guid.as_ref().cmp(b.guid.as_ref()));
+
+            let outgoing = store
+                .apply_incoming(
+                    IncomingChangeset::new(store.collection_name().to_string(), timestamp),
+                    &mut telemetry::EngineIncoming::new(),
+                )
+                .expect("Should fetch outgoing records");
+            let mut outgoing_guids = outgoing
+                .changes
+                .iter()
+                .map(|p| {
+                    BookmarkRecordId::from_payload_id(p.id.clone())
+                        .as_guid()
+                        .clone()
+                })
+                .collect::<Vec<_>>();
+            outgoing_guids.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+            assert_eq!(
+                preview.iter().map(|p| p.guid.clone()).collect::<Vec<_>>(),
+                outgoing_guids,
+                "Preview should match the outgoing records"
+            );
+            store
+                .sync_finished(
+                    timestamp,
+                    outgoing.changes.into_iter().map(|p| p.id).collect(),
+                )
+                .expect("Should push synced changes back to the store");
+            Ok(preview)
+        };
+
+        let preview = preview_and_sync(ServerTimestamp(1.0))?;
+        assert!(
+            preview
+                .iter()
+                .all(|p| p.reason == OutgoingReason::New && !p.is_tombstone),
+            "Everything should be new on the first sync"
+        );
+        let a = preview
+            .iter()
+            .find(|p| p.guid == "bookmarkAAAA".into())
+            .expect("Should preview A");
+        assert_eq!(a.kind, Some(SyncedBookmarkKind::Bookmark));
+        assert_eq!(a.snippet.as_ref().map(String::as_str), Some("A"));
+        let c = preview
+            .iter()
+            .find(|p| p.guid == "bookmarkCCCC".into())
+            .expect("Should preview C");
+        assert_eq!(
+            c.snippet.as_ref().map(String::as_str),
+            Some("http://example.com/c"),
+            "Should fall back to the URL for untitled bookmarks"
+        );
+
+        update_bookmark(
+            &writer,
+            &"bookmarkAAAA".into(),
+            &UpdatableBookmark {
+                title: Some("A (local)".into()),
+                ..UpdatableBookmark::default()
             }
             .into(),
         )?;
+        delete_bookmark(&writer, &"bookmarkCCCC".into())?;
 
-        let outgoing = store
-            .apply_incoming(
-                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0)),
-                &mut telemetry::EngineIncoming::new(),
-            )
-            .expect("Should fetch outgoing records after making local changes");
-        assert_eq!(outgoing.changes.len(), 1);
-        assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
-        assert_eq!(outgoing.changes[0].data["keyword"], "a");
+        let preview = preview_and_sync(ServerTimestamp(2.0))?;
+        assert_eq!(
+            preview,
+            vec![
+                OutgoingPreview {
+                    guid: "bookmarkAAAA".into(),
+                    kind: Some(SyncedBookmarkKind::Bookmark),
+                    snippet: Some("A (local)".into()),
+                    is_tombstone: false,
+                    reason: OutgoingReason::Changed,
+                },
+                OutgoingPreview {
+                    guid: "bookmarkCCCC".into(),
+                    kind: None,
+                    snippet: None,
+                    is_tombstone: true,
+                    reason: OutgoingReason::Changed,
+                },
+                OutgoingPreview {
+                    guid: "folderBBBBBB".into(),
+                    kind: Some(SyncedBookmarkKind::Folder),
+                    snippet: Some("B".into()),
+                    is_tombstone: false,
+                    reason: OutgoingReason::Changed,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    // Checks the invariants that readers rely on: every item except the root
+    // has a parent, and children have contiguous positions.
+    fn assert_tree_consistent(reader: &PlacesDb) -> Result<()> {
+        // Run both checks in the same read transaction, so that they see
+        // the same snapshot.
+        let tx = reader.begin_transaction()?;
+        let orphans = reader.query_one::<i64>(&format!(
+            "SELECT COUNT(*) FROM moz_bookmarks b
+             LEFT JOIN moz_bookmarks p ON p.id = b.parent
+             WHERE b.guid <> '{root_guid}' AND
+                   p.id IS NULL",
+            root_guid = BookmarkRootGuid::Root.as_guid().as_ref(),
+        ))?;
+        assert_eq!(orphans, 0, "Every item should have a parent");
+        let bad_parents = reader.query_one::<i64>(
+            "SELECT COUNT(*) FROM (
+               SELECT parent FROM moz_bookmarks
+               WHERE parent NOT NULL
+               GROUP BY parent
+               HAVING COUNT(DISTINCT position) <> COUNT(*) OR
+                      MIN(position) <> 0 OR
+                      MAX(position) <> COUNT(*) - 1
+             )",
+        )?;
+        assert_eq!(bad_parents, 0, "Positions should be contiguous");
+        tx.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_reads_see_consistent_tree() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        const FOLDERS: usize = 20;
+        const BOOKMARKS_PER_FOLDER: usize = 50;
+
+        let _ = env_logger::try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let api = PlacesApi::new(dir.path().join("places.sqlite"))?;
+
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": (0..BOOKMARKS_PER_FOLDER).map(|i| json!({
+                    "guid": format!("localbmk{:04}", i),
+                    "title": format!("Local {}", i),
+                    "url": format!("http://example.com/local/{}", i),
+                })).collect::<Vec<_>>(),
+            }),
+        )
+        .expect("should insert tree");
+
+        let folder_guid = |i: usize| format!("folder{:06}", i);
+        let bookmark_guid = |i: usize, j: usize| format!("bmk{:03}{:06}", i, j);
+        let folder_record = |i: usize, parent: &str, skip: usize| {
+            json!({
+                "id": folder_guid(i),
+                "type": "folder",
+                "parentid": parent,
+                "parentName": parent,
+                "dateAdded": 1_381_542_355_843u64,
+                "title": format!("Folder {}", i),
+                "children": (skip..BOOKMARKS_PER_FOLDER)
+                    .map(|j| bookmark_guid(i, j))
+                    .collect::<Vec<_>>(),
+            })
+        };
+        let root_record = |id: &str, folders: &[usize]| {
+            json!({
+                "id": id,
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": id,
+                "children": folders.iter().map(|&i| folder_guid(i)).collect::<Vec<_>>(),
+            })
+        };
+
+        // The first changeset adds lots of folders and bookmarks to the menu,
+        // moving the existing local bookmarks after them.
+        let mut first = vec![root_record("menu", &(0..FOLDERS).collect::<Vec<_>>())];
+        for i in 0..FOLDERS {
+            first.push(folder_record(i, "menu", 0));
+            for j in 0..BOOKMARKS_PER_FOLDER {
+                first.push(json!({
+                    "id": bookmark_guid(i, j),
+                    "type": "bookmark",
+                    "parentid": folder_guid(i),
+                    "parentName": format!("Folder {}", i),
+                    "dateAdded": 1_381_542_355_843u64,
+                    "title": format!("Bookmark {} {}", i, j),
+                    "bmkUri": format!("http://example.com/{}/{}", i, j),
+                }));
+            }
+        }
+
+        // The second moves half the folders to the toolbar, and deletes the
+        // first bookmark in each of those.
+        let (moved, kept): (Vec<usize>, Vec<usize>) = (0..FOLDERS).partition(|i| i % 2 == 0);
+        let mut second = vec![root_record("menu", &kept), root_record("toolbar", &moved)];
+        for &i in &moved {
+            second.push(folder_record(i, "toolbar", 1));
+            second.push(json!({
+                "id": bookmark_guid(i, 0),
+                "deleted": true,
+            }));
+        }
+
+        let reader = api.open_connection(ConnectionType::ReadOnly)?;
+        let done = Arc::new(AtomicBool::new(false));
+        let checker = {
+            let done = done.clone();
+            thread::spawn(move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    assert_tree_consistent(&reader)?;
+                }
+                assert_tree_consistent(&reader)
+            })
+        };
+
+        {
+            let syncer = api.open_sync_connection()?;
+            apply_incoming(&syncer, Value::Array(first));
+            apply_incoming(&syncer, Value::Array(second));
+        }
+        done.store(true, Ordering::SeqCst);
+        checker.join().expect("Reader thread shouldn't panic")?;
+
+        let local_count = writer.query_one::<i64>(&format!(
+            "SELECT COUNT(*) FROM moz_bookmarks
+             WHERE type = {}",
+            BookmarkType::Bookmark as u8
+        ))?;
+        assert_eq!(
+            local_count as usize,
+            BOOKMARKS_PER_FOLDER + FOLDERS * BOOKMARKS_PER_FOLDER - moved.len()
+        );
+        assert_tree_consistent(&writer)?;
+
+        let log = get_deletion_log(&writer, Timestamp(0))?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].op, DeletionOp::SyncDeleteBookmarks);
+        assert_eq!(log[0].initiator, DeletionInitiator::Sync);
+        assert_eq!(log[0].count as usize, moved.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_incoming_repairs_local_tree() -> Result<()> {
+        let _ = env_logger::try_init();
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+
+        // Make B a child of A, and delete the Places root without deleting
+        // its children. The merger can't build a local tree without a root.
+        writer.execute_batch(
+            "UPDATE moz_bookmarks SET
+               parent = (SELECT id FROM moz_bookmarks WHERE guid = 'bookmarkAAAA'),
+               position = 0
+             WHERE guid = 'bookmarkBBBB';
+
+             PRAGMA foreign_keys = OFF;
+             DELETE FROM moz_bookmarks WHERE guid = 'root________';
+             PRAGMA foreign_keys = ON;",
+        )?;
+
+        let syncer = api.open_sync_connection()?;
+        apply_incoming(
+            &syncer,
+            json!([{
+                "id": "bookmarkCCCC",
+                "type": "bookmark",
+                "parentid": "toolbar",
+                "parentName": "Bookmarks Toolbar",
+                "dateAdded": 1_381_542_355_843u64,
+                "title": "C",
+                "bmkUri": "http://example.com/c",
+            },
+            {
+                "id": "toolbar",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "Bookmarks Toolbar",
+                "children": ["bookmarkCCCC"],
+            }]),
+        );
+
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Root.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Root.as_guid(),
+                "children": [
+                    {
+                        "guid": &BookmarkRootGuid::Menu.as_guid(),
+                        "children": [{
+                            "guid": "bookmarkAAAA",
+                            "title": "A",
+                            "url": "http://example.com/a",
+                        }],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                        "children": [{
+                            "guid": "bookmarkCCCC",
+                            "title": "C",
+                            "url": "http://example.com/c",
+                        }],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                        "children": [{
+                            "guid": "bookmarkBBBB",
+                            "title": "B",
+                            "url": "http://example.com/b",
+                        }],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                        "children": [],
+                    },
+                ],
+            }),
+        );
 
+        // Everything is consistent now, so the next merge shouldn't need to
+        // repair anything.
+        assert_eq!(
+            bookmarks::repair(&syncer)?,
+            bookmarks::RepairReport::default()
+        );
+        apply_incoming(&syncer, json!([]));
         Ok(())
     }
 
@@ -1799,6 +6251,11 @@ mod tests {
 
         store.wipe().expect("Should wipe the store");
 
+        let log = get_deletion_log(&writer, Timestamp(0))?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].op, DeletionOp::WipeBookmarks);
+        assert_eq!(log[0].count, 8);
+
         // Wiping the store should delete all items except for the roots.
         assert_local_json_tree(
             &writer,
@@ -1911,4 +6368,165 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_reset() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        )?;
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1.0));
+        for record in vec![
+            json!({
+                "id": "menu",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "menu",
+                "children": ["bookmarkBBBB"],
+            }),
+            json!({
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "menu",
+                "parentName": "menu",
+                "dateAdded": 0,
+                "title": "B",
+                "bmkUri": "http://example.com/b",
+            }),
+        ] {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(1.0)));
+        }
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming records");
+        let outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        store
+            .sync_finished(ServerTimestamp(2.0), outgoing_ids)
+            .expect("Should push synced changes back to the store");
+        let request = store
+            .get_collection_request()
+            .expect("Should request records since the last sync");
+        assert_eq!(request.newer, Some(ServerTimestamp(2.0)));
+
+        store
+            .reset(&StoreSyncAssociation::Disconnected)
+            .expect("Should reset the store");
+
+        // Resetting should forget the last sync time, so that we download
+        // all records on the next sync...
+        let request = store
+            .get_collection_request()
+            .expect("Should request all records after resetting");
+        assert_eq!(request.newer, Some(ServerTimestamp(0.0)));
+        let synced_items = syncer.query_one::<i64>(
+            "SELECT COUNT(*) FROM moz_bookmarks_synced
+             WHERE guid = 'bookmarkBBBB'",
+        )?;
+        assert_eq!(synced_items, 0);
+
+        // ...and reupload everything.
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0)),
+                &mut telemetry::EngineIncoming::new(),
+            )
+            .expect("Should stage all local items for upload after resetting");
+        let mut outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        outgoing_ids.sort();
+        assert_eq!(
+            outgoing_ids,
+            &[
+                "bookmarkAAAA",
+                "bookmarkBBBB",
+                "menu",
+                "mobile",
+                "toolbar",
+                "unfiled"
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_kind_conversions() {
+        for kind in SyncedBookmarkKind::ALL.iter() {
+            assert_eq!(SyncedBookmarkKind::try_from(*kind as u8).unwrap(), *kind);
+            assert_eq!(
+                SyncedBookmarkKind::from(dogear::Kind::from(*kind)),
+                *kind,
+                "Should round-trip {:?} through dogear",
+                kind
+            );
+        }
+        assert!(SyncedBookmarkKind::try_from(0).is_err());
+        assert!(SyncedBookmarkKind::try_from(6).is_err());
+    }
+
+    #[test]
+    fn test_item_kind_fragment_matches_rust() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+
+        let sql = format!(
+            "WITH items(type, url) AS (VALUES(:type, :url))
+             SELECT {kind} FROM items",
+            kind = item_kind_fragment("type", UrlOrPlaceIdFragment::Url("url")),
+        );
+        let urls = [
+            None,
+            Some("http://example.com/"),
+            Some("place:tag=foo"),
+            Some("place:"),
+            Some("placebo:thing"),
+        ];
+        for typ in &[
+            BookmarkType::Bookmark,
+            BookmarkType::Folder,
+            BookmarkType::Separator,
+        ] {
+            for url in urls.iter() {
+                let sql_kind = conn.query_row_named_with_context(
+                    &sql,
+                    &[(":type", typ), (":url", url)],
+                    |row| row.get::<_, u8>(0),
+                )?;
+                let rust_kind = SyncedBookmarkKind::from_local_type(*typ, *url);
+                assert_eq!(
+                    sql_kind, rust_kind as u8,
+                    "SQL and Rust kinds should match for {:?} with URL {:?}",
+                    typ, url
+                );
+                assert_eq!(BookmarkType::from(rust_kind), *typ);
+            }
+        }
+        Ok(())
+    }
 }
@@ -3,17 +3,127 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use super::schema;
+use super::sql_stats::{SqlStats, SqlStatsRecorder};
 use crate::api::places_api::ConnectionType;
 use crate::error::*;
+use crate::frecency::FrecencySettings;
+use crate::types::{Clock, SystemClock, Timestamp};
 use rusqlite::Connection;
 use sql_support::{ConnExt, SqlInterruptHandle, SqlInterruptScope};
+use std::cell::RefCell;
 use std::ops::Deref;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use std::sync::{atomic::AtomicUsize, Arc, Mutex};
 
 pub const MAX_VARIABLE_NUMBER: usize = 999;
 
+/// How long SQLite waits for another connection to release its lock before
+/// failing with `SQLITE_BUSY`.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Controls how write APIs retry when the database is busy or locked. Even
+/// with WAL and a busy timeout, short writes can briefly fail while another
+/// connection checkpoints or holds a write lock, so we retry those a few
+/// times before giving up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusyRetryPolicy {
+    /// The total number of times to try a write, including the first. 1
+    /// disables retrying.
+    pub max_attempts: u32,
+    /// How long to sleep before the first retry. The delay doubles for each
+    /// retry after that, plus some jitter so that two connections don't
+    /// keep retrying in lockstep.
+    pub base_delay: Duration,
+}
+
+impl BusyRetryPolicy {
+    /// A policy that never retries.
+    pub fn disabled() -> Self {
+        BusyRetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(0),
+        }
+    }
+
+    fn delay_for_retry(&self, retry: u32) -> Duration {
+        let delay = self.base_delay * 2u32.pow(retry.min(16));
+        let base_millis = self.base_delay.as_millis() as u32;
+        if base_millis == 0 {
+            return delay;
+        }
+        // We don't need real randomness for jitter.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        delay + Duration::from_millis(u64::from(nanos % base_millis))
+    }
+}
+
+impl Default for BusyRetryPolicy {
+    fn default() -> Self {
+        BusyRetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(25),
+        }
+    }
+}
+
+/// Optional SQLite features that we detect when opening a connection. We
+/// bundle a recent SQLite, but embedders can link against an older system
+/// library, so anything that needs a newer feature should check here first.
+///
+/// The minimum supported version is SQLite 3.24.0, for upserts. Our SQL
+/// must not use anything newer without adding a flag here and a fallback
+/// for older versions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SqliteFeatures {
+    /// `INSERT ... ON CONFLICT DO UPDATE`, added in SQLite 3.24.0. Our
+    /// schema triggers use this, so we refuse to open a database without it.
+    pub upsert: bool,
+    /// Window functions, like `ROW_NUMBER() OVER (...)`, added in 3.25.0.
+    /// History sync uses these to fetch outgoing visits for many pages at
+    /// once, and falls back to a query per page without them.
+    pub window_functions: bool,
+}
+
+impl SqliteFeatures {
+    /// Returns the features supported by a SQLite version string like
+    /// "3.28.0". Unparseable versions are assumed to support nothing.
+    pub fn from_version(version: &str) -> Self {
+        let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+        let version = match (parts.next(), parts.next()) {
+            (Some(Some(major)), Some(Some(minor))) => (major, minor),
+            _ => (0, 0),
+        };
+        SqliteFeatures {
+            upsert: version >= (3, 24),
+            window_functions: version >= (3, 25),
+        }
+    }
+
+    fn detect(db: &Connection) -> Result<Self> {
+        let version: String =
+            db.query_row("SELECT sqlite_version()", rusqlite::NO_PARAMS, |row| {
+                row.get(0)
+            })?;
+        Self::check(&version)
+    }
+
+    /// Like `from_version`, but fails if the version is too old to use.
+    fn check(version: &str) -> Result<Self> {
+        let features = Self::from_version(version);
+        log::debug!("SQLite version {}: {:?}", version, features);
+        if !features.upsert {
+            return Err(ErrorKind::UnsupportedSqliteVersion(version.to_string()).into());
+        }
+        Ok(features)
+    }
+}
+
 #[derive(Debug)]
 pub struct PlacesDb {
     pub db: Connection,
@@ -21,6 +131,13 @@ pub struct PlacesDb {
     interrupt_counter: Arc<AtomicUsize>,
     api_id: usize,
     in_memory: bool,
+    busy_retry_policy: BusyRetryPolicy,
+    frecency_settings: RefCell<FrecencySettings>,
+    clock: RefCell<Arc<dyn Clock>>,
+    sqlite_features: SqliteFeatures,
+    // Boxed so that its address stays the same for the profile hook. It's
+    // declared after `db`, so it outlives the connection.
+    sql_stats: Box<SqlStatsRecorder>,
     pub(super) coop_tx_lock: Arc<Mutex<()>>,
 }
 
@@ -31,6 +148,7 @@ impl PlacesDb {
         api_id: usize,
         coop_tx_lock: Arc<Mutex<()>>,
         in_memory: bool,
+        busy_retry_policy: BusyRetryPolicy,
     ) -> Result<Self> {
         let initial_pragmas = "
             -- The value we use was taken from Desktop Firefox, and seems necessary to
@@ -64,8 +182,11 @@ impl PlacesDb {
             PRAGMA journal_mode=WAL;
         ";
 
+        let sqlite_features = SqliteFeatures::detect(&db)?;
         db.execute_batch(initial_pragmas)?;
-        define_functions(&db)?;
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        define_functions(&db, clock.clone())?;
         let res = Self {
             db,
             conn_type,
@@ -74,6 +195,11 @@ impl PlacesDb {
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
             coop_tx_lock,
             in_memory,
+            busy_retry_policy,
+            frecency_settings: RefCell::default(),
+            clock: RefCell::new(clock),
+            sqlite_features,
+            sql_stats: Box::default(),
         };
         match res.conn_type() {
             // For read-only connections, we can avoid opening a transaction,
@@ -91,11 +217,15 @@ impl PlacesDb {
         Ok(res)
     }
 
+    /// Opens a connection to the database at `path`. Write APIs on the
+    /// connection retry according to `busy_retry_policy` when the database
+    /// is busy.
     pub fn open(
         path: impl AsRef<Path>,
         conn_type: ConnectionType,
         api_id: usize,
         coop_tx_lock: Arc<Mutex<()>>,
+        busy_retry_policy: BusyRetryPolicy,
     ) -> Result<Self> {
         Ok(Self::with_connection(
             Connection::open_with_flags(path, conn_type.rusqlite_flags())?,
@@ -103,12 +233,13 @@ impl PlacesDb {
             api_id,
             coop_tx_lock,
             false,
+            busy_retry_policy,
         )?)
     }
 
     #[cfg(test)]
     // Useful for some tests (although most tests should use helper functions
-    // in api::places_api::test)
+    // in crate::testing)
     pub fn open_in_memory(conn_ty: ConnectionType) -> Result<Self> {
         Ok(Self::with_connection(
             Connection::open_in_memory()?,
@@ -116,6 +247,7 @@ impl PlacesDb {
             0,
             Arc::new(Mutex::new(())),
             true,
+            BusyRetryPolicy::default(),
         )?)
     }
 
@@ -145,6 +277,101 @@ impl PlacesDb {
     pub fn is_in_memory(&self) -> bool {
         self.in_memory
     }
+
+    /// Returns how write APIs on this connection retry when the database is
+    /// busy. This is set when the connection is opened.
+    #[inline]
+    pub fn busy_retry_policy(&self) -> BusyRetryPolicy {
+        self.busy_retry_policy
+    }
+
+    /// Sets the weights and bonuses that this connection uses to calculate
+    /// frecencies. This doesn't recalculate existing frecencies.
+    pub fn set_frecency_settings(&self, settings: FrecencySettings) {
+        self.frecency_settings.replace(settings);
+    }
+
+    #[inline]
+    pub fn frecency_settings(&self) -> FrecencySettings {
+        self.frecency_settings.borrow().clone()
+    }
+
+    /// Sets the clock that this connection uses for the current time,
+    /// including in the `now()` SQL function.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) -> Result<()> {
+        define_now_function(&self.db, clock.clone())?;
+        self.clock.replace(clock);
+        Ok(())
+    }
+
+    /// Returns the current time, according to this connection's clock.
+    #[inline]
+    pub fn now(&self) -> Timestamp {
+        self.clock.borrow().now()
+    }
+
+    /// Returns the optional SQLite features that this connection supports.
+    #[inline]
+    pub fn sqlite_features(&self) -> SqliteFeatures {
+        self.sqlite_features
+    }
+
+    /// Overrides the detected SQLite features, so that tests can exercise
+    /// the fallbacks for older versions.
+    #[cfg(test)]
+    pub(crate) fn force_sqlite_features(&mut self, features: SqliteFeatures) {
+        self.sqlite_features = features;
+    }
+
+    /// Enables or disables recording stats for the SQL that this connection
+    /// runs. Disabling tracing keeps the stats recorded so far. See
+    /// `sql_stats` for details.
+    pub fn set_sql_tracing(&mut self, enabled: bool) {
+        // Safe because we own the recorder, and drop it after closing the
+        // connection.
+        unsafe {
+            self.sql_stats.set_profile_hook(self.db.handle(), enabled);
+        }
+    }
+
+    /// Returns stats for the statements that this connection ran with
+    /// tracing enabled, most expensive first.
+    pub fn sql_stats(&self) -> Vec<SqlStats> {
+        self.sql_stats.get()
+    }
+
+    /// Forgets this connection's SQL stats.
+    pub fn reset_sql_stats(&self) {
+        self.sql_stats.reset();
+    }
+
+    /// Calls `f`, retrying it according to the busy retry policy if it fails
+    /// because the database is busy. `f` should begin and commit its own
+    /// transaction. If a transaction is already open, this calls `f` exactly
+    /// once, since retrying a single statement in the middle of a transaction
+    /// could leave it half-applied.
+    pub(crate) fn retry_on_busy<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        if !self.is_autocommit() {
+            return f();
+        }
+        let policy = self.busy_retry_policy();
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(err) if err.is_busy() && attempt < policy.max_attempts => {
+                    let delay = policy.delay_for_retry(attempt - 1);
+                    log::warn!(
+                        "Database busy on attempt {}; retrying in {:?}",
+                        attempt,
+                        delay
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
 }
 
 impl Drop for PlacesDb {
@@ -172,7 +399,7 @@ impl Deref for PlacesDb {
     }
 }
 
-fn define_functions(c: &Connection) -> Result<()> {
+fn define_functions(c: &Connection, clock: Arc<dyn Clock>) -> Result<()> {
     c.create_scalar_function("get_prefix", 1, true, sql_fns::get_prefix)?;
     c.create_scalar_function("get_host_and_port", 1, true, sql_fns::get_host_and_port)?;
     c.create_scalar_function(
@@ -184,8 +411,12 @@ fn define_functions(c: &Connection) -> Result<()> {
     c.create_scalar_function("reverse_host", 1, true, sql_fns::reverse_host)?;
     c.create_scalar_function("autocomplete_match", 10, true, sql_fns::autocomplete_match)?;
     c.create_scalar_function("hash", -1, true, sql_fns::hash)?;
-    c.create_scalar_function("now", 0, false, sql_fns::now)?;
     c.create_scalar_function("generate_guid", 0, false, sql_fns::generate_guid)?;
+    define_now_function(c, clock)
+}
+
+fn define_now_function(c: &Connection, clock: Arc<dyn Clock>) -> Result<()> {
+    c.create_scalar_function("now", 0, false, move |_ctx| Ok(clock.now()))?;
     Ok(())
 }
 
@@ -193,7 +424,7 @@ mod sql_fns {
     use crate::api::matcher::{split_after_host_and_port, split_after_prefix};
     use crate::hash;
     use crate::match_impl::{AutocompleteMatch, MatchBehavior, SearchBehavior};
-    use crate::types::{SyncGuid, Timestamp};
+    use crate::types::SyncGuid;
     use rusqlite::{functions::Context, types::ValueRef, Error, Result};
 
     // Helpers for define_functions
@@ -330,11 +561,6 @@ mod sql_fns {
         Ok(res)
     }
 
-    #[inline(never)]
-    pub fn now(_ctx: &Context<'_>) -> Result<Timestamp> {
-        Ok(Timestamp::now())
-    }
-
     #[inline(never)]
     pub fn generate_guid(_ctx: &Context<'_>) -> Result<SyncGuid> {
         Ok(SyncGuid::new())
@@ -344,7 +570,15 @@ mod sql_fns {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+    };
+    use crate::storage::history::apply_observation;
+    use crate::types::VisitTransition;
     use rusqlite::NO_PARAMS;
+    use std::sync::mpsc;
+    use url::Url;
 
     // Sanity check that we can create a database.
     #[test]
@@ -352,6 +586,134 @@ mod tests {
         PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
     }
 
+    #[test]
+    fn test_sqlite_features() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
+        // We bundle a new enough SQLite for everything.
+        assert_eq!(
+            conn.sqlite_features(),
+            SqliteFeatures {
+                upsert: true,
+                window_functions: true,
+            }
+        );
+
+        for (version, upsert, window_functions) in &[
+            ("3.22.0", false, false),
+            ("3.24.0", true, false),
+            ("3.25.3", true, true),
+            ("4.0", true, true),
+            ("garbage", false, false),
+        ] {
+            assert_eq!(
+                SqliteFeatures::from_version(version),
+                SqliteFeatures {
+                    upsert: *upsert,
+                    window_functions: *window_functions,
+                },
+                "SQLite {}",
+                version
+            );
+        }
+
+        // We can run without window functions, but not without upserts.
+        assert_eq!(
+            SqliteFeatures::check("3.24.0")?,
+            SqliteFeatures {
+                upsert: true,
+                window_functions: false,
+            }
+        );
+        for version in &["3.22.0", "garbage"] {
+            match SqliteFeatures::check(version).unwrap_err().kind() {
+                ErrorKind::UnsupportedSqliteVersion(v) => assert_eq!(v, *version),
+                err => panic!("Unexpected error for SQLite {}: {:?}", version, err),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_frecency_settings() -> Result<()> {
+        let settings: FrecencySettings = serde_json::from_str(r#"{"typedVisitBonus": 0}"#)?;
+        assert_eq!(
+            settings,
+            FrecencySettings {
+                typed_visit_bonus: 0,
+                ..FrecencySettings::default()
+            }
+        );
+
+        let url = Url::parse("https://www.example.com")?;
+        let frecency_with = |settings: Option<FrecencySettings>| -> Result<i64> {
+            let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+            if let Some(settings) = settings {
+                conn.set_frecency_settings(settings);
+            }
+            for _ in 0..3 {
+                apply_observation(
+                    &conn,
+                    VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Typed),
+                )?;
+            }
+            Ok(conn.query_one::<i64>("SELECT frecency FROM moz_places")?)
+        };
+        let default_frecency = frecency_with(None)?;
+        let no_typed_bonus_frecency = frecency_with(Some(settings))?;
+        assert!(
+            no_typed_bonus_frecency < default_frecency,
+            "Frecency without a typed bonus ({}) should be less than with ({})",
+            no_typed_bonus_frecency,
+            default_frecency
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pragmas() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = PlacesDb::open(
+            dir.path().join("places.sqlite"),
+            ConnectionType::ReadWrite,
+            0,
+            Arc::new(Mutex::new(())),
+            BusyRetryPolicy::default(),
+        )?;
+        assert_eq!(conn.query_one::<String>("PRAGMA journal_mode")?, "wal");
+        assert_eq!(conn.query_one::<i64>("PRAGMA foreign_keys")?, 1);
+        assert_eq!(
+            conn.query_one::<i64>("PRAGMA busy_timeout")?,
+            BUSY_TIMEOUT.as_millis() as i64
+        );
+        // 2 is `MEMORY`.
+        assert_eq!(conn.query_one::<i64>("PRAGMA temp_store")?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_foreign_keys_cascade() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://www.example.com/")?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+        )?;
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?,
+            1
+        );
+
+        conn.execute_named(
+            "DELETE FROM moz_places WHERE url = :url",
+            &[(":url", &url.as_str())],
+        )?;
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?,
+            0
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_reverse_host() {
         let conn = PlacesDb::open_in_memory(ConnectionType::ReadOnly).expect("no memory db");
@@ -369,4 +731,125 @@ mod tests {
             .unwrap();
         assert_eq!(rev_host, ".");
     }
+
+    // Holds an exclusive lock on the database from another connection for
+    // `millis`, and returns once the lock is held.
+    fn hold_lock(path: &Path, millis: u64) -> thread::JoinHandle<()> {
+        let (tx, rx) = mpsc::channel();
+        let path = path.to_owned();
+        let handle = thread::spawn(move || {
+            let conn = Connection::open(path).expect("should open a raw connection");
+            conn.execute_batch("BEGIN EXCLUSIVE")
+                .expect("should lock the database");
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(millis));
+            conn.execute_batch("COMMIT")
+                .expect("should unlock the database");
+        });
+        rx.recv().unwrap();
+        handle
+    }
+
+    #[test]
+    fn test_interrupt() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        // Interrupting a connection that isn't running a query does nothing,
+        // so the query tells us when it starts.
+        let (started_tx, started_rx) = mpsc::channel();
+        conn.create_scalar_function("started", 0, false, move |_ctx| {
+            let _ = started_tx.send(());
+            Ok(1)
+        })?;
+        let handle = conn.new_interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            started_rx.recv().unwrap();
+            handle.interrupt();
+        });
+        let err = conn
+            .query_one::<i64>(
+                "WITH RECURSIVE n(i) AS (
+                   SELECT started() UNION ALL SELECT i + 1 FROM n WHERE i < 1000000000
+                 )
+                 SELECT COUNT(*) FROM n",
+            )
+            .expect_err("should interrupt the query");
+        interrupter.join().unwrap();
+        let err = Error::from(err);
+        assert!(err.is_interrupted(), "Unexpected error: {:?}", err);
+
+        // Scopes that begin after the interrupt aren't affected.
+        let scope = conn.begin_interrupt_scope();
+        assert!(scope.err_if_interrupted().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_on_busy() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        let open = |policy: BusyRetryPolicy| -> Result<PlacesDb> {
+            let conn = PlacesDb::open(
+                &path,
+                ConnectionType::ReadWrite,
+                0,
+                Arc::new(Mutex::new(())),
+                policy,
+            )?;
+            // Fail right away instead of waiting for the lock, so that we
+            // only succeed if we retry.
+            conn.busy_timeout(Duration::from_millis(0))?;
+            Ok(conn)
+        };
+        let url = Url::parse("https://www.example.com/")?;
+        let bookmark = InsertableBookmark {
+            parent_guid: BookmarkRootGuid::Unfiled.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            url: url.clone(),
+            title: None,
+            page_title: None,
+        };
+
+        // Without retries, we surface the busy error.
+        let conn = open(BusyRetryPolicy::disabled())?;
+        let locker = hold_lock(&path, 200);
+        let err = insert_bookmark(&conn, &bookmark.clone().into())
+            .expect_err("should fail while the database is locked");
+        assert!(err.is_busy(), "Unexpected error: {:?}", err);
+        locker.join().unwrap();
+        drop(conn);
+
+        // With retries, the writes succeed once the lock is released.
+        let conn = open(BusyRetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(20),
+        })?;
+        let locker = hold_lock(&path, 100);
+        insert_bookmark(&conn, &bookmark.into())?;
+        locker.join().unwrap();
+
+        let locker = hold_lock(&path, 100);
+        apply_observation(&conn, VisitObservation::new(url))?;
+        locker.join().unwrap();
+
+        // We never retry inside a transaction.
+        let tx = conn.begin_transaction()?;
+        let mut attempts = 0;
+        conn.retry_on_busy(|| -> Result<()> {
+            attempts += 1;
+            Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )
+            .into())
+        })
+        .expect_err("should fail without retrying");
+        assert_eq!(attempts, 1);
+        tx.rollback()?;
+
+        Ok(())
+    }
 }
@@ -5,8 +5,9 @@
 // We don't want 'db.rs' as a sub-module. We could move the contents here? Or something else?
 #[allow(clippy::module_inception)] // FIXME
 pub mod db;
-mod schema;
+pub(crate) mod schema;
+pub mod sql_stats;
 mod tx;
 pub use self::tx::PlacesTransaction;
 
-pub use crate::db::db::PlacesDb;
+pub use crate::db::db::{BusyRetryPolicy, PlacesDb, SqliteFeatures};
@@ -8,14 +8,16 @@
 // db.rs.
 
 use crate::api::places_api::ConnectionType;
-use crate::bookmark_sync::{self, create_synced_bookmark_roots};
+use crate::bookmark_sync::{self, create_synced_bookmark_roots, SyncedBookmarkValidity};
 use crate::db::PlacesDb;
 use crate::error::*;
 use crate::storage::bookmarks::create_bookmark_roots;
+use crate::storage::keywords::normalize_synced_keyword;
+use crate::storage::put_internal_meta;
 use rusqlite::NO_PARAMS;
 use sql_support::ConnExt;
 
-const VERSION: i64 = 8;
+const VERSION: i64 = 23;
 
 // Shared schema and temp tables for the read-write and Sync connections.
 const CREATE_SHARED_SCHEMA_SQL: &str = include_str!("../../sql/create_shared_schema.sql");
@@ -28,6 +30,24 @@ const CREATE_SYNC_TRIGGERS_SQL: &str = include_str!("../../sql/create_sync_trigg
 // Triggers for the main read-write connection only.
 const CREATE_MAIN_TRIGGERS_SQL: &str = include_str!("../../sql/create_main_triggers.sql");
 
+// Copies keywords from synced bookmarks into `moz_keywords`, so that we
+// don't drop them the next time we upload those bookmarks. Each page gets
+// its most recently modified keyword, and each keyword goes to the most
+// recently modified page that uses it. This runs before we create the temp
+// triggers that maintain foreign counts, so we bump them here.
+const COPY_SYNCED_KEYWORDS_SQL: &str = "
+    INSERT OR IGNORE INTO moz_keywords(keyword, place_id, lastModified)
+    SELECT keyword, placeId, MAX(serverModified) FROM moz_bookmarks_synced
+    WHERE keyword NOT NULL AND
+          placeId NOT NULL AND
+          NOT isDeleted
+    GROUP BY placeId
+    ORDER BY MAX(serverModified) DESC;
+    UPDATE moz_places SET
+        foreign_count = foreign_count + (SELECT COUNT(*) FROM moz_keywords
+                                         WHERE place_id = moz_places.id)
+    WHERE id IN (SELECT place_id FROM moz_keywords);";
+
 lazy_static::lazy_static! {
     // Triggers for the read-write and Sync connections.
     static ref CREATE_SHARED_TRIGGERS_SQL: String = {
@@ -35,6 +55,7 @@ lazy_static::lazy_static! {
             include_str!("../../sql/create_shared_triggers.sql"),
             increase_frecency_stats = update_origin_frecency_stats("+"),
             decrease_frecency_stats = update_origin_frecency_stats("-"),
+            bookmark_change_counter = MOZ_META_KEY_BOOKMARK_CHANGE_COUNTER,
         )
     };
 }
@@ -44,6 +65,10 @@ pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_COUNT: &str = "origin_frecency_co
 pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM: &str = "origin_frecency_sum";
 pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES: &str =
     "origin_frecency_sum_of_squares";
+// Bumped by triggers every time a bookmark changes, and never reset.
+pub(crate) static MOZ_META_KEY_BOOKMARK_CHANGE_COUNTER: &str = "bookmark_change_counter";
+// The schema version that `repair_legacy_schema` last ran for.
+pub(crate) static MOZ_META_KEY_SCHEMA_REPAIRED: &str = "schema_repaired_version";
 
 fn update_origin_frecency_stats(op: &str) -> String {
     format!(
@@ -73,6 +98,27 @@ fn update_origin_frecency_stats(op: &str) -> String {
     )
 }
 
+/// Recomputes the frecency of all origins from their places, and the origin
+/// frecency stats from the origins.
+fn recompute_origin_frecencies_sql() -> String {
+    format!(
+        "UPDATE moz_origins SET
+           frecency = (SELECT IFNULL(SUM(MAX(frecency, 0)), 0) FROM moz_places
+                       WHERE origin_id = moz_origins.id);
+         INSERT OR REPLACE INTO moz_meta(key, value)
+         SELECT '{frecency_count}', COUNT(*) FROM moz_origins
+         WHERE frecency > 0
+         UNION ALL
+         SELECT '{frecency_sum}', IFNULL(SUM(MAX(frecency, 0)), 0) FROM moz_origins
+         UNION ALL
+         SELECT '{frecency_sum_of_squares}',
+                IFNULL(SUM(MAX(frecency, 0) * MAX(frecency, 0)), 0) FROM moz_origins",
+        frecency_count = MOZ_META_KEY_ORIGIN_FRECENCY_COUNT,
+        frecency_sum = MOZ_META_KEY_ORIGIN_FRECENCY_SUM,
+        frecency_sum_of_squares = MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES,
+    )
+}
+
 fn get_current_schema_version(db: &PlacesDb) -> Result<i64> {
     Ok(db.query_one::<i64>("PRAGMA user_version")?)
 }
@@ -85,16 +131,15 @@ pub fn init(db: &PlacesDb) -> Result<()> {
         if user_version < VERSION {
             upgrade(db, user_version)?;
         } else {
-            log::warn!(
-                "Loaded future schema version {} (we only understand version {}). \
-                 Optimistically ",
+            // A newer version of the library might have changed the schema
+            // in ways we don't understand, so writing to it could corrupt
+            // the database.
+            log::error!(
+                "Loaded future schema version {} (we only understand version {})",
                 user_version,
                 VERSION
             );
-            // Downgrade the schema version, so that anything added with our
-            // schema is migrated forward when the newer library reads our
-            // database.
-            db.execute_batch(&format!("PRAGMA user_version = {};", VERSION))?;
+            return Err(ErrorKind::UnsupportedSchemaVersion(user_version).into());
         }
     }
     match db.conn_type() {
@@ -164,11 +209,117 @@ where
     Ok(())
 }
 
+/// Adds a column to a table, unless it's already there. Migrations that run
+/// `CREATE_SHARED_SCHEMA_SQL` create missing tables with all their current
+/// columns, so a later `ALTER TABLE` for the same column would fail.
+fn add_column_if_missing(db: &PlacesDb, table: &str, column_def: &str) -> Result<()> {
+    let name = column_def.split_whitespace().next().unwrap_or_default();
+    let exists: bool = db.query_row_named_with_context(
+        &format!(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = :name)",
+            table
+        ),
+        &[(":name", &name)],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        db.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {}", table, column_def))?;
+    }
+    Ok(())
+}
+
+/// Moves search terms out of `moz_places_metadata`, which has the same name
+/// as an unrelated Desktop table. Databases that skipped version 18 never
+/// had the old table, because that migration creates the current schema.
+fn rename_search_terms_table(db: &PlacesDb) -> Result<()> {
+    let exists: bool = db.query_row_named_with_context(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master
+                       WHERE type = 'table' AND name = :name)",
+        &[(":name", &"moz_places_metadata")],
+        |row| row.get(0),
+    )?;
+    if exists {
+        db.execute_batch(
+            "INSERT OR IGNORE INTO moz_historyvisit_search_terms(visit_id, place_id, search_term)
+             SELECT visit_id, place_id, search_term FROM moz_places_metadata;
+             DROP TABLE moz_places_metadata;",
+        )?;
+    }
+    Ok(())
+}
+
+/// Older versions stored synced keywords as-is, so we normalize them the same
+/// way as incoming keywords, and flag bookmarks with changed keywords for
+/// reupload, before copying them into `moz_keywords`.
+fn copy_synced_keywords(db: &PlacesDb) -> Result<()> {
+    let keywords = db.query_rows_and_then_named(
+        "SELECT id, keyword FROM moz_bookmarks_synced
+         WHERE keyword NOT NULL",
+        &[],
+        |row| -> Result<(i64, String)> { Ok((row.get("id")?, row.get("keyword")?)) },
+    )?;
+    for (id, keyword) in keywords {
+        let (normalized, is_original) = normalize_synced_keyword(Some(&keyword));
+        if normalized.as_ref() == Some(&keyword) {
+            continue;
+        }
+        db.execute_named_cached(
+            &format!(
+                "UPDATE moz_bookmarks_synced SET
+                   keyword = :keyword,
+                   validity = CASE WHEN :isOriginal THEN validity
+                                   ELSE MAX(validity, {reupload})
+                              END
+                 WHERE id = :id",
+                reupload = SyncedBookmarkValidity::Reupload as u8,
+            ),
+            &[
+                (":keyword", &normalized),
+                (":isOriginal", &is_original),
+                (":id", &id),
+            ],
+        )?;
+    }
+    db.execute_batch(COPY_SYNCED_KEYWORDS_SQL)?;
+    Ok(())
+}
+
+/// Early builds created tables and columns as they went, so profiles from
+/// those builds may be missing tables, like `moz_bookmarks_deleted` and the
+/// synced bookmark tables, or columns that later migrations assumed existed.
+/// This creates anything that's missing, and is safe to run more than once.
+fn repair_legacy_schema(db: &PlacesDb, version: i64) -> Result<()> {
+    // Creates missing tables and indices, with all their current columns.
+    db.execute_batch(CREATE_SHARED_SCHEMA_SQL)?;
+    // New columns are filled with their defaults, so existing bookmarks have
+    // an unknown sync status and a pending change, and will be reconciled
+    // with the server on the next sync.
+    for (table, column_def) in &[
+        ("moz_bookmarks", "syncStatus INTEGER NOT NULL DEFAULT 0"),
+        (
+            "moz_bookmarks",
+            "syncChangeCounter INTEGER NOT NULL DEFAULT 1",
+        ),
+        ("moz_bookmarks_synced", "position INTEGER"),
+        ("moz_bookmarks_synced", "serverSortindex INTEGER"),
+        ("moz_bookmarks_synced", "hasDupe BOOLEAN NOT NULL DEFAULT 0"),
+        ("moz_bookmarks_synced", "parentTitle TEXT"),
+    ] {
+        add_column_if_missing(db, table, column_def)?;
+    }
+    put_internal_meta(db, MOZ_META_KEY_SCHEMA_REPAIRED, &version)
+}
+
 fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
     log::debug!("Upgrading schema from {} to {}", from, VERSION);
     if from == VERSION {
         return Ok(());
     }
+    // Profiles from early builds may be missing tables and columns that
+    // the migrations below assume exist, so we repair them first.
+    if from < 17 {
+        repair_legacy_schema(db, 17)?;
+    }
 
     migration(db, 2, 3, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
     migration(
@@ -202,6 +353,54 @@ fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
         ],
         || Ok(()),
     )?;
+    // The legacy schema repair and the 7 to 8 migration create
+    // `moz_bookmarks_synced` with all its current columns, so these need to
+    // check if the column already exists.
+    migration(db, 8, 9, &[], || {
+        add_column_if_missing(db, "moz_bookmarks_synced", "position INTEGER")
+    })?;
+    migration(db, 9, 10, &[], || {
+        add_column_if_missing(db, "moz_bookmarks_synced", "serverSortindex INTEGER")
+    })?;
+    migration(db, 10, 11, &[], || {
+        add_column_if_missing(
+            db,
+            "moz_bookmarks_synced",
+            "hasDupe BOOLEAN NOT NULL DEFAULT 0",
+        )
+    })?;
+    // Adds the `moz_bookmarks` parent and item indices.
+    migration(db, 11, 12, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
+    // Adds `moz_bookmarks_hidden_queries`.
+    migration(db, 12, 13, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
+    // Adds `moz_deletion_log`.
+    migration(db, 13, 14, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
+    // Fixes origin frecencies that were off by one, because we used to
+    // insert new origins with a frecency of -1.
+    migration(db, 14, 15, &[&recompute_origin_frecencies_sql()], || Ok(()))?;
+    migration(db, 15, 16, &[], || {
+        add_column_if_missing(db, "moz_bookmarks_synced", "parentTitle TEXT")
+    })?;
+    // Repairs the legacy schema, which we now do before any migrations.
+    migration(db, 16, 17, &[], || Ok(()))?;
+    // Adds `moz_places_metadata`, which is now
+    // `moz_historyvisit_search_terms`.
+    migration(db, 17, 18, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
+    // Adds `moz_keywords`.
+    migration(db, 18, 19, &[CREATE_SHARED_SCHEMA_SQL], || {
+        copy_synced_keywords(db)
+    })?;
+    migration(db, 19, 20, &[], || {
+        add_column_if_missing(db, "moz_bookmarks_synced", "unknownFields TEXT")
+    })?;
+    // Adds the case-insensitive `moz_bookmarks` GUID index.
+    migration(db, 20, 21, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
+    migration(db, 21, 22, &[], || {
+        add_column_if_missing(db, "moz_bookmarks", "changeSeq INTEGER NOT NULL DEFAULT 0")
+    })?;
+    migration(db, 22, 23, &[CREATE_SHARED_SCHEMA_SQL], || {
+        rename_search_terms_table(db)
+    })?;
     // Add more migrations here...
 
     if get_current_schema_version(db)? == VERSION {
@@ -236,6 +435,86 @@ mod tests {
             .expect("should allow running twice");
     }
 
+    #[test]
+    fn test_new_db_has_bookmarks() -> Result<()> {
+        use crate::storage::bookmarks::{
+            insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        };
+
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        for index in &["itemindex", "parentindex"] {
+            let exists: bool = conn.query_row_named_with_context(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master
+                               WHERE type = 'index' AND name = :name)",
+                &[(":name", index)],
+                |row| row.get(0),
+            )?;
+            assert!(exists, "Should create {}", index);
+        }
+        insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("https://www.example.com")?,
+                title: None,
+                page_title: None,
+            }
+            .into(),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_connection_schema() -> Result<()> {
+        fn has_object(conn: &PlacesDb, master: &str, name: &str) -> Result<bool> {
+            Ok(conn.query_row_named_with_context(
+                &format!("SELECT EXISTS(SELECT 1 FROM {} WHERE name = :name)", master),
+                &[(":name", &name)],
+                |row| row.get(0),
+            )?)
+        }
+
+        let mirror = ["moz_bookmarks_synced", "moz_bookmarks_synced_structure"];
+        let temp = [
+            "mergedTree",
+            "itemsToRemove",
+            "itemsToMerge",
+            "structureToMerge",
+            "idsToWeaklyUpload",
+            "itemsToUpload",
+            "structureToUpload",
+            "tagsToUpload",
+            "pushUploadedChanges",
+            "removeLocalItems",
+            "updateGuidsAndSyncFlags",
+            "updateLocalItems",
+            "updateLocalStructure",
+        ];
+
+        let syncer = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        for name in &mirror {
+            assert!(has_object(&syncer, "sqlite_master", name)?, "{}", name);
+        }
+        for name in &temp {
+            assert!(has_object(&syncer, "sqlite_temp_master", name)?, "{}", name);
+        }
+
+        // Other connections don't need the merge tables.
+        let writer = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        for name in &temp {
+            assert!(
+                !has_object(&writer, "sqlite_temp_master", name)?,
+                "{}",
+                name
+            );
+        }
+        Ok(())
+    }
+
     fn has_tombstone(conn: &PlacesDb, guid: &SyncGuid) -> bool {
         let count: Result<Option<u32>> = conn.try_query_row(
             "SELECT COUNT(*) from moz_places_tombstones
@@ -683,4 +962,476 @@ mod tests {
         )
         .expect_err("changing the guid should fail");
     }
+
+    #[test]
+    fn test_origins() -> Result<()> {
+        use crate::observation::VisitObservation;
+        use crate::storage::history::{apply_observation, delete_place_by_guid};
+        use crate::types::VisitTransition;
+
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        for url in &[
+            "https://www.example.com/a",
+            "https://www.example.com/b",
+            "http://mozilla.org/",
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?).with_visit_type(VisitTransition::Link),
+            )?;
+        }
+
+        let origins = conn.query_rows_and_then_named(
+            "SELECT o.prefix, o.host, o.rev_host, o.frecency,
+                    (SELECT SUM(frecency) FROM moz_places
+                     WHERE origin_id = o.id) AS places_frecency
+             FROM moz_origins o
+             ORDER BY o.host",
+            &[],
+            |row| -> rusqlite::Result<_> {
+                Ok((
+                    row.get::<_, String>("prefix")?,
+                    row.get::<_, String>("host")?,
+                    row.get::<_, String>("rev_host")?,
+                    row.get::<_, i64>("frecency")?,
+                    row.get::<_, i64>("places_frecency")?,
+                ))
+            },
+        )?;
+        assert_eq!(
+            origins
+                .iter()
+                .map(|(prefix, host, rev_host, _, _)| (
+                    prefix.as_str(),
+                    host.as_str(),
+                    rev_host.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("http://", "mozilla.org", "gro.allizom."),
+                ("https://", "www.example.com", "moc.elpmaxe.www."),
+            ]
+        );
+        for (_, host, _, frecency, places_frecency) in &origins {
+            assert!(*frecency > 0, "{} should have a frecency", host);
+            assert_eq!(frecency, places_frecency, "Wrong frecency for {}", host);
+        }
+
+        // The stats should match the origins.
+        let meta = |key: &str| -> Result<i64> {
+            Ok(conn.query_row_named_with_context(
+                "SELECT value FROM moz_meta WHERE key = :key",
+                &[(":key", &key)],
+                |row| row.get(0),
+            )?)
+        };
+        let frecencies = origins.iter().map(|o| o.3).collect::<Vec<_>>();
+        assert_eq!(meta(MOZ_META_KEY_ORIGIN_FRECENCY_COUNT)?, 2);
+        assert_eq!(
+            meta(MOZ_META_KEY_ORIGIN_FRECENCY_SUM)?,
+            frecencies.iter().sum::<i64>()
+        );
+        assert_eq!(
+            meta(MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES)?,
+            frecencies.iter().map(|f| f * f).sum::<i64>()
+        );
+
+        // Deleting the last place for an origin should delete the origin.
+        let guid = conn
+            .query_one::<String>("SELECT guid FROM moz_places WHERE url = 'http://mozilla.org/'")?;
+        delete_place_by_guid(&conn, &guid.into())?;
+        let hosts = conn.query_rows_and_then_named(
+            "SELECT host FROM moz_origins",
+            &[],
+            |row| -> rusqlite::Result<String> { row.get(0) },
+        )?;
+        assert_eq!(hosts, vec!["www.example.com".to_string()]);
+        assert_eq!(meta(MOZ_META_KEY_ORIGIN_FRECENCY_COUNT)?, 1);
+
+        // The migration should recompute the same frecencies and stats.
+        conn.execute_batch(
+            "UPDATE moz_origins SET frecency = frecency - 1;
+             DELETE FROM moz_meta WHERE key LIKE 'origin_frecency_%';",
+        )?;
+        conn.execute_batch(&recompute_origin_frecencies_sql())?;
+        let frecency = conn.query_one::<i64>("SELECT frecency FROM moz_origins")?;
+        assert_eq!(frecency, frecencies[1]);
+        assert_eq!(meta(MOZ_META_KEY_ORIGIN_FRECENCY_COUNT)?, 1);
+        assert_eq!(meta(MOZ_META_KEY_ORIGIN_FRECENCY_SUM)?, frecency);
+        Ok(())
+    }
+
+    fn open_file_db(path: &std::path::Path, conn_type: ConnectionType) -> Result<PlacesDb> {
+        PlacesDb::open(
+            path,
+            conn_type,
+            0,
+            std::sync::Arc::new(std::sync::Mutex::new(())),
+            crate::db::BusyRetryPolicy::default(),
+        )
+    }
+
+    #[test]
+    fn test_upgrade_preserves_data() -> Result<()> {
+        use crate::storage::bookmarks::{get_raw_bookmark, BookmarkRootGuid};
+        use crate::testing::insert_json_tree;
+        use serde_json::json;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+            insert_json_tree(
+                &conn,
+                json!({
+                    "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                    "children": [{
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "https://www.example.com/a",
+                    }],
+                }),
+            )
+            .expect("should insert tree");
+            // Roll the schema back to v11, before we added the bookmark
+            // indices, hidden queries, and the deletion log.
+            conn.execute_batch(
+                "DROP INDEX itemindex;
+                 DROP INDEX parentindex;
+                 DROP TABLE moz_bookmarks_hidden_queries;
+                 DROP TABLE moz_deletion_log;
+                 PRAGMA user_version = 11;",
+            )?;
+        }
+
+        let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+        assert_eq!(get_current_schema_version(&conn)?, VERSION);
+        for name in &[
+            "itemindex",
+            "parentindex",
+            "moz_bookmarks_hidden_queries",
+            "moz_deletion_log",
+        ] {
+            let exists: bool = conn.query_row_named_with_context(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = :name)",
+                &[(":name", name)],
+                |row| row.get(0),
+            )?;
+            assert!(exists, "Should create {}", name);
+        }
+        let bookmark = get_raw_bookmark(&conn, &"bookmarkAAAA".into())?
+            .expect("Should keep bookmarks when upgrading");
+        assert_eq!(bookmark.title, Some("A".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_renames_search_terms_table() -> Result<()> {
+        use crate::observation::VisitObservation;
+        use crate::storage::history::apply_observation;
+        use crate::types::VisitTransition;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        let visit_id = {
+            let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+            let visit_id = apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse("https://www.example.com/a")?)
+                    .with_visit_type(VisitTransition::Link)
+                    .with_search_term("example search".to_string()),
+            )?
+            .expect("should add a visit");
+            // Roll the schema back to v22, which used the old table name.
+            conn.execute_batch(
+                "ALTER TABLE moz_historyvisit_search_terms RENAME TO moz_places_metadata;
+                 DROP INDEX visitsearchtermindex;
+                 CREATE INDEX searchtermindex ON moz_places_metadata(search_term);
+                 PRAGMA user_version = 22;",
+            )?;
+            visit_id
+        };
+
+        let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+        assert_eq!(get_current_schema_version(&conn)?, VERSION);
+        for (name, should_exist) in &[
+            ("moz_places_metadata", false),
+            ("searchtermindex", false),
+            ("moz_historyvisit_search_terms", true),
+            ("visitsearchtermindex", true),
+        ] {
+            let exists: bool = conn.query_row_named_with_context(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = :name)",
+                &[(":name", name)],
+                |row| row.get(0),
+            )?;
+            assert_eq!(exists, *should_exist, "{}", name);
+        }
+        let search_term: String = conn.query_row_named_with_context(
+            "SELECT search_term FROM moz_historyvisit_search_terms WHERE visit_id = :id",
+            &[(":id", &visit_id)],
+            |row| row.get(0),
+        )?;
+        assert_eq!(search_term, "example search");
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_normalizes_synced_keywords() -> Result<()> {
+        use crate::storage::bookmarks::BookmarkRootGuid;
+        use crate::storage::keywords::get_url_for_keyword;
+        use crate::testing::insert_json_tree;
+        use serde_json::json;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+            insert_json_tree(
+                &conn,
+                json!({
+                    "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                    "children": [
+                        {"title": "A", "url": "https://example.com/a"},
+                        {"title": "B", "url": "https://example.com/b"},
+                        {"title": "C", "url": "https://example.com/c"},
+                    ],
+                }),
+            )
+            .expect("should insert tree");
+            // Roll the schema back to v18, before we added `moz_keywords`,
+            // with keywords that older versions stored as-is.
+            conn.execute_batch(
+                "DROP TABLE moz_keywords;
+                 INSERT INTO moz_bookmarks_synced(guid, parentGuid, kind, placeId,
+                                                  keyword, serverModified)
+                 SELECT v.guid, 'unfiled_____', 1, h.id, v.keyword, v.modified
+                 FROM (SELECT 'bookmarkAAAA' AS guid, 'https://example.com/a' AS url,
+                              ' Foo ' AS keyword, 1000 AS modified UNION ALL
+                       SELECT 'bookmarkBBBB', 'https://example.com/a', 'bar', 2000
+                       UNION ALL
+                       SELECT 'bookmarkCCCC', 'https://example.com/b', 'FOO', 500
+                       UNION ALL
+                       SELECT 'bookmarkDDDD', 'https://example.com/c', 'two words', 500
+                       UNION ALL
+                       SELECT 'bookmarkEEEE', 'https://example.com/c', '', 500) v
+                 JOIN moz_places h ON h.url = v.url;
+                 PRAGMA user_version = 18;",
+            )?;
+        }
+
+        let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+        assert_eq!(get_current_schema_version(&conn)?, VERSION);
+
+        // Each page should have at most one keyword, and the keywords
+        // should be reachable however they're cased.
+        let keywords = conn.query_rows_and_then_named(
+            "SELECT k.keyword, h.url FROM moz_keywords k
+             JOIN moz_places h ON h.id = k.place_id
+             ORDER BY k.keyword",
+            &[],
+            |row| -> Result<(String, String)> { Ok((row.get(0)?, row.get(1)?)) },
+        )?;
+        assert_eq!(
+            keywords,
+            vec![
+                ("bar".to_string(), "https://example.com/a".to_string()),
+                ("foo".to_string(), "https://example.com/b".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_url_for_keyword(&conn, "FOO")?,
+            Some(Url::parse("https://example.com/b")?)
+        );
+
+        // The mirror should have normalized keywords, and bookmarks whose
+        // keywords changed should be flagged for reupload.
+        let synced = conn.query_rows_and_then_named(
+            "SELECT guid, keyword, validity FROM moz_bookmarks_synced
+             WHERE guid LIKE 'bookmark%'
+             ORDER BY guid",
+            &[],
+            |row| -> Result<(String, Option<String>, u8)> {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            },
+        )?;
+        let reupload = SyncedBookmarkValidity::Reupload as u8;
+        let valid = SyncedBookmarkValidity::Valid as u8;
+        assert_eq!(
+            synced,
+            vec![
+                (
+                    "bookmarkAAAA".to_string(),
+                    Some("foo".to_string()),
+                    reupload
+                ),
+                ("bookmarkBBBB".to_string(), Some("bar".to_string()), valid),
+                (
+                    "bookmarkCCCC".to_string(),
+                    Some("foo".to_string()),
+                    reupload
+                ),
+                ("bookmarkDDDD".to_string(), None, reupload),
+                ("bookmarkEEEE".to_string(), None, valid),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_legacy_schema() -> Result<()> {
+        use crate::bookmark_sync::store::BookmarksStore;
+        use serde_json::json;
+        use sync15::{telemetry, IncomingChangeset, Payload, ServerTimestamp, Store};
+
+        // Fabricates the layout from an early build, without the sync
+        // columns in `moz_bookmarks`, the bookmark tombstones table, or any
+        // synced bookmark tables.
+        let create_legacy_db = |version: i64| -> Result<(tempfile::TempDir, std::path::PathBuf)> {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("places.sqlite");
+            let conn = rusqlite::Connection::open(&path)?;
+            conn.execute_batch(CREATE_SHARED_SCHEMA_SQL)?;
+            conn.execute_batch(
+                "DROP TABLE moz_bookmarks_synced_tag_relation;
+                 DROP TABLE moz_bookmarks_synced_structure;
+                 DROP TABLE moz_bookmarks_synced;
+                 DROP TABLE moz_bookmarks_deleted;
+                 DROP TABLE moz_bookmarks_hidden_queries;
+                 DROP TABLE moz_bookmarks;
+                 CREATE TABLE moz_bookmarks (
+                     id INTEGER PRIMARY KEY,
+                     fk INTEGER DEFAULT NULL,
+                     type INTEGER NOT NULL,
+                     parent INTEGER,
+                     position INTEGER NOT NULL,
+                     title TEXT,
+                     dateAdded INTEGER NOT NULL DEFAULT 0,
+                     lastModified INTEGER NOT NULL DEFAULT 0,
+                     guid TEXT NOT NULL UNIQUE
+                 );
+                 INSERT INTO moz_bookmarks(id, type, parent, position, guid)
+                 VALUES(1, 2, NULL, 0, 'root________'),
+                       (2, 2, 1, 0, 'menu________'),
+                       (3, 2, 1, 1, 'toolbar_____'),
+                       (4, 2, 1, 2, 'unfiled_____'),
+                       (5, 2, 1, 3, 'mobile______'),
+                       (6, 2, 4, 0, 'folderAAAAAA');",
+            )?;
+            conn.execute_batch(&format!("PRAGMA user_version = {};", version))?;
+            Ok((dir, path))
+        };
+
+        // Early builds wrote versions before the synced bookmark columns
+        // were added, too, so the `ALTER TABLE` migrations must also handle
+        // the legacy layout. We don't go below 4, since the 3 to 4
+        // migration replaces `moz_bookmarks`.
+        for &version in &[4, 8, 10, 16] {
+            let (_dir, path) = create_legacy_db(version)?;
+            let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+            assert_eq!(get_current_schema_version(&conn)?, VERSION);
+            for name in &[
+                "moz_bookmarks_deleted",
+                "moz_bookmarks_synced",
+                "moz_bookmarks_synced_structure",
+                "moz_bookmarks_synced_tag_relation",
+                "moz_bookmarks_hidden_queries",
+                "itemindex",
+                "parentindex",
+            ] {
+                let exists: bool = conn.query_row_named_with_context(
+                    "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = :name)",
+                    &[(":name", name)],
+                    |row| row.get(0),
+                )?;
+                assert!(exists, "Should create {} from v{}", name, version);
+            }
+            let (status, counter): (i64, i64) = conn.query_row(
+                "SELECT syncStatus, syncChangeCounter FROM moz_bookmarks
+                 WHERE guid = 'folderAAAAAA'",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            assert_eq!((status, counter), (SyncStatus::Unknown as i64, 1));
+            assert_eq!(
+                crate::storage::get_meta::<i64>(&conn, MOZ_META_KEY_SCHEMA_REPAIRED)?,
+                Some(17)
+            );
+        }
+
+        let (_dir, path) = create_legacy_db(16)?;
+        let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+        // Repairing an already repaired database shouldn't change anything.
+        repair_legacy_schema(&conn, 17)?;
+        drop(conn);
+
+        // Syncing should work, and upload the existing folder.
+        let conn = open_file_db(&path, ConnectionType::Sync)?;
+        let interrupt_scope = conn.begin_interrupt_scope();
+        let store = BookmarksStore::new(&conn, &interrupt_scope);
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in vec![
+            json!({
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "places",
+                "title": "Unfiled",
+                "children": ["bookmarkBBBB"],
+            }),
+            json!({
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "parentName": "Unfiled",
+                "title": "B",
+                "bmkUri": "https://www.example.com/b",
+            }),
+        ] {
+            incoming
+                .changes
+                .push((Payload::from_json(record).unwrap(), ServerTimestamp(0.0)));
+        }
+        let outgoing = store
+            .apply_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should apply incoming and stage outgoing records");
+        let ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.as_str())
+            .collect::<Vec<_>>();
+        assert!(ids.contains(&"folderAAAAAA"), "Should upload {:?}", ids);
+        assert!(
+            crate::storage::bookmarks::get_raw_bookmark(&conn, &"bookmarkBBBB".into())?.is_some()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_future_schema_version() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        {
+            let conn = open_file_db(&path, ConnectionType::ReadWrite)?;
+            conn.execute_batch(&format!("PRAGMA user_version = {};", VERSION + 1))?;
+        }
+
+        for &conn_type in &[ConnectionType::ReadWrite, ConnectionType::Sync] {
+            match open_file_db(&path, conn_type) {
+                Err(e) => match e.kind() {
+                    ErrorKind::UnsupportedSchemaVersion(version) => {
+                        assert_eq!(*version, VERSION + 1)
+                    }
+                    kind => panic!("Unexpected error opening future database: {:?}", kind),
+                },
+                Ok(_) => panic!("Shouldn't open a database from the future"),
+            }
+        }
+
+        // We shouldn't touch the version, so that the newer library can
+        // still open the database.
+        let reader = open_file_db(&path, ConnectionType::ReadOnly)?;
+        assert_eq!(get_current_schema_version(&reader)?, VERSION + 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,213 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Opt-in statistics about the SQL we run, for diagnosing slow syncs.
+//! `PlacesDb::set_sql_tracing` installs an SQLite profile hook that records
+//! how often each statement runs and how long it takes. Statements are
+//! grouped by fingerprint: the SQL with literals replaced by `?` and
+//! whitespace collapsed, so that we never record URLs or titles that were
+//! interpolated into a statement.
+//!
+//! Each connection keeps its own stats, which `PlacesDb::sql_stats` returns.
+//! rusqlite's profile hook is a plain function pointer, which can't tell
+//! connections apart, so we install the hook with `sqlite3_profile`
+//! directly, and pass the connection's recorder as the hook's argument.
+//! Connections without tracing don't install the hook, and pay nothing.
+
+use rusqlite::ffi;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::time::Duration;
+
+/// Stats for all statements with the same fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlStats {
+    pub fingerprint: String,
+    /// The number of times we ran a statement with this fingerprint.
+    pub count: u64,
+    /// The total time spent running those statements.
+    pub total_time: Duration,
+}
+
+/// Records stats for the statements that one connection runs. The
+/// connection owns its recorder, and must outlive the profile hook.
+#[derive(Debug, Default)]
+pub(crate) struct SqlStatsRecorder {
+    stats: RefCell<HashMap<String, (u64, Duration)>>,
+}
+
+impl SqlStatsRecorder {
+    /// Returns the stats for all traced statements, most expensive first.
+    pub(crate) fn get(&self) -> Vec<SqlStats> {
+        let mut stats = self
+            .stats
+            .borrow()
+            .iter()
+            .map(|(fingerprint, &(count, total_time))| SqlStats {
+                fingerprint: fingerprint.clone(),
+                count,
+                total_time,
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| {
+            b.total_time
+                .cmp(&a.total_time)
+                .then_with(|| a.fingerprint.cmp(&b.fingerprint))
+        });
+        stats
+    }
+
+    /// Forgets all recorded stats.
+    pub(crate) fn reset(&self) {
+        self.stats.borrow_mut().clear();
+    }
+
+    fn record(&self, sql: &str, elapsed: Duration) {
+        // The hook never runs while we're reading the stats, but we'd rather
+        // drop a sample than panic across the FFI boundary if it did.
+        if let Ok(mut stats) = self.stats.try_borrow_mut() {
+            let entry = stats
+                .entry(fingerprint(sql))
+                .or_insert((0, Duration::default()));
+            entry.0 += 1;
+            entry.1 += elapsed;
+        }
+    }
+
+    /// Installs or removes the profile hook for `db`.
+    ///
+    /// # Safety
+    ///
+    /// `db` must be a valid connection handle, and the recorder must not move
+    /// or be dropped until the hook is removed or the connection is closed.
+    pub(crate) unsafe fn set_profile_hook(&self, db: *mut ffi::sqlite3, enabled: bool) {
+        if enabled {
+            ffi::sqlite3_profile(
+                db,
+                Some(profile_callback),
+                self as *const SqlStatsRecorder as *mut c_void,
+            );
+        } else {
+            ffi::sqlite3_profile(db, None, ptr::null_mut());
+        }
+    }
+}
+
+unsafe extern "C" fn profile_callback(
+    recorder: *mut c_void,
+    sql: *const c_char,
+    nanoseconds: ffi::sqlite3_uint64,
+) {
+    let recorder = &*(recorder as *const SqlStatsRecorder);
+    let sql = CStr::from_ptr(sql).to_string_lossy();
+    recorder.record(&sql, Duration::from_nanos(nanoseconds));
+}
+
+/// Replaces string and numeric literals with `?`, and collapses runs of
+/// whitespace into a single space. Digits inside identifiers, like
+/// `moz_places_v2`, are kept.
+fn fingerprint(sql: &str) -> String {
+    let mut result = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut in_identifier = false;
+    let mut pending_space = false;
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            pending_space = !result.is_empty();
+            in_identifier = false;
+            continue;
+        }
+        if pending_space {
+            result.push(' ');
+            pending_space = false;
+        }
+        match c {
+            '\'' => {
+                // Skip to the end of the string, including escaped quotes.
+                while let Some(c) = chars.next() {
+                    if c == '\'' {
+                        if chars.peek() == Some(&'\'') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                result.push('?');
+                in_identifier = false;
+            }
+            c if c.is_ascii_digit() && !in_identifier => {
+                while chars
+                    .peek()
+                    .map_or(false, |c| c.is_ascii_alphanumeric() || *c == '.')
+                {
+                    chars.next();
+                }
+                result.push('?');
+            }
+            c => {
+                result.push(c);
+                in_identifier = c.is_alphanumeric() || c == '_';
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::ConnectionType;
+    use crate::db::PlacesDb;
+    use sql_support::ConnExt;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(
+            fingerprint(
+                "SELECT id FROM moz_places
+                 WHERE url_hash = hash('https://example.com/?q=1')
+                   AND frecency > -1.5 AND title = 'It''s'"
+            ),
+            "SELECT id FROM moz_places WHERE url_hash = hash(?) AND frecency > -? AND title = ?"
+        );
+        assert_eq!(
+            fingerprint("UPDATE moz_places_v2 SET x = :x WHERE id IN (1, 0x2f)"),
+            "UPDATE moz_places_v2 SET x = :x WHERE id IN (?, ?)"
+        );
+    }
+
+    #[test]
+    fn test_sql_tracing() -> crate::error::Result<()> {
+        let mut conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let other = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        conn.set_sql_tracing(true);
+        for i in 0..3 {
+            conn.query_one::<i64>(&format!("SELECT {} + 1", i))?;
+        }
+        conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places")?;
+        // Statements on other connections aren't recorded.
+        other.query_one::<i64>("SELECT 1")?;
+
+        let mut stats = conn.sql_stats();
+        stats.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        assert_eq!(
+            stats
+                .iter()
+                .map(|s| (s.fingerprint.as_str(), s.count))
+                .collect::<Vec<_>>(),
+            vec![("SELECT ? + ?", 3), ("SELECT COUNT(*) FROM moz_places", 1)]
+        );
+        assert!(other.sql_stats().is_empty());
+
+        conn.reset_sql_stats();
+        conn.set_sql_tracing(false);
+        conn.query_one::<i64>("SELECT 1")?;
+        assert!(conn.sql_stats().is_empty());
+        Ok(())
+    }
+}
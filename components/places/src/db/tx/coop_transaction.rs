@@ -66,6 +66,21 @@ impl PlacesDb {
         // is closely related to the timeouts configured on the database
         // itself.
         let commit_after = Duration::from_millis(1000);
+        self.time_chunked_coop_transaction(commit_after)
+    }
+
+    /// Begin a ChunkedCoopTransaction that commits after `commit_after`, on
+    /// either write connection. `commit_after` should be well under the busy
+    /// timeout, so that the other writer doesn't give up waiting for us.
+    pub(super) fn time_chunked_coop_transaction(
+        &self,
+        commit_after: Duration,
+    ) -> Result<ChunkedCoopTransaction<'_>> {
+        assert_ne!(
+            self.conn_type(),
+            ConnectionType::ReadOnly,
+            "time_chunked_coop_transaction must not be called on a ReadOnly connection"
+        );
         Ok(ChunkedCoopTransaction::new(
             self.conn(),
             commit_after,
@@ -136,14 +151,28 @@ impl<'conn> ChunkedCoopTransaction<'conn> {
 
     /// Checks to see if we have held a transaction for longer than the
     /// requested time, and if so, commits the current transaction and opens
-    /// another.
+    /// another. Returns true if we committed. Temp tables and triggers
+    /// belong to the connection, so they're still there in the new
+    /// transaction, along with any rows that were pending in them.
     #[inline]
-    pub fn maybe_commit(&mut self) -> Result<()> {
+    pub fn maybe_commit(&mut self) -> Result<bool> {
+        self.maybe_commit_with(|| Ok(()))
+    }
+
+    /// Like `maybe_commit`, but calls `before_commit` first if we're about
+    /// to commit, so that it can write anything that needs to be committed
+    /// along with the current chunk.
+    pub fn maybe_commit_with<F>(&mut self, before_commit: F) -> Result<bool>
+    where
+        F: FnOnce() -> Result<()>,
+    {
         if self.tx.started_at.elapsed() >= self.commit_after {
+            before_commit()?;
             log::debug!("ChunkedCoopTransaction commiting after taking allocated time");
             self.commit_and_start_new_tx()?;
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
     }
 
     fn commit_and_start_new_tx(&mut self) -> Result<()> {
@@ -9,6 +9,7 @@ use crate::error::*;
 use coop_transaction::ChunkedCoopTransaction;
 use rusqlite::Connection;
 use sql_support::{ConnExt, UncheckedTransaction};
+use std::time::Duration;
 
 macro_rules! debug_complaint {
     ($($fmt_args:tt)*) => {
@@ -30,6 +31,10 @@ enum PlacesTransactionRepr<'conn> {
     // Note: these might seem pointless, but can allow us to ensure consistency
     // between separate reads.
     ReadOnly(UncheckedTransaction<'conn>),
+    // A transaction started while another one is already open on the
+    // connection. The outer transaction decides whether to commit or roll
+    // back everything.
+    Nested(NestedTransaction<'conn>),
 }
 
 impl<'conn> PlacesTransaction<'conn> {
@@ -38,14 +43,30 @@ impl<'conn> PlacesTransaction<'conn> {
     ///   current transaction and opens another.
     /// - For transactions on other connections: `debug_assert!`s, or logs a
     ///   warning and does nothing.
+    ///
+    /// Returns true if we committed.
     #[inline]
-    pub fn maybe_commit(&mut self) -> Result<()> {
-        if let PlacesTransactionRepr::ChunkedWrite(tx) = &mut self.0 {
-            tx.maybe_commit()?;
-        } else {
-            debug_complaint!("maybe_commit called on a non-chunked transaction");
-        }
-        Ok(())
+    pub fn maybe_commit(&mut self) -> Result<bool> {
+        self.maybe_commit_with(|| Ok(()))
+    }
+
+    /// Like `maybe_commit`, but calls `before_commit` first if we're about
+    /// to commit. Use this for bookkeeping, like deletion log entries, that
+    /// must be committed in the same transaction as the changes it
+    /// describes.
+    pub fn maybe_commit_with<F>(&mut self, before_commit: F) -> Result<bool>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        Ok(match &mut self.0 {
+            PlacesTransactionRepr::ChunkedWrite(tx) => tx.maybe_commit_with(before_commit)?,
+            // Committing would also commit the outer transaction.
+            PlacesTransactionRepr::Nested(_) => false,
+            _ => {
+                debug_complaint!("maybe_commit called on a non-chunked transaction");
+                false
+            }
+        })
     }
 
     /// Consumes and commits a PlacesTransaction transaction.
@@ -54,6 +75,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::ReadOnly(t) => t.commit()?,
+            PlacesTransactionRepr::Nested(t) => t.commit()?,
         };
         Ok(())
     }
@@ -66,6 +88,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::ReadOnly(t) => t.rollback()?,
+            PlacesTransactionRepr::Nested(t) => t.rollback()?,
         };
         Ok(())
     }
@@ -77,7 +100,16 @@ impl super::PlacesDb {
     /// - For Sync connections, begins a chunked coop transaction.
     /// - for ReadWrite connections, begins a normal coop transaction
     /// - for ReadOnly connections, begins an unchecked transaction.
+    ///
+    /// If a transaction is already open on this connection, begins a nested
+    /// transaction using a savepoint instead. Nested transactions never
+    /// commit early, so that the outer transaction can roll everything back.
     pub fn begin_transaction(&self) -> Result<PlacesTransaction<'_>> {
+        if !self.is_autocommit() {
+            return Ok(PlacesTransaction(PlacesTransactionRepr::Nested(
+                NestedTransaction::new(self.conn())?,
+            )));
+        }
         Ok(PlacesTransaction(match self.conn_type() {
             ConnectionType::Sync => {
                 PlacesTransactionRepr::ChunkedWrite(self.chunked_coop_trransaction()?)
@@ -91,6 +123,29 @@ impl super::PlacesDb {
             }
         }))
     }
+
+    /// Begins a transaction on a write connection that `maybe_commit`
+    /// commits, and replaces with a new one, once it's been open for
+    /// `commit_after`. This keeps long-running writes from blocking readers
+    /// and the other writer. Like `begin_transaction`, if a transaction is
+    /// already open, this begins a nested transaction that never commits
+    /// early. Fails with `InvalidConnectionType` on a ReadOnly connection.
+    pub fn begin_time_chunked_transaction(
+        &self,
+        commit_after: Duration,
+    ) -> Result<PlacesTransaction<'_>> {
+        if self.conn_type() == ConnectionType::ReadOnly {
+            return Err(ErrorKind::InvalidConnectionType.into());
+        }
+        if !self.is_autocommit() {
+            return Ok(PlacesTransaction(PlacesTransactionRepr::Nested(
+                NestedTransaction::new(self.conn())?,
+            )));
+        }
+        Ok(PlacesTransaction(PlacesTransactionRepr::ChunkedWrite(
+            self.time_chunked_coop_transaction(commit_after)?,
+        )))
+    }
 }
 
 impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
@@ -101,6 +156,7 @@ impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => &t,
             PlacesTransactionRepr::UnchunkedWrite(t) => &t,
             PlacesTransactionRepr::ReadOnly(t) => &t,
+            PlacesTransactionRepr::Nested(t) => t.conn,
         }
     }
 }
@@ -111,3 +167,51 @@ impl<'conn> ConnExt for PlacesTransaction<'conn> {
         &*self
     }
 }
+
+/// A savepoint-backed transaction, used when `begin_transaction` is called
+/// while another transaction is open. Committing releases the savepoint, and
+/// dropping without committing rolls back to it.
+struct NestedTransaction<'conn> {
+    conn: &'conn Connection,
+    finished: bool,
+}
+
+impl<'conn> NestedTransaction<'conn> {
+    fn new(conn: &'conn Connection) -> Result<Self> {
+        conn.execute_batch("SAVEPOINT places_nested")?;
+        Ok(Self {
+            conn,
+            finished: false,
+        })
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        self.conn.execute_batch("RELEASE places_nested")?;
+        Ok(())
+    }
+
+    fn rollback(mut self) -> Result<()> {
+        self.rollback_()
+    }
+
+    fn rollback_(&mut self) -> Result<()> {
+        self.finished = true;
+        // `ROLLBACK TO` undoes the changes, but leaves the savepoint on the
+        // stack, so we need to release it, too.
+        self.conn
+            .execute_batch("ROLLBACK TO places_nested; RELEASE places_nested")?;
+        Ok(())
+    }
+}
+
+impl<'conn> Drop for NestedTransaction<'conn> {
+    fn drop(&mut self) {
+        if self.finished || self.conn.is_autocommit() {
+            return;
+        }
+        if let Err(e) = self.rollback_() {
+            log::warn!("Error dropping a nested transaction: {}", e);
+        }
+    }
+}
@@ -39,6 +39,30 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         &*self.0.get_context()
     }
+
+    /// Returns true if this error means another connection was holding a
+    /// lock on the database, so trying again later might succeed.
+    pub fn is_busy(&self) -> bool {
+        match self.kind() {
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) => match err.code {
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns true if this error means the operation was interrupted,
+    /// either by SQLite, or between statements by an interrupt scope.
+    pub fn is_interrupted(&self) -> bool {
+        match self.kind() {
+            ErrorKind::InterruptedError(_) => true,
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) => {
+                err.code == rusqlite::ErrorCode::OperationInterrupted
+            }
+            _ => false,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -101,6 +125,9 @@ pub enum ErrorKind {
     #[fail(display = "Incoming bookmark has unsupported type {}", _0)]
     UnsupportedIncomingBookmarkType(String),
 
+    #[fail(display = "Bookmark has unsupported type {}", _0)]
+    UnsupportedBookmarkType(u8),
+
     #[fail(display = "Synced bookmark has unsupported kind {}", _0)]
     UnsupportedSyncedBookmarkKind(u8),
 
@@ -118,6 +145,24 @@ pub enum ErrorKind {
 
     #[fail(display = "Database cannot be upgraded")]
     DatabaseUpgradeError,
+
+    // Unlike `DatabaseUpgradeError`, we never delete the database for this,
+    // since a newer version of the library wrote it.
+    #[fail(display = "Database schema version {} is newer than we support", _0)]
+    UnsupportedSchemaVersion(i64),
+
+    // Meta keys are names like "bookmarks_last_sync_time", never values, so
+    // they're fine to log.
+    #[fail(display = "The meta key {:?} is reserved", _0)]
+    ReservedMetaKey(String),
+
+    #[fail(display = "SQLite version {} is too old", _0)]
+    UnsupportedSqliteVersion(String),
+
+    // Query params are names like "sort" or "folder", never values, so
+    // they're fine to log.
+    #[fail(display = "Query has unsupported params: {:?}", _0)]
+    UnsupportedQuery(Vec<String>),
 }
 
 macro_rules! impl_from_error {
@@ -155,8 +200,19 @@ impl_from_error! {
 pub enum InvalidPlaceInfo {
     #[fail(display = "No url specified")]
     NoUrl,
+
+    // Like `NoSuchUrl`, we don't include the full URL, just enough of the
+    // start to help diagnose the problem. See `storage::parse_href`.
+    #[fail(display = "Malformed URL: {:?}", input_snippet)]
+    MalformedUrl { input_snippet: String },
+
     #[fail(display = "Invalid guid")]
     InvalidGuid,
+
+    // Like `NoSuchGuid`, it's fine to log the guid here.
+    #[fail(display = "An item with guid {} already exists", _0)]
+    GuidAlreadyExists(String),
+
     #[fail(display = "Invalid parent: {}", _0)]
     InvalidParent(String),
 
@@ -184,6 +240,11 @@ pub enum InvalidPlaceInfo {
     // Like Urls, a tag is considered private info, so the value isn't in the error.
     #[fail(display = "The tag value is invalid")]
     InvalidTag,
+
+    // Keywords are considered private info, just like tags.
+    #[fail(display = "The keyword value is invalid")]
+    InvalidKeyword,
+
     #[fail(
         display = "Cannot change the '{}' property of a bookmark of type {:?}",
         _0, _1
@@ -192,6 +253,10 @@ pub enum InvalidPlaceInfo {
 
     #[fail(display = "Cannot update the bookmark root {:?}", _0)]
     CannotUpdateRoot(BookmarkRootGuid),
+
+    // Like `NoSuchGuid`, it's fine to log the guid here.
+    #[fail(display = "Not a query bookmark: {}", _0)]
+    NotAQuery(String),
 }
 
 // Error types used when we can't continue due to corruption.
@@ -49,7 +49,7 @@ pub mod error_codes {
 
     /// `IllegalChange`: Attempt to change a property on a bookmark node that
     /// cannot have that property. E.g. trying to edit the URL of a folder,
-    /// title of a separator, etc.
+    /// title of a separator, hiding a bookmark that isn't a query, etc.
     pub const INVALID_PLACE_INFO_ILLEGAL_CHANGE: i32 = 64 + 3;
 
     /// `CannotUpdateRoot`: Attempt to modify a root in a way that is illegal, e.g. adding a child
@@ -67,8 +67,11 @@ fn get_code(err: &Error) -> ErrorCode {
                     error_codes::INVALID_PLACE_INFO_INVALID_PARENT
                 }
                 InvalidPlaceInfo::NoSuchGuid(..) => error_codes::INVALID_PLACE_INFO_NO_ITEM,
-                InvalidPlaceInfo::UrlTooLong => error_codes::INVALID_PLACE_INFO_INVALID_PARENT,
-                InvalidPlaceInfo::IllegalChange(..) => {
+                InvalidPlaceInfo::UrlTooLong => error_codes::INVALID_PLACE_INFO_URL_TOO_LONG,
+                // Consumers already handle URL parse errors, so we report
+                // malformed URLs the same way.
+                InvalidPlaceInfo::MalformedUrl { .. } => error_codes::URL_PARSE_ERROR,
+                InvalidPlaceInfo::IllegalChange(..) | InvalidPlaceInfo::NotAQuery(..) => {
                     error_codes::INVALID_PLACE_INFO_ILLEGAL_CHANGE
                 }
                 InvalidPlaceInfo::CannotUpdateRoot(..) => {
@@ -5,6 +5,7 @@
 use crate::error::*;
 use crate::types::VisitTransition;
 use rusqlite::Connection;
+use serde_derive::*;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum RedirectBonus {
@@ -13,7 +14,11 @@ enum RedirectBonus {
     Normal,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Weights and bonuses for calculating frecency. Embedders can override these
+/// for all connections with `PlacesApi::set_frecency_settings`, and can ship
+/// them as JSON; missing fields use the defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
 pub struct FrecencySettings {
     // TODO: These probably should not all be i32s...
     pub num_visits: i32,                     // from "places.frecency.numVisits"
@@ -39,6 +44,9 @@ pub struct FrecencySettings {
     pub unvisited_bookmark_bonus: i32,       // from "places.frecency.unvisitedBookmarkBonus"
     pub unvisited_typed_bonus: i32,          // from "places.frecency.unvisitedTypedBonus"
     pub reload_visit_bonus: i32,             // from "places.frecency.reloadVisitBonus"
+    // Desktop doesn't have an equivalent for this one, since it doesn't
+    // use the sortindex from incoming bookmarks.
+    pub max_remote_sortindex_bonus: i32,
 }
 
 pub const DEFAULT_FRECENCY_SETTINGS: FrecencySettings = FrecencySettings {
@@ -66,6 +74,8 @@ pub const DEFAULT_FRECENCY_SETTINGS: FrecencySettings = FrecencySettings {
     unvisited_bookmark_bonus: 140,
     unvisited_typed_bonus: 200,
     reload_visit_bonus: 0,
+    // More than an unvisited bookmark, but less than a single typed visit.
+    max_remote_sortindex_bonus: 500,
 };
 
 impl Default for FrecencySettings {
@@ -126,8 +136,10 @@ struct FrecencyComputation<'db, 's> {
 
     typed: i32,
     visit_count: i32,
+    local_visit_count: i32,
     foreign_count: i32,
     is_query: bool,
+    remote_sortindex: i32,
 }
 
 impl<'db, 's> FrecencyComputation<'db, 's> {
@@ -137,16 +149,20 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
         page_id: i64,
         most_recent_redirect_bonus: RedirectBonus,
     ) -> Result<Self> {
-        let (typed, visit_count, foreign_count, is_query) = conn.query_row_named("
-            SELECT typed, (visit_count_local + visit_count_remote) as visit_count, foreign_count, (substr(url, 0, 7) = 'place:') as is_query
-            FROM moz_places
+        let (typed, visit_count, local_visit_count, foreign_count, is_query, remote_sortindex) = conn.query_row_named("
+            SELECT typed, (visit_count_local + visit_count_remote) as visit_count, visit_count_local, foreign_count, (substr(url, 0, 7) = 'place:') as is_query,
+                   IFNULL((SELECT MAX(b.serverSortindex) FROM moz_bookmarks_synced b
+                           WHERE b.placeId = h.id AND NOT b.isDeleted), 0) as remote_sortindex
+            FROM moz_places h
             WHERE id = :page_id
         ", &[(":page_id", &page_id)], |row| {
             let typed: i32 = row.get("typed")?;
             let visit_count: i32 = row.get("visit_count")?;
+            let local_visit_count: i32 = row.get("visit_count_local")?;
             let foreign_count: i32 = row.get("foreign_count")?;
             let is_query: bool = row.get("is_query")?;
-            Ok((typed, visit_count, foreign_count, is_query))
+            let remote_sortindex: i32 = row.get("remote_sortindex")?;
+            Ok((typed, visit_count, local_visit_count, foreign_count, is_query, remote_sortindex))
         })?;
 
         Ok(Self {
@@ -156,8 +172,10 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
             most_recent_redirect_bonus,
             typed,
             visit_count,
+            local_visit_count,
             foreign_count,
             is_query,
+            remote_sortindex,
         })
     }
 
@@ -278,6 +296,24 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
         // would cause us to completely ignore the place during autocomplete
         score.ceil() as i32
     }
+
+    fn compute_remote_sortindex_bonus(&self) -> i32 {
+        // Synced bookmarks don't come with local visits, so they'd rank
+        // poorly in autocomplete even if they're used a lot on other devices.
+        // Desktop sets the sortindex for bookmark records from frecency, so we
+        // use it for a bounded bonus, which we halve for every local visit so
+        // that local history takes over.
+        if !self.has_bookmark() || self.is_query {
+            return 0;
+        }
+        let bonus = self
+            .remote_sortindex
+            .max(0)
+            .min(self.settings.max_remote_sortindex_bonus);
+        bonus
+            .checked_shr(self.local_visit_count.max(0) as u32)
+            .unwrap_or(0)
+    }
 }
 
 pub fn calculate_frecency(
@@ -302,7 +338,7 @@ pub fn calculate_frecency(
         (0, 0.0f32)
     };
 
-    Ok(if num_sampled_visits > 0 {
+    let frecency = if num_sampled_visits > 0 {
         // If we sampled some visits for this page, use the calculated weight.
         fc.get_frecency_for_sample(num_sampled_visits, sample_score)
     } else if !fc.has_bookmark() || fc.is_query {
@@ -312,5 +348,6 @@ pub fn calculate_frecency(
         // For unvisited bookmarks, produce a non-zero frecency, so that they show
         // up in URL bar autocomplete.
         fc.compute_unvisited_bookmark_frecency()
-    })
+    };
+    Ok(frecency.saturating_add(fc.compute_remote_sortindex_bonus()))
 }
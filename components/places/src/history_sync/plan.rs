@@ -2,20 +2,25 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use super::record::{HistoryRecord, HistoryRecordVisit, HistorySyncRecord};
+use super::record::{HistoryRecord, HistorySyncRecord};
 use super::{HISTORY_TTL, MAX_OUTGOING_PLACES, MAX_VISITS};
 use crate::api::history::can_add_url;
 use crate::db::PlacesDb;
 use crate::error::*;
+use crate::log_sampler::LogSampler;
+use crate::storage::deletion_log::{record_deletion, DeletionInitiator, DeletionOp};
 use crate::storage::history::history_sync::{
     apply_synced_deletion, apply_synced_reconciliation, apply_synced_visits, fetch_outgoing,
     fetch_visits, finish_incoming, finish_outgoing, FetchedVisit, FetchedVisitPage, OutgoingInfo,
+    SyncedVisit,
 };
+use crate::storage::parse_href;
 use crate::types::{SyncGuid, Timestamp, VisitTransition};
 use crate::valid_guid::is_valid_places_guid;
 use interrupt::Interruptee;
+use log::Level;
 use serde_json;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::time::{SystemTime, UNIX_EPOCH};
 use sync15::telemetry;
 use sync15::{IncomingChangeset, OutgoingChangeset, Payload};
@@ -29,10 +34,9 @@ use url::Url;
 // this timestamp.
 const EARLIEST_TIMESTAMP: Timestamp = Timestamp(727_747_200_000);
 
-/// Clamps a history visit date between the current date and the earliest
-/// sensible date.
-fn clamp_visit_date(visit_date: Timestamp) -> Timestamp {
-    let now = Timestamp::now();
+/// Clamps a history visit date between `now` and the earliest sensible
+/// date.
+fn clamp_visit_date(visit_date: Timestamp, now: Timestamp) -> Timestamp {
     if visit_date > now {
         return now;
     }
@@ -59,7 +63,7 @@ pub enum IncomingPlan {
     Apply {
         url: Url,
         new_title: Option<String>,
-        visits: Vec<HistoryRecordVisit>,
+        visits: Vec<SyncedVisit>,
     },
     /// Entry exists locally and it's the same as the incoming record. This is
     /// subtly different from Skip as we may still need to write metadata to
@@ -68,7 +72,7 @@ pub enum IncomingPlan {
 }
 
 fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usize) -> IncomingPlan {
-    let url = match Url::parse(&record.hist_uri) {
+    let url = match parse_href(&record.hist_uri) {
         Ok(u) => u,
         Err(e) => return IncomingPlan::Invalid(e.into()),
     };
@@ -107,6 +111,7 @@ fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usiz
         None => false,
     };
 
+    let now = conn.now();
     let mut cur_visit_map: HashSet<(VisitTransition, Timestamp)> =
         HashSet::with_capacity(existing_visits.len());
     for visit in &existing_visits {
@@ -115,7 +120,7 @@ fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usiz
             Some(t) => t,
             None => continue,
         };
-        let date_use = clamp_visit_date(visit.visit_date);
+        let date_use = clamp_visit_date(visit.visit_date, now);
         cur_visit_map.insert((transition, date_use));
     }
     // If we already have MAX_RECORDS visits, then we will ignore incoming
@@ -137,16 +142,16 @@ fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usiz
             Some(v) => v,
             None => continue,
         };
-        let timestamp = clamp_visit_date(incoming_visit.date.into());
+        let timestamp = clamp_visit_date(incoming_visit.date.into(), now);
         if earliest_allowed > timestamp.into() {
             continue;
         }
         // If the entry isn't in our map we should add it.
         let key = (transition, timestamp);
         if !cur_visit_map.contains(&key) {
-            to_apply.push(HistoryRecordVisit {
-                date: timestamp.into(),
-                transition: transition as u8,
+            to_apply.push(SyncedVisit {
+                date: timestamp,
+                transition,
             });
             cur_visit_map.insert(key);
         }
@@ -172,6 +177,22 @@ pub fn apply_plan(
     telem: &mut telemetry::EngineIncoming,
     interruptee: &impl Interruptee,
 ) -> Result<OutgoingChangeset> {
+    apply_plan_counting_suppressed_logs(db, inbound, telem, interruptee, &mut HashMap::new())
+}
+
+/// Like `apply_plan`, but also adds the number of log messages we suppressed
+/// for invalid records to `suppressed_logs`, keyed by sampler name.
+pub(crate) fn apply_plan_counting_suppressed_logs(
+    db: &PlacesDb,
+    inbound: IncomingChangeset,
+    telem: &mut telemetry::EngineIncoming,
+    interruptee: &impl Interruptee,
+    suppressed_logs: &mut HashMap<&'static str, usize>,
+) -> Result<OutgoingChangeset> {
+    // Sampler names are keys in the telemetry event, which are limited to
+    // 15 characters.
+    let mut undeserializable_records = LogSampler::new("badRecord", module_path!(), Level::Warn);
+    let mut invalid_records = LogSampler::new("invalidRecord", module_path!(), Level::Warn);
     // for a first-cut, let's do this in the most naive way possible...
     let mut plans: Vec<(SyncGuid, IncomingPlan)> = Vec::with_capacity(inbound.changes.len());
     for incoming in inbound.changes {
@@ -181,7 +202,8 @@ pub fn apply_plan(
             Err(e) => {
                 // We can't push IncomingPlan::Invalid into plans as we don't
                 // know the guid - just skip it.
-                log::warn!("Error deserializing incoming record: {}", e);
+                undeserializable_records
+                    .log(format_args!("Error deserializing incoming record: {}", e));
                 telem.failed(1);
                 continue;
             }
@@ -197,20 +219,31 @@ pub fn apply_plan(
     let mut tx = db.begin_transaction()?;
 
     let mut outgoing = OutgoingChangeset::new("history".into(), inbound.timestamp);
+    let mut deleted_pages = 0;
     for (guid, plan) in plans {
         interruptee.err_if_interrupted()?;
-        tx.maybe_commit()?;
+        // Log the pages we've deleted so far before committing them, so
+        // that the log entry is committed along with the deletions.
+        tx.maybe_commit_with(|| {
+            record_deletion(
+                db,
+                DeletionOp::SyncDeletePages,
+                DeletionInitiator::Sync,
+                deleted_pages,
+            )?;
+            deleted_pages = 0;
+            Ok(())
+        })?;
         match &plan {
             IncomingPlan::Skip => {
                 log::trace!("incoming: skipping item {:?}", guid);
                 // XXX - should we `telem.reconciled(1);` here?
             }
             IncomingPlan::Invalid(err) => {
-                log::warn!(
+                invalid_records.log(format_args!(
                     "incoming: record {:?} skipped because it is invalid: {}",
-                    guid,
-                    err
-                );
+                    guid, err
+                ));
                 telem.failed(1);
             }
             IncomingPlan::Failed(err) => {
@@ -219,7 +252,9 @@ pub fn apply_plan(
             }
             IncomingPlan::Delete => {
                 log::trace!("incoming: deleting {:?}", guid);
-                apply_synced_deletion(&db, &guid)?;
+                if apply_synced_deletion(&db, &guid)? {
+                    deleted_pages += 1;
+                }
                 telem.applied(1);
             }
             IncomingPlan::Apply {
@@ -234,7 +269,8 @@ pub fn apply_plan(
                     new_title,
                     visits
                 );
-                apply_synced_visits(&db, &guid, &url, new_title, visits)?;
+                let new_visits = apply_synced_visits(&db, &url, &guid, new_title, visits)?;
+                log::trace!("incoming: applied {} new visits to {:?}", new_visits, guid);
                 telem.applied(1);
             }
             IncomingPlan::Reconciled => {
@@ -244,6 +280,15 @@ pub fn apply_plan(
             }
         };
     }
+    for sampler in &mut [undeserializable_records, invalid_records] {
+        *suppressed_logs.entry(sampler.name()).or_default() += sampler.finish();
+    }
+    record_deletion(
+        db,
+        DeletionOp::SyncDeletePages,
+        DeletionInitiator::Sync,
+        deleted_pages,
+    )?;
     finish_incoming(&db)?;
     tx.commit()?;
     // It might make sense for fetch_outgoing to manage its own
@@ -252,6 +297,9 @@ pub fn apply_plan(
     // really is used only for performance, so it's certainly a candidate.
     let tx = db.begin_transaction()?;
     let mut out_infos = fetch_outgoing(db, MAX_OUTGOING_PLACES, MAX_VISITS)?;
+    // If we hit the limit, there are probably more pages to upload, so ask
+    // to sync again soon.
+    outgoing.overflow = out_infos.len() >= MAX_OUTGOING_PLACES;
 
     for (guid, out_record) in out_infos.drain() {
         let payload = match out_record {
@@ -281,11 +329,16 @@ mod tests {
     use crate::api::matcher::{search_frecent, SearchParams};
     use crate::api::places_api::ConnectionType;
     use crate::db::PlacesDb;
+    use crate::history_sync::record::HistoryRecordVisit;
     use crate::history_sync::ServerVisitTimestamp;
+    use crate::log_sampler::{record_suppressed_logs, DEFAULT_LOG_LIMIT};
     use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::storage::deletion_log::get_deletion_log;
     use crate::storage::history::history_sync::fetch_visits;
     use crate::storage::history::{apply_observation, delete_place_by_guid, url_to_guid};
-    use crate::types::{SyncStatus, Timestamp};
+    use crate::testing::insert_json_tree;
+    use crate::types::{FrozenClock, SyncStatus, Timestamp};
     use interrupt::NeverInterrupts;
     use serde_json::json;
     use sql_support::ConnExt;
@@ -330,6 +383,18 @@ mod tests {
             .expect("should have got values")
     }
 
+    #[test]
+    fn test_clamp_visit_date() {
+        let now = Timestamp(1_500_000_000_000);
+        assert_eq!(clamp_visit_date(Timestamp(now.0 + 1), now), now);
+        assert_eq!(clamp_visit_date(now, now), now);
+        assert_eq!(
+            clamp_visit_date(Timestamp(now.0 - 1), now),
+            Timestamp(now.0 - 1)
+        );
+        assert_eq!(clamp_visit_date(Timestamp(1), now), EARLIEST_TIMESTAMP);
+    }
+
     #[test]
     fn test_invalid_guid() -> Result<()> {
         let _ = env_logger::try_init();
@@ -462,6 +527,67 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_future_visit_dedupe_window() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let now = Timestamp(1_500_000_000_000);
+        let clock = FrozenClock::at(now);
+        db.set_clock(clock.clone())?;
+        let guid = SyncGuid::new();
+        let url = Url::parse("https://example.com")?;
+        // A visit from a device whose clock is an hour ahead of ours.
+        let future = Timestamp(now.as_millis() + 60 * 60 * 1000);
+
+        let apply_record = || -> Result<()> {
+            let mut incoming = IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64));
+            let payload = Payload::from_json(json!({
+                "id": guid,
+                "title": "title",
+                "histUri": url.as_str(),
+                "sortindex": 0,
+                "ttl": 100,
+                "visits": [ {"date": ServerVisitTimestamp::from(future), "type": 1}]
+            }))?;
+            incoming.changes.push((payload, ServerTimestamp(0f64)));
+            apply_plan(
+                &db,
+                incoming,
+                &mut telemetry::EngineIncoming::new(),
+                &NeverInterrupts,
+            )?;
+            Ok(())
+        };
+        let visit_dates = || -> Result<Vec<Timestamp>> {
+            let (_page, visits) = fetch_visits(&db, &url, 10)?.expect("page exists");
+            let mut dates = visits.iter().map(|v| v.visit_date).collect::<Vec<_>>();
+            dates.sort();
+            Ok(dates)
+        };
+
+        // The future visit is clamped to now.
+        apply_record()?;
+        assert_eq!(visit_dates()?, vec![now]);
+
+        // Until our clock moves, the incoming visit clamps to the same time,
+        // so it's a dupe of the one we stored.
+        apply_record()?;
+        assert_eq!(visit_dates()?, vec![now]);
+
+        // Once it moves, the window expires: the incoming visit clamps to a
+        // later time, and isn't a dupe anymore.
+        let later = Timestamp(now.as_millis() + 1000);
+        clock.set(later);
+        apply_record()?;
+        assert_eq!(visit_dates()?, vec![now, later]);
+
+        // Once our clock catches up, the visit is stored at its real date.
+        clock.set(future);
+        apply_record()?;
+        assert_eq!(visit_dates()?, vec![now, later, future]);
+        Ok(())
+    }
+
     // These "dupe" tests all do the full application of the plan and checks
     // the end state of the db.
     #[test]
@@ -648,6 +774,49 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_apply_plan_reports_suppressed_logs() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut inbound = IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64));
+        let bad_records = DEFAULT_LOG_LIMIT + 5;
+        for i in 0..bad_records {
+            let json = json!({
+                "id": format!("{:A>12}", i),
+                "histUri": 123,
+                "visits": "not visits",
+            });
+            inbound
+                .changes
+                .push((Payload::from_json(json).unwrap(), ServerTimestamp(0f64)));
+        }
+
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let mut suppressed_logs = HashMap::new();
+        apply_plan_counting_suppressed_logs(
+            &db,
+            inbound,
+            &mut incoming_telemetry,
+            &NeverInterrupts,
+            &mut suppressed_logs,
+        )?;
+        assert_eq!(suppressed_logs["badRecord"], 5);
+
+        // The sampler names must be valid telemetry event keys.
+        let mut sync_ping = telemetry::SyncTelemetryPing::new();
+        record_suppressed_logs("history", suppressed_logs.drain(), &mut sync_ping);
+        let ping = serde_json::to_value(&sync_ping)?;
+        assert_eq!(
+            ping["events"],
+            json!([{
+                "object": "history",
+                "method": "logsSuppressed",
+                "extra": {"badRecord": "5"},
+            }])
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_apply_plan_incoming_invalid_timestamp() -> Result<()> {
         let _ = env_logger::try_init();
@@ -987,6 +1156,102 @@ mod tests {
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 0, "should be nothing outgoing");
+
+        let log = get_deletion_log(&db, Timestamp(0))?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].op, DeletionOp::SyncDeletePages);
+        assert_eq!(log[0].initiator, DeletionInitiator::Sync);
+        assert_eq!(log[0].count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_incoming_tombstone_bookmarked() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let url = Url::parse("https://example.com")?;
+        let obs = VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Some(SystemTime::now().into()));
+        apply_observation(&db, obs)?;
+        insert_json_tree(
+            &db,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{ "title": "example", "url": url.as_str() }],
+            }),
+        )?;
+        let guid = get_existing_guid(&db, &url);
+
+        // and an incoming tombstone for that guid
+        let json = json!({
+            "id": guid,
+            "deleted": true,
+        });
+
+        let mut incoming = IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64));
+        let payload = Payload::from_json(json).unwrap();
+        incoming.changes.push((payload, ServerTimestamp(0f64)));
+
+        let outgoing = apply_plan(
+            &db,
+            incoming,
+            &mut telemetry::EngineIncoming::new(),
+            &NeverInterrupts,
+        )?;
+        assert_eq!(outgoing.changes.len(), 0, "should be nothing outgoing");
+
+        // The page is bookmarked, so we should keep it, but remove its
+        // visits.
+        assert_eq!(get_existing_guid(&db, &url), guid);
+        let visits: i64 = db.query_one("SELECT COUNT(*) FROM moz_historyvisits")?;
+        assert_eq!(visits, 0);
+        assert_eq!(get_tombstone_count(&db), 0, "should be no tombstones");
+        Ok(())
+    }
+
+    #[test]
+    fn test_outgoing_overflow() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        db.execute_batch(&format!(
+            "WITH RECURSIVE
+             pages(i) AS (
+               SELECT 1
+               UNION ALL
+               SELECT i + 1 FROM pages WHERE i <= {max_places}
+             )
+             INSERT INTO moz_places(guid, url, url_hash)
+             SELECT generate_guid(), 'http://example.com/' || i,
+                    hash('http://example.com/' || i)
+             FROM pages;
+
+             INSERT INTO moz_historyvisits(is_local, place_id, visit_date, visit_type)
+             SELECT 1, id, now(), {link} FROM moz_places;",
+            max_places = MAX_OUTGOING_PLACES,
+            link = VisitTransition::Link as u8,
+        ))?;
+
+        // We only stage as many pages as we're allowed to upload at once,
+        // and ask to sync again soon for the rest.
+        let outgoing = apply_plan(
+            &db,
+            IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64)),
+            &mut telemetry::EngineIncoming::new(),
+            &NeverInterrupts,
+        )?;
+        assert_eq!(outgoing.changes.len(), MAX_OUTGOING_PLACES);
+        assert!(outgoing.overflow);
+        finish_plan(&db)?;
+
+        let outgoing = apply_plan(
+            &db,
+            IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64)),
+            &mut telemetry::EngineIncoming::new(),
+            &NeverInterrupts,
+        )?;
+        assert_eq!(outgoing.changes.len(), 1);
+        assert!(!outgoing.overflow);
         Ok(())
     }
 
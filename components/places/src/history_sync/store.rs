@@ -5,12 +5,17 @@
 use crate::api::places_api::{ConnectionType, GLOBAL_STATE_META_KEY};
 use crate::db::PlacesDb;
 use crate::error::*;
+use crate::log_sampler::record_suppressed_logs;
 use crate::storage::history::history_sync::reset_storage;
+use crate::storage::sync_log::{record_sync, SyncLogEntry};
 use rusqlite::types::{FromSql, ToSql};
 use rusqlite::Connection;
 use sql_support::SqlInterruptScope;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::result;
+use std::time::Duration;
 use sync15::telemetry;
 use sync15::{
     extract_v1_state, sync_multiple, CollSyncIds, CollectionRequest, IncomingChangeset, KeyBundle,
@@ -18,30 +23,39 @@ use sync15::{
     Sync15StorageClientInit,
 };
 
-use super::plan::{apply_plan, finish_plan};
+use super::plan::{apply_plan_counting_suppressed_logs, finish_plan};
 use super::MAX_INCOMING_PLACES;
 
-const LAST_SYNC_META_KEY: &str = "history_last_sync_time";
+pub(crate) const LAST_SYNC_META_KEY: &str = "history_last_sync_time";
 // Note that all engines in this crate should use a *different* meta key
 // for the global sync ID, because engines are reset individually.
-const GLOBAL_SYNCID_META_KEY: &str = "history_global_sync_id";
-const COLLECTION_SYNCID_META_KEY: &str = "history_sync_id";
+pub(crate) const GLOBAL_SYNCID_META_KEY: &str = "history_global_sync_id";
+pub(crate) const COLLECTION_SYNCID_META_KEY: &str = "history_sync_id";
+// The global state key that older versions of this crate used.
+pub(crate) const V1_GLOBAL_STATE_META_KEY: &str = "history_global_state";
 
 // A HistoryStore is short-lived and constructed each sync by something which
 // owns the connection and ClientInfo.
 pub struct HistoryStore<'a> {
     pub db: &'a PlacesDb,
     interruptee: &'a SqlInterruptScope,
+    // The number of log messages we suppressed while applying incoming
+    // records, keyed by sampler name. These are reported in the sync ping.
+    suppressed_logs: RefCell<HashMap<&'static str, usize>>,
 }
 
 impl<'a> HistoryStore<'a> {
     pub fn new(db: &'a PlacesDb, interruptee: &'a SqlInterruptScope) -> Self {
         assert_eq!(db.conn_type(), ConnectionType::Sync);
-        Self { db, interruptee }
+        Self {
+            db,
+            interruptee,
+            suppressed_logs: RefCell::default(),
+        }
     }
 
     fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
-        crate::storage::put_meta(self.db, key, value)
+        crate::storage::put_internal_meta(self.db, key, value)
     }
 
     fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
@@ -49,7 +63,7 @@ impl<'a> HistoryStore<'a> {
     }
 
     fn delete_meta(&self, key: &str) -> Result<()> {
-        crate::storage::delete_meta(self.db, key)
+        crate::storage::delete_internal_meta(self.db, key)
     }
 
     fn do_apply_incoming(
@@ -58,7 +72,13 @@ impl<'a> HistoryStore<'a> {
         incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset> {
         let timestamp = inbound.timestamp;
-        let outgoing = apply_plan(&self.db, inbound, incoming_telemetry, self.interruptee)?;
+        let outgoing = apply_plan_counting_suppressed_logs(
+            &self.db,
+            inbound,
+            incoming_telemetry,
+            self.interruptee,
+            &mut self.suppressed_logs.borrow_mut(),
+        )?;
         // write the timestamp now, so if we are interrupted creating outgoing
         // changesets we don't need to re-reconcile what we just did.
         self.put_meta(LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
@@ -108,29 +128,30 @@ impl<'a> HistoryStore<'a> {
     /// written by bookmarks before we've had a chance to migrate `declined`
     /// over.
     pub fn migrate_v1_global_state(db: &PlacesDb) -> Result<()> {
-        if let Some(old_state) = crate::storage::get_meta(db, "history_global_state")? {
+        if let Some(old_state) = crate::storage::get_meta(db, V1_GLOBAL_STATE_META_KEY)? {
             log::info!("there's old global state - migrating");
             let tx = db.begin_transaction()?;
             let (new_sync_ids, new_global_state) = extract_v1_state(old_state, "history");
             if let Some(sync_ids) = new_sync_ids {
-                crate::storage::put_meta(db, GLOBAL_SYNCID_META_KEY, &sync_ids.global)?;
-                crate::storage::put_meta(db, COLLECTION_SYNCID_META_KEY, &sync_ids.coll)?;
+                crate::storage::put_internal_meta(db, GLOBAL_SYNCID_META_KEY, &sync_ids.global)?;
+                crate::storage::put_internal_meta(db, COLLECTION_SYNCID_META_KEY, &sync_ids.coll)?;
                 log::info!("migrated the sync IDs");
             }
             if let Some(new_global_state) = new_global_state {
                 // The global state is truly global, but both "history" and "places"
                 // are going to write it - which is why it's important this
                 // function is run before bookmarks is synced.
-                crate::storage::put_meta(db, GLOBAL_STATE_META_KEY, &new_global_state)?;
+                crate::storage::put_internal_meta(db, GLOBAL_STATE_META_KEY, &new_global_state)?;
                 log::info!("migrated the global state");
             }
-            crate::storage::delete_meta(db, "history_global_state")?;
+            crate::storage::delete_internal_meta(db, V1_GLOBAL_STATE_META_KEY)?;
             tx.commit()?;
         }
         Ok(())
     }
 
-    /// A convenience wrapper around sync_multiple.
+    /// A convenience wrapper around sync_multiple. Returns the recommended
+    /// time to wait before the next sync, if any.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
@@ -138,7 +159,7 @@ impl<'a> HistoryStore<'a> {
         mem_cached_state: &mut MemoryCachedState,
         disk_cached_state: &mut Option<String>,
         sync_ping: &mut telemetry::SyncTelemetryPing,
-    ) -> Result<()> {
+    ) -> Result<Option<Duration>> {
         let result = sync_multiple(
             &[self],
             disk_cached_state,
@@ -148,15 +169,18 @@ impl<'a> HistoryStore<'a> {
             sync_ping,
             self.interruptee,
         );
-        let failures = result?;
-        if failures.is_empty() {
-            Ok(())
-        } else {
-            assert_eq!(failures.len(), 1);
-            let (name, err) = failures.into_iter().next().unwrap();
-            assert_eq!(name, "history");
-            Err(err.into())
+        // Record the attempt even if the sync failed, so that we can see
+        // why later.
+        let entry = SyncLogEntry::new(self.db.now(), &result);
+        if let Err(e) = record_sync(self.db, entry) {
+            log::warn!("Failed to record sync in the sync log: {}", e);
         }
+        record_suppressed_logs(
+            "history",
+            self.suppressed_logs.borrow_mut().drain(),
+            sync_ping,
+        );
+        Ok(result?.into_single_store_result("history")?)
     }
 }
 
@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers shared by our importers.
+
+use crate::api::places_api::ConnectionType;
+use crate::db::{PlacesDb, PlacesTransaction};
+use crate::error::*;
+use crate::storage::{parse_href, URL_LENGTH_MAX};
+use crate::types::Timestamp;
+use serde_derive::*;
+use std::cmp::min;
+use std::path::Path;
+use url::Url;
+
+/// The number of rows we imported from a table in the source database, and
+/// the number we skipped because they were invalid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportCounts {
+    pub imported: u32,
+    pub skipped_invalid: u32,
+}
+
+/// Attaches the database at `path` to `db`, read-only, as `alias`. The
+/// database is detached when the returned guard is dropped. SQLite doesn't
+/// allow attaching or detaching inside a transaction, so the guard must
+/// outlive the import transaction.
+pub(super) fn attach_read_only<'a>(
+    db: &'a PlacesDb,
+    path: &Path,
+    alias: &'static str,
+) -> Result<AttachedDatabase<'a>> {
+    let path = std::fs::canonicalize(path)?;
+    let mut url =
+        Url::from_file_path(&path).map_err(|_| ErrorKind::IllegalDatabasePath(path.clone()))?;
+    url.set_query(Some("mode=ro"));
+    db.execute_named(
+        &format!("ATTACH DATABASE :url AS {}", alias),
+        &[(":url", &url.as_str())],
+    )?;
+    Ok(AttachedDatabase { db, alias })
+}
+
+pub(super) struct AttachedDatabase<'a> {
+    db: &'a PlacesDb,
+    alias: &'static str,
+}
+
+impl<'a> Drop for AttachedDatabase<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .db
+            .execute_batch(&format!("DETACH DATABASE {}", self.alias))
+        {
+            log::warn!("Error detaching imported database: {}", e);
+        }
+    }
+}
+
+/// Begins a transaction for an import. Imports can write many thousands of
+/// rows, so they must run on the sync connection, which commits in chunks
+/// instead of blocking the main writer for the whole import. Importers
+/// should call `maybe_commit` after each item. Fails with
+/// `InvalidConnectionType` on any other connection.
+pub(super) fn begin_import_transaction(db: &PlacesDb) -> Result<PlacesTransaction<'_>> {
+    if db.conn_type() != ConnectionType::Sync {
+        return Err(ErrorKind::InvalidConnectionType.into());
+    }
+    db.begin_transaction()
+}
+
+/// Parses a URL from the source database. Returns `None` for URLs that we
+/// can't store, which importers should count as invalid.
+pub(super) fn validate_url(href: &str) -> Option<Url> {
+    if href.len() > URL_LENGTH_MAX {
+        return None;
+    }
+    parse_href(href).ok()
+}
+
+/// Converts a timestamp in milliseconds from the source database to one
+/// that's safe to store. Returns `None` for timestamps at or before the
+/// epoch, and clamps timestamps after `now` to `now`.
+pub(super) fn sanitize_timestamp(millis: i64, now: Timestamp) -> Option<Timestamp> {
+    if millis <= 0 {
+        return None;
+    }
+    Some(Timestamp(min(millis as u64, now.as_millis())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("https://example.com").is_some());
+        assert!(validate_url("not a url").is_none());
+        assert!(validate_url(&format!(
+            "https://example.com/{}",
+            "a".repeat(URL_LENGTH_MAX)
+        ))
+        .is_none());
+    }
+
+    #[test]
+    fn test_sanitize_timestamp() {
+        let now = Timestamp(1_550_000_000_000);
+        assert_eq!(sanitize_timestamp(0, now), None);
+        assert_eq!(sanitize_timestamp(-1, now), None);
+        assert_eq!(
+            sanitize_timestamp(1_500_000_000_000, now),
+            Some(Timestamp(1_500_000_000_000))
+        );
+        assert_eq!(sanitize_timestamp(1_600_000_000_000, now), Some(now));
+    }
+}
@@ -0,0 +1,654 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Imports bookmarks and history from Firefox for iOS's `browser.db`.
+//!
+//! iOS stores bookmarks in three tables: `bookmarksMirror` holds the tree as
+//! of the last sync, `bookmarksLocal` holds local changes on top of it, and
+//! `bookmarksBuffer` holds incoming records that haven't been merged yet.
+//! Each has a matching structure table that lists the children of each
+//! folder. The user's current tree is the mirror overlaid with local
+//! changes, so we prefer local rows where both exist, and ignore the buffer.
+
+use super::common::*;
+use crate::db::{PlacesDb, PlacesTransaction};
+use crate::error::*;
+use crate::observation::{ObservationSource, VisitObservation};
+use crate::storage::bookmarks::{
+    get_raw_bookmark, insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+    InsertableFolder, InsertableItem, InsertableSeparator,
+};
+use crate::storage::history::apply_observation;
+use crate::types::{SyncGuid, Timestamp, VisitTransition};
+use crate::valid_guid::is_valid_places_guid;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+const IOS_ALIAS: &str = "ios";
+
+// iOS item types. Bookmarks, folders, and separators match ours; queries
+// are bookmarks with `place:` URLs. We don't support livemarks or dynamic
+// containers, which iOS never creates itself.
+const IOS_TYPE_BOOKMARK: u8 = 1;
+const IOS_TYPE_FOLDER: u8 = 2;
+const IOS_TYPE_SEPARATOR: u8 = 3;
+const IOS_TYPE_QUERY: u8 = 6;
+
+// The number of pages whose visits we load at a time. iOS doesn't limit
+// history, so we can't load every visit at once.
+const HISTORY_CHUNK_SIZE: u32 = 500;
+
+/// The number of rows imported from, and skipped in, each table.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IosImportSummary {
+    pub bookmarks_local: ImportCounts,
+    pub bookmarks_mirror: ImportCounts,
+    /// Pages without any visits aren't imported, or counted, since we only
+    /// store pages that are visited or bookmarked.
+    pub history: ImportCounts,
+    pub visits: ImportCounts,
+}
+
+/// Imports bookmarks and history from the iOS database at `path`. Bookmarks
+/// are appended to the matching local roots. This must be called on the
+/// sync connection.
+pub fn import_ios(db: &PlacesDb, path: impl AsRef<Path>) -> Result<IosImportSummary> {
+    let _attached = attach_read_only(db, path.as_ref(), IOS_ALIAS)?;
+    let mut tx = begin_import_transaction(db)?;
+    let mut summary = IosImportSummary::default();
+    BookmarksImporter::new(db)?.import(&mut tx, &mut summary)?;
+    import_history(db, &mut tx, &mut summary, HISTORY_CHUNK_SIZE)?;
+    tx.commit()?;
+    Ok(summary)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Local,
+    Mirror,
+}
+
+#[derive(Debug)]
+struct IosItem {
+    kind: u8,
+    url: Option<String>,
+    title: Option<String>,
+    date_added: Option<i64>,
+    source: Source,
+}
+
+struct BookmarksImporter<'a> {
+    db: &'a PlacesDb,
+    items: HashMap<String, IosItem>,
+    local_children: HashMap<String, Vec<String>>,
+    mirror_children: HashMap<String, Vec<String>>,
+    // Structure tables can be inconsistent, so we make sure to import each
+    // item at most once, and don't loop forever on cycles.
+    seen: HashSet<String>,
+}
+
+impl<'a> BookmarksImporter<'a> {
+    fn new(db: &'a PlacesDb) -> Result<Self> {
+        let mut items = HashMap::new();
+        for (guid, item, is_deleted) in fetch_items(db, "bookmarksMirror", Source::Mirror)? {
+            if !is_deleted {
+                items.insert(guid, item);
+            }
+        }
+        // Local rows, including deletions, take precedence over the mirror.
+        for (guid, item, is_deleted) in fetch_items(db, "bookmarksLocal", Source::Local)? {
+            if is_deleted {
+                items.remove(&guid);
+            } else {
+                items.insert(guid, item);
+            }
+        }
+        Ok(Self {
+            db,
+            items,
+            local_children: fetch_children(db, "bookmarksLocalStructure")?,
+            mirror_children: fetch_children(db, "bookmarksMirrorStructure")?,
+            seen: HashSet::new(),
+        })
+    }
+
+    fn import(
+        &mut self,
+        tx: &mut PlacesTransaction<'_>,
+        summary: &mut IosImportSummary,
+    ) -> Result<()> {
+        for &root in &[
+            BookmarkRootGuid::Menu,
+            BookmarkRootGuid::Toolbar,
+            BookmarkRootGuid::Unfiled,
+            BookmarkRootGuid::Mobile,
+        ] {
+            // iOS uses the same GUIDs for its roots as we do, but structure
+            // rows copied from Sync records may use the record IDs instead.
+            for ios_guid in &[root.as_str(), root_record_id(root)] {
+                self.import_children(ios_guid, &root.as_guid(), tx, summary)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn children(&self, parent_guid: &str) -> Vec<String> {
+        let use_local = match self.items.get(parent_guid) {
+            Some(parent) => parent.source == Source::Local,
+            // Roots might not have rows, so use whichever structure we have.
+            None => self.local_children.contains_key(parent_guid),
+        };
+        let children = if use_local {
+            &self.local_children
+        } else {
+            &self.mirror_children
+        };
+        children.get(parent_guid).cloned().unwrap_or_default()
+    }
+
+    fn import_children(
+        &mut self,
+        ios_parent_guid: &str,
+        parent_guid: &SyncGuid,
+        tx: &mut PlacesTransaction<'_>,
+        summary: &mut IosImportSummary,
+    ) -> Result<()> {
+        for ios_guid in self.children(ios_parent_guid) {
+            if !self.seen.insert(ios_guid.clone()) {
+                continue;
+            }
+            // Children of deleted folders, and children without rows, are
+            // skipped.
+            let item = match self.items.get(&ios_guid) {
+                Some(item) => item,
+                None => continue,
+            };
+            let counts = match item.source {
+                Source::Local => &mut summary.bookmarks_local,
+                Source::Mirror => &mut summary.bookmarks_mirror,
+            };
+            let guid = self.guid_for_insert(&ios_guid)?;
+            let date_added = item
+                .date_added
+                .and_then(|millis| sanitize_timestamp(millis, self.db.now()));
+            let insertable: InsertableItem = match item.kind {
+                IOS_TYPE_BOOKMARK | IOS_TYPE_QUERY => {
+                    match item.url.as_ref().and_then(|href| validate_url(href)) {
+                        Some(url) => InsertableBookmark {
+                            parent_guid: parent_guid.clone(),
+                            position: BookmarkPosition::Append,
+                            date_added,
+                            last_modified: date_added,
+                            guid,
+                            url,
+                            title: item.title.clone(),
+                            page_title: None,
+                        }
+                        .into(),
+                        None => {
+                            counts.skipped_invalid += 1;
+                            continue;
+                        }
+                    }
+                }
+                IOS_TYPE_FOLDER => InsertableFolder {
+                    parent_guid: parent_guid.clone(),
+                    position: BookmarkPosition::Append,
+                    date_added,
+                    last_modified: date_added,
+                    guid,
+                    title: item.title.clone(),
+                }
+                .into(),
+                IOS_TYPE_SEPARATOR => InsertableSeparator {
+                    parent_guid: parent_guid.clone(),
+                    position: BookmarkPosition::Append,
+                    date_added,
+                    last_modified: date_added,
+                    guid,
+                }
+                .into(),
+                _ => {
+                    counts.skipped_invalid += 1;
+                    continue;
+                }
+            };
+            let inserted = insert_bookmark(self.db, &insertable)?;
+            counts.imported += 1;
+            tx.maybe_commit()?;
+            if let InsertableItem::Folder(_) = insertable {
+                self.import_children(&ios_guid, &inserted.guid, tx, summary)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the GUID to use for an imported item, or `None` to generate
+    /// a new one if the iOS GUID is invalid or already taken.
+    fn guid_for_insert(&self, ios_guid: &str) -> Result<Option<SyncGuid>> {
+        if !is_valid_places_guid(ios_guid) {
+            return Ok(None);
+        }
+        let guid = SyncGuid(ios_guid.into());
+        Ok(match get_raw_bookmark(self.db, &guid)? {
+            Some(_) => None,
+            None => Some(guid),
+        })
+    }
+}
+
+fn root_record_id(root: BookmarkRootGuid) -> &'static str {
+    match root {
+        BookmarkRootGuid::Root => "places",
+        BookmarkRootGuid::Menu => "menu",
+        BookmarkRootGuid::Toolbar => "toolbar",
+        BookmarkRootGuid::Unfiled => "unfiled",
+        BookmarkRootGuid::Mobile => "mobile",
+    }
+}
+
+fn fetch_items(db: &PlacesDb, table: &str, source: Source) -> Result<Vec<(String, IosItem, bool)>> {
+    db.query_rows_and_then_named(
+        &format!(
+            "SELECT guid, type, bmkUri, title, date_added, is_deleted
+             FROM {alias}.{table}",
+            alias = IOS_ALIAS,
+            table = table
+        ),
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get("guid")?,
+                IosItem {
+                    kind: row.get::<_, Option<u8>>("type")?.unwrap_or_default(),
+                    url: row.get("bmkUri")?,
+                    title: row.get("title")?,
+                    date_added: row.get("date_added")?,
+                    source,
+                },
+                row.get::<_, Option<bool>>("is_deleted")?
+                    .unwrap_or_default(),
+            ))
+        },
+    )
+}
+
+fn fetch_children(db: &PlacesDb, table: &str) -> Result<HashMap<String, Vec<String>>> {
+    let rows = db.query_rows_and_then_named(
+        &format!(
+            "SELECT parent, child FROM {alias}.{table}
+             ORDER BY parent, idx",
+            alias = IOS_ALIAS,
+            table = table
+        ),
+        &[],
+        |row| -> Result<(String, String)> { Ok((row.get(0)?, row.get(1)?)) },
+    )?;
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    for (parent, child) in rows {
+        children.entry(parent).or_default().push(child);
+    }
+    Ok(children)
+}
+
+struct IosVisit {
+    site_id: i64,
+    url: String,
+    title: Option<String>,
+    // iOS stores visit dates in microseconds.
+    date_micros: i64,
+    visit_type: Option<u8>,
+    is_local: bool,
+}
+
+/// Fetches the IDs of the next `limit` pages after `after_site_id`.
+fn fetch_site_ids(db: &PlacesDb, after_site_id: i64, limit: u32) -> Result<Vec<i64>> {
+    db.query_rows_and_then_named(
+        &format!(
+            "SELECT id FROM {alias}.history
+             WHERE id > :after_site_id AND NOT is_deleted
+             ORDER BY id
+             LIMIT :limit",
+            alias = IOS_ALIAS
+        ),
+        &[(":after_site_id", &after_site_id), (":limit", &limit)],
+        |row| -> Result<i64> { Ok(row.get(0)?) },
+    )
+}
+
+/// Fetches the visits for pages with IDs between `first_site_id` and
+/// `last_site_id`, inclusive.
+fn fetch_visits(db: &PlacesDb, first_site_id: i64, last_site_id: i64) -> Result<Vec<IosVisit>> {
+    db.query_rows_and_then_named(
+        &format!(
+            "SELECT h.id, h.url, h.title, CAST(v.date AS INTEGER), v.type, v.is_local
+             FROM {alias}.visits v
+             JOIN {alias}.history h ON h.id = v.siteID
+             WHERE NOT h.is_deleted AND
+                   h.id BETWEEN :first_site_id AND :last_site_id
+             ORDER BY h.id, v.date",
+            alias = IOS_ALIAS
+        ),
+        &[
+            (":first_site_id", &first_site_id),
+            (":last_site_id", &last_site_id),
+        ],
+        |row| -> Result<_> {
+            Ok(IosVisit {
+                site_id: row.get(0)?,
+                url: row.get(1)?,
+                title: row.get(2)?,
+                date_micros: row.get(3)?,
+                visit_type: row.get(4)?,
+                is_local: row.get(5)?,
+            })
+        },
+    )
+}
+
+/// Imports visits for `chunk_size` pages at a time.
+fn import_history(
+    db: &PlacesDb,
+    tx: &mut PlacesTransaction<'_>,
+    summary: &mut IosImportSummary,
+    chunk_size: u32,
+) -> Result<()> {
+    let mut last_site_id = 0;
+    loop {
+        let site_ids = fetch_site_ids(db, last_site_id, chunk_size)?;
+        let (first, last) = match (site_ids.first(), site_ids.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => break,
+        };
+        import_visits(db, tx, summary, fetch_visits(db, first, last)?)?;
+        last_site_id = last;
+    }
+    Ok(())
+}
+
+fn import_visits(
+    db: &PlacesDb,
+    tx: &mut PlacesTransaction<'_>,
+    summary: &mut IosImportSummary,
+    visits: Vec<IosVisit>,
+) -> Result<()> {
+    // Visits are sorted by page, so we validate each page's URL once, and
+    // only set its title on its first visit. Chunks never split a page's
+    // visits.
+    let mut current_site_id = None;
+    let mut current_url = None;
+    let mut current_has_visits = false;
+    for visit in visits {
+        if current_site_id != Some(visit.site_id) {
+            current_site_id = Some(visit.site_id);
+            current_has_visits = false;
+            current_url = validate_url(&visit.url);
+            if current_url.is_none() {
+                summary.history.skipped_invalid += 1;
+            }
+        }
+        let url = match &current_url {
+            Some(url) => url.clone(),
+            None => {
+                summary.visits.skipped_invalid += 1;
+                continue;
+            }
+        };
+        let at = match sanitize_timestamp(visit.date_micros / 1000, db.now()) {
+            Some(at) => at,
+            None => {
+                summary.visits.skipped_invalid += 1;
+                continue;
+            }
+        };
+        let visit_type = visit
+            .visit_type
+            .and_then(VisitTransition::from_primitive)
+            .unwrap_or(VisitTransition::Link);
+        let mut obs = VisitObservation::new(url)
+            .with_at(at)
+            .with_visit_type(visit_type)
+            .with_source(if visit.is_local {
+                ObservationSource::Local
+            } else {
+                ObservationSource::Sync
+            });
+        if !current_has_visits {
+            obs = obs.with_title(visit.title.filter(|title| !title.is_empty()));
+            summary.history.imported += 1;
+            current_has_visits = true;
+        }
+        apply_observation(db, obs)?;
+        summary.visits.imported += 1;
+        tx.maybe_commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::{ConnectionType, PlacesApi};
+    use crate::storage::bookmarks::{fetch_tree, BookmarkTreeNode};
+    use crate::testing::{assert_json_tree, new_mem_api};
+    use crate::types::{BookmarkType, FrozenClock};
+    use pretty_assertions::assert_eq;
+    use rusqlite::Connection;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn create_ios_db() -> (TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("browser.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(include_str!("../../fixtures/ios_browser.sql"))
+            .unwrap();
+        (dir, path)
+    }
+
+    fn new_api_with_local_bookmark() -> Arc<PlacesApi> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite).unwrap();
+        // Take one of the GUIDs from the iOS database, so that we need to
+        // generate a new one for it.
+        insert_bookmark(
+            &writer,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Toolbar.as_guid(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some("bookmarkTAKN".into()),
+                url: "https://existing.example.com".parse().unwrap(),
+                title: Some("Existing".into()),
+                page_title: None,
+            }
+            .into(),
+        )
+        .unwrap();
+        api
+    }
+
+    #[test]
+    fn test_import_ios() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_dir, path) = create_ios_db();
+        let api = new_api_with_local_bookmark();
+        let syncer = api.open_sync_connection()?;
+        syncer.set_clock(FrozenClock::at(Timestamp(1_560_000_000_000)))?;
+
+        let summary = import_ios(&syncer, &path)?;
+        assert_eq!(
+            summary,
+            IosImportSummary {
+                // The renamed folder, and the new bookmark.
+                bookmarks_local: ImportCounts {
+                    imported: 2,
+                    skipped_invalid: 0,
+                },
+                // The unchanged bookmark, separator, query, bookmark with
+                // the taken GUID, and mobile bookmark; then, the bookmark
+                // with the invalid URL, and the livemark.
+                bookmarks_mirror: ImportCounts {
+                    imported: 5,
+                    skipped_invalid: 2,
+                },
+                // The page with a malformed URL, and its visit. The deleted
+                // page isn't counted.
+                history: ImportCounts {
+                    imported: 2,
+                    skipped_invalid: 1,
+                },
+                visits: ImportCounts {
+                    imported: 3,
+                    skipped_invalid: 2,
+                },
+            }
+        );
+
+        assert_json_tree(
+            &syncer,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "folderAAAAAA",
+                        "title": "Renamed locally",
+                        "children": [
+                            {
+                                "guid": "bookmarkBBBB",
+                                "title": "B",
+                                "url": "https://b.example.com/",
+                            },
+                            {
+                                "guid": "bookmarkNEWW",
+                                "title": "New",
+                                "url": "https://new.example.com/",
+                            },
+                        ],
+                    },
+                    {
+                        "guid": "separatorDDD",
+                        "type": BookmarkType::Separator as u8,
+                    },
+                    {
+                        "guid": "queryEEEEEEE",
+                        "title": "Tagged",
+                        "url": "place:tag=foo",
+                    },
+                ],
+            }),
+        );
+
+        let toolbar = fetch_tree(&syncer, &BookmarkRootGuid::Toolbar.as_guid())?
+            .expect("Should fetch toolbar");
+        let toolbar_children = match toolbar {
+            BookmarkTreeNode::Folder(f) => f.children,
+            _ => panic!("Toolbar should be a folder"),
+        };
+        assert_eq!(toolbar_children.len(), 2);
+        let imported = match &toolbar_children[1] {
+            BookmarkTreeNode::Bookmark(b) => b,
+            _ => panic!("Should import the bookmark with the taken GUID"),
+        };
+        assert_ne!(imported.guid, Some("bookmarkTAKN".into()));
+        assert_eq!(imported.url.as_str(), "https://taken.example.com/");
+
+        assert_json_tree(
+            &syncer,
+            &BookmarkRootGuid::Mobile.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                "children": [{
+                    "guid": "bookmarkMMMM",
+                    "title": "Mobile",
+                    "url": "https://mobile.example.com/",
+                }],
+            }),
+        );
+
+        let visits = syncer.query_rows_and_then_named(
+            "SELECT h.url, v.visit_date, v.is_local FROM moz_historyvisits v
+             JOIN moz_places h ON h.id = v.place_id
+             ORDER BY v.visit_date",
+            &[],
+            |row| -> Result<(String, i64, bool)> { Ok((row.get(0)?, row.get(1)?, row.get(2)?)) },
+        )?;
+        assert_eq!(
+            visits,
+            vec![
+                (
+                    "https://a.example.com/".to_string(),
+                    1_550_000_000_000,
+                    true
+                ),
+                (
+                    "https://a.example.com/".to_string(),
+                    1_550_000_100_000,
+                    false
+                ),
+                // Clamped to now.
+                (
+                    "https://b.example.com/".to_string(),
+                    1_560_000_000_000,
+                    true
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_ios_wrong_connection() -> Result<()> {
+        let (_dir, path) = create_ios_db();
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let err = import_ios(&writer, &path).expect_err("Should refuse to import");
+        match err.kind() {
+            ErrorKind::InvalidConnectionType => {}
+            kind => panic!("Wrong error kind: {:?}", kind),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_history_chunked() -> Result<()> {
+        let (_dir, path) = create_ios_db();
+        let api = new_mem_api();
+        let syncer = api.open_sync_connection()?;
+        syncer.set_clock(FrozenClock::at(Timestamp(1_560_000_000_000)))?;
+
+        let _attached = attach_read_only(&syncer, &path, IOS_ALIAS)?;
+        let mut tx = begin_import_transaction(&syncer)?;
+        let mut summary = IosImportSummary::default();
+        // One page per chunk should import the same visits as one chunk.
+        import_history(&syncer, &mut tx, &mut summary, 1)?;
+        tx.commit()?;
+
+        assert_eq!(
+            summary.history,
+            ImportCounts {
+                imported: 2,
+                skipped_invalid: 1,
+            }
+        );
+        assert_eq!(
+            summary.visits,
+            ImportCounts {
+                imported: 3,
+                skipped_invalid: 2,
+            }
+        );
+        assert_eq!(
+            syncer.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?,
+            3
+        );
+        Ok(())
+    }
+}
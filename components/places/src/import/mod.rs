@@ -2,5 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-mod synced_item;
-pub(crate) use synced_item::SyncedBookmarkItem;
+//! Importers for bookmarks and history from other browsers' databases.
+
+pub mod common;
+pub mod ios;
+
+pub use common::ImportCounts;
+pub use ios::{import_ios, IosImportSummary};
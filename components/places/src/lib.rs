@@ -15,12 +15,15 @@ pub mod ffi;
 pub mod frecency;
 pub mod hash;
 pub mod history_sync;
+pub mod import;
+mod log_sampler;
 // match_impl is pub mostly for benchmarks (which have to run as a separate pseudo-crate).
 pub mod match_impl;
+pub mod metrics;
 pub mod observation;
 pub mod storage;
-#[cfg(test)]
-mod tests;
+#[cfg(any(test, feature = "testutils"))]
+pub mod testing;
 mod util;
 mod valid_guid;
 
@@ -29,13 +32,15 @@ pub mod msg_types {
 }
 
 pub use crate::api::apply_observation;
-#[cfg(test)]
-pub use crate::api::places_api::test;
-pub use crate::api::places_api::{ConnectionType, PlacesApi};
+pub use crate::api::matcher::{search_frecent, MatchReason, SearchParams, SearchResult};
+pub use crate::api::places_api::{ConnectionType, PlacesApi, SyncOutcome};
 
-pub use crate::db::PlacesDb;
+pub use crate::db::{BusyRetryPolicy, PlacesDb, SqliteFeatures};
 pub use crate::error::*;
-pub use crate::observation::VisitObservation;
+pub use crate::observation::{ObservationSource, VisitObservation};
+pub use crate::storage::bookmarks::{
+    BookmarkNode, BookmarkRootGuid, BookmarkTreeNode, FolderNode, SeparatorNode,
+};
 pub use crate::storage::PageInfo;
 pub use crate::storage::RowId;
 pub use crate::types::*;
@@ -0,0 +1,198 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A rate-limited logger for hot paths, like applying incoming records. A
+//! corrupt collection can have tens of thousands of invalid records, and
+//! logging a line for each floods logcat and takes real time. Instead, we
+//! log the first few messages, count the rest, and log a summary at the end
+//! of the sync.
+
+use log::{Level, Log, Metadata, Record};
+use std::fmt;
+use sync15::telemetry;
+
+/// The default number of messages to log before suppressing the rest.
+pub(crate) const DEFAULT_LOG_LIMIT: usize = 10;
+
+pub(crate) struct LogSampler {
+    /// The key for this sampler's suppressed count in the sync ping.
+    name: &'static str,
+    /// The log target, usually the `module_path!()` of the caller.
+    target: &'static str,
+    level: Level,
+    limit: usize,
+    seen: usize,
+}
+
+impl LogSampler {
+    pub fn new(name: &'static str, target: &'static str, level: Level) -> Self {
+        Self::with_limit(name, target, level, DEFAULT_LOG_LIMIT)
+    }
+
+    pub fn with_limit(
+        name: &'static str,
+        target: &'static str,
+        level: Level,
+        limit: usize,
+    ) -> Self {
+        Self {
+            name,
+            target,
+            level,
+            limit,
+            seen: 0,
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the number of messages we didn't log.
+    #[inline]
+    pub fn suppressed(&self) -> usize {
+        self.seen.saturating_sub(self.limit)
+    }
+
+    /// Logs a message if we haven't hit the limit yet, and counts it either
+    /// way.
+    pub fn log(&mut self, args: fmt::Arguments<'_>) {
+        self.log_to(log::logger(), args)
+    }
+
+    /// Logs a summary of the suppressed messages, if there were any, and
+    /// resets the sampler. Returns the number of suppressed messages.
+    pub fn finish(&mut self) -> usize {
+        self.finish_to(log::logger())
+    }
+
+    fn log_to(&mut self, logger: &dyn Log, args: fmt::Arguments<'_>) {
+        self.seen += 1;
+        if self.seen <= self.limit {
+            self.emit(logger, args);
+        }
+    }
+
+    fn finish_to(&mut self, logger: &dyn Log) -> usize {
+        let suppressed = self.suppressed();
+        if suppressed > 0 {
+            self.emit(
+                logger,
+                format_args!(
+                    "Suppressed {} more {} messages ({} total)",
+                    suppressed, self.name, self.seen
+                ),
+            );
+        }
+        self.seen = 0;
+        suppressed
+    }
+
+    fn emit(&self, logger: &dyn Log, args: fmt::Arguments<'_>) {
+        let metadata = Metadata::builder()
+            .level(self.level)
+            .target(self.target)
+            .build();
+        if logger.enabled(&metadata) {
+            logger.log(
+                &Record::builder()
+                    .metadata(metadata)
+                    .module_path(Some(self.target))
+                    .args(args)
+                    .build(),
+            );
+        }
+    }
+}
+
+/// Records suppressed message counts in the sync ping, as a single event
+/// with one extra key per sampler that suppressed anything.
+pub(crate) fn record_suppressed_logs(
+    object: &'static str,
+    counts: impl IntoIterator<Item = (&'static str, usize)>,
+    sync_ping: &mut telemetry::SyncTelemetryPing,
+) {
+    let mut event = None;
+    for (name, count) in counts {
+        if count > 0 {
+            event = Some(
+                event
+                    .unwrap_or_else(|| telemetry::Event::new(object, "logsSuppressed"))
+                    .extra(name, count.to_string()),
+            );
+        }
+    }
+    if let Some(event) = event {
+        sync_ping.event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct CapturingLogger(Mutex<Vec<(Level, String, String)>>);
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            self.0.lock().unwrap().push((
+                record.level(),
+                record.target().to_string(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_log_sampler() {
+        let logger = CapturingLogger::default();
+        let mut sampler = LogSampler::with_limit("things", module_path!(), Level::Warn, 3);
+        for i in 0..10 {
+            sampler.log_to(&logger, format_args!("thing {}", i));
+        }
+        assert_eq!(sampler.suppressed(), 7);
+        assert_eq!(sampler.finish_to(&logger), 7);
+
+        let messages = logger.0.lock().unwrap();
+        assert_eq!(
+            messages
+                .iter()
+                .map(|(_, _, message)| message.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "thing 0",
+                "thing 1",
+                "thing 2",
+                "Suppressed 7 more things messages (10 total)",
+            ]
+        );
+        assert!(messages
+            .iter()
+            .all(|(level, target, _)| *level == Level::Warn
+                && target == "places::log_sampler::tests"));
+
+        // Finishing resets the sampler.
+        assert_eq!(sampler.suppressed(), 0);
+    }
+
+    #[test]
+    fn test_log_sampler_under_limit() {
+        let logger = CapturingLogger::default();
+        let mut sampler = LogSampler::with_limit("things", module_path!(), Level::Warn, 3);
+        for i in 0..3 {
+            sampler.log_to(&logger, format_args!("thing {}", i));
+        }
+        assert_eq!(sampler.finish_to(&logger), 0);
+        assert_eq!(logger.0.lock().unwrap().len(), 3, "Shouldn't log a summary");
+    }
+}
@@ -0,0 +1,390 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Collects a snapshot of the size and shape of the user's history and
+//! bookmarks, for product telemetry. Unlike the sync ping, these metrics
+//! aren't tied to a sync; they're meant to be collected periodically, like
+//! once a day, on a background thread.
+
+use crate::db::PlacesDb;
+use crate::error::Result;
+use crate::storage::bookmarks::count_guid_case_collisions;
+use crate::storage::oversized::{url_too_long_fragment, OVERSIZED_ROWS_FIXED_META_KEY};
+use crate::types::{BookmarkType, Timestamp};
+use rusqlite::NO_PARAMS;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::HashMap;
+
+/// Visits newer than this are counted as recent.
+const RECENT_VISIT_WINDOW_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// The maximum number of statements `collect` runs, no matter how large the
+/// database is. Counting pages and visits only reads the smallest index on
+/// each table, and recent visits use the visit date index. The only tables
+/// we scan are `moz_bookmarks`, which is orders of magnitude smaller than
+/// history, and the pragma tables.
+pub const MAX_METRICS_QUERIES: usize = 4;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlacesMetrics {
+    pub page_count: u64,
+    pub visit_count: u64,
+    /// The number of visits in the last 7 days.
+    pub recent_visit_count: u64,
+    pub bookmark_count: u64,
+    /// `folder_depths[n]` is the number of folders `n + 1` levels below the
+    /// Places root, so `folder_depths[0]` counts the built-in roots, and
+    /// `folder_depths[1]` counts the user's top-level folders. The Places
+    /// root itself isn't counted.
+    pub folder_depths: Vec<u64>,
+    pub db_size_bytes: u64,
+    /// The number of bookmarks whose GUIDs only differ by case from another
+    /// bookmark's, as reported by `run_maintenance`.
+    pub guid_case_collisions: u64,
+    /// The number of bookmarks with URLs too long to sync.
+    pub quarantined_bookmarks: u64,
+    /// The total number of oversized titles that `run_maintenance` has
+    /// truncated.
+    pub oversized_rows_fixed: u64,
+}
+
+/// Collects metrics for the database. This doesn't write anything, so it's
+/// safe to call on a read-only connection.
+pub fn collect(db: &PlacesDb) -> Result<PlacesMetrics> {
+    let mut metrics = PlacesMetrics::default();
+
+    let since = db.now().as_millis().saturating_sub(RECENT_VISIT_WINDOW_MS);
+    db.query_row_named_with_context(
+        &format!(
+            "SELECT (SELECT COUNT(*) FROM moz_places),
+                    (SELECT COUNT(*) FROM moz_historyvisits),
+                    (SELECT COUNT(*) FROM moz_historyvisits
+                     WHERE visit_date >= :since),
+                    (SELECT COUNT(*) FROM moz_bookmarks
+                     WHERE type = :bookmark_type),
+                    (SELECT COUNT(*) FROM moz_bookmarks b
+                     JOIN moz_places h ON h.id = b.fk
+                     WHERE {url_too_long}),
+                    IFNULL((SELECT value FROM moz_meta
+                            WHERE key = :oversized_key), 0)",
+            url_too_long = url_too_long_fragment("h.url"),
+        ),
+        &[
+            (":since", &Timestamp(since)),
+            (":bookmark_type", &BookmarkType::Bookmark),
+            (":oversized_key", &OVERSIZED_ROWS_FIXED_META_KEY),
+        ],
+        |row| {
+            metrics.page_count = row.get::<_, i64>(0)? as u64;
+            metrics.visit_count = row.get::<_, i64>(1)? as u64;
+            metrics.recent_visit_count = row.get::<_, i64>(2)? as u64;
+            metrics.bookmark_count = row.get::<_, i64>(3)? as u64;
+            metrics.quarantined_bookmarks = row.get::<_, i64>(4)? as u64;
+            metrics.oversized_rows_fixed = row.get::<_, i64>(5)? as u64;
+            Ok(())
+        },
+    )?;
+
+    let parents = db.query_rows_into::<HashMap<i64, Option<i64>>, _, _, _>(
+        "SELECT id, parent FROM moz_bookmarks WHERE type = :folder_type",
+        &[(":folder_type", &BookmarkType::Folder)],
+        |row| -> rusqlite::Result<_> { Ok((row.get(0)?, row.get(1)?)) },
+    )?;
+    metrics.folder_depths = count_folder_depths(&parents);
+
+    metrics.db_size_bytes = db.query_one::<i64>(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+    )? as u64;
+
+    metrics.guid_case_collisions = count_guid_case_collisions(db)? as u64;
+
+    Ok(metrics)
+}
+
+// Returns the number of folders at each depth below the root, given a map of
+// folder IDs to parent IDs. Folders that don't lead back to the root aren't
+// counted.
+fn count_folder_depths(parents: &HashMap<i64, Option<i64>>) -> Vec<u64> {
+    let mut depths = HashMap::with_capacity(parents.len());
+    let mut counts = Vec::new();
+    for &id in parents.keys() {
+        match folder_depth(id, parents, &mut depths) {
+            Some(0) | None => {}
+            Some(depth) => {
+                if counts.len() < depth {
+                    counts.resize(depth, 0);
+                }
+                counts[depth - 1] += 1;
+            }
+        }
+    }
+    counts
+}
+
+// Returns the depth of a folder, walking up its ancestors until we find one
+// with a known depth, and memoizing the depths of the folders we visited.
+fn folder_depth(
+    id: i64,
+    parents: &HashMap<i64, Option<i64>>,
+    depths: &mut HashMap<i64, usize>,
+) -> Option<usize> {
+    let mut path = Vec::new();
+    let mut current = id;
+    let base = loop {
+        if let Some(&depth) = depths.get(&current) {
+            break depth;
+        }
+        match parents.get(&current) {
+            Some(Some(parent)) => {
+                path.push(current);
+                if path.len() > parents.len() {
+                    // A cycle, which the schema should prevent.
+                    return None;
+                }
+                current = *parent;
+            }
+            Some(None) => {
+                depths.insert(current, 0);
+                break 0;
+            }
+            // The parent isn't a folder, which the schema doesn't prevent,
+            // but should never happen.
+            None => return None,
+        }
+    };
+    for (i, id) in path.into_iter().rev().enumerate() {
+        depths.insert(id, base + i + 1);
+    }
+    depths.get(&id).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::storage::history::apply_observation;
+    use crate::testing::{insert_json_tree, new_mem_connection};
+    use crate::types::{FrozenClock, VisitTransition};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use url::Url;
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    // Collects metrics with SQL tracing on, and returns them with the number
+    // of statements that `collect` ran.
+    fn collect_and_count_statements(conn: &mut PlacesDb) -> Result<(PlacesMetrics, u64)> {
+        conn.reset_sql_stats();
+        conn.set_sql_tracing(true);
+        let metrics = collect(conn);
+        conn.set_sql_tracing(false);
+        let statements = conn.sql_stats().iter().map(|stats| stats.count).sum();
+        Ok((metrics?, statements))
+    }
+
+    #[test]
+    fn test_collect() -> Result<()> {
+        let now = Timestamp(1_550_000_000_000);
+        let mut conn = new_mem_connection();
+        conn.set_clock(FrozenClock::at(now))?;
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [
+                            {
+                                "guid": "folderCCCCCC",
+                                "title": "C",
+                                "children": [{
+                                    "guid": "bookmarkDDDD",
+                                    "title": "D",
+                                    "url": "http://example.com/d",
+                                }],
+                            },
+                        ],
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [{
+                    "guid": "folderEEEEEE",
+                    "title": "E",
+                    "children": [],
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        conn.execute(
+            "UPDATE moz_bookmarks SET guid = 'BOOKMARKAAAA'
+             WHERE guid = 'bookmarkDDDD'",
+            NO_PARAMS,
+        )?;
+
+        for (url, days_ago) in &[
+            ("http://example.com/a", 1),
+            ("http://example.com/a", 30),
+            ("http://example.com/x", 6),
+            ("http://example.com/y", 8),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_at(Timestamp(now.as_millis() - days_ago * DAY_MS))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+
+        let (metrics, statements) = collect_and_count_statements(&mut conn)?;
+        assert!(statements <= MAX_METRICS_QUERIES as u64);
+
+        let db_size_bytes = metrics.db_size_bytes;
+        assert!(db_size_bytes > 0);
+        assert_eq!(
+            metrics,
+            PlacesMetrics {
+                // a, d, x, and y.
+                page_count: 4,
+                visit_count: 4,
+                recent_visit_count: 2,
+                bookmark_count: 2,
+                // The four built-in roots, B and E, then C.
+                folder_depths: vec![4, 2, 1],
+                db_size_bytes,
+                guid_case_collisions: 1,
+                quarantined_bookmarks: 0,
+                oversized_rows_fixed: 0,
+            }
+        );
+        assert_eq!(collect(&conn)?, metrics);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_large_profile() -> Result<()> {
+        const FOLDERS: usize = 50;
+        const BOOKMARKS_PER_FOLDER: usize = 20;
+        const PAGES: usize = 200;
+        const VISITS_PER_PAGE: usize = 5;
+
+        let now = Timestamp(1_550_000_000_000);
+        let mut conn = new_mem_connection();
+        conn.set_clock(FrozenClock::at(now))?;
+
+        // A chain of nested folders under the menu, each with its own
+        // bookmarks.
+        let mut subfolder = None;
+        for depth in (0..FOLDERS).rev() {
+            let mut children = (0..BOOKMARKS_PER_FOLDER)
+                .map(|i| {
+                    json!({
+                        "guid": format!("bkmk{:04}{:04}", depth, i),
+                        "title": format!("{}", i),
+                        "url": format!("http://example.com/{}/{}", depth, i),
+                    })
+                })
+                .collect::<Vec<_>>();
+            children.extend(subfolder.take());
+            subfolder = Some(json!({
+                "guid": format!("folder{:06}", depth),
+                "title": format!("{}", depth),
+                "children": children,
+            }));
+        }
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [subfolder.expect("should have folders")],
+            }),
+        )
+        .expect("should insert tree");
+        conn.execute(
+            "UPDATE moz_bookmarks SET guid = upper(guid)
+             WHERE guid IN ('bkmk00010000', 'bkmk00020000')",
+            NO_PARAMS,
+        )?;
+        conn.execute(
+            "UPDATE moz_bookmarks SET guid = 'bkmk00010000'
+             WHERE guid = 'bkmk00010001'",
+            NO_PARAMS,
+        )?;
+
+        // Visit half the pages in the last week, and half before.
+        for i in 0..PAGES {
+            let url = Url::parse(&format!("http://example.org/{}", i))?;
+            for j in 0..VISITS_PER_PAGE {
+                let days_ago = if i % 2 == 0 { 1 } else { 30 } + j as u64;
+                apply_observation(
+                    &conn,
+                    VisitObservation::new(url.clone())
+                        .with_at(Timestamp(now.as_millis() - days_ago * DAY_MS))
+                        .with_visit_type(VisitTransition::Link),
+                )?;
+            }
+        }
+
+        // The number of statements doesn't depend on the size of the
+        // profile.
+        let (metrics, statements) = collect_and_count_statements(&mut conn)?;
+        assert!(statements <= MAX_METRICS_QUERIES as u64);
+
+        assert_eq!(
+            metrics.page_count,
+            (FOLDERS * BOOKMARKS_PER_FOLDER + PAGES) as u64
+        );
+        assert_eq!(metrics.visit_count, (PAGES * VISITS_PER_PAGE) as u64);
+        // Visits 1-5 days ago for even pages.
+        assert_eq!(
+            metrics.recent_visit_count,
+            (PAGES / 2 * VISITS_PER_PAGE) as u64
+        );
+        assert_eq!(
+            metrics.bookmark_count,
+            (FOLDERS * BOOKMARKS_PER_FOLDER) as u64
+        );
+        // The four built-in roots, then one folder at each depth.
+        let mut folder_depths = vec![4];
+        folder_depths.extend(std::iter::repeat(1).take(FOLDERS));
+        assert_eq!(metrics.folder_depths, folder_depths);
+        // "BKMK00010000" and "bkmk00010000" collide; "BKMK00020000" doesn't.
+        assert_eq!(metrics.guid_case_collisions, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_folder_depths() {
+        let parents = [
+            (1, None),
+            (2, Some(1)),
+            (3, Some(2)),
+            (4, Some(3)),
+            (5, Some(2)),
+            // 6 and 7 form a cycle, and 8's parent isn't a folder.
+            (6, Some(7)),
+            (7, Some(6)),
+            (8, Some(9)),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<i64, Option<i64>>>();
+        assert_eq!(count_folder_depths(&parents), vec![1, 2, 1]);
+    }
+}
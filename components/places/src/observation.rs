@@ -6,6 +6,37 @@ use crate::types::*;
 use serde_derive::*;
 use url::Url;
 
+/// Where an observation came from. Observations from sync describe visits
+/// that happened on other devices, so they're applied differently:
+///
+/// - They never increment the typed count, since the user didn't type the
+///   URL on this device.
+/// - They never write to the adaptive input history, which only tracks what
+///   the user picked from autocomplete here.
+/// - Their visits are stored as remote visits, which frecency and the local
+///   visit counts treat differently, and we don't trust their redirect
+///   hints when calculating frecency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObservationSource {
+    /// The user visited the page on this device.
+    Local,
+    /// The visit was downloaded or imported from another device.
+    Sync,
+}
+
+impl ObservationSource {
+    pub fn is_local(self) -> bool {
+        self == ObservationSource::Local
+    }
+}
+
+impl Default for ObservationSource {
+    fn default() -> Self {
+        ObservationSource::Local
+    }
+}
+
 /// An "observation" based model for updating history.
 /// You create a VisitObservation, call functions on it which correspond
 /// with what you observed. The page will then be updated using this info.
@@ -19,7 +50,7 @@ use url::Url;
 /// It exposes a "builder api", but for convenience, that API allows Options too.
 /// So, eg, `.with_title(None)` or `with_is_error(None)` is allowed but records
 /// no observation.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VisitObservation {
     /// Ideally, we'd use url::Url here with `serde_url`, but we really would
     /// like to expose these errors over the FFI as UrlParseErrors and not json
@@ -55,6 +86,19 @@ pub struct VisitObservation {
     #[serde(default)]
     pub referrer: Option<String>,
 
+    /// The search term that led to this visit, if the user got here from a
+    /// search. Only stored for local visits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub search_term: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub source: Option<ObservationSource>,
+
+    /// The legacy way to mark an observation as coming from sync, which
+    /// our FFI consumers still send. Use `source()` to read it, or
+    /// `with_source` to set it.
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub is_remote: Option<bool>,
@@ -71,6 +115,8 @@ impl VisitObservation {
             is_permanent_redirect_source: None,
             at: None,
             referrer: None,
+            search_term: None,
+            source: None,
             is_remote: None,
         }
     }
@@ -108,6 +154,11 @@ impl VisitObservation {
         self
     }
 
+    pub fn with_source(mut self, v: impl Into<Option<ObservationSource>>) -> Self {
+        self.source = v.into();
+        self
+    }
+
     pub fn with_is_remote(mut self, v: impl Into<Option<bool>>) -> Self {
         self.is_remote = v.into();
         self
@@ -118,7 +169,28 @@ impl VisitObservation {
         self
     }
 
+    pub fn with_search_term(mut self, v: impl Into<Option<String>>) -> Self {
+        self.search_term = v.into();
+        self
+    }
+
     // Other helpers which can be derived.
+
+    /// Where the observation came from. An explicit `source` takes
+    /// precedence over the legacy `is_remote` flag.
+    pub fn source(&self) -> ObservationSource {
+        match (self.source, self.is_remote) {
+            (Some(source), _) => source,
+            (None, Some(true)) => ObservationSource::Sync,
+            (None, _) => ObservationSource::Local,
+        }
+    }
+
+    #[deprecated(note = "Use `source()` instead")]
+    pub fn is_remote(&self) -> bool {
+        self.source() == ObservationSource::Sync
+    }
+
     pub fn get_redirect_frecency_boost(&self) -> bool {
         self.is_redirect_source.is_some()
             && match self.visit_type {
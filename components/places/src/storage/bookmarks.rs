@@ -2,11 +2,13 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use super::deletion_log::{record_deletion, DeletionInitiator, DeletionOp};
 use super::RowId;
-use super::{fetch_page_info, new_page_info};
+use super::{fetch_page_info, new_page_info, PageInfo};
 use crate::db::PlacesDb;
 use crate::error::*;
 use crate::types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
+use crate::valid_guid::is_valid_places_guid;
 use rusqlite::types::ToSql;
 use rusqlite::{Connection, Row};
 use serde::{
@@ -22,16 +24,23 @@ use std::collections::HashMap;
 use url::Url;
 
 pub use public_node::PublicNode;
+pub use query::{
+    get_query_bookmarks, resolve_query_bookmark, resolve_query_url, set_query_bookmark_hidden,
+    PlaceQueryKind, QueryBookmark,
+};
+pub(crate) use repair::repair_guid_case_collisions;
+pub use repair::{repair, RepairReport};
 pub use root_guid::{BookmarkRootGuid, USER_CONTENT_ROOTS};
 
 mod conversions;
 pub mod public_node;
+pub mod query;
+mod repair;
 mod root_guid;
 
 fn create_root(
     db: &Connection,
-    title: &str,
-    guid: &SyncGuid,
+    root: BookmarkRootGuid,
     position: u32,
     when: Timestamp,
 ) -> Result<()> {
@@ -50,10 +59,10 @@ fn create_root(
     let params: Vec<(&str, &dyn ToSql)> = vec![
         (":item_type", &BookmarkType::Folder),
         (":item_position", &position),
-        (":item_title", &title),
+        (":item_title", &root.default_title()),
         (":date_added", &when),
         (":last_modified", &when),
-        (":guid", guid),
+        (":guid", root.guid()),
         (":sync_status", &SyncStatus::New),
     ];
     db.execute_named_cached(&sql, &params)?;
@@ -62,11 +71,46 @@ fn create_root(
 
 pub fn create_bookmark_roots(db: &Connection) -> Result<()> {
     let now = Timestamp::now();
-    create_root(db, "root", &BookmarkRootGuid::Root.into(), 0, now)?;
-    create_root(db, "menu", &BookmarkRootGuid::Menu.into(), 0, now)?;
-    create_root(db, "toolbar", &BookmarkRootGuid::Toolbar.into(), 1, now)?;
-    create_root(db, "unfiled", &BookmarkRootGuid::Unfiled.into(), 2, now)?;
-    create_root(db, "mobile", &BookmarkRootGuid::Mobile.into(), 3, now)?;
+    create_root(db, BookmarkRootGuid::Root, 0, now)?;
+    create_root(db, BookmarkRootGuid::Menu, 0, now)?;
+    create_root(db, BookmarkRootGuid::Toolbar, 1, now)?;
+    create_root(db, BookmarkRootGuid::Unfiled, 2, now)?;
+    create_root(db, BookmarkRootGuid::Mobile, 3, now)?;
+    Ok(())
+}
+
+/// Sets the titles for user content roots, like "Bookmarks Menu" or "Mobile
+/// Bookmarks". Apps should call this at startup with localized titles.
+///
+/// Root titles are local-only: we never upload them, and we ignore titles in
+/// incoming records for roots, so this doesn't bump the change counter.
+pub fn set_root_titles(db: &PlacesDb, titles: &[(BookmarkRootGuid, &str)]) -> Result<()> {
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = set_root_titles_in_tx(db, titles);
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
+}
+
+fn set_root_titles_in_tx(db: &PlacesDb, titles: &[(BookmarkRootGuid, &str)]) -> Result<()> {
+    use super::TITLE_LENGTH_MAX;
+    use crate::util::slice_up_to;
+    for (root, title) in titles {
+        if *root == BookmarkRootGuid::Root {
+            return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
+        }
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET title = :title WHERE guid = :guid",
+            &[
+                (":title", &slice_up_to(title, TITLE_LENGTH_MAX)),
+                (":guid", root.guid()),
+            ],
+        )?;
+    }
     Ok(())
 }
 
@@ -159,6 +203,10 @@ pub struct InsertableBookmark {
     pub guid: Option<SyncGuid>,
     pub url: Url,
     pub title: Option<String>,
+    /// The title of the page itself, as opposed to the title of the bookmark.
+    /// This is only used if we need to create a new place for the URL; the
+    /// title of an existing place is never changed.
+    pub page_title: Option<String>,
 }
 
 impl From<InsertableBookmark> for InsertableItem {
@@ -235,15 +283,33 @@ impl InsertableItem {
     impl_common_bookmark_getter!(guid, Option<SyncGuid>);
 }
 
-pub fn insert_bookmark(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
-    let tx = db.begin_transaction()?;
-    let result = insert_bookmark_in_tx(db, bm);
-    super::delete_pending_temp_tables(db)?;
-    match result {
-        Ok(_) => tx.commit()?,
-        Err(_) => tx.rollback()?,
-    }
-    result
+/// Information about an item we just inserted, populated from the values we
+/// actually wrote. This saves callers from needing to re-fetch the item to
+/// find out where it ended up.
+#[derive(Debug, Clone)]
+pub struct InsertedBookmark {
+    pub row_id: RowId,
+    pub guid: SyncGuid,
+    /// The position of the item in its parent, after clamping to the number
+    /// of children.
+    pub position: u32,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+    /// The GUID of the place for the URL. Only set for bookmarks.
+    pub place_guid: Option<SyncGuid>,
+}
+
+pub fn insert_bookmark(db: &PlacesDb, bm: &InsertableItem) -> Result<InsertedBookmark> {
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = insert_bookmark_in_tx(db, bm);
+        super::delete_pending_temp_tables(db)?;
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
 }
 
 pub fn maybe_truncate_title(t: &Option<String>) -> Option<&str> {
@@ -252,11 +318,21 @@ pub fn maybe_truncate_title(t: &Option<String>) -> Option<&str> {
     t.as_ref().map(|title| slice_up_to(title, TITLE_LENGTH_MAX))
 }
 
-fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
+fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<InsertedBookmark> {
     // find the row ID of the parent.
     if bm.parent_guid() == BookmarkRootGuid::Root {
         return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
     }
+    // Sync expects 12-character base64url GUIDs, and would otherwise replace
+    // them with new ones, so we don't let callers insert anything else.
+    if let Some(guid) = bm.guid() {
+        if !is_valid_places_guid(guid.as_ref()) {
+            return Err(InvalidPlaceInfo::InvalidGuid.into());
+        }
+        if get_raw_bookmark(db, guid)?.is_some() {
+            return Err(InvalidPlaceInfo::GuidAlreadyExists(guid.to_string()).into());
+        }
+    }
     let parent_guid = bm.parent_guid();
     let parent = get_raw_bookmark(db, parent_guid)?
         .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(parent_guid.to_string()))?;
@@ -269,83 +345,66 @@ fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid>
     // Note that we could probably do this 'fk' work as a sub-query (although
     // markh isn't clear how we could perform the insert) - it probably doesn't
     // matter in practice though...
-    let fk = match bm {
+    let page = match bm {
         InsertableItem::Bookmark(ref bm) => {
             let page_info = match fetch_page_info(db, &bm.url)? {
-                Some(info) => info.page,
-                None => new_page_info(db, &bm.url, None)?,
+                Some(info) => {
+                    maybe_unhide_page(db, &info.page)?;
+                    info.page
+                }
+                None => {
+                    let page_info = new_page_info(db, &bm.url, None)?;
+                    if let Some(page_title) = maybe_truncate_title(&bm.page_title) {
+                        db.execute_named_cached(
+                            "UPDATE moz_places SET title = :title WHERE id = :id",
+                            &[(":title", &page_title), (":id", &page_info.row_id)],
+                        )?;
+                    }
+                    page_info
+                }
             };
-            Some(page_info.row_id)
+            Some(page_info)
         }
         _ => None,
     };
+    let fk = page.as_ref().map(|p| p.row_id);
     let sql = "INSERT INTO moz_bookmarks
               (fk, type, parent, position, title, dateAdded, lastModified,
                guid, syncStatus, syncChangeCounter) VALUES
               (:fk, :type, :parent, :position, :title, :dateAdded, :lastModified,
                :guid, :syncStatus, :syncChangeCounter)";
 
-    let guid = bm.guid().clone().unwrap_or_else(SyncGuid::new);
-    let date_added = bm.date_added().unwrap_or_else(Timestamp::now);
+    let guid = match bm.guid() {
+        Some(guid) => guid.clone(),
+        None => new_bookmark_guid(db)?,
+    };
+    let date_added = bm.date_added().unwrap_or_else(|| db.now());
     // last_modified can't be before date_added
-    let last_modified = max(
-        bm.last_modified().unwrap_or_else(Timestamp::now),
-        date_added,
-    );
+    let last_modified = max(bm.last_modified().unwrap_or_else(|| db.now()), date_added);
 
     let bookmark_type = bm.bookmark_type();
-    match bm {
-        InsertableItem::Bookmark(ref b) => {
-            let title = maybe_truncate_title(&b.title);
-            db.execute_named_cached(
-                sql,
-                &[
-                    (":fk", &fk),
-                    (":type", &bookmark_type),
-                    (":parent", &parent.row_id),
-                    (":position", &position),
-                    (":title", &title),
-                    (":dateAdded", &date_added),
-                    (":lastModified", &last_modified),
-                    (":guid", &guid),
-                    (":syncStatus", &SyncStatus::New),
-                    (":syncChangeCounter", &1),
-                ],
-            )?;
-        }
-        InsertableItem::Separator(ref _s) => {
-            db.execute_named_cached(
-                sql,
-                &[
-                    (":type", &bookmark_type),
-                    (":parent", &parent.row_id),
-                    (":position", &position),
-                    (":dateAdded", &date_added),
-                    (":lastModified", &last_modified),
-                    (":guid", &guid),
-                    (":syncStatus", &SyncStatus::New),
-                    (":syncChangeCounter", &1),
-                ],
-            )?;
-        }
-        InsertableItem::Folder(ref f) => {
-            let title = maybe_truncate_title(&f.title);
-            db.execute_named_cached(
-                sql,
-                &[
-                    (":type", &bookmark_type),
-                    (":parent", &parent.row_id),
-                    (":title", &title),
-                    (":position", &position),
-                    (":dateAdded", &date_added),
-                    (":lastModified", &last_modified),
-                    (":guid", &guid),
-                    (":syncStatus", &SyncStatus::New),
-                    (":syncChangeCounter", &1),
-                ],
-            )?;
-        }
+    // Separators don't have titles.
+    let title = match bm {
+        InsertableItem::Bookmark(ref b) => maybe_truncate_title(&b.title),
+        InsertableItem::Folder(ref f) => maybe_truncate_title(&f.title),
+        InsertableItem::Separator(_) => None,
     };
+    db.execute_named_cached(
+        sql,
+        &[
+            (":fk", &fk),
+            (":type", &bookmark_type),
+            (":parent", &parent.row_id),
+            (":position", &position),
+            (":title", &title),
+            (":dateAdded", &date_added),
+            (":lastModified", &last_modified),
+            (":guid", &guid),
+            (":syncStatus", &SyncStatus::New),
+            (":syncChangeCounter", &1),
+        ],
+    )?;
+    let row_id = RowId(db.conn().last_insert_rowid());
 
     // Bump the parent's change counter.
     let sql_counter = "
@@ -353,31 +412,85 @@ fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid>
         WHERE id = :parent_id";
     db.execute_named_cached(sql_counter, &[(":parent_id", &parent.row_id)])?;
 
-    Ok(guid)
+    Ok(InsertedBookmark {
+        row_id,
+        guid,
+        position,
+        date_added,
+        last_modified,
+        place_guid: page.map(|p| p.guid),
+    })
 }
 
-/// Delete the specified bookmark. Returns true if a bookmark with the guid
-/// existed and was deleted, false otherwise.
-pub fn delete_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
-    let tx = db.begin_transaction()?;
-    let result = delete_bookmark_in_tx(db, guid);
-    match result {
-        Ok(_) => tx.commit()?,
-        Err(_) => tx.rollback()?,
+/// Generates a new bookmark GUID that doesn't differ only by case from an
+/// existing item. Some servers and older clients compare record IDs
+/// case-insensitively, so an item like that would collide on the server.
+fn new_bookmark_guid(db: &PlacesDb) -> Result<SyncGuid> {
+    loop {
+        let guid = SyncGuid::new();
+        // This uses the case-insensitive GUID index.
+        let exists = db.try_query_one::<bool>(
+            "SELECT EXISTS(SELECT 1 FROM moz_bookmarks
+                           WHERE guid = :guid COLLATE NOCASE)",
+            &[(":guid", &guid)],
+            true,
+        )?;
+        if exists != Some(true) {
+            return Ok(guid);
+        }
     }
-    result
 }
 
-fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+/// Returns the number of local items whose GUIDs only differ by case from
+/// another item's. `run_maintenance` and `repair` give these items new
+/// GUIDs.
+pub(crate) fn count_guid_case_collisions(db: &PlacesDb) -> Result<i64> {
+    Ok(db.query_one(
+        "SELECT IFNULL(SUM(n - 1), 0) FROM (
+           SELECT COUNT(*) AS n FROM moz_bookmarks
+           GROUP BY guid COLLATE NOCASE
+           HAVING n > 1
+         )",
+    )?)
+}
+
+/// Bookmarking a page the user has visited makes it visible again, but we
+/// don't want to unhide pages that only exist because they were bookmarked.
+fn maybe_unhide_page(db: &PlacesDb, page: &PageInfo) -> Result<()> {
+    if page.hidden && page.visit_count_local + page.visit_count_remote > 0 {
+        db.execute_named_cached(
+            "UPDATE moz_places SET hidden = 0 WHERE id = :id",
+            &[(":id", &page.row_id)],
+        )?;
+    }
+    Ok(())
+}
+
+/// Delete the specified bookmark, and all its descendants if it's a folder.
+/// Deleting a bookmark that doesn't exist does nothing. Roots can't be
+/// deleted, and fail with `InvalidPlaceInfo::InvalidGuid`.
+pub fn delete_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = delete_bookmark_in_tx(db, guid);
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
+}
+
+fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
     // Can't delete a root.
-    if let Some(root) = guid.as_root() {
-        return Err(InvalidPlaceInfo::CannotUpdateRoot(root).into());
+    if guid.is_root() {
+        return Err(InvalidPlaceInfo::InvalidGuid.into());
     }
     let record = match get_raw_bookmark(db, guid)? {
         Some(r) => r,
         None => {
             log::debug!("Can't delete bookmark '{:?}' as it doesn't exist", guid);
-            return Ok(false);
+            return Ok(());
         }
     };
     // There's an argument to be made here that we should still honor the
@@ -386,15 +499,188 @@ fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
     let record_parent_id = record
         .parent_id
         .ok_or_else(|| Corruption::NonRootWithoutParent(guid.to_string()))?;
+    let descendants = count_descendants(db, record.row_id)?;
+    record_deletion(
+        db,
+        DeletionOp::DeleteBookmark,
+        DeletionInitiator::Api,
+        descendants + 1,
+    )?;
     // must reorder existing children.
     update_pos_for_deletion(db, record.position, record_parent_id)?;
-    // and delete - children are recursively deleted.
+    // and delete - children are recursively deleted. The main connection's
+    // triggers write tombstones for all deleted items that we've synced.
     db.execute_named_cached(
         "DELETE from moz_bookmarks WHERE id = :id",
         &[(":id", &record.row_id)],
     )?;
+    // The parent's children changed, so we need to upload it, too.
+    set_ancestors_last_modified(db, record_parent_id, db.now())?;
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :parent_id",
+        &[(":parent_id", &record_parent_id)],
+    )?;
+    super::delete_pending_temp_tables(db)?;
+    Ok(())
+}
+
+/// Deletes all descendants of a folder, but keeps the folder itself. Unlike
+/// `delete_bookmark`, this works for the user content roots, so it can be
+/// used to clear all mobile bookmarks, for example. Does nothing if the
+/// folder is already empty.
+pub fn erase_folder_children(db: &PlacesDb, folder_guid: &SyncGuid) -> Result<()> {
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = erase_folder_children_in_tx(db, folder_guid);
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
+}
+
+fn erase_folder_children_in_tx(db: &PlacesDb, folder_guid: &SyncGuid) -> Result<()> {
+    if folder_guid == BookmarkRootGuid::Root {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
+    }
+    let folder = get_raw_bookmark(db, folder_guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(folder_guid.to_string()))?;
+    if folder.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(folder_guid.to_string()).into());
+    }
+    if folder.child_count == 0 {
+        return Ok(());
+    }
+    let descendants = count_descendants(db, folder.row_id)?;
+    record_deletion(
+        db,
+        DeletionOp::EraseFolderChildren,
+        DeletionInitiator::Api,
+        descendants,
+    )?;
+    // Grandchildren are recursively deleted, and the main connection's
+    // triggers write tombstones for all deleted items that we've synced.
+    // Since all the children are gone, there are no positions to fix up.
+    db.execute_named_cached(
+        "DELETE FROM moz_bookmarks WHERE parent = :parent_id",
+        &[(":parent_id", &folder.row_id)],
+    )?;
+    set_ancestors_last_modified(db, folder.row_id, db.now())?;
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :id",
+        &[(":id", &folder.row_id)],
+    )?;
+    super::delete_pending_temp_tables(db)?;
+    Ok(())
+}
+
+/// Returns the number of items under a folder, at any depth.
+fn count_descendants(db: &PlacesDb, row_id: RowId) -> Result<usize> {
+    let count = db.query_row_named_with_context(
+        "WITH RECURSIVE
+         descendants(id) AS (
+           SELECT id FROM moz_bookmarks
+           WHERE parent = :id
+           UNION ALL
+           SELECT b.id FROM moz_bookmarks b
+           JOIN descendants d ON d.id = b.parent
+         )
+         SELECT COUNT(*) FROM descendants",
+        &[(":id", &row_id)],
+        |row| row.get::<_, i64>(0),
+    )?;
+    Ok(count as usize)
+}
+
+/// The result of toggling a bookmark for a URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToggleResult {
+    /// Whether the URL is bookmarked after the toggle.
+    pub now_bookmarked: bool,
+    /// The GUID of the new bookmark, if we inserted one.
+    pub guid: Option<SyncGuid>,
+}
+
+/// Toggles whether a URL is bookmarked, like a "star" button. If the URL is
+/// already bookmarked anywhere, this deletes all its bookmarks; otherwise,
+/// this inserts a new bookmark at the end of `default_parent`. Queries
+/// (`place:` URLs) can't be unstarred: toggling one updates the title of the
+/// existing query in `default_parent`, or inserts one if there isn't one.
+pub fn toggle_bookmark(
+    db: &PlacesDb,
+    url: &Url,
+    title: Option<String>,
+    default_parent: &SyncGuid,
+) -> Result<ToggleResult> {
+    let tx = db.begin_transaction()?;
+    let result = toggle_bookmark_in_tx(db, url, title, default_parent);
     super::delete_pending_temp_tables(db)?;
-    Ok(true)
+    match result {
+        Ok(_) => tx.commit()?,
+        Err(_) => tx.rollback()?,
+    }
+    result
+}
+
+fn toggle_bookmark_in_tx(
+    db: &PlacesDb,
+    url: &Url,
+    title: Option<String>,
+    default_parent: &SyncGuid,
+) -> Result<ToggleResult> {
+    let existing = if url.scheme() == "place" {
+        let existing_query = get_raw_bookmarks_for_url(db, url)?
+            .into_iter()
+            .find(|bookmark| bookmark.parent_guid.as_ref() == Some(default_parent));
+        if let Some(query) = existing_query {
+            update_bookmark_in_tx(
+                db,
+                &query.guid,
+                &UpdatableBookmark {
+                    title,
+                    ..UpdatableBookmark::default()
+                }
+                .into(),
+            )?;
+            return Ok(ToggleResult {
+                now_bookmarked: true,
+                guid: Some(query.guid),
+            });
+        }
+        Vec::new()
+    } else {
+        get_raw_bookmarks_for_url(db, url)?
+    };
+    if existing.is_empty() {
+        let inserted = insert_bookmark_in_tx(
+            db,
+            &InsertableBookmark {
+                parent_guid: default_parent.clone(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title,
+                page_title: None,
+            }
+            .into(),
+        )?;
+        return Ok(ToggleResult {
+            now_bookmarked: true,
+            guid: Some(inserted.guid),
+        });
+    }
+    for bookmark in existing {
+        delete_bookmark_in_tx(db, &bookmark.guid)?;
+    }
+    Ok(ToggleResult {
+        now_bookmarked: false,
+        guid: None,
+    })
 }
 
 /// Support for modifying bookmarks, including changing the location in
@@ -480,11 +766,15 @@ impl UpdatableItem {
     }
 }
 pub fn update_bookmark(db: &PlacesDb, guid: &SyncGuid, item: &UpdatableItem) -> Result<()> {
-    let tx = db.begin_transaction()?;
-    let result = update_bookmark_in_tx(db, guid, item);
-    // Note: `tx` automatically rolls back on drop if we don't commit
-    tx.commit()?;
-    result
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = update_bookmark_in_tx(db, guid, item);
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
 }
 
 fn update_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid, item: &UpdatableItem) -> Result<()> {
@@ -541,6 +831,13 @@ fn update_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid, item: &UpdatableItem) -
             if new_parent.bookmark_type != BookmarkType::Folder {
                 return Err(InvalidPlaceInfo::InvalidParent(new_parent_guid.to_string()).into());
             }
+            // A folder can't be moved into itself or one of its descendants,
+            // or we'd create a cycle.
+            if existing.bookmark_type == BookmarkType::Folder
+                && is_same_or_descendant(db, new_parent.row_id, existing.row_id)?
+            {
+                return Err(InvalidPlaceInfo::InvalidParent(new_parent_guid.to_string()).into());
+            }
             parent_id = new_parent.row_id;
             update_old_parent_status = true;
             update_new_parent_status = true;
@@ -594,7 +891,7 @@ fn update_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid, item: &UpdatableItem) -
 
     let change_incr = title != existing.title || place_id != existing.place_id;
 
-    let now = Timestamp::now();
+    let now = db.now();
 
     let sql = "
         UPDATE moz_bookmarks SET
@@ -637,6 +934,250 @@ fn update_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid, item: &UpdatableItem) -
     Ok(())
 }
 
+/// Moves an item to a new position, either in its current folder or in a
+/// different folder. Unlike `update_bookmark`, this works for any item type,
+/// and doesn't change anything else about the item.
+pub fn move_bookmark(
+    db: &PlacesDb,
+    guid: &SyncGuid,
+    new_parent_guid: &SyncGuid,
+    pos: BookmarkPosition,
+) -> Result<()> {
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = move_bookmark_in_tx(db, guid, new_parent_guid, pos);
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
+}
+
+fn move_bookmark_in_tx(
+    db: &PlacesDb,
+    guid: &SyncGuid,
+    new_parent_guid: &SyncGuid,
+    pos: BookmarkPosition,
+) -> Result<()> {
+    if let Some(root) = BookmarkRootGuid::from_guid(guid) {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(root).into());
+    }
+    // Items can only live in the user content roots, not the Places root.
+    if let Some(root) = BookmarkRootGuid::from_guid(new_parent_guid) {
+        if !USER_CONTENT_ROOTS.contains(&root) {
+            return Err(InvalidPlaceInfo::CannotUpdateRoot(root).into());
+        }
+    }
+    let existing = get_raw_bookmark(db, guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(guid.to_string()))?;
+    let existing_parent_guid = existing
+        .parent_guid
+        .as_ref()
+        .ok_or_else(|| Corruption::NonRootWithoutParent(guid.to_string()))?;
+    let existing_parent = get_raw_bookmark(db, existing_parent_guid)?
+        .ok_or_else(|| Corruption::NoParent(guid.to_string(), existing_parent_guid.to_string()))?;
+
+    let new_parent = get_raw_bookmark(db, new_parent_guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(new_parent_guid.to_string()))?;
+    if new_parent.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(new_parent_guid.to_string()).into());
+    }
+    if existing.bookmark_type == BookmarkType::Folder
+        && is_same_or_descendant(db, new_parent.row_id, existing.row_id)?
+    {
+        return Err(InvalidPlaceInfo::InvalidParent(new_parent_guid.to_string()).into());
+    }
+
+    // Moving within the same folder shifts the siblings between the old and
+    // new positions, instead of closing and opening a gap. Doing the latter
+    // would use a child count that still includes the item we're moving.
+    let position = if new_parent.row_id == existing_parent.row_id {
+        update_pos_for_move(db, pos, &existing, &existing_parent)?
+    } else {
+        update_pos_for_deletion(db, existing.position, existing_parent.row_id)?;
+        resolve_pos_for_insert(db, pos, &new_parent)?
+    };
+
+    let now = db.now();
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET
+             parent = :parent,
+             position = :position,
+             lastModified = :now,
+             syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :id",
+        &[
+            (":parent", &new_parent.row_id),
+            (":position", &position),
+            (":now", &now),
+            (":id", &existing.row_id),
+        ],
+    )?;
+
+    set_ancestors_last_modified(db, existing_parent.row_id, now)?;
+    if new_parent.row_id != existing_parent.row_id {
+        set_ancestors_last_modified(db, new_parent.row_id, now)?;
+    }
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+         WHERE id IN (:old_parent_id, :new_parent_id)",
+        &[
+            (":old_parent_id", &existing_parent.row_id),
+            (":new_parent_id", &new_parent.row_id),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Reorders the children of a folder to match `child_guids`. Children that
+/// aren't in `child_guids` keep their relative order, after all the ones
+/// that are. GUIDs that aren't children of the folder are ignored. This
+/// matches Desktop's `PlacesUtils.bookmarks.reorder`.
+pub fn set_bookmark_children(
+    db: &PlacesDb,
+    folder_guid: &SyncGuid,
+    child_guids: &[SyncGuid],
+) -> Result<()> {
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = set_bookmark_children_in_tx(db, folder_guid, child_guids);
+        match result {
+            Ok(_) => tx.commit()?,
+            Err(_) => tx.rollback()?,
+        }
+        result
+    })
+}
+
+fn set_bookmark_children_in_tx(
+    db: &PlacesDb,
+    folder_guid: &SyncGuid,
+    child_guids: &[SyncGuid],
+) -> Result<()> {
+    if folder_guid == BookmarkRootGuid::Root {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
+    }
+    let folder = get_raw_bookmark(db, folder_guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(folder_guid.to_string()))?;
+    if folder.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(folder_guid.to_string()).into());
+    }
+
+    let mut children = get_raw_bookmarks_with_parent(db, folder.row_id)?
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+    let indices_by_guid = children
+        .iter()
+        .enumerate()
+        .filter_map(|(index, child)| child.as_ref().map(|c| (c.guid.clone(), index)))
+        .collect::<HashMap<_, _>>();
+    let mut new_order = Vec::with_capacity(children.len());
+    for guid in child_guids {
+        if let Some(&index) = indices_by_guid.get(guid) {
+            // `take` skips duplicate GUIDs.
+            if let Some(child) = children[index].take() {
+                new_order.push(child);
+            }
+        }
+    }
+    new_order.extend(children.into_iter().flatten());
+
+    let mut changed = false;
+    for (position, child) in new_order.iter().enumerate() {
+        if child.position == position as u32 {
+            continue;
+        }
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET position = :position WHERE id = :id",
+            &[(":position", &(position as u32)), (":id", &child.row_id)],
+        )?;
+        changed = true;
+    }
+    if !changed {
+        return Ok(());
+    }
+
+    // Only the folder's record lists its children, so we only need to
+    // upload the folder.
+    let now = db.now();
+    set_ancestors_last_modified(db, folder.row_id, now)?;
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :id",
+        &[(":id", &folder.row_id)],
+    )?;
+    Ok(())
+}
+
+/// Returns true if `id` is `ancestor_id`, or one of its descendants.
+fn is_same_or_descendant(db: &PlacesDb, id: RowId, ancestor_id: RowId) -> Result<bool> {
+    let sql = "
+        WITH RECURSIVE
+        ancestors(aid) AS (
+            SELECT :id
+            UNION
+            SELECT parent FROM moz_bookmarks
+            JOIN ancestors ON id = aid
+            WHERE parent NOT NULL
+        )
+        SELECT EXISTS(SELECT 1 FROM ancestors WHERE aid = :ancestor_id)
+    ";
+    Ok(db.query_row_named_with_context(
+        sql,
+        &[(":id", &id), (":ancestor_id", &ancestor_id)],
+        |row| row.get(0),
+    )?)
+}
+
+/// Returns an opaque token that changes when `folder_guid`, or anything in
+/// it, changes. Pass the token to `folder_changed_since` later to cheaply
+/// check if the folder needs to be fetched again.
+///
+/// Every bookmark change, local or from Sync, stamps the changed item with
+/// the next value of a counter that only goes up. Removing an item, or moving
+/// it out, stamps its old parent. The token is the newest stamp in the
+/// subtree, so it changes when items are added, removed, moved in or out, or
+/// updated. Callers shouldn't depend on its format.
+pub fn get_folder_change_token(db: &PlacesDb, folder_guid: &SyncGuid) -> Result<String> {
+    fetch_folder_change_token(db, folder_guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(folder_guid.to_string()).into())
+}
+
+/// Returns true if `folder_guid` or any of its descendants changed since
+/// `token` was returned from `get_folder_change_token`. A folder that no
+/// longer exists is considered changed.
+pub fn folder_changed_since(db: &PlacesDb, folder_guid: &SyncGuid, token: &str) -> Result<bool> {
+    Ok(match fetch_folder_change_token(db, folder_guid)? {
+        Some(current) => current != token,
+        None => true,
+    })
+}
+
+fn fetch_folder_change_token(db: &PlacesDb, folder_guid: &SyncGuid) -> Result<Option<String>> {
+    let folder = match get_raw_bookmark(db, folder_guid)? {
+        Some(folder) => folder,
+        None => return Ok(None),
+    };
+    if folder.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(folder_guid.to_string()).into());
+    }
+    let sql = "
+        WITH RECURSIVE
+        subtree(id, changeSeq) AS (
+            SELECT id, changeSeq FROM moz_bookmarks
+            WHERE id = :folder_id
+            UNION ALL
+            SELECT b.id, b.changeSeq FROM moz_bookmarks b
+            JOIN subtree s ON b.parent = s.id
+        )
+        SELECT MAX(changeSeq) FROM subtree";
+    let change_seq: i64 =
+        db.query_row_named_with_context(sql, &[(":folder_id", &folder.row_id)], |row| row.get(0))?;
+    Ok(Some(change_seq.to_string()))
+}
+
 fn set_ancestors_last_modified(db: &PlacesDb, parent_id: RowId, time: Timestamp) -> Result<()> {
     let sql = "
         WITH RECURSIVE
@@ -987,14 +1528,17 @@ mod test_serialize {
 
         Ok(())
     }
-
 }
 
-fn add_subtree_infos(parent: &SyncGuid, tree: &FolderNode, insert_infos: &mut Vec<InsertableItem>) {
-    // TODO: track last modified? Like desktop, we should probably have
-    // the default values passed in so the entire tree has consistent
-    // timestamps.
-    let default_when = Some(Timestamp::now());
+// Items without dates get `now`, so that the entire tree has consistent
+// timestamps.
+fn add_subtree_infos(
+    parent: &SyncGuid,
+    tree: &FolderNode,
+    now: Timestamp,
+    insert_infos: &mut Vec<InsertableItem>,
+) {
+    let default_when = Some(now);
     insert_infos.reserve(tree.children.len());
     for child in &tree.children {
         match child {
@@ -1007,6 +1551,7 @@ fn add_subtree_infos(parent: &SyncGuid, tree: &FolderNode, insert_infos: &mut Ve
                     guid: b.guid.clone(),
                     url: b.url.clone(),
                     title: b.title.clone(),
+                    page_title: None,
                 }
                 .into(),
             ),
@@ -1034,7 +1579,7 @@ fn add_subtree_infos(parent: &SyncGuid, tree: &FolderNode, insert_infos: &mut Ve
                     }
                     .into(),
                 );
-                add_subtree_infos(&my_guid, &f, insert_infos);
+                add_subtree_infos(&my_guid, &f, now, insert_infos);
             }
         };
     }
@@ -1047,7 +1592,7 @@ pub fn insert_tree(db: &PlacesDb, tree: &FolderNode) -> Result<()> {
     };
 
     let mut insert_infos: Vec<InsertableItem> = Vec::new();
-    add_subtree_infos(&parent_guid, tree, &mut insert_infos);
+    add_subtree_infos(&parent_guid, tree, db.now(), &mut insert_infos);
     log::info!("insert_tree inserting {} records", insert_infos.len());
     let tx = db.begin_transaction()?;
 
@@ -1117,12 +1662,38 @@ fn inflate(
     }
 }
 
+/// Options for `fetch_tree_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct FetchTreeOptions {
+    /// Whether to include query bookmarks hidden with
+    /// `set_query_bookmark_hidden`.
+    pub include_hidden_queries: bool,
+}
+
 /// Fetch the tree starting at the specified folder guid.
 /// Returns a BookmarkTreeNode::Folder(_)
 pub fn fetch_tree(db: &PlacesDb, item_guid: &SyncGuid) -> Result<Option<BookmarkTreeNode>> {
+    fetch_tree_with_options(
+        db,
+        item_guid,
+        &FetchTreeOptions {
+            include_hidden_queries: true,
+        },
+    )
+}
+
+/// Like `fetch_tree`, but lets the caller leave out hidden query bookmarks.
+/// Note that the positions of the remaining items aren't adjusted, so
+/// callers shouldn't use indices in the returned tree as positions.
+pub fn fetch_tree_with_options(
+    db: &PlacesDb,
+    item_guid: &SyncGuid,
+    options: &FetchTreeOptions,
+) -> Result<Option<BookmarkTreeNode>> {
     // XXX - this needs additional work for tags - unlike desktop, there's no
     // "tags" folder, but instead a couple of tables to join on.
-    let sql = r#"
+    let sql = format!(
+        r#"
         WITH RECURSIVE
         descendants(fk, level, type, id, guid, parent, parentGuid, position,
                     title, dateAdded, lastModified) AS (
@@ -1137,7 +1708,7 @@ pub fn fetch_tree(db: &PlacesDb, item_guid: &SyncGuid) -> Result<Option<Bookmark
           FROM moz_bookmarks b2
           JOIN descendants ON b2.parent = descendants.id) -- AND b2.id <> :tags_folder)
         SELECT d.level, d.id, d.guid, d.parent, d.parentGuid, d.type,
-               d.position, NULLIF(d.title, '') AS title, d.dateAdded,
+               d.position, NULLIF({title}, '') AS title, d.dateAdded,
                d.lastModified, h.url
 --               (SELECT icon_url FROM moz_icons i
 --                      JOIN moz_icons_to_pages ON icon_id = i.id
@@ -1158,14 +1729,25 @@ pub fn fetch_tree(db: &PlacesDb, item_guid: &SyncGuid) -> Result<Option<Bookmark
         FROM descendants d
         LEFT JOIN moz_bookmarks b3 ON b3.id = d.parent
         LEFT JOIN moz_places h ON h.id = d.fk
-        ORDER BY d.level, d.parent, d.position"#;
+        WHERE :include_hidden_queries OR
+              d.level = 0 OR
+              NOT EXISTS(SELECT 1 FROM moz_bookmarks_hidden_queries q
+                         WHERE q.bookmarkId = d.id)
+        ORDER BY d.level, d.parent, d.position"#,
+        title = super::display_title_fragment("d.title"),
+    );
 
     let scope = db.begin_interrupt_scope();
 
-    let mut stmt = db.conn().prepare(sql)?;
+    let mut stmt = db.conn().prepare(&sql)?;
 
-    let mut results =
-        stmt.query_and_then_named(&[(":item_guid", item_guid)], FetchedTreeRow::from_row)?;
+    let mut results = stmt.query_and_then_named(
+        &[
+            (":item_guid", item_guid),
+            (":include_hidden_queries", &options.include_hidden_queries),
+        ],
+        FetchedTreeRow::from_row,
+    )?;
 
     // The first row in the result set is always the root of our tree.
     let mut root = match results.next() {
@@ -1330,10 +1912,12 @@ pub(crate) fn get_raw_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<
     )?)
 }
 
+/// Returns all bookmarks for a URL, most recently modified first.
 fn get_raw_bookmarks_for_url(db: &PlacesDb, url: &Url) -> Result<Vec<RawBookmark>> {
     Ok(db.query_rows_into_cached(
         &format!(
-            "{} WHERE h.url_hash = hash(:url) AND h.url = :url",
+            "{} WHERE h.url_hash = hash(:url) AND h.url = :url
+             ORDER BY b.lastModified DESC, b.id DESC",
             RAW_BOOKMARK_SQL
         ),
         &[(":url", &url.as_str())],
@@ -1354,9 +1938,10 @@ fn get_raw_bookmarks_with_parent(db: &impl ConnExt, parent: RowId) -> Result<Vec
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::test::new_mem_connection;
     use crate::db::PlacesDb;
-    use crate::tests::{assert_json_tree, insert_json_tree};
+    use crate::storage::deletion_log::{get_deletion_log, DeletionLogEntry};
+    use crate::testing::{assert_json_tree, insert_json_tree, new_mem_connection};
+    use crate::types::FrozenClock;
     use pretty_assertions::assert_eq;
     use rusqlite::NO_PARAMS;
     use serde_json::Value;
@@ -1386,11 +1971,21 @@ mod tests {
             guid: None,
             url: url.clone(),
             title: Some("the title".into()),
+            page_title: None,
         });
-        let guid = insert_bookmark(&conn, &bm)?;
+        let inserted = insert_bookmark(&conn, &bm)?;
 
         // re-fetch it.
-        let rb = get_raw_bookmark(&conn, &guid)?.expect("should get the bookmark");
+        let rb = get_raw_bookmark(&conn, &inserted.guid)?.expect("should get the bookmark");
+
+        assert_eq!(inserted.row_id, rb.row_id);
+        assert_eq!(inserted.position, rb.position);
+        assert_eq!(inserted.date_added, rb.date_added);
+        assert_eq!(inserted.last_modified, rb.date_modified);
+        let page = fetch_page_info(&conn, &url)?
+            .expect("should have the place")
+            .page;
+        assert_eq!(inserted.place_guid, Some(page.guid));
 
         assert!(rb.place_id.is_some());
         assert_eq!(rb.bookmark_type, BookmarkType::Bookmark);
@@ -1409,6 +2004,74 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_insert_separator_and_folder() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        conn.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)
+            .expect("should work");
+
+        let date_added = Timestamp(1_550_000_000_000);
+        let last_modified = Timestamp(1_550_000_100_000);
+        let sep = InsertableItem::Separator(InsertableSeparator {
+            parent_guid: BookmarkRootGuid::Menu.into(),
+            position: BookmarkPosition::Append,
+            date_added: Some(date_added),
+            last_modified: Some(last_modified),
+            guid: Some("separatorAAA".into()),
+        });
+        let inserted_sep = insert_bookmark(&conn, &sep)?;
+        assert_eq!(inserted_sep.guid, SyncGuid::from("separatorAAA"));
+
+        let folder = InsertableItem::Folder(InsertableFolder {
+            parent_guid: BookmarkRootGuid::Menu.into(),
+            position: BookmarkPosition::Specific(0),
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            title: Some("the folder".into()),
+        });
+        let inserted_folder = insert_bookmark(&conn, &folder)?;
+
+        let rb = get_raw_bookmark(&conn, &inserted_sep.guid)?.expect("should get the separator");
+        assert_eq!(rb.row_id, inserted_sep.row_id);
+        assert_eq!(rb.place_id, None);
+        assert_eq!(rb.bookmark_type, BookmarkType::Separator);
+        assert_eq!(rb.parent_guid.unwrap(), BookmarkRootGuid::Menu);
+        // Moved down by the folder.
+        assert_eq!(rb.position, 1);
+        assert_eq!(rb.title, None);
+        assert_eq!(rb.url, None);
+        assert_eq!(rb.date_added, date_added);
+        assert_eq!(rb.date_modified, last_modified);
+        assert_eq!(rb.sync_status, SyncStatus::New);
+        assert_eq!(rb.sync_change_counter, 1);
+        assert_eq!(rb.child_count, 0);
+
+        let rb = get_raw_bookmark(&conn, &inserted_folder.guid)?.expect("should get the folder");
+        assert_eq!(rb.row_id, inserted_folder.row_id);
+        assert_eq!(rb.place_id, None);
+        assert_eq!(rb.bookmark_type, BookmarkType::Folder);
+        assert_eq!(rb.parent_guid.unwrap(), BookmarkRootGuid::Menu);
+        assert_eq!(rb.position, 0);
+        assert_eq!(rb.title, Some("the folder".into()));
+        assert_eq!(rb.url, None);
+        assert_eq!(rb.date_added, inserted_folder.date_added);
+        assert_eq!(rb.date_modified, inserted_folder.last_modified);
+        assert!(rb.date_modified >= rb.date_added);
+        assert_eq!(rb.sync_status, SyncStatus::New);
+        assert_eq!(rb.sync_change_counter, 1);
+        assert_eq!(rb.child_count, 0);
+
+        let menu = get_raw_bookmark(&conn, &BookmarkRootGuid::Menu.as_guid())?
+            .expect("should get the menu");
+        assert_eq!(menu.child_count, 2);
+        assert_eq!(menu.sync_change_counter, 2);
+
+        Ok(())
+    }
+
     #[test]
     fn test_insert_titles() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1423,8 +2086,9 @@ mod tests {
             guid: None,
             url: url.clone(),
             title: Some("".into()),
+            page_title: None,
         });
-        let guid = insert_bookmark(&conn, &bm)?;
+        let guid = insert_bookmark(&conn, &bm)?.guid;
         let rb = get_raw_bookmark(&conn, &guid)?.expect("should get the bookmark");
         assert_eq!(rb.title, None);
 
@@ -1436,13 +2100,107 @@ mod tests {
             guid: None,
             url: url.clone(),
             title: None,
+            page_title: None,
         });
-        let guid2 = insert_bookmark(&conn, &bm2)?;
+        let guid2 = insert_bookmark(&conn, &bm2)?.guid;
         let rb2 = get_raw_bookmark(&conn, &guid2)?.expect("should get the bookmark");
         assert_eq!(rb2.title, None);
         Ok(())
     }
 
+    #[test]
+    fn test_insert_page_title() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com")?;
+
+        let bm = InsertableItem::Bookmark(InsertableBookmark {
+            parent_guid: BookmarkRootGuid::Unfiled.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            url: url.clone(),
+            title: Some("the bookmark title".into()),
+            page_title: Some("the page title".into()),
+        });
+        let guid = insert_bookmark(&conn, &bm)?.guid;
+        let rb = get_raw_bookmark(&conn, &guid)?.expect("should get the bookmark");
+        assert_eq!(rb.title, Some("the bookmark title".into()));
+
+        let page = fetch_page_info(&conn, &url)?
+            .expect("should create the place")
+            .page;
+        assert_eq!(page.title, "the page title");
+        assert_eq!(page.hidden, false);
+
+        // Embedders pass the page title over the FFI.
+        let other_url = Url::parse("https://www.example.com/other")?;
+        let node = crate::msg_types::BookmarkNode {
+            node_type: Some(BookmarkType::Bookmark as i32),
+            parent_guid: Some(BookmarkRootGuid::Unfiled.as_guid().0),
+            url: Some(other_url.to_string()),
+            title: Some("the other bookmark title".into()),
+            page_title: Some("the other page title".into()),
+            ..Default::default()
+        };
+        insert_bookmark(&conn, &node.into_insertable()?)?;
+        let page = fetch_page_info(&conn, &other_url)?
+            .expect("should create the place")
+            .page;
+        assert_eq!(page.title, "the other page title");
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_existing_page_title() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com")?;
+
+        // A hidden place with a visit, and a hidden place without one.
+        let visited = new_page_info(&conn, &url, None)?;
+        let unvisited_url = Url::parse("https://www.example.com/unvisited")?;
+        let unvisited = new_page_info(&conn, &unvisited_url, None)?;
+        conn.execute_batch("UPDATE moz_places SET title = 'original', hidden = 1")?;
+        conn.execute_named(
+            "UPDATE moz_places SET visit_count_local = 1 WHERE id = :id",
+            &[(":id", &visited.row_id)],
+        )?;
+
+        for url in &[&url, &unvisited_url] {
+            insert_bookmark(
+                &conn,
+                &InsertableBookmark {
+                    parent_guid: BookmarkRootGuid::Unfiled.into(),
+                    position: BookmarkPosition::Append,
+                    date_added: None,
+                    last_modified: None,
+                    guid: None,
+                    url: (*url).clone(),
+                    title: None,
+                    page_title: Some("new title".into()),
+                }
+                .into(),
+            )?;
+        }
+
+        let page = fetch_page_info(&conn, &url)?
+            .expect("should have the place")
+            .page;
+        assert_eq!(page.row_id, visited.row_id);
+        assert_eq!(page.title, "original");
+        assert_eq!(page.hidden, false, "visited pages should be unhidden");
+
+        let page = fetch_page_info(&conn, &unvisited_url)?
+            .expect("should have the place")
+            .page;
+        assert_eq!(page.row_id, unvisited.row_id);
+        assert_eq!(page.title, "original");
+        assert_eq!(page.hidden, true, "unvisited pages should stay hidden");
+        Ok(())
+    }
+
     #[test]
     fn test_delete() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1480,7 +2238,7 @@ mod tests {
             ]
         });
 
-        insert_json_tree(&conn, jtree);
+        insert_json_tree(&conn, jtree).expect("should insert tree");
 
         // Make sure the positions are correct now.
         assert_eq!(get_pos(&conn, &guid1), 0);
@@ -1497,6 +2255,360 @@ mod tests {
         assert_eq!(get_pos(&conn, &guid1), 0);
         assert_eq!(get_pos(&conn, &guid3), 1);
 
+        // The deletion log should count the folder and its child.
+        let log = get_deletion_log(&conn, Timestamp(0))?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].op, DeletionOp::DeleteBookmark);
+        assert_eq!(log[0].count, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_sync_changes() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    {
+                        "guid": "folderAAAAAA",
+                        "title": "A",
+                        "children": [
+                            {
+                                "guid": "bookmarkBBBB",
+                                "title": "B",
+                                "url": "https://www.example.com/b",
+                            },
+                            {
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "https://www.example.com/c",
+                            },
+                        ],
+                    },
+                    {
+                        "guid": "bookmarkDDDD",
+                        "title": "D",
+                        "url": "https://www.example.com/d",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend we synced everything except C.
+        conn.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET
+               syncStatus = {normal},
+               syncChangeCounter = 0,
+               lastModified = 0;
+             UPDATE moz_bookmarks SET syncStatus = {new}
+             WHERE guid = 'bookmarkCCCC';",
+            normal = SyncStatus::Normal as u8,
+            new = SyncStatus::New as u8,
+        ))?;
+
+        let now = Timestamp(1_550_000_000_000);
+        conn.set_clock(FrozenClock::at(now))?;
+        delete_bookmark(&conn, &"folderAAAAAA".into())?;
+
+        // D moves into A's position.
+        assert_eq!(get_pos(&conn, &"bookmarkDDDD".into()), 0);
+
+        // We should write tombstones for A and B, but not C, since we never
+        // synced it.
+        let tombstones = conn.query_rows_and_then_named(
+            "SELECT guid FROM moz_bookmarks_deleted ORDER BY guid",
+            &[],
+            |row| row.get::<_, String>(0),
+        )?;
+        assert_eq!(tombstones, vec!["bookmarkBBBB", "folderAAAAAA"]);
+
+        // Unfiled's children changed, so we should bump its change counter
+        // and last modified time, and the root's last modified time.
+        let unfiled = get_raw_bookmark(&conn, &BookmarkRootGuid::Unfiled.as_guid())?
+            .expect("Should fetch unfiled");
+        assert_eq!(unfiled.sync_change_counter, 1);
+        assert_eq!(unfiled.date_modified, now);
+        let root = get_raw_bookmark(&conn, &BookmarkRootGuid::Root.as_guid())?
+            .expect("Should fetch the root");
+        assert_eq!(root.sync_change_counter, 0);
+        assert_eq!(root.date_modified, now);
+        let d = get_raw_bookmark(&conn, &"bookmarkDDDD".into())?.expect("Should fetch D");
+        assert_eq!(d.sync_change_counter, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_erase_folder_children() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let mobile = &BookmarkRootGuid::Mobile.as_guid();
+
+        // Erasing an empty folder is a no-op.
+        erase_folder_children(&conn, mobile)?;
+        let mobile_info = get_raw_bookmark(&conn, mobile)?.expect("Should fetch mobile");
+        assert_eq!(mobile_info.sync_change_counter, 1);
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": mobile,
+                "children": [
+                    {
+                        "guid": "folderAAAAAA",
+                        "title": "A",
+                        "children": [
+                            {
+                                "guid": "bookmarkBBBB",
+                                "title": "B",
+                                "url": "https://www.example.com/b",
+                            },
+                            {
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "https://www.example.com/c",
+                            },
+                        ],
+                    },
+                    {
+                        "guid": "bookmarkDDDD",
+                        "title": "D",
+                        "url": "https://www.example.com/d",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend we synced everything except C.
+        conn.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET
+               syncStatus = {normal},
+               syncChangeCounter = 0,
+               lastModified = 0;
+             UPDATE moz_bookmarks SET syncStatus = {new}
+             WHERE guid = 'bookmarkCCCC';",
+            normal = SyncStatus::Normal as u8,
+            new = SyncStatus::New as u8,
+        ))?;
+
+        let now = Timestamp(1_550_000_000_000);
+        conn.set_clock(FrozenClock::at(now))?;
+        erase_folder_children(&conn, mobile)?;
+
+        assert_json_tree(
+            &conn,
+            mobile,
+            json!({
+                "guid": mobile,
+                "children": [],
+            }),
+        );
+
+        // We should write tombstones for A, B, and D, but not C, since we
+        // never synced it.
+        let tombstones = conn.query_rows_and_then_named(
+            "SELECT guid FROM moz_bookmarks_deleted ORDER BY guid",
+            &[],
+            |row| row.get::<_, String>(0),
+        )?;
+        assert_eq!(
+            tombstones,
+            vec!["bookmarkBBBB", "bookmarkDDDD", "folderAAAAAA"]
+        );
+
+        let mobile_info = get_raw_bookmark(&conn, mobile)?.expect("Should fetch mobile");
+        assert_eq!(mobile_info.sync_change_counter, 1);
+        assert_eq!(mobile_info.date_modified, now);
+        assert_eq!(mobile_info.child_count, 0);
+
+        assert_eq!(
+            get_deletion_log(&conn, Timestamp(0))?,
+            vec![DeletionLogEntry {
+                op: DeletionOp::EraseFolderChildren,
+                at: now,
+                count: 4,
+                initiator: DeletionInitiator::Api,
+            }]
+        );
+
+        // Only folders have children to erase.
+        erase_folder_children(&conn, &BookmarkRootGuid::Root.as_guid())
+            .expect_err("can't erase the Places root");
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": mobile,
+                "children": [{
+                    "guid": "bookmarkEEEE",
+                    "url": "https://www.example.com/e",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        erase_folder_children(&conn, &"bookmarkEEEE".into())
+            .expect_err("can't erase the children of a bookmark");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_folder_change_token() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let unfiled = &BookmarkRootGuid::Unfiled.as_guid();
+        let toolbar = &BookmarkRootGuid::Toolbar.as_guid();
+        let folder_a = &SyncGuid::from("folderAAAAAA");
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "guid": folder_a,
+                    "title": "A",
+                    "children": [
+                        {
+                            "guid": "bookmarkBBBB",
+                            "title": "B",
+                            "url": "https://www.example.com/b",
+                        },
+                        {
+                            "guid": "folderCCCCCC",
+                            "title": "C",
+                            "children": [{
+                                "guid": "bookmarkDDDD",
+                                "title": "D",
+                                "url": "https://www.example.com/d",
+                            }],
+                        },
+                    ],
+                }],
+            }),
+        )
+        .expect("should insert tree");
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": toolbar,
+                "children": [{
+                    "guid": "bookmarkEEEE",
+                    "title": "E",
+                    "url": "https://www.example.com/e",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        // Runs `f`, and checks that it changes the token for A, but not for
+        // the toolbar.
+        let assert_changes_a = |f: &dyn Fn() -> Result<()>| -> Result<()> {
+            let token = get_folder_change_token(&conn, folder_a)?;
+            let toolbar_token = get_folder_change_token(&conn, toolbar)?;
+            assert!(!folder_changed_since(&conn, folder_a, &token)?);
+            f()?;
+            assert!(folder_changed_since(&conn, folder_a, &token)?);
+            assert!(!folder_changed_since(&conn, toolbar, &toolbar_token)?);
+            Ok(())
+        };
+
+        // Adding a descendant.
+        assert_changes_a(&|| {
+            insert_bookmark(
+                &conn,
+                &InsertableBookmark {
+                    parent_guid: "folderCCCCCC".into(),
+                    position: BookmarkPosition::Append,
+                    date_added: Some(Timestamp(0)),
+                    last_modified: Some(Timestamp(0)),
+                    guid: Some("bookmarkFFFF".into()),
+                    url: Url::parse("https://www.example.com/f")?,
+                    title: Some("F".into()),
+                    page_title: None,
+                }
+                .into(),
+            )?;
+            Ok(())
+        })?;
+
+        // Retitling a descendant.
+        assert_changes_a(&|| {
+            update_bookmark(
+                &conn,
+                &"bookmarkDDDD".into(),
+                &UpdatableBookmark {
+                    title: Some("D2".into()),
+                    ..Default::default()
+                }
+                .into(),
+            )
+        })?;
+
+        // Moving an item out, and back in.
+        assert_changes_a(&|| {
+            move_bookmark(
+                &conn,
+                &"bookmarkBBBB".into(),
+                unfiled,
+                BookmarkPosition::Append,
+            )
+        })?;
+        assert_changes_a(&|| {
+            move_bookmark(
+                &conn,
+                &"bookmarkBBBB".into(),
+                folder_a,
+                BookmarkPosition::Append,
+            )
+        })?;
+
+        // Deleting a descendant.
+        assert_changes_a(&|| {
+            delete_bookmark(&conn, &"folderCCCCCC".into())?;
+            Ok(())
+        })?;
+
+        // Changing an item outside of A shouldn't change its token.
+        let token = get_folder_change_token(&conn, folder_a)?;
+        update_bookmark(
+            &conn,
+            &"bookmarkEEEE".into(),
+            &UpdatableBookmark {
+                title: Some("E2".into()),
+                ..Default::default()
+            }
+            .into(),
+        )?;
+        assert!(!folder_changed_since(&conn, folder_a, &token)?);
+
+        // Neither is a change to just the Sync bookkeeping.
+        conn.execute(
+            "UPDATE moz_bookmarks SET syncChangeCounter = 0, syncStatus = 2",
+            NO_PARAMS,
+        )?;
+        assert!(!folder_changed_since(&conn, folder_a, &token)?);
+
+        // Changes that keep the modification time are still changes.
+        let token = get_folder_change_token(&conn, folder_a)?;
+        conn.execute(
+            "UPDATE moz_bookmarks SET title = 'B2'
+             WHERE guid = 'bookmarkBBBB'",
+            NO_PARAMS,
+        )?;
+        assert!(folder_changed_since(&conn, folder_a, &token)?);
+
+        // Deleting the folder itself counts as a change.
+        delete_bookmark(&conn, folder_a)?;
+        assert!(folder_changed_since(&conn, folder_a, &token)?);
+        assert!(get_folder_change_token(&conn, folder_a).is_err());
+
+        // Only folders have tokens.
+        assert!(get_folder_change_token(&conn, &"bookmarkEEEE".into()).is_err());
+
         Ok(())
     }
 
@@ -1505,9 +2617,147 @@ mod tests {
         let _ = env_logger::try_init();
         let conn = new_mem_connection();
 
-        delete_bookmark(&conn, &BookmarkRootGuid::Root.into()).expect_err("can't delete root");
-        delete_bookmark(&conn, &BookmarkRootGuid::Unfiled.into())
-            .expect_err("can't delete any root");
+        for &root in &[
+            BookmarkRootGuid::Root,
+            BookmarkRootGuid::Menu,
+            BookmarkRootGuid::Toolbar,
+            BookmarkRootGuid::Unfiled,
+            BookmarkRootGuid::Mobile,
+        ] {
+            match delete_bookmark(&conn, &root.into()) {
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidGuid) => {}
+                    kind => panic!("Unexpected error kind: {:?}", kind),
+                },
+                Ok(()) => panic!("Shouldn't delete {:?}", root),
+            }
+            assert!(get_raw_bookmark(&conn, &root.as_guid())?.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_toggle_multiple() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com/")?;
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "https://www.example.com/"
+                    },
+                    {
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [
+                            {
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "https://www.example.com/"
+                            },
+                            {
+                                "guid": "bookmarkDDDD",
+                                "title": "D",
+                                "url": "https://www.example2.com/"
+                            }
+                        ]
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend everything's been synced, so that we write tombstones.
+        conn.execute("UPDATE moz_bookmarks SET syncStatus = 2", NO_PARAMS)?;
+
+        let result = toggle_bookmark(
+            &conn,
+            &url,
+            Some("the title".into()),
+            &BookmarkRootGuid::Mobile.as_guid(),
+        )?;
+        assert_eq!(
+            result,
+            ToggleResult {
+                now_bookmarked: false,
+                guid: None,
+            }
+        );
+        assert!(get_raw_bookmarks_for_url(&conn, &url)?.is_empty());
+        assert_eq!(get_pos(&conn, &"bookmarkDDDD".into()), 0);
+
+        let tombstones = conn.query_rows_and_then_named(
+            "SELECT guid FROM moz_bookmarks_deleted ORDER BY guid",
+            &[],
+            |row| -> rusqlite::Result<String> { row.get(0) },
+        )?;
+        assert_eq!(tombstones, vec!["bookmarkAAAA", "bookmarkCCCC"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_toggle_twice() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com/")?;
+        let mobile = BookmarkRootGuid::Mobile.as_guid();
+
+        let first = toggle_bookmark(&conn, &url, Some("the title".into()), &mobile)?;
+        assert!(first.now_bookmarked);
+        let guid = first.guid.expect("should insert a bookmark");
+        let rb = get_raw_bookmark(&conn, &guid)?.expect("should get the bookmark");
+        assert_eq!(rb.parent_guid, Some(mobile.clone()));
+        assert_eq!(rb.title, Some("the title".into()));
+        assert_eq!(rb.url, Some(url.clone()));
+
+        let second = toggle_bookmark(&conn, &url, Some("the title".into()), &mobile)?;
+        assert_eq!(
+            second,
+            ToggleResult {
+                now_bookmarked: false,
+                guid: None,
+            }
+        );
+        assert!(get_raw_bookmark(&conn, &guid)?.is_none());
+        assert!(get_raw_bookmarks_for_url(&conn, &url)?.is_empty());
+
+        // Toggling again should star the page again, with a new bookmark.
+        let third = toggle_bookmark(&conn, &url, None, &mobile)?;
+        assert!(third.now_bookmarked);
+        assert_ne!(third.guid, Some(guid));
+        assert_eq!(get_raw_bookmarks_for_url(&conn, &url)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_toggle_query() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let url = Url::parse("place:tag=foo")?;
+        let mobile = BookmarkRootGuid::Mobile.as_guid();
+
+        let first = toggle_bookmark(&conn, &url, Some("Foo".into()), &mobile)?;
+        assert!(first.now_bookmarked);
+        let guid = first.guid.expect("should insert a query");
+
+        // Toggling the query again should update it, not add another one.
+        let second = toggle_bookmark(&conn, &url, Some("Bar".into()), &mobile)?;
+        assert_eq!(
+            second,
+            ToggleResult {
+                now_bookmarked: true,
+                guid: Some(guid.clone()),
+            }
+        );
+        let queries = get_raw_bookmarks_for_url(&conn, &url)?;
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].guid, guid);
+        assert_eq!(queries[0].title, Some("Bar".into()));
         Ok(())
     }
 
@@ -1525,13 +2775,90 @@ mod tests {
             guid: None,
             url: url.clone(),
             title: Some("the title".into()),
+            page_title: None,
         });
-        let guid = insert_bookmark(&conn, &bm)?;
+        let inserted = insert_bookmark(&conn, &bm)?;
+
+        // re-fetch it.
+        let rb = get_raw_bookmark(&conn, &inserted.guid)?.expect("should get the bookmark");
+
+        assert_eq!(rb.position, 0, "large value should have been ignored");
+        assert_eq!(inserted.position, 0, "should report the clamped position");
+
+        let inserted2 = insert_bookmark(&conn, &bm)?;
+        let rb2 = get_raw_bookmark(&conn, &inserted2.guid)?.expect("should get the bookmark");
+        assert_eq!(rb2.position, 1);
+        assert_eq!(inserted2.position, 1, "should report the clamped position");
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_guids() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        let separator = |guid: &str| {
+            InsertableItem::Separator(InsertableSeparator {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: Some(guid.into()),
+            })
+        };
+
+        // Generated GUIDs are valid.
+        for _ in 0..10 {
+            assert!(is_valid_places_guid(SyncGuid::new().as_ref()));
+        }
+
+        for guid in &["tooShort", "waaaaaaaaytoolong", "bookmark=AAA", ""] {
+            match insert_bookmark(&conn, &separator(guid))
+                .expect_err("Should reject invalid GUID")
+                .kind()
+            {
+                ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidGuid) => {}
+                kind => panic!("Unexpected error inserting {:?}: {:?}", guid, kind),
+            }
+        }
 
-        // re-fetch it.
-        let rb = get_raw_bookmark(&conn, &guid)?.expect("should get the bookmark");
+        insert_bookmark(&conn, &separator("separatorAAA"))?;
+        match insert_bookmark(&conn, &separator("separatorAAA"))
+            .expect_err("Should reject duplicate GUID")
+            .kind()
+        {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::GuidAlreadyExists(guid)) => {
+                assert_eq!(guid, "separatorAAA")
+            }
+            kind => panic!("Unexpected error inserting duplicate: {:?}", kind),
+        }
+
+        // `insert_tree` validates GUIDs for all descendants, and doesn't
+        // insert anything if one is invalid.
+        let tree = FolderNode {
+            guid: Some(BookmarkRootGuid::Menu.into()),
+            children: vec![FolderNode {
+                guid: Some("folderAAAAAA".into()),
+                children: vec![SeparatorNode {
+                    guid: Some("bad".into()),
+                    date_added: None,
+                    last_modified: None,
+                }
+                .into()],
+                ..Default::default()
+            }
+            .into()],
+            ..Default::default()
+        };
+        match insert_tree(&conn, &tree)
+            .expect_err("Should reject invalid GUID in tree")
+            .kind()
+        {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidGuid) => {}
+            kind => panic!("Unexpected error inserting tree: {:?}", kind),
+        }
+        assert!(get_raw_bookmark(&conn, &"folderAAAAAA".into())?.is_none());
 
-        assert_eq!(rb.position, 0, "large value should have been ignored");
         Ok(())
     }
 
@@ -1587,7 +2914,8 @@ mod tests {
 
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
 
         // Move a bookmark to the end.
         do_move("bookmark2___", BookmarkPosition::Append);
@@ -1632,6 +2960,241 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_move_bookmark() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let unfiled = &BookmarkRootGuid::Unfiled.as_guid();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "url": "https://www.example1.com/"
+                    },
+                    {
+                        "guid": "folder1_____",
+                        "title": "A folder",
+                        "children": [
+                            {
+                                "guid": "bookmark2___",
+                                "url": "https://www.example2.com/"
+                            },
+                        ]
+                    },
+                    {
+                        "type": BookmarkType::Separator as u8,
+                        "guid": "separator1__",
+                    },
+                    {
+                        "guid": "bookmark3___",
+                        "url": "https://www.example3.com/"
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+        let counter = |guid: &str| {
+            get_raw_bookmark(&conn, &guid.into())
+                .expect("should work")
+                .expect("should exist")
+                .sync_change_counter
+        };
+        conn.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)?;
+
+        // Move forward within the same folder.
+        move_bookmark(
+            &conn,
+            &"bookmark1___".into(),
+            unfiled,
+            BookmarkPosition::Specific(2),
+        )?;
+        assert_json_tree(
+            &conn,
+            unfiled,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {"guid": "folder1_____", "children": [
+                        {"guid": "bookmark2___", "url": "https://www.example2.com/"},
+                    ]},
+                    {"guid": "separator1__", "type": BookmarkType::Separator as u8},
+                    {"guid": "bookmark1___", "url": "https://www.example1.com/"},
+                    {"guid": "bookmark3___", "url": "https://www.example3.com/"},
+                ]
+            }),
+        );
+        assert_eq!(counter("bookmark1___"), 1);
+        assert_eq!(counter(unfiled.as_str()), 1);
+        assert_eq!(counter("folder1_____"), 0);
+
+        // Move backward within the same folder.
+        move_bookmark(
+            &conn,
+            &"bookmark3___".into(),
+            unfiled,
+            BookmarkPosition::Specific(0),
+        )?;
+        assert_json_tree(
+            &conn,
+            unfiled,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {"guid": "bookmark3___", "url": "https://www.example3.com/"},
+                    {"guid": "folder1_____", "children": [
+                        {"guid": "bookmark2___", "url": "https://www.example2.com/"},
+                    ]},
+                    {"guid": "separator1__", "type": BookmarkType::Separator as u8},
+                    {"guid": "bookmark1___", "url": "https://www.example1.com/"},
+                ]
+            }),
+        );
+
+        // Appending within the same folder shouldn't leave a gap.
+        move_bookmark(
+            &conn,
+            &"bookmark3___".into(),
+            unfiled,
+            BookmarkPosition::Append,
+        )?;
+        assert_eq!(get_pos(&conn, &"bookmark3___".into()), 3);
+
+        // Move a separator to a different folder, past the end.
+        move_bookmark(
+            &conn,
+            &"separator1__".into(),
+            &"folder1_____".into(),
+            BookmarkPosition::Specific(10),
+        )?;
+        assert_json_tree(
+            &conn,
+            unfiled,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {"guid": "folder1_____", "children": [
+                        {"guid": "bookmark2___", "url": "https://www.example2.com/"},
+                        {"guid": "separator1__", "type": BookmarkType::Separator as u8},
+                    ]},
+                    {"guid": "bookmark1___", "url": "https://www.example1.com/"},
+                    {"guid": "bookmark3___", "url": "https://www.example3.com/"},
+                ]
+            }),
+        );
+        assert_eq!(counter("separator1__"), 1);
+        assert_eq!(counter(unfiled.as_str()), 4);
+        assert_eq!(counter("folder1_____"), 1);
+
+        // A folder can't move into itself, or into a non-folder.
+        move_bookmark(
+            &conn,
+            &"folder1_____".into(),
+            &"folder1_____".into(),
+            BookmarkPosition::Append,
+        )
+        .expect_err("can't move a folder into itself");
+        move_bookmark(
+            &conn,
+            &"bookmark1___".into(),
+            &"bookmark3___".into(),
+            BookmarkPosition::Append,
+        )
+        .expect_err("can't move into a bookmark");
+
+        // Only the user content roots can hold items, and roots can't move.
+        move_bookmark(
+            &conn,
+            &"bookmark1___".into(),
+            &BookmarkRootGuid::Root.as_guid(),
+            BookmarkPosition::Append,
+        )
+        .expect_err("can't move into the Places root");
+        move_bookmark(
+            &conn,
+            unfiled,
+            &BookmarkRootGuid::Menu.as_guid(),
+            BookmarkPosition::Append,
+        )
+        .expect_err("can't move a root");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_bookmark_children() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+        let unfiled = &BookmarkRootGuid::Unfiled.as_guid();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {"guid": "bookmark1___", "url": "https://www.example1.com/"},
+                    {"guid": "bookmark2___", "url": "https://www.example2.com/"},
+                    {"guid": "bookmark3___", "url": "https://www.example3.com/"},
+                    {"guid": "bookmark4___", "url": "https://www.example4.com/"},
+                    {"guid": "bookmark5___", "url": "https://www.example5.com/"},
+                ]
+            }),
+        )
+        .expect("should insert tree");
+        conn.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)?;
+
+        // Bookmarks 1 and 3 aren't mentioned, so they should keep their
+        // relative order at the end. Unknown and duplicate GUIDs are ignored.
+        set_bookmark_children(
+            &conn,
+            unfiled,
+            &[
+                "bookmark5___".into(),
+                "bookmark2___".into(),
+                "nonexistent_".into(),
+                "bookmark4___".into(),
+                "bookmark5___".into(),
+            ],
+        )?;
+        for (guid, position) in &[
+            ("bookmark5___", 0),
+            ("bookmark2___", 1),
+            ("bookmark4___", 2),
+            ("bookmark1___", 3),
+            ("bookmark3___", 4),
+        ] {
+            assert_eq!(get_pos(&conn, &(*guid).into()), *position, "{}", guid);
+        }
+        let counter = |guid: &SyncGuid| {
+            get_raw_bookmark(&conn, guid)
+                .expect("should work")
+                .expect("should exist")
+                .sync_change_counter
+        };
+        assert_eq!(counter(unfiled), 1);
+        assert_eq!(counter(&"bookmark5___".into()), 0);
+
+        // Reordering to the same order is a no-op.
+        set_bookmark_children(&conn, unfiled, &["bookmark5___".into()])?;
+        assert_eq!(counter(unfiled), 1);
+
+        // Only folders have children.
+        match set_bookmark_children(&conn, &"bookmark1___".into(), &[]) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidParent(guid)) => {
+                    assert_eq!(guid.as_str(), "bookmark1___")
+                }
+                kind => panic!("Unexpected error kind: {:?}", kind),
+            },
+            Ok(_) => panic!("Shouldn't reorder the children of a bookmark"),
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_update() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1682,7 +3245,8 @@ mod tests {
 
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
 
         update_bookmark(
             &conn,
@@ -1798,7 +3362,8 @@ mod tests {
                     },
                 ],
             }),
-        );
+        )
+        .expect("should insert tree");
 
         conn.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)
             .expect("should work");
@@ -1923,7 +3488,8 @@ mod tests {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
 
         // reset all statuses and timestamps.
         conn.execute(
@@ -2014,7 +3580,8 @@ mod tests {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
         // Update an item that doesn't exist.
         update_bookmark(
             &conn,
@@ -2055,6 +3622,101 @@ mod tests {
             .into(),
         )
         .expect_err("can't move to the root");
+
+        // A failed update shouldn't leave any of its changes behind. Moving
+        // the bookmark out of unfiled shifts its siblings before we find out
+        // that the new URL is too long.
+        let long_url = Url::parse(&format!(
+            "https://www.example.com/{}",
+            "a".repeat(crate::storage::URL_LENGTH_MAX)
+        ))?;
+        update_bookmark(
+            &conn,
+            &"bookmark1___".into(),
+            &UpdatableBookmark {
+                location: UpdateTreeLocation::Parent(
+                    BookmarkRootGuid::Menu.as_guid(),
+                    BookmarkPosition::Append,
+                ),
+                url: Some(long_url),
+                ..Default::default()
+            }
+            .into(),
+        )
+        .expect_err("can't update with a URL that's too long");
+        assert_eq!(get_pos(&conn, &"bookmark1___".into()), 0);
+        assert_eq!(get_pos(&conn, &"folder1_____".into()), 1);
+
+        // A folder into itself, or into one of its descendants.
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": "folder1_____",
+                "children": [
+                    {
+                        "guid": "folder2_____",
+                        "title": "A subfolder",
+                        "children": [],
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+        for new_parent_guid in &["folder1_____", "folder2_____"] {
+            match update_bookmark(
+                &conn,
+                &"folder1_____".into(),
+                &UpdatableFolder {
+                    location: UpdateTreeLocation::Parent(
+                        (*new_parent_guid).into(),
+                        BookmarkPosition::Append,
+                    ),
+                    ..Default::default()
+                }
+                .into(),
+            ) {
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidParent(guid)) => {
+                        assert_eq!(guid.as_str(), *new_parent_guid)
+                    }
+                    kind => panic!("Unexpected error kind: {:?}", kind),
+                },
+                Ok(_) => panic!("Shouldn't move a folder into {}", new_parent_guid),
+            }
+        }
+        assert_json_tree(
+            &conn,
+            &"folder1_____".into(),
+            json!({
+                "guid": "folder1_____",
+                "children": [
+                    {
+                        "guid": "bookmark2___",
+                        "title": "bookmark in A folder",
+                        "url": "https://www.example2.com/"
+                    },
+                    {
+                        "guid": "folder2_____",
+                        "title": "A subfolder",
+                        "children": [],
+                    },
+                ]
+            }),
+        );
+
+        // A folder can still be moved into a sibling.
+        update_bookmark(
+            &conn,
+            &"folder2_____".into(),
+            &UpdatableFolder {
+                location: UpdateTreeLocation::Parent(
+                    BookmarkRootGuid::Menu.as_guid(),
+                    BookmarkPosition::Append,
+                ),
+                ..Default::default()
+            }
+            .into(),
+        )?;
         Ok(())
     }
 
@@ -2074,6 +3736,118 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fetch_tree_round_trip() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        let date_added = Timestamp(1_550_000_000_000);
+        let last_modified = Timestamp(1_550_000_100_000);
+        let tree = FolderNode {
+            guid: Some(BookmarkRootGuid::Menu.into()),
+            children: vec![
+                FolderNode {
+                    guid: Some("folderAAAAAA".into()),
+                    date_added: Some(date_added),
+                    title: Some("A".into()),
+                    children: vec![
+                        BookmarkNode {
+                            guid: Some("bookmarkBBBB".into()),
+                            date_added: Some(date_added),
+                            last_modified: Some(last_modified),
+                            title: Some("B".into()),
+                            url: Url::parse("https://example.com/b")?,
+                        }
+                        .into(),
+                        SeparatorNode {
+                            guid: Some("separatorCCC".into()),
+                            date_added: Some(date_added),
+                            last_modified: Some(last_modified),
+                        }
+                        .into(),
+                        FolderNode {
+                            guid: Some("folderDDDDDD".into()),
+                            date_added: Some(date_added),
+                            title: Some("D".into()),
+                            children: vec![FolderNode {
+                                guid: Some("folderEEEEEE".into()),
+                                date_added: Some(date_added),
+                                last_modified: Some(last_modified),
+                                title: Some("E".into()),
+                                children: Vec::new(),
+                            }
+                            .into()],
+                            ..Default::default()
+                        }
+                        .into(),
+                    ],
+                    ..Default::default()
+                }
+                .into(),
+                SeparatorNode {
+                    guid: Some("separatorFFF".into()),
+                    date_added: Some(date_added),
+                    last_modified: Some(last_modified),
+                }
+                .into(),
+                FolderNode {
+                    guid: Some("folderGGGGGG".into()),
+                    date_added: Some(date_added),
+                    last_modified: Some(last_modified),
+                    title: Some("G".into()),
+                    children: Vec::new(),
+                }
+                .into(),
+                BookmarkNode {
+                    guid: Some("bookmarkHHHH".into()),
+                    date_added: Some(date_added),
+                    last_modified: Some(last_modified),
+                    title: None,
+                    url: Url::parse("https://example.com/h")?,
+                }
+                .into(),
+            ],
+            ..Default::default()
+        };
+        insert_tree(&conn, &tree)?;
+
+        let fetched =
+            fetch_tree(&conn, &BookmarkRootGuid::Menu.into())?.expect("Should fetch the menu");
+        assert_eq!(fetched, BookmarkTreeNode::Folder(tree));
+
+        // Every fetched node should have its GUID and timestamps.
+        fn assert_complete(node: &BookmarkTreeNode) {
+            let (guid, date_added, last_modified) = match node {
+                BookmarkTreeNode::Bookmark(b) => (&b.guid, b.date_added, b.last_modified),
+                BookmarkTreeNode::Separator(s) => (&s.guid, s.date_added, s.last_modified),
+                BookmarkTreeNode::Folder(f) => {
+                    for child in &f.children {
+                        assert_complete(child);
+                    }
+                    (&f.guid, f.date_added, f.last_modified)
+                }
+            };
+            assert!(guid.is_some(), "Missing GUID: {:?}", node);
+            assert!(date_added.is_some(), "Missing date added: {:?}", node);
+            assert!(last_modified.is_some(), "Missing last modified: {:?}", node);
+        }
+        assert_complete(&fetched);
+
+        // Fetching a subtree works too.
+        let subtree = fetch_tree(&conn, &"folderDDDDDD".into())?.expect("Should fetch D");
+        match &subtree {
+            BookmarkTreeNode::Folder(f) => {
+                assert_eq!(f.title.as_ref().map(String::as_str), Some("D"));
+                assert_eq!(f.children.len(), 1);
+                assert_eq!(f.children[0].guid(), &SyncGuid::from("folderEEEEEE"));
+            }
+            _ => panic!("D should be a folder: {:?}", subtree),
+        }
+        assert!(fetch_tree(&conn, &"nonexistent_".into())?.is_none());
+
+        Ok(())
+    }
+
     #[test]
     fn test_insert_tree() -> Result<()> {
         let _ = env_logger::try_init();
@@ -10,8 +10,8 @@ use super::{
 
 use crate::error::{InvalidPlaceInfo, Result};
 use crate::msg_types;
+use crate::storage::parse_href;
 use crate::types::{BookmarkType, SyncGuid};
-use url::Url;
 
 impl From<BookmarkTreeNode> for PublicNode {
     // TODO: Eventually this should either be a function that takes an
@@ -38,6 +38,7 @@ impl From<BookmarkTreeNode> for PublicNode {
             }
             BookmarkTreeNode::Folder(f) => {
                 result.title = f.title;
+                result.child_count = f.children.len() as u32;
                 let own_guid = &result.guid;
                 result.child_nodes = Some(
                     f.children
@@ -86,6 +87,7 @@ impl From<PublicNode> for msg_types::BookmarkNode {
                     .collect()
             }),
             have_child_nodes,
+            page_title: None,
         }
     }
 }
@@ -102,6 +104,7 @@ impl From<RawBookmark> for PublicNode {
             last_modified: rb.date_modified,
             url: rb.url,
             title: rb.title,
+            child_count: rb.child_count,
             child_guids: None,
             child_nodes: None,
         }
@@ -178,12 +181,13 @@ impl msg_types::BookmarkNode {
                 parent_guid,
                 position,
                 title: self.title,
-                // This will fail if Url is empty, but with a url parse error,
-                // which is what we want.
-                url: Url::parse(&self.url.unwrap_or_default())?,
+                // This will fail if Url is empty, but with a malformed URL
+                // error, which is what we want.
+                url: parse_href(&self.url.unwrap_or_default())?,
                 guid: None,
                 date_added: None,
                 last_modified: None,
+                page_title: self.page_title,
             }),
             BookmarkType::Separator => InsertableItem::Separator(InsertableSeparator {
                 parent_guid,
@@ -254,7 +258,7 @@ impl BookmarkUpdateInfo {
             BookmarkType::Bookmark => UpdatableItem::Bookmark(UpdatableBookmark {
                 location,
                 title: self.title,
-                url: self.url.map(|u| Url::parse(&u)).transpose()?,
+                url: self.url.map(|u| parse_href(&u)).transpose()?,
             }),
             BookmarkType::Separator => UpdatableItem::Separator(UpdatableSeparator { location }),
             BookmarkType::Folder => UpdatableItem::Folder(UpdatableFolder {
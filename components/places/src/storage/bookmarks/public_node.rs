@@ -7,7 +7,9 @@ use crate::msg_types::BookmarkNode as ProtoBookmark;
 use sql_support::SqlInterruptScope;
 
 /// This type basically exists to become a msg_types::BookmarkNode, but is
-/// slightly less of a pain to deal with in rust.
+/// slightly less of a pain to deal with in rust. It's also the public shape
+/// of a single bookmark item for embedders, so it leaves out local row IDs
+/// and Sync metadata; the crate uses `RawBookmark` for those.
 #[derive(Debug, Clone)]
 pub struct PublicNode {
     pub node_type: BookmarkType,
@@ -19,6 +21,8 @@ pub struct PublicNode {
     pub last_modified: Timestamp,
     pub url: Option<Url>,
     pub title: Option<String>,
+    /// The number of children in a folder. Always 0 for other types.
+    pub child_count: u32,
     pub child_guids: Option<Vec<SyncGuid>>,
     pub child_nodes: Option<Vec<PublicNode>>,
 }
@@ -36,6 +40,7 @@ impl Default for PublicNode {
             last_modified: Timestamp(0),
             url: None,
             title: None,
+            child_count: 0,
             child_guids: None,
             child_nodes: None,
         }
@@ -45,6 +50,7 @@ impl Default for PublicNode {
 impl PartialEq for PublicNode {
     fn eq(&self, other: &PublicNode) -> bool {
         // Compare everything except date_added and last_modified.
+        // `child_count` is implied by the children, if we have them.
         self.node_type == other.node_type
             && self.guid == other.guid
             && self.parent_guid == other.parent_guid
@@ -54,6 +60,8 @@ impl PartialEq for PublicNode {
     }
 }
 
+/// Returns all bookmarks for a URL, most recently modified first. Returns an
+/// empty vec if the page isn't bookmarked.
 pub fn fetch_bookmarks_by_url(db: &PlacesDb, url: &Url) -> Result<Vec<PublicNode>> {
     let nodes = get_raw_bookmarks_for_url(db, url)?
         .into_iter()
@@ -72,6 +80,7 @@ pub fn fetch_bookmarks_by_url(db: &PlacesDb, url: &Url) -> Result<Vec<PublicNode
                 last_modified: rb.date_modified,
                 url: rb.url,
                 title: rb.title,
+                child_count: 0,
                 child_guids: None,
                 child_nodes: None,
             }
@@ -80,6 +89,29 @@ pub fn fetch_bookmarks_by_url(db: &PlacesDb, url: &Url) -> Result<Vec<PublicNode
     Ok(nodes)
 }
 
+/// Returns true if there's at least one bookmark for a URL. This is cheaper
+/// than `fetch_bookmarks_by_url` if you only need to know whether a page is
+/// bookmarked, like for the star button.
+pub fn bookmark_exists(db: &PlacesDb, url: &Url) -> Result<bool> {
+    Ok(db.query_row_and_then_named(
+        "SELECT EXISTS(
+           SELECT 1 FROM moz_bookmarks b
+           JOIN moz_places h ON h.id = b.fk
+           WHERE h.url_hash = hash(:url) AND h.url = :url
+         )",
+        &[(":url", &url.as_str())],
+        |row| row.get(0),
+        true,
+    )?)
+}
+
+/// Fetches a single item by GUID, without its children. This only runs a
+/// single query, so it's cheaper than `fetch_bookmark` if you don't need
+/// the children.
+pub fn get_bookmark(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<PublicNode>> {
+    Ok(get_raw_bookmark(db, guid)?.map(PublicNode::from))
+}
+
 /// This is similar to fetch_tree, but does not recursively fetch children of
 /// folders.
 ///
@@ -267,6 +299,7 @@ pub fn search_bookmarks(db: &PlacesDb, search: &str, limit: u32) -> Result<Vec<P
                     .get::<_, Option<String>>("url")?
                     .map(|href| url::Url::parse(&href))
                     .transpose()?,
+                child_count: 0,
                 child_guids: None,
                 child_nodes: None,
             })
@@ -283,7 +316,7 @@ lazy_static::lazy_static! {
             b.dateAdded,
             b.lastModified,
             -- Note we return null for titles with an empty string.
-            NULLIF(b.title, '') AS title,
+            NULLIF({title}, '') AS title,
             h.url AS url
         FROM moz_bookmarks b
         JOIN moz_bookmarks p ON p.id = b.parent
@@ -306,6 +339,7 @@ lazy_static::lazy_static! {
                 {search_bhvr}
             )
         LIMIT :limit",
+        title = crate::storage::display_title_fragment("b.title"),
         bookmark_type = BookmarkType::Bookmark as u8,
         match_bhvr = crate::match_impl::MatchBehavior::Anywhere as u32,
         search_bhvr = crate::match_impl::SearchBehavior::BOOKMARK.bits(),
@@ -314,8 +348,7 @@ lazy_static::lazy_static! {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::api::places_api::test::new_mem_connections;
-    use crate::tests::insert_json_tree;
+    use crate::testing::{insert_json_tree, new_mem_connections};
     use serde_json::json;
     #[test]
     fn test_get_by_url() -> Result<()> {
@@ -348,7 +381,8 @@ mod test {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
         let url = url::Url::parse("https://www.example2.com/a/b/c/d?q=v#abcde")?;
         let mut bmks = fetch_bookmarks_by_url(&conns.read, &url)?;
         bmks.sort_by_key(|b| b.guid.0.clone());
@@ -362,6 +396,7 @@ mod test {
                 url: Some(url.clone()),
                 parent_guid: Some(BookmarkRootGuid::Unfiled.into()),
                 position: 1,
+                child_count: 0,
                 child_guids: None,
                 child_nodes: None,
                 // Ignored by our PartialEq
@@ -378,6 +413,7 @@ mod test {
                 url: Some(url.clone()),
                 parent_guid: Some(BookmarkRootGuid::Unfiled.into()),
                 position: 3,
+                child_count: 0,
                 child_guids: None,
                 child_nodes: None,
                 // Ignored by our PartialEq
@@ -389,6 +425,73 @@ mod test {
         Ok(())
     }
     #[test]
+    fn test_get_by_url_order_and_exists() -> Result<()> {
+        let conns = new_mem_connections();
+        let _ = env_logger::try_init();
+        insert_json_tree(
+            &conns.write,
+            json!({
+                "guid": String::from(BookmarkRootGuid::Unfiled.as_str()),
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "url": "https://www.example1.com/",
+                        "title": "older",
+                    },
+                    {
+                        "guid": "bookmark2___",
+                        "url": "https://www.example1.com/",
+                        "title": "newer",
+                    },
+                    {
+                        "guid": "bookmark3___",
+                        "url": "https://www.example2.com/",
+                        "title": "other",
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+        conns.write.execute_named(
+            "UPDATE moz_bookmarks SET lastModified = :lastModified
+             WHERE guid = :guid",
+            &[(":lastModified", &Timestamp(2)), (":guid", &"bookmark1___")],
+        )?;
+        conns.write.execute_named(
+            "UPDATE moz_bookmarks SET lastModified = :lastModified
+             WHERE guid = :guid",
+            &[(":lastModified", &Timestamp(3)), (":guid", &"bookmark2___")],
+        )?;
+
+        let url = Url::parse("https://www.example1.com/")?;
+        let guids = fetch_bookmarks_by_url(&conns.read, &url)?
+            .into_iter()
+            .map(|b| b.guid)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            guids,
+            vec![SyncGuid::from("bookmark2___"), "bookmark1___".into()]
+        );
+        assert!(bookmark_exists(&conns.read, &url)?);
+
+        // A page with history, but no bookmarks.
+        let url = Url::parse("https://www.example3.com/")?;
+        crate::storage::history::apply_observation(
+            &conns.write,
+            crate::observation::VisitObservation::new(url.clone())
+                .with_visit_type(crate::types::VisitTransition::Link),
+        )?;
+        assert!(fetch_bookmarks_by_url(&conns.read, &url)?.is_empty());
+        assert!(!bookmark_exists(&conns.read, &url)?);
+
+        // A page we've never seen.
+        let url = Url::parse("https://www.example4.com/")?;
+        assert!(fetch_bookmarks_by_url(&conns.read, &url)?.is_empty());
+        assert!(!bookmark_exists(&conns.read, &url)?);
+
+        Ok(())
+    }
+    #[test]
     fn test_search() -> Result<()> {
         let conns = new_mem_connections();
         let _ = env_logger::try_init();
@@ -434,7 +537,8 @@ mod test {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
         let mut bmks = search_bookmarks(&conns.read, "ample", 10)?;
         bmks.sort_by_key(|b| b.guid.0.clone());
         assert_eq!(bmks.len(), 6);
@@ -498,7 +602,8 @@ mod test {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
 
         let root = fetch_bookmark(&conns.read, BookmarkRootGuid::Root.guid(), false)?.unwrap();
 
@@ -541,6 +646,66 @@ mod test {
         Ok(())
     }
     #[test]
+    fn test_get_bookmark() -> Result<()> {
+        let conns = new_mem_connections();
+        let _ = env_logger::try_init();
+
+        insert_json_tree(
+            &conns.write,
+            json!({
+                "guid": BookmarkRootGuid::Mobile.as_guid(),
+                "children": [
+                    {
+                        "guid": "folder1_____",
+                        "title": "A folder",
+                        "children": [
+                            {
+                                "guid": "bookmark1___",
+                                "title": "A bookmark",
+                                "url": "https://www.example1.com/"
+                            },
+                            {
+                                "type": BookmarkType::Separator as u8,
+                                "guid": "separator1__",
+                            },
+                        ]
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+
+        let folder = get_bookmark(&conns.read, &"folder1_____".into())?.unwrap();
+        assert_eq!(folder.node_type, BookmarkType::Folder);
+        assert_eq!(folder.parent_guid, Some(BookmarkRootGuid::Mobile.as_guid()));
+        assert_eq!(folder.position, 0);
+        assert_eq!(folder.title, Some("A folder".into()));
+        assert_eq!(folder.child_count, 2);
+        assert!(folder.child_guids.is_none());
+
+        let bookmark = get_bookmark(&conns.read, &"bookmark1___".into())?.unwrap();
+        assert_eq!(
+            bookmark,
+            PublicNode {
+                node_type: BookmarkType::Bookmark,
+                guid: "bookmark1___".into(),
+                parent_guid: Some("folder1_____".into()),
+                position: 0,
+                url: Some(Url::parse("https://www.example1.com/")?),
+                title: Some("A bookmark".into()),
+                ..PublicNode::default()
+            }
+        );
+        assert_eq!(bookmark.child_count, 0);
+
+        let separator = get_bookmark(&conns.read, &"separator1__".into())?.unwrap();
+        assert_eq!(separator.node_type, BookmarkType::Separator);
+        assert_eq!(separator.position, 1);
+
+        assert!(get_bookmark(&conns.read, &"nonexistent_".into())?.is_none());
+        Ok(())
+    }
+    #[test]
     fn test_fetch_tree() -> Result<()> {
         let conns = new_mem_connections();
         let _ = env_logger::try_init();
@@ -560,7 +725,8 @@ mod test {
                     },
                 ]
             }),
-        );
+        )
+        .expect("should insert tree");
 
         let root = fetch_public_tree(&conns.read, BookmarkRootGuid::Root.guid())?.unwrap();
         assert!(root.parent_guid.is_none());
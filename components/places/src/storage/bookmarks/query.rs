@@ -0,0 +1,654 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Parses and executes `place:` query URLs, like `place:tag=foo` and
+//! `place:folder=BOOKMARKS_MENU&excludeItems=1`. We only support a small
+//! subset of the params that Desktop does. Bookmark sync uses the same
+//! parser to fix up incoming queries.
+
+use super::*;
+use crate::storage::tags::get_urls_with_tag;
+use crate::valid_guid::is_valid_places_guid;
+
+// From Desktop's Ci.nsINavHistoryQueryOptions, but we define it as a str
+// as that's how we use it here.
+const RESULTS_AS_TAG_CONTENTS: &str = "7";
+
+/// A parsed `place:` query URL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlaceQuery {
+    pub tag: Option<String>,
+    pub folder: Option<SyncGuid>,
+    pub exclude_items: bool,
+    pub max_results: Option<usize>,
+    /// Whether the query has `type=7`, which Desktop uses for queries that
+    /// list the contents of its tag folders. Sync rewrites these as `tag=`
+    /// queries.
+    pub results_as_tag_contents: bool,
+    /// The names of params we don't support, in the order they appear.
+    pub unsupported: Vec<String>,
+}
+
+impl PlaceQuery {
+    /// Parses a query URL. Returns `None` if `url` isn't a `place:` URL.
+    pub fn from_url(url: &Url) -> Option<Self> {
+        if url.scheme() != "place" {
+            return None;
+        }
+        let mut query = PlaceQuery::default();
+        for (key, value) in query_params(url) {
+            match key.as_ref() {
+                "tag" if query.tag.is_none() && !value.is_empty() => {
+                    query.tag = Some(value.into_owned());
+                }
+                "folder" if query.folder.is_none() => match folder_guid(&value) {
+                    Some(guid) => query.folder = Some(guid),
+                    // Desktop also uses local row IDs, which are useless to
+                    // us.
+                    None => query.unsupported.push(key.into_owned()),
+                },
+                "excludeItems" => query.exclude_items |= value == "1",
+                "maxResults" => match value.parse() {
+                    Ok(max_results) => query.max_results = Some(max_results),
+                    Err(_) => query.unsupported.push(key.into_owned()),
+                },
+                "type" if value == RESULTS_AS_TAG_CONTENTS => {
+                    // We can't execute these until Sync rewrites them.
+                    query.results_as_tag_contents = true;
+                    query.unsupported.push(key.into_owned());
+                }
+                _ => query.unsupported.push(key.into_owned()),
+            }
+        }
+        if query.tag.is_some() && query.folder.is_some() {
+            // Desktop intersects these, but we don't.
+            query.unsupported.push("folder".into());
+        }
+        Some(query)
+    }
+
+    /// Returns true if the query has a `folder=` param, even one we don't
+    /// support.
+    pub fn has_folder(&self) -> bool {
+        self.folder.is_some() || self.unsupported.iter().any(|key| key == "folder")
+    }
+
+    pub fn kind(&self) -> PlaceQueryKind {
+        if !self.unsupported.is_empty() {
+            PlaceQueryKind::Unsupported
+        } else if self.tag.is_some() {
+            PlaceQueryKind::Tag
+        } else if self.folder.is_some() {
+            PlaceQueryKind::Folder
+        } else {
+            PlaceQueryKind::Unsupported
+        }
+    }
+}
+
+// The "params" are actually the path portion of the URL, so we can't use
+// `url.query_pairs()` here.
+fn query_params(url: &Url) -> url::form_urlencoded::Parse<'_> {
+    url::form_urlencoded::parse(url.path().as_bytes())
+}
+
+/// Returns a copy of the `place:` query URL with `excludeItems=1` appended.
+pub(crate) fn with_exclude_items(url: &Url) -> Result<Url> {
+    let params = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query_params(url))
+        .append_pair("excludeItems", "1")
+        .finish();
+    Ok(Url::parse(&format!("place:{}", params))?)
+}
+
+/// The kind of a `place:` query, for showing query bookmarks to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaceQueryKind {
+    /// A `tag=` query.
+    Tag,
+    /// A `folder=` query.
+    Folder,
+    /// A query that we can't execute.
+    Unsupported,
+}
+
+/// A `place:` query bookmark, from `get_query_bookmarks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryBookmark {
+    pub guid: SyncGuid,
+    pub title: Option<String>,
+    pub url: Url,
+    pub kind: PlaceQueryKind,
+    /// Whether the query is hidden on this device. See
+    /// `set_query_bookmark_hidden`.
+    pub hidden: bool,
+}
+
+/// Returns all `place:` query bookmarks. Desktop creates these for its
+/// "smart folders", which we can't show, so consumers can use this to let
+/// the user hide them.
+pub fn get_query_bookmarks(db: &PlacesDb) -> Result<Vec<QueryBookmark>> {
+    db.query_rows_and_then_named(
+        "SELECT b.guid, b.title, h.url,
+                EXISTS(SELECT 1 FROM moz_bookmarks_hidden_queries q
+                       WHERE q.bookmarkId = b.id) AS hidden
+         FROM moz_bookmarks b
+         JOIN moz_places h ON h.id = b.fk
+         WHERE b.type = :type AND
+               h.url_hash BETWEEN hash('place', 'prefix_lo') AND
+                                  hash('place', 'prefix_hi')
+         ORDER BY b.id",
+        &[(":type", &BookmarkType::Bookmark)],
+        |row| -> Result<_> {
+            let url = Url::parse(&row.get::<_, String>("url")?)?;
+            let kind = PlaceQuery::from_url(&url)
+                .map_or(PlaceQueryKind::Unsupported, |query| query.kind());
+            Ok(QueryBookmark {
+                guid: row.get("guid")?,
+                title: row.get("title")?,
+                url,
+                kind,
+                hidden: row.get("hidden")?,
+            })
+        },
+    )
+}
+
+/// Hides or shows a `place:` query bookmark on this device. Hidden queries
+/// are excluded from `fetch_tree_with_options`, unless the caller asks for
+/// them. Hiding is local-only: it doesn't change the bookmark's sync status
+/// or change counter, so the query stays on the server and other devices.
+/// Fails with `NoSuchGuid` if the bookmark doesn't exist, and `NotAQuery` if
+/// it isn't a `place:` query.
+pub fn set_query_bookmark_hidden(db: &PlacesDb, guid: &SyncGuid, hidden: bool) -> Result<()> {
+    let bookmark = get_raw_bookmark(db, guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(guid.to_string()))?;
+    match &bookmark.url {
+        Some(url) if url.scheme() == "place" => {}
+        _ => return Err(InvalidPlaceInfo::NotAQuery(guid.to_string()).into()),
+    }
+    if hidden {
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_bookmarks_hidden_queries(bookmarkId)
+             VALUES(:id)",
+            &[(":id", &bookmark.row_id)],
+        )?;
+    } else {
+        db.execute_named_cached(
+            "DELETE FROM moz_bookmarks_hidden_queries WHERE bookmarkId = :id",
+            &[(":id", &bookmark.row_id)],
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the GUID for a `folder=` param, which may be a GUID or one of
+/// Desktop's symbolic names for the roots.
+fn folder_guid(value: &str) -> Option<SyncGuid> {
+    let root = match value {
+        "PLACES_ROOT" => Some(BookmarkRootGuid::Root),
+        "BOOKMARKS_MENU" => Some(BookmarkRootGuid::Menu),
+        "TOOLBAR" => Some(BookmarkRootGuid::Toolbar),
+        "UNFILED_BOOKMARKS" => Some(BookmarkRootGuid::Unfiled),
+        "MOBILE_BOOKMARKS" => Some(BookmarkRootGuid::Mobile),
+        _ => None,
+    };
+    match root {
+        Some(root) => Some(root.as_guid()),
+        None if is_valid_places_guid(value) => Some(SyncGuid(value.into())),
+        None => None,
+    }
+}
+
+/// A result from executing a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryResult {
+    pub node_type: BookmarkType,
+    /// The GUID of the matching item. This is `None` for tag queries, which
+    /// match URLs instead of bookmarks.
+    pub guid: Option<SyncGuid>,
+    pub url: Option<Url>,
+    pub title: Option<String>,
+}
+
+/// Executes the query for the query bookmark with the given GUID. Returns at
+/// most `limit` results, or `maxResults` from the query if it's smaller.
+pub fn resolve_query_bookmark(
+    db: &PlacesDb,
+    guid: &SyncGuid,
+    limit: Option<usize>,
+) -> Result<Vec<QueryResult>> {
+    let bookmark = get_raw_bookmark(db, guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(guid.to_string()))?;
+    match bookmark.url {
+        Some(url) => resolve_query_url(db, &url, limit),
+        None => Err(InvalidPlaceInfo::MismatchedBookmarkType(
+            BookmarkType::Bookmark as u8,
+            bookmark.bookmark_type as u8,
+        )
+        .into()),
+    }
+}
+
+/// Executes a `place:` query URL. Fails with `UnsupportedQuery` if `url`
+/// isn't a `place:` URL, or has params we don't support.
+pub fn resolve_query_url(
+    db: &PlacesDb,
+    url: &Url,
+    limit: Option<usize>,
+) -> Result<Vec<QueryResult>> {
+    let query = match PlaceQuery::from_url(url) {
+        Some(query) => query,
+        None => return Err(ErrorKind::UnsupportedQuery(vec![url.scheme().into()]).into()),
+    };
+    if !query.unsupported.is_empty() {
+        return Err(ErrorKind::UnsupportedQuery(query.unsupported).into());
+    }
+    let limit = match (limit, query.max_results) {
+        (Some(limit), Some(max_results)) => min(limit, max_results),
+        (limit, max_results) => limit.or(max_results).unwrap_or(usize::max_value()),
+    };
+    let mut results = Vec::new();
+    if let Some(tag) = &query.tag {
+        if !query.exclude_items {
+            results.extend(
+                get_urls_with_tag(db, tag)?
+                    .into_iter()
+                    .take(limit)
+                    .map(|url| QueryResult {
+                        node_type: BookmarkType::Bookmark,
+                        guid: None,
+                        url: Some(url),
+                        title: None,
+                    }),
+            );
+        }
+    } else if let Some(folder) = &query.folder {
+        results = fetch_descendants(db, folder, query.exclude_items, limit)?;
+    }
+    Ok(results)
+}
+
+// Returns at most `limit` descendants of `folder`, depth-first, skipping
+// separators, hidden queries, and bookmarks if `exclude_items` is set.
+fn fetch_descendants(
+    db: &PlacesDb,
+    folder: &SyncGuid,
+    exclude_items: bool,
+    limit: usize,
+) -> Result<Vec<QueryResult>> {
+    let folder_type = db.try_query_row(
+        "SELECT type FROM moz_bookmarks WHERE guid = :guid",
+        &[(":guid", folder)],
+        |row| row.get::<_, BookmarkType>(0),
+        true,
+    )?;
+    match folder_type {
+        Some(BookmarkType::Folder) => {}
+        Some(_) => return Ok(Vec::new()),
+        None => return Err(InvalidPlaceInfo::NoSuchGuid(folder.to_string()).into()),
+    }
+    // Ordering the queue by level, deepest first, makes SQLite walk the
+    // tree depth-first, so the limit applies in the same order that we
+    // return the results, and we stop walking once we reach it.
+    let sql = format!(
+        "WITH RECURSIVE
+         descendants(id, level, position, type, guid, fk, title) AS (
+           SELECT id, 0, position, type, guid, fk, title FROM moz_bookmarks
+           WHERE guid = :guid
+           UNION ALL
+           SELECT b.id, d.level + 1, b.position, b.type, b.guid, b.fk, b.title
+           FROM moz_bookmarks b
+           JOIN descendants d ON b.parent = d.id
+           ORDER BY 2 DESC, 3
+         )
+         SELECT d.type, d.guid, h.url, NULLIF({title}, '') AS title
+         FROM descendants d
+         LEFT JOIN moz_places h ON h.id = d.fk
+         WHERE d.level > 0 AND
+               d.type <> :separator AND
+               NOT (:exclude_items AND d.type = :bookmark) AND
+               NOT EXISTS(SELECT 1 FROM moz_bookmarks_hidden_queries q
+                          WHERE q.bookmarkId = d.id)
+         LIMIT :limit",
+        title = crate::storage::display_title_fragment("d.title"),
+    );
+    db.query_rows_and_then_named(
+        &sql,
+        &[
+            (":guid", folder),
+            (":separator", &BookmarkType::Separator),
+            (":bookmark", &BookmarkType::Bookmark),
+            (":exclude_items", &exclude_items),
+            (":limit", &(min(limit, i64::max_value() as usize) as i64)),
+        ],
+        |row| -> Result<_> {
+            Ok(QueryResult {
+                node_type: row.get("type")?,
+                guid: Some(row.get("guid")?),
+                url: match row.get::<_, Option<String>>("url")? {
+                    Some(url) => Some(Url::parse(&url)?),
+                    None => None,
+                },
+                title: row.get("title")?,
+            })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tags::tag_url;
+    use crate::testing::{insert_json_tree, new_mem_connection};
+    use pretty_assertions::assert_eq;
+
+    fn insert_query_tree(conn: &PlacesDb) {
+        insert_json_tree(
+            conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [
+                            {
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "http://example.com/c",
+                            },
+                            {
+                                "guid": "separatorDDD",
+                                "type": BookmarkType::Separator as u8,
+                            },
+                        ],
+                    },
+                    {
+                        "guid": "bookmarkEEEE",
+                        "title": "E",
+                        "url": "http://example.com/e",
+                    },
+                ]
+            }),
+        )
+        .expect("should insert tree");
+    }
+
+    fn guids(results: &[QueryResult]) -> Vec<&str> {
+        results
+            .iter()
+            .map(|r| r.guid.as_ref().map_or("", |guid| guid.as_ref()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = |s: &str| PlaceQuery::from_url(&Url::parse(s).unwrap());
+        assert_eq!(query("http://example.com"), None);
+        assert_eq!(
+            query("place:tag=foo"),
+            Some(PlaceQuery {
+                tag: Some("foo".into()),
+                ..PlaceQuery::default()
+            })
+        );
+        assert_eq!(
+            query("place:folder=BOOKMARKS_MENU&excludeItems=1&maxResults=10"),
+            Some(PlaceQuery {
+                folder: Some(BookmarkRootGuid::Menu.as_guid()),
+                exclude_items: true,
+                max_results: Some(10),
+                ..PlaceQuery::default()
+            })
+        );
+        assert_eq!(
+            query("place:folder=123&sort=8"),
+            Some(PlaceQuery {
+                unsupported: vec!["folder".into(), "sort".into()],
+                ..PlaceQuery::default()
+            })
+        );
+        assert!(query("place:folder=123&sort=8").unwrap().has_folder());
+        assert_eq!(
+            query("place:type=7&queryType=1"),
+            Some(PlaceQuery {
+                results_as_tag_contents: true,
+                unsupported: vec!["type".into(), "queryType".into()],
+                ..PlaceQuery::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_resolve_tag_query() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_query_tree(&conn);
+        tag_url(&conn, &Url::parse("http://example.com/a")?, "foo")?;
+        tag_url(&conn, &Url::parse("http://example.com/c")?, "foo")?;
+        tag_url(&conn, &Url::parse("http://example.com/e")?, "bar")?;
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "queryFFFFFFF",
+                    "title": "foo",
+                    "url": "place:tag=foo",
+                }],
+            }),
+        )
+        .expect("should insert tree");
+
+        let mut urls = resolve_query_bookmark(&conn, &"queryFFFFFFF".into(), None)?
+            .into_iter()
+            .map(|r| r.url.expect("should have a URL").into_string())
+            .collect::<Vec<_>>();
+        urls.sort();
+        assert_eq!(urls, vec!["http://example.com/a", "http://example.com/c"]);
+
+        assert_eq!(
+            resolve_query_bookmark(&conn, &"queryFFFFFFF".into(), Some(1))?.len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_folder_query() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_query_tree(&conn);
+
+        let results = resolve_query_url(&conn, &Url::parse("place:folder=menu________")?, None)?;
+        assert_eq!(
+            guids(&results),
+            vec![
+                "bookmarkAAAA",
+                "folderBBBBBB",
+                "bookmarkCCCC",
+                "bookmarkEEEE"
+            ]
+        );
+
+        let results = resolve_query_url(
+            &conn,
+            &Url::parse("place:folder=BOOKMARKS_MENU&excludeItems=1")?,
+            None,
+        )?;
+        assert_eq!(guids(&results), vec!["folderBBBBBB"]);
+
+        let results = resolve_query_url(
+            &conn,
+            &Url::parse("place:folder=BOOKMARKS_MENU&maxResults=3")?,
+            Some(10),
+        )?;
+        assert_eq!(
+            guids(&results),
+            vec!["bookmarkAAAA", "folderBBBBBB", "bookmarkCCCC"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_unsupported_query() {
+        let conn = new_mem_connection();
+        let err = resolve_query_url(
+            &conn,
+            &Url::parse("place:folder=123&sort=8&tag=foo").unwrap(),
+            None,
+        )
+        .expect_err("should fail");
+        match err.kind() {
+            ErrorKind::UnsupportedQuery(params) => {
+                assert_eq!(params, &["folder".to_string(), "sort".to_string()]);
+            }
+            e => panic!("Expected unsupported query; got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_hide_query_bookmarks() -> Result<()> {
+        let conn = new_mem_connection();
+        let menu = &BookmarkRootGuid::Menu.as_guid();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": menu,
+                "children": [
+                    {
+                        "guid": "queryAAAAAAA",
+                        "title": "A",
+                        "url": "place:tag=foo",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                    {
+                        "guid": "queryCCCCCCC",
+                        "title": "C",
+                        "url": "place:folder=TOOLBAR",
+                    },
+                    {
+                        "guid": "queryDDDDDDD",
+                        "title": "D",
+                        "url": "place:sort=8&maxResults=10",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        // Pretend we synced everything.
+        conn.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET syncChangeCounter = 0, syncStatus = {}",
+            SyncStatus::Normal as u8
+        ))?;
+
+        let queries = get_query_bookmarks(&conn)?;
+        assert_eq!(
+            queries
+                .iter()
+                .map(|q| (q.guid.as_ref(), q.kind, q.hidden))
+                .collect::<Vec<_>>(),
+            vec![
+                ("queryAAAAAAA", PlaceQueryKind::Tag, false),
+                ("queryCCCCCCC", PlaceQueryKind::Folder, false),
+                ("queryDDDDDDD", PlaceQueryKind::Unsupported, false),
+            ]
+        );
+
+        set_query_bookmark_hidden(&conn, &"queryAAAAAAA".into(), true)?;
+        set_query_bookmark_hidden(&conn, &"queryDDDDDDD".into(), true)?;
+        set_query_bookmark_hidden(&conn, &"queryDDDDDDD".into(), false)?;
+        assert_eq!(
+            get_query_bookmarks(&conn)?
+                .iter()
+                .filter(|q| q.hidden)
+                .map(|q| q.guid.as_ref())
+                .collect::<Vec<_>>(),
+            vec!["queryAAAAAAA"]
+        );
+
+        // Hiding is local-only.
+        let query = get_raw_bookmark(&conn, &"queryAAAAAAA".into())?.expect("Should exist");
+        assert_eq!(query.sync_change_counter, 0);
+        assert_eq!(query.sync_status, SyncStatus::Normal);
+        let tombstones: i64 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 0);
+
+        let child_guids = |include_hidden_queries| -> Result<Vec<String>> {
+            let tree = fetch_tree_with_options(
+                &conn,
+                menu,
+                &FetchTreeOptions {
+                    include_hidden_queries,
+                },
+            )?;
+            Ok(match tree {
+                Some(BookmarkTreeNode::Folder(folder)) => folder
+                    .children
+                    .iter()
+                    .map(|child| child.guid().to_string())
+                    .collect(),
+                _ => panic!("Should fetch the menu"),
+            })
+        };
+        assert_eq!(
+            child_guids(false)?,
+            vec!["bookmarkBBBB", "queryCCCCCCC", "queryDDDDDDD"]
+        );
+        assert_eq!(
+            child_guids(true)?,
+            vec![
+                "queryAAAAAAA",
+                "bookmarkBBBB",
+                "queryCCCCCCC",
+                "queryDDDDDDD"
+            ]
+        );
+
+        // Only queries can be hidden, and failing to hide something else
+        // shouldn't change what's hidden.
+        for guid in &["bookmarkBBBB", menu.as_ref()] {
+            match set_query_bookmark_hidden(&conn, &(*guid).into(), true) {
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NotAQuery(g)) => {
+                        assert_eq!(g.as_str(), *guid)
+                    }
+                    kind => panic!("Unexpected error kind: {:?}", kind),
+                },
+                Ok(_) => panic!("Shouldn't hide {}", guid),
+            }
+        }
+        match set_query_bookmark_hidden(&conn, &"queryEEEEEEE".into(), true) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoSuchGuid(g)) => {
+                    assert_eq!(g.as_str(), "queryEEEEEEE")
+                }
+                kind => panic!("Unexpected error kind: {:?}", kind),
+            },
+            Ok(_) => panic!("Shouldn't hide a missing query"),
+        }
+        let hidden: Vec<String> = conn.query_rows_and_then_named(
+            "SELECT b.guid FROM moz_bookmarks_hidden_queries h
+             JOIN moz_bookmarks b ON b.id = h.bookmarkId",
+            &[],
+            |row| row.get(0),
+        )?;
+        assert_eq!(hidden, vec!["queryAAAAAAA"]);
+
+        // Deleting a hidden query forgets that it's hidden.
+        delete_bookmark(&conn, &"queryAAAAAAA".into())?;
+        let hidden: i64 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_hidden_queries")?;
+        assert_eq!(hidden, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,840 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Detects and fixes inconsistencies in the local bookmark tree, like
+//! orphaned items or gaps in positions. The schema and triggers should
+//! prevent these, but older versions, crashes, and bugs can still leave
+//! them behind, and some of them make the Sync merger refuse to run.
+//!
+//! Each pass only bumps change counters when the fix changes the tree that
+//! other devices see. Fixes that restore what Sync already assumed, like
+//! renumbering positions in the same order, stay local.
+
+use super::{create_root, BookmarkRootGuid, USER_CONTENT_ROOTS};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::{delete_pending_temp_tables, RowId};
+use crate::types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
+use rusqlite::{ToSql, NO_PARAMS};
+use serde_derive::*;
+use sql_support::ConnExt;
+
+/// The number of problems of each kind that `repair` found and fixed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairReport {
+    /// Built-in roots that were missing and recreated, or moved back under
+    /// the Places root.
+    pub roots: u32,
+    /// Items whose parent didn't exist, wasn't a folder, or wasn't
+    /// reachable from the Places root, which we moved to unfiled.
+    pub orphans: u32,
+    /// Bookmarks without a URL, which we deleted.
+    pub missing_urls: u32,
+    /// Folders whose children had gaps or duplicates in their positions.
+    pub positions: u32,
+    /// Pages whose foreign count didn't match the number of bookmarks,
+    /// synced bookmarks, and tags referencing them.
+    pub foreign_counts: u32,
+    /// Items whose GUIDs only differ by case from another item's, which we
+    /// gave new GUIDs.
+    pub guid_case_collisions: u32,
+}
+
+impl RepairReport {
+    /// Returns true if `repair` didn't change anything.
+    pub fn is_empty(&self) -> bool {
+        self.roots == 0
+            && self.orphans == 0
+            && self.missing_urls == 0
+            && self.positions == 0
+            && self.foreign_counts == 0
+            && self.guid_case_collisions == 0
+    }
+}
+
+/// Runs all consistency checks on the local tree, and fixes the problems
+/// they find, in a single transaction.
+pub fn repair(db: &PlacesDb) -> Result<RepairReport> {
+    let tx = db.begin_transaction()?;
+    let now = db.now();
+    // The order matters: later passes assume the roots exist, and fixing
+    // orphans and deleting bookmarks can leave gaps in positions.
+    let report = RepairReport {
+        roots: repair_roots(db, now)?,
+        orphans: repair_orphans(db, now)?,
+        missing_urls: repair_missing_urls(db, now)?,
+        positions: repair_positions(db)?,
+        foreign_counts: repair_foreign_counts(db)?,
+        guid_case_collisions: fix_guid_case_collisions(db, now)?,
+    };
+    delete_pending_temp_tables(db)?;
+    tx.commit()?;
+    if !report.is_empty() {
+        log::warn!("Repaired local bookmarks: {:?}", report);
+    }
+    Ok(report)
+}
+
+fn root_id(db: &PlacesDb, root: BookmarkRootGuid) -> Result<Option<RowId>> {
+    Ok(db.try_query_one(
+        "SELECT id FROM moz_bookmarks WHERE guid = :guid",
+        &[(":guid", root.guid())],
+        true,
+    )?)
+}
+
+/// Returns the position after the last child of `parent_id`.
+fn next_position(db: &PlacesDb, parent_id: RowId) -> Result<u32> {
+    Ok(db.query_row_named_with_context(
+        "SELECT IFNULL(MAX(position) + 1, 0) FROM moz_bookmarks
+         WHERE parent = :parent",
+        &[(":parent", &parent_id)],
+        |row| row.get(0),
+    )?)
+}
+
+/// Recreates missing roots, and moves user content roots that aren't
+/// children of the Places root back. Sync assumes the roots always exist in
+/// the same places, so these fixes don't need to be uploaded.
+fn repair_roots(db: &PlacesDb, now: Timestamp) -> Result<u32> {
+    let mut fixed = 0;
+    let places_root_id = match root_id(db, BookmarkRootGuid::Root)? {
+        Some(id) => id,
+        None => {
+            create_root(db, BookmarkRootGuid::Root, 0, now)?;
+            fixed += 1;
+            root_id(db, BookmarkRootGuid::Root)?.expect("Should have created the Places root")
+        }
+    };
+    for &root in USER_CONTENT_ROOTS {
+        let parent_id = db.try_query_row(
+            "SELECT parent FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", root.guid())],
+            |row| row.get::<_, Option<RowId>>(0),
+            true,
+        )?;
+        match parent_id {
+            Some(Some(parent_id)) if parent_id == places_root_id => continue,
+            Some(_) => {
+                let position = next_position(db, places_root_id)?;
+                db.execute_named_cached(
+                    "UPDATE moz_bookmarks SET
+                       parent = :parent,
+                       position = :position
+                     WHERE guid = :guid",
+                    &[
+                        (":parent", &places_root_id),
+                        (":position", &position),
+                        (":guid", root.guid()),
+                    ],
+                )?;
+            }
+            None => {
+                let position = next_position(db, places_root_id)?;
+                create_root(db, root, position, now)?;
+            }
+        }
+        fixed += 1;
+    }
+    Ok(fixed)
+}
+
+/// Moves items that aren't reachable from the Places root to the end of
+/// unfiled. Their new parent needs to be uploaded, so we bump the change
+/// counters for the moved items and unfiled.
+fn repair_orphans(db: &PlacesDb, now: Timestamp) -> Result<u32> {
+    let unfiled_id = root_id(db, BookmarkRootGuid::Unfiled)?.expect("Should have repaired unfiled");
+
+    // First, items whose parent doesn't exist, or isn't a folder.
+    let mut orphan_ids = db.query_rows_and_then_named(
+        &format!(
+            "SELECT b.id FROM moz_bookmarks b
+             LEFT JOIN moz_bookmarks p ON p.id = b.parent
+             WHERE b.guid <> '{root_guid}' AND
+                   (p.id IS NULL OR p.type <> {folder_type})
+             ORDER BY b.id",
+            root_guid = BookmarkRootGuid::Root.as_str(),
+            folder_type = BookmarkType::Folder as u8,
+        ),
+        &[],
+        |row| row.get::<_, RowId>(0),
+    )?;
+    move_to_unfiled(db, &orphan_ids, unfiled_id, now)?;
+    let mut fixed = orphan_ids.len() as u32;
+
+    // Then, folders in cycles, which have valid parents, but still aren't
+    // reachable. Moving one folder breaks its cycle, and makes all its
+    // descendants reachable again, so we move them one at a time.
+    loop {
+        orphan_ids = db.query_rows_and_then_named(
+            &format!(
+                "WITH RECURSIVE
+                 reachable(id) AS (
+                   SELECT id FROM moz_bookmarks WHERE guid = '{root_guid}'
+                   UNION ALL
+                   SELECT b.id FROM moz_bookmarks b
+                   JOIN reachable r ON r.id = b.parent
+                 )
+                 SELECT id FROM moz_bookmarks
+                 WHERE id NOT IN (SELECT id FROM reachable)
+                 ORDER BY id
+                 LIMIT 1",
+                root_guid = BookmarkRootGuid::Root.as_str(),
+            ),
+            &[],
+            |row| row.get::<_, RowId>(0),
+        )?;
+        if orphan_ids.is_empty() {
+            break;
+        }
+        move_to_unfiled(db, &orphan_ids, unfiled_id, now)?;
+        fixed += 1;
+    }
+    Ok(fixed)
+}
+
+fn move_to_unfiled(
+    db: &PlacesDb,
+    item_ids: &[RowId],
+    unfiled_id: RowId,
+    now: Timestamp,
+) -> Result<()> {
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+    let mut position = next_position(db, unfiled_id)?;
+    for item_id in item_ids {
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET
+               parent = :parent,
+               position = :position,
+               lastModified = :now,
+               syncChangeCounter = syncChangeCounter + 1
+             WHERE id = :id",
+            &[
+                (":parent", &unfiled_id),
+                (":position", &position),
+                (":now", &now),
+                (":id", item_id),
+            ],
+        )?;
+        position += 1;
+    }
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET
+           lastModified = :now,
+           syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :id",
+        &[(":now", &now), (":id", &unfiled_id)],
+    )?;
+    Ok(())
+}
+
+/// Deletes bookmarks without a URL, which we can't show or upload. We write
+/// tombstones for bookmarks that we've synced, and bump the change counters
+/// for their parents, so that other devices delete them, too.
+fn repair_missing_urls(db: &PlacesDb, now: Timestamp) -> Result<u32> {
+    let missing = db.query_rows_and_then_named(
+        &format!(
+            "SELECT b.id, b.guid, b.parent, b.syncStatus FROM moz_bookmarks b
+             LEFT JOIN moz_places h ON h.id = b.fk
+             WHERE b.type = {bookmark_type} AND h.id IS NULL",
+            bookmark_type = BookmarkType::Bookmark as u8,
+        ),
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, RowId>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<RowId>>(2)?,
+                SyncStatus::from_u8(row.get::<_, u8>(3)?),
+            ))
+        },
+    )?;
+    for (id, guid, parent_id, sync_status) in &missing {
+        if let Some(parent_id) = parent_id {
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks SET
+                   lastModified = :now,
+                   syncChangeCounter = syncChangeCounter + 1
+                 WHERE id = :id",
+                &[(":now", &now), (":id", parent_id)],
+            )?;
+        }
+        db.execute_named_cached("DELETE FROM moz_bookmarks WHERE id = :id", &[(":id", id)])?;
+        // The main connection writes tombstones via a trigger, but the Sync
+        // connection doesn't, so we write them ourselves. This must happen
+        // after the delete, to avoid conflicting with the trigger.
+        if *sync_status == SyncStatus::Normal {
+            db.execute_named_cached(
+                "INSERT OR IGNORE INTO moz_bookmarks_deleted(guid, dateRemoved)
+                 VALUES(:guid, :now)",
+                &[(":guid", guid), (":now", &now)],
+            )?;
+        }
+    }
+    Ok(missing.len() as u32)
+}
+
+/// Renumbers the children of folders with gaps or duplicates in their
+/// positions, keeping their existing order. Sync already reads children in
+/// this order, so we don't bump change counters.
+fn repair_positions(db: &PlacesDb) -> Result<u32> {
+    let parent_ids = db.query_rows_and_then_named(
+        "SELECT parent FROM moz_bookmarks
+         WHERE parent NOT NULL
+         GROUP BY parent
+         HAVING MIN(position) <> 0 OR
+                MAX(position) <> COUNT(*) - 1 OR
+                COUNT(DISTINCT position) <> COUNT(*)",
+        &[],
+        |row| row.get::<_, RowId>(0),
+    )?;
+    for parent_id in &parent_ids {
+        let child_ids = db.query_rows_and_then_named(
+            "SELECT id FROM moz_bookmarks
+             WHERE parent = :parent
+             ORDER BY position, id",
+            &[(":parent", parent_id)],
+            |row| row.get::<_, RowId>(0),
+        )?;
+        for (position, child_id) in child_ids.iter().enumerate() {
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks SET position = :position WHERE id = :id",
+                &[(":position", &(position as u32)), (":id", child_id)],
+            )?;
+        }
+    }
+    Ok(parent_ids.len() as u32)
+}
+
+/// Recalculates foreign counts that don't match the number of references to
+/// each page. A count that's too high keeps pages around forever; one
+/// that's too low lets us expire bookmarked pages. This is local-only.
+fn repair_foreign_counts(db: &PlacesDb) -> Result<u32> {
+    let expected = "(SELECT COUNT(*) FROM moz_bookmarks
+                     WHERE fk = moz_places.id) +
+                    (SELECT COUNT(*) FROM moz_bookmarks_synced
+                     WHERE placeId = moz_places.id) +
+                    (SELECT COUNT(*) FROM moz_tags_relation
+                     WHERE place_id = moz_places.id) +
+                    (SELECT COUNT(*) FROM moz_keywords
+                     WHERE place_id = moz_places.id)";
+    let fixed = db.execute(
+        &format!(
+            "UPDATE moz_places SET foreign_count = {expected}
+             WHERE foreign_count <> {expected}",
+            expected = expected,
+        ),
+        NO_PARAMS,
+    )?;
+    Ok(fixed as u32)
+}
+
+/// Like `repair`, but only gives new GUIDs to items whose GUIDs differ by
+/// case. `run_maintenance` calls this, since these items would overwrite
+/// each other on servers that ignore case.
+pub(crate) fn repair_guid_case_collisions(db: &PlacesDb) -> Result<u32> {
+    let tx = db.begin_transaction()?;
+    let fixed = fix_guid_case_collisions(db, db.now())?;
+    tx.commit()?;
+    Ok(fixed)
+}
+
+/// For each set of items whose GUIDs only differ by case, keeps the GUID of
+/// a root, or else of an item that we've synced, or else of the oldest
+/// item, and gives the others new GUIDs. The renamed items, and their
+/// parents, are uploaded as new items. We don't write tombstones for their
+/// old GUIDs, because a server that ignores case would apply them to the
+/// item that kept its GUID. We rename them in the mirror, too, so that the
+/// next merge doesn't bring them back under their old GUIDs.
+fn fix_guid_case_collisions(db: &PlacesDb, now: Timestamp) -> Result<u32> {
+    let colliding = db.query_rows_and_then_named(
+        &format!(
+            "SELECT b.id, b.guid, b.parent FROM moz_bookmarks b
+             WHERE EXISTS(SELECT 1 FROM moz_bookmarks d
+                          WHERE d.guid = b.guid COLLATE NOCASE AND
+                                d.id <> b.id)
+             ORDER BY lower(b.guid),
+                      b.syncStatus = {normal} DESC,
+                      b.id",
+            normal = SyncStatus::Normal as u8,
+        ),
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, RowId>(0)?,
+                row.get::<_, SyncGuid>(1)?,
+                row.get::<_, Option<RowId>>(2)?,
+            ))
+        },
+    )?;
+    let mut fixed = 0;
+    // The rows are grouped by folded GUID, with the item to keep first,
+    // unless one of the others is a root.
+    let mut groups: Vec<Vec<(RowId, SyncGuid, Option<RowId>)>> = Vec::new();
+    for item in colliding {
+        match groups.last_mut() {
+            Some(group) if group[0].1.as_ref().eq_ignore_ascii_case(item.1.as_ref()) => {
+                group.push(item)
+            }
+            _ => groups.push(vec![item]),
+        }
+    }
+    for mut group in groups {
+        let keep = group
+            .iter()
+            .position(|(_, guid, _)| guid.is_root())
+            .unwrap_or(0);
+        group.remove(keep);
+        for (id, guid, parent_id) in group {
+            let new_guid = super::new_bookmark_guid(db)?;
+            log::debug!(
+                "Changing GUID {} to {}, because it only differs by case from another item's",
+                guid,
+                new_guid
+            );
+            db.execute_named_cached(
+                &format!(
+                    "UPDATE moz_bookmarks SET
+                       guid = :guid,
+                       syncStatus = {new},
+                       syncChangeCounter = syncChangeCounter + 1,
+                       lastModified = :now
+                     WHERE id = :id",
+                    new = SyncStatus::New as u8,
+                ),
+                &[(":guid", &new_guid), (":now", &now), (":id", &id)],
+            )?;
+            // A folder's children reference it in the mirror structure until
+            // we update them, so we can only check foreign keys when the
+            // transaction commits.
+            db.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            let params: &[(&str, &dyn ToSql)] = &[(":oldGuid", &guid), (":newGuid", &new_guid)];
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks_synced SET guid = :newGuid WHERE guid = :oldGuid",
+                params,
+            )?;
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks_synced SET parentGuid = :newGuid
+                 WHERE parentGuid = :oldGuid",
+                params,
+            )?;
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks_synced_structure SET guid = :newGuid
+                 WHERE guid = :oldGuid",
+                params,
+            )?;
+            db.execute_named_cached(
+                "UPDATE moz_bookmarks_synced_structure SET parentGuid = :newGuid
+                 WHERE parentGuid = :oldGuid",
+                params,
+            )?;
+            if let Some(parent_id) = parent_id {
+                db.execute_named_cached(
+                    "UPDATE moz_bookmarks SET
+                       lastModified = :now,
+                       syncChangeCounter = syncChangeCounter + 1
+                     WHERE id = :id",
+                    &[(":now", &now), (":id", &parent_id)],
+                )?;
+            }
+            fixed += 1;
+        }
+    }
+    Ok(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{assert_json_tree, insert_json_tree, new_mem_connection};
+    use crate::valid_guid::is_valid_places_guid;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_repair() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [
+                            {
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "http://example.com/c",
+                            },
+                            {
+                                "guid": "bookmarkDDDD",
+                                "title": "D",
+                                "url": "http://example.com/d",
+                            },
+                        ],
+                    },
+                    {
+                        "guid": "bookmarkEEEE",
+                        "title": "E",
+                        "url": "http://example.com/e",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkFFFF",
+                        "title": "F",
+                        "url": "http://example.com/f",
+                    },
+                    {
+                        "guid": "bookmarkGGGG",
+                        "title": "G",
+                        "url": "http://example.com/g",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        conn.execute("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)?;
+
+        conn.execute_batch(
+            "-- Move mobile into B.
+             UPDATE moz_bookmarks SET
+               parent = (SELECT id FROM moz_bookmarks WHERE guid = 'folderBBBBBB'),
+               position = 2
+             WHERE guid = 'mobile______';
+
+             -- Make E a child of A, which isn't a folder.
+             UPDATE moz_bookmarks SET
+               parent = (SELECT id FROM moz_bookmarks WHERE guid = 'bookmarkAAAA'),
+               position = 0
+             WHERE guid = 'bookmarkEEEE';
+
+             -- Leave a gap in unfiled.
+             UPDATE moz_bookmarks SET position = 5 WHERE guid = 'bookmarkGGGG';
+
+             -- Clear D's URL. This also leaves the foreign count for its page
+             -- at 1, since the trigger doesn't handle NULLs.
+             PRAGMA ignore_check_constraints = ON;
+             UPDATE moz_bookmarks SET fk = NULL WHERE guid = 'bookmarkDDDD';
+             PRAGMA ignore_check_constraints = OFF;
+
+             -- Throw off the foreign count for F.
+             UPDATE moz_places SET foreign_count = 7
+             WHERE url = 'http://example.com/f';",
+        )?;
+
+        let report = repair(&conn)?;
+        assert_eq!(
+            report,
+            RepairReport {
+                roots: 1,
+                orphans: 1,
+                missing_urls: 1,
+                positions: 1,
+                foreign_counts: 2,
+                guid_case_collisions: 0,
+            }
+        );
+
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Root.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Root.as_guid(),
+                "children": [
+                    {
+                        "guid": &BookmarkRootGuid::Menu.as_guid(),
+                        "children": [
+                            {
+                                "guid": "bookmarkAAAA",
+                                "title": "A",
+                                "url": "http://example.com/a",
+                            },
+                            {
+                                "guid": "folderBBBBBB",
+                                "title": "B",
+                                "children": [{
+                                    "guid": "bookmarkCCCC",
+                                    "title": "C",
+                                    "url": "http://example.com/c",
+                                }],
+                            },
+                        ],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                        "children": [],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                        "children": [
+                            {
+                                "guid": "bookmarkFFFF",
+                                "title": "F",
+                                "url": "http://example.com/f",
+                            },
+                            {
+                                "guid": "bookmarkGGGG",
+                                "title": "G",
+                                "url": "http://example.com/g",
+                            },
+                            {
+                                "guid": "bookmarkEEEE",
+                                "title": "E",
+                                "url": "http://example.com/e",
+                            },
+                        ],
+                    },
+                    {
+                        "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                        "children": [],
+                    },
+                ],
+            }),
+        );
+
+        // Only the fixes that other devices need to know about should bump
+        // change counters: moving E to unfiled, and deleting D from B.
+        let changed = conn.query_rows_and_then_named(
+            "SELECT guid FROM moz_bookmarks
+             WHERE syncChangeCounter > 0
+             ORDER BY guid",
+            &[],
+            |row| row.get::<_, String>(0),
+        )?;
+        assert_eq!(
+            changed,
+            vec!["bookmarkEEEE", "folderBBBBBB", "unfiled_____"]
+        );
+
+        let foreign_counts = conn.query_rows_and_then_named(
+            "SELECT url, foreign_count FROM moz_places ORDER BY url",
+            &[],
+            |row| -> rusqlite::Result<_> { Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)) },
+        )?;
+        assert_eq!(
+            foreign_counts,
+            vec![
+                ("http://example.com/a".to_string(), 1),
+                ("http://example.com/c".to_string(), 1),
+                ("http://example.com/d".to_string(), 0),
+                ("http://example.com/e".to_string(), 1),
+                ("http://example.com/f".to_string(), 1),
+                ("http://example.com/g".to_string(), 1),
+            ]
+        );
+
+        // Repairing again shouldn't find anything.
+        assert_eq!(repair(&conn)?, RepairReport::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_cycles_and_missing_roots() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [
+                    {
+                        "guid": "folderAAAAAA",
+                        "title": "A",
+                        "children": [{
+                            "guid": "folderBBBBBB",
+                            "title": "B",
+                            "children": [{
+                                "guid": "bookmarkCCCC",
+                                "title": "C",
+                                "url": "http://example.com/c",
+                            }],
+                        }],
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+
+        // Make A a child of B, and delete the mobile root without deleting
+        // its children.
+        conn.execute_batch(
+            "UPDATE moz_bookmarks SET
+               parent = (SELECT id FROM moz_bookmarks WHERE guid = 'folderBBBBBB'),
+               position = 1
+             WHERE guid = 'folderAAAAAA';
+
+             PRAGMA foreign_keys = OFF;
+             DELETE FROM moz_bookmarks WHERE guid = 'mobile______';
+             PRAGMA foreign_keys = ON;",
+        )?;
+
+        let report = repair(&conn)?;
+        assert_eq!(
+            report,
+            RepairReport {
+                roots: 1,
+                orphans: 1,
+                // The toolbar is now empty, and the root is missing mobile,
+                // but both are already numbered from 0.
+                positions: 0,
+                ..RepairReport::default()
+            }
+        );
+
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "folderAAAAAA",
+                    "title": "A",
+                    "children": [{
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [{
+                            "guid": "bookmarkCCCC",
+                            "title": "C",
+                            "url": "http://example.com/c",
+                        }],
+                    }],
+                }],
+            }),
+        );
+        assert!(root_id(&conn, BookmarkRootGuid::Mobile)?.is_some());
+        assert_eq!(repair(&conn)?, RepairReport::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_guid_case_collisions() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = new_mem_connection();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                    {
+                        "guid": "bookmarkCCCC",
+                        "title": "C",
+                        "url": "http://example.com/c",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        conn.execute_batch(&format!(
+            "UPDATE moz_bookmarks SET
+               syncChangeCounter = 0,
+               syncStatus = {normal};
+
+             -- B collides with A, which is older.
+             UPDATE moz_bookmarks SET guid = 'bookmarkaaaa'
+             WHERE guid = 'bookmarkBBBB';
+
+             -- C collides with the toolbar root. We should keep the root's
+             -- GUID, even though C has synced and the root hasn't.
+             UPDATE moz_bookmarks SET guid = 'TOOLBAR_____'
+             WHERE guid = 'bookmarkCCCC';
+             UPDATE moz_bookmarks SET syncStatus = {new}
+             WHERE guid = 'toolbar_____';",
+            normal = SyncStatus::Normal as u8,
+            new = SyncStatus::New as u8,
+        ))?;
+        let renamed_ids = conn.query_rows_and_then_named(
+            "SELECT id FROM moz_bookmarks
+             WHERE guid IN ('bookmarkaaaa', 'TOOLBAR_____')
+             ORDER BY id",
+            &[],
+            |row| row.get::<_, RowId>(0),
+        )?;
+
+        let report = repair(&conn)?;
+        assert_eq!(
+            report,
+            RepairReport {
+                guid_case_collisions: 2,
+                ..RepairReport::default()
+            }
+        );
+
+        let guids = conn.query_rows_and_then_named(
+            "SELECT guid FROM moz_bookmarks
+             WHERE guid IN ('bookmarkAAAA', 'bookmarkaaaa',
+                            'toolbar_____', 'TOOLBAR_____')
+             ORDER BY guid",
+            &[],
+            |row| row.get::<_, String>(0),
+        )?;
+        assert_eq!(guids, vec!["bookmarkAAAA", "toolbar_____"]);
+
+        // The renamed items should be uploaded as new items, along with their
+        // parent. We shouldn't write tombstones for their old GUIDs.
+        for id in renamed_ids {
+            let (guid, status, counter) = conn.query_row_and_then_named(
+                "SELECT guid, syncStatus, syncChangeCounter FROM moz_bookmarks
+                 WHERE id = :id",
+                &[(":id", &id)],
+                |row| -> rusqlite::Result<_> {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        SyncStatus::from_u8(row.get::<_, u8>(1)?),
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+                false,
+            )?;
+            assert!(is_valid_places_guid(&guid));
+            assert!(!guid.eq_ignore_ascii_case("bookmarkAAAA"));
+            assert!(!guid.eq_ignore_ascii_case("toolbar_____"));
+            assert_eq!(status, SyncStatus::New);
+            assert_eq!(counter, 1);
+        }
+        let menu_counter: i64 = conn.query_row_and_then_named(
+            "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", &BookmarkRootGuid::Menu.as_guid())],
+            |row| row.get(0),
+            false,
+        )?;
+        assert_eq!(menu_counter, 2);
+        let tombstones: i64 = conn.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?;
+        assert_eq!(tombstones, 0);
+
+        // Repairing again shouldn't find anything.
+        assert_eq!(repair(&conn)?, RepairReport::default());
+        Ok(())
+    }
+}
@@ -60,6 +60,18 @@ impl BookmarkRootGuid {
         }
     }
 
+    /// The title we give the root when we create it, and upload for it. Apps
+    /// can show localized titles instead, using `set_root_titles`.
+    pub fn default_title(self) -> &'static str {
+        match self {
+            BookmarkRootGuid::Root => "root",
+            BookmarkRootGuid::Menu => "menu",
+            BookmarkRootGuid::Toolbar => "toolbar",
+            BookmarkRootGuid::Unfiled => "unfiled",
+            BookmarkRootGuid::Mobile => "mobile",
+        }
+    }
+
     pub fn guid(self) -> &'static SyncGuid {
         &GUIDS[self as usize].1
     }
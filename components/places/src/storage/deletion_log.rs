@@ -0,0 +1,252 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A local log of destructive operations on history and bookmarks. Each
+//! entry records what kind of deletion happened, when, how many items it
+//! removed, and what started it. Like the sync log, we never store URLs,
+//! titles, or GUIDs here; only counts.
+//!
+//! Entries are written in the same transaction as the deletion they
+//! describe, and `run_maintenance` prunes entries older than
+//! `DELETION_LOG_MAX_AGE_DAYS`.
+
+use super::history::MILLIS_PER_DAY;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::Timestamp;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{Result as RusqliteResult, Row};
+use sql_support::ConnExt;
+
+/// How long we keep deletion log entries.
+pub const DELETION_LOG_MAX_AGE_DAYS: u64 = 90;
+
+/// The kind of destructive operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionOp {
+    /// `delete_visits_between`. The count is the number of visits.
+    DeleteVisits,
+    /// `delete_everything`. The count is the number of visits.
+    DeleteEverything,
+    /// `wipe_local`. The count is the number of visits.
+    WipeHistory,
+    /// `delete_place_by_guid`. The count is the number of visits.
+    DeletePage,
+    /// Visits expired by the history retention policy.
+    ExpireVisits,
+    /// Visits pruned to keep the database under its size budget.
+    PruneVisits,
+    /// Pages deleted because another device deleted them.
+    SyncDeletePages,
+    /// `delete_bookmark`. The count includes the item's descendants.
+    DeleteBookmark,
+    /// `erase_folder_children`. The count includes all descendants.
+    EraseFolderChildren,
+    /// Bookmarks deleted by the Sync `wipe` command.
+    WipeBookmarks,
+    /// Bookmarks deleted while merging, usually because another device
+    /// deleted them.
+    SyncDeleteBookmarks,
+    /// `PlacesApi::wipe_local`. The count is the number of visits and
+    /// bookmarks, not including the roots.
+    WipeLocal,
+}
+
+impl DeletionOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeletionOp::DeleteVisits => "deleteVisits",
+            DeletionOp::DeleteEverything => "deleteEverything",
+            DeletionOp::WipeHistory => "wipeHistory",
+            DeletionOp::DeletePage => "deletePage",
+            DeletionOp::ExpireVisits => "expireVisits",
+            DeletionOp::PruneVisits => "pruneVisits",
+            DeletionOp::SyncDeletePages => "syncDeletePages",
+            DeletionOp::DeleteBookmark => "deleteBookmark",
+            DeletionOp::EraseFolderChildren => "eraseFolderChildren",
+            DeletionOp::WipeBookmarks => "wipeBookmarks",
+            DeletionOp::SyncDeleteBookmarks => "syncDeleteBookmarks",
+            DeletionOp::WipeLocal => "wipeLocal",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        Some(match s {
+            "deleteVisits" => DeletionOp::DeleteVisits,
+            "deleteEverything" => DeletionOp::DeleteEverything,
+            "wipeHistory" => DeletionOp::WipeHistory,
+            "deletePage" => DeletionOp::DeletePage,
+            "expireVisits" => DeletionOp::ExpireVisits,
+            "pruneVisits" => DeletionOp::PruneVisits,
+            "syncDeletePages" => DeletionOp::SyncDeletePages,
+            "deleteBookmark" => DeletionOp::DeleteBookmark,
+            "eraseFolderChildren" => DeletionOp::EraseFolderChildren,
+            "wipeBookmarks" => DeletionOp::WipeBookmarks,
+            "syncDeleteBookmarks" => DeletionOp::SyncDeleteBookmarks,
+            "wipeLocal" => DeletionOp::WipeLocal,
+            _ => return None,
+        })
+    }
+}
+
+impl ToSql for DeletionOp {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for DeletionOp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        DeletionOp::from_str(value.as_str()?).ok_or(FromSqlError::InvalidType)
+    }
+}
+
+/// What started a destructive operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionInitiator {
+    /// A call to a public API, usually on behalf of the user.
+    Api,
+    /// Sync.
+    Sync,
+    /// `run_maintenance`.
+    Maintenance,
+}
+
+impl DeletionInitiator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeletionInitiator::Api => "api",
+            DeletionInitiator::Sync => "sync",
+            DeletionInitiator::Maintenance => "maintenance",
+        }
+    }
+}
+
+impl ToSql for DeletionInitiator {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for DeletionInitiator {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(match value.as_str()? {
+            "api" => DeletionInitiator::Api,
+            "sync" => DeletionInitiator::Sync,
+            "maintenance" => DeletionInitiator::Maintenance,
+            _ => return Err(FromSqlError::InvalidType),
+        })
+    }
+}
+
+/// A destructive operation in the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeletionLogEntry {
+    pub op: DeletionOp,
+    pub at: Timestamp,
+    /// The number of items deleted. See `DeletionOp` for what's counted.
+    pub count: u32,
+    pub initiator: DeletionInitiator,
+}
+
+impl DeletionLogEntry {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            op: row.get("op")?,
+            at: row.get("ts")?,
+            count: row.get("count")?,
+            initiator: row.get("initiator")?,
+        })
+    }
+}
+
+/// Records a destructive operation. This should be called in the same
+/// transaction as the deletion. Operations that didn't delete anything
+/// aren't recorded.
+pub(crate) fn record_deletion(
+    db: &PlacesDb,
+    op: DeletionOp,
+    initiator: DeletionInitiator,
+    count: usize,
+) -> Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    db.execute_named_cached(
+        "INSERT INTO moz_deletion_log(op, ts, count, initiator)
+         VALUES(:op, :ts, :count, :initiator)",
+        &[
+            (":op", &op),
+            (":ts", &db.now()),
+            (":count", &(count as i64)),
+            (":initiator", &initiator),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns all logged operations at or after `since`, oldest first.
+pub fn get_deletion_log(db: &PlacesDb, since: Timestamp) -> Result<Vec<DeletionLogEntry>> {
+    db.query_rows_and_then_named(
+        "SELECT op, ts, count, initiator FROM moz_deletion_log
+         WHERE ts >= :since
+         ORDER BY ts, id",
+        &[(":since", &since)],
+        DeletionLogEntry::from_row,
+    )
+}
+
+/// Deletes entries older than `DELETION_LOG_MAX_AGE_DAYS`. Returns the
+/// number of entries deleted.
+pub(crate) fn prune_deletion_log(db: &PlacesDb) -> Result<usize> {
+    let cutoff = Timestamp(
+        db.now()
+            .as_millis()
+            .saturating_sub(DELETION_LOG_MAX_AGE_DAYS * MILLIS_PER_DAY),
+    );
+    Ok(db.execute_named_cached(
+        "DELETE FROM moz_deletion_log WHERE ts < :cutoff",
+        &[(":cutoff", &cutoff)],
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::new_mem_connection;
+    use crate::types::FrozenClock;
+
+    #[test]
+    fn test_prune_deletion_log() -> Result<()> {
+        let conn = new_mem_connection();
+        let now = Timestamp(1_550_000_000_000);
+        let clock = FrozenClock::at(now);
+        conn.set_clock(clock.clone())?;
+        let day = MILLIS_PER_DAY;
+        for (days_ago, count) in &[(DELETION_LOG_MAX_AGE_DAYS + 1, 1), (1, 2), (0, 0)] {
+            clock.set(Timestamp(now.as_millis() - days_ago * day));
+            record_deletion(
+                &conn,
+                DeletionOp::DeleteVisits,
+                DeletionInitiator::Api,
+                *count,
+            )?;
+        }
+
+        clock.set(now);
+        assert_eq!(get_deletion_log(&conn, Timestamp(0))?.len(), 2);
+        assert_eq!(prune_deletion_log(&conn)?, 1);
+        assert_eq!(
+            get_deletion_log(&conn, Timestamp(0))?,
+            vec![DeletionLogEntry {
+                op: DeletionOp::DeleteVisits,
+                at: Timestamp(now.as_millis() - day),
+                count: 2,
+                initiator: DeletionInitiator::Api,
+            }]
+        );
+        assert!(get_deletion_log(&conn, now)?.is_empty());
+        Ok(())
+    }
+}
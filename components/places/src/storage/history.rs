@@ -2,19 +2,26 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use super::{fetch_page_info, new_page_info, PageInfo, RowId};
+use super::{fetch_page_info, new_page_info, parse_href, PageInfo, RowId};
 use crate::db::PlacesDb;
 use crate::error::Result;
 use crate::frecency;
 use crate::hash;
+use crate::log_sampler::LogSampler;
 use crate::msg_types::{HistoryVisitInfo, HistoryVisitInfos};
 use crate::observation::VisitObservation;
-use crate::storage::{delete_pending_temp_tables, get_meta, put_meta};
+use crate::storage::deletion_log::{record_deletion, DeletionInitiator, DeletionOp};
+use crate::storage::{
+    delete_internal_meta, delete_pending_temp_tables, get_meta, put_internal_meta,
+};
 use crate::types::{SyncGuid, SyncStatus, Timestamp, VisitTransition, VisitTransitionSet};
+use log::Level;
 use rusqlite::types::ToSql;
 use rusqlite::Result as RusqliteResult;
 use rusqlite::{Row, NO_PARAMS};
+use serde_derive::*;
 use sql_support::{self, ConnExt};
+use std::time::Duration;
 use url::Url;
 
 /// When `delete_everything` is called (to perform a permanent local deletion), in
@@ -23,14 +30,169 @@ use url::Url;
 ///
 /// This allows us to avoid these visits trickling back in as other devices
 /// add visits to them remotely.
-static DELETION_HIGH_WATER_MARK_META_KEY: &str = "history_deleted_hwm";
+pub(crate) static DELETION_HIGH_WATER_MARK_META_KEY: &str = "history_deleted_hwm";
+
+// The history retention policy. See `HistoryRetention` for details.
+pub(crate) static RETENTION_DAYS_META_KEY: &str = "history_retention_days";
+pub(crate) static RETENTION_MAX_PAGES_META_KEY: &str = "history_retention_max_pages";
+pub(crate) static RETENTION_SYNC_DELETIONS_META_KEY: &str = "history_retention_sync_deletions";
+
+/// The date of the newest visit that we evicted to stay under the retention
+/// policy's `max_pages` limit. Like the deletion high water mark, we refuse to
+/// sync incoming visits from before this time, so that evicted pages don't
+/// come back on the next sync.
+pub(crate) static RETENTION_EVICTED_MARK_META_KEY: &str = "history_retention_evicted_mark";
+
+/// The maximum number of visits `run_maintenance` deletes to enforce the
+/// retention policy. Lowering the retention on a large profile can expire
+/// years of history at once, so we spread that work over several calls.
+pub const MAX_RETENTION_VISITS_PER_MAINTENANCE: usize = 2000;
+
+pub(crate) const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
 
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
 pub fn apply_observation(db: &PlacesDb, visit_ob: VisitObservation) -> Result<Option<RowId>> {
-    let tx = db.begin_transaction()?;
-    let result = apply_observation_direct(db, visit_ob)?;
-    tx.commit()?;
-    Ok(result)
+    db.retry_on_busy(|| {
+        let tx = db.begin_transaction()?;
+        let result = apply_observation_direct(db, visit_ob.clone())?;
+        // Flush pending origin changes, so that the origin's frecency
+        // includes the page's new frecency.
+        delete_pending_temp_tables(db)?;
+        tx.commit()?;
+        Ok(result)
+    })
+}
+
+/// How long `apply_observations` holds a transaction open before committing
+/// and starting a new one, so that big batches don't block readers and the
+/// other writer.
+const OBSERVATIONS_COMMIT_AFTER: Duration = Duration::from_millis(500);
+
+/// Applies a batch of observations, which is much faster than calling
+/// `apply_observation` for each one. Big batches are committed in chunks.
+/// Returns the number of visits added. See `ManyVisitObservations` for a way
+/// to batch observations as they arrive.
+pub fn apply_observations(db: &PlacesDb, observations: &[VisitObservation]) -> Result<usize> {
+    let mut progress = ObservationProgress::default();
+    apply_observations_resumable(db, observations, &mut progress, OBSERVATIONS_COMMIT_AFTER)?;
+    Ok(progress.visits_added)
+}
+
+/// How far `apply_observations_resumable` got: the number of observations at
+/// the start of the batch that we committed, and the visits they added.
+#[derive(Debug, Default)]
+struct ObservationProgress {
+    committed: usize,
+    visits_added: usize,
+}
+
+/// Applies the observations after the ones that `progress` says we already
+/// committed, committing every `commit_after`, and updates `progress` after
+/// each commit. If this fails part-way, callers can use `progress` to retry
+/// without adding the same visits twice.
+fn apply_observations_resumable(
+    db: &PlacesDb,
+    observations: &[VisitObservation],
+    progress: &mut ObservationProgress,
+    commit_after: Duration,
+) -> Result<()> {
+    if observations.len() <= progress.committed {
+        return Ok(());
+    }
+    let scope = db.begin_interrupt_scope();
+    // Batches come from bulk imports and history syncs, which can have many
+    // URLs that we can't store, so we limit how many we log.
+    let mut ignored = ignored_observations_sampler();
+    let result = db.retry_on_busy(|| {
+        let mut tx = db.begin_time_chunked_transaction(commit_after)?;
+        let mut visits_added = 0;
+        for (index, visit_ob) in observations.iter().enumerate().skip(progress.committed) {
+            scope.err_if_interrupted()?;
+            if apply_observation_sampled(db, visit_ob.clone(), &mut ignored)?.is_some() {
+                visits_added += 1;
+            }
+            // Update origins and frecencies for this chunk before we commit
+            // it, so that readers never see pages without their origins.
+            if tx.maybe_commit_with(|| delete_pending_temp_tables(db))? {
+                progress.committed = index + 1;
+                progress.visits_added += visits_added;
+                visits_added = 0;
+            }
+        }
+        delete_pending_temp_tables(db)?;
+        tx.commit()?;
+        progress.committed = observations.len();
+        progress.visits_added += visits_added;
+        Ok(())
+    });
+    ignored.finish();
+    result
+}
+
+/// The default number of observations that `ManyVisitObservations` buffers
+/// before applying them.
+pub const DEFAULT_OBSERVATION_BATCH_SIZE: usize = 500;
+
+/// Buffers observations, and applies them in batches with
+/// `apply_observations`. Call `flush` to apply the remaining observations
+/// when you're done; dropping this without flushing discards them.
+pub struct ManyVisitObservations<'a> {
+    db: &'a PlacesDb,
+    limit: usize,
+    pending: Vec<VisitObservation>,
+    visits_added: usize,
+}
+
+impl<'a> ManyVisitObservations<'a> {
+    pub fn new(db: &'a PlacesDb) -> Self {
+        Self::with_limit(db, DEFAULT_OBSERVATION_BATCH_SIZE)
+    }
+
+    pub fn with_limit(db: &'a PlacesDb, limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            db,
+            limit,
+            pending: Vec::with_capacity(limit),
+            visits_added: 0,
+        }
+    }
+
+    /// Adds an observation, applying the batch if it's full.
+    pub fn add(&mut self, visit_ob: VisitObservation) -> Result<()> {
+        self.pending.push(visit_ob);
+        if self.pending.len() >= self.limit {
+            self.apply_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Applies all remaining observations. Returns the total number of visits
+    /// added, including by earlier batches.
+    pub fn flush(mut self) -> Result<usize> {
+        self.apply_pending()?;
+        Ok(self.visits_added)
+    }
+
+    fn apply_pending(&mut self) -> Result<()> {
+        let mut progress = ObservationProgress::default();
+        let result = apply_observations_resumable(
+            self.db,
+            &self.pending,
+            &mut progress,
+            OBSERVATIONS_COMMIT_AFTER,
+        );
+        // Only remove the observations that we committed, so that the caller
+        // can retry after an error without losing observations, or adding
+        // their visits twice.
+        self.pending.drain(..progress.committed);
+        self.visits_added += progress.visits_added;
+        result
+    }
+}
+
+fn ignored_observations_sampler() -> LogSampler {
+    LogSampler::new("ignoredVisit", module_path!(), Level::Debug)
 }
 
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
@@ -38,17 +200,35 @@ pub fn apply_observation_direct(
     db: &PlacesDb,
     visit_ob: VisitObservation,
 ) -> Result<Option<RowId>> {
-    let url = Url::parse(&visit_ob.url)?;
+    apply_observation_sampled(db, visit_ob, &mut ignored_observations_sampler())
+}
+
+/// Like `apply_observation_direct`, but logs observations that we ignore
+/// through `ignored`.
+fn apply_observation_sampled(
+    db: &PlacesDb,
+    visit_ob: VisitObservation,
+    ignored: &mut LogSampler,
+) -> Result<Option<RowId>> {
+    let url = parse_href(&visit_ob.url)?;
     // Don't insert urls larger than our length max.
     if url.as_str().len() > super::URL_LENGTH_MAX {
+        ignored.log(format_args!(
+            "Ignoring observation with a URL that's too long"
+        ));
         return Ok(None);
     }
     let mut page_info = match fetch_page_info(db, &url)? {
         Some(info) => info.page,
         None => new_page_info(db, &url, None)?,
     };
+    // All the differences between local and synced observations are
+    // handled here. Note that we never write to the input history, which
+    // only `accept_result` does, for local autocomplete choices.
+    let source = visit_ob.source();
     let mut update_change_counter = false;
     let mut update_frec = false;
+    let mut redirect_source = None;
     let mut updates: Vec<(&str, &str, &dyn ToSql)> = Vec::new();
 
     if let Some(ref title) = visit_ob.title {
@@ -64,14 +244,39 @@ pub fn apply_observation_direct(
             if !visit_ob.get_is_hidden() {
                 updates.push(("hidden", ":hidden", &false));
             }
-            if visit_type == VisitTransition::Typed {
+            // The user didn't type URLs visited on other devices here.
+            if visit_type == VisitTransition::Typed && source.is_local() {
                 page_info.typed += 1;
                 updates.push(("typed", ":typed", &page_info.typed));
             }
 
-            let at = visit_ob.at.unwrap_or_else(Timestamp::now);
-            let is_remote = visit_ob.is_remote.unwrap_or(false);
-            let row_id = add_visit(db, page_info.row_id, None, at, visit_type, !is_remote)?;
+            let at = visit_ob.at.unwrap_or_else(|| db.now());
+            // Link the visit to the most recent visit to its referrer, so
+            // that frecency can follow redirect chains.
+            let referrer = match visit_ob.referrer {
+                Some(ref referrer) => find_referrer_visit(db, referrer, at)?,
+                None => None,
+            };
+            let row_id = add_visit(
+                db,
+                page_info.row_id,
+                referrer.map(|r| r.visit_id),
+                at,
+                visit_type,
+                source.is_local(),
+            )?;
+            if let Some(ref search_term) = visit_ob.search_term {
+                if source.is_local() && !search_term.is_empty() {
+                    add_search_term(db, row_id, page_info.row_id, search_term)?;
+                }
+            }
+            if visit_type == VisitTransition::RedirectPermanent
+                || visit_type == VisitTransition::RedirectTemporary
+            {
+                redirect_source = referrer
+                    .map(|r| r.place_id)
+                    .filter(|&place_id| place_id != page_info.row_id);
+            }
             // a new visit implies new frecency except in error cases.
             if !visit_ob.is_error.unwrap_or(false) {
                 update_frec = true;
@@ -109,19 +314,84 @@ pub fn apply_observation_direct(
     }
     // This needs to happen after the other updates.
     if update_frec {
-        update_frecency(
-            &db,
-            page_info.row_id,
-            Some(visit_ob.get_redirect_frecency_boost()),
-        )?;
+        // Redirect hints only make sense for local visits; for synced ones,
+        // frecency falls back to the redirects we've stored.
+        let redirect_boost = if source.is_local() {
+            Some(visit_ob.get_redirect_frecency_boost())
+        } else {
+            None
+        };
+        update_frecency(&db, page_info.row_id, redirect_boost)?;
+        // The referrer of a redirect is now a redirect source, so its
+        // frecency needs to use the redirect bonus for its latest visit.
+        if let Some(place_id) = redirect_source {
+            update_frecency(&db, place_id, None)?;
+        }
     }
     Ok(visit_row_id)
 }
 
+#[derive(Clone, Copy)]
+struct ReferrerVisit {
+    visit_id: RowId,
+    place_id: RowId,
+}
+
+// Finds the most recent visit to `referrer` at or before `visit_date`.
+// Referrers that aren't valid URLs, or that we haven't visited, are ignored.
+fn find_referrer_visit(
+    db: &PlacesDb,
+    referrer: &str,
+    visit_date: Timestamp,
+) -> Result<Option<ReferrerVisit>> {
+    let url = match Url::parse(referrer) {
+        Ok(url) => url,
+        Err(_) => return Ok(None),
+    };
+    Ok(db.try_query_row(
+        "SELECT v.id, v.place_id FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.url_hash = hash(:url) AND
+               h.url = :url AND
+               v.visit_date <= :visit_date
+         ORDER BY v.visit_date DESC
+         LIMIT 1",
+        &[(":url", &url.as_str()), (":visit_date", &visit_date)],
+        |row| -> rusqlite::Result<_> {
+            Ok(ReferrerVisit {
+                visit_id: row.get(0)?,
+                place_id: row.get(1)?,
+            })
+        },
+        true,
+    )?)
+}
+
+fn add_search_term(
+    db: &PlacesDb,
+    visit_id: RowId,
+    place_id: RowId,
+    search_term: &str,
+) -> Result<()> {
+    db.execute_named_cached(
+        "INSERT OR REPLACE INTO moz_historyvisit_search_terms(visit_id, place_id, search_term)
+         VALUES (:visit_id, :place_id, :search_term)",
+        &[
+            (":visit_id", &visit_id),
+            (":place_id", &place_id),
+            (
+                ":search_term",
+                &crate::util::slice_up_to(search_term, super::TITLE_LENGTH_MAX),
+            ),
+        ],
+    )?;
+    Ok(())
+}
+
 pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -> Result<()> {
     let score = frecency::calculate_frecency(
         db.conn(),
-        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        &db.frecency_settings(),
         id.0, // TODO: calculate_frecency should take a RowId here.
         redirect_boost,
     )?;
@@ -137,6 +407,35 @@ pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -
     Ok(())
 }
 
+/// Recalculates frecencies for up to `limit` pages marked as stale in
+/// `moz_places_stale_frecencies`, most recently marked first. The origin
+/// frecency stats are updated as part of the same transaction. Returns the
+/// number of stale pages that remain, so that callers can run this
+/// incrementally, like from an idle task, until it returns 0.
+pub fn update_all_frecencies(db: &PlacesDb, limit: usize) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let place_ids = db.query_rows_and_then_named(
+        "SELECT place_id FROM moz_places_stale_frecencies
+         ORDER BY stale_at DESC
+         LIMIT :limit",
+        &[(":limit", &(limit as i64))],
+        |row| -> rusqlite::Result<RowId> { row.get(0) },
+    )?;
+    for &place_id in &place_ids {
+        update_frecency(db, place_id, None)?;
+        db.execute_named_cached(
+            "DELETE FROM moz_places_stale_frecencies WHERE place_id = :place_id",
+            &[(":place_id", &place_id)],
+        )?;
+    }
+    // Flush pending origin changes, so that the origin frecencies and stats
+    // include the new frecencies.
+    delete_pending_temp_tables(db)?;
+    let remaining = db.query_one::<i64>("SELECT COUNT(*) FROM moz_places_stale_frecencies")?;
+    tx.commit()?;
+    Ok(remaining as usize)
+}
+
 /// Indicates if and when a URL's frecency was marked as stale.
 pub fn frecency_stale_at(db: &PlacesDb, url: &Url) -> Result<Option<Timestamp>> {
     let result = db.try_query_row(
@@ -203,6 +502,30 @@ pub fn url_to_guid(db: &PlacesDb, url: &Url) -> Result<Option<SyncGuid>> {
 /// Internal function for deleting a place, creating a tombstone if necessary.
 /// Assumes a transaction is already set up by the caller.
 fn do_delete_place_by_guid(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
+    let visits = db.query_rows_and_then_named(
+        "SELECT v.id, v.place_id, v.visit_date
+         FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.guid = :guid",
+        &[(":guid", guid)],
+        VisitToDelete::from_row,
+    )?;
+    record_deletion(
+        db,
+        DeletionOp::DeletePage,
+        DeletionInitiator::Api,
+        visits.len(),
+    )?;
+    let has_foreign = db.try_query_one::<bool>(
+        "SELECT foreign_count != 0 FROM moz_places WHERE guid = :guid",
+        &[(":guid", guid)],
+        true,
+    )?;
+    if has_foreign == Some(true) {
+        // We can't remove bookmarked pages, so remove all their visits
+        // instead. This writes visit tombstones, and updates the frecency.
+        return delete_visits_in_tx(db, &visits, true);
+    }
     // We only create tombstones for history which exists and with sync_status
     // == SyncStatus::Normal
     let sql = "INSERT OR IGNORE INTO moz_places_tombstones (guid)
@@ -216,12 +539,13 @@ fn do_delete_place_by_guid(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
     Ok(())
 }
 
-/// Delete a place given its guid, creating a tombstone if necessary.
+/// Delete a place given its guid, creating a tombstone if necessary. If the
+/// place is bookmarked, we keep it, and delete all its visits instead.
 pub fn delete_place_by_guid(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
     let tx = db.begin_transaction()?;
-    let result = do_delete_place_by_guid(db, guid);
+    do_delete_place_by_guid(db, guid)?;
     tx.commit()?;
-    result
+    Ok(())
 }
 
 /// Delete all visits in a date range.
@@ -246,17 +570,23 @@ pub fn prune_destructively(db: &PlacesDb) -> Result<()> {
 
 pub fn wipe_local(db: &PlacesDb) -> Result<()> {
     let tx = db.begin_transaction()?;
-    wipe_local_in_tx(db, tx)?;
+    wipe_local_in_tx(db, tx, DeletionOp::WipeHistory)?;
     Ok(())
 }
 
-fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Result<()> {
-    use crate::frecency::DEFAULT_FRECENCY_SETTINGS;
+fn wipe_local_in_tx(
+    db: &PlacesDb,
+    tx: crate::db::PlacesTransaction<'_>,
+    op: DeletionOp,
+) -> Result<()> {
+    let visit_count = db.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?;
+    record_deletion(db, op, DeletionInitiator::Api, visit_count as usize)?;
     db.execute_all(&[
         "DELETE FROM moz_places WHERE foreign_count == 0",
         "DELETE FROM moz_historyvisits",
         "DELETE FROM moz_places_tombstones",
         "DELETE FROM moz_inputhistory",
+        "DELETE FROM moz_historyvisit_search_terms",
         "DELETE FROM moz_historyvisit_tombstones",
         "DELETE FROM moz_origins
          WHERE id NOT IN (SELECT origin_id FROM moz_places)",
@@ -264,7 +594,7 @@ fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Resu
             "UPDATE moz_places SET
                 frecency = {unvisited_bookmark_frec},
                 sync_change_counter = 0",
-            unvisited_bookmark_frec = DEFAULT_FRECENCY_SETTINGS.unvisited_bookmark_bonus
+            unvisited_bookmark_frec = db.frecency_settings().unvisited_bookmark_bonus
         ),
     ])?;
 
@@ -294,16 +624,153 @@ pub fn delete_everything(db: &PlacesDb) -> Result<()> {
     let previous_mark =
         get_meta::<Timestamp>(db, DELETION_HIGH_WATER_MARK_META_KEY)?.unwrap_or_default();
 
-    let new_mark = Timestamp::now()
+    let new_mark = db
+        .now()
         .max(previous_mark)
         .max(most_recent_known_visit_time);
 
-    put_meta(db, DELETION_HIGH_WATER_MARK_META_KEY, &new_mark)?;
+    put_internal_meta(db, DELETION_HIGH_WATER_MARK_META_KEY, &new_mark)?;
+
+    wipe_local_in_tx(db, tx, DeletionOp::DeleteEverything)?;
+    Ok(())
+}
+
+/// An automatic history retention policy, like "keep 90 days of history",
+/// which `run_maintenance` enforces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryRetention {
+    /// Visits older than this are deleted. We also ignore incoming synced
+    /// visits older than this, so that they don't trickle back in.
+    pub max_age_days: u32,
+    /// If set, we also delete all visits to the least recently visited
+    /// pages, until at most this many pages have visits.
+    pub max_pages: Option<u32>,
+    /// If true, we write tombstones for expired visits and pages, so that
+    /// other devices delete them, too. If false, expiration is local-only,
+    /// and other devices keep their copies.
+    pub sync_deletions: bool,
+}
+
+impl HistoryRetention {
+    /// Returns the time before which visits are expired, as of `now`.
+    fn cutoff(&self, now: Timestamp) -> Timestamp {
+        Timestamp(
+            now.as_millis()
+                .saturating_sub(u64::from(self.max_age_days) * MILLIS_PER_DAY),
+        )
+    }
+}
 
-    wipe_local_in_tx(db, tx)?;
+/// Sets the history retention policy, or clears it if `policy` is `None`.
+/// The policy takes effect on the next call to `run_maintenance`.
+pub fn set_history_retention(db: &PlacesDb, policy: Option<HistoryRetention>) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    match policy {
+        Some(policy) => {
+            put_internal_meta(db, RETENTION_DAYS_META_KEY, &policy.max_age_days)?;
+            match policy.max_pages {
+                Some(max_pages) => put_internal_meta(db, RETENTION_MAX_PAGES_META_KEY, &max_pages)?,
+                None => {
+                    delete_internal_meta(db, RETENTION_MAX_PAGES_META_KEY)?;
+                    delete_internal_meta(db, RETENTION_EVICTED_MARK_META_KEY)?;
+                }
+            }
+            put_internal_meta(
+                db,
+                RETENTION_SYNC_DELETIONS_META_KEY,
+                &policy.sync_deletions,
+            )?;
+        }
+        None => {
+            delete_internal_meta(db, RETENTION_DAYS_META_KEY)?;
+            delete_internal_meta(db, RETENTION_MAX_PAGES_META_KEY)?;
+            delete_internal_meta(db, RETENTION_SYNC_DELETIONS_META_KEY)?;
+            delete_internal_meta(db, RETENTION_EVICTED_MARK_META_KEY)?;
+        }
+    }
+    tx.commit()?;
     Ok(())
 }
 
+pub fn get_history_retention(db: &PlacesDb) -> Result<Option<HistoryRetention>> {
+    let max_age_days = match get_meta::<u32>(db, RETENTION_DAYS_META_KEY)? {
+        Some(max_age_days) => max_age_days,
+        None => return Ok(None),
+    };
+    Ok(Some(HistoryRetention {
+        max_age_days,
+        max_pages: get_meta(db, RETENTION_MAX_PAGES_META_KEY)?,
+        sync_deletions: get_meta(db, RETENTION_SYNC_DELETIONS_META_KEY)?.unwrap_or(true),
+    }))
+}
+
+/// Deletes at most `limit` visits that the retention policy says we
+/// shouldn't keep, oldest first. Returns the number of visits deleted. If
+/// that's `limit`, there may be more to delete on the next call.
+pub fn enforce_history_retention(db: &PlacesDb, limit: usize) -> Result<usize> {
+    let policy = match get_history_retention(db)? {
+        Some(policy) => policy,
+        None => return Ok(0),
+    };
+    let tx = db.begin_transaction()?;
+    let cutoff = policy.cutoff(db.now());
+    let expired = db.query_rows_and_then_named(
+        "SELECT id, place_id, visit_date
+         FROM moz_historyvisits
+         WHERE visit_date < :cutoff
+         ORDER BY visit_date
+         LIMIT :limit",
+        &[(":cutoff", &cutoff), (":limit", &(limit as i64))],
+        VisitToDelete::from_row,
+    )?;
+    delete_visits_in_tx(db, &expired, policy.sync_deletions)?;
+    let mut deleted = expired.len();
+
+    if let Some(max_pages) = policy.max_pages {
+        let remaining = limit - deleted;
+        let pages_with_visits = db.query_one::<i64>(
+            "SELECT COUNT(*) FROM moz_places
+             WHERE last_visit_date_local + last_visit_date_remote <> 0",
+        )?;
+        let excess_pages = pages_with_visits - i64::from(max_pages);
+        if remaining > 0 && excess_pages > 0 {
+            let evicted = db.query_rows_and_then_named(
+                "SELECT id, place_id, visit_date
+                 FROM moz_historyvisits
+                 WHERE place_id IN (
+                   SELECT id FROM moz_places
+                   WHERE last_visit_date_local + last_visit_date_remote <> 0
+                   ORDER BY MAX(last_visit_date_local, last_visit_date_remote)
+                   LIMIT :excessPages
+                 )
+                 ORDER BY visit_date
+                 LIMIT :limit",
+                &[
+                    (":excessPages", &excess_pages),
+                    (":limit", &(remaining as i64)),
+                ],
+                VisitToDelete::from_row,
+            )?;
+            delete_visits_in_tx(db, &evicted, policy.sync_deletions)?;
+            if let Some(newest) = evicted.iter().map(|v| v.visit_date).max() {
+                let mark = get_meta::<Timestamp>(db, RETENTION_EVICTED_MARK_META_KEY)?
+                    .unwrap_or_default()
+                    .max(newest);
+                put_internal_meta(db, RETENTION_EVICTED_MARK_META_KEY, &mark)?;
+            }
+            deleted += evicted.len();
+        }
+    }
+    record_deletion(
+        db,
+        DeletionOp::ExpireVisits,
+        DeletionInitiator::Maintenance,
+        deleted,
+    )?;
+    tx.commit()?;
+    Ok(deleted)
+}
+
 fn delete_place_visit_at_time_in_tx(db: &PlacesDb, url: &str, visit_date: Timestamp) -> Result<()> {
     let place = db.conn().try_query_row(
         "SELECT h.id
@@ -351,7 +818,7 @@ fn delete_place_visit_at_time_in_tx(db: &PlacesDb, url: &str, visit_date: Timest
         true,
     )?;
 
-    cleanup_pages(db, &[to_clean])?;
+    cleanup_pages(db, &[to_clean], true)?;
     delete_pending_temp_tables(db)?;
     Ok(())
 }
@@ -369,18 +836,45 @@ pub fn delete_visits_between_in_tx(db: &PlacesDb, start: Timestamp, end: Timesta
     let visits = db.query_rows_and_then_named(
         sql,
         &[(":start", &start), (":end", &end)],
-        |row| -> rusqlite::Result<_> {
-            Ok((
-                row.get::<_, RowId>(0)?,
-                row.get::<_, RowId>(1)?,
-                row.get::<_, Timestamp>(2)?,
-            ))
-        },
+        VisitToDelete::from_row,
     )?;
+    delete_visits_in_tx(db, &visits, true)?;
+    record_deletion(
+        db,
+        DeletionOp::DeleteVisits,
+        DeletionInitiator::Api,
+        visits.len(),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct VisitToDelete {
+    id: RowId,
+    place_id: RowId,
+    visit_date: Timestamp,
+}
+
+impl VisitToDelete {
+    pub(super) fn from_row(row: &Row<'_>) -> RusqliteResult<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            place_id: row.get(1)?,
+            visit_date: row.get(2)?,
+        })
+    }
+}
 
+/// Deletes visits, and cleans up pages that have no more visits. If
+/// `write_tombstones` is false, other devices won't find out about the
+/// deletions.
+pub(super) fn delete_visits_in_tx(
+    db: &PlacesDb,
+    visits: &[VisitToDelete],
+    write_tombstones: bool,
+) -> Result<()> {
     sql_support::each_chunk_mapped(
-        &visits,
-        |(visit_id, _, _)| visit_id,
+        visits,
+        |visit| visit.id,
         |chunk, _| -> Result<()> {
             db.conn().execute(
                 &format!(
@@ -394,12 +888,12 @@ pub fn delete_visits_between_in_tx(db: &PlacesDb, start: Timestamp, end: Timesta
     )?;
 
     // Insert tombstones for the deleted visits.
-    if !visits.is_empty() {
+    if write_tombstones && !visits.is_empty() {
         let sql = format!(
             "INSERT OR IGNORE INTO moz_historyvisit_tombstones(place_id, visit_date) VALUES {}",
             sql_support::repeat_display(visits.len(), ",", |i, f| {
-                let (_, place_id, visit_date) = visits[i];
-                write!(f, "({},{})", place_id.0, visit_date.0)
+                let visit = visits[i];
+                write!(f, "({},{})", visit.place_id.0, visit.visit_date.0)
             })
         );
         db.conn().execute(&sql, NO_PARAMS)?;
@@ -407,8 +901,8 @@ pub fn delete_visits_between_in_tx(db: &PlacesDb, start: Timestamp, end: Timesta
 
     // Find out which pages have been possibly orphaned and clean them up.
     sql_support::each_chunk_mapped(
-        &visits,
-        |(_, place_id, _)| place_id.0,
+        visits,
+        |visit| visit.place_id.0,
         |chunk, _| -> Result<()> {
             let query = format!(
                 "SELECT id, -- url, url_hash, guid
@@ -422,7 +916,7 @@ pub fn delete_visits_between_in_tx(db: &PlacesDb, start: Timestamp, end: Timesta
             let mut stmt = db.conn().prepare(&query)?;
             let page_results = stmt.query_and_then(chunk, PageToClean::from_row)?;
             let pages: Vec<PageToClean> = page_results.collect::<Result<_>>()?;
-            cleanup_pages(db, &pages)
+            cleanup_pages(db, &pages, write_tombstones)
         },
     )?;
     delete_pending_temp_tables(db)?;
@@ -450,8 +944,9 @@ impl PageToClean {
 /// removing them entirely (if they are marked for removal,
 /// typically because all visits have been removed and there
 /// are no more foreign keys such as bookmarks) or updating
-/// their frecency.
-fn cleanup_pages(db: &PlacesDb, pages: &[PageToClean]) -> Result<()> {
+/// their frecency. Removed pages that we've synced get tombstones, unless
+/// `write_tombstones` is false.
+fn cleanup_pages(db: &PlacesDb, pages: &[PageToClean], write_tombstones: bool) -> Result<()> {
     // desktop does this frecency work using a function in a single sql
     // statement - we should see if we can do that too.
     let frec_ids = pages
@@ -475,20 +970,22 @@ fn cleanup_pages(db: &PlacesDb, pages: &[PageToClean]) -> Result<()> {
         .collect();
     sql_support::each_chunk(&remove_ids, |chunk, _| -> Result<()> {
         // tombstones first.
-        db.conn().execute(
-            &format!(
-                "
+        if write_tombstones {
+            db.conn().execute(
+                &format!(
+                    "
                 INSERT OR IGNORE INTO moz_places_tombstones (guid)
                 SELECT guid FROM moz_places
                 WHERE id in ({ids}) AND sync_status = {status}
                     AND foreign_count = 0
                     AND last_visit_date_local = 0
                     AND last_visit_date_remote = 0",
-                ids = sql_support::repeat_sql_vars(chunk.len()),
-                status = SyncStatus::Normal as u8,
-            ),
-            chunk,
-        )?;
+                    ids = sql_support::repeat_sql_vars(chunk.len()),
+                    status = SyncStatus::Normal as u8,
+                ),
+                chunk,
+            )?;
+        }
         db.conn().execute(
             &format!(
                 "
@@ -598,25 +1095,51 @@ pub mod history_sync {
         delete_pending_temp_tables(db)
     }
 
-    /// Apply history visit from sync. This assumes they have all been
-    /// validated, deduped, etc - it's just the storage we do here.
+    /// A visit from an incoming history record, after validation.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct SyncedVisit {
+        pub date: Timestamp,
+        pub transition: VisitTransition,
+    }
+
+    /// Applies all visits from an incoming record to the page for `url`,
+    /// creating the page if it doesn't exist. This assumes the visits have
+    /// been validated - it's just the storage we do here. Returns the number
+    /// of visits that were new.
+    ///
+    /// Records carry up to 20 visits, so we look up the page, insert the
+    /// visits, and recalculate frecency once per record instead of once per
+    /// visit.
     pub fn apply_synced_visits(
         db: &PlacesDb,
-        incoming_guid: &SyncGuid,
         url: &Url,
+        incoming_guid: &SyncGuid,
         title: &Option<String>,
-        visits: &[HistoryRecordVisit],
-    ) -> Result<()> {
+        visits: &[SyncedVisit],
+    ) -> Result<usize> {
         // At some point we may have done a local wipe of all visits. We skip applying
         // incoming visits that could have been part of that deletion, to avoid them
         // trickling back in.
-        let visit_ignored_mark =
+        let mut visit_ignored_mark =
             get_meta::<Timestamp>(db, DELETION_HIGH_WATER_MARK_META_KEY)?.unwrap_or_default();
+        // Likewise, we'd just expire visits older than the retention period
+        // on the next maintenance call, or evict them again if they're older
+        // than the visits we evicted to stay under the page limit.
+        if let Some(policy) = get_history_retention(db)? {
+            visit_ignored_mark = visit_ignored_mark.max(policy.cutoff(db.now()));
+            if policy.max_pages.is_some() {
+                if let Some(mark) = get_meta::<Timestamp>(db, RETENTION_EVICTED_MARK_META_KEY)? {
+                    visit_ignored_mark = visit_ignored_mark.max(mark);
+                }
+            }
+        }
 
+        // Make sure that even if a history entry weirdly has the same visit
+        // twice, we don't insert it twice.
+        let mut seen = HashSet::with_capacity(visits.len());
         let visits = visits
             .iter()
-            .cloned()
-            .filter(|v| Timestamp::from(v.date) > visit_ignored_mark)
+            .filter(|v| v.date > visit_ignored_mark && seen.insert(**v))
             .collect::<Vec<_>>();
 
         let mut counter_incr = 0;
@@ -645,58 +1168,42 @@ pub mod history_sync {
                 // Before we insert a new page_info, make sure we actually will
                 // have any visits to add.
                 if visits.is_empty() {
-                    return Ok(());
+                    return Ok(0);
                 }
                 new_page_info(db, &url, Some(incoming_guid.clone()))?
             }
         };
 
+        let mut new_visits = 0;
         if !visits.is_empty() {
-            // Skip visits that are in tombstones, or that happen at the same time
-            // as visit that's already present. The 2nd lets us avoid inserting
-            // visits that we sent up to the server in the first place.
-            //
-            // It does cause us to ignore visits that legitimately happen
-            // at the same time, but that's probably fine and not worth
-            // worrying about.
-            let mut visits_to_skip: HashSet<Timestamp> = db.query_rows_into(
+            // Insert all visits in one statement, skipping visits that are in
+            // tombstones, or that exactly match a visit that's already present.
+            // The latter lets us avoid inserting visits that we sent up to the
+            // server in the first place. The insert trigger updates the remote
+            // visit count and last visit date. Dates and transitions are
+            // numbers, so it's safe to interpolate them, and we don't need to
+            // worry about the bound parameter limit.
+            new_visits = db.conn().execute(
                 &format!(
-                    "SELECT t.visit_date AS visit_date
-                     FROM moz_historyvisit_tombstones t
-                     WHERE t.place_id = {place}
-                       AND t.visit_date IN ({dates})
-                     UNION ALL
-                     SELECT v.visit_date AS visit_date
-                     FROM moz_historyvisits v
-                     WHERE v.place_id = {place}
-                       AND v.visit_date IN ({dates})",
+                    "INSERT INTO moz_historyvisits(place_id, visit_date, visit_type, is_local)
+                     SELECT {place}, v.column1, v.column2, 0
+                     FROM (VALUES {values}) v
+                     WHERE NOT EXISTS(SELECT 1 FROM moz_historyvisits e
+                                      WHERE e.place_id = {place}
+                                        AND e.visit_date = v.column1
+                                        AND e.visit_type = v.column2)
+                       AND NOT EXISTS(SELECT 1 FROM moz_historyvisit_tombstones t
+                                      WHERE t.place_id = {place}
+                                        AND t.visit_date = v.column1)",
                     place = page_info.row_id,
-                    dates = sql_support::repeat_display(visits.len(), ",", |i, f| write!(
+                    values = sql_support::repeat_display(visits.len(), ",", |i, f| write!(
                         f,
-                        "{}",
-                        Timestamp::from(visits[i].date).0
+                        "({}, {})",
+                        visits[i].date.0, visits[i].transition as u8
                     )),
                 ),
-                &[],
-                |row| row.get::<_, Timestamp>(0),
+                NO_PARAMS,
             )?;
-
-            visits_to_skip.reserve(visits.len());
-
-            for visit in visits {
-                let timestamp = Timestamp::from(visit.date);
-                // Don't insert visits that have been locally deleted.
-                if visits_to_skip.contains(&timestamp) {
-                    continue;
-                }
-                let transition = VisitTransition::from_primitive(visit.transition)
-                    .expect("these should already be validated");
-                add_visit(db, page_info.row_id, None, timestamp, transition, false)?;
-                // Make sure that even if a history entry weirdly has the same visit
-                // twice, we don't insert it twice. (This avoids us needing to
-                // recompute visits_to_skip in each step of the iteration)
-                visits_to_skip.insert(timestamp);
-            }
         }
         // XXX - we really need a better story for frecency-boost than
         // Option<bool> - None vs Some(false) is confusing. We should use an enum.
@@ -725,7 +1232,7 @@ pub mod history_sync {
             ],
         )?;
 
-        Ok(())
+        Ok(new_visits)
     }
 
     pub fn apply_synced_reconciliation(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
@@ -739,14 +1246,35 @@ pub mod history_sync {
         Ok(())
     }
 
-    pub fn apply_synced_deletion(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
+    /// Applies an incoming tombstone for a page. Returns true if we removed
+    /// the page, or false if it didn't exist or is bookmarked.
+    pub fn apply_synced_deletion(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+        let has_foreign = db.try_query_one::<bool>(
+            "SELECT foreign_count != 0 FROM moz_places WHERE guid = :guid",
+            &[(":guid", guid)],
+            true,
+        )?;
+        if has_foreign == Some(true) {
+            // We can't remove bookmarked pages, so remove all their visits
+            // instead. Like below, we don't want tombstones for these.
+            let visits = db.query_rows_and_then_named(
+                "SELECT v.id, v.place_id, v.visit_date
+                 FROM moz_historyvisits v
+                 JOIN moz_places h ON h.id = v.place_id
+                 WHERE h.guid = :guid",
+                &[(":guid", guid)],
+                VisitToDelete::from_row,
+            )?;
+            delete_visits_in_tx(db, &visits, false)?;
+            return Ok(false);
+        }
         // Note that we don't use delete_place_by_guid because we do not want
         // a local tombstone for this item.
-        db.execute_named_cached(
+        let changes = db.execute_named_cached(
             "DELETE FROM moz_places WHERE guid = :guid",
             &[(":guid", guid)],
         )?;
-        Ok(())
+        Ok(changes > 0)
     }
 
     #[derive(Debug)]
@@ -755,6 +1283,67 @@ pub mod history_sync {
         Tombstone,
     }
 
+    /// Fetches the most recent `max_visits` visits for each place, newest
+    /// first. Window functions let us fetch them for many places at once;
+    /// older SQLite versions fall back to a query per place.
+    fn fetch_outgoing_visits(
+        db: &PlacesDb,
+        place_ids: &[RowId],
+        max_visits: usize,
+    ) -> Result<HashMap<RowId, Vec<HistoryRecordVisit>>> {
+        fn visit_from_row(row: &Row<'_>) -> RusqliteResult<HistoryRecordVisit> {
+            Ok(HistoryRecordVisit {
+                date: row.get::<_, Timestamp>("date")?.into(),
+                transition: row.get::<_, u8>("transition")?,
+            })
+        }
+
+        let mut visits_by_place: HashMap<RowId, Vec<HistoryRecordVisit>> = HashMap::new();
+        if db.sqlite_features().window_functions {
+            sql_support::each_chunk(place_ids, |chunk, _| -> Result<()> {
+                let mut stmt = db.prepare(&format!(
+                    "SELECT place_id, date, transition
+                     FROM (SELECT place_id, visit_date AS date, visit_type AS transition,
+                                  ROW_NUMBER() OVER (PARTITION BY place_id
+                                                     ORDER BY visit_date DESC) AS visit_rank
+                           FROM moz_historyvisits
+                           WHERE place_id IN ({vars}))
+                     WHERE visit_rank <= {max_visits}
+                     ORDER BY place_id, date DESC",
+                    vars = sql_support::repeat_sql_vars(chunk.len()),
+                    max_visits = max_visits,
+                ))?;
+                let mut rows = stmt.query(chunk)?;
+                while let Some(row) = rows.next()? {
+                    visits_by_place
+                        .entry(row.get("place_id")?)
+                        .or_default()
+                        .push(visit_from_row(row)?);
+                }
+                Ok(())
+            })?;
+        } else {
+            for &place_id in place_ids {
+                let visits = db.query_rows_and_then_named_cached(
+                    "SELECT visit_date as date, visit_type as transition
+                     FROM moz_historyvisits
+                     WHERE place_id = :place_id
+                     ORDER BY visit_date DESC
+                     LIMIT :max_visits",
+                    &[
+                        (":max_visits", &(max_visits as u32)),
+                        (":place_id", &place_id),
+                    ],
+                    visit_from_row,
+                )?;
+                if !visits.is_empty() {
+                    visits_by_place.insert(place_id, visits);
+                }
+            }
+        }
+        Ok(visits_by_place)
+    }
+
     pub fn fetch_outgoing(
         db: &PlacesDb,
         max_places: usize,
@@ -769,17 +1358,13 @@ pub mod history_sync {
                 last_visit_date_local, last_visit_date_remote,
                 sync_status, sync_change_counter
             FROM moz_places
-            WHERE (sync_change_counter > 0 OR sync_status != {})
+            WHERE (sync_change_counter > 0 OR sync_status != {normal}) AND
+                  NOT {url_too_long}
             ORDER BY frecency DESC
             LIMIT :max_places",
-            (SyncStatus::Normal as u8)
+            normal = SyncStatus::Normal as u8,
+            url_too_long = crate::storage::oversized::url_too_long_fragment("url"),
         );
-        let visits_sql = "
-            SELECT visit_date as date, visit_type as transition
-            FROM moz_historyvisits
-            WHERE place_id = :place_id
-            ORDER BY visit_date DESC
-            LIMIT :max_visits";
         // tombstones
         let tombstones_sql = "SELECT guid FROM moz_places_tombstones LIMIT :max_places";
 
@@ -824,21 +1409,11 @@ pub mod history_sync {
             &[(":max_places", &(max_places_left as u32))],
             PageInfo::from_row,
         )?;
+        let place_ids = rows.iter().map(|page| page.row_id).collect::<Vec<_>>();
+        let mut visits_by_place = fetch_outgoing_visits(db, &place_ids, max_visits)?;
         let mut ids_to_update = Vec::with_capacity(rows.len());
         for page in rows {
-            let visits = db.query_rows_and_then_named_cached(
-                visits_sql,
-                &[
-                    (":max_visits", &(max_visits as u32)),
-                    (":place_id", &page.row_id),
-                ],
-                |row| -> RusqliteResult<_> {
-                    Ok(HistoryRecordVisit {
-                        date: row.get::<_, Timestamp>("date")?.into(),
-                        transition: row.get::<_, u8>("transition")?,
-                    })
-                },
-            )?;
+            let visits = visits_by_place.remove(&page.row_id).unwrap_or_default();
             if result.contains_key(&page.guid) {
                 // should be impossible!
                 log::warn!("Found {:?} in both tombstones and live records", &page.guid);
@@ -951,6 +1526,10 @@ pub mod history_sync {
     }
 } // end of sync module.
 
+/// Returns whether we've visited each URL in `urls`, in the same order. We
+/// count hidden pages, like redirect sources, as visited. There's no limit on
+/// the number of URLs; we split them into chunks to stay under SQLite's
+/// variable limit.
 pub fn get_visited<I>(db: &PlacesDb, urls: I) -> Result<Vec<bool>>
 where
     I: IntoIterator<Item = Url>,
@@ -1003,6 +1582,76 @@ pub fn get_visited_into(
     Ok(())
 }
 
+/// Returns the frecency of each URL in `urls`, or `None` if we don't know the
+/// URL. Results are in the same order as `urls`, which may contain
+/// duplicates. This is used to rank results from external providers, like
+/// search suggestions and open tabs, alongside our own.
+pub fn get_frecencies(db: &PlacesDb, urls: &[Url]) -> Result<Vec<Option<i32>>> {
+    let mut result = vec![None; urls.len()];
+    sql_support::each_chunk_mapped(
+        urls,
+        |url| url.as_str(),
+        |chunk, offset| -> Result<()> {
+            let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| {
+                let idx = i + offset;
+                write!(f, "({},{},?)", idx, hash::hash_url(urls[idx].as_str()))
+            });
+            let sql = format!(
+                "WITH to_fetch(fetch_url_index, url_hash, url) AS (VALUES {})
+                 SELECT fetch_url_index, h.frecency
+                 FROM moz_places h
+                 JOIN to_fetch f ON h.url_hash = f.url_hash
+                   AND h.url = f.url",
+                values_with_idx
+            );
+            let mut stmt = db.prepare(&sql)?;
+            for row_r in stmt.query_and_then(chunk, |row| -> rusqlite::Result<_> {
+                Ok((row.get::<_, i64>(0)? as usize, row.get::<_, i32>(1)?))
+            })? {
+                let (idx, frecency) = row_r?;
+                result[idx] = Some(frecency);
+            }
+            Ok(())
+        },
+    )?;
+    Ok(result)
+}
+
+/// Like `get_frecencies`, but returns the highest frecency of any origin with
+/// the same host and port as each URL. This is cheaper and coarser than
+/// ranking by URL, and also ranks pages we haven't visited, but whose sites
+/// we have. URLs without a host are always `None`.
+pub fn get_origin_frecencies(db: &PlacesDb, urls: &[Url]) -> Result<Vec<Option<i32>>> {
+    let mut result = vec![None; urls.len()];
+    sql_support::each_chunk_mapped(
+        urls,
+        |url| url.as_str(),
+        |chunk, offset| -> Result<()> {
+            let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| {
+                write!(f, "({},get_host_and_port(?))", i + offset)
+            });
+            let sql = format!(
+                "WITH to_fetch(fetch_url_index, host) AS (VALUES {})
+                 SELECT fetch_url_index, MAX(o.frecency)
+                 FROM moz_origins o
+                 JOIN to_fetch f ON o.host = f.host
+                 WHERE f.host <> ''
+                 GROUP BY fetch_url_index",
+                values_with_idx
+            );
+            let mut stmt = db.prepare(&sql)?;
+            for row_r in stmt.query_and_then(chunk, |row| -> rusqlite::Result<_> {
+                Ok((row.get::<_, i64>(0)? as usize, row.get::<_, i32>(1)?))
+            })? {
+                let (idx, frecency) = row_r?;
+                result[idx] = Some(frecency);
+            }
+            Ok(())
+        },
+    )?;
+    Ok(result)
+}
+
 /// Get the set of urls that were visited between `start` and `end`. Only considers local visits
 /// unless you pass in `include_remote`.
 pub fn get_visited_urls(
@@ -1033,25 +1682,48 @@ pub fn get_visited_urls(
     )?)
 }
 
+/// Returns the URLs of all pages, local or remote, with visits between
+/// `start` and `end`, inclusive.
+pub fn get_visited_urls_in_range(
+    db: &PlacesDb,
+    start: Timestamp,
+    end: Timestamp,
+) -> Result<Vec<Url>> {
+    get_visited_urls(db, start, end, true)?
+        .iter()
+        .map(|url| Ok(Url::parse(url)?))
+        .collect()
+}
+
+/// Returns all visits between `start` and `end`, inclusive, oldest first.
+/// Visits to hidden pages, like redirect sources, are only included if
+/// `include_hidden` is true.
 pub fn get_visit_infos(
     db: &PlacesDb,
     start: Timestamp,
     end: Timestamp,
     exclude_types: VisitTransitionSet,
+    include_hidden: bool,
 ) -> Result<HistoryVisitInfos> {
     let allowed_types = exclude_types.complement();
     let infos = db.query_rows_and_then_named_cached(
-        "SELECT h.url, h.title, v.visit_date, v.visit_type
-         FROM moz_places h
-         JOIN moz_historyvisits v
-           ON h.id = v.place_id
-         WHERE v.visit_date BETWEEN :start AND :end
-           AND ((1 << visit_type) & :allowed_types) != 0
-         ORDER BY v.visit_date",
+        &format!(
+            "SELECT h.url, h.guid, {title} AS title, v.visit_date, v.visit_type,
+                    v.is_local
+             FROM moz_places h
+             JOIN moz_historyvisits v
+               ON h.id = v.place_id
+             WHERE v.visit_date BETWEEN :start AND :end
+               AND ((1 << visit_type) & :allowed_types) != 0
+               AND (:include_hidden OR NOT h.hidden)
+             ORDER BY v.visit_date",
+            title = super::display_title_fragment("h.title"),
+        ),
         rusqlite::named_params! {
             ":start": start,
             ":end": end,
             ":allowed_types": allowed_types,
+            ":include_hidden": include_hidden,
         },
         HistoryVisitInfo::from_row,
     )?;
@@ -1077,26 +1749,36 @@ pub fn get_visit_count(db: &PlacesDb, exclude_types: VisitTransitionSet) -> Resu
     Ok(count)
 }
 
+/// Returns a page of visits, newest first, for history UIs that load more
+/// visits as the user scrolls. Like `get_visit_infos`, visits to hidden pages
+/// are only included if `include_hidden` is true.
 pub fn get_visit_page(
     db: &PlacesDb,
     offset: i64,
     count: i64,
     exclude_types: VisitTransitionSet,
+    include_hidden: bool,
 ) -> Result<HistoryVisitInfos> {
     let allowed_types = exclude_types.complement();
     let infos = db.query_rows_and_then_named_cached(
-        "SELECT h.url, h.title, v.visit_date, v.visit_type
-         FROM moz_places h
-         JOIN moz_historyvisits v
-           ON h.id = v.place_id
-         WHERE ((1 << v.visit_type) & :allowed_types) != 0
-         ORDER BY v.visit_date DESC, v.id
-         LIMIT :count
-         OFFSET :offset",
+        &format!(
+            "SELECT h.url, h.guid, {title} AS title, v.visit_date, v.visit_type,
+                    v.is_local
+             FROM moz_places h
+             JOIN moz_historyvisits v
+               ON h.id = v.place_id
+             WHERE ((1 << v.visit_type) & :allowed_types) != 0
+               AND (:include_hidden OR NOT h.hidden)
+             ORDER BY v.visit_date DESC, v.id
+             LIMIT :count
+             OFFSET :offset",
+            title = super::display_title_fragment("h.title"),
+        ),
         rusqlite::named_params! {
             ":count": count,
             ":offset": offset,
             ":allowed_types": allowed_types,
+            ":include_hidden": include_hidden,
         },
         HistoryVisitInfo::from_row,
     )?;
@@ -1107,9 +1789,12 @@ pub fn get_visit_page(
 mod tests {
     use super::history_sync::*;
     use super::*;
+    use crate::api::matcher::accept_result;
     use crate::api::places_api::ConnectionType;
+    use crate::db::SqliteFeatures;
     use crate::history_sync::record::{HistoryRecord, HistoryRecordVisit};
-    use crate::types::Timestamp;
+    use crate::observation::ObservationSource;
+    use crate::types::{FrozenClock, Timestamp};
     use pretty_assertions::assert_eq;
     use std::time::{Duration, SystemTime};
 
@@ -1159,7 +1844,11 @@ mod tests {
                 &conn,
                 VisitObservation::new(Url::parse(url).unwrap())
                     .with_at(Timestamp(when))
-                    .with_is_remote(remote)
+                    .with_source(if remote {
+                        ObservationSource::Sync
+                    } else {
+                        ObservationSource::Local
+                    })
                     .with_visit_type(VisitTransition::Link),
             )
             .expect("Should apply visit");
@@ -1268,7 +1957,7 @@ mod tests {
             VisitObservation::new(url.clone())
                 .with_visit_type(VisitTransition::Link)
                 .with_at(Some(early_time.into()))
-                .with_is_remote(true),
+                .with_source(ObservationSource::Sync),
         )?
         .expect("should get a rowid");
 
@@ -1277,7 +1966,7 @@ mod tests {
             VisitObservation::new(url.clone())
                 .with_visit_type(VisitTransition::Link)
                 .with_at(Some(late_time.into()))
-                .with_is_remote(true),
+                .with_source(ObservationSource::Sync),
         )?
         .expect("should get a rowid");
 
@@ -1321,15 +2010,247 @@ mod tests {
     }
 
     #[test]
-    fn test_get_visited() -> Result<()> {
+    fn test_observation_source() -> Result<()> {
         let _ = env_logger::try_init();
         let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let local_url = Url::parse("https://example.com/local").expect("it's a valid url");
+        let synced_url = Url::parse("https://example.com/synced").expect("it's a valid url");
 
-        let unicode_in_path = "http://www.example.com/tëst😀abc";
-        let escaped_unicode_in_path = "http://www.example.com/t%C3%ABst%F0%9F%98%80abc";
-
-        let unicode_in_domain = "http://www.exämple😀123.com";
-        let escaped_unicode_in_domain = "http://www.xn--exmple123-w2a24222l.com";
+        for (url, source) in &[
+            (&local_url, ObservationSource::Local),
+            (&synced_url, ObservationSource::Sync),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new((*url).clone())
+                    .with_visit_type(VisitTransition::Typed)
+                    .with_source(*source),
+            )?;
+        }
+
+        // Synced visits are remote, and don't count as typed.
+        let local = fetch_page_info(&conn, &local_url)?
+            .expect("should have the local page")
+            .page;
+        assert_eq!(local.typed, 1);
+        assert_eq!(local.visit_count_local, 1);
+        assert_eq!(local.visit_count_remote, 0);
+        let synced = fetch_page_info(&conn, &synced_url)?
+            .expect("should have the synced page")
+            .page;
+        assert_eq!(synced.typed, 0);
+        assert_eq!(synced.visit_count_local, 0);
+        assert_eq!(synced.visit_count_remote, 1);
+
+        // Neither touches the input history, even for pages the user picked
+        // from autocomplete.
+        for url in &[&local_url, &synced_url] {
+            accept_result(&conn, "exam", url)?;
+        }
+        for (url, source) in &[
+            (&local_url, ObservationSource::Local),
+            (&synced_url, ObservationSource::Sync),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new((*url).clone())
+                    .with_visit_type(VisitTransition::Typed)
+                    .with_source(*source),
+            )?;
+        }
+        let mut stmt = conn.prepare(
+            "SELECT h.url, i.input, i.use_count
+             FROM moz_inputhistory i
+             JOIN moz_places h ON h.id = i.place_id
+             ORDER BY h.url",
+        )?;
+        let input_history = stmt
+            .query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, f64>(2)?,
+                ))
+            })?
+            .collect::<RusqliteResult<Vec<_>>>()?;
+        assert_eq!(
+            input_history,
+            vec![
+                (local_url.to_string(), "exam".to_string(), 1.0),
+                (synced_url.to_string(), "exam".to_string(), 1.0),
+            ]
+        );
+
+        // We use the redirect hint for local visits, but ignore it for synced
+        // ones.
+        let local_redirect_url =
+            Url::parse("https://example.com/local-redirect").expect("it's a valid url");
+        let synced_redirect_url =
+            Url::parse("https://example.com/synced-redirect").expect("it's a valid url");
+        for (url, source) in &[
+            (&local_redirect_url, ObservationSource::Local),
+            (&synced_redirect_url, ObservationSource::Sync),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new((*url).clone())
+                    .with_visit_type(VisitTransition::Link)
+                    .with_is_redirect_source(true)
+                    .with_source(*source),
+            )?;
+        }
+        let local_redirect = fetch_page_info(&conn, &local_redirect_url)?
+            .expect("should have the local redirect")
+            .page;
+        let synced_redirect = fetch_page_info(&conn, &synced_redirect_url)?
+            .expect("should have the synced redirect")
+            .page;
+        assert!(
+            local_redirect.frecency < synced_redirect.frecency,
+            "Local redirect source frecency {} should be less than synced {}",
+            local_redirect.frecency,
+            synced_redirect.frecency
+        );
+
+        // The legacy `is_remote` flag still works, but an explicit source
+        // wins.
+        let legacy: VisitObservation = serde_json::from_value(serde_json::json!({
+            "url": "https://example.com/",
+            "is_remote": true,
+        }))?;
+        assert_eq!(legacy.source(), ObservationSource::Sync);
+        let explicit: VisitObservation = serde_json::from_value(serde_json::json!({
+            "url": "https://example.com/",
+            "source": "local",
+            "is_remote": true,
+        }))?;
+        assert_eq!(explicit.source(), ObservationSource::Local);
+        assert_eq!(
+            VisitObservation::new(local_url).source(),
+            ObservationSource::Local
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_referrer_redirect_chain() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now().0;
+        let urls = (0..4)
+            .map(|i| Url::parse(&format!("https://example.com/{}", i)).expect("it's a valid url"))
+            .collect::<Vec<_>>();
+
+        // A link to page 0, which redirects 3 times to page 3. Only the
+        // first hop has a redirect hint, and only the final page has a search
+        // term.
+        let mut visit_ids = Vec::new();
+        for (i, url) in urls.iter().enumerate() {
+            let visit_type = match i {
+                0 => VisitTransition::Link,
+                1 => VisitTransition::RedirectPermanent,
+                _ => VisitTransition::RedirectTemporary,
+            };
+            let referrer = if i > 0 {
+                Some(urls[i - 1].clone())
+            } else {
+                None
+            };
+            let search_term = if i == 3 {
+                Some("example search".to_string())
+            } else {
+                None
+            };
+            let visit_id = apply_observation(
+                &conn,
+                VisitObservation::new(url.clone())
+                    .with_visit_type(visit_type)
+                    .with_at(Timestamp(now - 4000 + i as u64))
+                    .with_is_redirect_source(if i == 0 { Some(true) } else { None })
+                    .with_referrer(referrer)
+                    .with_search_term(search_term),
+            )?
+            .expect("should add a visit");
+            visit_ids.push(visit_id);
+        }
+        let control_url = Url::parse("https://example.com/control").expect("it's a valid url");
+        apply_observation(
+            &conn,
+            VisitObservation::new(control_url.clone())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp(now - 3999)),
+        )?;
+
+        let get_from_visit = |visit_id: RowId| -> Result<Option<RowId>> {
+            Ok(conn.query_row_and_then_named(
+                "SELECT from_visit FROM moz_historyvisits WHERE id = :id",
+                &[(":id", &visit_id)],
+                |row| row.get(0),
+                false,
+            )?)
+        };
+        assert_eq!(get_from_visit(visit_ids[0])?, None);
+        for pair in visit_ids.windows(2) {
+            assert_eq!(get_from_visit(pair[1])?, Some(pair[0]));
+        }
+
+        // Page 1 didn't have a redirect hint when we visited it, but it's
+        // since become a redirect source, so it should use the lower bonus.
+        let page_1 = fetch_page_info(&conn, &urls[1])?
+            .expect("should have page 1")
+            .page;
+        let control = fetch_page_info(&conn, &control_url)?
+            .expect("should have the control page")
+            .page;
+        assert!(
+            page_1.frecency < control.frecency,
+            "Redirect source frecency {} should be less than link frecency {}",
+            page_1.frecency,
+            control.frecency
+        );
+        assert_eq!(
+            page_1.frecency,
+            frecency::calculate_frecency(
+                conn.conn(),
+                &conn.frecency_settings(),
+                page_1.row_id.0,
+                None
+            )?
+        );
+
+        let search_term: String = conn.query_row_and_then_named(
+            "SELECT search_term FROM moz_historyvisit_search_terms WHERE visit_id = :id",
+            &[(":id", &visit_ids[3])],
+            |row| row.get(0),
+            false,
+        )?;
+        assert_eq!(search_term, "example search");
+
+        // Removing a visit in the middle of the chain unlinks the visit that
+        // came from it, and removing the final visit removes its search term.
+        conn.execute_named(
+            "DELETE FROM moz_historyvisits WHERE id IN (:middle, :last)",
+            &[(":middle", &visit_ids[1]), (":last", &visit_ids[3])],
+        )?;
+        assert_eq!(get_from_visit(visit_ids[2])?, None);
+        let search_terms_count: i64 =
+            conn.query_one("SELECT COUNT(*) FROM moz_historyvisit_search_terms")?;
+        assert_eq!(search_terms_count, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_visited() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+
+        let unicode_in_path = "http://www.example.com/tëst😀abc";
+        let escaped_unicode_in_path = "http://www.example.com/t%C3%ABst%F0%9F%98%80abc";
+
+        let unicode_in_domain = "http://www.exämple😀123.com";
+        let escaped_unicode_in_domain = "http://www.xn--exmple123-w2a24222l.com";
 
         let to_add = [
             "https://www.example.com/1".to_string(),
@@ -1401,6 +2322,150 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_visited_chunks() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now();
+        // More URLs than SQLite's default variable limit, so that we need
+        // multiple chunks.
+        let urls = (0..1500)
+            .map(|i| Url::parse(&format!("https://www.example.com/{}", i)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (i, url) in urls.iter().enumerate().filter(|(i, _)| i % 3 == 0) {
+            apply_observation(
+                &conn,
+                VisitObservation::new(url.clone())
+                    .with_at(Timestamp(now.as_millis() - i as u64))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        // Hidden pages count as visited.
+        conn.execute_named_cached(
+            "UPDATE moz_places SET hidden = 1 WHERE url = :url",
+            &[(":url", &urls[1497].as_str())],
+        )?;
+
+        // Ask in reverse order, to make sure we keep the order.
+        let visited = get_visited(&conn, urls.iter().rev().cloned())?;
+        assert_eq!(visited.len(), urls.len());
+        for (i, &did_see) in visited.iter().rev().enumerate() {
+            assert_eq!(did_see, i % 3 == 0, "Wrong value for {}", urls[i]);
+        }
+
+        let mut in_range = get_visited_urls_in_range(&conn, Timestamp(now.as_millis() - 9), now)?;
+        in_range.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(
+            in_range,
+            vec![
+                urls[0].clone(),
+                urls[3].clone(),
+                urls[6].clone(),
+                urls[9].clone()
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_visit_infos_and_pages() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now().as_millis();
+        let visits = [
+            ("https://www.example.com/a", VisitTransition::Link, false),
+            ("https://www.example.com/b", VisitTransition::Typed, true),
+            ("https://www.example.com/c", VisitTransition::Embed, false),
+            ("https://www.example.com/d", VisitTransition::Link, false),
+            ("https://www.example.com/e", VisitTransition::Typed, false),
+        ];
+        for (i, (url, visit_type, is_remote)) in visits.iter().enumerate() {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_at(Timestamp(now - 1000 + i as u64 * 100))
+                    .with_visit_type(*visit_type)
+                    .with_source(if *is_remote {
+                        ObservationSource::Sync
+                    } else {
+                        ObservationSource::Local
+                    }),
+            )?;
+        }
+        conn.execute_named_cached(
+            "UPDATE moz_places SET hidden = 1 WHERE url = :url",
+            &[(":url", &"https://www.example.com/d")],
+        )?;
+        let urls = |infos: HistoryVisitInfos| {
+            infos
+                .infos
+                .into_iter()
+                .map(|info| info.url)
+                .collect::<Vec<_>>()
+        };
+        let no_embeds = VisitTransitionSet::single(VisitTransition::Embed);
+
+        let infos = get_visit_infos(
+            &conn,
+            Timestamp(now - 1000),
+            Timestamp(now),
+            no_embeds,
+            false,
+        )?;
+        let page_guid = fetch_page_info(&conn, &Url::parse("https://www.example.com/b")?)?
+            .expect("Should have page info")
+            .page
+            .guid;
+        assert_eq!(infos.infos[1].guid, Some(page_guid.0));
+        assert_eq!(infos.infos[0].is_local, Some(true));
+        assert_eq!(infos.infos[1].is_local, Some(false));
+        assert_eq!(
+            urls(infos),
+            vec![
+                "https://www.example.com/a",
+                "https://www.example.com/b",
+                "https://www.example.com/e",
+            ]
+        );
+        assert_eq!(
+            urls(get_visit_infos(
+                &conn,
+                Timestamp(now - 1000),
+                Timestamp(now - 800),
+                no_embeds,
+                true,
+            )?),
+            vec!["https://www.example.com/a", "https://www.example.com/b"]
+        );
+        assert_eq!(
+            urls(get_visit_infos(
+                &conn,
+                Timestamp(now - 1000),
+                Timestamp(now),
+                VisitTransitionSet::empty(),
+                true,
+            )?)
+            .len(),
+            5
+        );
+
+        // Pages are newest first, and the last page may be short.
+        assert_eq!(
+            urls(get_visit_page(&conn, 0, 2, no_embeds, true)?),
+            vec!["https://www.example.com/e", "https://www.example.com/d"]
+        );
+        assert_eq!(
+            urls(get_visit_page(&conn, 2, 2, no_embeds, true)?),
+            vec!["https://www.example.com/b", "https://www.example.com/a"]
+        );
+        assert_eq!(
+            urls(get_visit_page(&conn, 2, 2, no_embeds, false)?),
+            vec!["https://www.example.com/a"]
+        );
+        assert!(get_visit_page(&conn, 4, 2, no_embeds, true)?
+            .infos
+            .is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_get_visited_into() {
         let _ = env_logger::try_init();
@@ -1454,6 +2519,134 @@ mod tests {
         assert_eq!(expect, results);
     }
 
+    #[test]
+    fn test_frecency_bucket_boundaries() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now();
+        conn.set_clock(FrozenClock::at(now))?;
+        let day = 24 * 60 * 60 * 1000;
+
+        let frecency_at_age = |age_in_days: u64| -> Result<i32> {
+            let url = Url::parse(&format!("https://www.example.com/{}", age_in_days))?;
+            apply_observation(
+                &conn,
+                VisitObservation::new(url.clone())
+                    .with_visit_type(VisitTransition::Link)
+                    .with_at(Timestamp(now.0 - age_in_days * day)),
+            )?;
+            Ok(fetch_page_info(&conn, &url)?
+                .expect("should have the page")
+                .page
+                .frecency)
+        };
+
+        let settings = &frecency::DEFAULT_FRECENCY_SETTINGS;
+        for &cutoff in &[
+            settings.first_bucket_cutoff_days,
+            settings.second_bucket_cutoff_days,
+            settings.third_bucket_cutoff_days,
+            settings.fourth_bucket_cutoff_days,
+        ] {
+            let cutoff = cutoff as u64;
+            let at_cutoff = frecency_at_age(cutoff)?;
+            assert_eq!(
+                frecency_at_age(cutoff - 1)?,
+                at_cutoff,
+                "Visits {} and {} days old should be in the same bucket",
+                cutoff - 1,
+                cutoff
+            );
+            assert!(
+                frecency_at_age(cutoff + 1)? < at_cutoff,
+                "Visits {} days old should be in an older bucket than {} days",
+                cutoff + 1,
+                cutoff
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_frecencies() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+
+        // Visit every tenth URL, so that we have known and unknown URLs in
+        // every chunk.
+        let urls = (0..1000)
+            .map(|i| Url::parse(&format!("https://www.example.com/{}", i)).unwrap())
+            .collect::<Vec<_>>();
+        for url in urls.iter().step_by(10) {
+            apply_observation(
+                &conn,
+                VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        let frecency_for = |url: &Url| -> Result<Option<i32>> {
+            Ok(fetch_page_info(&conn, url)?.map(|info| info.page.frecency))
+        };
+
+        let frecencies = get_frecencies(&conn, &urls)?;
+        assert_eq!(frecencies.len(), urls.len());
+        for (i, (url, frecency)) in urls.iter().zip(frecencies.iter()).enumerate() {
+            if i % 10 == 0 {
+                assert!(frecency.is_some(), "Should know {}", url);
+            } else {
+                assert!(frecency.is_none(), "Shouldn't know {}", url);
+            }
+            assert_eq!(*frecency, frecency_for(url)?);
+        }
+
+        let mixed = [
+            urls[10].clone(),
+            Url::parse("https://www.mozilla.org").unwrap(),
+            urls[20].clone(),
+            urls[10].clone(),
+        ];
+        assert_eq!(
+            get_frecencies(&conn, &mixed)?,
+            vec![
+                frecency_for(&urls[10])?,
+                None,
+                frecency_for(&urls[20])?,
+                frecency_for(&urls[10])?,
+            ]
+        );
+        assert_eq!(get_frecencies(&conn, &[])?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_origin_frecencies() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+
+        for url in &["https://www.example.com/1", "http://www.example.com/2"] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url).unwrap())
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        conn.execute_batch(
+            "UPDATE moz_origins SET frecency = 10 WHERE prefix = 'https://';
+             UPDATE moz_origins SET frecency = 20 WHERE prefix = 'http://';",
+        )?;
+
+        let urls = [
+            Url::parse("https://www.example.com/3").unwrap(),
+            Url::parse("https://www.mozilla.org").unwrap(),
+            Url::parse("data:text/plain,hello").unwrap(),
+            Url::parse("http://www.example.com/1").unwrap(),
+        ];
+        assert_eq!(
+            get_origin_frecencies(&conn, &urls)?,
+            vec![Some(20), None, None, Some(20)]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_delete_visited() {
         let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
@@ -1541,10 +2734,163 @@ mod tests {
             .is_none());
         // should be a tombstone for url4 and no others.
         assert_eq!(get_tombstone_count(&conn), 1);
-        // XXX - test frecency?
         // XXX - origins?
     }
 
+    #[test]
+    fn test_update_all_frecencies() -> Result<()> {
+        use crate::db::schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM;
+
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let urls = [
+            "https://www.example.com/1",
+            "https://www.example.com/2",
+            "https://www.example.org/",
+        ];
+        for (i, url) in urls.iter().enumerate() {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_at(Timestamp(Timestamp::now().as_millis() - i as u64))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        conn.execute_batch(
+            "UPDATE moz_places SET frecency = -1;
+             INSERT INTO moz_places_stale_frecencies(place_id, stale_at)
+             SELECT id, 1 FROM moz_places;",
+        )?;
+        delete_pending_temp_tables(&conn)?;
+
+        assert_eq!(update_all_frecencies(&conn, 2)?, 1);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places WHERE frecency > 0")?,
+            2
+        );
+        assert_eq!(update_all_frecencies(&conn, 2)?, 0);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places WHERE frecency <= 0")?,
+            0
+        );
+
+        // The origin stats should include the new frecencies.
+        let sum = conn.query_one::<i64>("SELECT SUM(frecency) FROM moz_places")?;
+        assert_eq!(
+            get_meta::<i64>(&conn, MOZ_META_KEY_ORIGIN_FRECENCY_SUM)?,
+            Some(sum)
+        );
+        assert_eq!(update_all_frecencies(&conn, 2)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_visits_between_recalculates() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now();
+        let early = Timestamp(now.as_millis() - 60 * 60 * 1000);
+        let late = Timestamp(now.as_millis() - 60 * 1000);
+        let url = Url::parse("https://www.example.com/1")?;
+        for &when in &[early, late] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(url.clone())
+                    .with_at(when)
+                    .with_visit_type(VisitTransition::Typed),
+            )?;
+        }
+        let before = fetch_page_info(&conn, &url)?
+            .expect("should get the page")
+            .page;
+        assert_eq!(before.visit_count_local, 2);
+        assert_eq!(before.last_visit_date_local, late);
+
+        delete_visits_between(&conn, late, now)?;
+
+        let after = fetch_page_info(&conn, &url)?
+            .expect("should keep the page")
+            .page;
+        assert_eq!(after.visit_count_local, 1);
+        assert_eq!(after.last_visit_date_local, early);
+        assert!(
+            after.frecency < before.frecency,
+            "Frecency should drop from {} to {}",
+            before.frecency,
+            after.frecency
+        );
+        // The frecency should already be current.
+        update_frecency(&conn, after.row_id, None)?;
+        let recalculated = fetch_page_info(&conn, &url)?
+            .expect("should keep the page")
+            .page;
+        assert_eq!(recalculated.frecency, after.frecency);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_bookmarked_place_by_guid() -> Result<()> {
+        use crate::storage::bookmarks::{
+            self, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        };
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://www.example.com/bookmarked")?;
+        let now = Timestamp::now();
+        for offset in 1..=3 {
+            apply_observation(
+                &conn,
+                VisitObservation::new(url.clone())
+                    .with_at(Timestamp(now.as_millis() - offset * 1000))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        bookmarks::insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: Some("bookmarked".into()),
+                page_title: None,
+            }
+            .into(),
+        )?;
+        conn.execute_cached(
+            &format!(
+                "UPDATE moz_places SET sync_status = {}",
+                (SyncStatus::Normal as u8)
+            ),
+            NO_PARAMS,
+        )?;
+        let before = fetch_page_info(&conn, &url)?
+            .expect("should get the page")
+            .page;
+
+        delete_place_by_guid(&conn, &before.guid)?;
+
+        // The page is bookmarked, so we should keep it, but remove all its
+        // visits.
+        let after = fetch_page_info(&conn, &url)?
+            .expect("should keep the bookmarked page")
+            .page;
+        assert_eq!(after.visit_count_local, 0);
+        assert_eq!(after.last_visit_date_local, Timestamp(0));
+        assert!(after.frecency < before.frecency);
+        assert_eq!(get_tombstone_count(&conn), 0);
+        let visit_tombstones =
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisit_tombstones")?;
+        assert_eq!(visit_tombstones, 3);
+        assert_eq!(
+            crate::storage::deletion_log::get_deletion_log(&conn, Timestamp(0))?
+                .iter()
+                .map(|e| (e.op, e.count))
+                .collect::<Vec<_>>(),
+            vec![(DeletionOp::DeletePage, 3)]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_change_counter() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1636,6 +2982,66 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fetch_outgoing_visits() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url1 = Url::parse("http://example.com/1")?;
+        let url2 = Url::parse("http://example.com/2")?;
+        for (url, count) in &[(&url1, 1), (&url2, 5)] {
+            for i in 0..*count {
+                apply_observation(
+                    &conn,
+                    VisitObservation::new((*url).clone())
+                        .with_visit_type(VisitTransition::Link)
+                        .with_at(Timestamp(1_500_000_000_000 + i * 1000)),
+                )?;
+            }
+        }
+
+        let outgoing_visits = |conn: &PlacesDb| -> Result<Vec<(String, Vec<HistoryRecordVisit>)>> {
+            let mut visits = fetch_outgoing(conn, 100, 3)?
+                .into_iter()
+                .filter_map(|(_, info)| match info {
+                    OutgoingInfo::Record(record) => Some((record.hist_uri, record.visits)),
+                    OutgoingInfo::Tombstone => None,
+                })
+                .collect::<Vec<_>>();
+            visits.sort_by(|(a, _), (b, _)| a.cmp(b));
+            // `fetch_outgoing` expects `finish_outgoing` to clear this.
+            conn.execute("DELETE FROM temp_sync_updated_meta", NO_PARAMS)?;
+            Ok(visits)
+        };
+        let visit = |at: u64| HistoryRecordVisit {
+            date: Timestamp(at).into(),
+            transition: VisitTransition::Link as u8,
+        };
+        let expected = vec![
+            (url1.to_string(), vec![visit(1_500_000_000_000)]),
+            (
+                url2.to_string(),
+                vec![
+                    visit(1_500_000_004_000),
+                    visit(1_500_000_003_000),
+                    visit(1_500_000_002_000),
+                ],
+            ),
+        ];
+
+        assert!(conn.sqlite_features().window_functions);
+        assert_eq!(outgoing_visits(&conn)?, expected);
+
+        // Older SQLite versions should fetch the same visits, one page at a
+        // time.
+        conn.force_sqlite_features(SqliteFeatures {
+            window_functions: false,
+            ..conn.sqlite_features()
+        });
+        assert_eq!(outgoing_visits(&conn)?, expected);
+
+        Ok(())
+    }
+
     #[test]
     fn test_tombstones() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1845,15 +3251,15 @@ mod tests {
         // Make sure syncing doesn't resurrect them.
         apply_synced_visits(
             &conn,
-            &info0.guid,
             &info0.url,
+            &info0.guid,
             &Some(info0.title.clone()),
             // Ignore dates[0] since we know it's present.
             &dates
                 .iter()
-                .map(|&d| HistoryRecordVisit {
-                    date: d.into(),
-                    transition: VisitTransition::Link as u8,
+                .map(|&d| SyncedVisit {
+                    date: d,
+                    transition: VisitTransition::Link,
                 })
                 .collect::<Vec<_>>(),
         )
@@ -1934,6 +3340,7 @@ mod tests {
                     guid: Some(guid.clone()),
                     url: url.clone(),
                     title: None,
+                    page_title: None,
                 }),
             )
             .unwrap();
@@ -1968,7 +3375,7 @@ mod tests {
         )
         .unwrap();
 
-        assert!(bookmarks::delete_bookmark(&conn, &b0.0).unwrap());
+        bookmarks::delete_bookmark(&conn, &b0.0).unwrap();
 
         wipe_local(&conn).unwrap();
 
@@ -2053,19 +3460,19 @@ mod tests {
 
         apply_synced_visits(
             &conn,
-            &SyncGuid::new(),
             &url::Url::parse("http://www.example.com/123").unwrap(),
+            &SyncGuid::new(),
             &None,
             &[
-                HistoryRecordVisit {
+                SyncedVisit {
                     // This should make it in
-                    date: Timestamp::now().into(),
-                    transition: VisitTransition::Link as u8,
+                    date: Timestamp::now(),
+                    transition: VisitTransition::Link,
                 },
-                HistoryRecordVisit {
+                SyncedVisit {
                     // This should not.
-                    date: start.into(),
-                    transition: VisitTransition::Link as u8,
+                    date: start,
+                    transition: VisitTransition::Link,
                 },
             ],
         )
@@ -2085,12 +3492,12 @@ mod tests {
         // Check that we don't insert a place if all visits are too old.
         apply_synced_visits(
             &conn,
-            &SyncGuid::new(),
             &url::Url::parse("http://www.example.com/1234").unwrap(),
+            &SyncGuid::new(),
             &None,
-            &[HistoryRecordVisit {
-                date: start.into(),
-                transition: VisitTransition::Link as u8,
+            &[SyncedVisit {
+                date: start,
+                transition: VisitTransition::Link,
             }],
         )
         .unwrap();
@@ -2107,6 +3514,237 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_many_visit_observations() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now().as_millis();
+        let mut many = ManyVisitObservations::with_limit(&conn, 100);
+        for i in 0..5000u64 {
+            let url = Url::parse(&format!("https://www.example.com/{}", i % 1000))?;
+            many.add(
+                VisitObservation::new(url)
+                    .with_at(Timestamp(now - i))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        assert_eq!(many.flush()?, 5000);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?,
+            5000
+        );
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places")?,
+            1000
+        );
+        assert_eq!(
+            conn.query_one::<i64>("SELECT SUM(visit_count_local) FROM moz_places")?,
+            5000
+        );
+
+        // A partial batch is applied when flushing.
+        let mut many = ManyVisitObservations::with_limit(&conn, 100);
+        many.add(
+            VisitObservation::new(Url::parse("https://www.example.com/new")?)
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        assert_eq!(many.flush()?, 1);
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?,
+            5001
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_chunked_transaction() -> Result<()> {
+        let api = crate::testing::new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let reader = api.open_connection(ConnectionType::ReadOnly)?;
+        let visit_count = || -> Result<i64> {
+            Ok(reader.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?)
+        };
+        reader
+            .begin_time_chunked_transaction(Duration::from_millis(0))
+            .err()
+            .expect("should fail to begin a chunked transaction on a reader");
+
+        // Commit after every observation, so that the reader sees our
+        // progress before we're done.
+        let mut tx = writer.begin_time_chunked_transaction(Duration::from_millis(0))?;
+        for i in 0..3 {
+            let url = Url::parse(&format!("https://www.example.com/{}", i))?;
+            apply_observation_direct(
+                &writer,
+                VisitObservation::new(url).with_visit_type(VisitTransition::Link),
+            )?;
+            assert!(tx.maybe_commit()?);
+            assert_eq!(visit_count()?, i + 1);
+        }
+        tx.commit()?;
+
+        // `maybe_commit_with` writes in the chunk that it commits.
+        let mut tx = writer.begin_time_chunked_transaction(Duration::from_millis(0))?;
+        assert!(tx.maybe_commit_with(|| {
+            apply_observation_direct(
+                &writer,
+                VisitObservation::new(Url::parse("https://www.example.com/3")?)
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+            Ok(())
+        })?);
+        assert_eq!(visit_count()?, 4);
+        tx.commit()?;
+
+        // Nested transactions never commit early, or call the hook.
+        let outer = writer.begin_transaction()?;
+        let mut tx = writer.begin_time_chunked_transaction(Duration::from_millis(0))?;
+        assert!(!tx.maybe_commit()?);
+        assert!(!tx.maybe_commit_with(|| panic!("Shouldn't commit a nested transaction"))?);
+        tx.commit()?;
+        outer.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_observations_in_chunks() -> Result<()> {
+        let api = crate::testing::new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let reader = api.open_connection(ConnectionType::ReadOnly)?;
+        let visit_count = || -> Result<i64> {
+            Ok(reader.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?)
+        };
+        let observations = (0..5)
+            .map(|i| {
+                VisitObservation::new(Url::parse(&format!("https://example.org/{}", i)).unwrap())
+                    .with_visit_type(VisitTransition::Link)
+            })
+            .collect::<Vec<_>>();
+
+        // Fail on the fourth page, after we've committed the first three.
+        writer.execute_batch(
+            "CREATE TEMP TRIGGER fail_on_insert
+             BEFORE INSERT ON moz_places
+             WHEN NEW.url = 'https://example.org/3'
+             BEGIN
+               SELECT RAISE(ABORT, 'Failing on purpose');
+             END",
+        )?;
+        // Committing after every observation means the reader should see
+        // the first three visits, even though the batch failed.
+        let mut progress = ObservationProgress::default();
+        apply_observations_resumable(
+            &writer,
+            &observations,
+            &mut progress,
+            Duration::from_millis(0),
+        )
+        .expect_err("Should fail on the fourth observation");
+        assert_eq!(progress.committed, 3);
+        assert_eq!(progress.visits_added, 3);
+        assert_eq!(visit_count()?, 3);
+        // The committed chunks' origin updates shouldn't be left pending in
+        // the writer's temp tables.
+        for table in &[
+            "moz_updateoriginsinsert_temp",
+            "moz_updateoriginsupdate_temp",
+            "moz_updateoriginsdelete_temp",
+        ] {
+            assert_eq!(
+                writer.query_one::<i64>(&format!("SELECT COUNT(*) FROM {}", table))?,
+                0,
+                "{} should be empty",
+                table
+            );
+        }
+        assert_eq!(
+            reader.query_one::<i64>(
+                "SELECT COUNT(*) FROM moz_places h
+                 JOIN moz_origins o ON o.id = h.origin_id
+                 WHERE o.host = 'example.org'"
+            )?,
+            3
+        );
+
+        // Retrying should pick up where we left off, without adding the
+        // first three visits again.
+        writer.execute_batch("DROP TRIGGER fail_on_insert")?;
+        apply_observations_resumable(
+            &writer,
+            &observations,
+            &mut progress,
+            Duration::from_millis(0),
+        )?;
+        assert_eq!(progress.committed, 5);
+        assert_eq!(progress.visits_added, 5);
+        assert_eq!(visit_count()?, 5);
+
+        // A big batch adds all its visits.
+        let observations = (0..500)
+            .map(|i| {
+                VisitObservation::new(Url::parse(&format!("https://example.com/{}", i)).unwrap())
+                    .with_visit_type(VisitTransition::Link)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(apply_observations(&writer, &observations)?, 500);
+        assert_eq!(visit_count()?, 505);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_synced_visits_idempotent() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let url = Url::parse("https://www.example.com/")?;
+        let guid = SyncGuid::from("pageAAAAAAAA");
+        let now = Timestamp::now().as_millis();
+        let visit = |ago: u64, transition: VisitTransition| SyncedVisit {
+            date: Timestamp(now - ago),
+            transition,
+        };
+        let first = [
+            visit(3000, VisitTransition::Link),
+            visit(2000, VisitTransition::Link),
+            // Duplicated in the same record.
+            visit(2000, VisitTransition::Link),
+        ];
+        // Overlaps with the first set. A visit at the same time with a
+        // different transition isn't a duplicate.
+        let second = [
+            visit(2000, VisitTransition::Link),
+            visit(2000, VisitTransition::Typed),
+            visit(1000, VisitTransition::Link),
+        ];
+
+        assert_eq!(apply_synced_visits(&conn, &url, &guid, &None, &first)?, 2);
+        assert_eq!(apply_synced_visits(&conn, &url, &guid, &None, &second)?, 2);
+        let counts = |conn: &PlacesDb| -> Result<(i64, i64, Timestamp, i64)> {
+            conn.query_row_and_then_named(
+                "SELECT h.visit_count_remote, h.visit_count_local,
+                        h.last_visit_date_remote,
+                        (SELECT COUNT(*) FROM moz_historyvisits v
+                         WHERE v.place_id = h.id)
+                 FROM moz_places h WHERE guid = :guid",
+                &[(":guid", &guid)],
+                |row| -> Result<_> { Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)) },
+                false,
+            )
+        };
+        assert_eq!(counts(&conn)?, (4, 0, Timestamp(now - 1000), 4));
+        let frecency = conn.query_one::<i64>("SELECT frecency FROM moz_places")?;
+        assert!(frecency > 0);
+
+        // Applying both sets again shouldn't change anything.
+        assert_eq!(apply_synced_visits(&conn, &url, &guid, &None, &first)?, 0);
+        assert_eq!(apply_synced_visits(&conn, &url, &guid, &None, &second)?, 0);
+        assert_eq!(counts(&conn)?, (4, 0, Timestamp(now - 1000), 4));
+        assert_eq!(
+            conn.query_one::<i64>("SELECT frecency FROM moz_places")?,
+            frecency
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_long_strings() {
         let _ = env_logger::try_init();
@@ -2150,4 +3788,221 @@ mod tests {
         assert_eq!(db_title.len(), crate::storage::TITLE_LENGTH_MAX);
         assert!(title.starts_with(&db_title));
     }
+
+    #[test]
+    fn test_history_retention() -> Result<()> {
+        let _ = env_logger::try_init();
+        let now = Timestamp(1_550_000_000_000);
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        conn.set_clock(FrozenClock::at(now))?;
+        let day = |days_ago: u64| Timestamp(now.as_millis() - days_ago * MILLIS_PER_DAY);
+
+        // 5 pages with 5 old visits each, and one page with recent visits.
+        for i in 0..5 {
+            let url = Url::parse(&format!("https://www.example.com/old/{}", i)).unwrap();
+            for j in 0..5 {
+                apply_observation(
+                    &conn,
+                    VisitObservation::new(url.clone())
+                        .with_at(day(i * 5 + j + 2))
+                        .with_visit_type(VisitTransition::Link),
+                )?;
+            }
+        }
+        let recent = Url::parse("https://www.example.com/recent").unwrap();
+        for hours_ago in &[1, 2] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(recent.clone())
+                    .with_at(Timestamp(now.as_millis() - hours_ago * 60 * 60 * 1000))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        // Pretend we've synced everything, so that removed pages get
+        // tombstones.
+        conn.execute(
+            &format!(
+                "UPDATE moz_places SET sync_status = {}",
+                SyncStatus::Normal as u8
+            ),
+            NO_PARAMS,
+        )?;
+
+        // No policy, so nothing to do.
+        assert_eq!(get_history_retention(&conn)?, None);
+        assert_eq!(enforce_history_retention(&conn, 10)?, 0);
+
+        let policy = HistoryRetention {
+            max_age_days: 1,
+            max_pages: None,
+            sync_deletions: true,
+        };
+        set_history_retention(&conn, Some(policy))?;
+        assert_eq!(get_history_retention(&conn)?, Some(policy));
+
+        // Enforcing the policy should converge over repeated calls.
+        assert_eq!(enforce_history_retention(&conn, 10)?, 10);
+        assert_eq!(enforce_history_retention(&conn, 10)?, 10);
+        assert_eq!(enforce_history_retention(&conn, 10)?, 5);
+        assert_eq!(enforce_history_retention(&conn, 10)?, 0);
+
+        let count = |sql: &str| -> Result<i64> { Ok(conn.query_one::<i64>(sql)?) };
+        assert_eq!(count("SELECT COUNT(*) FROM moz_historyvisits")?, 2);
+        assert_eq!(count("SELECT COUNT(*) FROM moz_places")?, 1);
+        assert!(fetch_page_info(&conn, &recent)?.is_some());
+        assert_eq!(
+            count("SELECT COUNT(*) FROM moz_historyvisit_tombstones")?,
+            25
+        );
+        assert_eq!(count("SELECT COUNT(*) FROM moz_places_tombstones")?, 5);
+        let expired = crate::storage::deletion_log::get_deletion_log(&conn, Timestamp(0))?;
+        assert!(expired
+            .iter()
+            .all(|entry| entry.op == DeletionOp::ExpireVisits
+                && entry.initiator == DeletionInitiator::Maintenance));
+        assert_eq!(expired.iter().map(|entry| entry.count).sum::<u32>(), 25);
+
+        // `run_maintenance` should enforce the policy, too.
+        apply_observation(
+            &conn,
+            VisitObservation::new(recent.clone())
+                .with_at(day(3))
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        crate::storage::run_maintenance(&conn)?;
+        crate::storage::run_maintenance(&conn)?;
+        assert_eq!(count("SELECT COUNT(*) FROM moz_historyvisits")?, 2);
+
+        set_history_retention(&conn, None)?;
+        assert_eq!(get_history_retention(&conn)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_history_retention_max_pages() -> Result<()> {
+        let _ = env_logger::try_init();
+        let now = Timestamp(1_550_000_000_000);
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        conn.set_clock(FrozenClock::at(now))?;
+        let minutes = |minutes_ago: u64| Timestamp(now.as_millis() - minutes_ago * 60 * 1000);
+
+        for (url, minutes_ago) in &[
+            ("https://www.example.com/a", 10),
+            ("https://www.example.com/a", 20),
+            ("https://www.example.com/b", 120),
+            ("https://www.example.com/b", 150),
+            ("https://www.example.com/c", 180),
+            ("https://www.example.com/c", 210),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url).unwrap())
+                    .with_at(minutes(*minutes_ago))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        conn.execute(
+            &format!(
+                "UPDATE moz_places SET sync_status = {}",
+                SyncStatus::Normal as u8
+            ),
+            NO_PARAMS,
+        )?;
+
+        set_history_retention(
+            &conn,
+            Some(HistoryRetention {
+                max_age_days: 30,
+                max_pages: Some(1),
+                sync_deletions: false,
+            }),
+        )?;
+
+        // The first call deletes both visits to "c", and the older visit to
+        // "b". The second deletes the remaining visit to "b".
+        assert_eq!(enforce_history_retention(&conn, 3)?, 3);
+        assert_eq!(enforce_history_retention(&conn, 3)?, 1);
+        assert_eq!(enforce_history_retention(&conn, 3)?, 0);
+
+        let urls = conn.query_rows_and_then_named(
+            "SELECT url FROM moz_places ORDER BY url",
+            &[],
+            |row| -> RusqliteResult<String> { row.get(0) },
+        )?;
+        assert_eq!(urls, vec!["https://www.example.com/a".to_string()]);
+
+        // Expiration is local-only, so we shouldn't write any tombstones.
+        let count = |sql: &str| -> Result<i64> { Ok(conn.query_one::<i64>(sql)?) };
+        assert_eq!(
+            count("SELECT COUNT(*) FROM moz_historyvisit_tombstones")?,
+            0
+        );
+        assert_eq!(count("SELECT COUNT(*) FROM moz_places_tombstones")?, 0);
+
+        // Incoming visits to an evicted page shouldn't bring it back, but
+        // newer visits are still applied.
+        let url = Url::parse("https://www.example.com/c")?;
+        let guid = SyncGuid::from("pageCCCCCCCC");
+        let visit = |minutes_ago: u64| SyncedVisit {
+            date: minutes(minutes_ago),
+            transition: VisitTransition::Link,
+        };
+        assert_eq!(
+            apply_synced_visits(&conn, &url, &guid, &None, &[visit(180), visit(210)])?,
+            0
+        );
+        assert_eq!(
+            apply_synced_visits(&conn, &url, &guid, &None, &[visit(5)])?,
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_deletion_log() -> Result<()> {
+        use crate::storage::deletion_log::{get_deletion_log, DeletionLogEntry};
+
+        let now = Timestamp(1_550_000_000_000);
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        conn.set_clock(FrozenClock::at(now))?;
+        for (url, minutes_ago) in &[
+            ("https://www.example.com/a", 10),
+            ("https://www.example.com/a", 20),
+            ("https://www.example.com/b", 30),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url).unwrap())
+                    .with_at(Timestamp(now.as_millis() - minutes_ago * 60 * 1000))
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+
+        // Deleting a range that doesn't have any visits shouldn't log
+        // anything.
+        delete_visits_between(&conn, Timestamp(0), Timestamp(1))?;
+        assert!(get_deletion_log(&conn, Timestamp(0))?.is_empty());
+
+        delete_visits_between(&conn, Timestamp(now.as_millis() - 15 * 60 * 1000), now)?;
+        delete_everything(&conn)?;
+
+        assert_eq!(
+            get_deletion_log(&conn, Timestamp(0))?,
+            vec![
+                DeletionLogEntry {
+                    op: DeletionOp::DeleteVisits,
+                    at: now,
+                    count: 1,
+                    initiator: DeletionInitiator::Api,
+                },
+                DeletionLogEntry {
+                    op: DeletionOp::DeleteEverything,
+                    at: now,
+                    count: 2,
+                    initiator: DeletionInitiator::Api,
+                },
+            ]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,233 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keeps the database under a size budget, by pruning visits to the least
+//! recently visited pages. Unlike the retention policy, this is a last
+//! resort for devices that are running out of space: pruning is local-only,
+//! so other devices keep their copies of the pruned visits.
+//!
+//! Pages that lose all their visits are removed, unless they're bookmarked.
+//! We prune visits to bookmarked pages last, and never remove their rows.
+
+use super::deletion_log::{record_deletion, DeletionInitiator, DeletionOp};
+use super::history::{delete_visits_in_tx, VisitToDelete};
+use crate::db::PlacesDb;
+use crate::error::*;
+use sql_support::ConnExt;
+
+/// The number of visits that `expire_history` prunes before checking the
+/// database size again.
+pub const PRUNE_VISITS_BATCH_SIZE: usize = 1000;
+
+/// If more than this fraction of the database's pages are free after
+/// pruning, `expire_history` vacuums the database to return the space to
+/// the file system.
+const VACUUM_FREE_PAGES_FRACTION: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy)]
+struct DbSize {
+    page_count: i64,
+    freelist_count: i64,
+    page_size: i64,
+}
+
+impl DbSize {
+    fn get(db: &PlacesDb) -> Result<Self> {
+        Ok(Self {
+            page_count: db.query_one("PRAGMA page_count")?,
+            freelist_count: db.query_one("PRAGMA freelist_count")?,
+            page_size: db.query_one("PRAGMA page_size")?,
+        })
+    }
+
+    /// The number of bytes in pages that hold data. Free pages don't count,
+    /// since SQLite reuses them before growing the file.
+    fn used_bytes(self) -> u64 {
+        ((self.page_count - self.freelist_count) * self.page_size) as u64
+    }
+
+    fn free_fraction(self) -> f64 {
+        if self.page_count == 0 {
+            return 0.0;
+        }
+        self.freelist_count as f64 / self.page_count as f64
+    }
+}
+
+/// Deletes at most `visit_limit` visits, starting with all visits to the
+/// least recently visited pages that aren't bookmarked. Visit counts and
+/// frecencies are updated for the affected pages. No tombstones are written,
+/// so the pruned visits aren't deleted on other devices. Returns the number
+/// of visits deleted.
+pub fn prune_destructively(db: &PlacesDb, visit_limit: usize) -> Result<usize> {
+    let tx = db.begin_transaction()?;
+    let visits = db.query_rows_and_then_named(
+        "SELECT v.id, v.place_id, v.visit_date
+         FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         ORDER BY h.foreign_count > 0,
+                  MAX(h.last_visit_date_local, h.last_visit_date_remote),
+                  v.visit_date
+         LIMIT :limit",
+        &[(":limit", &(visit_limit as i64))],
+        VisitToDelete::from_row,
+    )?;
+    delete_visits_in_tx(db, &visits, false)?;
+    record_deletion(
+        db,
+        DeletionOp::PruneVisits,
+        DeletionInitiator::Maintenance,
+        visits.len(),
+    )?;
+    tx.commit()?;
+    Ok(visits.len())
+}
+
+/// Prunes visits until the data in the database takes up at most
+/// `db_size_limit_bytes`, or there are no more visits to prune, then
+/// vacuums the database if that freed enough space. This can take a while
+/// for large databases, so it should be called from a background task.
+/// Returns the number of visits pruned.
+pub fn expire_history(db: &PlacesDb, db_size_limit_bytes: u64) -> Result<usize> {
+    let mut pruned = 0;
+    while DbSize::get(db)?.used_bytes() > db_size_limit_bytes {
+        let count = prune_destructively(db, PRUNE_VISITS_BATCH_SIZE)?;
+        if count == 0 {
+            // Everything that's left is bookmarks and their pages.
+            break;
+        }
+        pruned += count;
+    }
+    if pruned > 0 {
+        let size = DbSize::get(db)?;
+        log::info!(
+            "Pruned {} visits to fit the database in {} bytes; now using {}",
+            pruned,
+            db_size_limit_bytes,
+            size.used_bytes()
+        );
+        if size.free_fraction() > VACUUM_FREE_PAGES_FRACTION {
+            db.execute_batch("VACUUM")?;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::ConnectionType;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::storage::deletion_log::get_deletion_log;
+    use crate::storage::fetch_page_info;
+    use crate::storage::history::apply_observations;
+    use crate::testing::insert_json_tree;
+    use crate::types::{Timestamp, VisitTransition};
+    use serde_json::json;
+    use url::Url;
+
+    const MILLIS_PER_MINUTE: u64 = 60 * 1000;
+
+    fn page_url(i: usize) -> Url {
+        Url::parse(&format!("https://example.com/{}", i)).expect("it's a valid url")
+    }
+
+    #[test]
+    fn test_expire_history() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+
+        // Page 0 is the least recently visited, and page 499 the most.
+        let now = Timestamp::now().as_millis();
+        let mut observations = Vec::new();
+        for i in 0..500 {
+            let at = now - (500 - i as u64) * MILLIS_PER_MINUTE;
+            for j in 0..4 {
+                observations.push(
+                    VisitObservation::new(page_url(i))
+                        .with_title(format!("Page {} {}", i, "x".repeat(1000)))
+                        .with_visit_type(VisitTransition::Link)
+                        .with_at(Timestamp(at - j)),
+                );
+            }
+        }
+        apply_observations(&conn, &observations)?;
+        // Bookmark the two oldest pages.
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    { "title": "bookmark 0", "url": page_url(0).as_str() },
+                    { "title": "bookmark 1", "url": page_url(1).as_str() },
+                ],
+            }),
+        )?;
+
+        // There's nothing to do if we're under the budget.
+        let used_bytes = DbSize::get(&conn)?.used_bytes();
+        assert_eq!(expire_history(&conn, used_bytes)?, 0);
+
+        let limit = used_bytes / 2;
+        let pruned = expire_history(&conn, limit)?;
+        assert!(pruned > 0);
+        let size = DbSize::get(&conn)?;
+        assert!(
+            size.used_bytes() <= limit,
+            "Should use at most {} bytes; using {}",
+            limit,
+            size.used_bytes()
+        );
+        assert_eq!(size.freelist_count, 0, "Should vacuum after pruning");
+
+        // Bookmarked pages keep their rows, even though they're the oldest.
+        for i in 0..2 {
+            let page = fetch_page_info(&conn, &page_url(i))?
+                .expect("should keep bookmarked page")
+                .page;
+            assert_eq!(page.visit_count_local, 4);
+        }
+        // We pruned all visits to the oldest unbookmarked pages, and removed
+        // them, but kept the most recent ones.
+        assert!(fetch_page_info(&conn, &page_url(2))?.is_none());
+        assert!(fetch_page_info(&conn, &page_url(499))?.is_some());
+        let orphans: i64 = conn.query_one(
+            "SELECT COUNT(*) FROM moz_places
+             WHERE foreign_count = 0 AND
+                   last_visit_date_local + last_visit_date_remote = 0",
+        )?;
+        assert_eq!(orphans, 0);
+        let wrong_counts: i64 = conn.query_one(
+            "SELECT COUNT(*) FROM moz_places h
+             WHERE visit_count_local <> (SELECT COUNT(*) FROM moz_historyvisits v
+                                         WHERE v.place_id = h.id)",
+        )?;
+        assert_eq!(wrong_counts, 0);
+        // Pruning is local-only.
+        let tombstones: i64 = conn.query_one(
+            "SELECT (SELECT COUNT(*) FROM moz_places_tombstones) +
+                    (SELECT COUNT(*) FROM moz_historyvisit_tombstones)",
+        )?;
+        assert_eq!(tombstones, 0);
+
+        let log = get_deletion_log(&conn, Timestamp(0))?;
+        let logged: usize = log
+            .iter()
+            .filter(|entry| entry.op == DeletionOp::PruneVisits)
+            .map(|entry| entry.count as usize)
+            .sum();
+        assert_eq!(logged, pruned);
+
+        // Once only bookmarked pages are left, we prune their visits, but
+        // keep the pages.
+        expire_history(&conn, 0)?;
+        let visits: i64 = conn.query_one("SELECT COUNT(*) FROM moz_historyvisits")?;
+        assert_eq!(visits, 0);
+        let pages: i64 = conn.query_one("SELECT COUNT(*) FROM moz_places")?;
+        assert_eq!(pages, 2);
+
+        Ok(())
+    }
+}
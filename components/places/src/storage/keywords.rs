@@ -0,0 +1,235 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keywords are shortcuts for URLs, like "w" for Wikipedia. Like desktop,
+//! keywords are case-insensitive and can't contain whitespace. Each keyword
+//! maps to one URL, and each URL has at most one keyword, since that's all
+//! a synced bookmark record can hold.
+//!
+//! Keywords are synced as part of bookmark records, so changing a keyword
+//! flags all bookmarks for the affected URLs for upload.
+
+use super::{fetch_page_info, RowId};
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use sql_support::ConnExt;
+use url::Url;
+
+/// Trims and lowercases `keyword`, or returns an error if it's empty or
+/// contains whitespace.
+pub fn validate_keyword(keyword: &str) -> Result<String> {
+    let k = keyword.trim();
+    if k.is_empty() || k.find(char::is_whitespace).is_some() {
+        return Err(InvalidPlaceInfo::InvalidKeyword.into());
+    }
+    Ok(k.to_lowercase())
+}
+
+/// Normalizes a keyword from a synced bookmark like `validate_keyword`.
+/// Returns the normalized keyword, or `None` if it's empty or invalid, and
+/// whether the bookmark's keyword is unchanged. Empty keywords mean the
+/// bookmark doesn't have one, so they count as unchanged.
+pub(crate) fn normalize_synced_keyword(keyword: Option<&str>) -> (Option<String>, bool) {
+    match keyword {
+        Some(k) if k.trim().is_empty() => (None, true),
+        Some(k) => match validate_keyword(k) {
+            Ok(normalized) => {
+                let is_original = normalized == k;
+                (Some(normalized), is_original)
+            }
+            Err(_) => {
+                log::trace!("Ignoring invalid keyword on synced bookmark: {:?}", k);
+                (None, false)
+            }
+        },
+        None => (None, true),
+    }
+}
+
+/// Bumps the change counters of all bookmarks for the URLs that use
+/// `keyword`, or `place_id`, so that we'll reupload them with their new
+/// keywords.
+fn bump_bookmarks_for_keyword_change(
+    db: &PlacesDb,
+    keyword: &str,
+    place_id: Option<RowId>,
+) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET
+           syncChangeCounter = syncChangeCounter + 1,
+           lastModified = now()
+         WHERE fk = :place_id OR
+               fk IN (SELECT place_id FROM moz_keywords
+                      WHERE keyword = :keyword)",
+        &[(":keyword", &keyword), (":place_id", &place_id)],
+    )?;
+    Ok(())
+}
+
+/// Sets the keyword for a URL. If the URL already has a keyword, it's
+/// replaced; if the keyword is already used for a different URL, it's
+/// moved to this one.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `keyword` - The keyword to set. Keywords are trimmed and lowercased.
+///
+/// * `url` - The URL for the keyword. Like tags, this function won't create
+///   a new place, so the URL must already exist.
+///
+/// # Returns
+///
+/// There is no success return value.
+pub fn set_keyword(db: &PlacesDb, keyword: &str, url: &Url) -> Result<()> {
+    let keyword = validate_keyword(keyword)?;
+    let tx = db.begin_transaction()?;
+    let place_id = match fetch_page_info(db, url)? {
+        Some(info) => info.page.row_id,
+        None => return Err(InvalidPlaceInfo::NoSuchUrl.into()),
+    };
+    bump_bookmarks_for_keyword_change(db, &keyword, Some(place_id))?;
+    db.execute_named_cached(
+        "DELETE FROM moz_keywords
+         WHERE keyword = :keyword OR
+               place_id = :place_id",
+        &[(":keyword", &keyword), (":place_id", &place_id)],
+    )?;
+    db.execute_named_cached(
+        "INSERT INTO moz_keywords(keyword, place_id, lastModified)
+         VALUES(:keyword, :place_id, now())",
+        &[(":keyword", &keyword), (":place_id", &place_id)],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Removes a keyword. There is no success return value - the operation is
+/// ignored if the keyword doesn't exist.
+pub fn remove_keyword(db: &PlacesDb, keyword: &str) -> Result<()> {
+    let keyword = validate_keyword(keyword)?;
+    let tx = db.begin_transaction()?;
+    bump_bookmarks_for_keyword_change(db, &keyword, None)?;
+    db.execute_named_cached(
+        "DELETE FROM moz_keywords
+         WHERE keyword = :keyword",
+        &[(":keyword", &keyword)],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Returns the URL for a keyword, or `None` if the keyword isn't set.
+pub fn get_url_for_keyword(db: &PlacesDb, keyword: &str) -> Result<Option<Url>> {
+    let keyword = validate_keyword(keyword)?;
+    let url = db.try_query_row(
+        "SELECT h.url FROM moz_keywords k
+         JOIN moz_places h ON h.id = k.place_id
+         WHERE k.keyword = :keyword",
+        &[(":keyword", &keyword)],
+        |row| row.get::<_, String>(0),
+        true,
+    )?;
+    Ok(match url {
+        Some(url) => Some(Url::parse(&url)?),
+        None => None,
+    })
+}
+
+/// Returns the keyword for a URL, or `None` if the URL doesn't have one.
+pub fn get_keyword_for_url(db: &PlacesDb, url: &Url) -> Result<Option<String>> {
+    Ok(db.try_query_row(
+        "SELECT k.keyword FROM moz_keywords k
+         JOIN moz_places h ON h.id = k.place_id
+         WHERE h.url_hash = hash(:url) AND
+               h.url = :url",
+        &[(":url", &url.as_str())],
+        |row| row.get::<_, String>(0),
+        true,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bookmarks::{delete_bookmark, BookmarkRootGuid};
+    use crate::testing::{insert_json_tree, new_mem_connection};
+    use serde_json::json;
+
+    fn get_change_counter(db: &PlacesDb, url: &Url) -> i64 {
+        db.query_row_and_then_named(
+            "SELECT syncChangeCounter FROM moz_bookmarks b
+             JOIN moz_places h ON h.id = b.fk
+             WHERE h.url = :url",
+            &[(":url", &url.as_str())],
+            |row| row.get::<_, i64>(0),
+            false,
+        )
+        .expect("should have a bookmark")
+    }
+
+    #[test]
+    fn test_validate_keyword() {
+        assert_eq!(validate_keyword(" Foo ").expect("should work"), "foo");
+        assert!(validate_keyword("").is_err());
+        assert!(validate_keyword("foo bar").is_err());
+    }
+
+    #[test]
+    fn test_keywords() -> Result<()> {
+        let conn = new_mem_connection();
+        let url1 = Url::parse("http://example.com/1")?;
+        let url2 = Url::parse("http://example.com/2")?;
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [
+                    { "title": "1", "url": url1.as_str() },
+                    { "guid": "bookmark2222", "title": "2", "url": url2.as_str() },
+                ],
+            }),
+        )?;
+        conn.execute_batch("UPDATE moz_bookmarks SET syncChangeCounter = 0")?;
+
+        // Pages must exist before we can set keywords for them.
+        let unknown = Url::parse("http://example.com/unknown")?;
+        assert!(set_keyword(&conn, "u", &unknown).is_err());
+
+        set_keyword(&conn, " One", &url1)?;
+        assert_eq!(get_url_for_keyword(&conn, "one")?, Some(url1.clone()));
+        assert_eq!(get_url_for_keyword(&conn, "ONE")?, Some(url1.clone()));
+        assert_eq!(get_keyword_for_url(&conn, &url1)?, Some("one".to_string()));
+        assert_eq!(get_change_counter(&conn, &url1), 1);
+        assert_eq!(get_change_counter(&conn, &url2), 0);
+
+        // Setting a new keyword for a URL replaces the old one.
+        set_keyword(&conn, "uno", &url1)?;
+        assert_eq!(get_url_for_keyword(&conn, "one")?, None);
+        assert_eq!(get_keyword_for_url(&conn, &url1)?, Some("uno".to_string()));
+        assert_eq!(get_change_counter(&conn, &url1), 2);
+
+        // Reusing a keyword moves it to the new URL, and flags bookmarks for
+        // both URLs for upload.
+        set_keyword(&conn, "uno", &url2)?;
+        assert_eq!(get_url_for_keyword(&conn, "uno")?, Some(url2.clone()));
+        assert_eq!(get_keyword_for_url(&conn, &url1)?, None);
+        assert_eq!(get_change_counter(&conn, &url1), 3);
+        assert_eq!(get_change_counter(&conn, &url2), 1);
+
+        remove_keyword(&conn, "uno")?;
+        assert_eq!(get_url_for_keyword(&conn, "uno")?, None);
+        assert_eq!(get_change_counter(&conn, &url2), 2);
+        let keywords: i64 = conn.query_one("SELECT COUNT(*) FROM moz_keywords")?;
+        assert_eq!(keywords, 0);
+
+        // Removing the last bookmark for a URL removes its keyword.
+        set_keyword(&conn, "two", &url2)?;
+        delete_bookmark(&conn, &"bookmark2222".into())?;
+        assert_eq!(get_url_for_keyword(&conn, "two")?, None);
+
+        Ok(())
+    }
+}
@@ -6,13 +6,19 @@
 // API and the database.
 
 pub mod bookmarks;
+pub mod deletion_log;
 pub mod history;
+pub mod history_expiry;
+pub mod keywords;
+pub mod oversized;
+pub mod sync_log;
 pub mod tags;
 
 use crate::db::PlacesDb;
 use crate::error::{ErrorKind, InvalidPlaceInfo, Result};
 use crate::msg_types::HistoryVisitInfo;
 use crate::types::{SyncGuid, SyncStatus, Timestamp, VisitTransition};
+use crate::util::slice_up_to;
 use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::Result as RusqliteResult;
 use rusqlite::Row;
@@ -25,10 +31,46 @@ use url::Url;
 pub const URL_LENGTH_MAX: usize = 65536;
 pub const TITLE_LENGTH_MAX: usize = 4096;
 pub const TAG_LENGTH_MAX: usize = 100;
-// pub const DESCRIPTION_LENGTH_MAX: usize = 256;
+pub const DESCRIPTION_LENGTH_MAX: usize = 1024;
+
+/// The maximum number of bytes of a malformed URL to include in an error.
+const URL_SNIPPET_LENGTH_MAX: usize = 32;
+
+/// Returns an SQL expression that truncates the title in `column`, for
+/// queries that return titles for display. This guards against rows with
+/// oversized titles that `run_maintenance` hasn't fixed yet. `substr` counts
+/// characters, not bytes, but it's enough to bound the allocation.
+pub(crate) fn display_title_fragment(column: &str) -> String {
+    format!("substr({}, 1, {})", column, TITLE_LENGTH_MAX)
+}
+
+/// Parses a URL passed to us by an API consumer, or from an incoming record.
+/// Unlike `Url::parse`, the error includes the start of the input, so that
+/// we can tell what went wrong.
+pub fn parse_href(href: &str) -> std::result::Result<Url, InvalidPlaceInfo> {
+    Url::parse(href).map_err(|_| InvalidPlaceInfo::MalformedUrl {
+        input_snippet: url_snippet(href),
+    })
+}
+
+fn url_snippet(href: &str) -> String {
+    // `data:` URLs can embed entire documents, so we only keep the scheme.
+    match href.get(..5) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("data:") => return "data:...".into(),
+        _ => {}
+    }
+    let snippet = slice_up_to(href, URL_SNIPPET_LENGTH_MAX);
+    if snippet.len() < href.len() {
+        format!("{}...", snippet)
+    } else {
+        snippet.to_owned()
+    }
+}
 
 // Typesafe way to manage RowIds. Does it make sense? A better way?
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Deserialize, Serialize, Default)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Deserialize, Serialize, Default,
+)]
 pub struct RowId(pub i64);
 
 impl From<RowId> for i64 {
@@ -173,6 +215,38 @@ fn new_page_info(db: &PlacesDb, url: &Url, new_guid: Option<SyncGuid>) -> Result
     })
 }
 
+/// Updates the description and preview image shown for the page at `url`,
+/// like in page cards. Passing `None` clears a value. Descriptions are
+/// truncated to `DESCRIPTION_LENGTH_MAX`, and preview image URLs longer than
+/// `URL_LENGTH_MAX` are rejected. Returns `false`, without adding the page,
+/// if we don't know about `url`.
+pub fn update_page_meta(
+    db: &PlacesDb,
+    url: &Url,
+    description: Option<&str>,
+    preview_image_url: Option<&Url>,
+) -> Result<bool> {
+    let preview_image_url = preview_image_url.map(Url::as_str);
+    if let Some(preview_image_url) = preview_image_url {
+        if preview_image_url.len() > URL_LENGTH_MAX {
+            return Err(ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::UrlTooLong).into());
+        }
+    }
+    let description = description.map(|d| slice_up_to(d, DESCRIPTION_LENGTH_MAX));
+    let changes = db.execute_named_cached(
+        "UPDATE moz_places SET
+             description = :description,
+             preview_image_url = :preview_image_url
+         WHERE url_hash = hash(:url) AND url = :url",
+        &[
+            (":description", &description),
+            (":preview_image_url", &preview_image_url),
+            (":url", &url.as_str()),
+        ],
+    )?;
+    Ok(changes > 0)
+}
+
 impl HistoryVisitInfo {
     pub(crate) fn from_row(row: &rusqlite::Row<'_>) -> Result<Self> {
         let visit_type = VisitTransition::from_primitive(row.get::<_, u8>("visit_type")?)
@@ -186,24 +260,154 @@ impl HistoryVisitInfo {
             title: row.get("title")?,
             timestamp: visit_date.0 as i64,
             visit_type: visit_type as i32,
+            guid: row.get("guid")?,
+            is_local: row.get("is_local")?,
         })
     }
 }
 
 pub fn run_maintenance(conn: &PlacesDb) -> Result<()> {
+    let collisions = bookmarks::repair_guid_case_collisions(conn)?;
+    if collisions > 0 {
+        log::warn!(
+            "Changed {} bookmark GUIDs that only differed by case",
+            collisions
+        );
+    }
+    let expired =
+        history::enforce_history_retention(conn, history::MAX_RETENTION_VISITS_PER_MAINTENANCE)?;
+    if expired > 0 {
+        log::info!(
+            "Expired {} visits per the history retention policy",
+            expired
+        );
+    }
+    let oversized = oversized::fix_oversized_rows(conn)?;
+    if oversized != oversized::OversizedRows::default() {
+        log::warn!("Fixed oversized rows: {:?}", oversized);
+    }
+    let pruned = deletion_log::prune_deletion_log(conn)?;
+    if pruned > 0 {
+        log::info!("Pruned {} old deletion log entries", pruned);
+    }
     conn.execute_all(&["VACUUM", "PRAGMA optimize"])?;
     Ok(())
 }
 
-pub(crate) fn put_meta(db: &PlacesDb, key: &str, value: &dyn ToSql) -> Result<()> {
+/// Deletes all history, bookmarks, tags, keywords, and sync state, leaving
+/// the database like a newly created one, except for the deletion log,
+/// which records the wipe. Like a `reset` for a disconnected
+/// account, the next sync is a first sync, and downloads everything on the
+/// server again, so callers that want to forget everything should wipe the
+/// server, too. Callers must make sure that no other connection is writing.
+pub fn erase_everything(db: &PlacesDb) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    let visits = db.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?;
+    // Everything but the root and its children, which we recreate below.
+    let bookmarks: i64 = db.query_row_and_then_named(
+        "SELECT COUNT(*) FROM moz_bookmarks
+         WHERE parent <> (SELECT id FROM moz_bookmarks WHERE guid = :root_guid)",
+        &[(":root_guid", &bookmarks::BookmarkRootGuid::Root.as_guid())],
+        |row| row.get(0),
+        false,
+    )?;
+    deletion_log::record_deletion(
+        db,
+        deletion_log::DeletionOp::WipeLocal,
+        deletion_log::DeletionInitiator::Api,
+        (visits + bookmarks) as usize,
+    )?;
+    db.execute_all(&[
+        "DELETE FROM moz_bookmarks",
+        "DELETE FROM moz_bookmarks_deleted",
+        "DELETE FROM moz_bookmarks_hidden_queries",
+        "DELETE FROM moz_bookmarks_synced_tag_relation",
+        "DELETE FROM moz_bookmarks_synced_structure",
+        "DELETE FROM moz_bookmarks_synced",
+        "DELETE FROM moz_keywords",
+        "DELETE FROM moz_tags_relation",
+        "DELETE FROM moz_tags",
+        "DELETE FROM moz_historyvisits",
+        "DELETE FROM moz_historyvisit_tombstones",
+        "DELETE FROM moz_inputhistory",
+        "DELETE FROM moz_historyvisit_search_terms",
+        "DELETE FROM moz_places_stale_frecencies",
+        "DELETE FROM moz_places",
+        "DELETE FROM moz_places_tombstones",
+    ])?;
+    // Deleting pages queues origin updates, which also update the origin
+    // frecency stats in `moz_meta`, so we need to flush those first.
+    delete_pending_temp_tables(db)?;
+    db.execute_batch("DELETE FROM moz_origins")?;
+    // The repaired schema version describes the schema, not the data, so
+    // we keep it. We also keep the bookmark change counter, so that folder
+    // change tokens from before the wipe don't match the new roots.
     db.execute_named_cached(
-        "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
-        &[(":key", &key), (":value", value)],
+        "DELETE FROM moz_meta WHERE key NOT IN (:schema_repaired, :change_counter)",
+        &[
+            (
+                ":schema_repaired",
+                &crate::db::schema::MOZ_META_KEY_SCHEMA_REPAIRED,
+            ),
+            (
+                ":change_counter",
+                &crate::db::schema::MOZ_META_KEY_BOOKMARK_CHANGE_COUNTER,
+            ),
+        ],
     )?;
+    bookmarks::create_bookmark_roots(&db.conn())?;
+    tx.commit()?;
+    // SQLite can't VACUUM within a transaction.
+    db.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+/// Keys in `moz_meta` that this crate uses for its own state. Embedders can
+/// read these with `get_meta`, but `put_meta` and `delete_meta` refuse to
+/// change them.
+pub(crate) const RESERVED_META_KEYS: &[&str] = &[
+    crate::api::places_api::GLOBAL_STATE_META_KEY,
+    crate::bookmark_sync::store::LAST_SYNC_META_KEY,
+    crate::bookmark_sync::store::GLOBAL_SYNCID_META_KEY,
+    crate::bookmark_sync::store::COLLECTION_SYNCID_META_KEY,
+    crate::bookmark_sync::store::PARTIAL_SYNC_META_KEY,
+    crate::history_sync::store::LAST_SYNC_META_KEY,
+    crate::history_sync::store::GLOBAL_SYNCID_META_KEY,
+    crate::history_sync::store::COLLECTION_SYNCID_META_KEY,
+    crate::history_sync::store::V1_GLOBAL_STATE_META_KEY,
+    history::DELETION_HIGH_WATER_MARK_META_KEY,
+    history::RETENTION_DAYS_META_KEY,
+    history::RETENTION_MAX_PAGES_META_KEY,
+    history::RETENTION_SYNC_DELETIONS_META_KEY,
+    sync_log::SYNC_LOG_META_KEY,
+    oversized::OVERSIZED_ROWS_FIXED_META_KEY,
+    crate::db::schema::MOZ_META_KEY_ORIGIN_FRECENCY_COUNT,
+    crate::db::schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM,
+    crate::db::schema::MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES,
+    crate::db::schema::MOZ_META_KEY_BOOKMARK_CHANGE_COUNTER,
+    crate::db::schema::MOZ_META_KEY_SCHEMA_REPAIRED,
+];
+
+fn check_meta_key(key: &str) -> Result<()> {
+    if RESERVED_META_KEYS.contains(&key) {
+        return Err(ErrorKind::ReservedMetaKey(key.into()).into());
+    }
     Ok(())
 }
 
-pub(crate) fn get_meta<T: FromSql>(db: &PlacesDb, key: &str) -> Result<Option<T>> {
+/// Stores a small value in the metadata table, replacing any existing value
+/// for `key`. This is meant for embedders that need to persist bits of
+/// state, like the last time they ran maintenance, without creating their
+/// own tables. Fails with `ErrorKind::ReservedMetaKey` if `key` is one that
+/// we use internally.
+pub fn put_meta(db: &PlacesDb, key: &str, value: &dyn ToSql) -> Result<()> {
+    check_meta_key(key)?;
+    put_internal_meta(db, key, value)
+}
+
+/// Returns the value for `key` from the metadata table, or `None` if it's
+/// not set.
+pub fn get_meta<T: FromSql>(db: &PlacesDb, key: &str) -> Result<Option<T>> {
     let res = db.try_query_one(
         "SELECT value FROM moz_meta WHERE key = :key",
         &[(":key", &key)],
@@ -212,7 +416,26 @@ pub(crate) fn get_meta<T: FromSql>(db: &PlacesDb, key: &str) -> Result<Option<T>
     Ok(res)
 }
 
-pub(crate) fn delete_meta(db: &PlacesDb, key: &str) -> Result<()> {
+/// Removes `key` from the metadata table. Deleting a key that isn't set is
+/// not an error. Like `put_meta`, this refuses to delete reserved keys.
+pub fn delete_meta(db: &PlacesDb, key: &str) -> Result<()> {
+    check_meta_key(key)?;
+    delete_internal_meta(db, key)
+}
+
+/// Like `put_meta`, but allows reserved keys. This is what the rest of the
+/// crate should use.
+pub(crate) fn put_internal_meta(db: &PlacesDb, key: &str, value: &dyn ToSql) -> Result<()> {
+    db.retry_on_busy(|| {
+        db.execute_named_cached(
+            "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
+            &[(":key", &key), (":value", value)],
+        )?;
+        Ok(())
+    })
+}
+
+pub(crate) fn delete_internal_meta(db: &PlacesDb, key: &str) -> Result<()> {
     db.execute_named_cached("DELETE FROM moz_meta WHERE key = :key", &[(":key", &key)])?;
     Ok(())
 }
@@ -230,7 +453,102 @@ pub(crate) fn delete_pending_temp_tables(conn: &PlacesDb) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::testing::new_mem_connection;
+
+    #[test]
+    fn test_parse_href() {
+        assert_eq!(
+            parse_href("https://example.com/a").unwrap().as_str(),
+            "https://example.com/a"
+        );
+
+        let malformed = |href: &str| match parse_href(href) {
+            Err(InvalidPlaceInfo::MalformedUrl { input_snippet }) => input_snippet,
+            r => panic!("Expected malformed URL; got {:?}", r),
+        };
+        assert_eq!(malformed("not a url"), "not a url");
+        assert_eq!(malformed(""), "");
+        // Long inputs are truncated, without splitting characters.
+        assert_eq!(
+            malformed(&format!("http://[{}", "\u{e9}".repeat(100))),
+            format!("http://[{}...", "\u{e9}".repeat(12))
+        );
+        // `data:` URLs aren't included at all. These almost never fail to
+        // parse, so we check the snippet directly.
+        assert_eq!(url_snippet("DATA:text/plain,hello"), "data:...");
+    }
+
+    #[test]
+    fn test_update_page_meta() -> Result<()> {
+        let conn = new_mem_connection();
+        let url = Url::parse("https://example.com/page").expect("it's a valid url");
+        let image_url = Url::parse("https://example.com/image.png").expect("it's a valid url");
+
+        // We shouldn't add pages we don't know about.
+        assert!(!update_page_meta(
+            &conn,
+            &url,
+            Some("A page"),
+            Some(&image_url)
+        )?);
+        assert!(fetch_page_info(&conn, &url)?.is_none());
+
+        history::apply_observation(
+            &conn,
+            VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+        )?;
+        let long_description = "x".repeat(DESCRIPTION_LENGTH_MAX + 10);
+        assert!(update_page_meta(
+            &conn,
+            &url,
+            Some(&long_description),
+            Some(&image_url)
+        )?);
+        let get_page_meta = || -> Result<(Option<String>, Option<String>)> {
+            Ok(conn.query_row_and_then_named(
+                "SELECT description, preview_image_url FROM moz_places
+                 WHERE url = :url",
+                &[(":url", &url.as_str())],
+                |row| -> rusqlite::Result<_> { Ok((row.get(0)?, row.get(1)?)) },
+                false,
+            )?)
+        };
+        let (description, preview_image_url) = get_page_meta()?;
+        assert_eq!(
+            description,
+            Some(long_description[..DESCRIPTION_LENGTH_MAX].to_string())
+        );
+        assert_eq!(
+            preview_image_url.as_ref().map(String::as_str),
+            Some(image_url.as_str())
+        );
+
+        // Preview image URLs that are too long are rejected, and leave the
+        // existing values alone.
+        let long_image_url = Url::parse(&format!(
+            "https://example.com/{}",
+            "x".repeat(URL_LENGTH_MAX)
+        ))
+        .expect("it's a valid url");
+        match update_page_meta(&conn, &url, None, Some(&long_image_url))
+            .expect_err("should reject long URL")
+            .kind()
+        {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::UrlTooLong) => {}
+            e => panic!("Expected URL too long; got {:?}", e),
+        }
+        assert_eq!(
+            get_page_meta()?.0.map(|d| d.len()),
+            Some(DESCRIPTION_LENGTH_MAX)
+        );
+
+        // Passing `None` clears both.
+        assert!(update_page_meta(&conn, &url, None, None)?);
+        assert_eq!(get_page_meta()?, (None, None));
+
+        Ok(())
+    }
 
     #[test]
     fn test_meta() {
@@ -253,4 +571,44 @@ mod tests {
             .is_none());
         delete_meta(&conn, "foo").expect("delete non-existing should work");
     }
+
+    #[test]
+    fn test_meta_round_trip() -> Result<()> {
+        let conn = new_mem_connection();
+
+        put_meta(&conn, "int", &-1_234_567_890_123i64)?;
+        assert_eq!(get_meta::<i64>(&conn, "int")?, Some(-1_234_567_890_123));
+
+        put_meta(&conn, "string", &"caf\u{e9}")?;
+        assert_eq!(
+            get_meta::<String>(&conn, "string")?,
+            Some("caf\u{e9}".to_string())
+        );
+
+        put_meta(&conn, "yes", &true)?;
+        put_meta(&conn, "no", &false)?;
+        assert_eq!(get_meta::<bool>(&conn, "yes")?, Some(true));
+        assert_eq!(get_meta::<bool>(&conn, "no")?, Some(false));
+
+        delete_meta(&conn, "yes")?;
+        assert_eq!(get_meta::<bool>(&conn, "yes")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserved_meta_keys() -> Result<()> {
+        let conn = new_mem_connection();
+        let key = crate::bookmark_sync::store::LAST_SYNC_META_KEY;
+        put_internal_meta(&conn, key, &123i64)?;
+
+        for result in &[put_meta(&conn, key, &0i64), delete_meta(&conn, key)] {
+            match result.as_ref().map_err(|e| e.kind()) {
+                Err(ErrorKind::ReservedMetaKey(k)) => assert_eq!(k, key),
+                r => panic!("Expected reserved key error; got {:?}", r),
+            }
+        }
+        // Reading reserved keys is fine.
+        assert_eq!(get_meta::<i64>(&conn, key)?, Some(123));
+        Ok(())
+    }
 }
@@ -0,0 +1,214 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Finds and fixes rows with titles and URLs longer than we allow. We
+//! enforce `TITLE_LENGTH_MAX` and `URL_LENGTH_MAX` when writing, but older
+//! versions didn't, so a few rows, like bookmarklets with huge `javascript:`
+//! URLs, may still exceed them. Every query that touches these rows makes a
+//! large allocation, and the server rejects records for them.
+//!
+//! Oversized titles are truncated. URLs can't be fixed without changing
+//! them, so we quarantine bookmarks with oversized URLs instead: we keep
+//! them locally, but never upload them.
+
+use super::{get_meta, put_internal_meta};
+use super::{TITLE_LENGTH_MAX, URL_LENGTH_MAX};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::util::slice_up_to;
+use sql_support::ConnExt;
+
+/// The total number of rows that `fix_oversized_rows` has fixed, for
+/// metrics.
+pub(crate) const OVERSIZED_ROWS_FIXED_META_KEY: &str = "oversized_rows_fixed";
+
+/// What `fix_oversized_rows` found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OversizedRows {
+    /// The number of page and bookmark titles we truncated.
+    pub truncated_titles: usize,
+    /// The number of bookmarks with oversized URLs, which we won't upload.
+    pub quarantined_bookmarks: usize,
+}
+
+/// Returns an SQL expression that's true if `column` holds a URL that's too
+/// long to sync. URLs are always ASCII, so counting characters is the same
+/// as counting bytes.
+pub(crate) fn url_too_long_fragment(column: &str) -> String {
+    format!("IFNULL(length({}), 0) > {}", column, URL_LENGTH_MAX)
+}
+
+/// Truncates oversized titles, and counts bookmarks with oversized URLs.
+/// This scans all of history, so it should only be called from
+/// `run_maintenance`.
+pub(crate) fn fix_oversized_rows(db: &PlacesDb) -> Result<OversizedRows> {
+    let tx = db.begin_transaction()?;
+    let mut result = OversizedRows::default();
+
+    for table in &["moz_places", "moz_bookmarks"] {
+        let titles = db.query_rows_and_then_named(
+            &format!(
+                "SELECT id, title FROM {table}
+                 WHERE length(CAST(title AS BLOB)) > {max}",
+                table = table,
+                max = TITLE_LENGTH_MAX,
+            ),
+            &[],
+            |row| -> Result<(i64, String)> { Ok((row.get("id")?, row.get("title")?)) },
+        )?;
+        for (id, title) in &titles {
+            let title = slice_up_to(title, TITLE_LENGTH_MAX);
+            if *table == "moz_bookmarks" {
+                // Upload the truncated title, so that other devices get it, too.
+                db.execute_named_cached(
+                    "UPDATE moz_bookmarks SET
+                       title = :title,
+                       lastModified = :now,
+                       syncChangeCounter = syncChangeCounter + 1
+                     WHERE id = :id",
+                    &[(":id", id), (":title", &title), (":now", &db.now())],
+                )?;
+            } else {
+                db.execute_named_cached(
+                    "UPDATE moz_places SET title = :title WHERE id = :id",
+                    &[(":id", id), (":title", &title)],
+                )?;
+            }
+        }
+        result.truncated_titles += titles.len();
+    }
+
+    result.quarantined_bookmarks = db.query_one::<i64>(&format!(
+        "SELECT COUNT(*) FROM moz_bookmarks b
+         JOIN moz_places h ON h.id = b.fk
+         WHERE {}",
+        url_too_long_fragment("h.url"),
+    ))? as usize;
+
+    if result.truncated_titles > 0 {
+        let total = get_meta::<i64>(db, OVERSIZED_ROWS_FIXED_META_KEY)?.unwrap_or(0);
+        put_internal_meta(
+            db,
+            OVERSIZED_ROWS_FIXED_META_KEY,
+            &(total + result.truncated_titles as i64),
+        )?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::storage::history::apply_observation;
+    use crate::testing::{insert_json_tree, new_mem_connection};
+    use crate::types::VisitTransition;
+    use serde_json::json;
+    use url::Url;
+
+    #[test]
+    fn test_fix_oversized_rows() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    },
+                    {
+                        "guid": "bookmarkBBBB",
+                        "title": "B",
+                        "url": "http://example.com/b",
+                    },
+                ],
+            }),
+        )
+        .expect("should insert tree");
+        for url in &["http://example.com/a", "http://example.com/c"] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?).with_visit_type(VisitTransition::Link),
+            )?;
+        }
+
+        // Pretend these were written by an older version, before we enforced
+        // the limits.
+        let long_title = "\u{e9}".repeat(TITLE_LENGTH_MAX);
+        for url in &["http://example.com/a", "http://example.com/c"] {
+            conn.execute_named(
+                "UPDATE moz_places SET url = :long_url WHERE url = :url",
+                &[
+                    (
+                        ":long_url",
+                        &format!("{}#{}", url, "x".repeat(URL_LENGTH_MAX)),
+                    ),
+                    (":url", url),
+                ],
+            )?;
+        }
+        conn.execute_named(
+            "UPDATE moz_places SET title = :title WHERE url = 'http://example.com/b'",
+            &[(":title", &long_title)],
+        )?;
+        conn.execute_named(
+            "UPDATE moz_bookmarks SET title = :title, syncChangeCounter = 0
+             WHERE guid = 'bookmarkBBBB'",
+            &[(":title", &long_title)],
+        )?;
+
+        assert_eq!(
+            fix_oversized_rows(&conn)?,
+            OversizedRows {
+                truncated_titles: 2,
+                quarantined_bookmarks: 1,
+            }
+        );
+
+        // Titles are truncated without splitting characters.
+        let (page_title, bookmark_title, counter) = conn.query_row_named_with_context(
+            "SELECT h.title, b.title, b.syncChangeCounter
+             FROM moz_bookmarks b
+             JOIN moz_places h ON h.id = b.fk
+             WHERE b.guid = 'bookmarkBBBB'",
+            &[],
+            |row| -> rusqlite::Result<(String, String, i64)> {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            },
+        )?;
+        assert_eq!(page_title, "\u{e9}".repeat(TITLE_LENGTH_MAX / 2));
+        assert_eq!(bookmark_title, page_title);
+        assert_eq!(counter, 1);
+
+        // Pages with oversized URLs are kept, whether or not they're
+        // bookmarked.
+        assert_eq!(conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places")?, 3);
+        assert!(
+            crate::storage::bookmarks::get_raw_bookmark(&conn, &"bookmarkAAAA".into())?.is_some()
+        );
+
+        assert_eq!(
+            get_meta::<i64>(&conn, OVERSIZED_ROWS_FIXED_META_KEY)?,
+            Some(2)
+        );
+        let metrics = crate::metrics::collect(&conn)?;
+        assert_eq!(metrics.oversized_rows_fixed, 2);
+        assert_eq!(metrics.quarantined_bookmarks, 1);
+
+        // Running it again shouldn't fix anything else.
+        assert_eq!(
+            fix_oversized_rows(&conn)?,
+            OversizedRows {
+                truncated_titles: 0,
+                quarantined_bookmarks: 1,
+            }
+        );
+        Ok(())
+    }
+}
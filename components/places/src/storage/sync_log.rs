@@ -0,0 +1,207 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A log of recent sync attempts, for debugging. Each entry records when we
+//! synced, how many records each engine downloaded and uploaded, and the
+//! class of any error. We never store URLs, titles, or tokens here; only
+//! counts and error classes.
+
+use super::{get_meta, put_internal_meta};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::types::Timestamp;
+use serde_derive::*;
+
+/// The maximum number of sync attempts we keep in the log.
+pub const MAX_SYNC_LOG_ENTRIES: usize = 30;
+
+pub(crate) const SYNC_LOG_META_KEY: &str = "sync_log";
+
+/// A sync attempt in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncLogEntry {
+    /// When the sync finished.
+    pub at: Timestamp,
+    /// Outcomes for each engine that we tried to sync, sorted by name.
+    /// This is empty if the sync failed before any engines ran.
+    pub engines: Vec<EngineSyncLogEntry>,
+    /// The class of the error that aborted the sync, if any.
+    pub error: Option<String>,
+}
+
+/// The outcome of syncing a single engine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineSyncLogEntry {
+    pub name: String,
+    /// The number of records downloaded.
+    pub incoming: usize,
+    /// The number of records uploaded.
+    pub outgoing: usize,
+    /// The class of the error that failed this engine, if any.
+    pub error: Option<String>,
+}
+
+impl SyncLogEntry {
+    /// Summarizes the result of `sync15::sync_multiple`.
+    pub fn new(at: Timestamp, result: &sync15::Result<sync15::SyncResult>) -> Self {
+        match result {
+            Ok(result) => {
+                let mut engines = result
+                    .successes
+                    .iter()
+                    .map(|(name, outcome)| EngineSyncLogEntry {
+                        name: name.clone(),
+                        incoming: outcome.incoming,
+                        outgoing: outcome.outgoing,
+                        error: None,
+                    })
+                    .chain(
+                        result
+                            .failures
+                            .iter()
+                            .map(|(name, err)| EngineSyncLogEntry {
+                                name: name.clone(),
+                                incoming: 0,
+                                outgoing: 0,
+                                error: Some(err.class().into()),
+                            }),
+                    )
+                    .collect::<Vec<_>>();
+                engines.sort_by(|a, b| a.name.cmp(&b.name));
+                SyncLogEntry {
+                    at,
+                    engines,
+                    error: None,
+                }
+            }
+            Err(err) => SyncLogEntry {
+                at,
+                engines: Vec::new(),
+                error: Some(err.class().into()),
+            },
+        }
+    }
+}
+
+/// Appends an entry for a sync attempt to the log, dropping the oldest
+/// entries if the log is full.
+pub(crate) fn record_sync(db: &PlacesDb, entry: SyncLogEntry) -> Result<()> {
+    let mut entries = fetch_entries(db)?;
+    entries.push(entry);
+    if entries.len() > MAX_SYNC_LOG_ENTRIES {
+        let excess = entries.len() - MAX_SYNC_LOG_ENTRIES;
+        entries.drain(..excess);
+    }
+    put_internal_meta(db, SYNC_LOG_META_KEY, &serde_json::to_string(&entries)?)
+}
+
+/// Returns up to `limit` of the most recent sync attempts, newest first.
+pub fn get_sync_history(db: &PlacesDb, limit: usize) -> Result<Vec<SyncLogEntry>> {
+    let mut entries = fetch_entries(db)?;
+    entries.reverse();
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+fn fetch_entries(db: &PlacesDb) -> Result<Vec<SyncLogEntry>> {
+    Ok(match get_meta::<String>(db, SYNC_LOG_META_KEY)? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::new_mem_connection;
+    use std::collections::HashMap;
+    use sync15::{CollectionSyncOutcome, ErrorKind as SyncErrorKind, SyncResult, TokenserverError};
+
+    #[test]
+    fn test_sync_history() -> Result<()> {
+        let conn = new_mem_connection();
+
+        assert!(get_sync_history(&conn, 10)?.is_empty());
+
+        // A sync where history succeeded and bookmarks failed.
+        let mut successes = HashMap::new();
+        successes.insert(
+            "history".to_string(),
+            CollectionSyncOutcome {
+                incoming: 5,
+                outgoing: 2,
+                overflow: false,
+            },
+        );
+        let mut failures = HashMap::new();
+        failures.insert(
+            "bookmarks".to_string(),
+            sync15::Error::from(SyncErrorKind::StorageHttpError {
+                code: 500,
+                route: "https://example.com/storage/bookmarks".into(),
+            }),
+        );
+        let result = Ok(SyncResult {
+            failures,
+            successes,
+            ..SyncResult::default()
+        });
+        record_sync(&conn, SyncLogEntry::new(Timestamp(1), &result))?;
+
+        // A sync that failed before any engines ran.
+        let result =
+            Err(SyncErrorKind::TokenserverError(TokenserverError::InvalidCredentials).into());
+        record_sync(&conn, SyncLogEntry::new(Timestamp(2), &result))?;
+
+        assert_eq!(
+            get_sync_history(&conn, 10)?,
+            vec![
+                SyncLogEntry {
+                    at: Timestamp(2),
+                    engines: Vec::new(),
+                    error: Some("auth".into()),
+                },
+                SyncLogEntry {
+                    at: Timestamp(1),
+                    engines: vec![
+                        EngineSyncLogEntry {
+                            name: "bookmarks".into(),
+                            incoming: 0,
+                            outgoing: 0,
+                            error: Some("http".into()),
+                        },
+                        EngineSyncLogEntry {
+                            name: "history".into(),
+                            incoming: 5,
+                            outgoing: 2,
+                            error: None,
+                        },
+                    ],
+                    error: None,
+                },
+            ]
+        );
+        assert_eq!(get_sync_history(&conn, 1)?.len(), 1);
+
+        // Make sure we don't persist anything from the error message.
+        let json = get_meta::<String>(&conn, SYNC_LOG_META_KEY)?.expect("Should store the log");
+        assert!(!json.contains("example.com"), "{}", json);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_history_max_entries() -> Result<()> {
+        let conn = new_mem_connection();
+        for i in 0..MAX_SYNC_LOG_ENTRIES + 5 {
+            let result = Ok(SyncResult::default());
+            record_sync(&conn, SyncLogEntry::new(Timestamp(i as u64), &result))?;
+        }
+        let entries = get_sync_history(&conn, MAX_SYNC_LOG_ENTRIES * 2)?;
+        assert_eq!(entries.len(), MAX_SYNC_LOG_ENTRIES);
+        assert_eq!(entries[0].at, Timestamp((MAX_SYNC_LOG_ENTRIES + 4) as u64));
+        assert_eq!(entries[MAX_SYNC_LOG_ENTRIES - 1].at, Timestamp(5));
+        Ok(())
+    }
+}
@@ -2,9 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use super::{fetch_page_info, TAG_LENGTH_MAX};
+use super::{fetch_page_info, RowId, TAG_LENGTH_MAX};
 use crate::db::PlacesDb;
 use crate::error::{InvalidPlaceInfo, Result};
+use rusqlite::Connection;
 use sql_support::ConnExt;
 use url::Url;
 
@@ -55,7 +56,33 @@ pub fn validate_tag(tag: &str) -> ValidatedTag<'_> {
     }
 }
 
-/// Tags the specified URL.
+/// Returns the id of the tag that matches `tag`, ignoring case, and adds
+/// the tag if it doesn't exist. The first spelling of a tag wins, so tagging
+/// a URL with "Foo" after "foo" reuses "foo". Like SQLite's `NOCASE`
+/// collation, this only ignores the case of ASCII letters.
+pub(crate) fn get_or_insert_tag_id(db: &Connection, tag: &str) -> Result<RowId> {
+    let existing = db.try_query_row(
+        "SELECT id FROM moz_tags
+         WHERE tag = :tag COLLATE NOCASE
+         ORDER BY id
+         LIMIT 1",
+        &[(":tag", &tag)],
+        |row| row.get::<_, RowId>(0),
+        true,
+    )?;
+    if let Some(id) = existing {
+        return Ok(id);
+    }
+    db.execute_named_cached(
+        "INSERT INTO moz_tags(tag, lastModified)
+         VALUES(:tag, now())",
+        &[(":tag", &tag)],
+    )?;
+    Ok(RowId(db.last_insert_rowid()))
+}
+
+/// Tags the specified URL. Tags are trimmed, and matched to existing tags
+/// ignoring case.
 ///
 /// # Arguments
 ///
@@ -80,16 +107,11 @@ pub fn tag_url(db: &PlacesDb, url: &Url, tag: &str) -> Result<()> {
         None => return Err(InvalidPlaceInfo::NoSuchUrl.into()),
     };
 
-    db.execute_named_cached(
-        "INSERT OR IGNORE INTO moz_tags(tag, lastModified)
-         VALUES(:tag, now())",
-        &[(":tag", &tag)],
-    )?;
-
+    let tag_id = get_or_insert_tag_id(db, tag)?;
     db.execute_named_cached(
         "INSERT OR IGNORE INTO moz_tags_relation(tag_id, place_id)
-         VALUES((SELECT id FROM moz_tags WHERE tag = :tag), :place_id)",
-        &[(":tag", &tag), (":place_id", &place_id)],
+         VALUES(:tag_id, :place_id)",
+        &[(":tag_id", &tag_id), (":place_id", &place_id)],
     )?;
     tx.commit()?;
     Ok(())
@@ -113,8 +135,8 @@ pub fn untag_url(db: &PlacesDb, url: &Url, tag: &str) -> Result<()> {
     let tag = validate_tag(&tag).ensure_valid()?;
     db.execute_named_cached(
         "DELETE FROM moz_tags_relation
-         WHERE tag_id = (SELECT id FROM moz_tags
-                         WHERE tag = :tag)
+         WHERE tag_id IN (SELECT id FROM moz_tags
+                          WHERE tag = :tag COLLATE NOCASE)
          AND place_id = (SELECT id FROM moz_places
                          WHERE url_hash = hash(:url)
                          AND url = :url)",
@@ -160,7 +182,7 @@ pub fn remove_all_tags_from_url(db: &PlacesDb, url: &Url) -> Result<()> {
 pub fn remove_tag(db: &PlacesDb, tag: &str) -> Result<()> {
     db.execute_named_cached(
         "DELETE FROM moz_tags
-         WHERE tag = :tag",
+         WHERE tag = :tag COLLATE NOCASE",
         &[(":tag", &tag)],
     )?;
     Ok(())
@@ -185,7 +207,7 @@ pub fn get_urls_with_tag(db: &PlacesDb, tag: &str) -> Result<Vec<Url>> {
         "SELECT p.url FROM moz_places p
          JOIN moz_tags_relation r ON r.place_id = p.id
          JOIN moz_tags t ON t.id = r.tag_id
-         WHERE t.tag = :tag
+         WHERE t.tag = :tag COLLATE NOCASE
          ORDER BY p.frecency",
     )?;
 
@@ -231,8 +253,8 @@ pub fn get_tags_for_url(db: &PlacesDb, url: &Url) -> Result<Vec<String>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::places_api::test::new_mem_connection;
     use crate::storage::new_page_info;
+    use crate::testing::new_mem_connection;
 
     fn check_tags_for_url(db: &PlacesDb, url: &Url, mut expected: Vec<String>) {
         let mut tags = get_tags_for_url(&db, &url).expect("should work");
@@ -359,4 +381,24 @@ mod tests {
             .expect("should work")
             .expect("should exist");
     }
+
+    #[test]
+    fn test_tags_ignore_case() {
+        let conn = new_mem_connection();
+        let url = Url::parse("http://example.com").expect("valid url");
+        new_page_info(&conn, &url, None).expect("should create the page");
+
+        tag_url(&conn, &url, "Foo").expect("should work");
+        tag_url(&conn, &url, " foo ").expect("should work");
+        tag_url(&conn, &url, "FOO").expect("should work");
+        check_tags_for_url(&conn, &url, vec!["Foo".to_string()]);
+        assert_eq!(get_foreign_count(&conn, &url), 1);
+        check_urls_with_tag(&conn, "fOO", vec![url.clone()]);
+
+        untag_url(&conn, &url, "foo").expect("should work");
+        check_tags_for_url(&conn, &url, vec![]);
+        assert_eq!(get_foreign_count(&conn, &url), 0);
+
+        assert!(tag_url(&conn, &url, &"f".repeat(TAG_LENGTH_MAX + 1)).is_err());
+    }
 }
@@ -0,0 +1,125 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for tests. These are always available to this crate's unit tests,
+//! and to other crates with the `testutils` feature enabled, so that their
+//! tests can build bookmark fixtures without reaching into private modules.
+//! They're not compiled into normal builds.
+
+mod synced_item;
+pub use synced_item::{SyncedBookmarkItem, SyncedBookmarkValue};
+
+use pretty_assertions::assert_eq;
+use rusqlite::NO_PARAMS;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::{
+    api::places_api::{ConnectionType, PlacesApi},
+    db::PlacesDb,
+    error::*,
+    storage::bookmarks::{fetch_tree, insert_tree, BookmarkTreeNode},
+    types::SyncGuid,
+};
+
+// A helper for our tests to get their own memory Api.
+static ATOMIC_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a new API for an in-memory database. Each call returns a
+/// separate database.
+pub fn new_mem_api() -> Arc<PlacesApi> {
+    let counter = ATOMIC_COUNTER.fetch_add(1, Ordering::Relaxed);
+    PlacesApi::new_memory(&format!("test-api-{}", counter)).expect("should get an API")
+}
+
+/// Returns a read-write connection to a new in-memory database.
+pub fn new_mem_connection() -> PlacesDb {
+    new_mem_api()
+        .open_connection(ConnectionType::ReadWrite)
+        .expect("should get a connection")
+}
+
+pub struct MemConnections {
+    pub read: PlacesDb,
+    pub write: PlacesDb,
+    pub api: Arc<PlacesApi>,
+}
+
+/// Returns read-only and read-write connections to a new in-memory
+/// database.
+pub fn new_mem_connections() -> MemConnections {
+    let api = new_mem_api();
+    let read = api
+        .open_connection(ConnectionType::ReadOnly)
+        .expect("should get a read connection");
+    let write = api
+        .open_connection(ConnectionType::ReadWrite)
+        .expect("should get a write connection");
+    MemConnections { api, read, write }
+}
+
+/// Inserts a bookmark tree, described as JSON in the same format as
+/// `BookmarkTreeNode`. The root of the tree must be an existing folder.
+pub fn insert_json_tree(conn: &PlacesDb, jtree: Value) -> Result<()> {
+    let tree: BookmarkTreeNode = serde_json::from_value(jtree)?;
+    let folder_node = match tree {
+        BookmarkTreeNode::Folder(folder_node) => folder_node,
+        _ => {
+            return Err(InvalidPlaceInfo::InvalidParent(
+                "The root of the tree must be a folder".into(),
+            )
+            .into())
+        }
+    };
+    insert_tree(conn, &folder_node)
+}
+
+/// Asserts that the tree for `folder` matches `expected`, and that positions
+/// in all folders are correct.
+pub fn assert_json_tree(conn: &PlacesDb, folder: &SyncGuid, expected: Value) {
+    let fetched = fetch_tree(conn, folder)
+        .expect("error fetching tree")
+        .unwrap();
+    let deser_tree: BookmarkTreeNode = serde_json::from_value(expected).unwrap();
+    assert_eq!(fetched, deser_tree);
+    // and while checking the tree, check positions are correct.
+    check_positions(&conn);
+}
+
+// check the positions for children in a folder are "correct" in that
+// the first child has a value of zero, etc - ie, this will fail if there
+// are holes or duplicates in the position values.
+// Clever implementation stolen from desktop.
+pub fn check_positions(conn: &PlacesDb) {
+    // Use triangular numbers to detect skipped position, then
+    // a subquery to select enough fields to help diagnose when it fails.
+    let sql = "
+        WITH bad_parents(pid) as (
+            SELECT parent
+            FROM moz_bookmarks
+            GROUP BY parent
+            HAVING (SUM(DISTINCT position + 1) - (count(*) * (count(*) + 1) / 2)) <> 0
+        )
+        SELECT parent, guid, title, position FROM moz_bookmarks
+        WHERE parent in bad_parents
+        ORDER BY parent, position
+    ";
+
+    let mut stmt = conn.prepare(sql).expect("sql is ok");
+    let parents: Vec<_> = stmt
+        .query_and_then(NO_PARAMS, |row| -> rusqlite::Result<_> {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, u32>(3)?,
+            ))
+        })
+        .expect("should work")
+        .map(std::result::Result::unwrap)
+        .collect();
+
+    assert_eq!(parents, Vec::new());
+}
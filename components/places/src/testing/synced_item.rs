@@ -66,6 +66,7 @@ pub struct SyncedBookmarkItem {
     pub smart_bookmark_name: SyncedBookmarkValue<Option<String>>,
     pub feed_url: SyncedBookmarkValue<Option<String>>,
     pub site_url: SyncedBookmarkValue<Option<String>>,
+    pub position: SyncedBookmarkValue<Option<i64>>,
     // Note that url is *not* in the table, but a convenience for tests.
     pub url: SyncedBookmarkValue<Option<Url>>,
     pub tags: SyncedBookmarkValue<Vec<String>>,
@@ -146,6 +147,7 @@ impl SyncedBookmarkItem {
     impl_builder_opt_string!(smart_bookmark_name);
     impl_builder_opt_string!(feed_url);
     impl_builder_opt_string!(site_url);
+    impl_builder_simple!(position, Option<i64>);
 
     pub fn tags<'a>(&'a mut self, mut tags: Vec<String>) -> &'a mut SyncedBookmarkItem {
         tags.sort();
@@ -237,6 +239,7 @@ impl SyncedBookmarkItem {
             smart_bookmark_name: SyncedBookmarkValue::Specified(row.get("smartBookmarkName")?),
             feed_url: SyncedBookmarkValue::Specified(row.get("feedUrl")?),
             site_url: SyncedBookmarkValue::Specified(row.get("siteUrl")?),
+            position: SyncedBookmarkValue::Specified(row.get("position")?),
             url: SyncedBookmarkValue::Specified(
                 row.get::<_, Option<String>>("url")?
                     .and_then(|s| Url::parse(&s).ok()),
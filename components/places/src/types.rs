@@ -2,6 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::error::{Error, ErrorKind};
 use crate::storage::bookmarks::BookmarkRootGuid;
 use dogear;
 use failure::Fail;
@@ -11,6 +12,11 @@ use serde::ser::{Serialize, Serializer};
 use serde_derive::*;
 use std::convert::TryFrom;
 use std::fmt;
+#[cfg(test)]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 mod visit_transition_set;
@@ -83,6 +89,8 @@ impl fmt::Display for SyncGuid {
 pub struct Timestamp(pub u64);
 
 impl Timestamp {
+    /// Returns the current system time. Code that has a connection should
+    /// use `PlacesDb::now` instead, which respects the connection's `Clock`.
     pub fn now() -> Self {
         SystemTime::now().into()
     }
@@ -100,6 +108,50 @@ impl Timestamp {
     }
 }
 
+/// A source of the current time. Each `PlacesDb` has a clock, which it uses
+/// for the timestamps that it writes, and for the `now()` SQL function.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Timestamp;
+}
+
+/// The system clock. Connections use this unless they're given another
+/// clock with `PlacesDb::set_clock`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Timestamp {
+        Timestamp::now()
+    }
+}
+
+/// A clock that stays at a given time until it's moved. This makes it
+/// possible to test logic that depends on the current time, like frecency
+/// buckets, at exact boundaries.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct FrozenClock(AtomicU64);
+
+#[cfg(test)]
+impl FrozenClock {
+    pub fn at(now: Timestamp) -> Arc<Self> {
+        Arc::new(FrozenClock(AtomicU64::new(now.0)))
+    }
+
+    /// Moves the frozen clock to a new time.
+    pub fn set(&self, now: Timestamp) {
+        self.0.store(now.0, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FrozenClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.0.load(Ordering::SeqCst))
+    }
+}
+
 impl From<Timestamp> for u64 {
     #[inline]
     fn from(ts: Timestamp) -> Self {
@@ -261,6 +313,13 @@ impl FromSql for BookmarkType {
     }
 }
 
+impl TryFrom<u8> for BookmarkType {
+    type Error = Error;
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        BookmarkType::from_u8(v).ok_or_else(|| ErrorKind::UnsupportedBookmarkType(v).into())
+    }
+}
+
 impl BookmarkType {
     #[inline]
     pub fn from_u8(v: u8) -> Option<Self> {
@@ -354,4 +413,29 @@ mod tests {
         );
         assert_eq!(None, VisitTransition::from_primitive(99));
     }
+
+    #[test]
+    fn test_bookmark_type_try_from() {
+        for typ in &[
+            BookmarkType::Bookmark,
+            BookmarkType::Folder,
+            BookmarkType::Separator,
+        ] {
+            assert_eq!(BookmarkType::try_from(*typ as u8).unwrap(), *typ);
+        }
+        for v in &[0, 4] {
+            match BookmarkType::try_from(*v).unwrap_err().kind() {
+                ErrorKind::UnsupportedBookmarkType(t) => assert_eq!(t, v),
+                k => panic!("Unexpected error kind {:?}", k),
+            }
+        }
+    }
+
+    #[test]
+    fn test_frozen_clock() {
+        let clock = FrozenClock::at(Timestamp(1_000));
+        assert_eq!(clock.now(), Timestamp(1_000));
+        clock.set(Timestamp(2_000));
+        assert_eq!(clock.now(), Timestamp(2_000));
+    }
 }
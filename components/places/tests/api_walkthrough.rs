@@ -0,0 +1,142 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Walks through the intended workflow for the public Places API, the way
+//! an app would use it. This is an integration test, so it can only use
+//! what the crate exports; if you need to reach into a private module here,
+//! the API is missing something.
+
+use places::storage::bookmarks::{delete_bookmark, fetch_tree, insert_tree};
+use places::{
+    apply_observation, metrics, search_frecent, storage, BookmarkNode, BookmarkRootGuid,
+    BookmarkTreeNode, ConnectionType, ErrorKind, FolderNode, InvalidPlaceInfo, PlacesApi,
+    SearchParams, SyncGuid, VisitObservation, VisitTransition,
+};
+use serde_json::json;
+use url::Url;
+
+#[test]
+fn test_api_walkthrough() -> places::Result<()> {
+    let api = PlacesApi::new_memory("test_api_walkthrough")?;
+    let writer = api.open_connection(ConnectionType::ReadWrite)?;
+    let reader = api.open_connection(ConnectionType::ReadOnly)?;
+
+    // Note some visits.
+    for (url, title, transition) in &[
+        (
+            "https://www.mozilla.org/",
+            "Mozilla",
+            VisitTransition::Typed,
+        ),
+        ("https://www.mozilla.org/", "Mozilla", VisitTransition::Link),
+        ("https://www.rust-lang.org/", "Rust", VisitTransition::Link),
+        ("https://example.com/page", "Example", VisitTransition::Link),
+    ] {
+        apply_observation(
+            &writer,
+            VisitObservation::new(Url::parse(url)?)
+                .with_title(title.to_string())
+                .with_visit_type(*transition),
+        )?;
+    }
+
+    // Autocomplete from another connection.
+    let results = search_frecent(
+        &reader,
+        SearchParams {
+            search_string: "mozilla".into(),
+            limit: 10,
+        },
+    )?;
+    assert!(
+        results
+            .iter()
+            .any(|result| result.url.as_str().starts_with("https://www.mozilla.org/")),
+        "Should match Mozilla: {:?}",
+        results
+    );
+    assert!(
+        results
+            .iter()
+            .all(|result| !result.url.as_str().contains("rust-lang")),
+        "Shouldn't match Rust: {:?}",
+        results
+    );
+
+    // Insert a tree of bookmarks into the menu.
+    insert_tree(
+        &writer,
+        &FolderNode {
+            guid: Some(BookmarkRootGuid::Menu.as_guid()),
+            children: vec![
+                FolderNode {
+                    guid: Some("folderAAAAAA".into()),
+                    title: Some("Work".into()),
+                    children: vec![BookmarkNode {
+                        guid: Some("bookmarkBBBB".into()),
+                        date_added: None,
+                        last_modified: None,
+                        title: Some("Docs".into()),
+                        url: Url::parse("https://docs.example.com/")?,
+                    }
+                    .into()],
+                    ..FolderNode::default()
+                }
+                .into(),
+                BookmarkNode {
+                    guid: Some("bookmarkCCCC".into()),
+                    date_added: None,
+                    last_modified: None,
+                    title: Some("Mozilla".into()),
+                    url: Url::parse("https://www.mozilla.org/")?,
+                }
+                .into(),
+            ],
+            ..FolderNode::default()
+        },
+    )?;
+
+    // Fetch it back, and serialize it to JSON, like the FFI does.
+    let menu = fetch_tree(&reader, &BookmarkRootGuid::Menu.as_guid())?.expect("Menu should exist");
+    match &menu {
+        BookmarkTreeNode::Folder(folder) => assert_eq!(folder.children.len(), 2),
+        _ => panic!("Menu should be a folder: {:?}", menu),
+    }
+    let menu_json = serde_json::to_value(&menu)?;
+    assert_eq!(menu_json["guid"], json!("menu________"));
+    assert_eq!(menu_json["children"][0]["guid"], json!("folderAAAAAA"));
+    assert_eq!(menu_json["children"][0]["title"], json!("Work"));
+    assert_eq!(
+        menu_json["children"][0]["children"][0]["url"],
+        json!("https://docs.example.com/")
+    );
+    assert_eq!(menu_json["children"][1]["guid"], json!("bookmarkCCCC"));
+
+    // Delete the folder, which also deletes its children.
+    delete_bookmark(&writer, &SyncGuid::from("folderAAAAAA"))?;
+    assert!(fetch_tree(&reader, &"bookmarkBBBB".into())?.is_none());
+    // Deleting it again does nothing.
+    delete_bookmark(&writer, &"folderAAAAAA".into())?;
+
+    // Errors can be matched by kind.
+    match delete_bookmark(&writer, &BookmarkRootGuid::Menu.as_guid()) {
+        Err(err) => match err.kind() {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidGuid) => {}
+            kind => panic!("Unexpected error deleting the menu: {:?}", kind),
+        },
+        Ok(()) => panic!("Shouldn't be able to delete the menu"),
+    }
+
+    storage::run_maintenance(&writer)?;
+
+    let metrics = metrics::collect(&reader)?;
+    assert_eq!(metrics.visit_count, 4);
+    assert_eq!(metrics.bookmark_count, 1);
+    // Just the built-in roots.
+    assert_eq!(metrics.folder_depths, vec![4]);
+    assert!(metrics.page_count >= 3);
+    assert!(metrics.db_size_bytes > 0);
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Uses the helpers in `places::testing` the way another crate's tests
+//! would. Only built with the `testutils` feature enabled.
+
+use places::testing::{assert_json_tree, insert_json_tree, new_mem_connection};
+use places::BookmarkRootGuid;
+use serde_json::json;
+
+#[test]
+fn test_json_tree_fixture() {
+    let conn = new_mem_connection();
+    insert_json_tree(
+        &conn,
+        json!({
+            "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+            "children": [
+                {
+                    "guid": "folder1_____",
+                    "title": "A folder",
+                    "children": [
+                        {
+                            "guid": "bookmark1___",
+                            "title": "the bookmark",
+                            "url": "https://www.example.com/"
+                        },
+                    ],
+                },
+                {
+                    "guid": "bookmark2___",
+                    "url": "https://www.example.com/2",
+                },
+            ]
+        }),
+    )
+    .expect("should insert tree");
+
+    assert_json_tree(
+        &conn,
+        &BookmarkRootGuid::Unfiled.into(),
+        json!({
+            "guid": &BookmarkRootGuid::Unfiled.as_guid(),
+            "children": [
+                {
+                    "guid": "folder1_____",
+                    "title": "A folder",
+                    "children": [
+                        {
+                            "guid": "bookmark1___",
+                            "title": "the bookmark",
+                            "url": "https://www.example.com/"
+                        },
+                    ],
+                },
+                {
+                    "guid": "bookmark2___",
+                    "url": "https://www.example.com/2",
+                },
+            ]
+        }),
+    );
+}
+
+#[test]
+fn test_json_tree_root_must_be_folder() {
+    let conn = new_mem_connection();
+    insert_json_tree(
+        &conn,
+        json!({
+            "guid": "bookmark1___",
+            "url": "https://www.example.com/"
+        }),
+    )
+    .expect_err("should reject a bookmark as the root");
+}
@@ -14,6 +14,7 @@ pub mod hmac;
 #[cfg(not(target_os = "ios"))]
 mod p11;
 pub mod rand;
+pub mod sensitive;
 #[cfg(not(target_os = "ios"))]
 mod util;
 
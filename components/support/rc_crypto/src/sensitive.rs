@@ -0,0 +1,200 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Wrappers for secret material (tokens, derived keys, ...) that overwrite
+//! their contents with zeros when dropped and never print them in
+//! `Debug` or `Display` output.
+
+use std::{
+    fmt, hash,
+    ops::{Deref, DerefMut},
+    sync::atomic::{self, Ordering},
+};
+
+/// What `SensitiveBytes` and `SensitiveString` print instead of their contents.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Overwrites `buf` with zeros in a way the optimizer isn't allowed to elide.
+pub fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        // Safety: `byte` is a valid, aligned, exclusive reference.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// A byte buffer holding secret material.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SensitiveBytes(Vec<u8>);
+
+impl SensitiveBytes {
+    /// Zeroes the buffer in place. This happens automatically on drop.
+    pub fn zeroize(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl Drop for SensitiveBytes {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl From<Vec<u8>> for SensitiveBytes {
+    #[inline]
+    fn from(bytes: Vec<u8>) -> Self {
+        SensitiveBytes(bytes)
+    }
+}
+
+impl From<&[u8]> for SensitiveBytes {
+    #[inline]
+    fn from(bytes: &[u8]) -> Self {
+        SensitiveBytes(bytes.to_vec())
+    }
+}
+
+impl Deref for SensitiveBytes {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SensitiveBytes {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for SensitiveBytes {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl hash::Hash for SensitiveBytes {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Debug for SensitiveBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SensitiveBytes({})", REDACTED)
+    }
+}
+
+impl fmt::Display for SensitiveBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// A string holding secret material, such as a hex-encoded session token.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SensitiveString(String);
+
+impl SensitiveString {
+    /// Zeroes the string in place. This happens automatically on drop.
+    pub fn zeroize(&mut self) {
+        // Safety: all zeros is valid UTF-8.
+        zeroize(unsafe { self.0.as_bytes_mut() });
+    }
+}
+
+impl Drop for SensitiveString {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl From<String> for SensitiveString {
+    #[inline]
+    fn from(s: String) -> Self {
+        SensitiveString(s)
+    }
+}
+
+impl From<&str> for SensitiveString {
+    #[inline]
+    fn from(s: &str) -> Self {
+        SensitiveString(s.to_owned())
+    }
+}
+
+impl Deref for SensitiveString {
+    type Target = str;
+    #[inline]
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for SensitiveString {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SensitiveString {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl hash::Hash for SensitiveString {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Debug for SensitiveString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SensitiveString({})", REDACTED)
+    }
+}
+
+impl fmt::Display for SensitiveString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let bytes = SensitiveBytes::from(b"hunter2".to_vec());
+        assert_eq!(format!("{:?}", bytes), "SensitiveBytes([REDACTED])");
+        assert_eq!(bytes.to_string(), REDACTED);
+        assert_eq!(&*bytes, b"hunter2");
+
+        let s = SensitiveString::from("hunter2");
+        assert_eq!(format!("{:?}", s), "SensitiveString([REDACTED])");
+        assert_eq!(s.to_string(), REDACTED);
+        assert_eq!(&*s, "hunter2");
+    }
+
+    #[test]
+    fn test_zeroize() {
+        // `Drop` delegates to `zeroize`. We can't soundly look at the buffer
+        // once it's been freed, so check `zeroize` directly.
+        let mut bytes = SensitiveBytes::from(vec![0xAAu8; 64]);
+        bytes.zeroize();
+        assert_eq!(&*bytes, &[0u8; 64][..]);
+
+        let mut s = SensitiveString::from("hunter2".to_string());
+        s.zeroize();
+        assert!(s.bytes().all(|b| b == 0));
+        assert_eq!(s.len(), 7);
+    }
+}
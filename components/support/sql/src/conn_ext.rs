@@ -15,6 +15,31 @@ use crate::maybe_cached::MaybeCached;
 
 pub struct Conn(rusqlite::Connection);
 
+/// The maximum number of characters of a failing statement that we include
+/// in error messages.
+const ERROR_SQL_SNIPPET_LENGTH: usize = 100;
+
+/// Adds a truncated copy of `sql`, with its whitespace collapsed, to the
+/// message of a SQLite failure. Other errors are returned unchanged, since
+/// they don't have anywhere to put it.
+pub fn with_sql_context(err: rusqlite::Error, sql: &str) -> rusqlite::Error {
+    match err {
+        rusqlite::Error::SqliteFailure(code, msg) => {
+            let mut snippet = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+            if let Some((end, _)) = snippet.char_indices().nth(ERROR_SQL_SNIPPET_LENGTH) {
+                snippet.truncate(end);
+                snippet.push_str("...");
+            }
+            let msg = match msg {
+                Some(msg) => format!("{} (in statement: {})", msg, snippet),
+                None => format!("{} (in statement: {})", code, snippet),
+            };
+            rusqlite::Error::SqliteFailure(code, Some(msg))
+        }
+        err => err,
+    }
+}
+
 /// This trait exists so that we can use these helpers on `rusqlite::{Transaction, Connection}`.
 /// Note that you must import ConnExt in order to call these methods on anything.
 pub trait ConnExt {
@@ -42,11 +67,13 @@ pub trait ConnExt {
         MaybeCached::prepare(self.conn(), sql, cache)
     }
 
-    /// Execute all the provided statements.
+    /// Execute all the provided statements. If one fails, the error includes
+    /// a truncated copy of the statement, so that we can tell which one it was.
     fn execute_all(&self, stmts: &[&str]) -> SqlResult<()> {
         let conn = self.conn();
         for sql in stmts {
-            conn.execute(sql, NO_PARAMS)?;
+            conn.execute(sql, NO_PARAMS)
+                .map_err(|e| with_sql_context(e, sql))?;
         }
         Ok(())
     }
@@ -75,10 +102,30 @@ pub trait ConnExt {
         crate::maybe_log_plan(self.conn(), sql, &[]);
         let res: T = self
             .conn()
-            .query_row_and_then(sql, NO_PARAMS, |row| row.get(0))?;
+            .query_row_and_then(sql, NO_PARAMS, |row| row.get(0))
+            .map_err(|e| with_sql_context(e, sql))?;
         Ok(res)
     }
 
+    /// Equivalent to `rusqlite::Connection::query_row_named`, but includes a
+    /// truncated copy of the statement in the error if it fails. This has a
+    /// different name so that it doesn't shadow the `Connection` method.
+    fn query_row_named_with_context<T, F>(
+        &self,
+        sql: &str,
+        params: &[(&str, &dyn ToSql)],
+        f: F,
+    ) -> SqlResult<T>
+    where
+        Self: Sized,
+        F: FnOnce(&Row<'_>) -> SqlResult<T>,
+    {
+        crate::maybe_log_plan(self.conn(), sql, params);
+        self.conn()
+            .query_row_named(sql, params, f)
+            .map_err(|e| with_sql_context(e, sql))
+    }
+
     /// Execute a query that returns 0 or 1 result columns, returning None
     /// if there were no rows, or if the only result was NULL.
     fn try_query_one<T: FromSql>(
@@ -222,9 +269,15 @@ pub trait ConnExt {
     {
         crate::maybe_log_plan(self.conn(), sql, params);
         let conn = self.conn();
-        let mut stmt = MaybeCached::prepare(conn, sql, cache)?;
-        let mut rows = stmt.query_named(params)?;
-        rows.next()?.map(mapper).transpose()
+        let mut stmt =
+            MaybeCached::prepare(conn, sql, cache).map_err(|e| with_sql_context(e, sql))?;
+        let mut rows = stmt
+            .query_named(params)
+            .map_err(|e| with_sql_context(e, sql))?;
+        rows.next()
+            .map_err(|e| with_sql_context(e, sql))?
+            .map(mapper)
+            .transpose()
     }
 
     fn unchecked_transaction(&self) -> SqlResult<UncheckedTransaction<'_>> {
@@ -370,3 +423,61 @@ where
     let iter = stmt.query_and_then_named(params, mapper)?;
     Ok(iter.collect::<Result<Coll, E>>()?)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_execute_all_error_context() {
+        let conn = Connection::open_in_memory().unwrap();
+        let err = conn
+            .execute_all(&[
+                "CREATE TABLE foo(a INTEGER)",
+                "INSERT INTO foo(a) VALUES(1)",
+                "INSERT INTO foo(a) VALUES(2",
+                "INSERT INTO foo(a) VALUES(3)",
+            ])
+            .expect_err("should fail on the syntax error");
+        match err {
+            rusqlite::Error::SqliteFailure(_, Some(msg)) => {
+                assert!(
+                    msg.ends_with("(in statement: INSERT INTO foo(a) VALUES(2)"),
+                    "Unexpected message: {}",
+                    msg
+                );
+            }
+            err => panic!("Unexpected error: {:?}", err),
+        }
+        // Statements before the failing one should still have run, and the
+        // ones after it shouldn't have.
+        assert_eq!(conn.query_one::<i64>("SELECT SUM(a) FROM foo").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_query_error_context() {
+        let conn = Connection::open_in_memory().unwrap();
+        let long_sql = format!("SELECT {} FROM", "1 + ".repeat(50));
+        for err in vec![
+            conn.query_one::<i64>(&long_sql).unwrap_err(),
+            conn.query_row_named_with_context(&long_sql, &[], |row| row.get::<_, i64>(0))
+                .unwrap_err(),
+            conn.try_query_row(&long_sql, &[], |row| row.get::<_, i64>(0), false)
+                .unwrap_err(),
+        ] {
+            match err {
+                rusqlite::Error::SqliteFailure(_, Some(msg)) => {
+                    let (_, snippet) =
+                        msg.split_at(msg.find("(in statement: ").expect("should have context"));
+                    assert!(snippet.starts_with("(in statement: SELECT 1 + 1 + "));
+                    assert!(snippet.ends_with("...)"));
+                    assert_eq!(
+                        snippet.chars().count(),
+                        "(in statement: ".len() + ERROR_SQL_SNIPPET_LENGTH + "...)".len()
+                    );
+                }
+                err => panic!("Unexpected error: {:?}", err),
+            }
+        }
+    }
+}
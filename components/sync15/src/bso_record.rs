@@ -10,7 +10,7 @@ use serde::de::{Deserialize, DeserializeOwned};
 use serde::ser::Serialize;
 use serde_derive::*;
 use serde_json::{self, Map, Value as JsonValue};
-use std::convert::From;
+use std::convert::{From, TryFrom};
 use std::ops::{Deref, DerefMut};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -166,6 +166,13 @@ impl<T> DerefMut for BsoRecord<T> {
 /// Note: If we implement a full sync client in rust we may want to consider using stronger types for each record
 /// (we did this in the past as well), but for now, since everything is just going over the FFI, there's not a lot of
 /// benefit here.
+///
+/// The `id` here is the record's ID inside the encrypted payload, and must
+/// match the `id` of the BSO that contains it. The server only knows the
+/// outer ID, so that's the one we use for lookups and deletions. Some old
+/// clients have uploaded records where the two don't match; `decrypt`
+/// replaces the inner ID with the outer one for those, and `encrypt` asserts
+/// that our own records match in debug builds.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Payload {
     pub id: String,
@@ -218,6 +225,17 @@ impl Payload {
         self.deleted
     }
 
+    /// Returns the server-side TTL, in seconds, of the BSO this payload was
+    /// decrypted from, or `None` if the record never expires. TTLs too large
+    /// for a `u32` are clamped, since they're far longer than any record
+    /// lives anyway. See `util::is_expired`.
+    pub fn ttl(&self) -> Option<u32> {
+        self.data
+            .get("ttl")
+            .and_then(JsonValue::as_u64)
+            .map(|ttl| u32::try_from(ttl).unwrap_or(u32::max_value()))
+    }
+
     pub fn into_bso(mut self, collection: String) -> CleartextBso {
         let id = self.id.clone();
         let sortindex: Option<i32> = self.take_auto_field("sortindex");
@@ -236,13 +254,20 @@ impl Payload {
         Ok(serde_json::from_value(value)?)
     }
 
+    /// Deserializes this payload into a typed record, like a login or a
+    /// bookmark. Errors include the record ID, so that engines can log which
+    /// incoming record was malformed.
     pub fn into_record<T>(self) -> error::Result<T>
     where
         for<'a> T: Deserialize<'a>,
     {
-        Ok(serde_json::from_value(JsonValue::from(self))?)
+        let id = self.id.clone();
+        serde_json::from_value(JsonValue::from(self))
+            .map_err(|e| error::ErrorKind::MalformedRecord(id, e).into())
     }
 
+    /// Serializes a typed record into a payload. The record must serialize
+    /// to a JSON object with an `id` field.
     pub fn from_record<T: Serialize>(v: T) -> error::Result<Payload> {
         // TODO: This is dumb, we do to_value and then from_value. If we end up using this
         // method a lot we should rethink... As it is it should just be for uploading
@@ -400,6 +425,16 @@ impl EncryptedPayload {
 impl EncryptedBso {
     pub fn decrypt(self, key: &KeyBundle) -> error::Result<CleartextBso> {
         let mut new_payload: Payload = self.payload.decrypt_and_parse_payload(key)?;
+        if new_payload.id != self.id {
+            // The server only knows the outer ID, so prefer it. Otherwise,
+            // we'd never be able to update or delete this record.
+            log::warn!(
+                "Record {} has a mismatched payload ID {}; using the record ID",
+                self.id,
+                new_payload.id
+            );
+            new_payload.id = self.id.clone();
+        }
         // This is a slightly dodgy place to do this, but whatever.
         new_payload.add_auto_field("sortindex", self.sortindex);
         new_payload.add_auto_field("ttl", self.ttl);
@@ -418,6 +453,7 @@ impl EncryptedBso {
 
 impl CleartextBso {
     pub fn encrypt(self, key: &KeyBundle) -> error::Result<EncryptedBso> {
+        debug_assert_eq!(self.id, self.payload.id, "BSO and payload IDs must match");
         let encrypted_payload = EncryptedPayload::from_cleartext_payload(key, &self.payload)?;
         Ok(self.with_payload(encrypted_payload))
     }
@@ -582,5 +618,89 @@ mod tests {
 
         assert_eq!(decrypted.sortindex, Some(100));
         assert_eq!(decrypted.ttl, Some(99));
+        assert_eq!(decrypted.payload.ttl(), Some(99));
+    }
+
+    #[test]
+    fn test_decrypt_ttl() {
+        let keybundle = KeyBundle::new_random().unwrap();
+        let payload = json!({ "id": "aaaaaaaaaaaa", "name": "Phone" });
+        let encrypted = EncryptedPayload::from_cleartext_payload(&keybundle, &payload).unwrap();
+        let serialized = json!({
+            "id": "aaaaaaaaaaaa",
+            "collection": "clients",
+            "modified": 1000.5,
+            "ttl": 1_814_400,
+            "payload": serde_json::to_string(&encrypted).unwrap(),
+        });
+        let record: EncryptedBso = serde_json::from_value(serialized).unwrap();
+        assert_eq!(record.ttl, Some(1_814_400));
+
+        let (payload, modified) = record
+            .decrypt(&keybundle)
+            .unwrap()
+            .into_timestamped_payload();
+        assert_eq!(payload.ttl(), Some(1_814_400));
+        assert_eq!(modified, ServerTimestamp(1000.5));
+
+        let without_ttl = Payload::from_json(json!({ "id": "bbbbbbbbbbbb" })).unwrap();
+        assert_eq!(without_ttl.ttl(), None);
+
+        let huge_ttl =
+            Payload::from_json(json!({ "id": "cccccccccccc", "ttl": 1u64 << 40 })).unwrap();
+        assert_eq!(huge_ttl.ttl(), Some(u32::max_value()));
+    }
+
+    #[test]
+    fn test_decrypt_mismatched_id() {
+        let keybundle = KeyBundle::new_random().unwrap();
+        // Seen in the wild: the payload ID doesn't match the BSO ID.
+        let payload = json!({ "id": "bbbbbbbbbbbb", "name": "Phone" });
+        let encrypted = EncryptedPayload::from_cleartext_payload(&keybundle, &payload).unwrap();
+        let serialized = json!({
+            "id": "aaaaaaaaaaaa",
+            "collection": "clients",
+            "modified": 1000.5,
+            "payload": serde_json::to_string(&encrypted).unwrap(),
+        });
+        let record: EncryptedBso = serde_json::from_value(serialized).unwrap();
+        let decrypted = record.decrypt(&keybundle).unwrap();
+        assert_eq!(decrypted.id, "aaaaaaaaaaaa");
+        assert_eq!(decrypted.payload.id(), "aaaaaaaaaaaa");
+        assert_eq!(decrypted.payload.data["name"], "Phone");
+
+        // Re-encrypting the repaired record should round-trip.
+        let reencrypted = decrypted.clone().encrypt(&keybundle).unwrap();
+        assert_eq!(reencrypted.decrypt(&keybundle).unwrap(), decrypted);
+    }
+
+    #[test]
+    fn test_record_helpers() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Client {
+            id: String,
+            name: String,
+        }
+
+        let client = Client {
+            id: "aaaaaaaaaaaa".into(),
+            name: "Phone".into(),
+        };
+        let payload = Payload::from_record(&client).unwrap();
+        assert_eq!(payload.id(), "aaaaaaaaaaaa");
+        assert!(!payload.is_tombstone());
+        assert_eq!(payload.into_record::<Client>().unwrap(), client);
+
+        let tombstone = Payload::new_tombstone_with_ttl("bbbbbbbbbbbb".into(), 60);
+        assert_eq!(tombstone.id(), "bbbbbbbbbbbb");
+        assert!(tombstone.is_tombstone());
+        assert_eq!(tombstone.ttl(), Some(60));
+
+        // Errors should say which record was malformed.
+        let malformed = Payload::from_json(json!({ "id": "cccccccccccc", "name": 1 })).unwrap();
+        match malformed.into_record::<Client>().unwrap_err().kind() {
+            error::ErrorKind::MalformedRecord(id, _) => assert_eq!(id, "cccccccccccc"),
+            kind => panic!("Wrong error kind: {:?}", kind),
+        }
     }
 }
@@ -7,7 +7,7 @@ use crate::client::{Sync15ClientResponse, Sync15StorageClient};
 use crate::error::{self, ErrorKind, Result};
 use crate::key_bundle::KeyBundle;
 use crate::request::{CollectionRequest, NormalResponseHandler, UploadInfo};
-use crate::util::ServerTimestamp;
+use crate::util::{is_expired, ServerTimestamp};
 use crate::CollState;
 
 #[derive(Debug, Clone)]
@@ -18,6 +18,15 @@ pub struct RecordChangeset<Payload> {
     /// For POSTs, this is the XIUS timestamp.
     pub timestamp: ServerTimestamp,
     pub collection: String,
+    /// For POSTs, true if the store has more outgoing changes than it included
+    /// in this changeset, and wants to sync again soon to upload the rest.
+    /// Always false for GETs.
+    pub overflow: bool,
+    /// For GETs, the server's clock when it returned the records, if it told
+    /// us. Unlike `timestamp`, which is when the collection last changed,
+    /// stores can compare this to their own clock to estimate skew. Always
+    /// `None` for POSTs.
+    pub server_time: Option<ServerTimestamp>,
 }
 
 pub type IncomingChangeset = RecordChangeset<(Payload, ServerTimestamp)>;
@@ -31,6 +40,8 @@ impl<T> RecordChangeset<T> {
             changes: vec![],
             timestamp,
             collection,
+            overflow: false,
+            server_time: None,
         }
     }
 }
@@ -76,6 +87,7 @@ impl IncomingChangeset {
         // xxx - duplication below of `timestamp` smells wrong
         state.last_modified = timestamp;
         let mut result = IncomingChangeset::new(collection, timestamp);
+        result.server_time = client.last_server_time();
         result.changes.reserve(records.len());
         for record in records {
             // if we see a HMAC error, we've made an explicit decision to
@@ -88,6 +100,20 @@ impl IncomingChangeset {
         }
         Ok(result)
     }
+
+    /// Removes records with a TTL that have expired by `now`, and returns the
+    /// number of records removed. The server won't return records that have
+    /// already expired, but stores that keep incoming records around, like
+    /// the clients collection, should call this before using them.
+    pub fn remove_expired(&mut self, now: ServerTimestamp) -> usize {
+        let count = self.changes.len();
+        self.changes
+            .retain(|(payload, modified)| match payload.ttl() {
+                Some(ttl) => !is_expired(ttl, *modified, now),
+                None => true,
+            });
+        count - self.changes.len()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -173,3 +199,59 @@ impl<'a> CollectionUpdate<'a> {
         Ok(info)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bso_record::EncryptedPayload;
+    use serde_json::json;
+
+    #[test]
+    fn test_remove_expired() {
+        let keybundle = KeyBundle::new_random().unwrap();
+        let bsos = json!([{
+            "id": "expiredAAAAA",
+            "collection": "clients",
+            "modified": 1000.0,
+            "ttl": 60,
+            "name": "Old phone",
+        }, {
+            "id": "currentBBBBB",
+            "collection": "clients",
+            "modified": 1050.0,
+            "ttl": 60,
+            "name": "New phone",
+        }, {
+            "id": "foreverCCCCC",
+            "collection": "clients",
+            "modified": 1.0,
+            "name": "Laptop",
+        }]);
+
+        let mut incoming = IncomingChangeset::new("clients".into(), ServerTimestamp(1100.0));
+        for bso in bsos.as_array().unwrap() {
+            let cleartext = json!({ "id": bso["id"], "name": bso["name"] });
+            let mut record = bso.clone();
+            record["payload"] = json!(serde_json::to_string(
+                &EncryptedPayload::from_cleartext_payload(&keybundle, &cleartext).unwrap()
+            )
+            .unwrap());
+            let record: EncryptedBso = serde_json::from_value(record).unwrap();
+            incoming.changes.push(
+                record
+                    .decrypt(&keybundle)
+                    .unwrap()
+                    .into_timestamped_payload(),
+            );
+        }
+
+        assert_eq!(incoming.remove_expired(ServerTimestamp(1100.0)), 1);
+        let ids = incoming
+            .changes
+            .iter()
+            .map(|(payload, _)| payload.id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["currentBBBBB", "foreverCCCCC"]);
+        assert_eq!(incoming.remove_expired(ServerTimestamp(1100.0)), 0);
+    }
+}
@@ -11,7 +11,10 @@ use crate::request::{
 };
 use crate::token;
 use crate::util::ServerTimestamp;
+use std::cell::Cell;
+use std::cmp;
 use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 use viaduct::{
     header_names::{self, AUTHORIZATION},
@@ -72,6 +75,59 @@ impl<T> Sync15ClientResponse<T> {
     }
 }
 
+/// Scheduling hints sent by the server in response headers. These are
+/// accumulated over every request made during a sync, and used to recommend
+/// when the next sync should happen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerScheduleHints {
+    /// The longest backoff requested via the `X-Weave-Backoff`, `X-Backoff`
+    /// or `Retry-After` headers. Servers may send these on successful
+    /// responses as a form of soft throttling.
+    pub backoff: Option<Duration>,
+    /// True if the server told us we're over our storage quota, either with
+    /// a 507 status or a non-positive `X-Weave-Quota-Remaining` header.
+    pub over_quota: bool,
+}
+
+impl ServerScheduleHints {
+    pub fn from_response(resp: &Response) -> ServerScheduleHints {
+        let backoff = [
+            header_names::X_WEAVE_BACKOFF,
+            header_names::X_BACKOFF,
+            header_names::RETRY_AFTER,
+        ]
+        .iter()
+        // `Retry-After` may also be an HTTP date, which we ignore; the
+        // other headers are always a number of seconds.
+        .filter_map(|name| resp.headers.try_get::<u64, _>(name.clone()))
+        .map(Duration::from_secs)
+        .max();
+        let quota_exhausted = resp
+            .headers
+            .try_get::<f64, _>(header_names::X_WEAVE_QUOTA_REMAINING)
+            .map_or(false, |remaining| remaining <= 0.0);
+        ServerScheduleHints {
+            backoff,
+            over_quota: resp.status == 507 || quota_exhausted,
+        }
+    }
+
+    /// Combines two sets of hints, keeping the longest backoff.
+    pub fn merge(self, other: ServerScheduleHints) -> ServerScheduleHints {
+        ServerScheduleHints {
+            backoff: cmp::max(self.backoff, other.backoff),
+            over_quota: self.over_quota || other.over_quota,
+        }
+    }
+}
+
+/// Returns the server's clock from the `X-Weave-Timestamp` header, if the
+/// response has one.
+fn server_time_from_response(resp: &Response) -> Option<ServerTimestamp> {
+    resp.headers
+        .try_get::<ServerTimestamp, _>(header_names::X_WEAVE_TIMESTAMP)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Sync15StorageClientInit {
     pub key_id: String,
@@ -100,6 +156,8 @@ pub trait SetupStorageClient {
 #[derive(Debug)]
 pub struct Sync15StorageClient {
     tsc: token::TokenProvider,
+    schedule_hints: Cell<ServerScheduleHints>,
+    server_time: Cell<Option<ServerTimestamp>>,
 }
 
 impl SetupStorageClient for Sync15StorageClient {
@@ -175,7 +233,24 @@ impl Sync15StorageClient {
             init_params.access_token,
             init_params.key_id,
         )?;
-        Ok(Sync15StorageClient { tsc })
+        Ok(Sync15StorageClient {
+            tsc,
+            schedule_hints: Cell::default(),
+            server_time: Cell::default(),
+        })
+    }
+
+    /// Returns the scheduling hints accumulated since the last call, and
+    /// resets them.
+    pub fn take_schedule_hints(&self) -> ServerScheduleHints {
+        self.schedule_hints.replace(ServerScheduleHints::default())
+    }
+
+    /// Returns the server's clock when it sent the last response, from the
+    /// `X-Weave-Timestamp` header, or `None` if the response didn't have
+    /// one.
+    pub fn last_server_time(&self) -> Option<ServerTimestamp> {
+        self.server_time.get()
     }
 
     pub fn get_encrypted_records(
@@ -249,6 +324,15 @@ impl Sync15StorageClient {
         let resp = req.send()?;
         log::trace!("response: {}", resp.status);
 
+        self.server_time.set(server_time_from_response(&resp));
+
+        let hints = ServerScheduleHints::from_response(&resp);
+        if hints != ServerScheduleHints::default() {
+            log::info!("Server sent scheduling hints: {:?}", hints);
+            self.schedule_hints
+                .set(self.schedule_hints.get().merge(hints));
+        }
+
         if require_success && !resp.is_success() {
             log::warn!(
                 "HTTP error {} during storage request to {}",
@@ -262,11 +346,6 @@ impl Sync15StorageClient {
             .into());
         }
 
-        // TODO:
-        // - handle backoff
-        // - x-weave-quota?
-        // - ... almost certainly other things too...
-
         Ok(resp)
     }
 
@@ -357,4 +436,87 @@ mod test {
         // Compile will fail if not send.
         ensure_send::<Sync15StorageClient>();
     }
+
+    fn response(status: u16, headers: &[(&'static str, &str)]) -> Response {
+        let mut resp = Response {
+            request_method: Method::Get,
+            url: Url::parse("https://example.com/1.5/123/info/collections").unwrap(),
+            status,
+            headers: viaduct::Headers::new(),
+            body: vec![],
+        };
+        for (name, value) in headers {
+            resp.headers.insert(*name, *value).unwrap();
+        }
+        resp
+    }
+
+    #[test]
+    fn test_schedule_hints() {
+        assert_eq!(
+            ServerScheduleHints::from_response(&response(200, &[])),
+            ServerScheduleHints::default()
+        );
+        assert_eq!(
+            ServerScheduleHints::from_response(&response(
+                200,
+                &[("x-weave-backoff", "300"), ("x-backoff", "60")]
+            )),
+            ServerScheduleHints {
+                backoff: Some(Duration::from_secs(300)),
+                over_quota: false,
+            }
+        );
+        assert_eq!(
+            ServerScheduleHints::from_response(&response(
+                503,
+                &[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")]
+            )),
+            ServerScheduleHints::default()
+        );
+        assert_eq!(
+            ServerScheduleHints::from_response(&response(
+                200,
+                &[("x-weave-quota-remaining", "0.0")]
+            )),
+            ServerScheduleHints {
+                backoff: None,
+                over_quota: true,
+            }
+        );
+        assert_eq!(
+            ServerScheduleHints::from_response(&response(
+                200,
+                &[("x-weave-quota-remaining", "1024.5")]
+            )),
+            ServerScheduleHints::default()
+        );
+        assert!(ServerScheduleHints::from_response(&response(507, &[])).over_quota);
+
+        let merged = ServerScheduleHints {
+            backoff: Some(Duration::from_secs(10)),
+            over_quota: true,
+        }
+        .merge(ServerScheduleHints {
+            backoff: Some(Duration::from_secs(20)),
+            over_quota: false,
+        });
+        assert_eq!(merged.backoff, Some(Duration::from_secs(20)));
+        assert!(merged.over_quota);
+    }
+
+    #[test]
+    fn test_server_time_from_response() {
+        assert_eq!(server_time_from_response(&response(200, &[])), None);
+        assert_eq!(
+            server_time_from_response(&response(
+                200,
+                &[
+                    ("x-weave-timestamp", "1583000000.25"),
+                    ("x-last-modified", "1582000000.00"),
+                ]
+            )),
+            Some(ServerTimestamp(1_583_000_000.25))
+        );
+    }
 }
@@ -44,6 +44,40 @@ impl Error {
             _ => false,
         }
     }
+
+    /// Returns a short, stable name for the kind of error. Unlike the
+    /// `Display` output, this never includes URLs, server responses, or
+    /// other details, so it's safe to persist.
+    pub fn class(&self) -> &'static str {
+        match self.kind() {
+            ErrorKind::BadKeyLength(..) | ErrorKind::HmacMismatch => "keys",
+            ErrorKind::TokenserverError(err) if err.is_auth_error() => "auth",
+            ErrorKind::TokenserverHttpError(_) | ErrorKind::TokenserverError(_) => "tokenserver",
+            ErrorKind::StorageHttpError { .. } | ErrorKind::UnexpectedStatus(_) => "http",
+            ErrorKind::BackoffError(_) => "backoff",
+            ErrorKind::RecordTooLargeError
+            | ErrorKind::BatchInterrupted
+            | ErrorKind::RecordUploadFailed
+            | ErrorKind::ServerBatchProblem(_) => "upload",
+            ErrorKind::StorageResetError => "storage_reset",
+            ErrorKind::SetupRace | ErrorKind::ClientUpgradeRequired | ErrorKind::SetupRequired => {
+                "setup"
+            }
+            ErrorKind::StoreError(_) => "store",
+            ErrorKind::CryptoError(_) | ErrorKind::OpensslError(_) | ErrorKind::HawkError(_) => {
+                "crypto"
+            }
+            ErrorKind::Base64Decode(_)
+            | ErrorKind::JsonError(_)
+            | ErrorKind::MalformedRecord(..)
+            | ErrorKind::BadCleartextUtf8(_) => "invalid_record",
+            ErrorKind::RequestError(_) => "network",
+            ErrorKind::UnacceptableUrl(_)
+            | ErrorKind::MissingServerTimestamp
+            | ErrorKind::MalformedUrl(_) => "server",
+            ErrorKind::Interrupted(_) => "interrupted",
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -74,6 +108,9 @@ pub enum ErrorKind {
     )]
     TokenserverHttpError(u16),
 
+    #[fail(display = "Tokenserver error: {}", _0)]
+    TokenserverError(TokenserverError),
+
     #[fail(
         display = "HTTP status {} during a storage request to \"{}\"",
         code, route
@@ -137,6 +174,9 @@ pub enum ErrorKind {
     #[fail(display = "JSON error: {}", _0)]
     JsonError(#[fail(cause)] serde_json::Error),
 
+    #[fail(display = "Record {} has an unexpected format: {}", _0, _1)]
+    MalformedRecord(String, #[fail(cause)] serde_json::Error),
+
     #[fail(display = "Bad cleartext UTF8: {}", _0)]
     BadCleartextUtf8(#[fail(cause)] string::FromUtf8Error),
 
@@ -156,6 +196,58 @@ pub enum ErrorKind {
     Interrupted(#[fail(cause)] Interrupted),
 }
 
+/// An error returned by the tokenserver, parsed from the `status` in the
+/// response body.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum TokenserverError {
+    /// The OAuth token or key ID was rejected. The user needs to sign in
+    /// again.
+    #[fail(display = "Invalid credentials")]
+    InvalidCredentials,
+
+    /// The key ID doesn't match the tokenserver's record for this user,
+    /// usually because they reset their password. The caller needs to fetch
+    /// kB again, and engines need to reset.
+    #[fail(display = "Invalid client state; the account's keys changed")]
+    InvalidClientState,
+
+    /// The key ID is older than one the tokenserver has already seen for
+    /// this user.
+    #[fail(display = "Invalid key generation")]
+    InvalidGeneration,
+
+    /// Our clock is too far from the tokenserver's.
+    #[fail(display = "Invalid timestamp")]
+    InvalidTimestamp,
+
+    /// The tokenserver isn't accepting new users.
+    #[fail(display = "New users are disabled")]
+    NewUsersDisabled,
+
+    /// The tokenserver is down for maintenance, and didn't tell us when to
+    /// retry. Responses with a `Retry-After` header are reported as a
+    /// `BackoffError` instead.
+    #[fail(display = "Service unavailable")]
+    Unavailable,
+
+    #[fail(display = "HTTP status {} with status \"{}\"", code, status)]
+    Other { code: u16, status: String },
+}
+
+impl TokenserverError {
+    /// Returns true if the user needs to sign in again, or the caller needs
+    /// to fetch new keys, before we can get a token.
+    pub fn is_auth_error(&self) -> bool {
+        match self {
+            TokenserverError::InvalidCredentials
+            | TokenserverError::InvalidClientState
+            | TokenserverError::InvalidGeneration => true,
+            TokenserverError::Other { code, .. } => *code == 401,
+            _ => false,
+        }
+    }
+}
+
 macro_rules! impl_from_error {
     ($(($variant:ident, $type:ty)),+) => ($(
         impl From<$type> for ErrorKind {
@@ -8,12 +8,13 @@ use rc_crypto::{
     digest,
     hmac::{self, Signature, SigningKey, VerificationKey},
     rand,
+    sensitive::{self, SensitiveBytes},
 };
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct KeyBundle {
-    enc_key: Vec<u8>,
-    mac_key: Vec<u8>,
+    enc_key: SensitiveBytes,
+    mac_key: SensitiveBytes,
 }
 
 impl KeyBundle {
@@ -29,15 +30,17 @@ impl KeyBundle {
             return Err(ErrorKind::BadKeyLength("mac_key", mac.len(), 32).into());
         }
         Ok(KeyBundle {
-            enc_key: enc,
-            mac_key: mac,
+            enc_key: enc.into(),
+            mac_key: mac.into(),
         })
     }
 
     pub fn new_random() -> Result<KeyBundle> {
         let mut buffer = [0u8; 64];
         rand::fill(&mut buffer)?;
-        KeyBundle::from_ksync_bytes(&buffer)
+        let result = KeyBundle::from_ksync_bytes(&buffer);
+        sensitive::zeroize(&mut buffer);
+        result
     }
 
     pub fn from_ksync_bytes(ksync: &[u8]) -> Result<KeyBundle> {
@@ -178,6 +181,14 @@ mod test {
         "LyIsInZpc2l0cyI6W3siZGF0ZSI6MTMxOTE0OTAxMjM3MjQyNSwidHlwZSI6MX1dfQ==",
     ];
 
+    #[test]
+    fn test_debug_redacted() {
+        let key_bundle = KeyBundle::from_base64(ENC_KEY_B64, HMAC_KEY_B64).unwrap();
+        let debug = format!("{:?}", key_bundle);
+        assert!(debug.contains(rc_crypto::sensitive::REDACTED));
+        assert!(!debug.contains("244"), "{}", debug); // 0xF4, first enc_key byte.
+    }
+
     #[test]
     fn test_hmac() {
         let key_bundle = KeyBundle::from_base64(ENC_KEY_B64, HMAC_KEY_B64).unwrap();
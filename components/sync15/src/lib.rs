@@ -25,13 +25,17 @@ mod util;
 // Re-export some of the types callers are likely to want for convenience.
 pub use crate::bso_record::{BsoRecord, CleartextBso, EncryptedBso, EncryptedPayload, Payload};
 pub use crate::changeset::{IncomingChangeset, OutgoingChangeset, RecordChangeset};
-pub use crate::client::{SetupStorageClient, Sync15StorageClient, Sync15StorageClientInit};
+pub use crate::client::{
+    ServerScheduleHints, SetupStorageClient, Sync15StorageClient, Sync15StorageClientInit,
+};
 pub use crate::coll_state::{CollState, CollSyncIds, StoreSyncAssociation};
-pub use crate::error::{Error, ErrorKind, Result};
+pub use crate::error::{Error, ErrorKind, Result, TokenserverError};
 pub use crate::key_bundle::KeyBundle;
 pub use crate::migrate_state::extract_v1_state;
-pub use crate::request::CollectionRequest;
+pub use crate::request::{CollectionRequest, RequestOrder};
 pub use crate::state::{GlobalState, SetupStateMachine};
-pub use crate::sync::{synchronize, Store};
-pub use crate::sync_multiple::{sync_multiple, MemoryCachedState};
-pub use crate::util::{random_guid, ServerTimestamp, SERVER_EPOCH};
+pub use crate::sync::{synchronize, CollectionSyncOutcome, Store};
+pub use crate::sync_multiple::{
+    sync_multiple, MemoryCachedState, SyncResult, SyncScheduleInputs, DEFAULT_BASE_SYNC_INTERVAL,
+};
+pub use crate::util::{is_expired, random_guid, ServerTimestamp, SERVER_EPOCH};
@@ -21,9 +21,36 @@ pub struct MetaGlobalRecord {
     #[serde(rename = "storageVersion")]
     pub storage_version: usize,
     pub engines: HashMap<String, MetaGlobalEngine>,
+    // Very old clients didn't write `declined`.
+    #[serde(default)]
     pub declined: Vec<String>,
 }
 
+impl MetaGlobalRecord {
+    /// Returns false if another client wrote a newer version of the engine's
+    /// storage format than `ours`, in which case we must not sync that engine.
+    /// Engines that aren't in `meta/global` at all are considered supported.
+    pub fn engine_version_supported(&self, name: &str, ours: usize) -> bool {
+        match self.engines.get(name) {
+            Some(engine) => engine.version <= ours,
+            None => true,
+        }
+    }
+
+    /// Updates the sync ID for an engine, after it resets. The record still
+    /// needs to be uploaded with `SetupStorageClient::put_meta_global`.
+    /// Returns false if the engine isn't in `meta/global`.
+    pub fn set_engine_sync_id(&mut self, name: &str, sync_id: String) -> bool {
+        match self.engines.get_mut(name) {
+            Some(engine) => {
+                engine.sync_id = sync_id;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
 pub struct CryptoKeysRecord {
     pub id: String,
@@ -31,3 +58,72 @@ pub struct CryptoKeysRecord {
     pub default: [String; 2],
     pub collections: HashMap<String, [String; 2]>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A `meta/global` record written by Desktop, with a bookmarks engine
+    // from the future.
+    fn desktop_meta_global() -> serde_json::Value {
+        json!({
+            "syncID": "yvyBmHCyOkrw",
+            "storageVersion": 5,
+            "engines": {
+                "clients": {"version": 1, "syncID": "uQgyiGdQWrxu"},
+                "bookmarks": {"version": 3, "syncID": "SUjmEtsSGrS8"},
+                "history": {"version": 1, "syncID": "UYusp6Jt9Zfu"},
+                "passwords": {"version": 1, "syncID": "uPFYzM3s2bZ2"},
+                "tabs": {"version": 1, "syncID": "XsRi7EGhvNoq"},
+            },
+            "declined": ["addons", "prefs"],
+        })
+    }
+
+    #[test]
+    fn test_meta_global_engines() {
+        let mut global: MetaGlobalRecord =
+            serde_json::from_value(desktop_meta_global()).expect("Should parse meta/global");
+        assert_eq!(global.storage_version, 5);
+        assert_eq!(global.engines["history"].sync_id, "UYusp6Jt9Zfu");
+        assert_eq!(global.declined, vec!["addons", "prefs"]);
+
+        assert!(global.engine_version_supported("history", 1));
+        assert!(!global.engine_version_supported("bookmarks", 2));
+        assert!(global.engine_version_supported("bookmarks", 3));
+        assert!(global.engine_version_supported("addresses", 1));
+
+        assert!(global.set_engine_sync_id("history", "newHistoryID".into()));
+        assert!(!global.set_engine_sync_id("addresses", "newAddressID".into()));
+        let json = serde_json::to_value(&global).expect("Should serialize meta/global");
+        assert_eq!(
+            json["engines"]["history"],
+            json!({"version": 1, "syncID": "newHistoryID"})
+        );
+        assert!(json["engines"].get("addresses").is_none());
+    }
+
+    #[test]
+    fn test_meta_global_validation() {
+        // Missing `declined` is OK...
+        let mut value = desktop_meta_global();
+        value.as_object_mut().unwrap().remove("declined");
+        let global: MetaGlobalRecord =
+            serde_json::from_value(value).expect("Should parse without `declined`");
+        assert!(global.declined.is_empty());
+
+        // ...But engines must have a version and a sync ID.
+        let mut value = desktop_meta_global();
+        value["engines"]["tabs"] = json!({"version": 1});
+        assert!(serde_json::from_value::<MetaGlobalRecord>(value).is_err());
+
+        let mut value = desktop_meta_global();
+        value["engines"]["tabs"] = json!({"syncID": "XsRi7EGhvNoq"});
+        assert!(serde_json::from_value::<MetaGlobalRecord>(value).is_err());
+
+        let mut value = desktop_meta_global();
+        value.as_object_mut().unwrap().remove("syncID");
+        assert!(serde_json::from_value::<MetaGlobalRecord>(value).is_err());
+    }
+}
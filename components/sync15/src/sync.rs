@@ -49,6 +49,17 @@ pub trait Store {
     fn wipe(&self) -> Result<(), failure::Error>;
 }
 
+/// What happened during a successful sync of a single collection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CollectionSyncOutcome {
+    /// The number of records downloaded.
+    pub incoming: usize,
+    /// The number of records uploaded.
+    pub outgoing: usize,
+    /// True if the store has more records to upload than it staged.
+    pub overflow: bool,
+}
+
 pub fn synchronize(
     client: &Sync15StorageClient,
     global_state: &GlobalState,
@@ -56,7 +67,7 @@ pub fn synchronize(
     fully_atomic: bool,
     telem_engine: &mut telemetry::Engine,
     interruptee: &impl Interruptee,
-) -> Result<(), Error> {
+) -> Result<CollectionSyncOutcome, Error> {
     let collection = store.collection_name();
     log::info!("Syncing collection {}", collection);
 
@@ -69,7 +80,7 @@ pub fn synchronize(
                 "can't setup for the {} collection - hopefully it works later",
                 collection
             );
-            return Ok(());
+            return Ok(CollectionSyncOutcome::default());
         }
     };
 
@@ -88,6 +99,7 @@ pub fn synchronize(
         incoming_changes.changes.len()
     );
     let new_timestamp = incoming_changes.timestamp;
+    let num_incoming = incoming_changes.changes.len();
     let mut telem_incoming = telemetry::EngineIncoming::new();
     let mut outgoing = store.apply_incoming(incoming_changes, &mut telem_incoming)?;
     telem_engine.incoming(telem_incoming);
@@ -98,6 +110,7 @@ pub fn synchronize(
     coll_state.last_modified = new_timestamp;
 
     log::info!("Uploading {} outgoing changes", outgoing.changes.len());
+    let overflow = outgoing.overflow;
     let upload_info =
         CollectionUpdate::new_from_changeset(client, &coll_state, outgoing, fully_atomic)?
             .upload()?;
@@ -114,8 +127,13 @@ pub fn synchronize(
     telem_outgoing.failed(upload_info.failed_ids.len());
     telem_engine.outgoing(telem_outgoing);
 
+    let num_outgoing = upload_info.successful_ids.len();
     store.sync_finished(upload_info.modified_timestamp, upload_info.successful_ids)?;
 
     log::info!("Sync finished!");
-    Ok(())
+    Ok(CollectionSyncOutcome {
+        incoming: num_incoming,
+        outgoing: num_outgoing,
+        overflow,
+    })
 }
@@ -5,16 +5,112 @@
 // This helps you perform a sync of multiple stores and helps you manage
 // global and local state between syncs.
 
-use crate::client::{Sync15StorageClient, Sync15StorageClientInit};
+use crate::client::{ServerScheduleHints, Sync15StorageClient, Sync15StorageClientInit};
 use crate::error::Error;
 use crate::key_bundle::KeyBundle;
 use crate::state::{GlobalState, PersistedGlobalState, SetupStateMachine};
 use crate::sync::{self, Store};
 use crate::telemetry;
 use interrupt::Interruptee;
+use std::cmp;
 use std::collections::HashMap;
 use std::mem;
 use std::result;
+use std::time::Duration;
+
+/// The default interval between syncs, used as the basis for the
+/// `next_sync_after` recommendation in `SyncResult`.
+pub const DEFAULT_BASE_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How soon to sync again if a store has more records to upload.
+const MORE_TO_UPLOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything that affects when we should sync next.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyncScheduleInputs {
+    /// Hints from server response headers.
+    pub server: ServerScheduleHints,
+    /// True if any store has more records to upload than it staged.
+    pub more_to_upload: bool,
+    /// True if every store synced successfully, without downloading or
+    /// uploading any records. A store that failed to sync isn't a no-op.
+    pub no_op: bool,
+}
+
+impl SyncScheduleInputs {
+    /// Updates the inputs with the result of syncing a single store.
+    fn record_store_result(&mut self, result: &result::Result<sync::CollectionSyncOutcome, Error>) {
+        match result {
+            Ok(outcome) => {
+                self.more_to_upload |= outcome.overflow;
+                self.no_op &= outcome.incoming == 0 && outcome.outgoing == 0;
+            }
+            Err(_) => self.no_op = false,
+        }
+    }
+
+    /// Recommends how long to wait before the next sync, or `None` if the
+    /// scheduler should use `base_interval` as usual. In order of priority:
+    ///
+    /// * If we're over quota, uploading more won't help, so wait 4x the base
+    ///   interval.
+    /// * If a store has more to upload, sync again in a minute (or the base
+    ///   interval, if that's shorter).
+    /// * If nothing happened, back off to 2x the base interval.
+    ///
+    /// In all cases, we never recommend syncing before a server-requested
+    /// backoff expires.
+    pub fn next_sync_after(&self, base_interval: Duration) -> Option<Duration> {
+        let recommended = if self.server.over_quota {
+            Some(base_interval * 4)
+        } else if self.more_to_upload {
+            Some(cmp::min(MORE_TO_UPLOAD_INTERVAL, base_interval))
+        } else if self.no_op {
+            Some(base_interval * 2)
+        } else {
+            None
+        };
+        match (recommended, self.server.backoff) {
+            (Some(recommended), Some(backoff)) => Some(cmp::max(recommended, backoff)),
+            (Some(recommended), None) => Some(recommended),
+            (None, Some(backoff)) => Some(cmp::max(base_interval, backoff)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The result of a (possibly partially) successful `sync_multiple`.
+#[derive(Debug, Default)]
+pub struct SyncResult {
+    /// Errors for stores that failed to sync, keyed by collection name. The
+    /// absence of a name in the map implies the store succeeded.
+    pub failures: HashMap<String, Error>,
+    /// Outcomes for stores that synced successfully, keyed by collection
+    /// name.
+    pub successes: HashMap<String, sync::CollectionSyncOutcome>,
+    /// The inputs used to compute `next_sync_after`. Embedders with a base
+    /// interval other than `DEFAULT_BASE_SYNC_INTERVAL` can use these to
+    /// compute their own recommendation.
+    pub schedule_inputs: SyncScheduleInputs,
+    /// The recommended time to wait before the next sync, based on
+    /// `DEFAULT_BASE_SYNC_INTERVAL`.
+    pub next_sync_after: Option<Duration>,
+}
+
+impl SyncResult {
+    /// For callers that only sync one store: returns the error if the store
+    /// named `collection` failed to sync, or the recommended time to wait
+    /// before the next sync if it succeeded.
+    pub fn into_single_store_result(
+        mut self,
+        collection: &str,
+    ) -> result::Result<Option<Duration>, Error> {
+        match self.failures.remove(collection) {
+            Some(err) => Err(err),
+            None => Ok(self.next_sync_after),
+        }
+    }
+}
 
 /// Info about the client to use. We reuse the client unless
 /// we discover the client_init has changed, in which case we re-create one.
@@ -48,10 +144,10 @@ pub struct MemoryCachedState {
 ///   configured.
 /// * `root_sync_key` - The KeyBundle used for encryption.
 ///
-/// Returns a map, keyed by name and holding an error value - if any store
-/// fails, the sync will continue on to other stores, but the error will be
-/// places in this map. The absence of a name in the map implies the store
-/// succeeded.
+/// Returns a `SyncResult` with a map of failures, keyed by name and holding an
+/// error value - if any store fails, the sync will continue on to other
+/// stores, but the error will be placed in this map - and a recommendation
+/// for when to sync next.
 pub fn sync_multiple(
     stores: &[&dyn Store],
     persisted_global_state: &mut Option<String>,
@@ -60,7 +156,7 @@ pub fn sync_multiple(
     root_sync_key: &KeyBundle,
     sync_ping: &mut telemetry::SyncTelemetryPing,
     interruptee: &impl Interruptee,
-) -> result::Result<HashMap<String, Error>, Error> {
+) -> result::Result<SyncResult, Error> {
     interruptee.err_if_interrupted()?;
     let mut pgs = match persisted_global_state {
         Some(persisted_string) => {
@@ -127,6 +223,11 @@ pub fn sync_multiple(
 
     let mut telem_sync = telemetry::SyncTelemetry::new();
     let mut failures: HashMap<String, Error> = HashMap::new();
+    let mut successes: HashMap<String, sync::CollectionSyncOutcome> = HashMap::new();
+    let mut schedule_inputs = SyncScheduleInputs {
+        no_op: true,
+        ..SyncScheduleInputs::default()
+    };
     for store in stores {
         let name = store.collection_name();
         log::info!("Syncing {} engine!", name);
@@ -140,9 +241,13 @@ pub fn sync_multiple(
             &mut telem_engine,
             interruptee,
         );
+        schedule_inputs.record_store_result(&result);
 
         match result {
-            Ok(()) => log::info!("Sync of {} was successful!", name),
+            Ok(outcome) => {
+                log::info!("Sync of {} was successful!", name);
+                successes.insert(name.into(), outcome);
+            }
             Err(e) => {
                 // XXX - while we arrange to reset the global state machine
                 // here via, ideally we'd be more fine-grained
@@ -160,11 +265,158 @@ pub fn sync_multiple(
     }
 
     sync_ping.sync(telem_sync);
+    schedule_inputs.server = client_info.client.take_schedule_hints();
     if !failures.is_empty() {
         log::info!("Updating persisted global state");
         mem_cached_state.last_client_info = Some(client_info);
         mem_cached_state.last_global_state = Some(global_state);
     }
 
-    Ok(failures)
+    let next_sync_after = schedule_inputs.next_sync_after(DEFAULT_BASE_SYNC_INTERVAL);
+    log::info!("Recommending next sync after {:?}", next_sync_after);
+    Ok(SyncResult {
+        failures,
+        successes,
+        schedule_inputs,
+        next_sync_after,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    const BASE: Duration = Duration::from_secs(600);
+
+    fn inputs(
+        backoff: Option<u64>,
+        over_quota: bool,
+        more_to_upload: bool,
+        no_op: bool,
+    ) -> SyncScheduleInputs {
+        SyncScheduleInputs {
+            server: ServerScheduleHints {
+                backoff: backoff.map(Duration::from_secs),
+                over_quota,
+            },
+            more_to_upload,
+            no_op,
+        }
+    }
+
+    #[test]
+    fn test_next_sync_after() {
+        // Work happened, nothing special: no recommendation.
+        assert_eq!(
+            inputs(None, false, false, false).next_sync_after(BASE),
+            None
+        );
+        // No-op syncs back off.
+        assert_eq!(
+            inputs(None, false, false, true).next_sync_after(BASE),
+            Some(BASE * 2)
+        );
+        // More to upload syncs again soon...
+        assert_eq!(
+            inputs(None, false, true, false).next_sync_after(BASE),
+            Some(MORE_TO_UPLOAD_INTERVAL)
+        );
+        // ...but never later than the base interval.
+        assert_eq!(
+            inputs(None, false, true, false).next_sync_after(Duration::from_secs(30)),
+            Some(Duration::from_secs(30))
+        );
+        // Over quota wins over more to upload.
+        assert_eq!(
+            inputs(None, true, true, false).next_sync_after(BASE),
+            Some(BASE * 4)
+        );
+        assert_eq!(
+            inputs(None, true, false, true).next_sync_after(BASE),
+            Some(BASE * 4)
+        );
+    }
+
+    #[test]
+    fn test_next_sync_after_backoff() {
+        // A backoff on its own is at least the base interval.
+        assert_eq!(
+            inputs(Some(60), false, false, false).next_sync_after(BASE),
+            Some(BASE)
+        );
+        assert_eq!(
+            inputs(Some(6000), false, false, false).next_sync_after(BASE),
+            Some(Duration::from_secs(6000))
+        );
+        // We never sync again before the backoff expires, even with more to
+        // upload.
+        assert_eq!(
+            inputs(Some(300), false, true, false).next_sync_after(BASE),
+            Some(Duration::from_secs(300))
+        );
+        // But longer recommendations still win.
+        assert_eq!(
+            inputs(Some(300), false, false, true).next_sync_after(BASE),
+            Some(BASE * 2)
+        );
+        assert_eq!(
+            inputs(Some(300), true, false, false).next_sync_after(BASE),
+            Some(BASE * 4)
+        );
+        assert_eq!(
+            inputs(Some(6000), true, false, false).next_sync_after(BASE),
+            Some(Duration::from_secs(6000))
+        );
+    }
+
+    #[test]
+    fn test_record_store_result() {
+        let mut inputs = inputs(None, false, false, true);
+        inputs.record_store_result(&Ok(sync::CollectionSyncOutcome::default()));
+        assert!(inputs.no_op);
+        assert!(!inputs.more_to_upload);
+
+        // A failed store shouldn't be treated as a no-op sync, so we don't
+        // back off after an error.
+        inputs.record_store_result(&Err(ErrorKind::StorageResetError.into()));
+        assert!(!inputs.no_op);
+        assert_eq!(inputs.next_sync_after(BASE), None);
+
+        inputs.record_store_result(&Ok(sync::CollectionSyncOutcome {
+            incoming: 0,
+            outgoing: 100,
+            overflow: true,
+        }));
+        assert!(!inputs.no_op);
+        assert!(inputs.more_to_upload);
+    }
+
+    #[test]
+    fn test_into_single_store_result() {
+        let result = SyncResult {
+            next_sync_after: Some(BASE),
+            ..SyncResult::default()
+        };
+        assert_eq!(
+            result.into_single_store_result("history").unwrap(),
+            Some(BASE)
+        );
+
+        let mut result = SyncResult {
+            next_sync_after: Some(BASE),
+            ..SyncResult::default()
+        };
+        result
+            .failures
+            .insert("history".into(), ErrorKind::StorageResetError.into());
+        match result
+            .into_single_store_result("history")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::StorageResetError => {}
+            k => panic!("Unexpected error kind {:?}", k),
+        }
+    }
 }
@@ -375,6 +375,40 @@ impl EngineOutgoing {
     }
 }
 
+/// A problem that an engine's validator found in the server's records, and
+/// the number of records that had it.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    name: &'static str,
+    count: usize,
+}
+
+/// Validation results for an engine's sync.
+#[derive(Debug, Default, Serialize)]
+pub struct Validation {
+    version: u32,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<Problem>,
+}
+
+impl Validation {
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            ..Default::default()
+        }
+    }
+
+    /// Records a problem. Problems that no records had are ignored.
+    pub fn problem(&mut self, name: &'static str, count: usize) -> &mut Self {
+        if count > 0 {
+            self.problems.push(Problem { name, count });
+        }
+        self
+    }
+}
+
 /// One engine's sync.
 #[derive(Debug, Serialize)]
 pub struct Engine {
@@ -392,6 +426,9 @@ pub struct Engine {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "failureReason")]
     failure: Option<SyncFailure>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validation: Option<Validation>,
 }
 
 impl Engine {
@@ -402,6 +439,7 @@ impl Engine {
             incoming: None,
             outgoing: Vec::new(),
             failure: None,
+            validation: None,
         }
     }
 
@@ -428,6 +466,17 @@ impl Engine {
         }
     }
 
+    /// Records validation results. Unlike failures, we keep the latest
+    /// results, since they describe the most recent state of the data.
+    pub fn validation(&mut self, v: Validation) {
+        if let Some(old) = self.validation.replace(v) {
+            log::warn!(
+                "engine already has validation results - replacing {:?}",
+                old
+            );
+        }
+    }
+
     fn finished(&mut self) {
         self.when_took = self.when_took.finished();
     }
@@ -492,6 +541,47 @@ mod engine_tests {
         );
     }
 
+    #[test]
+    fn test_validation() {
+        let mut v = Validation::new(1);
+        v.problem("orphans", 2).problem("missingChildren", 0);
+        let mut e = Engine::new("TestEngine");
+        e.validation(v);
+        e.finished();
+        assert_json(
+            &e,
+            json!({"name": "TestEngine",
+             "when": 0.0,
+             "validation": {
+                 "version": 1,
+                 "problems": [{"name": "orphans", "count": 2}]
+             }
+            }),
+        );
+    }
+
+    #[test]
+    fn test_validation_replaced() {
+        let mut e = Engine::new("TestEngine");
+        let mut v = Validation::new(1);
+        v.problem("orphans", 2);
+        e.validation(v);
+        let mut v = Validation::new(1);
+        v.problem("missingChildren", 1);
+        e.validation(v);
+        e.finished();
+        assert_json(
+            &e,
+            json!({"name": "TestEngine",
+             "when": 0.0,
+             "validation": {
+                 "version": 1,
+                 "problems": [{"name": "missingChildren", "count": 1}]
+             }
+            }),
+        );
+    }
+
     #[test]
     fn test_raw() {
         let mut e = Engine::new("TestEngine");
@@ -683,6 +773,17 @@ impl SyncTelemetryPing {
     pub fn event(&mut self, e: Event) {
         self.events.push(e);
     }
+
+    /// Returns the most recently recorded sync's telemetry for the engine
+    /// named `name`, so that callers can add details that only the engine's
+    /// store knows.
+    pub fn engine_mut(&mut self, name: &str) -> Option<&mut Engine> {
+        self.syncs
+            .last_mut()?
+            .engines
+            .iter_mut()
+            .find(|e| e.name == name)
+    }
 }
 
 #[cfg(test)]
@@ -715,4 +816,37 @@ mod ping_tests {
             }),
         );
     }
+
+    #[test]
+    fn test_engine_mut() {
+        let mut p = SyncTelemetryPing::new();
+        assert!(p.engine_mut("test").is_none());
+        let mut s = SyncTelemetry::new();
+        s.engine(Engine::new("test"));
+        p.sync(s);
+        assert!(p.engine_mut("other").is_none());
+        let mut v = Validation::new(1);
+        v.problem("orphans", 1);
+        p.engine_mut("test")
+            .expect("should find engine")
+            .validation(v);
+        assert_json(
+            &p,
+            json!({
+                "syncs": [{
+                    "engines": [{
+                        "name": "test",
+                        "when": 0.0,
+                        "validation": {
+                            "version": 1,
+                            "problems": [{"name": "orphans", "count": 1}]
+                        }
+                    }],
+                    "when": 0.0
+                }],
+                "uid": null,
+                "version": 1
+            }),
+        );
+    }
 }
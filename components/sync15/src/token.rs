@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::error::{self, ErrorKind, Result};
+use crate::error::{self, ErrorKind, Result, TokenserverError};
 use crate::util::ServerTimestamp;
 use serde_derive::*;
 use std::borrow::{Borrow, Cow};
@@ -10,7 +10,7 @@ use std::cell::RefCell;
 use std::fmt;
 use std::time::{Duration, SystemTime};
 use url::Url;
-use viaduct::{header_names, Request};
+use viaduct::{header_names, Request, Response};
 
 const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
 
@@ -26,6 +26,50 @@ struct TokenserverToken {
     hashed_fxa_uid: String,
 }
 
+// The error body returned by the token server, like
+// `{"status": "invalid-credentials", "errors": [...]}`. We only care about
+// the status.
+#[derive(Deserialize, Debug)]
+struct TokenserverErrorBody {
+    status: String,
+}
+
+// Converts a non-success response from the token server into an error,
+// parsing the body if we can.
+fn error_from_response(resp: &Response, now: SystemTime) -> error::Error {
+    if let Some(res) = resp.headers.get_as::<f64, _>(header_names::RETRY_AFTER) {
+        let ms = res
+            .ok()
+            .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
+        let when = now + Duration::from_millis(ms);
+        return ErrorKind::BackoffError(when).into();
+    }
+    let code = resp.status;
+    if code == 503 {
+        return ErrorKind::TokenserverError(TokenserverError::Unavailable).into();
+    }
+    match resp.json::<TokenserverErrorBody>() {
+        Ok(body) => {
+            let err = match body.status.as_str() {
+                "invalid-credentials" => TokenserverError::InvalidCredentials,
+                "invalid-client-state" => TokenserverError::InvalidClientState,
+                "invalid-generation" => TokenserverError::InvalidGeneration,
+                "invalid-timestamp" => TokenserverError::InvalidTimestamp,
+                "new-users-disabled" => TokenserverError::NewUsersDisabled,
+                _ => TokenserverError::Other {
+                    code,
+                    status: body.status,
+                },
+            };
+            ErrorKind::TokenserverError(err).into()
+        }
+        Err(e) => {
+            log::warn!("Failed to parse tokenserver error body: {}", e);
+            ErrorKind::TokenserverHttpError(code).into()
+        }
+    }
+}
+
 // The struct returned by the TokenFetcher - the token itself and the
 // server timestamp.
 struct TokenFetchResult {
@@ -94,19 +138,8 @@ impl TokenFetcher for TokenServerFetcher {
 
         if !resp.is_success() {
             log::warn!("Non-success status when fetching token: {}", resp.status);
-            // TODO: the body should be JSON and contain a status parameter we might need?
             log::trace!("  Response body {}", resp.text());
-            // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
-            // have a TokenserverHttpError as its cause?
-            if let Some(res) = resp.headers.get_as::<f64, _>(header_names::RETRY_AFTER) {
-                let ms = res
-                    .ok()
-                    .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
-                let when = self.now() + Duration::from_millis(ms);
-                return Err(ErrorKind::BackoffError(when).into());
-            }
-            let status = resp.status;
-            return Err(ErrorKind::TokenserverHttpError(status).into());
+            return Err(error_from_response(&resp, self.now()));
         }
 
         let token: TokenserverToken = resp.json()?;
@@ -535,6 +568,119 @@ mod tests {
         assert_eq!(counter.get(), 2);
     }
 
+    fn error_response(status: u16, body: &str) -> Response {
+        Response {
+            request_method: viaduct::Method::Get,
+            url: Url::parse("https://token.services.mozilla.com/1.0/sync/1.5").unwrap(),
+            status,
+            headers: viaduct::Headers::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn tokenserver_error(err: &error::Error) -> Option<&TokenserverError> {
+        match err.kind() {
+            ErrorKind::TokenserverError(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_error_from_response() {
+        let now = SystemTime::now();
+        let statuses = [
+            (
+                401,
+                "invalid-credentials",
+                TokenserverError::InvalidCredentials,
+            ),
+            (
+                401,
+                "invalid-client-state",
+                TokenserverError::InvalidClientState,
+            ),
+            (
+                401,
+                "invalid-generation",
+                TokenserverError::InvalidGeneration,
+            ),
+            (401, "invalid-timestamp", TokenserverError::InvalidTimestamp),
+            (
+                403,
+                "new-users-disabled",
+                TokenserverError::NewUsersDisabled,
+            ),
+            (
+                400,
+                "something-new",
+                TokenserverError::Other {
+                    code: 400,
+                    status: "something-new".into(),
+                },
+            ),
+        ];
+        for (code, status, expected) in &statuses {
+            let body = format!(
+                r#"{{"status": "{}", "errors": [{{"location": "body", "name": "", "description": "Unauthorized"}}]}}"#,
+                status
+            );
+            let err = error_from_response(&error_response(*code, &body), now);
+            assert_eq!(tokenserver_error(&err), Some(expected), "{}", status);
+        }
+
+        // Maintenance, without and with `Retry-After`.
+        let err = error_from_response(&error_response(503, r#"{"status": "error"}"#), now);
+        assert_eq!(
+            tokenserver_error(&err),
+            Some(&TokenserverError::Unavailable)
+        );
+        let mut resp = error_response(503, r#"{"status": "error"}"#);
+        resp.headers
+            .insert(header_names::RETRY_AFTER, "30")
+            .unwrap();
+        match error_from_response(&resp, now).kind() {
+            ErrorKind::BackoffError(when) => {
+                assert_eq!(*when, now + Duration::from_secs(30));
+            }
+            e => panic!("Expected backoff error; got {:?}", e),
+        }
+
+        // Bodies that aren't JSON.
+        match error_from_response(&error_response(500, "Internal Server Error"), now).kind() {
+            ErrorKind::TokenserverHttpError(500) => {}
+            e => panic!("Expected HTTP error; got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_node_reassignment() {
+        let endpoint = RefCell::new("https://node1.example.com".to_string());
+        let fetch = || {
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: endpoint.borrow().clone(),
+                    uid: 1,
+                    duration: 10,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+        let now: Cell<SystemTime> = Cell::new(SystemTime::now());
+        let tsc = make_tsc(fetch, || now.get());
+        tsc.api_endpoint().expect("should get a valid token");
+
+        // Expire the token, and move to a new node.
+        now.set(now.get() + Duration::new(20, 0));
+        *endpoint.borrow_mut() = "https://node2.example.com".to_string();
+        match tsc.api_endpoint().expect_err("should fail").kind() {
+            ErrorKind::StorageResetError => {}
+            e => panic!("Expected storage reset error; got {:?}", e),
+        }
+    }
+
     #[test]
     fn test_server_url() {
         assert_eq!(
@@ -81,6 +81,18 @@ impl ServerTimestamp {
     }
 }
 
+/// Returns true if a record with a server-side `ttl`, in seconds, that was
+/// last modified at `modified` has expired by `now`. The server stops
+/// returning expired records, but clients that cache records locally need
+/// to expire them, too.
+pub fn is_expired(ttl: u32, modified: ServerTimestamp, now: ServerTimestamp) -> bool {
+    match now.duration_since(modified) {
+        Some(age) => age >= Duration::from_secs(u64::from(ttl)),
+        // Records modified after `now` haven't expired.
+        None => false,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -97,6 +109,15 @@ mod test {
         assert_eq!(dur.subsec_nanos(), 100_000_000);
     }
 
+    #[test]
+    fn test_is_expired() {
+        let modified = ServerTimestamp(1000.0);
+        assert!(!is_expired(60, modified, ServerTimestamp(1059.99)));
+        assert!(is_expired(60, modified, ServerTimestamp(1060.0)));
+        assert!(is_expired(0, modified, modified));
+        assert!(!is_expired(60, modified, ServerTimestamp(900.0)));
+    }
+
     #[test]
     fn test_gen_guid() {
         let mut set = HashSet::new();